@@ -0,0 +1,76 @@
+/*
+ * C interface of the fclones library.
+ *
+ * Build the shared library with:
+ *   cargo rustc --release --lib --features ffi --crate-type cdylib
+ *
+ * Functions returning int return 0 on success and -1 on failure,
+ * including when passed a NULL pointer.
+ * Call fclones_last_error() to get the message describing the failure.
+ */
+
+#ifndef FCLONES_H
+#define FCLONES_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define FCLONES_REMOVE 0
+#define FCLONES_SOFT_LINK 1
+#define FCLONES_HARD_LINK 2
+
+/* Configuration of a scan for duplicate files */
+typedef struct FclonesConfig FclonesConfig;
+
+/* A group of identical files */
+typedef struct FclonesGroup {
+    uint64_t file_len;
+    /* Hash of the contents as hexadecimal digits, may be NULL */
+    const char *file_hash;
+    const char *const *files;
+    size_t file_count;
+    /* Start of the scan that found the group, in milliseconds since the Unix epoch;
+     * fclones_dedupe leaves intact the files modified later. 0 disables the check. */
+    int64_t scan_start_ms;
+} FclonesGroup;
+
+typedef struct FclonesDedupeResult {
+    uint64_t processed_count;
+    uint64_t reclaimed_bytes;
+    uint64_t failed_count;
+} FclonesDedupeResult;
+
+/* Receives the groups found by fclones_group; the group is valid only during the call */
+typedef void (*FclonesGroupCallback)(const FclonesGroup *group, void *user_data);
+
+/* Returns the message of the last failure on the current thread or NULL */
+const char *fclones_last_error(void);
+
+FclonesConfig *fclones_config_new(void);
+void fclones_config_free(FclonesConfig *config);
+int fclones_config_add_path(FclonesConfig *config, const char *path);
+int fclones_config_add_name_pattern(FclonesConfig *config, const char *pattern);
+int fclones_config_add_exclude_pattern(FclonesConfig *config, const char *pattern);
+int fclones_config_set_min_size(FclonesConfig *config, uint64_t bytes);
+int fclones_config_set_max_size(FclonesConfig *config, uint64_t bytes);
+int fclones_config_set_skip_hidden(FclonesConfig *config, bool skip);
+int fclones_config_set_follow_links(FclonesConfig *config, bool follow);
+int fclones_config_set_hard_links(FclonesConfig *config, bool hard_links);
+int fclones_config_set_rf_over(FclonesConfig *config, size_t count);
+
+/* Scans the files and calls the callback on the calling thread for each group found */
+int fclones_group(const FclonesConfig *config, FclonesGroupCallback callback, void *user_data);
+
+/* Removes or links the redundant files of the group, or only counts them if dry_run is set */
+int fclones_dedupe(const FclonesGroup *group, int op, bool dry_run, FclonesDedupeResult *result);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
@@ -0,0 +1,109 @@
+//! Notes attached to the groups of a JSON report.
+//!
+//! Annotations are not produced by fclones. They are added to the groups of a JSON report
+//! by people or tools reviewing it, e.g. `"annotations": {"reviewed": true}`, and are
+//! carried over by `refilter` and into dedupe plans, so later steps can honor the decisions.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use serde_json::Value;
+
+/// Annotations of a group, by name
+pub type Annotations = BTreeMap<String, Value>;
+
+/// Condition on the annotations of a group given as `name=value` or just `name`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AnnotationFilter {
+    pub name: String,
+    /// If `None`, the annotation must only be present
+    pub value: Option<String>,
+}
+
+impl AnnotationFilter {
+    /// Returns true if the annotations satisfy the condition.
+    /// Values other than strings are compared to the expected value parsed as JSON,
+    /// so `reviewed=true` matches both `"reviewed": true` and `"reviewed": "true"`.
+    pub fn matches(&self, annotations: &Annotations) -> bool {
+        match (annotations.get(&self.name), &self.value) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(Value::String(s)), Some(expected)) => s == expected,
+            (Some(v), Some(expected)) => {
+                serde_json::from_str(expected).map_or(false, |e: Value| e == *v)
+            }
+        }
+    }
+}
+
+/// Returns true if the annotations satisfy all the filters
+pub fn matches_all(filters: &[AnnotationFilter], annotations: &Annotations) -> bool {
+    filters.iter().all(|f| f.matches(annotations))
+}
+
+impl FromStr for AnnotationFilter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, value) = match s.find('=') {
+            Some(i) => (&s[..i], Some(s[i + 1..].to_owned())),
+            None => (s, None),
+        };
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(format!("Annotation name expected: {}", s));
+        }
+        Ok(AnnotationFilter {
+            name: name.to_owned(),
+            value,
+        })
+    }
+}
+
+impl Display for AnnotationFilter {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match &self.value {
+            Some(value) => write!(f, "{}={}", self.name, value),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::*;
+
+    fn annotations(value: Value) -> Annotations {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn parse_filter() {
+        let filter: AnnotationFilter = "reviewed=true".parse().unwrap();
+        assert_eq!(filter.name, "reviewed");
+        assert_eq!(filter.value.as_deref(), Some("true"));
+        let filter: AnnotationFilter = "ticket".parse().unwrap();
+        assert_eq!(filter.value, None);
+        assert!("=true".parse::<AnnotationFilter>().is_err());
+    }
+
+    #[test]
+    fn match_values() {
+        let a = annotations(json!({"reviewed": true, "owner": "ops", "count": 2}));
+        let matches = |s: &str| s.parse::<AnnotationFilter>().unwrap().matches(&a);
+        assert!(matches("reviewed=true"));
+        assert!(!matches("reviewed=false"));
+        assert!(matches("owner=ops"));
+        assert!(matches("count=2"));
+        assert!(matches("owner"));
+        assert!(!matches("ticket"));
+        assert!(!matches_all(
+            &["owner".parse().unwrap(), "ticket".parse().unwrap()],
+            &a
+        ));
+    }
+}
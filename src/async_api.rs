@@ -0,0 +1,184 @@
+//! Asynchronous interface to the library, enabled by the `async` feature.
+//!
+//! Grouping and deduplication block the calling thread until all files are processed,
+//! which is not acceptable in async tasks. The functions of this module run the blocking
+//! work on a dedicated thread and return a [`Stream`] of its results, so the groups can be
+//! consumed by any async runtime as soon as they are found.
+
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread;
+
+use futures_core::Stream;
+use rayon::iter::ParallelIterator;
+use tokio::sync::mpsc;
+
+use crate::config::{DedupeConfig, GroupConfig};
+use crate::dedupe::{dedupe, DedupeOp, FsCommand};
+use crate::log::Log;
+use crate::path::Path;
+use crate::{group_files_to_sink, Error, FileGroup};
+
+/// Number of items the background thread can produce ahead of the consumer.
+/// When the buffer is full, the background thread waits for the consumer to catch up.
+const BUFFER_LEN: usize = 1024;
+
+/// Stream of items produced by a background thread.
+/// Dropping the stream cancels the work of the thread and discards its remaining items.
+pub struct ChannelStream<T> {
+    rx: mpsc::Receiver<T>,
+    cancel: Arc<AtomicBool>,
+}
+
+impl<T> Drop for ChannelStream<T> {
+    fn drop(&mut self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+}
+
+impl<T> Stream for ChannelStream<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+/// Runs `task` on a new thread and returns the stream of items passed by it to the sender.
+/// The `cancel` flag is set when the stream is dropped.
+fn spawn<T, F>(name: &str, cancel: Arc<AtomicBool>, task: F) -> ChannelStream<T>
+where
+    T: Send + 'static,
+    F: FnOnce(mpsc::Sender<T>) + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(BUFFER_LEN);
+    thread::Builder::new()
+        .name(name.to_owned())
+        .spawn(move || task(tx))
+        .unwrap();
+    ChannelStream { rx, cancel }
+}
+
+/// Asynchronous version of [`group_files`](crate::group_files).
+///
+/// Groups the files in the background and streams each group as soon as it is found,
+/// in no particular order, like [`group_files_to_sink`].
+/// If grouping fails, the error is the last item of the stream.
+/// Dropping the stream cancels grouping, as if the [`GroupConfig::cancel`] flag was set.
+pub fn group_files_async(
+    mut config: GroupConfig,
+    log: Arc<Log>,
+) -> ChannelStream<Result<FileGroup<Path>, Error>> {
+    let cancel = config.cancel.get_or_insert_with(Default::default).clone();
+    spawn("fclones-group", cancel.clone(), move |tx| {
+        let result = group_files_to_sink(&config, &log, |group| {
+            // The consumer may have dropped the stream already
+            if tx.blocking_send(Ok(group)).is_err() {
+                cancel.store(true, Ordering::Relaxed);
+            }
+        });
+        if let Err(e) = result {
+            let _ = tx.blocking_send(Err(e));
+        }
+    })
+}
+
+/// Asynchronous version of [`dedupe`].
+///
+/// Computes the commands removing or linking the redundant files of the `groups`
+/// in the background and streams them in no particular order.
+/// The commands are not executed.
+/// Dropping the stream stops computing the remaining commands.
+pub fn dedupe_async<I>(
+    groups: I,
+    op: DedupeOp,
+    config: DedupeConfig,
+    log: Arc<Log>,
+) -> ChannelStream<FsCommand>
+where
+    I: IntoIterator<Item = FileGroup<Path>> + Send + 'static,
+{
+    let cancel = Arc::new(AtomicBool::new(false));
+    spawn("fclones-dedupe", cancel.clone(), move |tx| {
+        let groups: Vec<_> = groups
+            .into_iter()
+            .take_while(|_| !cancel.load(Ordering::Relaxed))
+            .collect();
+        // Fails as soon as the consumer drops the stream:
+        let _ = dedupe(groups, op, &config, &log).try_for_each(|cmd| tx.blocking_send(cmd).ok());
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::task::{RawWaker, RawWakerVTable, Waker};
+
+    use crate::util::test::{with_dir, write_file};
+
+    use super::*;
+
+    /// Polls the stream to the end on the current thread
+    fn collect<S: Stream + Unpin>(mut stream: S) -> Vec<S::Item> {
+        fn no_op(_: *const ()) {}
+        fn clone(_: *const ()) -> RawWaker {
+            RawWaker::new(std::ptr::null(), &VTABLE)
+        }
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, no_op, no_op, no_op);
+        let waker = unsafe { Waker::from_raw(clone(std::ptr::null())) };
+        let mut cx = Context::from_waker(&waker);
+        let mut items = Vec::new();
+        loop {
+            match Pin::new(&mut stream).poll_next(&mut cx) {
+                Poll::Ready(Some(item)) => items.push(item),
+                Poll::Ready(None) => return items,
+                Poll::Pending => thread::yield_now(),
+            }
+        }
+    }
+
+    #[test]
+    fn group_files_async_streams_groups() {
+        with_dir("async_api/group_files_async", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            write_file(&file1, "foo");
+            write_file(&file2, "foo");
+
+            let config = GroupConfig::builder().paths(vec![file1, file2]).build();
+            let log = Arc::new(Log::new());
+            let groups = collect(group_files_async(config, log.clone()));
+            assert_eq!(groups.len(), 1);
+            let group = groups.into_iter().next().unwrap().unwrap();
+            assert_eq!(group.files.len(), 2);
+
+            let commands = collect(dedupe_async(
+                vec![group],
+                DedupeOp::Remove,
+                DedupeConfig::default(),
+                log,
+            ));
+            assert_eq!(commands.len(), 1);
+        });
+    }
+
+    #[test]
+    fn dropping_stream_cancels_grouping() {
+        with_dir("async_api/dropping_stream_cancels_grouping", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            write_file(&file1, "foo");
+            write_file(&file2, "foo");
+
+            let cancel = Arc::new(AtomicBool::new(false));
+            let config = GroupConfig::builder()
+                .paths(vec![file1, file2])
+                .cancel(cancel.clone())
+                .build();
+            let stream = group_files_async(config, Arc::new(Log::new()));
+            drop(stream);
+            assert!(cancel.load(Ordering::Relaxed));
+        });
+    }
+}
@@ -0,0 +1,156 @@
+//! Recording the changes made to the file system by the deduplication commands.
+
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::{fmt, io};
+
+use chrono::{DateTime, FixedOffset, Local};
+use serde::{Deserialize, Serialize};
+
+use crate::dedupe::FsCommand;
+use crate::files::{group_id, FileHash, FileLen};
+use crate::path::Path;
+use crate::report::parse_path;
+
+/// A single executed command, written as one line of JSON
+#[derive(Serialize)]
+struct AuditEntry {
+    timestamp: DateTime<FixedOffset>,
+    action: &'static str,
+    /// The file that was removed, moved or replaced by a link
+    source: Path,
+    /// The target of the move or of the link
+    #[serde(skip_serializing_if = "Option::is_none")]
+    destination: Option<Path>,
+    hash: FileHash,
+    size: FileLen,
+    /// Identifier of the group of the file in the report
+    group: String,
+    result: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    /// Shell commands that perform the failed action, for running it again later
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command: Option<Vec<String>>,
+}
+
+impl AuditEntry {
+    fn new(cmd: &FsCommand, result: &io::Result<FileLen>) -> AuditEntry {
+        let (action, source, destination, hash) = cmd.describe();
+        let now = Local::now();
+        AuditEntry {
+            timestamp: DateTime::from_utc(now.naive_utc(), *now.offset()),
+            action,
+            source: source.path.clone(),
+            destination: destination.cloned(),
+            hash: hash.clone(),
+            size: FileLen(source.metadata.len()),
+            group: group_id(FileLen(source.metadata.len()), hash),
+            result: if result.is_ok() { "ok" } else { "failed" },
+            error: result.as_ref().err().map(|e| e.to_string()),
+            command: result.as_ref().err().map(|_| cmd.to_shell_str()),
+        }
+    }
+}
+
+/// Append-only log of the commands executed by the deduplication commands.
+///
+/// Each command is written as a separate line of JSON as soon as it completes,
+/// with the time, the kind of the action, the paths involved, the hash and size
+/// of the file and whether the command succeeded. Entries of failed commands
+/// also contain the shell commands to run them again. Existing entries are never modified.
+pub struct AuditLog {
+    path: PathBuf,
+    file: Mutex<File>,
+}
+
+impl AuditLog {
+    /// Opens the log for appending, creating the file if it doesn't exist
+    pub fn open(path: &std::path::Path) -> io::Result<AuditLog> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("Failed to open audit log {}: {}", path.display(), e),
+                )
+            })?;
+        Ok(AuditLog {
+            path: path.to_path_buf(),
+            file: Mutex::new(file),
+        })
+    }
+
+    /// Reads the paths of the files successfully removed, moved or replaced by links
+    /// according to the log written by an earlier, possibly interrupted, run.
+    /// Lines that can't be parsed, e.g. a line cut short by a crash, are skipped.
+    pub fn read_completed(path: &std::path::Path) -> io::Result<HashSet<Path>> {
+        #[derive(Deserialize)]
+        struct Entry {
+            source: String,
+            result: String,
+        }
+
+        let error = |e: io::Error| {
+            io::Error::new(
+                e.kind(),
+                format!("Failed to read audit log {}: {}", path.display(), e),
+            )
+        };
+        let file = File::open(path).map_err(error)?;
+        let mut completed = HashSet::new();
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(error)?;
+            if let Ok(entry) = serde_json::from_str::<Entry>(&line) {
+                if entry.result == "ok" {
+                    if let Ok(source) = parse_path(&entry.source) {
+                        completed.insert(source);
+                    }
+                }
+            }
+        }
+        Ok(completed)
+    }
+
+    /// Appends the entry describing the executed command and its result
+    pub fn record(&self, cmd: &FsCommand, result: &io::Result<FileLen>) -> io::Result<()> {
+        let mut line = serde_json::to_vec(&AuditEntry::new(cmd, result))?;
+        line.push(b'\n');
+        self.file.lock().unwrap().write_all(&line)
+    }
+}
+
+impl Display for AuditLog {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.path.display())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::util::test::{with_dir, write_file};
+
+    use super::*;
+
+    #[test]
+    fn read_completed_returns_only_successful_actions() {
+        with_dir("audit/read_completed", |root| {
+            let log_path = root.join("audit.log");
+            write_file(
+                &log_path,
+                "{\"action\":\"remove\",\"source\":\"/a\",\"result\":\"ok\"}\n\
+                 {\"action\":\"remove\",\"source\":\"/b\",\"result\":\"failed\"}\n\
+                 {\"action\":\"remove\",\"source\":\"/c\",\"res",
+            );
+            let completed = AuditLog::read_completed(&log_path).unwrap();
+            assert_eq!(completed.len(), 1);
+            assert!(completed.contains(&Path::from("/a")));
+        })
+    }
+}
@@ -0,0 +1,138 @@
+//! Reusing file hashes from a previous report, so unchanged files don't need to be read again.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::sync::Arc;
+
+use chrono::{DateTime, FixedOffset, Local};
+use fallible_iterator::FallibleIterator;
+
+use crate::config::UnicodeForm;
+use crate::files::{Digests, FileHash, FileInfo, FileLen, HashFn};
+use crate::path::Path;
+use crate::report::{open_report, GroupingHash};
+use crate::Error;
+
+/// File hashes read from a previous report
+pub(crate) struct Baseline {
+    /// Time the scan producing the report started; files modified later must be hashed again
+    timestamp: DateTime<FixedOffset>,
+    hashes: HashMap<Path, (FileLen, FileHash, Digests)>,
+    /// Normalization applied to the paths before looking them up
+    normalization: Option<UnicodeForm>,
+}
+
+impl Baseline {
+    /// Reads the hashes of the files from the report.
+    /// Relative paths in the report are resolved against `base_dir`.
+    /// If `normalization` is given, paths are matched after converting them to that
+    /// Unicode normalization form, so a report written on another system can be used.
+    ///
+    /// Only groups of at least two files are taken into account, because only then
+    /// the reported hash is computed over the whole contents of the files.
+    /// The hashes by other hash functions recorded in the groups are loaded as well.
+    /// Reports created with `--transform` are rejected, as their hashes are computed
+    /// from the transformed data. So are reports with hashes computed by a different
+    /// hash function or of a different width than the hashes computed now.
+    pub fn load(
+        path: &std::path::Path,
+        base_dir: &Arc<Path>,
+        normalization: Option<UnicodeForm>,
+    ) -> Result<Baseline, Error> {
+        let error = |e: std::io::Error| {
+            Error::new(format!(
+                "Failed to read baseline report {}: {}",
+                path.display(),
+                e
+            ))
+        };
+        let file = File::open(path).map_err(error)?;
+        let mut reader = open_report(file).map_err(error)?;
+        let header = reader.read_header().map_err(error)?;
+        if header
+            .command
+            .iter()
+            .any(|arg| arg.starts_with("--transform"))
+        {
+            return Err(Error::new(format!(
+                "Baseline report {} was created with --transform",
+                path.display()
+            )));
+        }
+        let expected = GroupingHash::CURRENT;
+        if let Some(hash) = header.hash {
+            if hash != expected {
+                return Err(Error::new(format!(
+                    "Baseline report {} contains hashes computed with {}, expected {}",
+                    path.display(),
+                    hash,
+                    expected
+                )));
+            }
+        }
+
+        let mut hashes = HashMap::new();
+        let mut groups = reader.read_groups().map_err(error)?;
+        while let Some(group) = groups.next().map_err(error)? {
+            if group.files.len() < 2 || group.file_hash.as_bytes().is_empty() {
+                continue;
+            }
+            // Reports written by older versions don't name the hash function:
+            let bits = group.file_hash.as_bytes().len() * 8;
+            if bits != expected.bits {
+                return Err(Error::new(format!(
+                    "Baseline report {} contains {}-bit hashes, expected {}",
+                    path.display(),
+                    bits,
+                    expected
+                )));
+            }
+            for f in group.files {
+                let f = base_dir.resolve(f);
+                let f = match normalization {
+                    Some(form) => f.normalized(form),
+                    None => f,
+                };
+                hashes.insert(
+                    f,
+                    (
+                        group.file_len,
+                        group.file_hash.clone(),
+                        group.hashes.clone(),
+                    ),
+                );
+            }
+        }
+        Ok(Baseline {
+            timestamp: header.timestamp,
+            hashes,
+            normalization,
+        })
+    }
+
+    /// Returns the hash of the file and its hashes by `hash_fns` recorded in the baseline report,
+    /// if the file has the same size and was not modified after the scan producing the report
+    /// started. Returns `None` if any of the hashes by `hash_fns` is not recorded.
+    pub fn hashes(&self, file: &FileInfo, hash_fns: &[HashFn]) -> Option<(FileHash, Digests)> {
+        let (len, hash, digests) = match self.normalization {
+            Some(form) => self.hashes.get(&file.path.normalized(form))?,
+            None => self.hashes.get(&file.path)?,
+        };
+        if *len != file.len {
+            return None;
+        }
+        let digests = hash_fns
+            .iter()
+            .map(|f| digests.get(f).map(|d| (*f, d.clone())))
+            .collect::<Option<Digests>>()?;
+        let modified = std::fs::metadata(file.path.to_path_buf())
+            .and_then(|m| m.modified())
+            .ok()?;
+        let modified: DateTime<FixedOffset> = DateTime::<Local>::from(modified).into();
+        if modified < self.timestamp {
+            Some((hash.clone(), digests))
+        } else {
+            None
+        }
+    }
+}
@@ -0,0 +1,391 @@
+//! Measuring hashing throughput for tuning the `--threads` and `--buffer-size` settings.
+
+use std::ffi::OsString;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rayon::prelude::*;
+use rayon::ThreadPoolBuilder;
+
+use crate::config::BenchConfig;
+use crate::device::DiskDevices;
+use crate::files::{
+    evict_page_cache, file_hash, stream_hashes, Caching, ExtraHashes, FileLen, FilePos, HashFn,
+};
+use crate::hash_backend::{cpu_features, HashBackend};
+use crate::log::Log;
+use crate::path::Path;
+use crate::walk::Walk;
+use crate::Error;
+
+/// Settings giving throughput within this fraction of the best one are considered equally good.
+/// Among them, the one using the least resources is recommended.
+const TOLERANCE: f64 = 0.05;
+
+/// Size of the in-memory buffer used for measuring the speed of the hash function alone
+const HASH_BENCH_LEN: usize = 64 * 1024 * 1024;
+
+/// Throughput measured for a single combination of settings
+#[derive(Clone, Debug)]
+pub struct BenchResult {
+    pub threads: usize,
+    pub buf_len: usize,
+    pub bytes: FileLen,
+    pub duration: Duration,
+}
+
+impl BenchResult {
+    /// Returns the throughput in bytes per second
+    pub fn throughput(&self) -> f64 {
+        self.bytes.0 as f64 / self.duration.as_secs_f64().max(1e-9)
+    }
+}
+
+/// Speed of a hash function computed over data already in memory
+#[derive(Clone, Debug)]
+pub struct HashBenchResult {
+    pub hash_fn: HashFn,
+    pub backend: HashBackend,
+    pub bytes: FileLen,
+    pub duration: Duration,
+}
+
+impl HashBenchResult {
+    /// Returns the throughput in bytes per second
+    pub fn throughput(&self) -> f64 {
+        self.bytes.0 as f64 / self.duration.as_secs_f64().max(1e-9)
+    }
+}
+
+/// Outcome of the `bench` command
+#[derive(Clone, Debug)]
+pub struct BenchReport {
+    /// Name of the device holding the benchmarked files
+    pub device: OsString,
+    /// Size of the thread pool the device currently uses for random I/O
+    pub random_threads: usize,
+    /// CPU extensions accelerating hashing detected on this machine
+    pub cpu_features: Vec<&'static str>,
+    /// Number of files read in each measurement
+    pub file_count: usize,
+    /// Speeds of hash functions, not including the I/O
+    pub hash_results: Vec<HashBenchResult>,
+    /// Throughput of reading and hashing files, for each combination of settings
+    pub results: Vec<BenchResult>,
+}
+
+impl BenchReport {
+    /// Returns the recommended settings.
+    /// Prefers fewer threads and smaller buffers if they are not noticeably slower.
+    pub fn best(&self) -> Option<&BenchResult> {
+        let max_throughput = self
+            .results
+            .iter()
+            .map(|r| r.throughput())
+            .fold(0.0, f64::max);
+        self.results
+            .iter()
+            .filter(|r| r.throughput() >= (1.0 - TOLERANCE) * max_throughput)
+            .min_by_key(|r| (r.threads, r.buf_len))
+    }
+}
+
+/// Formats byte count in a form accepted by `--buffer-size`
+fn format_buf_len(len: usize) -> String {
+    const MIB: usize = 1024 * 1024;
+    const KIB: usize = 1024;
+    if len % MIB == 0 {
+        format!("{}MiB", len / MIB)
+    } else if len % KIB == 0 {
+        format!("{}KiB", len / KIB)
+    } else {
+        format!("{}", len)
+    }
+}
+
+/// Formats throughput given in bytes per second
+fn format_throughput(throughput: f64) -> String {
+    format!("{}/s", FileLen(throughput as u64))
+}
+
+impl Display for BenchReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Device: {}", self.device.to_string_lossy())?;
+        writeln!(f, "Files: {}", self.file_count)?;
+        if self.cpu_features.is_empty() {
+            writeln!(f, "CPU hashing extensions: none")?;
+        } else {
+            writeln!(
+                f,
+                "CPU hashing extensions: {}",
+                self.cpu_features.join(", ")
+            )?;
+        }
+        for r in self.hash_results.iter() {
+            writeln!(
+                f,
+                "Hash function {} ({}, in memory): {}",
+                r.hash_fn,
+                r.backend,
+                format_throughput(r.throughput())
+            )?;
+        }
+        writeln!(f, "{:>8} {:>12} {:>14}", "Threads", "Buffer", "Throughput")?;
+        for r in self.results.iter() {
+            writeln!(
+                f,
+                "{:>8} {:>12} {:>14}",
+                r.threads,
+                format_buf_len(r.buf_len),
+                format_throughput(r.throughput())
+            )?;
+        }
+        match self.best() {
+            Some(best) => {
+                let device = self.device.to_string_lossy();
+                write!(
+                    f,
+                    "Recommended settings: --threads dev:{}:{},{} --buffer-size dev:{}:{}",
+                    device,
+                    self.random_threads,
+                    best.threads,
+                    device,
+                    format_buf_len(best.buf_len)
+                )
+            }
+            None => write!(f, "No measurements to recommend settings from"),
+        }
+    }
+}
+
+/// Collects regular files under `path` until their total size reaches `max_size`.
+/// Returns the files with the number of bytes to read from each of them.
+/// The list is sorted by path, so that repeated runs read the same data.
+fn collect_files(path: &Path, max_size: FileLen, log: &Log) -> Vec<(Path, FileLen)> {
+    let files = Mutex::new(Vec::new());
+    let mut walk = Walk::new();
+    walk.log = Some(log);
+    walk.run(vec![path.clone()], |path| {
+        match std::fs::metadata(path.to_path_buf()) {
+            Ok(m) if m.is_file() && m.len() > 0 => {
+                files.lock().unwrap().push((path, FileLen(m.len())))
+            }
+            Ok(_) => {}
+            Err(e) => log.warn(format!("Failed to read metadata of {}: {}", path, e)),
+        }
+    });
+    let mut files = files.into_inner().unwrap();
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let mut remaining = max_size;
+    let mut result = Vec::new();
+    for (path, len) in files {
+        if remaining == FileLen(0) {
+            break;
+        }
+        let len = len.min(remaining);
+        remaining = remaining - len;
+        result.push((path, len));
+    }
+    result
+}
+
+/// Removes the files from the page cache, so the next measurement has to read them
+/// from the device. Works only on Linux, and only for pages that are not dirty.
+fn evict(files: &[(Path, FileLen)]) {
+    for (path, len) in files {
+        if let Ok(file) = File::open(path.to_path_buf()) {
+            evict_page_cache(&file, FilePos(0), *len);
+        }
+    }
+}
+
+/// Reads and hashes the files with the given settings and measures the time it took
+fn measure(files: &[(Path, FileLen)], threads: usize, buf_len: usize) -> io::Result<BenchResult> {
+    let pool = ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    evict(files);
+    let start = Instant::now();
+    pool.install(|| {
+        files.par_iter().try_for_each(|(path, len)| {
+            file_hash(path, FilePos(0), *len, buf_len, Caching::Sequential, |_| {}).map(|_| ())
+        })
+    })?;
+    Ok(BenchResult {
+        threads,
+        buf_len,
+        bytes: files.iter().map(|(_, len)| *len).sum(),
+        duration: start.elapsed(),
+    })
+}
+
+/// Measures the speed of the hash function computed by the backend without doing any I/O.
+/// The `metro` hash is always computed, so its time is included in the times of other hashes.
+fn measure_hash(
+    hash_fn: HashFn,
+    backend: &HashBackend,
+    len: FileLen,
+    buf_len: usize,
+) -> io::Result<HashBenchResult> {
+    let data = vec![0x5a; len.0 as usize];
+    let hashes = ExtraHashes {
+        fns: vec![hash_fn],
+        backend: backend.clone(),
+    };
+    let start = Instant::now();
+    stream_hashes(&mut data.as_slice(), len, buf_len, &hashes, |_| {})?;
+    Ok(HashBenchResult {
+        hash_fn,
+        backend: backend.clone(),
+        bytes: len,
+        duration: start.elapsed(),
+    })
+}
+
+/// Measures the speed of all hash functions with the built-in backend and the given backends
+fn measure_hashes(
+    backends: &[HashBackend],
+    len: FileLen,
+    buf_len: usize,
+) -> Result<Vec<HashBenchResult>, Error> {
+    let mut runs = vec![(HashFn::Metro, &HashBackend::Builtin)];
+    let backends = Some(&HashBackend::Builtin)
+        .into_iter()
+        .chain(backends.iter().filter(|&b| *b != HashBackend::Builtin));
+    for backend in backends {
+        backend.check()?;
+        runs.push((HashFn::Sha256, backend));
+        runs.push((HashFn::Sha512, backend));
+    }
+    runs.into_iter()
+        .map(|(hash_fn, backend)| {
+            measure_hash(hash_fn, backend, len, buf_len)
+                .map_err(|e| Error::new(format!("Failed to hash with backend {}: {}", backend, e)))
+        })
+        .collect()
+}
+
+/// Runs the benchmark described by the config.
+/// Reports progress with a progress bar.
+pub fn run_bench(config: &BenchConfig, log: &Log) -> Result<BenchReport, Error> {
+    let path = Path::from(&config.path).canonicalize();
+    let thread_counts = config.thread_counts();
+    let buffer_sizes = config.buffer_sizes();
+    if thread_counts.is_empty() {
+        return Err(Error::from("Thread counts must be positive"));
+    }
+    if buffer_sizes.is_empty() {
+        return Err(Error::from("Buffer sizes must be positive"));
+    }
+
+    let files = collect_files(&path, config.max_size, log);
+    if files.is_empty() {
+        return Err(Error::new(format!("No files to read in {}", path)));
+    }
+    let devices = DiskDevices::new(&Default::default());
+    let device = devices.get_by_path(&path);
+
+    let hash_len = config.max_size.min(FileLen(HASH_BENCH_LEN as u64));
+    let hash_results = measure_hashes(
+        &config.hash_backend,
+        hash_len,
+        *buffer_sizes.last().unwrap(),
+    )?;
+    let progress = log.progress_bar(
+        "Benchmarking",
+        (thread_counts.len() * buffer_sizes.len()) as u64,
+    );
+    let mut results = Vec::new();
+    for &threads in thread_counts.iter() {
+        for &buf_len in buffer_sizes.iter() {
+            let result = measure(&files, threads, buf_len)
+                .map_err(|e| Error::new(format!("Failed to read files: {}", e)))?;
+            results.push(result);
+            progress.tick();
+        }
+    }
+    Ok(BenchReport {
+        device: device.name.clone(),
+        random_threads: device.parallelism.random,
+        cpu_features: cpu_features(),
+        file_count: files.len(),
+        hash_results,
+        results,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{create_dir, File};
+    use std::io::Write;
+
+    use crate::util::test::*;
+
+    use super::*;
+
+    fn result(threads: usize, buf_len: usize, millis: u64) -> BenchResult {
+        BenchResult {
+            threads,
+            buf_len,
+            bytes: FileLen(1000000),
+            duration: Duration::from_millis(millis),
+        }
+    }
+
+    #[test]
+    fn recommends_cheapest_settings_among_the_fastest() {
+        let report = BenchReport {
+            device: OsString::from("/dev/sda"),
+            random_threads: 8,
+            cpu_features: vec![],
+            file_count: 1,
+            hash_results: vec![],
+            results: vec![
+                result(1, 65536, 400),
+                result(2, 65536, 101),
+                result(2, 1048576, 100),
+                result(4, 65536, 100),
+            ],
+        };
+        let best = report.best().unwrap();
+        assert_eq!(best.threads, 2);
+        assert_eq!(best.buf_len, 65536);
+        assert!(report
+            .to_string()
+            .ends_with("--threads dev:/dev/sda:8,2 --buffer-size dev:/dev/sda:64KiB"));
+    }
+
+    #[test]
+    fn bench_reads_files_up_to_max_size() {
+        with_dir("bench/max_size", |root| {
+            create_dir(root.join("dir")).unwrap();
+            for name in ["dir/file1", "file2"].iter() {
+                let mut f = File::create(root.join(name)).unwrap();
+                f.write_all(&[1; 1000]).unwrap();
+            }
+            let mut log = Log::new();
+            log.no_progress = true;
+            let config = BenchConfig {
+                thread_counts: vec![2, 1],
+                buffer_sizes: vec![FileLen(512), FileLen(4096)],
+                hash_backend: vec![],
+                max_size: FileLen(1500),
+                path: root.to_path_buf(),
+            };
+            let report = run_bench(&config, &log).unwrap();
+            assert_eq!(report.file_count, 2);
+            assert_eq!(report.hash_results.len(), 3);
+            assert!(report.hash_results.iter().all(|r| r.bytes == FileLen(1500)));
+            assert_eq!(report.results.len(), 4);
+            assert_eq!(report.results[0].threads, 1);
+            assert_eq!(report.results[0].buf_len, 512);
+            assert!(report.results.iter().all(|r| r.bytes == FileLen(1500)));
+        });
+    }
+}
@@ -1,34 +1,52 @@
 //! Main program configuration.
 
+use std::cmp::min;
 use std::collections::HashMap;
 use std::ffi::OsString;
 use std::fmt::{Display, Formatter};
 use std::io;
 use std::io::{stdin, BufRead, BufReader};
+use std::iter;
 use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
 
 use chrono::{DateTime, FixedOffset, Local};
 use clap::AppSettings;
+use itertools::Itertools;
 use structopt::StructOpt;
+use unicode_normalization::UnicodeNormalization;
 
-use crate::files::FileLen;
+use crate::annotation::AnnotationFilter;
+use crate::files::{ExtraHashes, FileHash, FileLen, HashFn, SizeRange};
+use crate::hash_backend::HashBackend;
+use crate::ignore_file::IgnoreFile;
+use crate::keep_list::KeepList;
 use crate::path::Path;
 use crate::pattern::{Pattern, PatternError, PatternOpts};
+use crate::progress::StageProgress;
 use crate::selector::PathSelector;
+use crate::summary::DedupeCounters;
 use crate::transform::Transform;
+use crate::window::RunWindow;
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum OutputFormat {
     Default,
     Fdupes,
     Csv,
     Json,
+    /// Only the statistics from the header, with no file lists
+    Summary,
+    /// Text format with escaped paths, tagged group lines and a checksum trailer
+    TextV2,
 }
 
 impl OutputFormat {
     pub fn variants() -> Vec<&'static str> {
-        vec!["default", "fdupes", "csv", "json"]
+        vec!["default", "fdupes", "csv", "json", "summary", "text-v2"]
     }
 }
 
@@ -39,6 +57,8 @@ impl Display for OutputFormat {
             OutputFormat::Fdupes => f.pad("fdupes"),
             OutputFormat::Csv => f.pad("csv"),
             OutputFormat::Json => f.pad("json"),
+            OutputFormat::Summary => f.pad("summary"),
+            OutputFormat::TextV2 => f.pad("text-v2"),
         }
     }
 }
@@ -52,6 +72,8 @@ impl FromStr for OutputFormat {
             "fdupes" => Ok(OutputFormat::Fdupes),
             "csv" => Ok(OutputFormat::Csv),
             "json" => Ok(OutputFormat::Json),
+            "summary" => Ok(OutputFormat::Summary),
+            "text-v2" => Ok(OutputFormat::TextV2),
             s => Err(format!("Unrecognized output format: {}", s)),
         }
     }
@@ -63,6 +85,487 @@ impl Default for OutputFormat {
     }
 }
 
+/// An additional report file written in the given format, see `--extra-output`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtraOutput {
+    pub format: OutputFormat,
+    pub path: PathBuf,
+}
+
+impl FromStr for ExtraOutput {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((format, path)) if !path.is_empty() => Ok(ExtraOutput {
+                format: format.parse()?,
+                path: PathBuf::from(path),
+            }),
+            _ => Err(format!(
+                "Invalid output: {}. Must be given as <format>:<path>",
+                s
+            )),
+        }
+    }
+}
+
+/// Character separating the fields of the CSV reports, see `--csv-delimiter`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsvDelimiter(pub u8);
+
+impl Default for CsvDelimiter {
+    fn default() -> CsvDelimiter {
+        CsvDelimiter(b',')
+    }
+}
+
+impl FromStr for CsvDelimiter {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tab" | "\\t" | "\t" => Ok(CsvDelimiter(b'\t')),
+            s if s.len() == 1
+                && s.is_ascii()
+                && !s.contains(|c: char| c.is_ascii_alphanumeric() || "\"\r\n".contains(c)) =>
+            {
+                Ok(CsvDelimiter(s.as_bytes()[0]))
+            }
+            s => Err(format!(
+                "Invalid CSV delimiter: {}. Expected `tab` or a single ASCII character \
+                 other than a letter, a digit, a quote or a line break",
+                s
+            )),
+        }
+    }
+}
+
+/// Decides how file paths are written in the text and `fdupes` reports
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PathFormat {
+    /// Paths are written as they are
+    Plain,
+    /// Paths are written as `file://` URIs with special characters percent-encoded
+    Uri,
+    /// Paths containing special characters are quoted so they can be pasted into a shell
+    ShellQuoted,
+}
+
+impl PathFormat {
+    pub fn variants() -> Vec<&'static str> {
+        vec!["plain", "uri", "shell-quoted"]
+    }
+}
+
+impl FromStr for PathFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "plain" => Ok(PathFormat::Plain),
+            "uri" => Ok(PathFormat::Uri),
+            "shell-quoted" => Ok(PathFormat::ShellQuoted),
+            s => Err(format!("Unrecognized path format: {}", s)),
+        }
+    }
+}
+
+impl Default for PathFormat {
+    fn default() -> PathFormat {
+        PathFormat::Plain
+    }
+}
+
+/// Decides what to do when one input path is contained in another one
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverlapPolicy {
+    /// Scans the contained path only as a part of the containing path
+    Merge,
+    /// Refuses to run
+    Error,
+}
+
+impl OverlapPolicy {
+    pub fn variants() -> Vec<&'static str> {
+        vec!["merge", "error"]
+    }
+}
+
+impl FromStr for OverlapPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "merge" => Ok(OverlapPolicy::Merge),
+            "error" => Ok(OverlapPolicy::Error),
+            s => Err(format!("Unrecognized overlap policy: {}", s)),
+        }
+    }
+}
+
+impl Default for OverlapPolicy {
+    fn default() -> OverlapPolicy {
+        OverlapPolicy::Merge
+    }
+}
+
+/// Decides whether the output should be colored
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorMode {
+    /// Colors the output if it is a terminal and `NO_COLOR` is not set
+    Auto,
+    /// Always colors the output, even if it is redirected to a file
+    Always,
+    /// Never colors the output
+    Never,
+}
+
+impl ColorMode {
+    pub fn variants() -> Vec<&'static str> {
+        vec!["auto", "always", "never"]
+    }
+
+    /// Returns true if the output should be colored.
+    /// The `is_term` argument tells if the output goes to a terminal.
+    pub fn enabled(&self, is_term: bool) -> bool {
+        match self {
+            ColorMode::Auto => {
+                is_term && std::env::var_os("NO_COLOR").map_or(true, |v| v.is_empty())
+            }
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+        }
+    }
+}
+
+impl FromStr for ColorMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(ColorMode::Auto),
+            "always" => Ok(ColorMode::Always),
+            "never" => Ok(ColorMode::Never),
+            s => Err(format!("Unrecognized color mode: {}", s)),
+        }
+    }
+}
+
+impl Default for ColorMode {
+    fn default() -> ColorMode {
+        ColorMode::Auto
+    }
+}
+
+/// Unicode normalization form used to compare paths
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnicodeForm {
+    /// Canonical composition, e.g. `é` is a single code point
+    Nfc,
+    /// Canonical decomposition, e.g. `é` is `e` followed by a combining accent
+    Nfd,
+}
+
+impl UnicodeForm {
+    pub fn variants() -> Vec<&'static str> {
+        vec!["nfc", "nfd"]
+    }
+
+    /// Converts the string to this normalization form
+    pub fn normalize(&self, s: &str) -> String {
+        match self {
+            UnicodeForm::Nfc => s.nfc().collect(),
+            UnicodeForm::Nfd => s.nfd().collect(),
+        }
+    }
+}
+
+impl FromStr for UnicodeForm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "nfc" => Ok(UnicodeForm::Nfc),
+            "nfd" => Ok(UnicodeForm::Nfd),
+            s => Err(format!("Unrecognized Unicode normalization form: {}", s)),
+        }
+    }
+}
+
+/// Format of the report of `--dry-run`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlanFormat {
+    /// Shell commands
+    Shell,
+    /// One line of JSON per action
+    Json,
+}
+
+impl PlanFormat {
+    pub fn variants() -> Vec<&'static str> {
+        vec!["shell", "json"]
+    }
+}
+
+impl Default for PlanFormat {
+    fn default() -> PlanFormat {
+        PlanFormat::Shell
+    }
+}
+
+impl FromStr for PlanFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "shell" => Ok(PlanFormat::Shell),
+            "json" => Ok(PlanFormat::Json),
+            s => Err(format!("Unrecognized plan format: {}", s)),
+        }
+    }
+}
+
+/// Key to break the reported data down by, see `--stats-by`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatsBy {
+    /// The user owning the files
+    Owner,
+}
+
+impl StatsBy {
+    pub fn variants() -> Vec<&'static str> {
+        vec!["owner"]
+    }
+}
+
+impl FromStr for StatsBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "owner" => Ok(StatsBy::Owner),
+            s => Err(format!("Unrecognized statistics key: {}", s)),
+        }
+    }
+}
+
+/// Additional information on each file written to the report, see `--include-metadata`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileMetadataKind {
+    /// The device, mount point and file system type holding the file
+    Device,
+}
+
+impl FileMetadataKind {
+    pub fn variants() -> Vec<&'static str> {
+        vec!["device"]
+    }
+}
+
+impl FromStr for FileMetadataKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "device" => Ok(FileMetadataKind::Device),
+            s => Err(format!("Unrecognized metadata kind: {}", s)),
+        }
+    }
+}
+
+/// What to do with a special file or a symbolic link found by the walk,
+/// see `--special-files` and `--file-links`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecialFilePolicy {
+    /// Skip the file silently, only counting it in the summary
+    Skip,
+    /// Skip the file and log a warning
+    Warn,
+    /// Report a symbolic link to a regular file as a file
+    Follow,
+}
+
+impl SpecialFilePolicy {
+    pub fn variants() -> Vec<&'static str> {
+        vec!["skip", "warn", "follow"]
+    }
+}
+
+impl Default for SpecialFilePolicy {
+    fn default() -> SpecialFilePolicy {
+        SpecialFilePolicy::Skip
+    }
+}
+
+impl FromStr for SpecialFilePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "skip" => Ok(SpecialFilePolicy::Skip),
+            "warn" => Ok(SpecialFilePolicy::Warn),
+            "follow" => Ok(SpecialFilePolicy::Follow),
+            s => Err(format!("Unrecognized policy: {}", s)),
+        }
+    }
+}
+
+/// Named set of file extensions selected with `--preset`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtPreset {
+    Images,
+    Audio,
+    Video,
+    /// Images, audio and video
+    Media,
+    Documents,
+    Archives,
+}
+
+impl ExtPreset {
+    pub fn variants() -> Vec<&'static str> {
+        vec!["images", "audio", "video", "media", "documents", "archives"]
+    }
+
+    /// Returns the extensions of the files in the preset, in lowercase
+    pub fn extensions(&self) -> Vec<&'static str> {
+        const IMAGES: &[&str] = &[
+            "jpg", "jpeg", "png", "gif", "bmp", "tif", "tiff", "webp", "heic", "heif", "avif",
+            "svg", "psd", "raw", "cr2", "cr3", "nef", "arw", "orf", "rw2", "dng", "raf",
+        ];
+        const AUDIO: &[&str] = &[
+            "mp3", "flac", "wav", "aac", "m4a", "ogg", "oga", "opus", "wma", "aiff", "alac", "ape",
+        ];
+        const VIDEO: &[&str] = &[
+            "mp4", "m4v", "mkv", "mov", "avi", "wmv", "flv", "webm", "mpg", "mpeg", "3gp", "mts",
+            "m2ts", "vob",
+        ];
+        const DOCUMENTS: &[&str] = &[
+            "pdf", "doc", "docx", "odt", "rtf", "txt", "md", "xls", "xlsx", "ods", "csv", "ppt",
+            "pptx", "odp", "epub", "mobi", "djvu",
+        ];
+        const ARCHIVES: &[&str] = &[
+            "zip", "rar", "7z", "tar", "gz", "tgz", "bz2", "xz", "zst", "iso", "dmg",
+        ];
+        match self {
+            ExtPreset::Images => IMAGES.to_vec(),
+            ExtPreset::Audio => AUDIO.to_vec(),
+            ExtPreset::Video => VIDEO.to_vec(),
+            ExtPreset::Media => [IMAGES, AUDIO, VIDEO].concat(),
+            ExtPreset::Documents => DOCUMENTS.to_vec(),
+            ExtPreset::Archives => ARCHIVES.to_vec(),
+        }
+    }
+}
+
+impl FromStr for ExtPreset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "images" => Ok(ExtPreset::Images),
+            "audio" => Ok(ExtPreset::Audio),
+            "video" => Ok(ExtPreset::Video),
+            "media" => Ok(ExtPreset::Media),
+            "documents" => Ok(ExtPreset::Documents),
+            "archives" => Ok(ExtPreset::Archives),
+            s => Err(format!("Unrecognized preset: {}", s)),
+        }
+    }
+}
+
+/// What `link` does with a file that can't be hard linked to the kept file,
+/// because it is located on a different file system, see `--cross-device`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CrossDevicePolicy {
+    /// Replace the file with a soft link to the kept file
+    SoftLink,
+    /// Leave the file alone
+    Skip,
+    /// Keep a copy on each file system and hard link the other copies on it to that copy
+    Local,
+    /// Keep a copy on each file system and replace the other copies on it with
+    /// reflinked copies of that copy
+    Reflink,
+}
+
+impl CrossDevicePolicy {
+    pub fn variants() -> Vec<&'static str> {
+        vec!["soft-link", "skip", "local", "reflink"]
+    }
+}
+
+impl Default for CrossDevicePolicy {
+    fn default() -> CrossDevicePolicy {
+        CrossDevicePolicy::SoftLink
+    }
+}
+
+impl FromStr for CrossDevicePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "soft-link" => Ok(CrossDevicePolicy::SoftLink),
+            "skip" => Ok(CrossDevicePolicy::Skip),
+            "local" => Ok(CrossDevicePolicy::Local),
+            "reflink" => Ok(CrossDevicePolicy::Reflink),
+            s => Err(format!("Unrecognized cross-device policy: {}", s)),
+        }
+    }
+}
+
+/// How thoroughly the files are checked for changes made since the report was produced,
+/// before acting on them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Verify {
+    /// Don't check the files
+    None,
+    /// Check the sizes and modification times of the files
+    Stat,
+    /// Check the metadata and compare the hashes of the beginnings of the files
+    Prefix,
+    /// Check the metadata and compare the hashes of the whole files with the reported hash
+    Full,
+}
+
+impl Verify {
+    pub fn variants() -> Vec<&'static str> {
+        vec!["none", "stat", "prefix", "full"]
+    }
+}
+
+impl Default for Verify {
+    fn default() -> Verify {
+        Verify::Stat
+    }
+}
+
+impl Display for Verify {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Verify::None => f.pad("none"),
+            Verify::Stat => f.pad("stat"),
+            Verify::Prefix => f.pad("prefix"),
+            Verify::Full => f.pad("full"),
+        }
+    }
+}
+
+impl FromStr for Verify {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "none" => Ok(Verify::None),
+            "stat" => Ok(Verify::Stat),
+            "prefix" => Ok(Verify::Prefix),
+            "full" => Ok(Verify::Full),
+            s => Err(format!("Unrecognized verification level: {}", s)),
+        }
+    }
+}
+
 /// Parses date time string, accepts wide range of human-readable formats
 fn parse_date_time(s: &str) -> Result<DateTime<FixedOffset>, String> {
     match dtparse::parse(s) {
@@ -71,7 +574,54 @@ fn parse_date_time(s: &str) -> Result<DateTime<FixedOffset>, String> {
             let local_offset = *Local::now().offset();
             Ok(DateTime::from_utc(dt, local_offset))
         }
-        Err(e) => Err(format!("Failed to parse {} as date: {}", s, e.to_string())),
+        Err(e) => Err(format!("Failed to parse {} as date: {}", s, e)),
+    }
+}
+
+/// Parses a duration given as a number with an optional unit: `ms`, `s` (default), `m` or `h`
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let unit_start = s
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(s.len());
+    let (value, unit) = s.split_at(unit_start);
+    let value: f64 = value
+        .parse()
+        .map_err(|e| format!("Invalid duration {}: {}", s, e))?;
+    let secs = match unit.trim() {
+        "ms" => value / 1000.0,
+        "" | "s" => value,
+        "m" => value * 60.0,
+        "h" => value * 3600.0,
+        unit => return Err(format!("Unrecognized duration unit: {}", unit)),
+    };
+    Ok(Duration::from_secs_f64(secs))
+}
+
+/// Decides whether paths differing only in letter case may refer to the same file.
+/// Shared by the `group` and deduplication commands.
+#[derive(Clone, Copy, Debug, Default, StructOpt)]
+pub struct CaseOpts {
+    /// Treats paths differing only in letter case as different files.
+    ///
+    /// This is the default on systems other than Windows and macOS.
+    #[structopt(long, conflicts_with = "case-insensitive")]
+    pub case_sensitive: bool,
+
+    /// Treats paths differing only in letter case as the same file
+    /// if they also have the same file identifier.
+    ///
+    /// This is the default on Windows and macOS. Use it for case-insensitive file systems
+    /// mounted on other systems.
+    #[structopt(long)]
+    pub case_insensitive: bool,
+}
+
+impl CaseOpts {
+    /// Returns true if paths differing only in letter case may refer to the same file.
+    /// If neither flag is set, follows the default file systems of the current platform.
+    pub fn ignore_case(&self) -> bool {
+        !self.case_sensitive && (self.case_insensitive || cfg!(any(windows, target_os = "macos")))
     }
 }
 
@@ -100,13 +650,30 @@ fn parse_thread_count_option(s: &str) -> Result<(OsString, Parallelism), String>
     Ok((key, Parallelism { random, sequential }))
 }
 
+/// Parses string with format: `[<device>:]<size>`
+fn parse_buffer_size_option(s: &str) -> Result<(OsString, FileLen), String> {
+    let (key, value) = if s.contains(':') {
+        let index = s.rfind(':').unwrap();
+        (&s[0..index], &s[(index + 1)..])
+    } else {
+        ("default", s)
+    };
+    let size: FileLen = value
+        .parse()
+        .map_err(|e| format!("Invalid buffer size {}: {}", value, e))?;
+    if size == FileLen(0) {
+        return Err(format!("Buffer size must be positive: {}", value));
+    }
+    Ok((OsString::from(key), size))
+}
+
 fn is_positive_int(v: String) -> Result<(), String> {
     if let Ok(f) = v.parse::<u64>() {
         if f > 0 {
             return Ok(());
         }
     }
-    return Err(format!("Not a positive integer: {}", &*v));
+    Err(format!("Not a positive integer: {}", &*v))
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -127,16 +694,211 @@ pub struct GroupConfig {
     #[structopt(short = "o", long, value_name("path"))]
     pub output: Option<PathBuf>,
 
-    /// Sets output file format
+    /// Sets output file format.
+    ///
+    /// `summary` writes only the report header with the statistics, without the lists of files.
+    /// `text-v2` is a text format that can be parsed reliably: paths are escaped, group lines are
+    /// tagged and carry a checksum of the group, and the report ends with a checksum of all groups.
     #[structopt(short = "f", long, possible_values = &OutputFormat::variants(),
     case_insensitive = true, default_value="default")]
     pub format: OutputFormat,
 
+    /// Writes the report also to a file in another format, given as `<format>:<path>`.
+    ///
+    /// Can be given multiple times. All reports are written in a single pass over the groups,
+    /// e.g. `-o report.json -f json --extra-output summary:summary.txt`.
+    #[structopt(long = "extra-output", value_name("format:path"), number_of_values = 1)]
+    pub extra_outputs: Vec<ExtraOutput>,
+
+    /// Sets the format of the paths in the default and `fdupes` output formats.
+    ///
+    /// `uri` writes paths as `file://` URIs, `shell-quoted` quotes the paths containing
+    /// characters special to the shell. Paths that would be ambiguous in the chosen format,
+    /// e.g. plain paths looking like quoted strings or paths with line breaks,
+    /// are written as URIs. Reports written with any of the path formats
+    /// can be read back by the deduplication commands.
+    #[structopt(long, value_name("format"), possible_values = &PathFormat::variants(),
+    case_insensitive = true, default_value="plain")]
+    pub path_format: PathFormat,
+
+    /// Writes paths in the `json` and `csv` formats in a compact form.
+    ///
+    /// Directories are stored once in a table and files refer to them by index,
+    /// which makes reports with many files in the same directories much smaller.
+    /// Compact JSON reports can be read back by the deduplication commands.
+    /// CSV reports are write-only, so compact CSV reports need to be expanded by the tools
+    /// that read them.
+    #[structopt(long)]
+    pub compact_paths: bool,
+
+    /// Separates the fields of the `csv` format with the given character instead of a comma,
+    /// e.g. `;` expected by spreadsheets in locales using the decimal comma, or `tab`.
+    #[structopt(long, value_name("char"), default_value = ",")]
+    pub csv_delimiter: CsvDelimiter,
+
+    /// Starts the `csv` format with the UTF-8 byte order mark.
+    ///
+    /// Needed by Microsoft Excel to display paths containing non-ASCII characters correctly.
+    #[structopt(long)]
+    pub csv_bom: bool,
+
+    /// Adds the group identifier column to the `csv` format, after the file count.
+    ///
+    /// Not written by default, so the files always start at the fourth column.
+    #[structopt(long)]
+    pub csv_group_id: bool,
+
+    /// Lists at most n files of each group in the report.
+    ///
+    /// Groups with more files, e.g. millions of identical empty build artifacts, are truncated
+    /// to their first n files. The report still counts all files of such groups and marks
+    /// them as truncated, so the statistics stay correct, but the deduplication commands
+    /// see only the listed files. Use `--skip-truncated` to leave such groups alone.
+    /// Not applied to the `fdupes` format, which lists all files.
+    #[structopt(long, value_name = "count")]
+    pub max_group_files: Option<usize>,
+
+    /// Writes paths of files located under the given directory relative to that directory.
+    ///
+    /// Makes the report portable between machines that mount the same file system
+    /// at different locations. Use `--base` to tell the deduplication commands which
+    /// directory the paths are relative to. Paths outside of the directory are written
+    /// as absolute paths.
+    #[structopt(long, value_name("base"), parse(from_os_str))]
+    pub relative_to: Option<PathBuf>,
+
     /// Reads the list of input paths from the standard input instead of the arguments.
     /// This flag is mostly useful together with Unix `find` utility.
     #[structopt(long)]
     pub stdin: bool,
 
+    /// Decides what to do if an input path is contained in another input path.
+    ///
+    /// `merge` scans the nested path only once, as a part of the containing one.
+    /// `error` reports an error and exits.
+    #[structopt(long, value_name("policy"), possible_values = &OverlapPolicy::variants(),
+    case_insensitive = true, default_value="merge")]
+    pub overlapping_roots: OverlapPolicy,
+
+    /// Lists files and directories that could not be accessed in the report header.
+    ///
+    /// Use it to get the information on which parts of the file system were not
+    /// covered by the scan. The list is not included in the `fdupes` and `csv` formats.
+    #[structopt(long)]
+    pub report_errors: bool,
+
+    /// Writes the files and directories that could not be accessed to the given file
+    /// as a JSON report.
+    ///
+    /// For each file, the report lists the operation that failed (`stat`, `read_link`,
+    /// `read_dir`, `read` or `read_xattrs`), the error with its operating system error code,
+    /// and the device holding the file with its mount point, file system and type.
+    /// Useful for finding damaged areas of an aging drive.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    pub unreadable_report: Option<PathBuf>,
+
+    /// Annotates each file in the report with the input path it was found under.
+    ///
+    /// Files of each group are ordered by the input path, so it is easy to see
+    /// which input path holds which copies. Supported only by the default and `json` formats.
+    #[structopt(long)]
+    pub group_by_root: bool,
+
+    /// Sorts the report in a stable order that doesn't depend on thread scheduling.
+    ///
+    /// Groups are sorted by hash, then by file size, then by the paths of their files.
+    /// Files within each group are sorted by path.
+    /// The list of scan errors is sorted alphabetically.
+    /// Useful for comparing reports of different runs.
+    #[structopt(long)]
+    pub deterministic: bool,
+
+    /// Reports the number and total size of the files found under each input path
+    /// in the report header. Not included in the `fdupes` and `csv` formats.
+    #[structopt(long)]
+    pub root_stats: bool,
+
+    /// Reports the number and total size of the found files with each extension
+    /// in the report header, largest first. Not included in the `fdupes` and `csv` formats.
+    #[structopt(long)]
+    pub ext_stats: bool,
+
+    /// Breaks down the reported data by the given key in the report header.
+    ///
+    /// `owner` reports the number and total size of the found files owned by each user,
+    /// largest first, and how much of it are copies owned by the same user.
+    /// Tells users how much of their disk quota is taken by duplicate data.
+    /// Supported on Unix only. Not included in the `fdupes` and `csv` formats.
+    #[structopt(long, value_name = "key", possible_values = &StatsBy::variants())]
+    pub stats_by: Option<StatsBy>,
+
+    /// Annotates each file in the report with additional information.
+    ///
+    /// `device` adds the device, the mount point and the file system type holding the file,
+    /// so tools processing the report can tell which files can be linked together
+    /// without accessing them again. The information comes from the table of mounted
+    /// file systems at the time the report is written.
+    /// Supported only by the `json` and `csv` formats, other formats are rejected.
+    #[structopt(long, value_name = "kind", possible_values = &FileMetadataKind::variants())]
+    pub include_metadata: Vec<FileMetadataKind>,
+
+    /// Reports how much space can be reclaimed in the groups of files of each size range
+    /// and in the groups with each number of files, in the report header.
+    ///
+    /// Tells if the redundant data is in a few huge files or in many small ones.
+    /// Not included in the `fdupes` and `csv` formats.
+    #[structopt(long)]
+    pub histogram: bool,
+
+    /// Describes the machine the scan ran on in the report header.
+    ///
+    /// Includes the host name, the operating system, the file system of each input path
+    /// and the optional features `fclones` was built with, so reports collected from many
+    /// machines can be told apart. Off by default, because it discloses details of the machine.
+    /// Not included in the `fdupes` and `csv` formats.
+    #[structopt(long)]
+    pub header_metadata: bool,
+
+    /// Marks files in each group that already share their data on disk.
+    ///
+    /// Files that are hard links to the same inode or reflinked copies sharing the same
+    /// extents form a link cluster. Files of each group are ordered by cluster and the
+    /// number and size of the files already shared are reported in the header.
+    /// Hard links are reported only together with `--hard-links`.
+    /// Reflinks are detected on Linux only. Supported only by the default and `json` formats.
+    #[structopt(long, conflicts_with_all = &["group-by-root", "stream"])]
+    pub link_clusters: bool,
+
+    /// Computes the amount of space that can be reclaimed from the disk space allocated
+    /// to the files rather than from their logical length.
+    ///
+    /// Sparse and compressed files (e.g. on btrfs or NTFS) often take less disk space
+    /// than their length suggests. Files are still grouped by their logical length.
+    /// The text and JSON reports also show the allocated size of the redundant files
+    /// of each group.
+    /// Supported on Unix only; on other platforms the logical length is used.
+    #[structopt(long)]
+    pub use_allocated_size: bool,
+
+    /// Reuses the hashes from a previous report for files that haven't changed since.
+    ///
+    /// A file is considered unchanged if it has the same size as in the report and it was
+    /// last modified before the scan that produced the report started. Only the new and changed
+    /// files are read. The output is a full report, as if all files were hashed.
+    /// Reports with hashes computed by a different hash function are rejected.
+    /// Cannot be used together with `--transform`.
+    #[structopt(long, value_name("report"), conflicts_with = "transform")]
+    pub baseline: Option<PathBuf>,
+
+    /// Writes the groups as soon as they are found, instead of waiting
+    /// until all files are processed.
+    ///
+    /// The groups are written in no particular order. The statistics of the report are
+    /// written after the last group rather than in the header.
+    /// If the output is closed by its reader, e.g. `head`, grouping stops early.
+    #[structopt(long, conflicts_with_all = &["deterministic", "root-stats", "report-errors", "use-allocated-size"])]
+    pub stream: bool,
+
     /// Limits recursion depth.
     ///
     /// 0 disables descending into directories.
@@ -145,19 +907,80 @@ pub struct GroupConfig {
     #[structopt(short = "d", long)]
     pub depth: Option<usize>,
 
-    /// Skips hidden files
-    #[structopt(short = "A", long)]
+    /// Skips hidden files and directories.
+    ///
+    /// A file is considered hidden if its name starts with a dot.
+    /// On Windows, files with the hidden or system attribute set are considered hidden as well.
+    /// Hidden files are included by default.
+    #[structopt(short = "A", long, overrides_with("include-hidden"))]
     pub skip_hidden: bool,
 
-    /// Follows symbolic links
-    #[structopt(short = "L", long)]
+    /// Includes hidden files and directories (default).
+    ///
+    /// Overrides an earlier `--skip-hidden`.
+    #[structopt(long, overrides_with("skip-hidden"))]
+    pub include_hidden: bool,
+
+    /// Includes trash and cache directories.
+    ///
+    /// By default, directories holding deleted files (`.Trash`, `.Trash-*`, `.Trashes`,
+    /// `$RECYCLE.BIN`, `~/.local/share/Trash`) and caches (`~/.cache`, `~/Library/Caches`)
+    /// are skipped, unless given explicitly as input paths.
+    #[structopt(long)]
+    pub include_trash: bool,
+
+    /// Includes btrfs and ZFS snapshots.
+    ///
+    /// By default, btrfs subvolumes created as snapshots and `.zfs/snapshot` directories
+    /// are skipped, unless given explicitly as input paths. Their files share data with
+    /// the live files, so removing them doesn't reclaim any space.
+    #[structopt(long)]
+    pub include_snapshots: bool,
+
+    /// Follows symbolic links.
+    ///
+    /// Links to files and directories are treated as their targets.
+    /// Each physical directory is scanned at most once, so link cycles are safe.
+    #[structopt(
+        short = "L",
+        long,
+        overrides_with_all(&["no-follow-links", "report-symlinks"])
+    )]
     pub follow_links: bool,
 
+    /// Ignores symbolic links (default).
+    #[structopt(long, overrides_with_all(&["follow-links", "report-symlinks"]))]
+    pub no_follow_links: bool,
+
+    /// Reports symbolic links to regular files as files, but doesn't descend into
+    /// symbolic links to directories.
+    #[structopt(long, overrides_with_all(&["follow-links", "no-follow-links"]))]
+    pub report_symlinks: bool,
+
+    /// Sets what to do with symbolic links to regular files when links are not followed.
+    ///
+    /// `skip` ignores them, `warn` ignores them with a warning and `follow` reports them
+    /// as files, like `--report-symlinks`. Skipped links are counted in the summary.
+    #[structopt(long, value_name = "policy", possible_values = &SpecialFilePolicy::variants(),
+    default_value = "skip")]
+    pub file_links: SpecialFilePolicy,
+
+    /// Sets what to do with FIFOs, sockets and device nodes.
+    ///
+    /// They are never read. `skip` ignores them and `warn` ignores them with a warning.
+    /// Skipped special files are counted in the summary.
+    #[structopt(long, value_name = "policy", possible_values = &["skip", "warn"],
+    default_value = "skip")]
+    pub special_files: SpecialFilePolicy,
+
     /// Treats files reachable from multiple paths through
     /// hard links as duplicates
     #[structopt(short = "H", long)]
     pub hard_links: bool,
 
+    #[structopt(flatten)]
+    pub case: CaseOpts,
+
     /// Before matching, transforms each file by the specified program.
     /// The value of this parameter should contain a command: the path to the program
     /// and optionally a list of space-separated arguments.
@@ -196,18 +1019,83 @@ pub struct GroupConfig {
     /// Searches for over-replicated files with replication factor above the specified value.
     /// Specifying neither `--rf-over` nor `--rf-under` is equivalent to `--rf-over 1` which would
     /// report duplicate files.
-    #[structopt(short("n"), long, conflicts_with("rf-under"), value_name("count"))]
+    #[structopt(short("n"), long, value_name("count"))]
     pub rf_over: Option<usize>,
 
     /// Searches for under-replicated files with replication factor below the specified value.
-    /// Specifying `--rf-under 2` will report unique files.
-    #[structopt(long, conflicts_with("rf-over"), value_name("count"))]
+    ///
+    /// Specifying `--rf-under 2` will report unique files, i.e. files with no other copy
+    /// in any of the input paths. Can be combined with `--rf-over` to report the files
+    /// with a number of copies in the given range, e.g. `--rf-over 1 --rf-under 3`
+    /// reports the files with exactly 2 copies.
+    #[structopt(long, value_name("count"))]
     pub rf_under: Option<usize>,
 
     /// Instead of searching for duplicates, searches for unique files.
     #[structopt(long, conflicts_with_all(&["rf-over", "rf-under"]))]
     pub unique: bool,
 
+    /// Reports only the files duplicated across the two given input paths.
+    ///
+    /// Requires exactly two input paths, none contained in the other.
+    /// A group is reported only if it has at least one file under each of the paths.
+    /// Files duplicated only within one of the paths are ignored.
+    /// Use it to check if everything from one directory is already in another one.
+    #[structopt(long, conflicts_with_all(&["stdin", "unique", "rf-under"]))]
+    pub compare: bool,
+
+    /// Together with `--compare`, reports the files under the first input path
+    /// that have no copy with the same contents under the second input path.
+    ///
+    /// Files are grouped by contents, so each group lists identical files
+    /// that are all missing from the second path.
+    /// Use it to check if a backup is complete.
+    #[structopt(long, requires("compare"), conflicts_with("rf-over"))]
+    pub missing: bool,
+
+    /// Treats each input path as a separate namespace and reports only the files
+    /// duplicated within the same input path, never across input paths.
+    ///
+    /// Identical files under different input paths are never put in the same group.
+    /// The groups of the report are ordered by the input path holding their files
+    /// and annotated with it, as with `--group-by-root`.
+    /// Use it to scan the directories of several users or clients in one run.
+    #[structopt(long, conflicts_with_all(&["stdin", "compare"]))]
+    pub isolate: bool,
+
+    /// Groups files only if their extended attributes are the same, too.
+    ///
+    /// Use it for files carrying security labels, e.g. SELinux contexts, or custom tags,
+    /// which would be lost by replacing a file with a link to its copy.
+    /// On Linux, this includes access control lists.
+    /// Files with the same contents, but different attributes, are put in separate groups.
+    /// Supported on Linux and macOS only.
+    #[structopt(long)]
+    pub match_xattrs: bool,
+
+    /// Groups files only if their POSIX access control lists are the same, too.
+    ///
+    /// Files with the same contents, but different access control lists, are put
+    /// in separate groups. Supported on Linux only.
+    #[structopt(long)]
+    pub match_acl: bool,
+
+    /// Reports only the groups of files with the given hash, written in hexadecimal digits
+    /// as in the group headers of the report.
+    ///
+    /// Hashes computed with any of the functions given by `--hash-fn` are matched.
+    /// Can be given multiple times.
+    #[structopt(long, value_name("hex"), parse(try_from_str = FileHash::from_hex))]
+    pub with_hash: Vec<FileHash>,
+
+    /// Reports only the groups containing the given file, i.e. the other copies of the file.
+    ///
+    /// The file must be under one of the input paths.
+    /// Files of a different size are not hashed, so this is much faster than a full search.
+    /// Can be given multiple times.
+    #[structopt(long, value_name("path"), parse(from_os_str))]
+    pub containing: Vec<PathBuf>,
+
     /// Minimum file size in bytes. Units like KB, KiB, MB, MiB, GB, GiB are supported. Inclusive.
     #[structopt(short = "s", long("min"), default_value = "1", value_name("bytes"))]
     pub min_size: FileLen,
@@ -216,6 +1104,57 @@ pub struct GroupConfig {
     #[structopt(long("max"), value_name("bytes"))]
     pub max_size: Option<FileLen>,
 
+    /// Selects only files with sizes in the given range, e.g. `1M..2G`. Inclusive.
+    ///
+    /// Either bound can be omitted, e.g. `..100K` or `1G..`, and a single size, e.g. `4K`,
+    /// selects files of exactly that size. Can be given multiple times to select files
+    /// in any of the ranges, e.g. `--size ..1M --size 1G..`. Files must also satisfy `--min` and `--max`.
+    #[structopt(long("size"), value_name("range"))]
+    pub size_ranges: Vec<SizeRange>,
+
+    /// Selects only files taking the amount of disk space in the given range, e.g. `..1M`.
+    ///
+    /// Like `--size`, but compares the space allocated to the files, which is smaller than
+    /// the length for sparse and compressed files, and larger for files not filling their last block.
+    /// Can be given multiple times. Supported on Unix only; on other platforms the length is used.
+    #[structopt(long("allocated-size"), value_name("range"))]
+    pub allocated_size_ranges: Vec<SizeRange>,
+
+    /// Skips empty files, even if `--min` allows them.
+    #[structopt(long, conflicts_with("empty-as-group"))]
+    pub ignore_empty: bool,
+
+    /// Reports all empty files as a single group, without opening them.
+    ///
+    /// Empty files are identical by definition, so they skip the hashing stages.
+    /// Includes empty files regardless of `--min`.
+    #[structopt(long)]
+    pub empty_as_group: bool,
+
+    /// Includes only files modified after the given time.
+    ///
+    /// Accepts a wide range of human-readable date and time formats.
+    #[structopt(long, value_name("timestamp"), parse(try_from_str = parse_date_time))]
+    pub modified_after: Option<DateTime<FixedOffset>>,
+
+    /// Includes only files modified before the given time.
+    ///
+    /// Accepts a wide range of human-readable date and time formats.
+    #[structopt(long, value_name("timestamp"), parse(try_from_str = parse_date_time))]
+    pub modified_before: Option<DateTime<FixedOffset>>,
+
+    /// Includes only files created after the given time.
+    ///
+    /// Files on file systems that don't record the creation time are skipped.
+    #[structopt(long, value_name("timestamp"), parse(try_from_str = parse_date_time))]
+    pub created_after: Option<DateTime<FixedOffset>>,
+
+    /// Includes only files created before the given time.
+    ///
+    /// Files on file systems that don't record the creation time are skipped.
+    #[structopt(long, value_name("timestamp"), parse(try_from_str = parse_date_time))]
+    pub created_before: Option<DateTime<FixedOffset>>,
+
     /// Includes only file names matched fully by any of the given patterns.
     #[structopt(long = "name", value_name("pattern"))]
     pub name_patterns: Vec<String>,
@@ -228,6 +1167,51 @@ pub struct GroupConfig {
     #[structopt(long = "exclude", value_name("pattern"))]
     pub exclude_patterns: Vec<String>,
 
+    /// Doesn't descend into directories with names matched fully by any of the given patterns,
+    /// e.g. `node_modules` or `.git`.
+    ///
+    /// Unlike `--exclude`, the excluded directories are not read at all,
+    /// which makes scanning trees containing huge directories of no interest much faster.
+    /// Directories given as input paths are scanned even if they match.
+    #[structopt(long = "exclude-dir", value_name("pattern"))]
+    pub exclude_dir_patterns: Vec<String>,
+
+    /// Reads the patterns always excluded from scans from the given file.
+    ///
+    /// By default they are read from `~/.config/fclones/ignore`, if it exists.
+    /// Each line holds a pattern as given to `--exclude`, or a pattern ending with `/`
+    /// as given to `--exclude-dir`. Lines starting with `#` are ignored.
+    /// The patterns are added to the ones given on the command line.
+    #[structopt(long, value_name("path"), parse(from_os_str))]
+    pub ignore_file: Option<PathBuf>,
+
+    /// Doesn't read the patterns of the ignore file, see `--ignore-file`
+    #[structopt(long, conflicts_with = "ignore-file")]
+    pub no_ignore_file: bool,
+
+    /// Includes only files with any of the given extensions, e.g. `jpg,png,cr2`.
+    ///
+    /// Extensions are matched case-insensitively and may contain dots, e.g. `tar.gz`.
+    /// Can be given multiple times and combined with `--preset`.
+    #[structopt(long = "ext", value_name("extensions"), use_delimiter = true)]
+    pub extensions: Vec<String>,
+
+    /// Excludes files with any of the given extensions, e.g. `tmp,part`.
+    ///
+    /// Extensions are matched case-insensitively. Takes precedence over `--ext` and `--preset`.
+    #[structopt(long = "no-ext", value_name("extensions"), use_delimiter = true)]
+    pub excluded_extensions: Vec<String>,
+
+    /// Includes only files with the extensions of the given set.
+    ///
+    /// `images`, `audio`, `video` and `documents` select the common formats of the kind,
+    /// including raw photos, `media` selects images, audio and video together,
+    /// `archives` selects compressed archives and disk images.
+    /// Can be given multiple times and combined with `--ext`.
+    #[structopt(long = "preset", value_name("name"), possible_values = &ExtPreset::variants(),
+    case_insensitive = true, use_delimiter = true)]
+    pub presets: Vec<ExtPreset>,
+
     /// Makes pattern matching case-insensitive
     #[structopt(short = "i", long)]
     pub caseless: bool,
@@ -236,6 +1220,15 @@ pub struct GroupConfig {
     #[structopt(short = "g", long)]
     pub regex: bool,
 
+    /// Converts paths to the given Unicode normalization form before matching them
+    /// against patterns or looking them up in the `--baseline` report.
+    ///
+    /// Lets the same patterns match names created on macOS, which are usually decomposed (NFD),
+    /// and names created on Linux or Windows, which are usually composed (NFC).
+    /// Affects only the matching, the reported paths are not changed.
+    #[structopt(long, value_name = "form", possible_values = &UnicodeForm::variants())]
+    pub normalize_unicode: Option<UnicodeForm>,
+
     /// Sets the sizes of thread-pools
     ///
     /// The spec has the following format: `[<name>:]<r>[,<s>]`.
@@ -259,6 +1252,118 @@ pub struct GroupConfig {
       verbatim_doc_comment)]
     pub threads: Vec<(OsString, Parallelism)>,
 
+    /// Computes the hashes of the contents of the files with the given hash functions
+    /// and records them in the report.
+    ///
+    /// The fast, 128-bit `metro` hash is always used for grouping the files.
+    /// The other hashes, e.g. `sha256`, are computed in the same pass over the data,
+    /// in the last stage of grouping. They can be used to verify the files later or to look
+    /// them up in external integrity databases. Files with different hashes are never
+    /// put in the same group. Can't be used with `--transform`.
+    #[structopt(
+        long,
+        value_name = "name",
+        use_delimiter = true,
+        possible_values = &HashFn::variants())]
+    pub hash_fn: Vec<HashFn>,
+
+    /// Selects the implementation of the hash functions given with `--hash-fn`.
+    ///
+    /// `builtin` uses the SHA extensions of x86 CPUs if available.
+    /// `command:<program>` runs the program for each file, e.g. to offload hashing
+    /// to an accelerator. The program gets the name of the hash function as the last argument
+    /// and the contents of the file on the standard input, and must print the digest
+    /// in hexadecimal as the first word of its output.
+    /// Use `fclones bench` to compare the backends.
+    #[structopt(long, value_name = "backend", default_value = "builtin")]
+    pub hash_backend: HashBackend,
+
+    /// Sets the number of threads computing hashes.
+    ///
+    /// Files are read by the threads configured with `--threads`, but hashing is done
+    /// on a separate pool of threads, so reading the next part of a file can overlap
+    /// with hashing the previous one. Set to 0 to compute hashes on the threads reading
+    /// the files.
+    ///
+    /// Defaults to the number of CPU cores.
+    #[structopt(long, value_name = "count")]
+    pub hash_threads: Option<usize>,
+
+    /// Limits the number of files open at the same time.
+    ///
+    /// Threads wait for other threads to close their files if the limit is reached,
+    /// instead of failing with "Too many open files".
+    /// Defaults to the limit of open file descriptors set by the operating system
+    /// (`ulimit -n`), reduced by the descriptors needed for other purposes.
+    #[structopt(long, value_name = "count")]
+    pub max_open_files: Option<usize>,
+
+    /// Reads the files only in the given time window of each day, e.g. `22:00-06:00`.
+    ///
+    /// Outside of the window, scanning directories and hashing files is paused and continues
+    /// where it stopped when the window opens again, so a long scan can span many nights.
+    /// The times are in the local time zone. The window spans midnight if it ends before
+    /// it starts.
+    #[structopt(long, value_name = "start-end")]
+    pub run_window: Option<RunWindow>,
+
+    /// Gives up reading a file or a directory if reading its metadata, listing it,
+    /// opening it or a single read from it takes longer than the given time, e.g. `30s`.
+    ///
+    /// Protects the scan from files on dying disks or hung network file systems,
+    /// which could otherwise block it forever. The abandoned files are reported
+    /// as unreadable and the scan goes on. Disables `--mmap`, because reads
+    /// from memory-mapped files can't be abandoned.
+    #[structopt(long, value_name = "duration", parse(try_from_str = parse_duration))]
+    pub io_timeout: Option<Duration>,
+
+    /// Records the files abandoned because of `--io-timeout` in the given file,
+    /// one path per line, and skips the files already listed there.
+    ///
+    /// Once a file stalls, reading it again in the next scans would likely stall as well,
+    /// so it is kept out of them until removed from the list.
+    #[structopt(long, value_name = "path", parse(from_os_str), requires = "io-timeout")]
+    pub quarantine: Option<PathBuf>,
+
+    /// Sets the sizes of buffers used for reading file contents
+    ///
+    /// The spec has the following format: `[<name>:]<size>`.
+    /// The name can be one of:
+    /// (1) a physical block device when prefixed with `dev:` e.g. `dev:/dev/sda`;
+    /// (2) a type of device - `ssd`, `hdd`, `removable` or `unknown`;
+    /// (3) `network` - applies to network file systems like NFS or SMB;
+    /// (4) `default` - applies to all devices not matched by other specs.
+    /// If the name is not given, `default` is assumed.
+    ///
+    /// Defaults: 64 KB for SSDs, 1 MB for network file systems and 256 KB for others.
+    ///
+    /// This parameter can be used multiple times to configure multiple devices.
+    #[structopt(
+      long = "buffer-size",
+      value_name = "spec",
+      parse(try_from_str = parse_buffer_size_option),
+      verbatim_doc_comment)]
+    pub buffer_sizes: Vec<(OsString, FileLen)>,
+
+    /// Reads files larger than the given size by mapping them into memory
+    /// instead of using buffered reads.
+    ///
+    /// This can be faster for very large files on some systems.
+    /// If the size is not given, it defaults to 64 MB.
+    /// If a file can't be mapped, it is read the regular way.
+    #[structopt(long, value_name = "bytes", require_equals(true))]
+    pub mmap: Option<Option<FileLen>>,
+
+    /// Groups files by a fingerprint instead of the prefix.
+    ///
+    /// The fingerprint is computed from the size and up to 4 KB at the beginning and
+    /// at the end of each file longer than the prefix, in a single read per file.
+    /// Files differing only at the end are discarded before hashing their suffixes, which saves
+    /// memory and hashing work if the vast majority of the files are unique.
+    /// Ignored with `--transform`.
+    #[structopt(long)]
+    pub fingerprint_filter: bool,
+
     /// A list of input paths.
     ///
     /// Accepts files and directories.
@@ -266,15 +1371,55 @@ pub struct GroupConfig {
     /// limit is specified with `--depth`.
     #[structopt(parse(from_os_str), required_unless("stdin"))]
     pub paths: Vec<PathBuf>,
+
+    /// Stops grouping as soon as possible when set to true by another thread.
+    /// Grouping then fails with a "Cancelled" error.
+    #[structopt(skip)]
+    pub cancel: Option<Arc<AtomicBool>>,
+
+    /// Receives the number of bytes hashed and to be hashed in the current stage of grouping,
+    /// for estimating the time left in another thread.
+    #[structopt(skip)]
+    pub progress: Option<Arc<StageProgress>>,
 }
 
 impl GroupConfig {
+    /// Returns a builder initialized with the defaults of the `group` command
+    pub fn builder() -> GroupConfigBuilder {
+        GroupConfigBuilder {
+            config: GroupConfig {
+                min_size: FileLen(1),
+                ..GroupConfig::default()
+            },
+        }
+    }
+
+    /// Returns the hash functions selected with `--hash-fn` other than the one used for grouping
+    pub fn extra_hash_fns(&self) -> Vec<HashFn> {
+        self.hash_fn
+            .iter()
+            .copied()
+            .filter(|&f| f != HashFn::Metro)
+            .unique()
+            .collect()
+    }
+
+    /// Returns the hash functions to compute in addition to the one used for grouping,
+    /// together with the backend set with `--hash-backend`
+    pub fn extra_hashes(&self) -> ExtraHashes {
+        ExtraHashes {
+            fns: self.extra_hash_fns(),
+            backend: self.hash_backend.clone(),
+        }
+    }
+
     fn compile_pattern(&self, s: &str) -> Result<Pattern, PatternError> {
         let pattern_opts = if self.caseless {
             PatternOpts::case_insensitive()
         } else {
             PatternOpts::default()
         };
+        let pattern_opts = pattern_opts.normalized(self.normalize_unicode);
         if self.regex {
             Pattern::regex_with(s, &pattern_opts)
         } else {
@@ -282,7 +1427,13 @@ impl GroupConfig {
         }
     }
 
-    pub fn path_selector(&self, base_dir: &Path) -> Result<PathSelector, PatternError> {
+    /// Returns the selector of the scanned paths.
+    /// The patterns of the ignore file are excluded in addition to `--exclude` and `--exclude-dir`.
+    pub fn path_selector(
+        &self,
+        base_dir: &Path,
+        ignored: &IgnoreFile,
+    ) -> Result<PathSelector, PatternError> {
         let include_names: Result<Vec<Pattern>, PatternError> = self
             .name_patterns
             .iter()
@@ -296,23 +1447,74 @@ impl GroupConfig {
         let exclude_paths: Result<Vec<Pattern>, PatternError> = self
             .exclude_patterns
             .iter()
+            .chain(ignored.exclude_patterns.iter())
+            .map(|p| self.compile_pattern(p))
+            .collect();
+
+        let exclude_dir_names: Result<Vec<Pattern>, PatternError> = self
+            .exclude_dir_patterns
+            .iter()
+            .chain(ignored.exclude_dir_patterns.iter())
             .map(|p| self.compile_pattern(p))
             .collect();
+        let include_extensions = self
+            .extensions
+            .iter()
+            .map(|e| e.as_str())
+            .chain(self.presets.iter().flat_map(|p| p.extensions()))
+            .map(|e| e.to_owned())
+            .collect();
 
         Ok(PathSelector::new(base_dir.clone())
             .include_names(include_names?)
             .include_paths(include_paths?)
-            .exclude_paths(exclude_paths?))
+            .exclude_paths(exclude_paths?)
+            .exclude_dir_names(exclude_dir_names?)
+            .include_extensions(include_extensions)
+            .exclude_extensions(self.excluded_extensions.clone()))
+    }
+
+    /// Returns the minimum size of files to be read through memory mapping
+    /// or `None` if memory mapping is disabled, also implicitly by `--io-timeout`
+    pub fn mmap_threshold(&self) -> Option<FileLen> {
+        self.mmap
+            .filter(|_| self.io_timeout.is_none())
+            .map(|threshold| threshold.unwrap_or(FileLen(64 * 1024 * 1024)))
+    }
+
+    /// Returns true if any of the modification or creation time filters is set
+    pub fn has_time_filters(&self) -> bool {
+        self.modified_after.is_some()
+            || self.modified_before.is_some()
+            || self.created_after.is_some()
+            || self.created_before.is_some()
+    }
+
+    /// Returns true if paths differing only in letter case may refer to the same file
+    pub fn ignore_case(&self) -> bool {
+        self.case.ignore_case()
+    }
+
+    /// Returns the files the report should be written to, each with its format.
+    /// The standard output is returned as `None`.
+    pub fn outputs(&self) -> Vec<(Option<&PathBuf>, OutputFormat)> {
+        let extra = self.extra_outputs.iter().map(|o| (Some(&o.path), o.format));
+        iter::once((self.output.as_ref(), self.format))
+            .chain(extra)
+            .collect()
     }
 
     pub fn rf_over(&self) -> usize {
         // don't prune small groups if:
         // - there is transformation defined
         //   (distinct files can become identical after the transform)
-        // - or we're looking for under-replicated files
+        // - or we're looking for under-replicated files with no lower bound of copies
         // - or we're looking for unique files
-        if self.transform.is_some() || self.rf_under.is_some() || self.unique {
+        // - or we're looking for files missing from one of the roots
+        if self.transform.is_some() || self.unique || self.missing {
             0
+        } else if self.rf_under.is_some() {
+            self.rf_over.unwrap_or(0)
         } else {
             self.rf_over.unwrap_or(1)
         }
@@ -331,6 +1533,8 @@ impl GroupConfig {
             "unique"
         } else if self.rf_under.is_some() {
             "under-replicated"
+        } else if self.missing {
+            "missing"
         } else {
             "redundant"
         }
@@ -364,20 +1568,314 @@ impl GroupConfig {
         Ok(tr)
     }
 
-    /// Constructs the transform object.
-    /// Returns None if the transform was not set
-    pub fn transform(&self) -> Option<io::Result<Transform>> {
-        self.transform
-            .as_ref()
-            .map(|command| self.build_transform(command))
+    /// Constructs the transform object.
+    /// Returns None if the transform was not set
+    pub fn transform(&self) -> Option<io::Result<Transform>> {
+        self.transform
+            .as_ref()
+            .map(|command| self.build_transform(command))
+    }
+
+    pub fn buffer_sizes(&self) -> HashMap<OsString, FileLen> {
+        self.buffer_sizes.iter().cloned().collect()
+    }
+
+    pub fn thread_pool_sizes(&self) -> HashMap<OsString, Parallelism> {
+        let mut map = HashMap::new();
+        for (k, v) in self.threads.iter() {
+            map.insert(k.clone(), *v);
+        }
+        map
+    }
+}
+
+/// Builds a [`GroupConfig`] programmatically, without going through the command line parser.
+///
+/// Starts from the same defaults as the `group` command, e.g. the minimum file size is 1 byte.
+/// Options that have no setter can be changed on the built config directly.
+///
+/// # Example
+/// ```
+/// use fclones::config::GroupConfig;
+/// use fclones::files::{FileLen, HashFn};
+///
+/// let config = GroupConfig::builder()
+///     .paths(vec!["/path/to/a/dir", "/path/to/another/dir"])
+///     .min_size(FileLen(1024))
+///     .hash_fn(vec![HashFn::Sha256])
+///     .skip_hidden(true)
+///     .build();
+/// assert_eq!(config.paths.len(), 2);
+/// ```
+#[derive(Debug)]
+pub struct GroupConfigBuilder {
+    config: GroupConfig,
+}
+
+impl GroupConfigBuilder {
+    /// Sets the input paths, replacing the ones set earlier
+    pub fn paths<I, P>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<PathBuf>,
+    {
+        self.config.paths = paths.into_iter().map(|p| p.into()).collect();
+        self
+    }
+
+    /// Adds an input path
+    pub fn path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.paths.push(path.into());
+        self
+    }
+
+    /// Limits recursion depth, see `--depth`
+    pub fn depth(mut self, depth: usize) -> Self {
+        self.config.depth = Some(depth);
+        self
+    }
+
+    /// Skips hidden files and directories, see `--skip-hidden`
+    pub fn skip_hidden(mut self, skip: bool) -> Self {
+        self.config.skip_hidden = skip;
+        self
+    }
+
+    /// Includes trash and cache directories, see `--include-trash`
+    pub fn include_trash(mut self, include: bool) -> Self {
+        self.config.include_trash = include;
+        self
+    }
+
+    /// Includes btrfs and ZFS snapshots, see `--include-snapshots`
+    pub fn include_snapshots(mut self, include: bool) -> Self {
+        self.config.include_snapshots = include;
+        self
+    }
+
+    /// Follows symbolic links, see `--follow-links`
+    pub fn follow_links(mut self, follow: bool) -> Self {
+        self.config.follow_links = follow;
+        self
+    }
+
+    /// Treats hard links to the same file as duplicates, see `--hard-links`
+    pub fn hard_links(mut self, hard_links: bool) -> Self {
+        self.config.hard_links = hard_links;
+        self
+    }
+
+    /// Sets the minimum file size, inclusive
+    pub fn min_size(mut self, size: FileLen) -> Self {
+        self.config.min_size = size;
+        self
+    }
+
+    /// Sets the maximum file size, inclusive
+    pub fn max_size(mut self, size: FileLen) -> Self {
+        self.config.max_size = Some(size);
+        self
+    }
+
+    /// Adds a range of file sizes to select, inclusive.
+    /// If any ranges are added, only the files in any of them are selected.
+    pub fn size_range(mut self, range: SizeRange) -> Self {
+        self.config.size_ranges.push(range);
+        self
+    }
+
+    /// Adds a range of allocated file sizes to select, inclusive.
+    /// If any ranges are added, only the files taking the disk space in any of them are selected.
+    pub fn allocated_size_range(mut self, range: SizeRange) -> Self {
+        self.config.allocated_size_ranges.push(range);
+        self
+    }
+
+    /// Includes only files modified after the given time
+    pub fn modified_after(mut self, time: DateTime<FixedOffset>) -> Self {
+        self.config.modified_after = Some(time);
+        self
+    }
+
+    /// Includes only files modified before the given time
+    pub fn modified_before(mut self, time: DateTime<FixedOffset>) -> Self {
+        self.config.modified_before = Some(time);
+        self
+    }
+
+    /// Adds a pattern the file names must match, see `--name`
+    pub fn name_pattern(mut self, pattern: impl Into<String>) -> Self {
+        self.config.name_patterns.push(pattern.into());
+        self
+    }
+
+    /// Adds a pattern the paths must match, see `--path`
+    pub fn path_pattern(mut self, pattern: impl Into<String>) -> Self {
+        self.config.path_patterns.push(pattern.into());
+        self
+    }
+
+    /// Adds a pattern of the paths to exclude, see `--exclude`
+    pub fn exclude_pattern(mut self, pattern: impl Into<String>) -> Self {
+        self.config.exclude_patterns.push(pattern.into());
+        self
+    }
+
+    /// Adds a pattern of the names of directories to skip, see `--exclude-dir`
+    pub fn exclude_dir_pattern(mut self, pattern: impl Into<String>) -> Self {
+        self.config.exclude_dir_patterns.push(pattern.into());
+        self
+    }
+
+    /// Adds an extension of the files to include, see `--ext`
+    pub fn extension(mut self, extension: impl Into<String>) -> Self {
+        self.config.extensions.push(extension.into());
+        self
+    }
+
+    /// Adds an extension of the files to exclude, see `--no-ext`
+    pub fn excluded_extension(mut self, extension: impl Into<String>) -> Self {
+        self.config.excluded_extensions.push(extension.into());
+        self
+    }
+
+    /// Adds a set of extensions of the files to include, see `--preset`
+    pub fn preset(mut self, preset: ExtPreset) -> Self {
+        self.config.presets.push(preset);
+        self
+    }
+
+    /// Makes pattern matching case-insensitive
+    pub fn caseless(mut self, caseless: bool) -> Self {
+        self.config.caseless = caseless;
+        self
+    }
+
+    /// Interprets the patterns as regular expressions instead of globs
+    pub fn regex(mut self, regex: bool) -> Self {
+        self.config.regex = regex;
+        self
+    }
+
+    /// Searches for files with more than `count` copies, see `--rf-over`.
+    /// Can be combined with [`rf_under`](GroupConfigBuilder::rf_under).
+    pub fn rf_over(mut self, count: usize) -> Self {
+        self.config.rf_over = Some(count);
+        self
+    }
+
+    /// Searches for files with fewer than `count` copies, see `--rf-under`.
+    /// Can be combined with [`rf_over`](GroupConfigBuilder::rf_over).
+    pub fn rf_under(mut self, count: usize) -> Self {
+        self.config.rf_under = Some(count);
+        self
+    }
+
+    /// Groups files by a fingerprint of their beginning and end instead of the prefix,
+    /// see `--fingerprint-filter`
+    pub fn fingerprint_filter(mut self, enabled: bool) -> Self {
+        self.config.fingerprint_filter = enabled;
+        self
+    }
+
+    /// Computes the extra hashes with the given backend, see `--hash-backend`
+    pub fn hash_backend(mut self, backend: HashBackend) -> Self {
+        self.config.hash_backend = backend;
+        self
+    }
+
+    /// Gives up reading files that stall for longer than `timeout`, see `--io-timeout`
+    pub fn io_timeout(mut self, timeout: Duration) -> Self {
+        self.config.io_timeout = Some(timeout);
+        self
+    }
+
+    /// Searches for unique files instead of duplicates
+    pub fn unique(mut self, unique: bool) -> Self {
+        self.config.unique = unique;
+        self
+    }
+
+    /// Sets the hash functions whose hashes are recorded in the report, see `--hash-fn`
+    pub fn hash_fn(mut self, hash_fns: impl IntoIterator<Item = HashFn>) -> Self {
+        self.config.hash_fn = hash_fns.into_iter().collect();
+        self
+    }
+
+    /// Transforms the files by the given command before hashing, see `--transform`
+    pub fn transform(mut self, command: impl Into<String>) -> Self {
+        self.config.transform = Some(command.into());
+        self
+    }
+
+    /// Sets the sizes of the thread pools for random and sequential access of the given
+    /// pool, device or device type, see `--threads`
+    pub fn threads(mut self, name: impl Into<OsString>, random: usize, sequential: usize) -> Self {
+        let parallelism = Parallelism { random, sequential };
+        self.config.threads.push((name.into(), parallelism));
+        self
+    }
+
+    /// Sets the number of threads computing hashes, see `--hash-threads`
+    pub fn hash_threads(mut self, count: usize) -> Self {
+        self.config.hash_threads = Some(count);
+        self
+    }
+
+    /// Limits the number of files open at the same time, see `--max-open-files`
+    pub fn max_open_files(mut self, count: usize) -> Self {
+        self.config.max_open_files = Some(count);
+        self
+    }
+
+    /// Reuses the hashes of unchanged files from the given report, see `--baseline`
+    pub fn baseline(mut self, report: impl Into<PathBuf>) -> Self {
+        self.config.baseline = Some(report.into());
+        self
+    }
+
+    /// Sorts the groups and files in a stable order
+    pub fn deterministic(mut self, deterministic: bool) -> Self {
+        self.config.deterministic = deterministic;
+        self
+    }
+
+    /// Sets the format of the report written by [`write_report`](crate::write_report)
+    pub fn format(mut self, format: OutputFormat) -> Self {
+        self.config.format = format;
+        self
+    }
+
+    /// Writes the report to a file instead of the standard output
+    pub fn output(mut self, path: impl Into<PathBuf>) -> Self {
+        self.config.output = Some(path.into());
+        self
+    }
+
+    /// Writes the report also to the given file in the given format, see `--extra-output`
+    pub fn extra_output(mut self, format: OutputFormat, path: impl Into<PathBuf>) -> Self {
+        self.config.extra_outputs.push(ExtraOutput {
+            format,
+            path: path.into(),
+        });
+        self
+    }
+
+    /// Sets the flag that cancels grouping when set to true
+    pub fn cancel(mut self, flag: Arc<AtomicBool>) -> Self {
+        self.config.cancel = Some(flag);
+        self
     }
 
-    pub fn thread_pool_sizes(&self) -> HashMap<OsString, Parallelism> {
-        let mut map = HashMap::new();
-        for (k, v) in self.threads.iter() {
-            map.insert(k.clone(), *v);
-        }
-        map
+    /// Sets the object receiving the progress of hashing
+    pub fn progress(mut self, progress: Arc<StageProgress>) -> Self {
+        self.config.progress = Some(progress);
+        self
+    }
+
+    /// Returns the configuration to pass to [`group_files`](crate::group_files)
+    pub fn build(self) -> GroupConfig {
+        self.config
     }
 }
 
@@ -385,12 +1883,25 @@ impl GroupConfig {
 pub enum Priority {
     Newest,
     Oldest,
+    /// Like `Newest`, but falls back to the modification time if the file system
+    /// doesn't record the birth time of files
+    NewestCreated,
+    /// Like `Oldest`, but falls back to the modification time if the file system
+    /// doesn't record the birth time of files
+    OldestCreated,
     MostRecentlyModified,
     LeastRecentlyModified,
     MostRecentlyAccessed,
     LeastRecentlyAccessed,
     MostNested,
     LeastNested,
+    /// Drops the copies on the fullest file systems first,
+    /// keeping the ones on the file systems with the most free space
+    FreeSpace,
+    ShortestPath,
+    LongestPath,
+    /// Keeps the files with paths matching the patterns given by `--prefer-path`
+    PreferredPath,
 }
 
 impl Priority {
@@ -398,12 +1909,18 @@ impl Priority {
         vec![
             "newest",
             "oldest",
+            "newest-created",
+            "oldest-created",
             "most-recently-modified",
             "least-recently-modified",
             "most-recently-accessed",
             "least-recently-accessed",
             "most-nested",
             "least-nested",
+            "free-space",
+            "shortest-path",
+            "longest-path",
+            "preferred-path",
         ]
     }
 }
@@ -414,17 +1931,202 @@ impl FromStr for Priority {
         match s.to_lowercase().as_str() {
             "newest" => Ok(Priority::Newest),
             "oldest" => Ok(Priority::Oldest),
+            "newest-created" => Ok(Priority::NewestCreated),
+            "oldest-created" => Ok(Priority::OldestCreated),
             "most-recently-modified" | "mrm" => Ok(Priority::MostRecentlyModified),
             "least-recently-modified" | "lrm" => Ok(Priority::MostRecentlyModified),
             "most-recently-accessed" | "mra" => Ok(Priority::MostRecentlyAccessed),
             "least-recently-accessed" | "lra" => Ok(Priority::LeastRecentlyAccessed),
             "most-nested" => Ok(Priority::MostNested),
             "least-nested" => Ok(Priority::LeastNested),
+            "free-space" => Ok(Priority::FreeSpace),
+            "shortest-path" => Ok(Priority::ShortestPath),
+            "longest-path" => Ok(Priority::LongestPath),
+            "preferred-path" => Ok(Priority::PreferredPath),
             _ => Err(format!("Unrecognized priority: {}", s)),
         }
     }
 }
 
+/// Configures the `bench` subcommand
+#[derive(Debug, StructOpt)]
+#[structopt(
+    setting(AppSettings::DeriveDisplayOrder),
+    setting(AppSettings::DisableVersion),
+    setting(AppSettings::ColoredHelp)
+)]
+pub struct BenchConfig {
+    /// Numbers of threads to try.
+    /// Defaults to powers of two up to the double number of CPU cores, at most 32.
+    #[structopt(short, long = "threads", value_name = "count", use_delimiter = true)]
+    pub thread_counts: Vec<usize>,
+
+    /// Sizes of read buffers to try.
+    #[structopt(
+        long = "buffer-size",
+        value_name = "size",
+        use_delimiter = true,
+        default_value = "64KiB,256KiB,1MiB,4MiB"
+    )]
+    pub buffer_sizes: Vec<FileLen>,
+
+    /// Measures the hash functions also with the given backends, see `fclones group --hash-backend`.
+    /// The built-in backend is always measured.
+    #[structopt(long, value_name = "backend")]
+    pub hash_backend: Vec<HashBackend>,
+
+    /// Maximum amount of data to read in a single measurement.
+    /// Larger amounts give more accurate results, but make the benchmark run longer.
+    #[structopt(long, value_name = "bytes", default_value = "256MiB")]
+    pub max_size: FileLen,
+
+    /// A file or a directory with files to read.
+    /// Should be located on the device you want to measure.
+    #[structopt(parse(from_os_str), required = true)]
+    pub path: PathBuf,
+}
+
+impl BenchConfig {
+    /// Returns the thread counts to try, sorted and without duplicates
+    pub fn thread_counts(&self) -> Vec<usize> {
+        let mut counts = if self.thread_counts.is_empty() {
+            let max_count = min(2 * num_cpus::get(), 32);
+            (0..)
+                .map(|i| 1 << i)
+                .take_while(|&c| c <= max_count)
+                .collect()
+        } else {
+            self.thread_counts.clone()
+        };
+        counts.retain(|&c| c > 0);
+        counts.sort_unstable();
+        counts.dedup();
+        counts
+    }
+
+    /// Returns the buffer sizes to try, sorted and without duplicates
+    pub fn buffer_sizes(&self) -> Vec<usize> {
+        let mut sizes: Vec<usize> = self
+            .buffer_sizes
+            .iter()
+            .map(|s| s.0 as usize)
+            .filter(|&s| s > 0)
+            .collect();
+        sizes.sort_unstable();
+        sizes.dedup();
+        sizes
+    }
+}
+
+/// Configures the `refilter` subcommand
+#[derive(Debug, Default, StructOpt)]
+#[structopt(
+    setting(AppSettings::DeriveDisplayOrder),
+    setting(AppSettings::DisableVersion),
+    setting(AppSettings::ColoredHelp)
+)]
+pub struct RefilterConfig {
+    /// Writes the report to a file instead of the standard output
+    #[structopt(short = "o", long, value_name("path"))]
+    pub output: Option<PathBuf>,
+
+    /// Sets output file format.
+    ///
+    /// `summary` writes only the report header with the statistics, without the lists of files.
+    #[structopt(short = "f", long, possible_values = &OutputFormat::variants(),
+    case_insensitive = true, default_value="default")]
+    pub format: OutputFormat,
+
+    /// Sets the format of the paths in the default and `fdupes` output formats.
+    ///
+    /// `uri` writes paths as `file://` URIs, `shell-quoted` quotes the paths containing
+    /// characters special to the shell. Paths that would be ambiguous in the chosen format,
+    /// e.g. plain paths looking like quoted strings or paths with line breaks,
+    /// are written as URIs. Reports written with any of the path formats
+    /// can be read back by the deduplication commands.
+    #[structopt(long, value_name("format"), possible_values = &PathFormat::variants(),
+    case_insensitive = true, default_value="plain")]
+    pub path_format: PathFormat,
+
+    /// Writes paths in the `json` and `csv` formats in a compact form.
+    ///
+    /// Directories are stored once in a table and files refer to them by index,
+    /// which makes reports with many files in the same directories much smaller.
+    /// Compact JSON reports can be read back by the deduplication commands.
+    /// CSV reports are write-only, so compact CSV reports need to be expanded by the tools
+    /// that read them.
+    #[structopt(long)]
+    pub compact_paths: bool,
+
+    /// Separates the fields of the `csv` format with the given character instead of a comma,
+    /// e.g. `;` expected by spreadsheets in locales using the decimal comma, or `tab`.
+    #[structopt(long, value_name("char"), default_value = ",")]
+    pub csv_delimiter: CsvDelimiter,
+
+    /// Starts the `csv` format with the UTF-8 byte order mark.
+    ///
+    /// Needed by Microsoft Excel to display paths containing non-ASCII characters correctly.
+    #[structopt(long)]
+    pub csv_bom: bool,
+
+    /// Adds the group identifier column to the `csv` format, after the file count.
+    ///
+    /// Not written by default, so the files always start at the fourth column.
+    #[structopt(long)]
+    pub csv_group_id: bool,
+
+    /// Keeps groups only if they have more than n files left after filtering.
+    ///
+    /// If not given, it is assumed to be the same as the
+    /// `--rf-over` value in the earlier `fclones group` run.
+    #[structopt(short = "n", long, value_name = "count")]
+    pub rf_over: Option<usize>,
+
+    /// Minimum file size in bytes. Units like KB, KiB, MB, MiB, GB, GiB are supported. Inclusive.
+    #[structopt(short = "s", long("min"), alias("min-size"), value_name("bytes"))]
+    pub min_size: Option<FileLen>,
+
+    /// Maximum file size in bytes. Units like KB, KiB, MB, MiB, GB, GiB are supported. Inclusive.
+    #[structopt(long("max"), alias("max-size"), value_name("bytes"))]
+    pub max_size: Option<FileLen>,
+
+    /// Keeps only files with names matched fully by any of the given patterns.
+    #[structopt(long = "name", value_name = "pattern")]
+    pub name_patterns: Vec<Pattern>,
+
+    /// Keeps only files with paths matched fully by any of the given patterns.
+    #[structopt(long = "path", alias("path-glob"), value_name = "pattern")]
+    pub path_patterns: Vec<Pattern>,
+
+    /// Drops files with paths matched fully by any of the given patterns.
+    #[structopt(long = "exclude", value_name = "pattern")]
+    pub exclude_patterns: Vec<Pattern>,
+
+    /// Keeps only the given number of groups with the largest amount of redundant data.
+    #[structopt(long, value_name = "count")]
+    pub top: Option<usize>,
+
+    /// Keeps only the groups of files with the given hash, written in hexadecimal digits.
+    /// Can be given multiple times.
+    #[structopt(long, value_name = "hex", parse(try_from_str = FileHash::from_hex))]
+    pub with_hash: Vec<FileHash>,
+
+    /// Keeps only the groups containing the given file.
+    /// Relative paths are resolved against the current directory.
+    /// Can be given multiple times.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    pub containing: Vec<PathBuf>,
+
+    /// Keeps only the groups of a JSON report with the given annotation.
+    ///
+    /// The value is given as `name=value`, or just `name` to require only the presence
+    /// of the annotation. Can be given multiple times; all conditions must hold.
+    /// The annotations of the groups are preserved in JSON output.
+    /// Other report formats have no annotations, so they are rejected.
+    #[structopt(long, value_name = "name=value")]
+    pub only_annotated: Vec<AnnotationFilter>,
+}
+
 /// Configures which files should be removed
 #[derive(Debug, Default, StructOpt)]
 #[structopt(
@@ -442,12 +2144,110 @@ pub struct DedupeConfig {
     #[structopt(short = "o", long, value_name = "path")]
     pub output: Option<PathBuf>,
 
+    /// Sets the format of the `dry_run` report.
+    ///
+    /// `shell` lists the commands that would be executed. `json` writes a plan with one line
+    /// of JSON per action, which can be compared to another plan with `fclones plan-diff`.
+    #[structopt(long, value_name = "format", possible_values = &PlanFormat::variants(),
+        default_value = "shell")]
+    pub plan_format: PlanFormat,
+
+    /// Prints statistics of the `dry_run` plan to the standard error.
+    ///
+    /// Lists the number of files and bytes per type of action and per device,
+    /// the largest groups, and estimates how long running the plan would take
+    /// based on a simple throughput model of each device.
+    #[structopt(long, requires = "dry-run")]
+    pub stats: bool,
+
     /// Deduplicates only the files that were modified before the given time.
     ///
-    /// If any of the files in a group was modified later, the whole group is skipped.
+    /// If any of the files in a group was modified later, the whole group is skipped,
+    /// unless `--verify full` confirms the contents of the file haven't changed.
+    /// Defaults to the time the report was produced. Ignored with `--verify none`.
     #[structopt(long, short = "m", value_name = "timestamp", parse(try_from_str = parse_date_time))]
     pub modified_before: Option<DateTime<FixedOffset>>,
 
+    /// Checks the files for changes made since the report was produced, before acting on them.
+    ///
+    /// - `none`: doesn't check the files; use only if nothing could have modified them
+    ///
+    /// - `stat`: skips files with a size different than reported and groups with files
+    ///   modified after `--modified-before`
+    ///
+    /// - `prefix`: like `stat`, and additionally skips groups whose files differ
+    ///   in the first 4 KiB
+    ///
+    /// - `full`: like `stat`, and additionally skips files whose contents don't match
+    ///   the reported hash; files modified after `--modified-before` are not skipped
+    ///   if their contents still match
+    ///
+    /// Reading the files with `prefix` and `full` is not supported for reports produced
+    /// with `--transform`, so `stat` is used instead.
+    #[structopt(
+        long,
+        value_name = "level",
+        default_value = "stat",
+        possible_values = &Verify::variants()
+    )]
+    pub verify: Verify,
+
+    /// Gives up reading the metadata or the contents of a file if a single call takes
+    /// longer than the given time, e.g. `30s`.
+    ///
+    /// The group of such a file is skipped, so a file on a dying disk or a hung network
+    /// file system can't block the deduplication forever.
+    #[structopt(long, value_name = "duration", parse(try_from_str = parse_duration))]
+    pub io_timeout: Option<Duration>,
+
+    /// Sets what `link` does with the files that can't be hard linked to the kept file,
+    /// because they are located on a different file system.
+    ///
+    /// - `soft-link`: replaces them with soft links to the kept file
+    /// - `skip`: leaves them alone and logs a warning for each of them
+    /// - `local`: keeps one copy on each file system and hard links the other copies
+    ///   on that file system to it, so the copies share their data without crossing
+    ///   file systems
+    /// - `reflink`: like `local`, but replaces the other copies with reflinked copies
+    ///   (`cp --reflink=always`) instead of hard links, so they share their data
+    ///   but stay independent files with their own permissions;
+    ///   requires a file system supporting reflinks, e.g. Btrfs or XFS, on Linux
+    ///
+    /// Not used by `link --soft`, `remove` and `move`.
+    #[structopt(
+        long,
+        value_name = "policy",
+        default_value = "soft-link",
+        possible_values = &CrossDevicePolicy::variants()
+    )]
+    pub cross_device: CrossDevicePolicy,
+
+    /// Replaces files with links even if their security labels differ from the labels
+    /// of the files they would link to.
+    ///
+    /// Security labels are the extended attributes in the `security` namespace,
+    /// e.g. SELinux contexts and file capabilities. A hard link shares the labels of its target,
+    /// and a soft link is accessed with the labels of its target, so linking would change them.
+    /// Such files are skipped by default.
+    #[structopt(long)]
+    pub ignore_security_labels: bool,
+
+    /// Deduplicates also the groups truncated with `--max-group-files`.
+    ///
+    /// A truncated group lists only some of its files, so the files to keep are chosen
+    /// without knowing all the copies. By default such groups are skipped with a warning.
+    #[structopt(long)]
+    pub dedupe_truncated: bool,
+
+    /// Deduplicates only the groups of a JSON report with the given annotation.
+    ///
+    /// The value is given as `name=value`, or just `name` to require only the presence
+    /// of the annotation, e.g. `--only-annotated reviewed=true`.
+    /// Can be given multiple times; all conditions must hold.
+    /// The other groups are left intact. Requires a JSON report.
+    #[structopt(long, value_name = "name=value")]
+    pub only_annotated: Vec<AnnotationFilter>,
+
     /// Keeps at least n replicas untouched.
     ///
     /// If not given, it is assumed to be the same as the
@@ -455,6 +2255,30 @@ pub struct DedupeConfig {
     #[structopt(short = "n", long, value_name = "count", validator(is_positive_int))]
     pub rf_over: Option<usize>,
 
+    /// Keeps exactly n copies of each group, regardless of the `--rf-over` value
+    /// of the report.
+    ///
+    /// The copies to keep are chosen by `--priority`, but copies on devices not holding
+    /// any kept copy yet are preferred, so the kept copies end up on distinct devices
+    /// whenever the group spans enough of them. More copies are kept only if more files
+    /// are protected by `--keep-name`, `--keep-path` or the drop restrictions.
+    #[structopt(
+        long,
+        value_name = "count",
+        validator(is_positive_int),
+        conflicts_with("rf-over")
+    )]
+    pub keep_copies: Option<usize>,
+
+    /// Requires the copies kept by `--keep-copies` to be located on distinct devices.
+    ///
+    /// The kept copies must span as many devices as `--keep-copies` asks for, or all devices
+    /// holding copies of the group if there are fewer of them. A group that can't be
+    /// deduplicated that way, e.g. because the files protected by `--keep-name` or
+    /// `--keep-path` are on a single device, is skipped with a warning.
+    #[structopt(long, requires("keep-copies"))]
+    pub spread_devices: bool,
+
     /// Restricts the set of files that can be removed or replaced by links to files
     /// with the name matching any given patterns.
     #[structopt(long = "name", value_name = "pattern")]
@@ -466,9 +2290,22 @@ pub struct DedupeConfig {
     pub path_patterns: Vec<Pattern>,
 
     /// Sets the priority for files to be removed or replaced by links.
+    ///
+    /// Can be given multiple times. The first priority decides which files are removed first,
+    /// each following one only orders the files the earlier ones consider equal.
+    /// Files still equal after all priorities are ordered by path, and the file with
+    /// the path first in lexicographic order is kept. Without any priority, the files
+    /// are kept in the order of the report.
     #[structopt(long, value_name = "priority", possible_values = &Priority::variants())]
     pub priority: Vec<Priority>,
 
+    /// Sets the patterns of the paths of files kept by `--priority preferred-path`.
+    ///
+    /// Can be given multiple times. Files matching an earlier pattern are kept before
+    /// the files matching only a later one, and the files matching none go last.
+    #[structopt(long = "prefer-path", value_name = "pattern")]
+    pub prefer_path_patterns: Vec<Pattern>,
+
     /// Keeps files with names matching any given patterns untouched.
     #[structopt(long = "keep-name", value_name = "pattern")]
     pub keep_name_patterns: Vec<Pattern>,
@@ -476,6 +2313,145 @@ pub struct DedupeConfig {
     /// Keeps files with paths matching any given patterns untouched.
     #[structopt(long = "keep-path", value_name = "pattern")]
     pub keep_path_patterns: Vec<Pattern>,
+
+    /// Removes or replaces by links only the files located under the given directory.
+    ///
+    /// Files outside of the directory are always kept. Within a group, all files under
+    /// the directory are dropped, as long as enough files outside are kept to satisfy
+    /// the replication factor. Useful together with `fclones group --compare`.
+    #[structopt(long, value_name = "dir", parse(from_os_str))]
+    pub drop_root: Option<PathBuf>,
+
+    /// Resolves relative paths in the input report against the given directory.
+    ///
+    /// Use it for reports written with `--relative-to`.
+    /// If not given, relative paths are resolved against the current working directory.
+    #[structopt(long, value_name = "dir", parse(from_os_str))]
+    pub base: Option<PathBuf>,
+
+    /// Appends a record of every executed action to the given file.
+    ///
+    /// Each action is written as a single line of JSON with the time, the kind of the action,
+    /// the source and destination paths, the hash and size of the file and the result.
+    /// Existing contents of the file are preserved.
+    #[structopt(
+        long,
+        value_name = "path",
+        parse(from_os_str),
+        conflicts_with = "dry-run"
+    )]
+    pub audit_log: Option<PathBuf>,
+
+    /// Retries actions failing with transient errors up to the given number of times.
+    ///
+    /// Errors caused by busy or locked files and by network file system timeouts
+    /// are considered transient.
+    #[structopt(long, value_name = "count", default_value = "0")]
+    pub retries: u32,
+
+    /// Time to wait before retrying a failed action, e.g. `500ms`, `2s` or `1m`.
+    #[structopt(long, value_name = "duration", default_value = "1s", parse(try_from_str = parse_duration))]
+    pub retry_delay: Duration,
+
+    /// Runs at most one action at a time on each network file system.
+    ///
+    /// Avoids overwhelming NFS or SMB servers with many concurrent requests
+    /// at the cost of a slower run.
+    #[structopt(long)]
+    pub serialize_network: bool,
+
+    /// Limits the number of files open at the same time.
+    ///
+    /// Threads wait for other threads to close their files if the limit is reached,
+    /// instead of failing with "Too many open files".
+    /// Defaults to the limit of open file descriptors set by the operating system
+    /// (`ulimit -n`), reduced by the descriptors needed for other purposes.
+    #[structopt(long, value_name = "count")]
+    pub max_open_files: Option<usize>,
+
+    /// Lets a privileged helper process started with the given command change the files.
+    ///
+    /// The report is read and the actions are planned by this process, which needs only
+    /// the permission to read the files. Each action is sent to the helper over a pipe,
+    /// e.g. `--helper 'sudo fclones helper --allow /data'`. See `fclones helper`.
+    /// Not supported by `fclones move`.
+    #[structopt(long, value_name = "command", conflicts_with = "dry-run")]
+    pub helper: Option<String>,
+
+    /// Skips files already processed by an interrupted run, according to its audit log.
+    ///
+    /// Files successfully removed, moved or replaced by links recorded in the log written
+    /// with `--audit-log` are left out of their groups. Groups with no files left to process
+    /// are skipped without accessing their files.
+    #[structopt(long, value_name = "audit-log", parse(from_os_str))]
+    pub resume_from: Option<PathBuf>,
+
+    /// Keeps the files chosen by an external tool in the listed groups.
+    ///
+    /// Each line of the file holds a group identifier or a group hash, followed by the path
+    /// of the file that must be kept in that group. The listed file is kept and becomes
+    /// the target of the links, regardless of priorities and patterns. Groups that are
+    /// not listed are handled as usual. Groups not containing the listed file are skipped.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    pub keep_list: Option<PathBuf>,
+
+    /// Files to keep read from `keep_list`
+    #[structopt(skip)]
+    pub keep_files: KeepList,
+
+    /// Receives the numbers of files read from the report and bytes verified,
+    /// see [`DedupeSummary`](crate::summary::DedupeSummary).
+    #[structopt(skip)]
+    pub counters: Option<Arc<DedupeCounters>>,
+
+    /// Appends a record of every action that failed after all retries to the given file.
+    ///
+    /// The records have the same format as the ones written by `--audit-log`
+    /// and contain the shell commands performing the failed actions, for running them
+    /// again later.
+    #[structopt(
+        long,
+        value_name = "path",
+        parse(from_os_str),
+        conflicts_with = "dry-run"
+    )]
+    pub failed_log: Option<PathBuf>,
+
+    #[structopt(flatten)]
+    pub case: CaseOpts,
+
+    /// Converts paths to the given Unicode normalization form before matching them
+    /// against patterns or comparing them.
+    ///
+    /// Lets the same patterns match names created on macOS, which are usually decomposed (NFD),
+    /// and names created on Linux or Windows, which are usually composed (NFC).
+    /// Affects only the matching, the files are not renamed.
+    #[structopt(long, value_name = "form", possible_values = &UnicodeForm::variants())]
+    pub normalize_unicode: Option<UnicodeForm>,
+}
+
+impl DedupeConfig {
+    /// Returns true if paths differing only in letter case may refer to the same file
+    pub fn ignore_case(&self) -> bool {
+        self.case.ignore_case()
+    }
+
+    /// Makes the name and path patterns normalize Unicode in the matched paths
+    /// according to `normalize_unicode`
+    pub fn normalize_patterns(&mut self) {
+        let form = self.normalize_unicode;
+        for patterns in [
+            &mut self.name_patterns,
+            &mut self.path_patterns,
+            &mut self.keep_name_patterns,
+            &mut self.keep_path_patterns,
+            &mut self.prefer_path_patterns,
+        ] {
+            for p in patterns.iter_mut() {
+                *p = p.normalized(form);
+            }
+        }
+    }
 }
 
 #[derive(Debug, StructOpt)]
@@ -486,7 +2462,7 @@ pub enum Command {
     /// files with the same hash together.
     /// Writes the list of groups of files to the standard output, unless the target file
     /// is specified. This command is safe and does not modify the filesystem.
-    Group(GroupConfig),
+    Group(Box<GroupConfig>),
 
     /// Replaces redundant files with links.
     ///
@@ -494,8 +2470,8 @@ pub enum Command {
     /// on the standard input. Only the default text format is supported.
     ///
     /// Unless `--soft` is specified, hard links are created for links within
-    /// the same file system. Soft links are always created to link files between
-    /// different file systems.
+    /// the same file system. Files on different file systems are linked
+    /// as set by `--cross-device`.
     Link {
         #[structopt(flatten)]
         config: DedupeConfig,
@@ -515,6 +2491,10 @@ pub enum Command {
     ///
     /// The list of groups earlier produced by `fclones group` should be submitted
     /// on the standard input. Only the default text format is supported.
+    ///
+    /// Files on a different file system than the target directory are copied first
+    /// and removed only after the copy has been verified. An interrupted copy is resumed
+    /// by the next run.
     Move {
         #[structopt(flatten)]
         config: DedupeConfig,
@@ -522,7 +2502,236 @@ pub enum Command {
         /// Target directory where the redundant files should be moved to.
         #[structopt(parse(from_os_str))]
         target: PathBuf,
+
+        /// Skips files located on a different file system than the target directory.
+        ///
+        /// By default, such files are copied to the target directory, verified and then removed.
+        #[structopt(long)]
+        no_cross_device: bool,
     },
+
+    /// Measures hashing throughput and recommends tuning settings.
+    ///
+    /// Reads files under the given path with different numbers of threads and buffer sizes,
+    /// and prints the throughput of each combination, together with the `--threads`
+    /// and `--buffer-size` settings recommended for the device holding the path.
+    /// This command is safe and does not modify the filesystem.
+    Bench(BenchConfig),
+
+    /// Filters an existing report without rescanning the file system.
+    ///
+    /// The report earlier produced by `fclones group` should be submitted on the standard input.
+    /// Both the default text and JSON formats are supported.
+    /// Files not matching the filters are dropped from their groups, groups left with too few
+    /// files are dropped and the statistics in the report header are recomputed.
+    Refilter(RefilterConfig),
+
+    /// Serves requests of other programs over a local socket.
+    ///
+    /// Accepts JSON-RPC 2.0 requests, one per line, to start scans (`scan`), query their
+    /// progress (`status`), fetch their results (`groups`), cancel them (`cancel`)
+    /// and deduplicate the files found (`dedupe`). Scans reuse the hashes computed by
+    /// earlier scans for files that haven't changed since. Supported on Unix only.
+    Daemon(DaemonConfig),
+
+    /// Reports the features of the file system holding the given path.
+    ///
+    /// Tells if the file system supports hard links, symbolic links, reflinks,
+    /// extended attributes and file creation times, and which of the `link`, `remove`
+    /// and `move` commands can be used on it. The features are detected by creating
+    /// a temporary file in the directory, so write access to it is required.
+    FsInfo(FsInfoConfig),
+
+    /// Lists the copies of a single file.
+    ///
+    /// Scans the given directories recursively for files with the same contents as the given
+    /// file and writes their paths to the standard output, one per line.
+    /// Only the files of the same size are hashed, so this is much faster than `fclones group`.
+    /// This command is safe and does not modify the filesystem.
+    FindCopies(FindCopiesConfig),
+
+    /// Checks if every file in a directory has a copy in another directory.
+    ///
+    /// Writes the paths of the files under the first directory that have no copy with the same
+    /// contents under the second directory to the standard output, one per line,
+    /// and exits with an error if there are any. The first directory may be nested
+    /// in the second one, but the copies inside of it don't count.
+    /// Use it to make sure nothing is lost before removing a directory.
+    /// This command is safe and does not modify the filesystem.
+    Contains(ContainsConfig),
+
+    /// Removes files and replaces files by links on behalf of an unprivileged process.
+    ///
+    /// Started by `fclones remove` or `fclones link` with the `--helper` option, usually
+    /// with elevated privileges. Reads the actions from the standard input, one line of JSON
+    /// each, and writes their results to the standard output.
+    /// Only files under the allowed directories that haven't changed since they were scanned
+    /// are changed, and files are replaced by links only if their contents are equal to
+    /// the contents of the link targets.
+    Helper(HelperConfig),
+
+    /// Compares two plans written by `--dry-run --plan-format json`.
+    ///
+    /// Lists the actions present only in the old plan with `-`, the actions present only
+    /// in the new plan with `+`, and the actions on the same file that differ, e.g. because
+    /// the file changed or the link target is different, with `~`.
+    /// This command is safe and does not modify the filesystem.
+    PlanDiff(PlanDiffConfig),
+
+    /// Copies files with too few copies to another directory.
+    ///
+    /// The report earlier produced by `fclones group --rf-under` should be submitted on
+    /// the standard input. For every group with fewer files than the `--rf-under` setting
+    /// of the report, as many files as missing are copied into the target directory, keeping
+    /// their paths relative to the `--base` directory, or their full paths if no base is given.
+    /// Each file is copied at most once, so use a separate target for every missing copy
+    /// of single files. Files that changed since the report was produced are not copied.
+    /// Copies already in the target directory count, so running the command again after
+    /// an interruption copies only the remaining files.
+    Replicate(ReplicateConfig),
+}
+
+/// Configuration of the `replicate` subcommand
+#[derive(Clone, Debug, Default, StructOpt)]
+#[structopt(
+    setting(AppSettings::ColoredHelp),
+    setting(AppSettings::DeriveDisplayOrder),
+    setting(AppSettings::DisableVersion)
+)]
+pub struct ReplicateConfig {
+    /// Directory to copy the files to
+    #[structopt(long, value_name = "dir", parse(from_os_str), required = true)]
+    pub target: PathBuf,
+
+    /// Keeps the paths of the copied files relative to this directory.
+    ///
+    /// Files outside of it are copied with their full paths.
+    /// Relative paths in the report, e.g. written with `--relative-to`, are resolved against it.
+    #[structopt(long, value_name = "dir", parse(from_os_str))]
+    pub base: Option<PathBuf>,
+
+    /// Copies files of the groups with fewer than the given number of files.
+    ///
+    /// Defaults to the `--rf-under` setting of the `fclones group` run that produced the report.
+    #[structopt(long, value_name = "count")]
+    pub rf_under: Option<usize>,
+
+    /// Only prints the copy commands, without copying anything
+    #[structopt(long)]
+    pub dry_run: bool,
+}
+
+/// Configuration of the `plan-diff` subcommand
+#[derive(Debug, StructOpt)]
+#[structopt(
+    setting(AppSettings::ColoredHelp),
+    setting(AppSettings::DeriveDisplayOrder),
+    setting(AppSettings::DisableVersion)
+)]
+pub struct PlanDiffConfig {
+    /// The earlier plan
+    #[structopt(parse(from_os_str), required = true)]
+    pub old: PathBuf,
+
+    /// The later plan
+    #[structopt(parse(from_os_str), required = true)]
+    pub new: PathBuf,
+}
+
+/// Configuration of the `helper` subcommand
+#[derive(Debug, StructOpt)]
+#[structopt(
+    setting(AppSettings::ColoredHelp),
+    setting(AppSettings::DeriveDisplayOrder),
+    setting(AppSettings::DisableVersion)
+)]
+pub struct HelperConfig {
+    /// Allows changing files under the given directory
+    #[structopt(long, value_name = "dir", parse(from_os_str), required = true)]
+    pub allow: Vec<PathBuf>,
+}
+
+/// Configuration of the `fs-info` subcommand
+#[derive(Debug, StructOpt)]
+#[structopt(
+    setting(AppSettings::ColoredHelp),
+    setting(AppSettings::DeriveDisplayOrder),
+    setting(AppSettings::DisableVersion)
+)]
+pub struct FsInfoConfig {
+    /// A directory or a file on the file system to probe
+    #[structopt(parse(from_os_str), required = true)]
+    pub path: PathBuf,
+}
+
+/// Configuration of the `find-copies` subcommand
+#[derive(Debug, StructOpt)]
+#[structopt(
+    setting(AppSettings::ColoredHelp),
+    setting(AppSettings::DeriveDisplayOrder),
+    setting(AppSettings::DisableVersion)
+)]
+pub struct FindCopiesConfig {
+    /// Follows symbolic links to files and directories
+    #[structopt(short = "L", long)]
+    pub follow_links: bool,
+
+    /// Skips hidden files and directories
+    #[structopt(short = "A", long)]
+    pub skip_hidden: bool,
+
+    /// The file to find the copies of
+    #[structopt(parse(from_os_str), required = true)]
+    pub file: PathBuf,
+
+    /// Input directories to scan for the copies
+    #[structopt(parse(from_os_str), required = true)]
+    pub paths: Vec<PathBuf>,
+}
+
+/// Configuration of the `contains` subcommand
+#[derive(Debug, StructOpt)]
+#[structopt(
+    setting(AppSettings::ColoredHelp),
+    setting(AppSettings::DeriveDisplayOrder),
+    setting(AppSettings::DisableVersion)
+)]
+pub struct ContainsConfig {
+    /// Follows symbolic links to files and directories
+    #[structopt(short = "L", long)]
+    pub follow_links: bool,
+
+    /// Skips hidden files and directories
+    #[structopt(short = "A", long)]
+    pub skip_hidden: bool,
+
+    /// The directory whose files should have copies
+    #[structopt(parse(from_os_str), required = true)]
+    pub dir: PathBuf,
+
+    /// The directory to search for the copies
+    #[structopt(parse(from_os_str), required = true)]
+    pub root: PathBuf,
+}
+
+/// Configuration of the `daemon` subcommand
+#[derive(Debug, StructOpt)]
+#[structopt(
+    setting(AppSettings::ColoredHelp),
+    setting(AppSettings::DeriveDisplayOrder),
+    setting(AppSettings::DisableVersion)
+)]
+pub struct DaemonConfig {
+    /// Path of the Unix socket to listen on
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    pub socket: PathBuf,
+
+    /// Number of finished jobs whose results are kept.
+    ///
+    /// When more jobs finish, the results of the oldest ones are dropped
+    /// and their identifiers become unknown.
+    #[structopt(long, value_name = "count", default_value = "16")]
+    pub max_finished_jobs: usize,
 }
 
 /// Finds and cleans up redundant files
@@ -537,7 +2746,100 @@ pub struct Config {
     #[structopt(short("-q"), long)]
     pub quiet: bool,
 
+    /// Controls coloring of the log messages and the report.
+    ///
+    /// `auto` colors the output only if it goes to a terminal and the `NO_COLOR`
+    /// environment variable is not set. Reports written to a file with `-o` are colored only
+    /// if `always` is set. The colors of the report can be changed with the `FCLONES_COLORS`
+    /// environment variable, e.g. `FCLONES_COLORS=header=cyan:group=yellow.bold:path=green`.
+    #[structopt(long, value_name("when"), possible_values = &ColorMode::variants(),
+    case_insensitive = true, default_value="auto")]
+    pub color: ColorMode,
+
+    /// Writes the human-readable sizes in SI units, powers of 1000, e.g. `1.5 MB`.
+    ///
+    /// This is the default. The exact byte counts in the reports are not affected.
+    /// The decimal separator follows the `LC_ALL`, `LC_NUMERIC` or `LANG` locale,
+    /// e.g. `1,5 MB` for `de_DE.UTF-8`; set `LC_NUMERIC=C` to always use a dot.
+    #[structopt(long, conflicts_with("binary"))]
+    pub si: bool,
+
+    /// Writes the human-readable sizes in binary units, powers of 1024, e.g. `1.4 MiB`
+    #[structopt(long)]
+    pub binary: bool,
+
+    /// Reports the progress to systemd when running as a service of `Type=notify`.
+    ///
+    /// Sends the readiness notification and status updates shown by `systemctl status`.
+    /// If `WatchdogSec` is set for the service, pings the watchdog as long as reading
+    /// the files makes progress, so systemd can restart the service if a read hangs.
+    #[structopt(long)]
+    pub notify_systemd: bool,
+
+    /// Posts a JSON summary of the run to the given URL when the run completes or fails.
+    ///
+    /// The summary contains the command, the status, the error message, the start and finish
+    /// times, the number of warnings and the statistics of the run.
+    /// Both `http://` and `https://` URLs are supported; use `--notify-cmd` with an HTTP client,
+    /// e.g. `curl`, for other protocols.
+    #[structopt(long, value_name = "url")]
+    pub notify_webhook: Option<String>,
+
+    /// Runs the given command with the JSON summary of the run on its standard input
+    /// when the run completes or fails, e.g. to send it by email.
+    #[structopt(long, value_name = "command")]
+    pub notify_cmd: Option<String>,
+
+    /// Writes the warnings, the errors and the outcome of the run to the system log.
+    ///
+    /// Uses syslog on Unix and the Application event log on Windows.
+    /// Warnings and errors are logged with the warning and error severities.
+    /// When the run finishes, a summary with the command, the duration and the number
+    /// of warnings is logged with the notice severity, or with the error severity if the run failed.
+    #[structopt(long)]
+    pub log_syslog: bool,
+
+    /// Appends the warnings, the errors and the files skipped during the run to the given file
+    /// as JSON objects, one per line, so they can be processed by scripts.
+    ///
+    /// Each object has the `timestamp`, the `kind` of the event, the `message`,
+    /// and the `path` of the file the event is about, if any.
+    /// The kinds are: `error`, `warning`, `unreadable` (a file that couldn't be read),
+    /// `changed` (a file modified after it was scanned) and `filtered` (a file
+    /// excluded by the selection criteria). Filtered files are not shown in the regular log.
+    #[structopt(long, value_name = "path", parse(from_os_str))]
+    pub log_json: Option<PathBuf>,
+
     /// Finds files
     #[structopt(subcommand)]
     pub command: Command,
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn group_config_builder_defaults_match_command_line_defaults() {
+        // the command line requires at least one path
+        let built = GroupConfig::builder().path(".").build();
+        let parsed = GroupConfig::from_iter(["fclones", "."]);
+        assert_eq!(format!("{:#?}", built), format!("{:#?}", parsed));
+    }
+
+    #[test]
+    fn group_config_builder_sets_fields() {
+        let config = GroupConfig::builder()
+            .paths(["dir1", "dir2"])
+            .min_size(FileLen(100))
+            .hash_fn([HashFn::Sha256, HashFn::Metro])
+            .build();
+        assert_eq!(
+            config.paths,
+            vec![PathBuf::from("dir1"), PathBuf::from("dir2")]
+        );
+        assert_eq!(config.min_size, FileLen(100));
+        assert_eq!(config.hash_fn, vec![HashFn::Sha256, HashFn::Metro]);
+        assert_eq!(config.extra_hash_fns(), vec![HashFn::Sha256]);
+    }
+}
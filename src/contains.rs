@@ -0,0 +1,224 @@
+//! Checking if all files of a directory have copies elsewhere.
+//!
+//! Before removing an old directory, it is good to know that every file in it
+//! is already stored in another place. The copies are compared by contents, so the files
+//! may be renamed or organized differently.
+//!
+//! Files of the directory located in trash or cache directories are checked as well,
+//! because they are removed together with the directory. Copies in trash or cache
+//! directories don't count though, as they may disappear any time. Snapshots are not scanned:
+//! they are not removed together with the directory and their copies may be deleted
+//! when they are rotated.
+
+use std::env::current_dir;
+use std::fs;
+use std::sync::Arc;
+
+use crate::config::{ContainsConfig, GroupConfig};
+use crate::files::FileLen;
+use crate::log::Log;
+use crate::path::Path;
+use crate::trash;
+use crate::{group_files, Error};
+
+/// Describes how many files of a directory have copies in another directory
+#[derive(Debug)]
+pub struct Coverage {
+    /// The number of files found in the checked directory
+    pub file_count: usize,
+    /// The files of the checked directory without any copy elsewhere, sorted by path.
+    /// Includes the files and directories that couldn't be read, as their copies
+    /// couldn't be checked.
+    pub uncovered: Vec<Path>,
+}
+
+impl Coverage {
+    /// Returns true if all files have a copy
+    pub fn is_complete(&self) -> bool {
+        self.uncovered.is_empty()
+    }
+}
+
+/// Finds the files under `config.dir` that have no copy with the same contents
+/// under `config.root`. The directory may be nested in the root, but the copies
+/// inside the directory don't count.
+pub fn contains(config: &ContainsConfig, log: &Log) -> Result<Coverage, Error> {
+    let base_dir = Arc::new(Path::from(current_dir().unwrap_or_default()));
+    let dir = base_dir.resolve(Path::from(&config.dir)).canonicalize();
+    let root = base_dir.resolve(Path::from(&config.root)).canonicalize();
+    for path in [&dir, &root].iter() {
+        match fs::metadata(path.to_path_buf()) {
+            Ok(m) if m.is_dir() => {}
+            Ok(_) => return Err(format!("{} is not a directory", path).into()),
+            Err(e) => return Err(format!("Cannot access {}: {}", path, e).into()),
+        }
+    }
+    if dir.is_prefix_of(&root) {
+        return Err(format!("{} is contained in {}", root, dir).into());
+    }
+
+    let paths = if root.is_prefix_of(&dir) {
+        vec![root.to_path_buf()]
+    } else {
+        vec![dir.to_path_buf(), root.to_path_buf()]
+    };
+    let group_config = GroupConfig {
+        paths,
+        // Keep the files without any copy as single-file groups
+        rf_over: Some(0),
+        min_size: FileLen(0),
+        // A hard link outside of the directory keeps the data after the directory is removed
+        hard_links: true,
+        follow_links: config.follow_links,
+        skip_hidden: config.skip_hidden,
+        include_trash: true,
+        include_snapshots: false,
+        // A copy hidden by the ignore file would be reported as missing
+        no_ignore_file: true,
+        ..GroupConfig::default()
+    };
+
+    let in_dir = |f: &Path| dir.is_prefix_of(f);
+    let is_copy = |f: &Path| !in_dir(f) && !is_volatile(f);
+    let mut file_count = 0;
+    let mut uncovered = Vec::new();
+    log.record_unreadable();
+    for g in group_files(&group_config, log)? {
+        file_count += g.files.iter().filter(|f| in_dir(f)).count();
+        if !g.files.iter().any(&is_copy) {
+            uncovered.extend(g.files.into_iter().filter(|f| in_dir(f)));
+        }
+    }
+    for unreadable in log.take_unreadable() {
+        if in_dir(&unreadable.path) {
+            file_count += 1;
+            uncovered.push(unreadable.path);
+        }
+    }
+    uncovered.sort();
+    uncovered.dedup();
+    Ok(Coverage {
+        file_count,
+        uncovered,
+    })
+}
+
+/// Returns true if the file is in a trash or cache directory
+fn is_volatile(file: &Path) -> bool {
+    let file = file.to_path_buf();
+    file.ancestors()
+        .skip(1)
+        .any(|dir| trash::is_trash(dir) || trash::is_cache(dir))
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{create_dir, create_dir_all};
+
+    use crate::util::test::*;
+
+    use super::*;
+
+    fn test_log() -> Log {
+        let mut log = Log::new();
+        log.no_progress = true;
+        log
+    }
+
+    fn config(dir: &std::path::Path, root: &std::path::Path) -> ContainsConfig {
+        ContainsConfig {
+            follow_links: false,
+            skip_hidden: false,
+            dir: dir.to_path_buf(),
+            root: root.to_path_buf(),
+        }
+    }
+
+    #[test]
+    fn lists_files_without_copies() {
+        with_dir("contains/separate", |root| {
+            let (old, new) = (root.join("old"), root.join("new"));
+            create_dir(&old).unwrap();
+            create_dir(&new).unwrap();
+            fs::write(old.join("1"), b"copied").unwrap();
+            fs::write(old.join("2"), b"not copied").unwrap();
+            fs::write(old.join("3"), b"").unwrap();
+            fs::write(new.join("1.bak"), b"copied").unwrap();
+            fs::write(new.join("3"), b"").unwrap();
+
+            let log = test_log();
+            let coverage = contains(&config(&old, &new), &log).unwrap();
+            assert_eq!(coverage.file_count, 3);
+            assert_eq!(coverage.uncovered, vec![Path::from(old.join("2"))]);
+
+            fs::write(new.join("2"), b"not copied").unwrap();
+            let coverage = contains(&config(&old, &new), &log).unwrap();
+            assert!(coverage.is_complete());
+        });
+    }
+
+    #[test]
+    fn ignores_copies_inside_of_nested_dir() {
+        with_dir("contains/nested", |root| {
+            let old = root.join("old");
+            create_dir(&old).unwrap();
+            fs::write(old.join("1"), b"copied").unwrap();
+            fs::write(old.join("2"), b"duplicated within old").unwrap();
+            fs::write(old.join("3"), b"duplicated within old").unwrap();
+            fs::write(root.join("1"), b"copied").unwrap();
+
+            let log = test_log();
+            let coverage = contains(&config(&old, root), &log).unwrap();
+            assert_eq!(coverage.file_count, 3);
+            assert_eq!(
+                coverage.uncovered,
+                vec![Path::from(old.join("2")), Path::from(old.join("3"))]
+            );
+            assert!(contains(&config(root, &old), &log).is_err());
+        });
+    }
+
+    #[test]
+    fn checks_files_in_trash_but_ignores_copies_in_trash() {
+        with_dir("contains/trash", |root| {
+            let (old, new) = (root.join("old"), root.join("new"));
+            create_dir_all(old.join(".Trash-1000")).unwrap();
+            create_dir_all(new.join(".Trash-1000")).unwrap();
+            fs::write(old.join(".Trash-1000").join("1"), b"in trash").unwrap();
+            fs::write(old.join("2"), b"copied to trash").unwrap();
+            fs::write(new.join(".Trash-1000").join("2"), b"copied to trash").unwrap();
+
+            let log = test_log();
+            let coverage = contains(&config(&old, &new), &log).unwrap();
+            assert_eq!(coverage.file_count, 2);
+            assert_eq!(
+                coverage.uncovered,
+                vec![
+                    Path::from(old.join("2")),
+                    Path::from(old.join(".Trash-1000").join("1"))
+                ]
+            );
+        });
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn reports_unreadable_files_as_uncovered() {
+        with_dir("contains/unreadable", |root| {
+            let (old, new) = (root.join("old"), root.join("new"));
+            create_dir(&old).unwrap();
+            create_dir(&new).unwrap();
+            fs::write(old.join("1"), b"copied").unwrap();
+            fs::write(new.join("1"), b"copied").unwrap();
+            let missing = old.join("missing");
+            std::os::unix::fs::symlink(&missing, old.join("dangling")).unwrap();
+
+            let log = test_log();
+            let mut config = config(&old, &new);
+            config.follow_links = true;
+            let coverage = contains(&config, &log).unwrap();
+            assert!(!coverage.is_complete());
+            assert_eq!(coverage.uncovered, vec![Path::from(missing)]);
+        });
+    }
+}
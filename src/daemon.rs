@@ -0,0 +1,630 @@
+//! Serving requests of other programs over a local socket.
+//!
+//! The daemon accepts connections on a Unix socket and reads JSON-RPC 2.0 requests from them,
+//! one per line, answering each with a single line of JSON. Scans run in the background,
+//! so a client can start a scan, poll its status and fetch the groups when it is finished.
+//! The report of the last finished scan is kept in a private temporary directory, removed
+//! when the daemon exits, and used as the baseline of the following scans, so files that
+//! haven't changed since are not read again. Only the results of the most recently finished
+//! jobs are kept, the older ones are dropped.
+//!
+//! Methods:
+//! - `scan`: starts grouping files; `args` holds the arguments of the `group` command.
+//!   Returns the identifier of the new job.
+//! - `status`: returns the state of the given `job`, or of all jobs if no job is given.
+//!   The state of a running job includes the number of bytes hashed and to be hashed
+//!   in the current `stage` and the estimated number of seconds left (`eta_secs`).
+//! - `groups`: returns the groups found by a finished `job`.
+//! - `cancel`: stops the given `job`.
+//! - `dedupe`: runs `op` (`remove`, `soft_link`, `hard_link` or `move` to `target`) on the
+//!   groups found by a finished `job`; `args` holds the options of the deduplication commands.
+
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs;
+use std::io;
+use std::io::{BufRead, BufReader, Write};
+use std::iter;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use chrono::{DateTime, FixedOffset, Local};
+use itertools::Itertools;
+use nix::sys::signal::{SigSet, Signal};
+use serde_json::{json, Value};
+use structopt::StructOpt;
+use tempfile::{NamedTempFile, TempDir};
+
+use crate::config::{DaemonConfig, DedupeConfig, GroupConfig, OutputFormat};
+use crate::dedupe::{dedupe, log_script, run_script, DedupeOp, RunOptions};
+use crate::log::Log;
+use crate::path::Path;
+use crate::progress::StageProgress;
+use crate::systemd;
+use crate::{group_files, write_report, AuditLog, Error, FileGroup};
+
+/// Error codes defined by the JSON-RPC 2.0 specification
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// Error code of valid requests that failed
+const REQUEST_FAILED: i64 = -32000;
+
+struct RpcError {
+    code: i64,
+    message: String,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Display) -> RpcError {
+        RpcError {
+            code,
+            message: message.to_string(),
+        }
+    }
+
+    fn invalid_params(message: impl Display) -> RpcError {
+        RpcError::new(INVALID_PARAMS, message)
+    }
+
+    fn failed(message: impl Display) -> RpcError {
+        RpcError::new(REQUEST_FAILED, message)
+    }
+}
+
+enum JobState {
+    Running,
+    Finished {
+        groups: Vec<FileGroup<Path>>,
+        /// Files modified after this time are not deduplicated
+        started: DateTime<FixedOffset>,
+    },
+    Failed(String),
+    Cancelled,
+}
+
+struct Job {
+    state: Mutex<JobState>,
+    cancel: Arc<AtomicBool>,
+    progress: Arc<StageProgress>,
+    rf_over: usize,
+}
+
+/// Handles the requests received by the daemon
+pub struct Server {
+    log: Arc<Log>,
+    jobs: Mutex<BTreeMap<u64, Arc<Job>>>,
+    next_id: AtomicU64,
+    /// Report of the last finished scan, used as the baseline of new scans
+    cache: PathBuf,
+    /// Number of finished jobs kept; the oldest ones are dropped first
+    max_finished_jobs: usize,
+}
+
+impl Server {
+    pub fn new(log: Arc<Log>, cache: PathBuf, max_finished_jobs: usize) -> Server {
+        Server {
+            log,
+            jobs: Mutex::new(BTreeMap::new()),
+            next_id: AtomicU64::new(1),
+            cache,
+            max_finished_jobs,
+        }
+    }
+
+    /// Handles a single JSON-RPC request and returns the response.
+    /// Returns `None` for notifications, i.e. requests without an `id`.
+    pub fn handle(self: &Arc<Self>, request: &str) -> Option<String> {
+        let request: Value = match serde_json::from_str(request) {
+            Ok(request) => request,
+            Err(e) => return Some(response(Value::Null, Err(RpcError::new(PARSE_ERROR, e)))),
+        };
+        let id = request.get("id").cloned();
+        let params = request.get("params").cloned().unwrap_or(Value::Null);
+        let result = match request.get("method").and_then(Value::as_str) {
+            Some(method) => self.call(method, &params),
+            None => Err(RpcError::new(INVALID_REQUEST, "Missing method")),
+        };
+        match id {
+            Some(id) => Some(response(id, result)),
+            None if result.is_err() => Some(response(Value::Null, result)),
+            None => None,
+        }
+    }
+
+    fn call(self: &Arc<Self>, method: &str, params: &Value) -> Result<Value, RpcError> {
+        match method {
+            "scan" => self.scan(params),
+            "status" => self.status(params),
+            "groups" => self.groups(params),
+            "cancel" => self.cancel(params),
+            "dedupe" => self.dedupe(params),
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("Unknown method: {}", method),
+            )),
+        }
+    }
+
+    fn scan(self: &Arc<Self>, params: &Value) -> Result<Value, RpcError> {
+        let args = string_array(params, "args")?;
+        let mut config = GroupConfig::from_iter_safe(iter::once("group".to_owned()).chain(args))
+            .map_err(|e| RpcError::invalid_params(error_cause(&e.message)))?;
+        if config.stdin {
+            return Err(RpcError::invalid_params("--stdin is not supported"));
+        }
+        if config.baseline.is_none() && config.transform.is_none() && self.cache.exists() {
+            config.baseline = Some(self.cache.clone());
+        }
+        let cancel = Arc::new(AtomicBool::new(false));
+        config.cancel = Some(cancel.clone());
+        let progress = Arc::new(StageProgress::default());
+        config.progress = Some(progress.clone());
+
+        let job = Arc::new(Job {
+            state: Mutex::new(JobState::Running),
+            cancel,
+            progress,
+            rf_over: config.rf_over(),
+        });
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.jobs.lock().unwrap().insert(id, job.clone());
+        let server = self.clone();
+        thread::Builder::new()
+            .name(format!("fclones-scan-{}", id))
+            .spawn(move || server.run_scan(config, &job))
+            .unwrap();
+        Ok(json!({ "job": id }))
+    }
+
+    fn run_scan(&self, config: GroupConfig, job: &Job) {
+        let started = Local::now().into();
+        let state = match group_files(&config, &self.log) {
+            Ok(groups) => {
+                if config.transform.is_none() {
+                    self.update_cache(config, &groups);
+                }
+                JobState::Finished { groups, started }
+            }
+            Err(_) if job.cancel.load(Ordering::Relaxed) => JobState::Cancelled,
+            Err(e) => JobState::Failed(e.message),
+        };
+        let mut jobs = self.jobs.lock().unwrap();
+        *job.state.lock().unwrap() = state;
+        self.drop_old_jobs(&mut jobs);
+    }
+
+    /// Forgets the oldest finished jobs, so that at most `max_finished_jobs` remain
+    fn drop_old_jobs(&self, jobs: &mut BTreeMap<u64, Arc<Job>>) {
+        let finished: Vec<u64> = jobs
+            .iter()
+            .filter(|(_, job)| !matches!(*job.state.lock().unwrap(), JobState::Running))
+            .map(|(&id, _)| id)
+            .collect();
+        let excess = finished.len().saturating_sub(self.max_finished_jobs);
+        for id in &finished[..excess] {
+            jobs.remove(id);
+        }
+    }
+
+    /// Writes the groups to the cache as a JSON report, so the next scans can use them
+    /// as the baseline
+    fn update_cache(&self, mut config: GroupConfig, groups: &[FileGroup<Path>]) {
+        let dir = self
+            .cache
+            .parent()
+            .unwrap_or_else(|| std::path::Path::new("."));
+        let result = NamedTempFile::new_in(dir).and_then(|tmp| {
+            config.output = Some(tmp.path().to_path_buf());
+            config.format = OutputFormat::Json;
+            config.extra_outputs.clear();
+            config.relative_to = None;
+            config.compact_paths = false;
+            write_report(&config, &self.log, groups)?;
+            // The temporary file is removed on drop if it couldn't be renamed:
+            tmp.persist(&self.cache).map_err(|e| e.error)?;
+            Ok(())
+        });
+        if let Err(e) = result {
+            self.log.warn(format!(
+                "Failed to write hash cache {}: {}",
+                self.cache.display(),
+                e
+            ));
+        }
+    }
+
+    fn job(&self, params: &Value) -> Result<(u64, Arc<Job>), RpcError> {
+        let id = params
+            .get("job")
+            .and_then(Value::as_u64)
+            .ok_or_else(|| RpcError::invalid_params("Missing job"))?;
+        match self.jobs.lock().unwrap().get(&id) {
+            Some(job) => Ok((id, job.clone())),
+            None => Err(RpcError::invalid_params(format!("Unknown job: {}", id))),
+        }
+    }
+
+    fn status(&self, params: &Value) -> Result<Value, RpcError> {
+        if params.get("job").is_some() {
+            let (id, job) = self.job(params)?;
+            return Ok(job_status(id, &job));
+        }
+        let jobs = self.jobs.lock().unwrap();
+        Ok(Value::Array(
+            jobs.iter().map(|(&id, job)| job_status(id, job)).collect(),
+        ))
+    }
+
+    fn groups(&self, params: &Value) -> Result<Value, RpcError> {
+        let (id, job) = self.job(params)?;
+        let state = job.state.lock().unwrap();
+        match &*state {
+            JobState::Finished { groups, .. } => {
+                serde_json::to_value(groups).map_err(RpcError::failed)
+            }
+            _ => Err(RpcError::failed(format!("Job {} is not finished", id))),
+        }
+    }
+
+    fn cancel(&self, params: &Value) -> Result<Value, RpcError> {
+        let (_, job) = self.job(params)?;
+        let running = matches!(*job.state.lock().unwrap(), JobState::Running);
+        job.cancel.store(true, Ordering::Relaxed);
+        Ok(Value::Bool(running))
+    }
+
+    fn dedupe(&self, params: &Value) -> Result<Value, RpcError> {
+        let (id, job) = self.job(params)?;
+        let (groups, started) = match &*job.state.lock().unwrap() {
+            JobState::Finished { groups, started } => (groups.clone(), *started),
+            _ => return Err(RpcError::failed(format!("Job {} is not finished", id))),
+        };
+        let op = match params.get("op").and_then(Value::as_str) {
+            Some("remove") => DedupeOp::Remove,
+            Some("soft_link") => DedupeOp::SoftLink,
+            Some("hard_link") => DedupeOp::HardLink,
+            Some("move") => {
+                let target = params
+                    .get("target")
+                    .and_then(Value::as_str)
+                    .ok_or_else(|| RpcError::invalid_params("Missing target"))?;
+                DedupeOp::Move {
+                    target: Arc::new(Path::from(target)),
+                    cross_device: true,
+                }
+            }
+            Some(op) => return Err(RpcError::invalid_params(format!("Unknown op: {}", op))),
+            None => return Err(RpcError::invalid_params("Missing op")),
+        };
+        let args = string_array(params, "args")?;
+        let mut config = DedupeConfig::from_iter_safe(iter::once("dedupe".to_owned()).chain(args))
+            .map_err(|e| RpcError::invalid_params(error_cause(&e.message)))?;
+        config.rf_over = Some(job.rf_over);
+        config.normalize_patterns();
+        if config.modified_before.is_none() {
+            config.modified_before = Some(started);
+        }
+
+        let open_log = |path: &Option<PathBuf>| match path {
+            Some(path) => AuditLog::open(path).map(Some).map_err(RpcError::failed),
+            None => Ok(None),
+        };
+        let audit = open_log(&config.audit_log)?;
+        let failures = open_log(&config.failed_log)?;
+
+        let script = dedupe(groups, op, &config, &self.log);
+        if config.dry_run {
+            let mut out = Vec::new();
+            let result = log_script(script, &mut out).map_err(RpcError::failed)?;
+            let script: Vec<_> = String::from_utf8_lossy(&out)
+                .lines()
+                .map(|l| l.to_owned())
+                .collect();
+            Ok(json!({
+                "processed_count": result.processed_count,
+                "reclaimed_bytes": result.reclaimed_space.0,
+                "script": script,
+            }))
+        } else {
+            let options = RunOptions {
+                audit: audit.as_ref(),
+                failures: failures.as_ref(),
+                retries: config.retries,
+                retry_delay: config.retry_delay,
+                serialize_network: config.serialize_network,
+                max_open_files: config.max_open_files,
+                helper: None,
+            };
+            let result = run_script(script, &options, &self.log);
+            Ok(json!({
+                "processed_count": result.processed_count,
+                "reclaimed_bytes": result.reclaimed_space.0,
+                "failed_count": result.failed_count,
+            }))
+        }
+    }
+
+    /// Answers the requests received over the connection until the client disconnects
+    fn serve_connection(self: &Arc<Self>, stream: UnixStream) -> io::Result<()> {
+        let mut writer = stream.try_clone()?;
+        for line in BufReader::new(stream).lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            if let Some(response) = self.handle(&line) {
+                writeln!(writer, "{}", response)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn job_status(id: u64, job: &Job) -> Value {
+    match &*job.state.lock().unwrap() {
+        JobState::Running => {
+            let progress = job.progress.snapshot();
+            json!({
+                "job": id,
+                "state": "running",
+                "stage": progress.stage,
+                "bytes_done": progress.bytes_done,
+                "bytes_total": progress.bytes_total,
+                "eta_secs": progress.eta.map(|eta| eta.as_secs()),
+            })
+        }
+        JobState::Finished { groups, .. } => json!({
+            "job": id,
+            "state": "finished",
+            "group_count": groups.len(),
+            "file_count": groups.iter().map(|g| g.files.len()).sum::<usize>(),
+        }),
+        JobState::Failed(e) => json!({ "job": id, "state": "failed", "error": e }),
+        JobState::Cancelled => json!({ "job": id, "state": "cancelled" }),
+    }
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> String {
+    let response = match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err(e) => json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": e.code, "message": e.message },
+        }),
+    };
+    response.to_string()
+}
+
+/// Reads an optional array of strings from the request parameters
+fn string_array(params: &Value, name: &str) -> Result<Vec<String>, RpcError> {
+    match params.get(name) {
+        None => Ok(vec![]),
+        Some(Value::Array(values)) => values
+            .iter()
+            .map(|v| v.as_str().map(|s| s.to_owned()))
+            .collect::<Option<_>>()
+            .ok_or_else(|| RpcError::invalid_params(format!("{} must be strings", name))),
+        Some(_) => Err(RpcError::invalid_params(format!(
+            "{} must be an array",
+            name
+        ))),
+    }
+}
+
+/// Joins the lines of a clap error into one, skipping the usage information
+fn error_cause(message: &str) -> String {
+    message
+        .trim_start_matches("error: ")
+        .lines()
+        .take_while(|l| !l.starts_with("USAGE:"))
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty())
+        .join(" ")
+}
+
+/// Creates the directory of the files of the daemon, accessible only to the current user,
+/// so other users can't read the cached hashes or replace the files
+fn create_state_dir() -> io::Result<TempDir> {
+    let dir = tempfile::Builder::new()
+        .prefix("fclones-daemon-")
+        .tempdir()?;
+    fs::set_permissions(dir.path(), fs::Permissions::from_mode(0o700))?;
+    Ok(dir)
+}
+
+/// Removes the state directory when the daemon is terminated by a signal.
+/// Must be called before spawning other threads, so that the signals are delivered
+/// to the waiting thread only.
+fn remove_on_exit(state_dir: &TempDir, log: &Arc<Log>) -> io::Result<()> {
+    let mut signals = SigSet::empty();
+    signals.add(Signal::SIGINT);
+    signals.add(Signal::SIGTERM);
+    signals.add(Signal::SIGHUP);
+    signals
+        .thread_block()
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    let path = state_dir.path().to_path_buf();
+    let log = log.clone();
+    thread::Builder::new()
+        .name("fclones-signals".to_owned())
+        .spawn(move || {
+            let signal = signals.wait();
+            if let Err(e) = fs::remove_dir_all(&path) {
+                log.warn(format!("Failed to remove {}: {}", path.display(), e));
+            }
+            log.info(format!("Stopped by {:?}", signal));
+            std::process::exit(0);
+        })?;
+    Ok(())
+}
+
+/// Listens on the socket given in the config and serves the requests until the process
+/// is terminated
+pub fn serve(config: &DaemonConfig, log: Arc<Log>) -> Result<(), Error> {
+    let socket = &config.socket;
+    if socket.exists() {
+        if UnixStream::connect(socket).is_ok() {
+            return Err(Error::new(format!(
+                "Another daemon is listening on {}",
+                socket.display()
+            )));
+        }
+        // The socket was left behind by a daemon that didn't exit cleanly
+        fs::remove_file(socket)
+            .map_err(|e| format!("Failed to remove {}: {}", socket.display(), e))?;
+    }
+    let listener = UnixListener::bind(socket)
+        .map_err(|e| format!("Failed to listen on {}: {}", socket.display(), e))?;
+    let state_dir =
+        create_state_dir().map_err(|e| format!("Failed to create the state directory: {}", e))?;
+    remove_on_exit(&state_dir, &log)
+        .map_err(|e| format!("Failed to handle termination signals: {}", e))?;
+    let cache = state_dir.path().join("cache.json");
+    let server = Arc::new(Server::new(log.clone(), cache, config.max_finished_jobs));
+    log.info(format!("Listening on {}", socket.display()));
+    systemd::ready();
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let server = server.clone();
+                thread::spawn(move || server.serve_connection(stream));
+            }
+            Err(e) => log.warn(format!("Failed to accept connection: {}", e)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::util::test::{with_dir, write_file};
+
+    use super::*;
+
+    fn call(server: &Arc<Server>, request: Value) -> Value {
+        let response = server.handle(&request.to_string()).unwrap();
+        serde_json::from_str(&response).unwrap()
+    }
+
+    fn wait_for_job(server: &Arc<Server>, job: &Value) -> Value {
+        for _ in 0..1000 {
+            let status = call(
+                server,
+                json!({"jsonrpc": "2.0", "id": 1, "method": "status", "params": {"job": job}}),
+            );
+            if status["result"]["state"] != "running" {
+                return status["result"].clone();
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        panic!("Job {} didn't finish", job)
+    }
+
+    #[test]
+    fn scan_and_dedupe() {
+        with_dir("daemon/scan_and_dedupe", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            write_file(&file1, "foo");
+            write_file(&file2, "foo");
+
+            let server = Arc::new(Server::new(
+                Arc::new(Log::new()),
+                root.join("cache.json"),
+                16,
+            ));
+            let root_arg = root.to_str().unwrap();
+            let scan = json!({"jsonrpc": "2.0", "id": 1, "method": "scan",
+                "params": {"args": [root_arg, "--name", "file*"]}});
+            let response = call(&server, scan.clone());
+            let job = response["result"]["job"].clone();
+            let status = wait_for_job(&server, &job);
+            assert_eq!(status["state"], "finished");
+            assert_eq!(status["group_count"], 1);
+            assert!(root.join("cache.json").exists());
+
+            let groups = call(
+                &server,
+                json!({"jsonrpc": "2.0", "id": 2, "method": "groups", "params": {"job": job}}),
+            );
+            assert_eq!(groups["result"][0]["files"].as_array().unwrap().len(), 2);
+
+            let dedupe = call(
+                &server,
+                json!({"jsonrpc": "2.0", "id": 3, "method": "dedupe",
+                    "params": {"job": job, "op": "remove", "args": ["--dry-run"]}}),
+            );
+            assert_eq!(dedupe["result"]["processed_count"], 1);
+            assert!(!dedupe["result"]["script"].as_array().unwrap().is_empty());
+            assert!(file1.exists() && file2.exists());
+
+            // The second scan uses the results of the first one as the baseline
+            let response = call(&server, scan);
+            let status = wait_for_job(&server, &response["result"]["job"]);
+            assert_eq!(status["group_count"], 1);
+        });
+    }
+
+    #[test]
+    fn old_jobs_are_dropped() {
+        with_dir("daemon/old_jobs_are_dropped", |root| {
+            write_file(&root.join("file1"), "foo");
+            let server = Arc::new(Server::new(
+                Arc::new(Log::new()),
+                root.join("cache.json"),
+                1,
+            ));
+            let scan = json!({"jsonrpc": "2.0", "id": 1, "method": "scan",
+                "params": {"args": [root.to_str().unwrap()]}});
+            let job1 = call(&server, scan.clone())["result"]["job"].clone();
+            wait_for_job(&server, &job1);
+            let job2 = call(&server, scan)["result"]["job"].clone();
+            assert_eq!(wait_for_job(&server, &job2)["state"], "finished");
+
+            let status = call(
+                &server,
+                json!({"jsonrpc": "2.0", "id": 2, "method": "status", "params": {"job": job1}}),
+            );
+            assert_eq!(status["error"]["code"], INVALID_PARAMS);
+        });
+    }
+
+    #[test]
+    fn state_dir_is_private() {
+        let dir = create_state_dir().unwrap();
+        let mode = fs::metadata(dir.path()).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+    }
+
+    #[test]
+    fn report_errors() {
+        let server = Arc::new(Server::new(
+            Arc::new(Log::new()),
+            PathBuf::from("cache.json"),
+            16,
+        ));
+        let response = call(&server, json!({"jsonrpc": "2.0", "id": 1, "method": "foo"}));
+        assert_eq!(response["error"]["code"], METHOD_NOT_FOUND);
+        let response = call(
+            &server,
+            json!({"jsonrpc": "2.0", "id": 2, "method": "status", "params": {"job": 7}}),
+        );
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+        let response = call(
+            &server,
+            json!({"jsonrpc": "2.0", "id": 3, "method": "scan", "params": {"args": ["--foo"]}}),
+        );
+        assert_eq!(response["error"]["code"], INVALID_PARAMS);
+        let response = server.handle("{").unwrap();
+        assert!(response.contains(&PARSE_ERROR.to_string()));
+    }
+}
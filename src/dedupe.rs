@@ -2,10 +2,11 @@
 
 use std::cmp::{max, min, Reverse};
 use std::fmt::{Display, Formatter};
-use std::fs::Metadata;
-use std::io::{BufWriter, ErrorKind, Write};
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::ops::{Add, AddAssign};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 use std::{fmt, fs, io};
 
 use chrono::{DateTime, FixedOffset, Local};
@@ -15,23 +16,41 @@ use rand::Rng;
 use rayon::iter::IntoParallelIterator;
 use rayon::iter::ParallelIterator;
 
-use crate::config::{DedupeConfig, Priority};
+use crate::annotation;
+use crate::audit::AuditLog;
+use crate::config::{CrossDevicePolicy, DedupeConfig, Priority, Verify};
 use crate::device::DiskDevices;
-use crate::files::FileLen;
+use crate::fd;
+use crate::files::{
+    available_space, file_hash, file_hash_or_log_err, Caching, FileHash, FileId, FileLen, FilePos,
+};
+use crate::fs_info::probe_hard_links;
+use crate::helper::Helper;
+use crate::io_timeout;
 use crate::lock::FileLock;
-use crate::log::Log;
+use crate::log::{EventKind, Log};
 use crate::path::Path;
-use crate::util::fallible_sort_by_key;
+use crate::summary::DedupeCounters;
+use crate::systemd;
+use crate::trash;
+use crate::util::{fallible_sort_by_key, raw_os_error, with_context};
+use crate::xattr;
 use crate::{Error, FileGroup, TIMESTAMP_FMT};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use sysinfo::DiskType;
 
 /// Defines what to do with redundant files
 #[derive(Clone)]
 pub enum DedupeOp {
     /// Removes redundant files.
     Remove,
-    /// Moves redundant files to a different dir
-    Move(Arc<Path>),
+    /// Moves redundant files to a different dir.
+    /// Files on a different file system than the target dir are copied
+    /// and then removed, unless `cross_device` is false.
+    Move {
+        target: Arc<Path>,
+        cross_device: bool,
+    },
     /// Replaces redundant files with soft-links (ln -s on Unix).
     SoftLink,
     /// Replaces redundant files with hard-links (ln on Unix).
@@ -48,12 +67,8 @@ pub struct FileMetadata {
 impl FileMetadata {
     pub fn new(path: Path) -> io::Result<FileMetadata> {
         let path_buf = path.to_path_buf();
-        let metadata = fs::symlink_metadata(&path_buf).map_err(|e| {
-            io::Error::new(
-                e.kind(),
-                format!("Failed to read metadata of {}: {}", path, e),
-            )
-        })?;
+        let metadata = fs::symlink_metadata(&path_buf)
+            .map_err(|e| with_context(format!("Failed to read metadata of {}: {}", path, e), e))?;
         Ok(FileMetadata { path, metadata })
     }
 
@@ -65,9 +80,22 @@ impl FileMetadata {
 
     #[cfg(windows)]
     pub fn device_id(&self) -> Option<u64> {
-        use crate::files::FileId;
         FileId::new(&self.path).ok().map(|f| f.device)
     }
+
+    #[cfg(unix)]
+    pub(crate) fn file_id(&self) -> Option<FileId> {
+        use std::os::unix::fs::MetadataExt;
+        Some(FileId {
+            inode: self.metadata.ino() as u128,
+            device: self.metadata.dev(),
+        })
+    }
+
+    #[cfg(windows)]
+    pub(crate) fn file_id(&self) -> Option<FileId> {
+        FileId::new(&self.path).ok()
+    }
 }
 
 impl Display for FileMetadata {
@@ -76,23 +104,38 @@ impl Display for FileMetadata {
     }
 }
 
-/// Portable abstraction for commands used to remove duplicates
+/// Portable abstraction for commands used to remove duplicates.
+/// Each command carries the hash of the group the changed file belongs to.
 pub enum FsCommand {
     Remove {
         file: FileMetadata,
+        /// A copy of the file that is kept, if known.
+        /// The privileged helper removes only files with a kept copy of the same contents.
+        kept: Option<Arc<FileMetadata>>,
+        file_hash: FileHash,
     },
     Move {
         source: FileMetadata,
         target: Path,
         use_rename: bool, // try to move the file directly by issuing fs rename command
+        allow_copy: bool, // fall back to copying the file when renaming across file systems
+        file_hash: FileHash,
     },
     SoftLink {
         target: Arc<FileMetadata>,
         link: FileMetadata,
+        file_hash: FileHash,
     },
     HardLink {
         target: Arc<FileMetadata>,
         link: FileMetadata,
+        file_hash: FileHash,
+    },
+    /// Replaces the file with a copy of the target sharing its data (`cp --reflink` on Linux)
+    RefLink {
+        target: Arc<FileMetadata>,
+        link: FileMetadata,
+        file_hash: FileHash,
     },
 }
 
@@ -100,7 +143,7 @@ impl FsCommand {
     fn remove(path: &Path) -> io::Result<()> {
         let _ = FileLock::new(path)?;
         fs::remove_file(path.to_path_buf())
-            .map_err(|e| io::Error::new(e.kind(), format!("Failed to remove file {}: {}", path, e)))
+            .map_err(|e| with_context(format!("Failed to remove file {}: {}", path, e), e))
     }
 
     #[cfg(unix)]
@@ -113,27 +156,87 @@ impl FsCommand {
         std::os::windows::fs::symlink_file(target, link)
     }
 
-    fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    pub(crate) fn symlink(target: &Path, link: &Path) -> io::Result<()> {
         Self::symlink_internal(&target.to_path_buf(), &link.to_path_buf()).map_err(|e| {
-            io::Error::new(
-                e.kind(),
+            with_context(
                 format!(
                     "Failed to create symbolic link {} -> {}: {}",
                     link, target, e
                 ),
+                e,
             )
         })
     }
 
     fn hardlink(target: &Path, link: &Path) -> io::Result<()> {
-        fs::hard_link(&target.to_path_buf(), &link.to_path_buf()).map_err(|e| {
-            io::Error::new(
-                e.kind(),
+        fs::hard_link(target.to_path_buf(), link.to_path_buf()).map_err(|e| {
+            with_context(
                 format!("Failed to create hard link {} -> {}: {}", link, target, e),
+                e,
             )
         })
     }
 
+    /// Creates `link` as a copy of `target` sharing the data blocks with it.
+    /// Fails if the file system doesn't support reflinks or `link` exists.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn reflink(target: &Path, link: &Path) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+        /// `_IOW(0x94, 9, int)`
+        const FICLONE: u32 = 0x4004_9409;
+
+        let error = |e: io::Error| {
+            with_context(
+                format!("Failed to create reflink {} -> {}: {}", link, target, e),
+                e,
+            )
+        };
+        let _fds = fd::acquire(2);
+        let src = File::open(target.to_path_buf()).map_err(error)?;
+        let dest = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(link.to_path_buf())
+            .map_err(error)?;
+        if unsafe { libc::ioctl(dest.as_raw_fd(), FICLONE as _, src.as_raw_fd()) } != 0 {
+            let e = io::Error::last_os_error();
+            drop(dest);
+            let _ = fs::remove_file(link.to_path_buf());
+            return Err(error(e));
+        }
+        Ok(())
+    }
+
+    /// Reflinks are supported on Linux only
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn reflink(target: &Path, link: &Path) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            format!(
+                "Failed to create reflink {} -> {}: Reflinks are supported on Linux only",
+                link, target
+            ),
+        ))
+    }
+
+    /// Replaces `link` with a reflinked copy of `target`,
+    /// keeping the permissions of the replaced file
+    fn reflink_copy(target: &Path, link: &FileMetadata) -> io::Result<()> {
+        Self::reflink(target, &link.path)?;
+        let result = fs::set_permissions(link.path.to_path_buf(), link.metadata.permissions())
+            .map_err(|e| {
+                with_context(
+                    format!("Failed to set permissions of {}: {}", link.path, e),
+                    e,
+                )
+            })
+            .and_then(|_| Self::copy_security_labels(target, &link.path));
+        if result.is_err() {
+            let _ = fs::remove_file(link.path.to_path_buf());
+        }
+        result
+    }
+
     fn check_can_rename(source: &Path, target: &Path) -> io::Result<()> {
         if target.to_path_buf().exists() {
             return Err(io::Error::new(
@@ -149,47 +252,26 @@ impl FsCommand {
     }
 
     fn mkdirs(path: &Path) -> io::Result<()> {
-        fs::create_dir_all(path.to_path_buf()).map_err(|e| {
-            io::Error::new(
-                e.kind(),
-                format!("Failed to create directory {}: {}", path, e),
-            )
-        })
+        fs::create_dir_all(path.to_path_buf())
+            .map_err(|e| with_context(format!("Failed to create directory {}: {}", path, e), e))
     }
 
     /// Renames/moves a file from one location to another.
     /// If the target exists, it would be overwritten.
     fn unsafe_rename(source: &Path, target: &Path) -> io::Result<()> {
-        fs::rename(&source.to_path_buf(), &target.to_path_buf()).map_err(|e| {
-            io::Error::new(
-                e.kind(),
+        fs::rename(source.to_path_buf(), target.to_path_buf()).map_err(|e| {
+            with_context(
                 format!(
                     "Failed to rename file from {} to {}: {}",
                     source,
                     target.display(),
                     e
                 ),
+                e,
             )
         })
     }
 
-    /// Copies a file from one location to another.
-    /// If the target exists, it would be overwritten.
-    fn unsafe_copy(source: &Path, target: &Path) -> io::Result<()> {
-        fs::copy(&source.to_path_buf(), &target.to_path_buf()).map_err(|e| {
-            io::Error::new(
-                e.kind(),
-                format!(
-                    "Failed to copy file from {} to {}: {}",
-                    source,
-                    target.display(),
-                    e
-                ),
-            )
-        })?;
-        Ok(())
-    }
-
     /// Moves the file from one location to another by single `fs::rename` command.
     /// Fails if target exists.
     fn move_rename(source: &Path, target: &Path) -> io::Result<()> {
@@ -199,19 +281,196 @@ impl FsCommand {
         Ok(())
     }
 
+    /// Moves the file with `rename`. If the rename fails because the target is located
+    /// on another file system and `allow_copy` is set, copies the file and removes the original.
+    /// Any other error is returned as is, so a file is never copied needlessly.
+    fn move_file(
+        source: &Path,
+        target: &Path,
+        allow_copy: bool,
+        rename: impl FnOnce(&Path, &Path) -> io::Result<()>,
+    ) -> io::Result<()> {
+        match rename(source, target) {
+            Err(e) if allow_copy && Self::is_cross_device(&e) => Self::move_copy(source, target),
+            result => result,
+        }
+    }
+
+    /// Returns true if the error means a file can't be renamed to another file system
+    #[cfg(unix)]
+    fn is_cross_device(e: &io::Error) -> bool {
+        raw_os_error(e) == Some(libc::EXDEV)
+    }
+
+    #[cfg(windows)]
+    fn is_cross_device(e: &io::Error) -> bool {
+        /// `ERROR_NOT_SAME_DEVICE`
+        const NOT_SAME_DEVICE: i32 = 17;
+        raw_os_error(e) == Some(NOT_SAME_DEVICE)
+    }
+
     /// Moves the file by copying it first to another location and then removing the original.
     /// Fails if target exists.
+    ///
+    /// The source is removed only after the copy is complete, so an interruption at any point
+    /// leaves at least one complete copy.
     fn move_copy(source: &Path, target: &Path) -> io::Result<()> {
+        Self::copy(source, target)?;
+        Self::remove(source)?;
+        Ok(())
+    }
+
+    /// Copies the file to another location, creating the missing parent directories.
+    /// Fails if target exists.
+    ///
+    /// The data are copied to a temporary file next to the target first. The temporary file
+    /// is compared with the source and renamed to the target only if the copy is complete.
+    /// The temporary file has a fixed name, so an interrupted copy is resumed by the next run.
+    pub(crate) fn copy(source: &Path, target: &Path) -> io::Result<()> {
         Self::check_can_rename(source, target)?;
         Self::mkdirs(target.parent().unwrap())?;
-        Self::unsafe_copy(source, target)?;
-        Self::remove(source)?;
+        let part = Self::partial_file(target);
+        Self::resume_copy(source, &part)?;
+        Self::copy_security_labels(source, &part)?;
+        if !Self::same_contents(source, &part)? {
+            let _ = fs::remove_file(part.to_path_buf());
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Failed to copy {} to {}: Copied data differ from the source",
+                    source, target
+                ),
+            ));
+        }
+        Self::check_can_rename(source, target)?;
+        Self::unsafe_rename(&part, target)?;
+        Ok(())
+    }
+
+    /// Returns the path of the temporary file holding the data copied to `target`
+    /// while moving a file between file systems
+    fn partial_file(target: &Path) -> Path {
+        let mut name = target
+            .file_name()
+            .expect("must be a regular file with a name");
+        name.push(".fclones-part");
+        match target.parent() {
+            Some(parent) => parent.join(Path::from(name)),
+            None => Path::from(name),
+        }
+    }
+
+    /// Copies a file from one location to another.
+    /// If the target already holds a part of the data, only the remaining data are copied.
+    fn resume_copy(source: &Path, target: &Path) -> io::Result<()> {
+        let error = |e: io::Error| {
+            with_context(
+                format!("Failed to copy file from {} to {}: {}", source, target, e),
+                e,
+            )
+        };
+        let _fds = fd::acquire(2);
+        let mut src = File::open(source.to_path_buf()).map_err(error)?;
+        let src_metadata = src.metadata().map_err(error)?;
+        let mut dst = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(target.to_path_buf())
+            .map_err(error)?;
+        let mut copied = dst.metadata().map_err(error)?.len();
+        if copied > src_metadata.len() {
+            dst.set_len(0).map_err(error)?;
+            copied = 0;
+        }
+        src.seek(SeekFrom::Start(copied)).map_err(error)?;
+        dst.seek(SeekFrom::Start(copied)).map_err(error)?;
+        // Not using io::copy, so the watchdog knows the copying makes progress:
+        let mut buf = vec![0; 64 * 1024];
+        loop {
+            let len = match src.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(error(e)),
+            };
+            dst.write_all(&buf[..len]).map_err(error)?;
+            systemd::tick(len as u64);
+        }
+        dst.sync_all().map_err(error)?;
+        fs::set_permissions(target.to_path_buf(), src_metadata.permissions()).map_err(error)?;
+        Ok(())
+    }
+
+    /// Sets the security labels of the target to the labels of the source, so the SELinux
+    /// context and file capabilities survive moving the file between file systems.
+    fn copy_security_labels(source: &Path, target: &Path) -> io::Result<()> {
+        let error = |e: io::Error| {
+            with_context(
+                format!(
+                    "Failed to copy security labels from {} to {}: {}",
+                    source, target, e
+                ),
+                e,
+            )
+        };
+        for (name, value) in xattr::read_security_labels(source).map_err(error)? {
+            match xattr::write_attribute(target, &name, &value) {
+                Ok(()) => {}
+                // File systems without extended attributes label all files with the SELinux
+                // context given at mount time, which is the correct context for them
+                Err(e) if name == "security.selinux" && xattr::is_not_supported(&e) => {}
+                Err(e) => return Err(error(e)),
+            }
+        }
         Ok(())
     }
 
+    /// Returns true if both files have the same contents
+    pub(crate) fn same_contents(path1: &Path, path2: &Path) -> io::Result<bool> {
+        let open = |path: &Path| {
+            File::open(path.to_path_buf())
+                .map_err(|e| with_context(format!("Failed to open file {}: {}", path, e), e))
+        };
+        let _fds = fd::acquire(2);
+        let mut file1 = open(path1)?;
+        let mut file2 = open(path2)?;
+        Self::same_file_contents(&mut file1, &mut file2)
+    }
+
+    /// Returns true if both open files have the same contents from the current positions on
+    pub(crate) fn same_file_contents(file1: &mut File, file2: &mut File) -> io::Result<bool> {
+        fn read_chunk(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+            let mut len = 0;
+            while len < buf.len() {
+                match file.read(&mut buf[len..]) {
+                    Ok(0) => break,
+                    Ok(n) => len += n,
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(len)
+        }
+
+        let mut buf1 = vec![0; 64 * 1024];
+        let mut buf2 = vec![0; 64 * 1024];
+        loop {
+            let len1 = read_chunk(file1, &mut buf1)?;
+            let len2 = read_chunk(file2, &mut buf2)?;
+            systemd::tick((len1 + len2) as u64);
+            if buf1[..len1] != buf2[..len2] {
+                return Ok(false);
+            }
+            if len1 == 0 {
+                return Ok(true);
+            }
+        }
+    }
+
     /// Returns a random temporary file name in the same directory, guaranteed to not collide with
     /// any other file in the same directory
-    fn temp_file(path: &Path) -> Path {
+    pub(crate) fn temp_file(path: &Path) -> Path {
         let mut name = path
             .file_name()
             .expect("must be a regular file with a name");
@@ -259,43 +518,87 @@ impl FsCommand {
     /// Executes the command and returns the number of bytes reclaimed
     pub fn execute(&self, log: &Log) -> io::Result<FileLen> {
         match self {
-            FsCommand::Remove { file } => {
+            FsCommand::Remove { file, .. } => {
                 Self::remove(&file.path)?;
                 Ok(FileLen(file.metadata.len()))
             }
-            FsCommand::SoftLink { target, link } => {
+            FsCommand::SoftLink { target, link, .. } => {
                 Self::safe_remove(&link.path, |link| Self::symlink(&target.path, link), log)?;
                 Ok(FileLen(link.metadata.len()))
             }
-            FsCommand::HardLink { target, link } => {
+            FsCommand::HardLink { target, link, .. } => {
                 Self::safe_remove(&link.path, |link| Self::hardlink(&target.path, link), log)?;
                 Ok(FileLen(link.metadata.len()))
             }
+            FsCommand::RefLink { target, link, .. } => {
+                Self::safe_remove(&link.path, |_| Self::reflink_copy(&target.path, link), log)?;
+                Ok(FileLen(link.metadata.len()))
+            }
             FsCommand::Move {
                 source,
                 target,
                 use_rename,
+                allow_copy,
+                ..
             } => {
                 let len = FileLen(source.metadata.len());
-                if *use_rename && Self::move_rename(&source.path, target).is_ok() {
-                    return Ok(len);
+                if *use_rename {
+                    Self::move_file(&source.path, target, *allow_copy, Self::move_rename)?;
+                } else {
+                    Self::move_copy(&source.path, target)?;
                 }
-                Self::move_copy(&source.path, target)?;
                 Ok(len)
             }
         }
     }
 
-    /// Returns how much disk space running this command would reclaim
-    pub fn space_to_reclaim(&self) -> FileLen {
+    /// Returns the name of the action, the file removed, moved or replaced by a link,
+    /// the target of the move or of the link, and the hash of the file
+    pub(crate) fn describe(&self) -> (&'static str, &FileMetadata, Option<&Path>, &FileHash) {
+        match self {
+            FsCommand::Remove {
+                file, file_hash, ..
+            } => ("remove", file, None, file_hash),
+            FsCommand::Move {
+                source,
+                target,
+                file_hash,
+                ..
+            } => ("move", source, Some(target), file_hash),
+            FsCommand::SoftLink {
+                target,
+                link,
+                file_hash,
+            } => ("soft_link", link, Some(&target.path), file_hash),
+            FsCommand::HardLink {
+                target,
+                link,
+                file_hash,
+            } => ("hard_link", link, Some(&target.path), file_hash),
+            FsCommand::RefLink {
+                target,
+                link,
+                file_hash,
+            } => ("reflink", link, Some(&target.path), file_hash),
+        }
+    }
+
+    /// Returns the file removed, moved or replaced by a link by this command
+    fn changed_file(&self) -> &FileMetadata {
         match self {
             FsCommand::Remove { file, .. }
             | FsCommand::SoftLink { link: file, .. }
             | FsCommand::HardLink { link: file, .. }
-            | FsCommand::Move { source: file, .. } => FileLen(file.metadata.len()),
+            | FsCommand::RefLink { link: file, .. }
+            | FsCommand::Move { source: file, .. } => file,
         }
     }
 
+    /// Returns how much disk space running this command would reclaim
+    pub fn space_to_reclaim(&self) -> FileLen {
+        FileLen(self.changed_file().metadata.len())
+    }
+
     /// Formats the command as a string that can be pasted to a Unix shell (e.g. bash)
     #[cfg(unix)]
     pub fn to_shell_str(&self) -> Vec<String> {
@@ -321,18 +624,33 @@ impl FsCommand {
                 result.push(format!("ln {} {}", target, link));
                 result.push(format!("rm {}", tmp));
             }
+            FsCommand::RefLink { target, link, .. } => {
+                let tmp = Self::temp_file(&link.path);
+                let target = target.path.shell_quote();
+                let link = link.path.shell_quote();
+                result.push(format!("mv {} {}", link, tmp));
+                result.push(format!("cp --reflink=always {} {}", target, link));
+                result.push(format!("chmod --reference={} {}", tmp, link));
+                result.push(format!("rm {}", tmp));
+            }
             FsCommand::Move {
                 source,
                 target,
                 use_rename,
+                ..
             } => {
+                let part = Self::partial_file(target).shell_quote();
                 let source = source.path.shell_quote();
                 let target = target.shell_quote();
                 if *use_rename {
                     result.push(format!("mv {} {}", &source, &target));
                 } else {
-                    result.push(format!("cp {} {}", &source, &target));
-                    result.push(format!("rm {}", &source));
+                    result.push(format!(
+                        "cp {src} {part} && cmp -s {src} {part} && mv {part} {tgt} && rm {src}",
+                        src = &source,
+                        part = &part,
+                        tgt = &target
+                    ));
                 }
             }
         }
@@ -363,18 +681,32 @@ impl FsCommand {
                 result.push(format!("mklink /H {} {}", target, link));
                 result.push(format!("del {}", tmp));
             }
+            FsCommand::RefLink { target, link, .. } => {
+                let tmp = Self::temp_file(&link.path);
+                let target = target.path.shell_quote();
+                let link = link.path.shell_quote();
+                result.push(format!("move {} {}", link, tmp));
+                result.push(format!("copy /b {} {}", target, link));
+                result.push(format!("del {}", tmp));
+            }
             FsCommand::Move {
                 source,
                 target,
                 use_rename,
+                ..
             } => {
+                let part = Self::partial_file(target).shell_quote();
                 let source = source.path.shell_quote();
                 let target = target.shell_quote();
                 if *use_rename {
                     result.push(format!("move {} {}", &source, &target));
                 } else {
-                    result.push(format!("copy {} {}", &source, &target));
-                    result.push(format!("del {}", &source));
+                    result.push(format!(
+                        "copy /b {src} {part} && fc /b {src} {part} >nul && move {part} {tgt} && del {src}",
+                        src = &source,
+                        part = &part,
+                        tgt = &target
+                    ));
                 }
             }
         }
@@ -387,6 +719,8 @@ impl FsCommand {
 pub struct DedupeResult {
     pub processed_count: u64,
     pub reclaimed_space: FileLen,
+    /// Number of commands that failed
+    pub failed_count: u64,
 }
 
 impl Add<DedupeResult> for DedupeResult {
@@ -396,6 +730,7 @@ impl Add<DedupeResult> for DedupeResult {
         DedupeResult {
             processed_count: self.processed_count + rhs.processed_count,
             reclaimed_space: self.reclaimed_space + rhs.reclaimed_space,
+            failed_count: self.failed_count + rhs.failed_count,
         }
     }
 }
@@ -404,6 +739,7 @@ impl AddAssign for DedupeResult {
     fn add_assign(&mut self, rhs: Self) {
         self.processed_count += rhs.processed_count;
         self.reclaimed_space += rhs.reclaimed_space;
+        self.failed_count += rhs.failed_count;
     }
 }
 
@@ -422,20 +758,25 @@ fn was_modified(files: &[FileMetadata], after: DateTime<FixedOffset>, log: &Log)
             Ok(file_timestamp) => {
                 let file_timestamp: DateTime<Local> = file_timestamp.into();
                 if file_timestamp > after {
-                    log.warn(format!(
-                        "File {} was updated after {} (at {})",
+                    log.warn_file(
+                        EventKind::Changed,
                         p,
-                        after.format(TIMESTAMP_FMT),
-                        file_timestamp.format(TIMESTAMP_FMT)
-                    ));
+                        format!(
+                            "File {} was updated after {} (at {})",
+                            p,
+                            after.format(TIMESTAMP_FMT),
+                            file_timestamp.format(TIMESTAMP_FMT)
+                        ),
+                    );
                     result = true;
                 }
             }
             Err(e) => {
-                log.warn(format!(
-                    "Failed to read modification time of file {}: {}",
-                    p, e
-                ));
+                log.warn_file(
+                    EventKind::Unreadable,
+                    p,
+                    format!("Failed to read modification time of file {}: {}", p, e),
+                );
                 result = true;
             }
         }
@@ -443,11 +784,25 @@ fn was_modified(files: &[FileMetadata], after: DateTime<FixedOffset>, log: &Log)
     result
 }
 
+/// Returns the birth time of the file (`btime`).
+/// Falls back to the modification time if the platform or the file system
+/// doesn't record the birth time.
+fn birth_time(m: &FileMetadata) -> Result<SystemTime, String> {
+    m.metadata
+        .created()
+        .or_else(|_| m.metadata.modified())
+        .map_err(|e| format!("Failed to read creation time of file {}: {}", m.path, e))
+}
+
 /// Sort files so that files with highest priority (newest, most recently updated,
 /// recently accessed, etc) are sorted last.
 /// In cases when metadata of a file cannot be accessed, an error message is pushed
 /// in the result vector and such file is placed at the beginning of the list.
-fn sort_by_priority(files: &mut Vec<FileMetadata>, priority: &Priority) -> Vec<Error> {
+fn sort_by_priority(
+    files: &mut [FileMetadata],
+    priority: &Priority,
+    config: &DedupeConfig,
+) -> Vec<Error> {
     let errors = match priority {
         Priority::Newest => fallible_sort_by_key(files, |m| {
             m.metadata
@@ -460,6 +815,8 @@ fn sort_by_priority(files: &mut Vec<FileMetadata>, priority: &Priority) -> Vec<E
                 .map(Reverse)
                 .map_err(|e| format!("Failed to read creation time of file {}: {}", m.path, e))
         }),
+        Priority::NewestCreated => fallible_sort_by_key(files, birth_time),
+        Priority::OldestCreated => fallible_sort_by_key(files, |m| birth_time(m).map(Reverse)),
         Priority::MostRecentlyModified => fallible_sort_by_key(files, |m| {
             m.metadata
                 .modified()
@@ -490,6 +847,52 @@ fn sort_by_priority(files: &mut Vec<FileMetadata>, priority: &Priority) -> Vec<E
             files.sort_by_key(|m| Reverse(m.path.component_count()));
             vec![]
         }
+        Priority::ShortestPath => {
+            files.sort_by_cached_key(|m| Reverse(m.path.to_path_buf().as_os_str().len()));
+            vec![]
+        }
+        Priority::LongestPath => {
+            files.sort_by_cached_key(|m| m.path.to_path_buf().as_os_str().len());
+            vec![]
+        }
+        Priority::PreferredPath => {
+            files.sort_by_cached_key(|m| {
+                let path = m.path.to_path_buf();
+                config
+                    .prefer_path_patterns
+                    .iter()
+                    .map(|p| !p.matches_path(&path))
+                    .collect::<Vec<_>>()
+            });
+            vec![]
+        }
+        // Free space is read once per device, before sorting, so the files on the same
+        // file system compare as equal and the following priorities can break the tie
+        Priority::FreeSpace => {
+            let mut errors = Vec::new();
+            let mut free_space = HashMap::new();
+            files.sort_by_cached_key(|m| {
+                let device = FileId::new(&m.path).ok().map(|id| id.device);
+                let space = match device.and_then(|d| free_space.get(&d)) {
+                    Some(space) => *space,
+                    None => {
+                        let space = available_space(&m.path).map_err(|e| {
+                            errors.push(format!(
+                                "Failed to read free space of the file system of {}: {}",
+                                m.path, e
+                            ))
+                        });
+                        if let Some(device) = device {
+                            free_space.insert(device, space.ok());
+                        }
+                        space.ok()
+                    }
+                };
+                // Copies on the fullest file systems go last, to be dropped first
+                space.map(Reverse)
+            });
+            errors
+        }
     };
     errors.into_iter().map(Error::from).collect()
 }
@@ -537,28 +940,221 @@ fn may_drop(path: &Path, config: &DedupeConfig) -> bool {
         || matches_any_path()
 }
 
+/// Information about the file systems shared by the scripts of all groups
+struct ScriptContext<'a> {
+    devices: DiskDevices,
+    /// If false, the file systems must not be modified, so hard link support is not probed
+    probe_hard_links: bool,
+    /// Results of probing hard link support on network file systems, by mount point
+    hard_links: Mutex<HashMap<Path, bool>>,
+    /// If true, files are not replaced with links to files with different security labels
+    check_security_labels: bool,
+    /// Effective user id of the process, if the kernel restricts creating hard links
+    /// to files owned by other users (`fs.protected_hardlinks`)
+    protected_hard_links_uid: Option<u32>,
+    /// Counts the hard links skipped, because the kernel would refuse to create them
+    counters: Option<Arc<DedupeCounters>>,
+    /// What to do with files that can't be hard linked, because they are on another file system
+    cross_device: CrossDevicePolicy,
+    log: &'a Log,
+}
+
+/// Returns the effective user id of the process if Linux restricts creating hard links
+/// to files owned by other users, unless the process has the `CAP_FOWNER` capability,
+/// which lifts the restriction.
+#[cfg(target_os = "linux")]
+fn protected_hard_links_uid() -> Option<u32> {
+    let enabled =
+        fs::read_to_string("/proc/sys/fs/protected_hardlinks").map_or(false, |s| s.trim() == "1");
+    (enabled && !has_cap_fowner()).then(|| nix::unistd::geteuid().as_raw())
+}
+
+/// Returns true if `CAP_FOWNER` is in the effective capability set of the process.
+/// If the set can't be read, assumes the capability is missing.
+#[cfg(target_os = "linux")]
+fn has_cap_fowner() -> bool {
+    const CAP_FOWNER: u32 = 3;
+    let status = fs::read_to_string("/proc/self/status").unwrap_or_default();
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .map_or(false, |caps| caps & (1 << CAP_FOWNER) != 0)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn protected_hard_links_uid() -> Option<u32> {
+    None
+}
+
+impl ScriptContext<'_> {
+    /// Returns the reason why the kernel would refuse to create a hard link to the file
+    /// because of `fs.protected_hardlinks`, or `None` if the link is permitted.
+    /// The owner of a file may always link to it. Other users may link only to files
+    /// they can read and write, which are neither setuid nor executable setgid.
+    /// Like the kernel, checks the access with the effective ids of the process.
+    #[cfg(target_os = "linux")]
+    fn hard_link_restriction(&self, target: &FileMetadata) -> Option<&'static str> {
+        use std::os::unix::fs::MetadataExt;
+
+        let uid = self.protected_hard_links_uid?;
+        let m = &target.metadata;
+        if m.uid() == uid {
+            return None;
+        }
+        let setgid_exec = libc::S_ISGID | libc::S_IXGRP;
+        if m.mode() & libc::S_ISUID != 0 {
+            Some("it is a setuid file of another user")
+        } else if m.mode() & setgid_exec == setgid_exec {
+            Some("it is an executable setgid file of another user")
+        } else if !Self::accessible(&target.path) {
+            Some("it belongs to another user and is not readable and writable by the current user")
+        } else {
+            None
+        }
+    }
+
+    /// Returns true if the process can read and write the file with its effective ids
+    #[cfg(target_os = "linux")]
+    fn accessible(path: &Path) -> bool {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let path = match CString::new(path.to_path_buf().as_os_str().as_bytes()) {
+            Ok(path) => path,
+            Err(_) => return false,
+        };
+        let mode = libc::R_OK | libc::W_OK;
+        unsafe { libc::faccessat(libc::AT_FDCWD, path.as_ptr(), mode, libc::AT_EACCESS) == 0 }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn hard_link_restriction(&self, _target: &FileMetadata) -> Option<&'static str> {
+        None
+    }
+
+    /// Returns false if the kernel would refuse to create a hard link to `target`.
+    /// Such files are recorded as filtered and counted, so a single summary warning can be
+    /// logged at the end, and the large numbers of them in multi-user trees don't drown
+    /// the other warnings.
+    fn hard_link_permitted(&self, target: &FileMetadata, link: &Path) -> bool {
+        match self.hard_link_restriction(target) {
+            Some(reason) => {
+                self.log.filtered_file(
+                    link,
+                    format!(
+                        "Hard link to {} not permitted by fs.protected_hardlinks: {}",
+                        target.path, reason
+                    ),
+                );
+                if let Some(counters) = &self.counters {
+                    counters.add_not_permitted_link();
+                }
+                false
+            }
+            None => true,
+        }
+    }
+
+    /// Returns false if the file is located on a network file system that doesn't support
+    /// hard links. Support is checked once per mount point, by creating a hard link to
+    /// a temporary file in the directory of the first file checked.
+    fn hard_links_supported(&self, file: &Path) -> bool {
+        if !self.probe_hard_links || !self.devices.get_by_path(file).network {
+            return true;
+        }
+        let mount_point = self.devices.get_mount_point(file);
+        let mut hard_links = self.hard_links.lock().unwrap();
+        if let Some(supported) = hard_links.get(mount_point) {
+            return *supported;
+        }
+        let supported = match file.parent().map(probe_hard_links) {
+            Some(Ok(supported)) => supported,
+            // If we can't even create a file there, linking will fail anyway with a better message
+            _ => true,
+        };
+        if !supported {
+            self.log.warn(format!(
+                "Network file system mounted at {} does not support hard links. \
+                Creating soft links instead",
+                mount_point
+            ));
+        }
+        hard_links.insert(mount_point.clone(), supported);
+        supported
+    }
+
+    /// Returns true if replacing the `link` file with a link to `target` wouldn't change
+    /// its security labels, e.g. its SELinux context or file capabilities.
+    /// Logs the reason of skipping the file otherwise.
+    fn security_labels_match(&self, target: &Path, link: &Path) -> bool {
+        if !self.check_security_labels {
+            return true;
+        }
+        let labels = |path: &Path| {
+            xattr::read_security_labels(path).map_err(|e| {
+                skip(
+                    self.log,
+                    EventKind::Unreadable,
+                    link,
+                    format!("Failed to read security labels of {}: {}", path, e),
+                )
+            })
+        };
+        let (target_labels, link_labels) = match (labels(target), labels(link)) {
+            (Ok(t), Ok(l)) => (t, l),
+            _ => return false,
+        };
+        if target_labels == link_labels {
+            return true;
+        }
+        let mut names: Vec<_> = target_labels
+            .iter()
+            .chain(link_labels.iter())
+            .filter(|&a| !target_labels.contains(a) || !link_labels.contains(a))
+            .map(|(name, _)| name.to_string_lossy())
+            .collect();
+        names.sort();
+        names.dedup();
+        skip(
+            self.log,
+            EventKind::Filtered,
+            link,
+            format!(
+                "Linking to {} would change its security labels ({}). \
+                Use --ignore-security-labels to link it anyway",
+                target,
+                names.join(", ")
+            ),
+        );
+        false
+    }
+}
+
 struct PartitionedFileGroup {
+    file_hash: FileHash,
     to_keep: Vec<FileMetadata>,
     to_drop: Vec<FileMetadata>,
 }
 
-impl PartitionedFileGroup {
-    /// Returns the destination path where the file should be moved when the
-    /// dedupe mode was selected to move
-    fn move_target(target_dir: &Arc<Path>, source_path: &Path) -> Path {
-        let root = source_path
-            .root()
-            .to_string()
-            .replace("/", "")
-            .replace(":", "");
-        let suffix = source_path.strip_root();
-        if root.is_empty() {
-            target_dir.join(suffix)
-        } else {
-            Arc::new(target_dir.join(Path::from(root))).join(suffix)
-        }
+/// Returns the destination path where the file should be moved or copied to,
+/// preserving the full path of the file under the target directory
+pub(crate) fn move_target(target_dir: &Arc<Path>, source_path: &Path) -> Path {
+    let root = source_path
+        .root()
+        .to_string()
+        .replace(":", "")
+        .trim_start_matches(|c| c == '/' || c == '\\')
+        .to_string();
+    let suffix = source_path.strip_root();
+    if root.is_empty() {
+        target_dir.join(suffix)
+    } else {
+        Arc::new(target_dir.join(Path::from(root))).join(suffix)
     }
+}
 
+impl PartitionedFileGroup {
     fn are_on_same_mount(devices: &DiskDevices, file1: &Path, file2: &Path) -> bool {
         let mount1 = devices.get_mount_point(file1);
         let mount2 = devices.get_mount_point(file2);
@@ -566,7 +1162,8 @@ impl PartitionedFileGroup {
     }
 
     /// Returns a list of commands that would remove redundant files in this group when executed.
-    fn dedupe_script(mut self, strategy: &DedupeOp, devices: &DiskDevices) -> Vec<FsCommand> {
+    fn dedupe_script(mut self, strategy: &DedupeOp, ctx: &ScriptContext) -> Vec<FsCommand> {
+        let devices = &ctx.devices;
         if self.to_drop.is_empty() {
             return vec![];
         }
@@ -576,32 +1173,130 @@ impl PartitionedFileGroup {
         );
         let mut commands = Vec::new();
         let retained_file = Arc::new(self.to_keep.swap_remove(0));
+        // Files kept on each device, which the copies on the same device
+        // can be linked to with `--cross-device local` or `reflink`
+        let mut local_targets: HashMap<Option<u64>, Arc<FileMetadata>> = HashMap::new();
+        local_targets.insert(retained_file.device_id(), retained_file.clone());
+        for f in self.to_keep {
+            local_targets
+                .entry(f.device_id())
+                .or_insert_with(|| Arc::new(f));
+        }
         for dropped_file in self.to_drop {
+            let file_hash = self.file_hash.clone();
             let devices_differ = retained_file.device_id() != dropped_file.device_id();
+            // With `--cross-device skip` or `local`, a file on another device
+            // is not linked to the retained file
+            let links_to_retained = match strategy {
+                DedupeOp::SoftLink => true,
+                DedupeOp::HardLink => {
+                    !devices_differ || ctx.cross_device == CrossDevicePolicy::SoftLink
+                }
+                _ => false,
+            };
+            if links_to_retained
+                && !ctx.security_labels_match(&retained_file.path, &dropped_file.path)
+            {
+                continue;
+            }
             match strategy {
                 DedupeOp::SoftLink => commands.push(FsCommand::SoftLink {
                     target: retained_file.clone(),
                     link: dropped_file,
+                    file_hash,
                 }),
                 // hard links are not supported between files on different file systems
-                DedupeOp::HardLink if devices_differ => commands.push(FsCommand::SoftLink {
-                    target: retained_file.clone(),
-                    link: dropped_file,
-                }),
-                DedupeOp::HardLink => commands.push(FsCommand::HardLink {
-                    target: retained_file.clone(),
-                    link: dropped_file,
+                DedupeOp::HardLink if devices_differ => match ctx.cross_device {
+                    CrossDevicePolicy::SoftLink => commands.push(FsCommand::SoftLink {
+                        target: retained_file.clone(),
+                        link: dropped_file,
+                        file_hash,
+                    }),
+                    CrossDevicePolicy::Skip => skip(
+                        ctx.log,
+                        EventKind::Filtered,
+                        &dropped_file.path,
+                        format!(
+                            "Can't hard link to {} located on another file system",
+                            retained_file.path
+                        ),
+                    ),
+                    CrossDevicePolicy::Local | CrossDevicePolicy::Reflink => {
+                        let device = dropped_file.device_id();
+                        let target = match local_targets.get(&device) {
+                            Some(target) => target.clone(),
+                            None => {
+                                // The first copy on this device is kept for the others
+                                local_targets.insert(device, Arc::new(dropped_file));
+                                continue;
+                            }
+                        };
+                        if !ctx.security_labels_match(&target.path, &dropped_file.path) {
+                            continue;
+                        }
+                        if ctx.cross_device == CrossDevicePolicy::Reflink {
+                            commands.push(FsCommand::RefLink {
+                                target,
+                                link: dropped_file,
+                                file_hash,
+                            })
+                        } else if !ctx.hard_links_supported(&dropped_file.path) {
+                            commands.push(FsCommand::SoftLink {
+                                target,
+                                link: dropped_file,
+                                file_hash,
+                            })
+                        } else if ctx.hard_link_permitted(&target, &dropped_file.path) {
+                            commands.push(FsCommand::HardLink {
+                                target,
+                                link: dropped_file,
+                                file_hash,
+                            })
+                        }
+                    }
+                },
+                // some network file systems don't support hard links at all
+                DedupeOp::HardLink if !ctx.hard_links_supported(&dropped_file.path) => commands
+                    .push(FsCommand::SoftLink {
+                        target: retained_file.clone(),
+                        link: dropped_file,
+                        file_hash,
+                    }),
+                DedupeOp::HardLink => {
+                    if ctx.hard_link_permitted(&retained_file, &dropped_file.path) {
+                        commands.push(FsCommand::HardLink {
+                            target: retained_file.clone(),
+                            link: dropped_file,
+                            file_hash,
+                        })
+                    }
+                }
+                DedupeOp::Remove => commands.push(FsCommand::Remove {
+                    file: dropped_file,
+                    kept: Some(retained_file.clone()),
+                    file_hash,
                 }),
-                DedupeOp::Remove => commands.push(FsCommand::Remove { file: dropped_file }),
-                DedupeOp::Move(target_dir) => {
+                DedupeOp::Move {
+                    target: target_dir,
+                    cross_device,
+                } => {
                     let source = dropped_file;
                     let source_path = &source.path;
                     let use_rename = Self::are_on_same_mount(devices, source_path, target_dir);
-                    let target = Self::move_target(target_dir, source_path);
+                    if !use_rename && !cross_device {
+                        ctx.log.warn(format!(
+                            "Skipping file {}: Moving to {} would cross file systems",
+                            source_path, target_dir
+                        ));
+                        continue;
+                    }
+                    let target = move_target(target_dir, source_path);
                     commands.push(FsCommand::Move {
                         source,
                         target,
                         use_rename,
+                        allow_copy: *cross_device,
+                        file_hash,
                     })
                 }
             }
@@ -610,6 +1305,132 @@ impl PartitionedFileGroup {
     }
 }
 
+/// Number of bytes at the beginning of the files compared by `--verify prefix`
+const VERIFY_PREFIX_LEN: FileLen = FileLen(4096);
+
+/// Size of the buffer used for reading the files when verifying their contents
+const VERIFY_BUF_LEN: usize = 64 * 1024;
+
+/// Logs that the file is left out of its group and won't be acted on
+fn skip(log: &Log, kind: EventKind, path: &Path, reason: impl Display) {
+    log.warn_file(kind, path, format!("Skipping file {}: {}", path, reason));
+}
+
+/// Adds the bytes read when verifying the files to the counters of the summary, if requested
+fn count_hashed_bytes(config: &DedupeConfig, delta: usize) {
+    if let Some(counters) = &config.counters {
+        counters.add_hashed_bytes(delta);
+    }
+}
+
+/// Checks that the files of a group haven't changed since the report was produced,
+/// as thoroughly as requested by `config.verify`.
+///
+/// Returns the files that passed the checks. Files which are known to differ from
+/// the reported contents are skipped. If only some files could have changed, but it is not
+/// known which ones, returns an error, because the whole group must be skipped.
+fn verify(
+    mut files: Vec<FileMetadata>,
+    file_len: FileLen,
+    group_hash: &FileHash,
+    config: &DedupeConfig,
+    log: &Log,
+) -> Result<Vec<FileMetadata>, String> {
+    if config.verify == Verify::None {
+        return Ok(files);
+    }
+
+    // If file has a different length, then we really know it has been modified.
+    // Therefore, it does not belong to the group and we can safely skip it.
+    files.retain(|m| {
+        let len_ok = FileLen(m.metadata.len()) == file_len;
+        if !len_ok {
+            let reason = format!(
+                "Length {} different than the group length {}",
+                m.metadata.len(),
+                file_len.0
+            );
+            skip(log, EventKind::Changed, &m.path, reason);
+        }
+        len_ok
+    });
+
+    // Comparing the whole contents with the reported hash tells exactly which files changed,
+    // so the modification times don't matter.
+    if config.verify == Verify::Full {
+        files.retain(|m| {
+            let progress = |delta: usize| {
+                systemd::tick(delta as u64);
+                count_hashed_bytes(config, delta);
+            };
+            let hash = file_hash_or_log_err(
+                &m.path,
+                FilePos(0),
+                file_len,
+                VERIFY_BUF_LEN,
+                Caching::Sequential.with_timeout(config.io_timeout),
+                progress,
+                log,
+            );
+            match hash {
+                Some(hash) if hash == *group_hash => true,
+                Some(hash) => {
+                    let reason = format!("Hash {} different than the group hash", hash);
+                    skip(log, EventKind::Changed, &m.path, reason);
+                    false
+                }
+                None => {
+                    skip(
+                        log,
+                        EventKind::Unreadable,
+                        &m.path,
+                        "Contents could not be read",
+                    );
+                    false
+                }
+            }
+        });
+        return Ok(files);
+    }
+
+    // Bail out as well if any file has been modified after `config.modified_before`.
+    // We need to skip the whole group, because we don't know if these files are really different.
+    if let Some(max_timestamp) = config.modified_before {
+        if was_modified(&files, max_timestamp, log) {
+            return Err("Some files could be updated since the previous run of fclones".to_owned());
+        }
+    }
+
+    // Files differing in the prefix can't be all identical, but the prefix doesn't tell
+    // which of them changed. Therefore, the whole group must be skipped.
+    if config.verify == Verify::Prefix {
+        let prefix_len = min(file_len, VERIFY_PREFIX_LEN);
+        let mut first: Option<(&Path, FileHash)> = None;
+        for m in files.iter() {
+            let hash = file_hash(
+                &m.path,
+                FilePos(0),
+                prefix_len,
+                VERIFY_BUF_LEN,
+                Caching::Random.with_timeout(config.io_timeout),
+                |delta| count_hashed_bytes(config, delta),
+            )
+            .map_err(|e| format!("Failed to read file {}: {}", m.path, e))?;
+            match &first {
+                None => first = Some((&m.path, hash)),
+                Some((_, first_hash)) if *first_hash == hash => {}
+                Some((first_path, _)) => {
+                    return Err(format!(
+                        "Files {} and {} differ in the first {}",
+                        first_path, m.path, prefix_len
+                    ))
+                }
+            }
+        }
+    }
+    Ok(files)
+}
+
 /// Partitions a group of files into files to keep and files that can be safely dropped
 /// (or linked).
 fn partition(
@@ -617,6 +1438,7 @@ fn partition(
     config: &DedupeConfig,
     log: &Log,
 ) -> Result<PartitionedFileGroup, Error> {
+    let keep_file = config.keep_files.get(&group).cloned();
     let file_len = group.file_len;
     let file_hash = group.file_hash;
     let error = |msg: &str| {
@@ -626,20 +1448,29 @@ fn partition(
         )))
     };
 
+    if let Some(counters) = &config.counters {
+        counters.add_files(group.files.len());
+    }
+    if !config.dedupe_truncated && group.truncated_from.is_some() {
+        return error("The group was truncated by --max-group-files");
+    }
+
     // Fetch metadata of the files
     let mut metadata_err = false;
     let mut files: Vec<_> = group
         .files
         .into_iter()
-        .map(|p| {
-            FileMetadata::new(p)
+        .filter_map(|p| {
+            let path = p.clone();
+            io_timeout::run(config.io_timeout, move || FileMetadata::new(p))
+                .map_err(|e| with_context(format!("Failed to read metadata of {}: {}", path, e), e))
+                .and_then(|m| m)
                 .map_err(|e| {
                     log.warn(e);
                     metadata_err = true;
                 })
                 .ok()
         })
-        .flatten()
         .collect();
 
     // On metadata errors, we're just ignoring the group.
@@ -655,41 +1486,62 @@ fn partition(
     files.retain(|m| {
         let is_file = m.metadata.is_file();
         if !is_file {
-            log.warn(format!("Skipping file {}: Not a regular file", m.path));
+            skip(log, EventKind::Filtered, &m.path, "Not a regular file");
         }
         is_file
     });
 
-    // If file has a different length, then we really know it has been modified.
-    // Therefore, it does not belong to the group and we can safely skip it.
+    // The same file can be listed more than once, e.g. under paths differing only in letter case
+    // on a case-insensitive file system. Dropping one of the entries would drop the other one too.
+    let ignore_case = config.ignore_case();
+    let mut listed: HashSet<Path> = HashSet::with_capacity(files.len());
+    let mut listed_by_id: HashMap<FileId, Vec<Path>> = HashMap::new();
     files.retain(|m| {
-        let len_ok = FileLen(m.metadata.len()) == file_len;
-        if !len_ok {
-            log.warn(format!(
-                "Skipping file {} with length {} different than the group length {}",
-                m.path,
-                m.metadata.len(),
-                file_len.0,
-            ));
+        let same_file = if listed.contains(&m.path) {
+            Some(m.path.clone())
+        } else if let (true, Some(id)) = (ignore_case, m.file_id()) {
+            let paths = listed_by_id.entry(id).or_default();
+            let same_file = paths.iter().find(|p| m.path.eq_ignore_case(p)).cloned();
+            paths.push(m.path.clone());
+            same_file
+        } else {
+            None
+        };
+        match same_file {
+            Some(other) => {
+                skip(
+                    log,
+                    EventKind::Filtered,
+                    &m.path,
+                    format!("Same file as {}", other),
+                );
+                false
+            }
+            None => {
+                listed.insert(m.path.clone());
+                true
+            }
         }
-        len_ok
     });
 
-    // Bail out as well if any file has been modified after `config.modified_before`.
-    // We need to skip the whole group, because we don't know if these files are really different.
-    if let Some(max_timestamp) = config.modified_before {
-        if was_modified(&files, max_timestamp, log) {
-            return error("Some files could be updated since the previous run of fclones");
-        }
-    }
+    // Make sure the files haven't changed since the report was produced
+    let mut files = match verify(files, file_len, &file_hash, config, log) {
+        Ok(files) => files,
+        Err(msg) => return error(&msg),
+    };
 
     // Sort files to remove in user selected order.
     // The priorities at the beginning of the argument list have precedence over
     // the priorities given at the end of the argument list, therefore we're applying
-    // them in reversed order.
+    // them in reversed order. The sorts are stable, so sorting by path first makes
+    // the path the final tie-breaker, regardless of the order of files in the report.
+    // Without priorities, the order of the report is kept.
+    if !config.priority.is_empty() {
+        files.sort_by_cached_key(|m| m.path.to_path_buf());
+    }
     let mut sort_errors = Vec::new();
     for priority in config.priority.iter().rev() {
-        sort_errors.extend(sort_by_priority(&mut files, priority));
+        sort_errors.extend(sort_by_priority(&mut files, priority, config));
     }
 
     if !sort_errors.is_empty() {
@@ -699,20 +1551,110 @@ fn partition(
         return error("Metadata of some files could not be read.");
     }
 
+    // Copies in the trash have been deleted already, so they go last regardless of priorities.
+    // The sort is stable, so the order within both parts is preserved.
+    files.sort_by_key(|m| trash::is_in_trash(&m.path.to_path_buf()));
+
+    // The file chosen in the keep list overrides the priorities. It goes first,
+    // so it becomes the target of the links.
+    if let Some(keep_file) = &keep_file {
+        match files.iter().position(|m| m.path == *keep_file) {
+            Some(i) => {
+                let m = files.remove(i);
+                files.insert(0, m);
+            }
+            None => {
+                return error(&format!(
+                    "File {} from the keep list is not in the group",
+                    keep_file
+                ))
+            }
+        }
+    }
+
     // Split the set of files into two sets - a set that we want to keep intact and a set
     // that we can remove or replace with links:
-    let (mut to_retain, mut to_drop): (Vec<_>, Vec<_>) = files
-        .into_iter()
-        .partition(|m| should_keep(&m.path, config) || !may_drop(&m.path, config));
+    let normalize = |p: Path| match config.normalize_unicode {
+        Some(form) => p.normalized(form),
+        None => p,
+    };
+    let drop_root = config.drop_root.as_ref().map(|r| normalize(Path::from(r)));
+    let outside_drop_root =
+        |p: &Path| matches!(&drop_root, Some(root) if !root.is_prefix_of(&normalize(p.clone())));
+    let (mut to_retain, mut to_drop): (Vec<_>, Vec<_>) = files.into_iter().partition(|m| {
+        keep_file.as_ref() == Some(&m.path)
+            || should_keep(&m.path, config)
+            || !may_drop(&m.path, config)
+            || outside_drop_root(&m.path)
+    });
+
+    // The drop selection must never cover the whole group. If the patterns matched all files,
+    // tell the user, because the file kept below may not be the one they expected.
+    let drop_filtered =
+        !config.name_patterns.is_empty() || !config.path_patterns.is_empty() || drop_root.is_some();
+    if drop_filtered && to_retain.is_empty() && !to_drop.is_empty() {
+        log.warn(format!(
+            "All files in group with hash {} and len {} were selected for dropping. \
+            Keeping the highest priority file {}",
+            file_hash, file_len.0, to_drop[0].path
+        ));
+    }
 
     // If the set to retain is smaller than the number of files we must keep (rf), then
     // move some higher priority files from `to_drop` and append them to `to_retain`.
-    let n = max(1, config.rf_over.unwrap_or(1));
+    let n = max(1, config.keep_copies.or(config.rf_over).unwrap_or(1));
     let missing_count = min(to_drop.len(), n.saturating_sub(to_retain.len()));
-    to_retain.extend(to_drop.drain(0..missing_count));
+    if config.keep_copies.is_some() {
+        // Files on devices not holding any retained file yet go first, so the retained
+        // copies don't all perish with a single device.
+        for _ in 0..missing_count {
+            let devices: HashSet<_> = to_retain.iter().map(|m| m.device_id()).collect();
+            let i = to_drop
+                .iter()
+                .position(|m| !devices.contains(&m.device_id()))
+                .unwrap_or(0);
+            to_retain.push(to_drop.remove(i));
+        }
+    } else {
+        to_retain.extend(to_drop.drain(0..missing_count));
+    }
+    if config.spread_devices {
+        let kept_devices: HashSet<_> = to_retain.iter().map(|m| m.device_id()).collect();
+        let all_devices: HashSet<_> = to_retain
+            .iter()
+            .chain(to_drop.iter())
+            .map(|m| m.device_id())
+            .collect();
+        if kept_devices.len() < min(n, all_devices.len()) {
+            return error(&format!(
+                "Can't keep {} copies on distinct devices, because the files protected \
+                from dropping are on {} device(s) only",
+                n,
+                kept_devices.len()
+            ));
+        }
+    }
 
+    // Keeping only the copies in the trash would leave the user with no copy outside of it
+    let in_trash = |m: &FileMetadata| trash::is_in_trash(&m.path.to_path_buf());
+    if to_retain.iter().all(in_trash) {
+        if let Some(i) = to_drop.iter().position(|m| !in_trash(m)) {
+            let m = to_drop.remove(i);
+            log.warn(format!(
+                "Keeping file {}, because all other kept files are in the trash",
+                m.path
+            ));
+            to_retain.push(m);
+        }
+    }
+
+    // Last line of defense against losing data: never plan removing all copies.
+    if to_retain.is_empty() && !to_drop.is_empty() {
+        return error("Refusing to drop all files of the group");
+    }
     assert!(to_retain.len() >= n || to_drop.is_empty());
     Ok(PartitionedFileGroup {
+        file_hash,
         to_keep: to_retain,
         to_drop,
     })
@@ -726,12 +1668,18 @@ fn partition(
 /// This function performs extensive checks if files can be removed.
 /// It rejects a group of files if:
 /// - metadata of any files in the group cannot be read,
-/// - any file in the group was modified after the `modified_before` configuration property
+/// - any file in the group was modified after the `modified_before` configuration property,
+///   unless `verify` is `Full` or `None`,
+/// - the beginnings of the files differ or cannot be read, if `verify` is `Prefix`
 ///
 /// Additionally it will never emit commands to remove a file which:
-/// - has length that does not match the file length recorded in the group metadata
+/// - has length that does not match the file length recorded in the group metadata,
+///   unless `verify` is `None`
+/// - has contents not matching the group hash, if `verify` is `Full`
 /// - was matched by any of the `retain_path` or `retain_name` patterns
 /// - was not matched by all `drop_path` and `drop_name` patterns
+/// - is located outside of `drop_root`, if given
+/// - belongs to a group without the annotations required by `only_annotated`
 ///
 /// # Parameters
 /// - `groups`: iterator over groups of identical files
@@ -747,11 +1695,21 @@ pub fn dedupe<'a, I>(
 where
     I: IntoParallelIterator<Item = FileGroup<Path>> + 'a,
 {
-    let devices = DiskDevices::new(&HashMap::new());
+    let ctx = ScriptContext {
+        devices: DiskDevices::new(&HashMap::new()),
+        probe_hard_links: !config.dry_run,
+        hard_links: Mutex::new(HashMap::new()),
+        check_security_labels: !config.ignore_security_labels,
+        protected_hard_links_uid: protected_hard_links_uid(),
+        counters: config.counters.clone(),
+        cross_device: config.cross_device,
+        log,
+    };
     groups
         .into_par_iter()
+        .filter(move |group| annotation::matches_all(&config.only_annotated, &group.annotations))
         .flat_map(move |group| match partition(group, config, log) {
-            Ok(group) => group.dedupe_script(&op, &devices),
+            Ok(group) => group.dedupe_script(&op, &ctx),
             Err(e) => {
                 log.warn(e);
                 Vec::new()
@@ -759,27 +1717,221 @@ where
         })
 }
 
+/// Controls how [`run_script`] executes the commands
+#[derive(Default)]
+pub struct RunOptions<'a> {
+    /// Records every executed command
+    pub audit: Option<&'a AuditLog>,
+    /// Records the commands that failed after all retries
+    pub failures: Option<&'a AuditLog>,
+    /// How many times to retry a command that failed with a transient error
+    pub retries: u32,
+    /// How long to wait before retrying a failed command
+    pub retry_delay: Duration,
+    /// Runs at most one command at a time on each network file system
+    pub serialize_network: bool,
+    /// Limits the number of files open at the same time
+    pub max_open_files: Option<usize>,
+    /// Sends the commands to a privileged helper process instead of executing them directly
+    pub helper: Option<&'a Helper>,
+}
+
+/// Locks serializing the commands changing files on the same network file system
+struct NetworkLocks<'a> {
+    devices: &'a DiskDevices,
+    locks: Mutex<HashMap<Path, Arc<Mutex<()>>>>,
+}
+
+impl<'a> NetworkLocks<'a> {
+    fn new(devices: &'a DiskDevices) -> NetworkLocks<'a> {
+        NetworkLocks {
+            devices,
+            locks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the lock of the network file system holding the file,
+    /// or `None` if the file is on a local file system
+    fn get(&self, file: &Path) -> Option<Arc<Mutex<()>>> {
+        if !self.devices.get_by_path(file).network {
+            return None;
+        }
+        let mount_point = self.devices.get_mount_point(file);
+        let mut locks = self.locks.lock().unwrap();
+        Some(locks.entry(mount_point.clone()).or_default().clone())
+    }
+}
+
+/// Commands changing files on the same device
+struct DeviceBatch {
+    /// True if the commands should be executed one by one, in order
+    sequential: bool,
+    commands: Vec<FsCommand>,
+}
+
+/// Splits the commands into batches by the device holding the files they change.
+///
+/// Commands on rotational drives are ordered by directory and by file name and executed
+/// sequentially, so the drive updates the metadata of one directory after another instead of
+/// seeking randomly across the platter. Commands on other devices are executed in parallel.
+fn batch_by_device(commands: Vec<FsCommand>, devices: &DiskDevices) -> Vec<DeviceBatch> {
+    let mut batches: Vec<DeviceBatch> = devices
+        .iter()
+        .map(|d| DeviceBatch {
+            sequential: d.disk_type == DiskType::HDD,
+            commands: Vec::new(),
+        })
+        .collect();
+    for cmd in commands {
+        let index = devices.get_by_path(&cmd.changed_file().path).index;
+        batches[index].commands.push(cmd);
+    }
+    batches.retain(|b| !b.commands.is_empty());
+    for batch in batches.iter_mut().filter(|b| b.sequential) {
+        batch.commands.sort_by_cached_key(|cmd| {
+            let path = &cmd.changed_file().path;
+            (path.parent().map(|p| p.to_path_buf()), path.file_name())
+        });
+    }
+    batches
+}
+
+/// Returns true if the error is likely to go away when the operation is retried,
+/// e.g. because the file is temporarily locked or a network file system timed out
+fn is_transient(e: &io::Error) -> bool {
+    if let ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut = e.kind() {
+        return true;
+    }
+    #[cfg(unix)]
+    {
+        matches!(
+            raw_os_error(e),
+            Some(
+                libc::EAGAIN
+                    | libc::EBUSY
+                    | libc::ETXTBSY
+                    | libc::ESTALE
+                    | libc::ETIMEDOUT
+                    | libc::EIO
+            )
+        )
+    }
+    #[cfg(windows)]
+    {
+        const ERROR_SHARING_VIOLATION: i32 = 32;
+        const ERROR_LOCK_VIOLATION: i32 = 33;
+        matches!(
+            raw_os_error(e),
+            Some(ERROR_SHARING_VIOLATION | ERROR_LOCK_VIOLATION)
+        )
+    }
+}
+
+/// Executes the command, retrying it as long as it fails with a transient error
+/// and the number of retries allowed by `options` is not exceeded
+fn execute_with_retries(cmd: &FsCommand, options: &RunOptions, log: &Log) -> io::Result<FileLen> {
+    with_retries(options, log, || match options.helper {
+        Some(helper) => helper.execute(cmd),
+        None => cmd.execute(log),
+    })
+}
+
+/// Calls `action` until it succeeds, fails with an error that is not transient,
+/// or the number of retries allowed by `options` is exceeded
+fn with_retries<T>(
+    options: &RunOptions,
+    log: &Log,
+    mut action: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match action() {
+            Err(e) if attempt < options.retries && is_transient(&e) => {
+                attempt += 1;
+                log.warn(format!("{}. Retrying ({}/{})", e, attempt, options.retries));
+                std::thread::sleep(options.retry_delay);
+            }
+            result => return result,
+        }
+    }
+}
+
 /// Runs a deduplication script generated by [`dedupe`].
 ///
 /// Calling this function is going to change the contents of the file-system.
 /// No safety checks are performed.
-/// Commands are executed in parallel, on the default Rayon thread-pool.
+/// Commands are executed in parallel, on the default Rayon thread-pool, except for the commands
+/// on rotational drives, which are executed one at a time per drive, grouped by directory.
+/// Commands failing with transient errors are retried as configured in `options`.
 /// On command execution failure, a warning is logged and the execution of remaining commands
 /// continues.
 /// Returns the number of files processed and the amount of disk space reclaimed.
-pub fn run_script(script: impl IntoParallelIterator<Item = FsCommand>, log: &Log) -> DedupeResult {
-    script
-        .into_par_iter()
-        .map(|cmd| cmd.execute(log))
-        .inspect(|res| {
-            if let Err(e) = res {
+pub fn run_script(
+    script: impl IntoParallelIterator<Item = FsCommand>,
+    options: &RunOptions,
+    log: &Log,
+) -> DedupeResult {
+    let record = |audit: Option<&AuditLog>, cmd: &FsCommand, result: &io::Result<FileLen>| {
+        if let Some(audit) = audit {
+            if let Err(e) = audit.record(cmd, result) {
+                log.warn(format!("Failed to write to {}: {}", audit, e));
+            }
+        }
+    };
+    if let Some(limit) = options.max_open_files {
+        fd::set_limit(limit);
+    }
+    let devices = DiskDevices::new(&HashMap::new());
+    let network_locks = if options.serialize_network {
+        Some(NetworkLocks::new(&devices))
+    } else {
+        None
+    };
+    let execute = |cmd: FsCommand| {
+        let lock = network_locks
+            .as_ref()
+            .and_then(|l| l.get(&cmd.changed_file().path));
+        let guard = lock.as_ref().map(|l| l.lock().unwrap());
+        let result = execute_with_retries(&cmd, options, log);
+        drop(guard);
+        systemd::tick(result.as_ref().map_or(0, |len| len.0));
+        record(options.audit, &cmd, &result);
+        if result.is_err() {
+            record(options.failures, &cmd, &result);
+        }
+        match result {
+            Ok(len) => DedupeResult {
+                processed_count: 1,
+                reclaimed_space: len,
+                failed_count: 0,
+            },
+            Err(e) => {
                 log.warn(e);
+                DedupeResult {
+                    processed_count: 0,
+                    reclaimed_space: FileLen(0),
+                    failed_count: 1,
+                }
+            }
+        }
+    };
+    let commands: Vec<FsCommand> = script.into_par_iter().collect();
+    batch_by_device(commands, &devices)
+        .into_par_iter()
+        .map(|batch| {
+            if batch.sequential {
+                batch
+                    .commands
+                    .into_iter()
+                    .map(execute)
+                    .fold(DedupeResult::default(), |a, b| a + b)
+            } else {
+                batch
+                    .commands
+                    .into_par_iter()
+                    .map(execute)
+                    .reduce(DedupeResult::default, |a, b| a + b)
             }
-        })
-        .filter_map(|res| res.ok())
-        .map(|len| DedupeResult {
-            processed_count: 1,
-            reclaimed_space: len,
         })
         .reduce(DedupeResult::default, |a, b| a + b)
 }
@@ -808,6 +1960,7 @@ pub fn log_script(
             Some(DedupeResult {
                 processed_count: 1,
                 reclaimed_space: cmd.space_to_reclaim(),
+                failed_count: 0,
             })
         })
         .while_some()
@@ -822,14 +1975,22 @@ pub fn log_script(
 #[cfg(test)]
 mod test {
     use std::collections::HashSet;
-    use std::path::PathBuf;
     use std::{thread, time};
 
     use chrono::Duration;
+    use fallible_iterator::FallibleIterator;
 
-    use crate::files::FileHash;
+    use crate::annotation::Annotations;
+    use crate::config::{FsInfoConfig, OutputFormat, UnicodeForm};
+    use crate::files::{Digests, FileHash};
+    use crate::fs_info::fs_info;
+    use crate::keep_list::KeepList;
     use crate::pattern::Pattern;
-    use crate::util::test::{create_file, create_file_newer_than, read_file, with_dir, write_file};
+    use crate::report::{open_report, ReportHeader, ReportWriter};
+    use crate::summary::DedupeSummary;
+    use crate::util::test::{
+        create_file, create_file_newer_than, read_file, with_dir, write_file, write_xattr,
+    };
 
     use super::*;
 
@@ -845,6 +2006,23 @@ mod test {
         assert_eq!(path.parent(), temp.parent());
     }
 
+    #[test]
+    fn test_copy_and_compare_tick_watchdog() {
+        with_dir("dedupe/copy_and_compare_tick", |root| {
+            let source = Path::from(root.join("source"));
+            let target = Path::from(root.join("target"));
+            fs::write(source.to_path_buf(), vec![1; 256 * 1024]).unwrap();
+
+            let activity = systemd::activity();
+            FsCommand::resume_copy(&source, &target).unwrap();
+            assert!(systemd::activity() >= activity + 4);
+
+            let activity = systemd::activity();
+            assert!(FsCommand::same_contents(&source, &target).unwrap());
+            assert!(systemd::activity() >= activity + 4);
+        })
+    }
+
     #[test]
     fn test_remove_command_removes_file() {
         with_dir("dedupe/remove_cmd", |root| {
@@ -852,7 +2030,11 @@ mod test {
             let file_path = root.join("file");
             create_file(&file_path);
             let file = FileMetadata::new(Path::from(&file_path)).unwrap();
-            let cmd = FsCommand::Remove { file };
+            let cmd = FsCommand::Remove {
+                file,
+                kept: None,
+                file_hash: FileHash::from(0),
+            };
             cmd.execute(&log).unwrap();
             assert!(!file_path.exists())
         })
@@ -870,6 +2052,8 @@ mod test {
                 source: file,
                 target: target.clone(),
                 use_rename: true,
+                allow_copy: true,
+                file_hash: FileHash::from(0),
             };
             cmd.execute(&log).unwrap();
             assert!(!file_path.exists());
@@ -889,6 +2073,8 @@ mod test {
                 source: file,
                 target: target.clone(),
                 use_rename: false,
+                allow_copy: true,
+                file_hash: FileHash::from(0),
             };
             cmd.execute(&log).unwrap();
             assert!(!file_path.exists());
@@ -896,6 +2082,81 @@ mod test {
         })
     }
 
+    #[test]
+    #[cfg(unix)]
+    fn test_move_copies_file_only_if_rename_crosses_file_systems() {
+        with_dir("dedupe/move_rename_error", |root| {
+            let file_path = Path::from(root.join("file"));
+            let target = Path::from(root.join("target"));
+            create_file(&file_path.to_path_buf());
+
+            let denied = |_: &Path, _: &Path| Err(io::Error::from_raw_os_error(libc::EACCES));
+            let result = FsCommand::move_file(&file_path, &target, true, denied);
+            assert_eq!(raw_os_error(&result.unwrap_err()), Some(libc::EACCES));
+            assert!(file_path.to_path_buf().exists());
+            assert!(!target.to_path_buf().exists());
+
+            let cross_device = |_: &Path, _: &Path| Err(io::Error::from_raw_os_error(libc::EXDEV));
+            FsCommand::move_file(&file_path, &target, false, cross_device).unwrap_err();
+            assert!(file_path.to_path_buf().exists());
+            assert!(!target.to_path_buf().exists());
+
+            FsCommand::move_file(&file_path, &target, true, cross_device).unwrap();
+            assert!(!file_path.to_path_buf().exists());
+            assert!(target.to_path_buf().exists());
+        })
+    }
+
+    #[test]
+    fn test_move_by_copy_resumes_interrupted_copy() {
+        with_dir("dedupe/move_copy_resume", |root| {
+            let log = Log::new();
+            let file_path = root.join("file");
+            let target = Path::from(root.join("target"));
+            let part = root.join("target.fclones-part");
+            write_file(&file_path, "foobar");
+            write_file(&part, "foo");
+            let cmd = FsCommand::Move {
+                source: FileMetadata::new(Path::from(&file_path)).unwrap(),
+                target: target.clone(),
+                use_rename: false,
+                allow_copy: true,
+                file_hash: FileHash::from(0),
+            };
+            cmd.execute(&log).unwrap();
+            assert!(!file_path.exists());
+            assert!(!part.exists());
+            assert_eq!(read_file(&target.to_path_buf()), "foobar");
+        })
+    }
+
+    #[test]
+    fn test_move_by_copy_keeps_source_if_copy_differs() {
+        with_dir("dedupe/move_copy_verify", |root| {
+            let log = Log::new();
+            let file_path = root.join("file");
+            let target = Path::from(root.join("target"));
+            let part = root.join("target.fclones-part");
+            write_file(&file_path, "foobar");
+            write_file(&part, "xyz");
+            let cmd = FsCommand::Move {
+                source: FileMetadata::new(Path::from(&file_path)).unwrap(),
+                target: target.clone(),
+                use_rename: false,
+                allow_copy: true,
+                file_hash: FileHash::from(0),
+            };
+            assert!(cmd.execute(&log).is_err());
+            assert!(file_path.exists());
+            assert!(!part.exists());
+            assert!(!target.to_path_buf().exists());
+
+            cmd.execute(&log).unwrap();
+            assert!(!file_path.exists());
+            assert_eq!(read_file(&target.to_path_buf()), "foobar");
+        })
+    }
+
     #[test]
     fn test_move_fails_if_target_exists() {
         with_dir("dedupe/move_target_exists", |root| {
@@ -909,6 +2170,8 @@ mod test {
                 source: file,
                 target: Path::from(&target),
                 use_rename: false,
+                allow_copy: true,
+                file_hash: FileHash::from(0),
             };
             assert!(cmd.execute(&log).is_err());
         })
@@ -928,6 +2191,7 @@ mod test {
             let cmd = FsCommand::SoftLink {
                 target: Arc::new(file_1),
                 link: file_2,
+                file_hash: FileHash::from(0),
             };
             cmd.execute(&log).unwrap();
 
@@ -956,6 +2220,7 @@ mod test {
             let cmd = FsCommand::HardLink {
                 target: Arc::new(file_1),
                 link: file_2,
+                file_hash: FileHash::from(0),
             };
             cmd.execute(&log).unwrap();
 
@@ -966,7 +2231,7 @@ mod test {
     }
 
     /// Creates 3 empty files with different creation time and returns a FileGroup describing them
-    fn make_group(root: &PathBuf) -> FileGroup<Path> {
+    fn make_group(root: &std::path::Path) -> FileGroup<Path> {
         let file_1 = root.join("file_1");
         let file_2 = root.join("file_2");
         let file_3 = root.join("file_3");
@@ -975,16 +2240,15 @@ mod test {
         let ctime_2 = create_file_newer_than(&file_2, ctime_1);
         create_file_newer_than(&file_3, ctime_2);
 
-        let group = FileGroup {
-            file_len: FileLen(0),
-            file_hash: FileHash(0),
-            files: vec![
+        FileGroup::new(
+            FileLen(0),
+            FileHash::from(0),
+            vec![
                 Path::from(&file_1),
                 Path::from(&file_2),
                 Path::from(&file_3),
             ],
-        };
-        group
+        )
     }
 
     #[test]
@@ -1009,6 +2273,18 @@ mod test {
         })
     }
 
+    #[test]
+    fn test_partition_skips_truncated_group_unless_requested() {
+        with_dir("dedupe/partition/truncated", |root| {
+            let mut group = make_group(root);
+            group.truncate(2);
+            let mut config = DedupeConfig::default();
+            assert!(partition(group.clone(), &config, &Log::new()).is_err());
+            config.dedupe_truncated = true;
+            assert!(partition(group, &config, &Log::new()).is_ok());
+        })
+    }
+
     #[test]
     fn test_partition_skips_file_with_different_len() {
         with_dir("dedupe/partition/file_len", |root| {
@@ -1032,10 +2308,233 @@ mod test {
         })
     }
 
-    fn path_set(v: &Vec<FileMetadata>) -> HashSet<&Path> {
+    /// Creates files with the given contents and returns a group with the hash of the first one
+    fn make_group_with_contents(root: &std::path::Path, contents: &[&str]) -> FileGroup<Path> {
+        let files: Vec<_> = contents
+            .iter()
+            .enumerate()
+            .map(|(i, c)| {
+                let path = root.join(format!("file_{}", i));
+                write_file(&path, c);
+                Path::from(&path)
+            })
+            .collect();
+        let file_len = FileLen(contents[0].len() as u64);
+        let file_hash = file_hash(
+            &files[0],
+            FilePos(0),
+            file_len,
+            4096,
+            Caching::Default,
+            |_| {},
+        )
+        .unwrap();
+        FileGroup {
+            file_len,
+            file_hash,
+            hashes: Digests::new(),
+            files,
+            truncated_from: None,
+            annotations: Annotations::new(),
+        }
+    }
+
+    #[test]
+    fn test_partition_with_verify_none_ignores_modification_time() {
+        with_dir("dedupe/partition/verify_none", |root| {
+            let group = make_group(root);
+            let mut config = DedupeConfig::default();
+            config.modified_before = Some(DateTime::from(Local::now() - Duration::days(1)));
+            config.verify = Verify::None;
+            let partitioned = partition(group, &config, &Log::new()).unwrap();
+            assert_eq!(partitioned.to_keep.len(), 1);
+            assert_eq!(partitioned.to_drop.len(), 2);
+        })
+    }
+
+    #[test]
+    fn test_partition_with_verify_prefix_bails_out_if_prefixes_differ() {
+        with_dir("dedupe/partition/verify_prefix", |root| {
+            let group = make_group_with_contents(root, &["foo", "foo", "bar"]);
+            let mut config = DedupeConfig::default();
+            config.verify = Verify::Prefix;
+            let partitioned = partition(group, &config, &Log::new());
+            assert!(partitioned.is_err());
+        })
+    }
+
+    #[test]
+    fn test_partition_with_verify_full_skips_files_with_different_contents() {
+        with_dir("dedupe/partition/verify_full", |root| {
+            let group = make_group_with_contents(root, &["foo", "foo", "bar"]);
+            let changed = group.files[2].clone();
+            let mut config = DedupeConfig::default();
+            // Modification times don't matter if the contents are verified:
+            config.modified_before = Some(DateTime::from(Local::now() - Duration::days(1)));
+            config.verify = Verify::Full;
+            let partitioned = partition(group, &config, &Log::new()).unwrap();
+            assert_eq!(partitioned.to_keep.len(), 1);
+            assert_eq!(partitioned.to_drop.len(), 1);
+            assert!(!path_set(&partitioned.to_keep).contains(&changed));
+            assert!(!path_set(&partitioned.to_drop).contains(&changed));
+        })
+    }
+
+    #[test]
+    fn test_partition_drops_copies_in_trash_first() {
+        with_dir("dedupe/partition/trash", |root| {
+            let trash = root.join(".Trash-1000").join("files");
+            fs::create_dir_all(&trash).unwrap();
+            let file_1 = trash.join("file_1");
+            let file_2 = root.join("file_2");
+            create_file(&file_1);
+            let mtime_1 = fs::metadata(&file_1).unwrap().modified().unwrap();
+            create_file_newer_than(&file_2, mtime_1);
+            let group = FileGroup::new(
+                FileLen(0),
+                FileHash::from(0),
+                vec![Path::from(&file_1), Path::from(&file_2)],
+            );
+
+            let mut config = DedupeConfig::default();
+            config.priority = vec![Priority::LeastRecentlyModified];
+            let p = partition(group.clone(), &config, &Log::new()).unwrap();
+            assert_eq!(
+                path_set(&p.to_keep),
+                vec![&group.files[1]].into_iter().collect()
+            );
+            assert_eq!(
+                path_set(&p.to_drop),
+                vec![&group.files[0]].into_iter().collect()
+            );
+
+            // Even if the file in the trash must be kept, the other copy can't be dropped:
+            config.keep_path_patterns = vec![Pattern::glob("**/.Trash-*/**").unwrap()];
+            let p = partition(group.clone(), &config, &Log::new()).unwrap();
+            assert_eq!(p.to_keep.len(), 2);
+            assert!(p.to_drop.is_empty());
+        })
+    }
+
+    fn path_set(v: &[FileMetadata]) -> HashSet<&Path> {
         v.iter().map(|f| &f.path).collect()
     }
 
+    #[test]
+    fn test_partition_free_space_priority_falls_back_to_next_priority() {
+        with_dir("dedupe/partition/free_space_priority", |root| {
+            let file_1 = root.join("file_1");
+            let file_2 = root.join("file_2");
+            create_file(&file_1);
+            let mtime_1 = fs::metadata(&file_1).unwrap().modified().unwrap();
+            create_file_newer_than(&file_2, mtime_1);
+            let group = FileGroup::new(
+                FileLen(0),
+                FileHash::from(0),
+                vec![Path::from(&file_1), Path::from(&file_2)],
+            );
+
+            // Both files are on the same file system, so the modification time decides:
+            let mut config = DedupeConfig::default();
+            config.priority = vec![Priority::FreeSpace, Priority::MostRecentlyModified];
+            let p = partition(group.clone(), &config, &Log::new()).unwrap();
+            assert_eq!(
+                path_set(&p.to_keep),
+                vec![&group.files[0]].into_iter().collect()
+            );
+            assert_eq!(
+                path_set(&p.to_drop),
+                vec![&group.files[1]].into_iter().collect()
+            );
+        })
+    }
+
+    #[test]
+    fn test_partition_applies_priorities_in_order_and_breaks_ties_by_path() {
+        with_dir("dedupe/partition/priority_chain", |root| {
+            let files = ["x/bb", "x/b", "archive/long_name", "x/a"];
+            for f in files.iter() {
+                let path = root.join(f);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                create_file(&path);
+            }
+            let group = FileGroup::new(
+                FileLen(0),
+                FileHash::from(0),
+                files.iter().map(|f| Path::from(root.join(f))).collect(),
+            );
+
+            let mut config = DedupeConfig::default();
+            config.priority = vec![
+                "preferred-path".parse().unwrap(),
+                "shortest-path".parse().unwrap(),
+            ];
+            config.prefer_path_patterns = vec![Pattern::glob("**/archive/**").unwrap()];
+            config.rf_over = Some(3);
+            let p = partition(group, &config, &Log::new()).unwrap();
+            let kept: Vec<_> = p.to_keep.iter().map(|m| m.path.to_path_buf()).collect();
+            assert_eq!(
+                kept,
+                vec![
+                    root.join("archive/long_name"),
+                    root.join("x/bb"),
+                    root.join("x/a")
+                ]
+            );
+            assert_eq!(p.to_drop.len(), 1);
+            assert_eq!(p.to_drop[0].path.to_path_buf(), root.join("x/b"));
+        })
+    }
+
+    #[test]
+    fn test_partition_keeps_report_order_without_priorities() {
+        with_dir("dedupe/partition/report_order", |root| {
+            let files = ["b", "a"];
+            for f in files.iter() {
+                create_file(&root.join(f));
+            }
+            let group = FileGroup::new(
+                FileLen(0),
+                FileHash::from(0),
+                files.iter().map(|f| Path::from(root.join(f))).collect(),
+            );
+            let p = partition(group, &DedupeConfig::default(), &Log::new()).unwrap();
+            assert_eq!(p.to_keep.len(), 1);
+            assert_eq!(p.to_keep[0].path.to_path_buf(), root.join("b"));
+            assert_eq!(p.to_drop[0].path.to_path_buf(), root.join("a"));
+        })
+    }
+
+    #[test]
+    fn test_partition_keeps_file_from_keep_list() {
+        with_dir("dedupe/partition/keep_list", |root| {
+            let files = ["a", "b", "c"];
+            for f in files.iter() {
+                create_file(&root.join(f));
+            }
+            let group = FileGroup::new(
+                FileLen(0),
+                FileHash::from(0),
+                files.iter().map(|f| Path::from(root.join(f))).collect(),
+            );
+            let list_file = root.join("keep.txt");
+            let listed = |path: &str| {
+                fs::write(&list_file, format!("{} {}\n", group.id(), path)).unwrap();
+                let mut config = DedupeConfig::default();
+                config.keep_files = KeepList::read(&list_file).unwrap();
+                config.keep_name_patterns = vec![Pattern::glob("a").unwrap()];
+                partition(group.clone(), &config, &Log::new())
+            };
+
+            let p = listed(root.join("c").to_str().unwrap()).unwrap();
+            let kept: Vec<_> = p.to_keep.iter().map(|m| m.path.to_path_buf()).collect();
+            assert_eq!(kept, vec![root.join("c"), root.join("a")]);
+            assert_eq!(p.to_drop.len(), 1);
+
+            assert!(listed("/not/in/group").is_err());
+        })
+    }
+
     #[test]
     fn test_partition_respects_creation_time_priority() {
         with_dir("dedupe/partition/ctime_priority", |root| {
@@ -1062,6 +2561,27 @@ mod test {
         });
     }
 
+    #[test]
+    fn test_partition_respects_birth_time_priority() {
+        with_dir("dedupe/partition/btime_priority", |root| {
+            let group = make_group(root);
+            let mut config = DedupeConfig::default();
+            config.priority = vec![Priority::NewestCreated];
+            let partitioned_1 = partition(group.clone(), &config, &Log::new()).unwrap();
+            config.priority = vec![Priority::OldestCreated];
+            let partitioned_2 = partition(group.clone(), &config, &Log::new()).unwrap();
+
+            assert_ne!(
+                path_set(&partitioned_1.to_keep),
+                path_set(&partitioned_2.to_keep)
+            );
+            assert_ne!(
+                path_set(&partitioned_1.to_drop),
+                path_set(&partitioned_2.to_drop)
+            );
+        });
+    }
+
     #[test]
     fn test_partition_respects_modification_time_priority() {
         with_dir("dedupe/partition/mtime_priority", |root| {
@@ -1107,6 +2627,28 @@ mod test {
         })
     }
 
+    #[test]
+    fn test_partition_matches_keep_patterns_after_unicode_normalization() {
+        with_dir("dedupe/partition/keep_unicode", |root| {
+            let file_1 = root.join("cafe\u{301}_1");
+            let file_2 = root.join("cafe\u{301}_2");
+            create_file(&file_1);
+            create_file(&file_2);
+            let group = FileGroup::new(
+                FileLen(0),
+                FileHash::from(0),
+                vec![Path::from(&file_2), Path::from(&file_1)],
+            );
+            let mut config = DedupeConfig::default();
+            config.keep_name_patterns = vec![Pattern::glob("caf\u{e9}_1").unwrap()];
+            config.normalize_unicode = Some(UnicodeForm::Nfc);
+            config.normalize_patterns();
+            let p = partition(group, &config, &Log::new()).unwrap();
+            assert_eq!(p.to_keep.len(), 1);
+            assert_eq!(p.to_keep[0].path, Path::from(&file_1));
+        })
+    }
+
     #[test]
     fn test_partition_respects_drop_patterns() {
         with_dir("dedupe/partition/drop", |root| {
@@ -1126,6 +2668,356 @@ mod test {
         })
     }
 
+    #[test]
+    fn test_partition_keeps_one_file_if_drop_patterns_match_all() {
+        with_dir("dedupe/partition/drop_all", |root| {
+            let group = make_group(root);
+            let mut config = DedupeConfig::default();
+            config.priority = vec![Priority::MostRecentlyModified];
+            config.name_patterns = vec![Pattern::glob("file_*").unwrap()];
+            let log = Log::new();
+            let p = partition(group.clone(), &config, &log).unwrap();
+            assert_eq!(p.to_keep.len(), 1);
+            assert_eq!(&p.to_keep[0].path, &group.files[0]);
+            assert_eq!(p.to_drop.len(), 2);
+            assert_eq!(log.warning_count(), 1);
+        })
+    }
+
+    #[test]
+    fn test_partition_skips_files_listed_twice() {
+        with_dir("dedupe/partition/listed_twice", |root| {
+            let file = root.join("file");
+            create_file(&file);
+            let group = FileGroup::new(
+                FileLen(0),
+                FileHash::from(0),
+                vec![Path::from(&file), Path::from(&file)],
+            );
+            let log = Log::new();
+            let p = partition(group, &DedupeConfig::default(), &log).unwrap();
+            assert_eq!(p.to_keep.len(), 1);
+            assert!(p.to_drop.is_empty());
+            assert_eq!(log.warning_count(), 1);
+        })
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_partition_skips_paths_differing_only_in_case_if_case_insensitive() {
+        with_dir("dedupe/partition/case_insensitive", |root| {
+            // Simulates a case-insensitive file system with a hard link:
+            let file = root.join("file");
+            let alias = root.join("FILE");
+            create_file(&file);
+            fs::hard_link(&file, &alias).unwrap();
+            let group = FileGroup::new(
+                FileLen(0),
+                FileHash::from(0),
+                vec![Path::from(&file), Path::from(&alias)],
+            );
+
+            let mut config = DedupeConfig::default();
+            config.case.case_insensitive = true;
+            let p = partition(group.clone(), &config, &Log::new()).unwrap();
+            assert_eq!(p.to_keep.len(), 1);
+            assert!(p.to_drop.is_empty());
+
+            let mut config = DedupeConfig::default();
+            config.case.case_sensitive = true;
+            let p = partition(group, &config, &Log::new()).unwrap();
+            assert_eq!(p.to_keep.len(), 1);
+            assert_eq!(p.to_drop.len(), 1);
+        })
+    }
+
+    #[test]
+    fn test_partition_drops_only_files_under_drop_root() {
+        with_dir("dedupe/partition/drop_root", |root| {
+            let drop_root = root.join("b");
+            fs::create_dir(&drop_root).unwrap();
+            let file_a = root.join("file_a");
+            let file_b1 = drop_root.join("file_b1");
+            let file_b2 = drop_root.join("file_b2");
+            for f in [&file_a, &file_b1, &file_b2] {
+                create_file(f);
+            }
+            let group = FileGroup::new(
+                FileLen(0),
+                FileHash::from(0),
+                vec![
+                    Path::from(&file_b1),
+                    Path::from(&file_a),
+                    Path::from(&file_b2),
+                ],
+            );
+            let mut config = DedupeConfig::default();
+            config.drop_root = Some(drop_root);
+            let p = partition(group, &config, &Log::new()).unwrap();
+            assert_eq!(p.to_keep.len(), 1);
+            assert_eq!(p.to_keep[0].path, Path::from(&file_a));
+            assert_eq!(p.to_drop.len(), 2);
+        })
+    }
+
+    /// Sets the attribute treated as a security label by the tests on the file.
+    /// Returns false if the file system doesn't support user attributes.
+    #[cfg(target_os = "linux")]
+    fn set_security_label(path: &std::path::Path) -> bool {
+        write_xattr(path, xattr::TEST_SECURITY_LABEL, b"1")
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_link_skips_files_with_different_security_labels() {
+        with_dir("dedupe/security_labels", |root| {
+            let group = make_group(root);
+            if !set_security_label(&root.join("file_1")) {
+                return;
+            }
+            let log = Log::new();
+            let mut config = DedupeConfig::default();
+            config.priority = vec![Priority::LeastRecentlyModified];
+            let script: Vec<_> =
+                dedupe(vec![group.clone()], DedupeOp::HardLink, &config, &log).collect();
+            let links: HashSet<_> = script.iter().map(|c| &c.changed_file().path).collect();
+            assert_eq!(links.len(), 1);
+            assert!(!links.contains(&Path::from(root.join("file_1"))));
+
+            config.ignore_security_labels = true;
+            let script: Vec<_> = dedupe(vec![group], DedupeOp::HardLink, &config, &log).collect();
+            assert_eq!(script.len(), 2);
+        });
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    #[ignore = "requires /dev/shm on a different file system than the test directory"]
+    fn test_hard_link_across_devices_follows_cross_device_policy() {
+        with_dir("dedupe/cross_device", |root| {
+            let other_dir = tempfile::tempdir_in("/dev/shm").unwrap();
+            let other_root = other_dir.path().to_path_buf();
+            let mut files = vec![root.join("a"), root.join("d")];
+            files.extend(vec![other_root.join("b"), other_root.join("c")]);
+            for f in files.iter() {
+                create_file(f);
+            }
+            let device =
+                |f: &std::path::PathBuf| FileMetadata::new(Path::from(f)).unwrap().device_id();
+            assert_ne!(device(&files[0]), device(&files[2]));
+            let group = FileGroup::new(
+                FileLen(0),
+                FileHash::from(0),
+                files.iter().map(Path::from).collect(),
+            );
+            let count = |policy: CrossDevicePolicy| {
+                let mut config = DedupeConfig::default();
+                config.cross_device = policy;
+                let script: Vec<_> = dedupe(
+                    vec![group.clone()],
+                    DedupeOp::HardLink,
+                    &config,
+                    &Log::new(),
+                )
+                .collect();
+                let hard = script
+                    .iter()
+                    .filter(|c| matches!(c, FsCommand::HardLink { .. }))
+                    .count();
+                (hard, script.len() - hard)
+            };
+            assert_eq!(count(CrossDevicePolicy::SoftLink), (1, 2));
+            assert_eq!(count(CrossDevicePolicy::Skip), (1, 0));
+            assert_eq!(count(CrossDevicePolicy::Local), (2, 0));
+            assert_eq!(count(CrossDevicePolicy::Reflink), (1, 1));
+        });
+    }
+
+    #[test]
+    fn test_reflink_replaces_file_or_leaves_it_intact() {
+        with_dir("dedupe/reflink", |root| {
+            let file_a = root.join("file_a");
+            let file_b = root.join("file_b");
+            write_file(&file_a, "foo");
+            write_file(&file_b, "foo");
+            let config = FsInfoConfig {
+                path: root.to_path_buf(),
+            };
+            let supported = fs_info(&config).unwrap().reflinks;
+            let target = FileMetadata::new(Path::from(&file_a)).unwrap();
+            let link = FileMetadata::new(Path::from(&file_b)).unwrap();
+            let cmd = FsCommand::RefLink {
+                target: Arc::new(target),
+                link,
+                file_hash: FileHash::from(0),
+            };
+            let result = cmd.execute(&Log::new());
+            assert_eq!(result.is_ok(), supported);
+            assert_eq!(fs::read_to_string(&file_b).unwrap(), "foo");
+            assert_eq!(fs::read_dir(root).unwrap().count(), 2);
+        });
+    }
+
+    #[test]
+    fn test_partition_keeps_exactly_keep_copies_files() {
+        with_dir("dedupe/partition/keep_copies", |root| {
+            let files: Vec<_> = (0..4).map(|i| root.join(format!("file_{}", i))).collect();
+            for f in files.iter() {
+                create_file(f);
+            }
+            let group = FileGroup::new(
+                FileLen(0),
+                FileHash::from(0),
+                files.iter().map(Path::from).collect(),
+            );
+            let mut config = DedupeConfig::default();
+            config.rf_over = Some(1);
+            config.keep_copies = Some(2);
+            let p = partition(group.clone(), &config, &Log::new()).unwrap();
+            let kept: Vec<_> = p.to_keep.iter().map(|m| m.path.to_path_buf()).collect();
+            assert_eq!(kept, vec![files[0].clone(), files[1].clone()]);
+            assert_eq!(p.to_drop.len(), 2);
+
+            config.keep_copies = None;
+            let p = partition(group, &config, &Log::new()).unwrap();
+            assert_eq!(p.to_keep.len(), 1);
+            assert_eq!(p.to_drop.len(), 3);
+        });
+    }
+
+    #[test]
+    fn test_spread_devices_keeps_exactly_keep_copies_files() {
+        with_dir("dedupe/partition/spread_devices", |root| {
+            let files: Vec<_> = (0..4).map(|i| root.join(format!("file_{}", i))).collect();
+            for f in files.iter() {
+                create_file(f);
+            }
+            let group = FileGroup::new(
+                FileLen(0),
+                FileHash::from(0),
+                files.iter().map(Path::from).collect(),
+            );
+            let mut config = DedupeConfig::default();
+            config.keep_copies = Some(2);
+            config.spread_devices = true;
+            // All files are on one device, so the copies can't be spread any further:
+            let p = partition(group, &config, &Log::new()).unwrap();
+            assert_eq!(p.to_keep.len(), 2);
+            assert_eq!(p.to_drop.len(), 2);
+        });
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    #[ignore = "requires /dev/shm on a different file system than the test directory"]
+    fn test_partition_keeps_copies_on_distinct_devices() {
+        with_dir("dedupe/partition/distinct_devices", |root| {
+            let other_dir = tempfile::tempdir_in("/dev/shm").unwrap();
+            let files = [root.join("a"), root.join("b"), other_dir.path().join("c")];
+            for f in files.iter() {
+                create_file(f);
+            }
+            let device =
+                |f: &std::path::PathBuf| FileMetadata::new(Path::from(f)).unwrap().device_id();
+            assert_ne!(device(&files[0]), device(&files[2]));
+            let group = FileGroup::new(
+                FileLen(0),
+                FileHash::from(0),
+                files.iter().map(Path::from).collect(),
+            );
+            let mut config = DedupeConfig::default();
+            config.priority = vec!["preferred-path".parse().unwrap()];
+            config.prefer_path_patterns = vec![
+                Pattern::glob("**/a").unwrap(),
+                Pattern::glob("**/b").unwrap(),
+            ];
+            // `-n` keeps the files in the order of priority:
+            config.rf_over = Some(2);
+            let p = partition(group.clone(), &config, &Log::new()).unwrap();
+            let kept: Vec<_> = p.to_keep.iter().map(|m| m.path.to_path_buf()).collect();
+            assert_eq!(kept, vec![files[0].clone(), files[1].clone()]);
+
+            config.keep_copies = Some(2);
+            let p = partition(group.clone(), &config, &Log::new()).unwrap();
+            let kept: Vec<_> = p.to_keep.iter().map(|m| m.path.to_path_buf()).collect();
+            assert_eq!(kept, vec![files[0].clone(), files[2].clone()]);
+            assert_eq!(p.to_drop[0].path.to_path_buf(), files[1]);
+
+            config.spread_devices = true;
+            let p = partition(group.clone(), &config, &Log::new()).unwrap();
+            assert_eq!(p.to_keep.len(), 2);
+
+            // Files kept by patterns count as copies, even if they share a device:
+            config.spread_devices = false;
+            config.keep_path_patterns =
+                vec![Pattern::glob(&format!("{}/*", root.display())).unwrap()];
+            let p = partition(group.clone(), &config, &Log::new()).unwrap();
+            assert_eq!(p.to_keep.len(), 2);
+            assert_eq!(p.to_drop[0].path.to_path_buf(), files[2]);
+
+            // Keeping the copy on the other device too would keep more copies than asked for:
+            config.spread_devices = true;
+            assert!(partition(group, &config, &Log::new()).is_err());
+        });
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_protected_hard_links_forbid_linking_to_setuid_files_of_other_users() {
+        use std::os::unix::fs::PermissionsExt;
+
+        with_dir("dedupe/protected_hard_links", |root| {
+            let file = root.join("file");
+            create_file(&file);
+            let log = Log::new();
+            let counters = Arc::new(DedupeCounters::default());
+            // Pretend the files belong to another user:
+            let ctx = ScriptContext {
+                devices: DiskDevices::new(&HashMap::new()),
+                probe_hard_links: false,
+                hard_links: Mutex::new(HashMap::new()),
+                check_security_labels: false,
+                protected_hard_links_uid: Some(nix::unistd::geteuid().as_raw() + 1),
+                counters: Some(counters.clone()),
+                cross_device: CrossDevicePolicy::SoftLink,
+                log: &log,
+            };
+            let link = Path::from(root.join("link"));
+            fs::set_permissions(&file, fs::Permissions::from_mode(0o644)).unwrap();
+            let target = FileMetadata::new(Path::from(&file)).unwrap();
+            assert!(ctx.hard_link_permitted(&target, &link));
+            fs::set_permissions(&file, fs::Permissions::from_mode(0o4755)).unwrap();
+            let target = FileMetadata::new(Path::from(&file)).unwrap();
+            assert!(!ctx.hard_link_permitted(&target, &link));
+            assert_eq!(counters.not_permitted_link_count(), 1);
+        });
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_move_by_copy_keeps_security_labels() {
+        with_dir("dedupe/move_copy_security_labels", |root| {
+            let log = Log::new();
+            let file_path = root.join("file");
+            let target = Path::from(root.join("target"));
+            create_file(&file_path);
+            if !set_security_label(&file_path) {
+                return;
+            }
+            let file = FileMetadata::new(Path::from(&file_path)).unwrap();
+            let cmd = FsCommand::Move {
+                source: file,
+                target: target.clone(),
+                use_rename: false,
+                allow_copy: true,
+                file_hash: FileHash::from(0),
+            };
+            cmd.execute(&log).unwrap();
+            let labels = xattr::read_security_labels(&target).unwrap();
+            assert!(labels.contains(&(xattr::TEST_SECURITY_LABEL.into(), b"1".to_vec())));
+        })
+    }
+
     #[test]
     fn test_run_dedupe_script() {
         with_dir("dedupe/partition/dedupe_script", |root| {
@@ -1134,11 +3026,229 @@ mod test {
             config.priority = vec![Priority::LeastRecentlyModified];
             let log = Log::new();
             let script = dedupe(vec![group], DedupeOp::Remove, &config, &log);
-            let dedupe_result = run_script(script, &log);
+            let dedupe_result = run_script(script, &RunOptions::default(), &log);
             assert_eq!(dedupe_result.processed_count, 2);
             assert!(!root.join("file_1").exists());
             assert!(!root.join("file_2").exists());
             assert!(root.join("file_3").exists());
         });
     }
+
+    #[test]
+    fn test_dedupe_summary() {
+        with_dir("dedupe/partition/dedupe_summary", |root| {
+            let group = make_group_with_contents(root, &["foo", "foo", "foo"]);
+            let mut config = DedupeConfig::default();
+            config.verify = Verify::Full;
+            let log = Log::new();
+            let run = DedupeSummary::start(&log);
+            config.counters = Some(run.counters.clone());
+            let script = dedupe(vec![group], DedupeOp::Remove, &config, &log);
+            let dedupe_result = run_script(script, &RunOptions::default(), &log);
+            let summary = DedupeSummary::finish(run, &dedupe_result, &log);
+            assert_eq!(summary.file_count, 3);
+            assert_eq!(summary.bytes_hashed, FileLen(9));
+            assert_eq!(summary.processed_count, 2);
+            assert_eq!(summary.failed_count, 0);
+            assert_eq!(summary.events.changed, 0);
+        });
+    }
+
+    #[test]
+    fn test_batch_by_device_orders_hdd_commands_by_directory() {
+        with_dir("dedupe/batch_by_device", |root| {
+            let paths = ["b/2", "a/sub/x", "b/1", "a/2", "a/1"];
+            let commands = paths
+                .iter()
+                .map(|p| {
+                    let path = root.join(p);
+                    fs::create_dir_all(path.parent().unwrap()).unwrap();
+                    create_file(&path);
+                    FsCommand::Remove {
+                        file: FileMetadata::new(Path::from(&path)).unwrap(),
+                        kept: None,
+                        file_hash: FileHash::from(0),
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            let hdd = DiskDevices::single(DiskType::HDD, 1);
+            let batches = batch_by_device(commands, &hdd);
+            assert_eq!(batches.len(), 1);
+            assert!(batches[0].sequential);
+            let order = batches[0]
+                .commands
+                .iter()
+                .map(|c| c.changed_file().path.strip_prefix(&Path::from(&root)))
+                .map(|p| p.unwrap().to_string())
+                .collect::<Vec<_>>();
+            assert_eq!(order, vec!["a/1", "a/2", "a/sub/x", "b/1", "b/2"]);
+
+            let ssd = DiskDevices::single(DiskType::SSD, 1);
+            let batches = batch_by_device(batches.into_iter().next().unwrap().commands, &ssd);
+            assert!(!batches[0].sequential);
+        });
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_busy_file_error_is_transient() {
+        let busy = io::Error::from_raw_os_error(libc::EBUSY);
+        let busy = with_context(format!("Failed to remove file foo: {}", busy), busy);
+        assert!(is_transient(&busy));
+        let not_found = io::Error::from_raw_os_error(libc::ENOENT);
+        let not_found = with_context("Failed to remove file foo".to_owned(), not_found);
+        assert!(!is_transient(&not_found));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_transient_errors_are_retried() {
+        let log = Log::new();
+        let options = RunOptions {
+            retries: 2,
+            retry_delay: std::time::Duration::from_millis(1),
+            ..RunOptions::default()
+        };
+        let busy = || io::Error::from_raw_os_error(libc::EBUSY);
+
+        // Succeeds at the last allowed attempt:
+        let mut attempts = 0;
+        let result = with_retries(&options, &log, || {
+            attempts += 1;
+            if attempts < 3 {
+                Err(busy())
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result.unwrap(), 3);
+
+        // Gives up after the allowed number of retries:
+        let mut attempts = 0;
+        let result: io::Result<()> = with_retries(&options, &log, || {
+            attempts += 1;
+            Err(busy())
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 3);
+
+        // Errors that are not transient are not retried:
+        let mut attempts = 0;
+        let result: io::Result<()> = with_retries(&options, &log, || {
+            attempts += 1;
+            Err(io::Error::from_raw_os_error(libc::ENOENT))
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    fn test_run_dedupe_script_writes_audit_log() {
+        with_dir("dedupe/partition/audit_log", |root| {
+            let mut group = make_group(root);
+            group.file_hash = FileHash::from(0xabcd);
+            let mut config = DedupeConfig::default();
+            config.priority = vec![Priority::LeastRecentlyModified];
+            let log = Log::new();
+            let audit_path = root.join("audit.log");
+            write_file(&audit_path, "{}\n");
+            let audit = AuditLog::open(&audit_path).unwrap();
+            let script = dedupe(vec![group], DedupeOp::Remove, &config, &log);
+            let options = RunOptions {
+                audit: Some(&audit),
+                ..RunOptions::default()
+            };
+            run_script(script, &options, &log);
+
+            let audit = read_file(&audit_path);
+            let mut lines = audit.lines();
+            assert_eq!(lines.next(), Some("{}"));
+            let mut sources = Vec::new();
+            for line in lines {
+                let entry: serde_json::Value = serde_json::from_str(line).unwrap();
+                assert_eq!(entry["action"], "remove");
+                assert_eq!(entry["hash"], "0000000000000000000000000000abcd");
+                assert_eq!(entry["size"], 0);
+                assert_eq!(entry["result"], "ok");
+                sources.push(entry["source"].as_str().unwrap().to_owned());
+            }
+            sources.sort();
+            assert_eq!(
+                sources,
+                vec![
+                    Path::from(root.join("file_1")).to_string(),
+                    Path::from(root.join("file_2")).to_string()
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn test_dedupe_skips_edited_groups_of_text_v2_report() {
+        with_dir("dedupe/edited_text_v2", |root| {
+            let files: Vec<_> = ["a1", "a2", "b1", "b2", "c"]
+                .iter()
+                .map(|name| root.join(name))
+                .collect();
+            for f in files.iter() {
+                write_file(f, "foo");
+            }
+            let group = |hash: u128, files: &[std::path::PathBuf]| {
+                FileGroup::new(
+                    FileLen(3),
+                    FileHash::from(hash),
+                    files.iter().map(Path::from).collect(),
+                )
+            };
+            let groups = [group(1, &files[0..2]), group(2, &files[2..4])];
+            let header = ReportHeader {
+                version: env!("CARGO_PKG_VERSION").to_owned(),
+                timestamp: DateTime::from(Local::now()),
+                command: vec!["fclones".to_owned(), "group".to_owned()],
+                hash: None,
+                stats: None,
+                errors: vec![],
+                root_stats: vec![],
+                ext_stats: vec![],
+                owner_stats: vec![],
+                size_histogram: vec![],
+                copies_histogram: vec![],
+                link_stats: None,
+                host: None,
+            };
+            let mut report = Vec::new();
+            ReportWriter::new(&mut report, false)
+                .write(OutputFormat::TextV2, &header, groups.iter())
+                .unwrap();
+            // Point the second group to a file that was never reported:
+            let report = String::from_utf8(report).unwrap().replace("/b2\n", "/c\n");
+
+            let mut reader = open_report(io::Cursor::new(report)).unwrap();
+            reader.read_header().unwrap();
+            let mut result = Ok(());
+            let groups: Vec<_> = reader
+                .read_groups()
+                .unwrap()
+                .iterator()
+                .map(|g| match g {
+                    Ok(g) => Some(g),
+                    Err(e) => {
+                        result = Err(e);
+                        None
+                    }
+                })
+                .take_while(|g| g.is_some())
+                .map(|g| g.unwrap())
+                .collect();
+            let config = DedupeConfig::default();
+            let script: Vec<_> = dedupe(groups, DedupeOp::Remove, &config, &Log::new()).collect();
+            let removed: Vec<_> = script
+                .iter()
+                .map(|c| c.changed_file().path.to_path_buf())
+                .collect();
+            assert_eq!(removed, vec![files[1].clone()]);
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::InvalidData);
+        });
+    }
 }
@@ -52,6 +52,9 @@ pub struct DiskDevice {
     pub name: OsString,
     pub disk_type: DiskType,
     pub parallelism: Parallelism,
+    /// True if the device is backed by a network file system
+    pub network: bool,
+    buf_len: usize,
     seq_thread_pool: Lazy<ThreadPool>,
     rand_thread_pool: Lazy<ThreadPool>,
 }
@@ -61,6 +64,7 @@ impl DiskDevice {
         index: usize,
         name: OsString,
         disk_type: DiskType,
+        network: bool,
         parallelism: Parallelism,
     ) -> DiskDevice {
         DiskDevice {
@@ -68,30 +72,53 @@ impl DiskDevice {
             name,
             disk_type,
             parallelism,
+            network,
+            buf_len: Self::default_buf_len(disk_type, network),
             seq_thread_pool: Lazy::new(),
             rand_thread_pool: Lazy::new(),
         }
     }
 
-    fn build_thread_pool(num_threads: usize) -> ThreadPool {
+    /// Returns the name of the device without the directory, e.g. `sda1` for `/dev/sda1`
+    pub fn short_name(&self) -> String {
+        let name = self.name.to_string_lossy();
+        match name
+            .trim_end_matches(|c| c == '/' || c == '\\')
+            .rsplit(|c| c == '/' || c == '\\')
+            .next()
+        {
+            Some(short) if !short.is_empty() => short.to_owned(),
+            _ => name.into_owned(),
+        }
+    }
+
+    /// Builds a pool of threads named after the device and the access type,
+    /// so they can be told apart in tools like `top -H`
+    fn build_thread_pool(&self, access: &'static str, num_threads: usize) -> ThreadPool {
+        let name = self.short_name();
         ThreadPoolBuilder::default()
             .num_threads(num_threads)
+            .thread_name(move |i| format!("{}-{}-{}", name, access, i))
             .build()
             .unwrap()
     }
 
     pub fn seq_thread_pool(&self) -> &ThreadPool {
         self.seq_thread_pool
-            .get_or_create(|| Self::build_thread_pool(self.parallelism.sequential))
+            .get_or_create(|| self.build_thread_pool("seq", self.parallelism.sequential))
     }
 
     pub fn rand_thread_pool(&self) -> &ThreadPool {
         self.rand_thread_pool
-            .get_or_create(|| Self::build_thread_pool(self.parallelism.random))
+            .get_or_create(|| self.build_thread_pool("rand", self.parallelism.random))
     }
 
-    pub fn buf_len(&self) -> usize {
-        match self.disk_type {
+    fn default_buf_len(disk_type: DiskType, network: bool) -> usize {
+        if network {
+            // Network file systems suffer from high latency of each request
+            return 1024 * 1024;
+        }
+        match disk_type {
             DiskType::SSD => 64 * 1024,
             DiskType::HDD => 256 * 1024,
             DiskType::Removable => 256 * 1024,
@@ -99,6 +126,10 @@ impl DiskDevice {
         }
     }
 
+    pub fn buf_len(&self) -> usize {
+        self.buf_len
+    }
+
     pub fn min_prefix_len(&self) -> FileLen {
         FileLen(match self.disk_type {
             DiskType::SSD => 4 * 1024,
@@ -135,6 +166,8 @@ impl DiskDevice {
 pub struct DiskDevices {
     devices: Vec<DiskDevice>,
     mount_points: Vec<(Path, usize)>,
+    /// Types of the file systems, by mount point
+    file_systems: HashMap<Path, OsString>,
 }
 
 impl DiskDevices {
@@ -144,6 +177,7 @@ impl DiskDevices {
             0,
             OsString::from("/"),
             disk_type,
+            false,
             Parallelism {
                 random: parallelism,
                 sequential: parallelism,
@@ -152,6 +186,7 @@ impl DiskDevices {
         DiskDevices {
             devices: vec![device],
             mount_points: vec![(Path::from("/"), 0)],
+            file_systems: HashMap::new(),
         }
     }
 
@@ -194,6 +229,7 @@ impl DiskDevices {
         &mut self,
         name: OsString,
         disk_type: DiskType,
+        network: bool,
         pool_sizes: &HashMap<OsString, Parallelism>,
     ) -> usize {
         if let Some((index, _)) = self.devices.iter().find_position(|d| d.name == name) {
@@ -201,12 +237,65 @@ impl DiskDevices {
         } else {
             let index = self.devices.len();
             let parallelism = Self::get_parallelism(&name, disk_type, pool_sizes);
-            self.devices
-                .push(DiskDevice::new(index, name, disk_type, parallelism));
+            self.devices.push(DiskDevice::new(
+                index,
+                name,
+                disk_type,
+                network,
+                parallelism,
+            ));
             index
         }
     }
 
+    /// Overrides the default read buffer sizes of the devices.
+    /// The buffer size for a device is looked up in the `buffer_sizes` map by device name
+    /// prefixed with `dev:`, then by `network` if the device is a network file system,
+    /// then by device type and finally by the `default` key.
+    /// If no key matches, the device keeps its default buffer size.
+    pub fn set_buffer_sizes(&mut self, buffer_sizes: &HashMap<OsString, FileLen>) {
+        for device in self.devices.iter_mut() {
+            let mut dev_key = OsString::new();
+            dev_key.push("dev:");
+            dev_key.push(&device.name);
+            let type_key = match device.disk_type {
+                DiskType::SSD => "ssd",
+                DiskType::HDD => "hdd",
+                DiskType::Removable => "removable",
+                DiskType::Unknown(_) => "unknown",
+            };
+            let size = buffer_sizes
+                .get(&dev_key)
+                .or_else(|| {
+                    if device.network {
+                        buffer_sizes.get(OsStr::new("network"))
+                    } else {
+                        None
+                    }
+                })
+                .or_else(|| buffer_sizes.get(OsStr::new(type_key)))
+                .or_else(|| buffer_sizes.get(OsStr::new("default")));
+            if let Some(size) = size {
+                device.buf_len = size.0 as usize;
+            }
+        }
+    }
+
+    /// Returns true if the file system type denotes a network file system
+    fn is_network_fs(fs_type: &[u8]) -> bool {
+        const NETWORK_FS: [&[u8]; 8] = [
+            b"nfs",
+            b"nfs4",
+            b"cifs",
+            b"smbfs",
+            b"smb3",
+            b"afs",
+            b"9p",
+            b"fuse.sshfs",
+        ];
+        NETWORK_FS.contains(&fs_type)
+    }
+
     /// If `name` is a disk partition, it attempts to return the disk device name the partition
     /// resides on. Otherwise, and on failures, it just returns the same `name`.
     #[cfg(target_os = "linux")]
@@ -235,17 +324,27 @@ impl DiskDevices {
         let mut result = DiskDevices {
             devices: Vec::new(),
             mount_points: Vec::new(),
+            file_systems: HashMap::new(),
         };
 
         // Default device used when we don't find any real device
-        result.add_device(OsString::from("default"), DiskType::Unknown(-1), pool_sizes);
+        result.add_device(
+            OsString::from("default"),
+            DiskType::Unknown(-1),
+            false,
+            pool_sizes,
+        );
 
         for d in sys.get_disks() {
             let device_name = Self::physical_device_name(d.get_name());
-            let index = result.add_device(device_name, d.get_type(), pool_sizes);
+            let network = Self::is_network_fs(d.get_file_system());
+            let index = result.add_device(device_name, d.get_type(), network, pool_sizes);
+            let mount_point = Path::from(d.get_mount_point());
+            let file_system = String::from_utf8_lossy(d.get_file_system()).into_owned();
             result
-                .mount_points
-                .push((Path::from(d.get_mount_point()), index));
+                .file_systems
+                .insert(mount_point.clone(), OsString::from(file_system));
+            result.mount_points.push((mount_point, index));
         }
         result
             .mount_points
@@ -263,6 +362,13 @@ impl DiskDevices {
             .unwrap_or(&self.mount_points[0].0)
     }
 
+    /// Returns the type of the file system holding given path, e.g. `ext4` or `nfs`
+    pub fn get_file_system(&self, path: &Path) -> Option<&OsStr> {
+        self.file_systems
+            .get(self.get_mount_point(path))
+            .map(|fs| fs.as_os_str())
+    }
+
     /// Returns the disk device which holds the given path
     pub fn get_by_path(&self, path: &Path) -> &DiskDevice {
         self.mount_points
@@ -317,6 +423,40 @@ impl Index<usize> for DiskDevices {
 mod test {
     use super::*;
 
+    #[test]
+    fn test_buffer_sizes() {
+        let mut devices = DiskDevices::single(DiskType::SSD, 1);
+        assert_eq!(devices.get_default().buf_len(), 64 * 1024);
+
+        let mut sizes = HashMap::new();
+        sizes.insert(OsString::from("hdd"), FileLen(1024));
+        devices.set_buffer_sizes(&sizes);
+        assert_eq!(devices.get_default().buf_len(), 64 * 1024);
+
+        sizes.insert(OsString::from("default"), FileLen(2048));
+        devices.set_buffer_sizes(&sizes);
+        assert_eq!(devices.get_default().buf_len(), 2048);
+
+        sizes.insert(OsString::from("ssd"), FileLen(4096));
+        devices.set_buffer_sizes(&sizes);
+        assert_eq!(devices.get_default().buf_len(), 4096);
+
+        sizes.insert(OsString::from("dev:/"), FileLen(8192));
+        devices.set_buffer_sizes(&sizes);
+        assert_eq!(devices.get_default().buf_len(), 8192);
+    }
+
+    #[test]
+    fn test_short_name() {
+        let device = |name: &str| {
+            let parallelism = Parallelism::default_for(DiskType::SSD);
+            DiskDevice::new(0, OsString::from(name), DiskType::SSD, false, parallelism)
+        };
+        assert_eq!(device("/dev/sda1").short_name(), "sda1");
+        assert_eq!(device("nvme0n1").short_name(), "nvme0n1");
+        assert_eq!(device("/").short_name(), "/");
+    }
+
     #[test]
     fn test_physical_device_name() {
         assert_eq!(
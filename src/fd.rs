@@ -0,0 +1,192 @@
+//! Limiting the number of files open at the same time.
+//!
+//! Each thread hashing or deduplicating files keeps one or two files open.
+//! With many threads and a low limit of open file descriptors set by the operating system,
+//! opening files could fail with `EMFILE` ("Too many open files"). To prevent that, the
+//! threads reserve descriptors from a budget shared by the whole process before opening
+//! any files and wait for other threads to close their files if the budget is exhausted.
+
+use std::cmp::max;
+use std::sync::{Condvar, Mutex};
+
+use lazy_static::lazy_static;
+
+/// Number of descriptors left out of the budget for the standard streams, the log files,
+/// the report and the directories being read by the walker.
+const RESERVED: usize = 64;
+
+struct State {
+    limit: usize,
+    open: usize,
+}
+
+/// Limits the number of file descriptors open at the same time.
+pub(crate) struct FdBudget {
+    state: Mutex<State>,
+    cvar: Condvar,
+}
+
+/// Returns the reserved descriptors to the budget when dropped.
+pub(crate) struct FdGuard<'a> {
+    budget: &'a FdBudget,
+    count: usize,
+}
+
+impl FdBudget {
+    pub fn new(limit: usize) -> FdBudget {
+        FdBudget {
+            state: Mutex::new(State {
+                limit: max(limit, 1),
+                open: 0,
+            }),
+            cvar: Condvar::new(),
+        }
+    }
+
+    /// Reserves `count` descriptors, blocking until they are available.
+    /// If `count` exceeds the limit, waits until no other descriptors are reserved,
+    /// so a single request can always make progress.
+    pub fn acquire(&self, count: usize) -> FdGuard<'_> {
+        let mut state = self.state.lock().unwrap();
+        while state.open > 0 && state.open + count > state.limit {
+            state = self.cvar.wait(state).unwrap();
+        }
+        state.open += count;
+        FdGuard {
+            budget: self,
+            count,
+        }
+    }
+
+    /// Reserves `count` descriptors without waiting, even if that exceeds the limit.
+    /// Meant for descriptors duplicated from ones reserved already, where waiting for
+    /// other threads could deadlock. The excess delays the following calls to `acquire`.
+    pub fn reserve(&self, count: usize) -> FdGuard<'_> {
+        self.state.lock().unwrap().open += count;
+        FdGuard {
+            budget: self,
+            count,
+        }
+    }
+
+    /// Changes the maximum number of descriptors that can be reserved at the same time.
+    /// Descriptors reserved already are not affected.
+    pub fn set_limit(&self, limit: usize) {
+        self.state.lock().unwrap().limit = max(limit, 1);
+        self.cvar.notify_all();
+    }
+
+    /// Returns the number of descriptors reserved at the moment
+    pub fn open_count(&self) -> usize {
+        self.state.lock().unwrap().open
+    }
+
+    fn release(&self, count: usize) {
+        self.state.lock().unwrap().open -= count;
+        self.cvar.notify_all();
+    }
+}
+
+impl Drop for FdGuard<'_> {
+    fn drop(&mut self) {
+        self.budget.release(self.count)
+    }
+}
+
+lazy_static! {
+    static ref BUDGET: FdBudget = FdBudget::new(default_limit());
+}
+
+/// Reserves `count` descriptors from the budget of the process.
+/// Blocks until other threads release enough descriptors.
+pub(crate) fn acquire(count: usize) -> FdGuard<'static> {
+    BUDGET.acquire(count)
+}
+
+/// Reserves `count` descriptors from the budget of the process without waiting,
+/// see [`FdBudget::reserve`].
+pub(crate) fn reserve(count: usize) -> FdGuard<'static> {
+    BUDGET.reserve(count)
+}
+
+/// Returns the number of files kept open at the moment for hashing or deduplicating
+pub(crate) fn open_count() -> usize {
+    BUDGET.open_count()
+}
+
+/// Sets the maximum number of files the process keeps open at the same time
+/// for hashing or deduplicating.
+pub(crate) fn set_limit(limit: usize) {
+    BUDGET.set_limit(limit)
+}
+
+/// Returns the soft limit of open file descriptors, less the descriptors needed for other
+/// purposes than reading the files.
+#[cfg(unix)]
+pub(crate) fn default_limit() -> usize {
+    let mut rlimit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut rlimit) } != 0 {
+        return 1024 - RESERVED;
+    }
+    let limit = rlimit.rlim_cur.min(usize::MAX as libc::rlim_t) as usize;
+    max(limit.saturating_sub(RESERVED), limit / 2)
+}
+
+/// Windows doesn't limit the number of open handles per process,
+/// so this is just high enough to never be a bottleneck.
+#[cfg(not(unix))]
+pub(crate) fn default_limit() -> usize {
+    16384 - RESERVED
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn acquire_blocks_until_descriptors_are_released() {
+        let budget = FdBudget::new(2);
+        let released = AtomicBool::new(false);
+        crossbeam_utils::thread::scope(|s| {
+            let guard = budget.acquire(2);
+            s.spawn(|_| {
+                let _guard = budget.acquire(1);
+                assert!(released.load(Ordering::SeqCst));
+            });
+            thread::sleep(Duration::from_millis(100));
+            released.store(true, Ordering::SeqCst);
+            drop(guard);
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn acquire_more_than_limit_if_nothing_else_is_open() {
+        let budget = FdBudget::new(1);
+        let _guard = budget.acquire(2);
+    }
+
+    #[test]
+    fn reserve_counts_against_limit_without_waiting() {
+        let budget = FdBudget::new(1);
+        let guard = budget.acquire(1);
+        let extra = budget.reserve(1);
+        assert_eq!(budget.open_count(), 2);
+        drop(guard);
+        assert_eq!(budget.open_count(), 1);
+        drop(extra);
+        assert_eq!(budget.open_count(), 0);
+    }
+
+    #[test]
+    fn default_limit_is_positive() {
+        assert!(default_limit() > 0);
+    }
+}
@@ -0,0 +1,498 @@
+//! C interface for embedding fclones in programs written in other languages,
+//! enabled by the `ffi` feature.
+//!
+//! Build the shared library with:
+//! ```text
+//! cargo rustc --release --lib --features ffi --crate-type cdylib
+//! ```
+//! The functions and types are declared in `include/fclones.h`.
+//!
+//! Conventions:
+//! - Strings are null-terminated. Paths are passed as raw bytes on Unix and as UTF-8 elsewhere.
+//! - Pointers passed to the functions must be valid for the duration of the call.
+//!   Pointers passed to the callbacks are valid only until the callback returns.
+//! - Objects created by a `*_new` function must be released by the matching `*_free` function.
+//! - Functions returning `int` return 0 on success and -1 on failure, including when
+//!   passed a null pointer or when an internal error (panic) occurs.
+//!   The message describing the last failure on the current thread is returned by
+//!   `fclones_last_error`.
+#![allow(clippy::missing_safety_doc)]
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::fmt::Display;
+use std::io;
+use std::os::raw::{c_char, c_int, c_void};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::ptr;
+
+use chrono::{DateTime, FixedOffset, TimeZone, Utc};
+
+use crate::config::{DedupeConfig, GroupConfig};
+use crate::dedupe::{dedupe, log_script, run_script, DedupeOp, DedupeResult, RunOptions};
+use crate::files::{FileHash, FileLen};
+use crate::log::Log;
+use crate::path::Path;
+use crate::{group_files_to_sink, FileGroup};
+
+pub const FCLONES_REMOVE: c_int = 0;
+pub const FCLONES_SOFT_LINK: c_int = 1;
+pub const FCLONES_HARD_LINK: c_int = 2;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+fn set_last_error(message: impl Display) {
+    let message = message.to_string().replace('\0', "");
+    LAST_ERROR.with(|e| *e.borrow_mut() = CString::new(message).ok());
+}
+
+/// Runs the operation and converts its result to the return code of a C function.
+/// Panics are caught, because unwinding into the C caller is undefined behavior.
+fn status(op: impl FnOnce() -> Result<(), String>) -> c_int {
+    match catch_unwind(AssertUnwindSafe(op)) {
+        Ok(Ok(())) => 0,
+        Ok(Err(e)) => {
+            set_last_error(e);
+            -1
+        }
+        Err(panic) => {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|s| s.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "Unknown error".to_owned());
+            set_last_error(format!("Internal error: {}", message));
+            -1
+        }
+    }
+}
+
+unsafe fn deref<'a, T>(p: *const T, name: &str) -> Result<&'a T, String> {
+    p.as_ref().ok_or_else(|| format!("Null {}", name))
+}
+
+unsafe fn deref_mut<'a, T>(p: *mut T, name: &str) -> Result<&'a mut T, String> {
+    p.as_mut().ok_or_else(|| format!("Null {}", name))
+}
+
+/// Runs the operation on the configuration, if it is not null
+unsafe fn configure(config: *mut FclonesConfig, op: impl FnOnce(&mut GroupConfig)) -> c_int {
+    status(|| {
+        op(&mut deref_mut(config, "config")?.config);
+        Ok(())
+    })
+}
+
+unsafe fn to_str<'a>(s: *const c_char) -> Result<&'a str, String> {
+    if s.is_null() {
+        return Err("Null string".to_owned());
+    }
+    CStr::from_ptr(s).to_str().map_err(|e| e.to_string())
+}
+
+#[cfg(unix)]
+unsafe fn to_path_buf(s: *const c_char) -> Result<PathBuf, String> {
+    use std::ffi::OsStr;
+    use std::os::unix::ffi::OsStrExt;
+    if s.is_null() {
+        return Err("Null path".to_owned());
+    }
+    Ok(PathBuf::from(OsStr::from_bytes(
+        CStr::from_ptr(s).to_bytes(),
+    )))
+}
+
+#[cfg(not(unix))]
+unsafe fn to_path_buf(s: *const c_char) -> Result<PathBuf, String> {
+    to_str(s).map(PathBuf::from)
+}
+
+#[cfg(unix)]
+fn to_c_string(path: &Path) -> CString {
+    use std::os::unix::ffi::OsStringExt;
+    // Paths can't contain null bytes on Unix
+    CString::new(path.to_path_buf().into_os_string().into_vec()).unwrap()
+}
+
+#[cfg(not(unix))]
+fn to_c_string(path: &Path) -> CString {
+    CString::new(path.to_string_lossy()).unwrap()
+}
+
+fn new_log() -> Log {
+    let mut log = Log::new();
+    log.no_progress = true;
+    log
+}
+
+/// Returns the message describing the last failure on the current thread or null if there was
+/// no failure. The message is valid until the next call to a function of this library.
+#[no_mangle]
+pub extern "C" fn fclones_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| e.borrow().as_ref().map_or(ptr::null(), |s| s.as_ptr()))
+}
+
+/// Configuration of a scan for duplicate files, opaque to C
+pub struct FclonesConfig {
+    config: GroupConfig,
+}
+
+/// Returns a new scan configuration with the defaults of the `group` command
+#[no_mangle]
+pub extern "C" fn fclones_config_new() -> *mut FclonesConfig {
+    let config = GroupConfig::builder().build();
+    Box::into_raw(Box::new(FclonesConfig { config }))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn fclones_config_free(config: *mut FclonesConfig) {
+    if !config.is_null() {
+        drop(Box::from_raw(config))
+    }
+}
+
+/// Adds a file or directory to scan
+#[no_mangle]
+pub unsafe extern "C" fn fclones_config_add_path(
+    config: *mut FclonesConfig,
+    path: *const c_char,
+) -> c_int {
+    status(|| {
+        let config = deref_mut(config, "config")?;
+        config.config.paths.push(to_path_buf(path)?);
+        Ok(())
+    })
+}
+
+/// Adds a glob pattern the file names must match
+#[no_mangle]
+pub unsafe extern "C" fn fclones_config_add_name_pattern(
+    config: *mut FclonesConfig,
+    pattern: *const c_char,
+) -> c_int {
+    status(|| {
+        let config = deref_mut(config, "config")?;
+        config
+            .config
+            .name_patterns
+            .push(to_str(pattern)?.to_owned());
+        Ok(())
+    })
+}
+
+/// Adds a glob pattern of the paths to skip
+#[no_mangle]
+pub unsafe extern "C" fn fclones_config_add_exclude_pattern(
+    config: *mut FclonesConfig,
+    pattern: *const c_char,
+) -> c_int {
+    status(|| {
+        let config = deref_mut(config, "config")?;
+        config
+            .config
+            .exclude_patterns
+            .push(to_str(pattern)?.to_owned());
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn fclones_config_set_min_size(
+    config: *mut FclonesConfig,
+    bytes: u64,
+) -> c_int {
+    configure(config, |c| c.min_size = FileLen(bytes))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn fclones_config_set_max_size(
+    config: *mut FclonesConfig,
+    bytes: u64,
+) -> c_int {
+    configure(config, |c| c.max_size = Some(FileLen(bytes)))
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn fclones_config_set_skip_hidden(
+    config: *mut FclonesConfig,
+    skip: bool,
+) -> c_int {
+    configure(config, |c| c.skip_hidden = skip)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn fclones_config_set_follow_links(
+    config: *mut FclonesConfig,
+    follow: bool,
+) -> c_int {
+    configure(config, |c| c.follow_links = follow)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn fclones_config_set_hard_links(
+    config: *mut FclonesConfig,
+    hard_links: bool,
+) -> c_int {
+    configure(config, |c| c.hard_links = hard_links)
+}
+
+/// Reports files with more than `count` copies. The default is 1, i.e. all duplicates.
+#[no_mangle]
+pub unsafe extern "C" fn fclones_config_set_rf_over(
+    config: *mut FclonesConfig,
+    count: usize,
+) -> c_int {
+    configure(config, |c| c.rf_over = Some(count))
+}
+
+/// A group of identical files
+#[repr(C)]
+pub struct FclonesGroup {
+    pub file_len: u64,
+    /// Hash of the contents as hexadecimal digits, may be null
+    pub file_hash: *const c_char,
+    pub files: *const *const c_char,
+    pub file_count: usize,
+    /// Start of the scan that found the group, in milliseconds since the Unix epoch.
+    /// `fclones_dedupe` leaves intact the files modified after that time.
+    /// Zero if unknown, which disables the check.
+    pub scan_start_ms: i64,
+}
+
+/// Receives the groups found by `fclones_group`
+pub type FclonesGroupCallback = extern "C" fn(group: *const FclonesGroup, user_data: *mut c_void);
+
+/// Scans the files as configured and passes each group of duplicates to the `callback`
+/// as soon as it is found, together with the `user_data`.
+/// The callback is called on the calling thread. Blocks until the scan completes.
+#[no_mangle]
+pub unsafe extern "C" fn fclones_group(
+    config: *const FclonesConfig,
+    callback: Option<FclonesGroupCallback>,
+    user_data: *mut c_void,
+) -> c_int {
+    status(|| {
+        let config = deref(config, "config")?;
+        let log = new_log();
+        let result = group_files_to_sink(&config.config, &log, |group| {
+            let callback = match callback {
+                Some(callback) => callback,
+                None => return,
+            };
+            let file_hash = CString::new(group.file_hash.to_string()).unwrap();
+            let files: Vec<CString> = group.files.iter().map(to_c_string).collect();
+            let file_ptrs: Vec<*const c_char> = files.iter().map(|f| f.as_ptr()).collect();
+            let c_group = FclonesGroup {
+                file_len: group.file_len.0,
+                file_hash: file_hash.as_ptr(),
+                files: file_ptrs.as_ptr(),
+                file_count: file_ptrs.len(),
+                scan_start_ms: log.scan_start().map_or(0, |t| t.timestamp_millis()),
+            };
+            callback(&c_group, user_data);
+        });
+        result.map(|_| ()).map_err(|e| e.to_string())
+    })
+}
+
+/// Number of files deduplicated by `fclones_dedupe` and the amount of space reclaimed
+#[repr(C)]
+#[derive(Default)]
+pub struct FclonesDedupeResult {
+    pub processed_count: u64,
+    pub reclaimed_bytes: u64,
+    pub failed_count: u64,
+}
+
+unsafe fn to_file_group(group: &FclonesGroup) -> Result<FileGroup<Path>, String> {
+    let file_hash = if group.file_hash.is_null() {
+        FileHash::default()
+    } else {
+        FileHash::from_hex(to_str(group.file_hash)?)?
+    };
+    if group.files.is_null() && group.file_count > 0 {
+        return Err("Null file list".to_owned());
+    }
+    let files = (0..group.file_count)
+        .map(|i| to_path_buf(*group.files.add(i)).map(Path::from))
+        .collect::<Result<_, _>>()?;
+    Ok(FileGroup::new(FileLen(group.file_len), file_hash, files))
+}
+
+/// Removes the redundant files of the `group`, or replaces them with links, leaving the oldest
+/// file intact. `op` is one of `FCLONES_REMOVE`, `FCLONES_SOFT_LINK` or `FCLONES_HARD_LINK`.
+/// Files that changed length since they were grouped, or were modified after
+/// the `scan_start_ms` of the group, are left intact.
+/// If `dry_run` is set, only counts the files that would be processed.
+/// The counts are stored in `result` if it is not null.
+#[no_mangle]
+pub unsafe extern "C" fn fclones_dedupe(
+    group: *const FclonesGroup,
+    op: c_int,
+    dry_run: bool,
+    result: *mut FclonesDedupeResult,
+) -> c_int {
+    status(|| {
+        let op = match op {
+            FCLONES_REMOVE => DedupeOp::Remove,
+            FCLONES_SOFT_LINK => DedupeOp::SoftLink,
+            FCLONES_HARD_LINK => DedupeOp::HardLink,
+            _ => return Err(format!("Invalid deduplication operation: {}", op)),
+        };
+        let c_group = deref(group, "group")?;
+        let modified_before = scan_start(c_group.scan_start_ms)?;
+        let group = to_file_group(c_group)?;
+        let log = new_log();
+        let config = DedupeConfig {
+            dry_run,
+            modified_before,
+            ..DedupeConfig::default()
+        };
+        let script = dedupe(vec![group], op, &config, &log);
+        let counts: io::Result<DedupeResult> = if dry_run {
+            log_script(script, io::sink())
+        } else {
+            Ok(run_script(script, &RunOptions::default(), &log))
+        };
+        let counts = counts.map_err(|e| e.to_string())?;
+        if !result.is_null() {
+            *result = FclonesDedupeResult {
+                processed_count: counts.processed_count,
+                reclaimed_bytes: counts.reclaimed_space.0,
+                failed_count: counts.failed_count,
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Converts the `scan_start_ms` of a group to the `modified_before` limit of deduplication
+fn scan_start(millis: i64) -> Result<Option<DateTime<FixedOffset>>, String> {
+    if millis == 0 {
+        return Ok(None);
+    }
+    match Utc.timestamp_millis_opt(millis).single() {
+        Some(time) => Ok(Some(time.into())),
+        None => Err(format!("Invalid scan start time: {}", millis)),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::util::test::{with_dir, write_file};
+
+    use super::*;
+
+    extern "C" fn collect_group(group: *const FclonesGroup, user_data: *mut c_void) {
+        let groups = unsafe { &mut *(user_data as *mut Vec<Vec<CString>>) };
+        let group = unsafe { &*group };
+        let files = (0..group.file_count)
+            .map(|i| unsafe { CStr::from_ptr(*group.files.add(i)).to_owned() })
+            .collect();
+        groups.push(files);
+    }
+
+    #[test]
+    fn group_and_dedupe_through_c_interface() {
+        with_dir("ffi/group_and_dedupe", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            write_file(&file1, "foo");
+            write_file(&file2, "foo");
+
+            let mut groups: Vec<Vec<CString>> = Vec::new();
+            unsafe {
+                let config = fclones_config_new();
+                for f in [&file1, &file2].iter() {
+                    let path = CString::new(f.to_str().unwrap()).unwrap();
+                    assert_eq!(fclones_config_add_path(config, path.as_ptr()), 0);
+                }
+                let user_data = &mut groups as *mut Vec<Vec<CString>> as *mut c_void;
+                assert_eq!(fclones_group(config, Some(collect_group), user_data), 0);
+                fclones_config_free(config);
+            }
+            assert_eq!(groups.len(), 1);
+            assert_eq!(groups[0].len(), 2);
+
+            let file_ptrs: Vec<_> = groups[0].iter().map(|f| f.as_ptr()).collect();
+            let group = FclonesGroup {
+                file_len: 3,
+                file_hash: ptr::null(),
+                files: file_ptrs.as_ptr(),
+                file_count: file_ptrs.len(),
+                scan_start_ms: 0,
+            };
+            let mut result = FclonesDedupeResult::default();
+            unsafe {
+                assert_eq!(fclones_dedupe(&group, FCLONES_REMOVE, true, &mut result), 0);
+                assert_eq!(fclones_dedupe(&group, 42, true, &mut result), -1);
+                assert!(!fclones_last_error().is_null());
+            }
+            assert_eq!(result.processed_count, 1);
+            assert_eq!(result.reclaimed_bytes, 3);
+            assert!(file1.exists() && file2.exists());
+        });
+    }
+
+    extern "C" fn dedupe_group(group: *const FclonesGroup, user_data: *mut c_void) {
+        let results = unsafe { &mut *(user_data as *mut Vec<FclonesDedupeResult>) };
+        let mut result = FclonesDedupeResult::default();
+        assert_eq!(
+            unsafe { fclones_dedupe(group, FCLONES_REMOVE, false, &mut result) },
+            0
+        );
+        results.push(result);
+    }
+
+    #[test]
+    fn dedupe_through_c_interface() {
+        with_dir("ffi/dedupe", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            write_file(&file1, "foo");
+            write_file(&file2, "foo");
+
+            let mut results: Vec<FclonesDedupeResult> = Vec::new();
+            unsafe {
+                let config = fclones_config_new();
+                let path = CString::new(root.to_str().unwrap()).unwrap();
+                assert_eq!(fclones_config_add_path(config, path.as_ptr()), 0);
+                let user_data = &mut results as *mut Vec<FclonesDedupeResult> as *mut c_void;
+                assert_eq!(fclones_group(config, Some(dedupe_group), user_data), 0);
+                fclones_config_free(config);
+            }
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].processed_count, 1);
+            assert_eq!(results[0].reclaimed_bytes, 3);
+            assert_eq!(results[0].failed_count, 0);
+            assert!(file1.exists() != file2.exists());
+        });
+    }
+
+    #[test]
+    fn null_pointers_are_rejected() {
+        unsafe {
+            assert_eq!(fclones_config_set_min_size(ptr::null_mut(), 1), -1);
+            assert_eq!(fclones_config_add_path(ptr::null_mut(), ptr::null()), -1);
+            assert_eq!(fclones_group(ptr::null(), None, ptr::null_mut()), -1);
+            let result = ptr::null_mut();
+            assert_eq!(
+                fclones_dedupe(ptr::null(), FCLONES_REMOVE, true, result),
+                -1
+            );
+            let group = FclonesGroup {
+                file_len: 3,
+                file_hash: ptr::null(),
+                files: ptr::null(),
+                file_count: 2,
+                scan_start_ms: 0,
+            };
+            assert_eq!(fclones_dedupe(&group, FCLONES_REMOVE, true, result), -1);
+            let message = CStr::from_ptr(fclones_last_error()).to_str().unwrap();
+            assert_eq!(message, "Null file list");
+        }
+    }
+}
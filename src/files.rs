@@ -4,7 +4,8 @@
 use core::fmt;
 use std::cell::RefCell;
 use std::cmp::{max, min};
-use std::fmt::Display;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Write as FmtWrite};
 use std::fs::{File, OpenOptions};
 use std::hash::{Hash, Hasher};
 use std::io;
@@ -15,19 +16,30 @@ use std::ops::{Add, AddAssign, BitXor, Mul, Sub};
 use std::os::unix::fs::OpenOptionsExt;
 #[cfg(unix)]
 use std::os::unix::io::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 
 use byte_unit::Byte;
-use bytesize::ByteSize;
 use metrohash::MetroHash128;
 use serde::*;
 use smallvec::alloc::fmt::Formatter;
 use smallvec::alloc::str::FromStr;
+use smallvec::SmallVec;
 #[cfg(unix)]
 use sysinfo::{System, SystemExt};
 
 use crate::device::DiskDevices;
-use crate::log::Log;
+use crate::fd;
+use crate::hash_backend::{CommandDigest, HashBackend};
+use crate::hash_pool;
+use crate::io_timeout;
+use crate::io_timeout::TimedRead;
+use crate::log::{FileOp, Log};
 use crate::path::Path;
+#[cfg(unix)]
+use crate::sigbus;
+#[cfg(unix)]
+use crate::walk::FileStat;
 
 /// Represents data position in the file, counted from the beginning of the file, in bytes.
 /// Provides more type safety and nicer formatting over using a raw u64.
@@ -171,9 +183,133 @@ impl FromStr for FileLen {
     }
 }
 
+/// An inclusive range of file sizes, written as `min..max`, e.g. `1M..2G`.
+/// Either bound can be omitted, e.g. `..100K` or `1G..`, and a single size, e.g. `4K`,
+/// matches only that size.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SizeRange {
+    pub min: FileLen,
+    pub max: FileLen,
+}
+
+impl SizeRange {
+    pub fn contains(&self, len: FileLen) -> bool {
+        len >= self.min && len <= self.max
+    }
+
+    /// Returns true if any of the ranges contains the size, or if there are no ranges
+    pub fn any_contains(ranges: &[SizeRange], len: FileLen) -> bool {
+        ranges.is_empty() || ranges.iter().any(|r| r.contains(len))
+    }
+}
+
+impl FromStr for SizeRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |bound: &str, default: FileLen| match bound.trim() {
+            "" => Ok(default),
+            bound => FileLen::from_str(bound).map_err(|e| format!("Invalid size {}: {}", bound, e)),
+        };
+        let range = match s.find("..") {
+            Some(i) => SizeRange {
+                min: parse(&s[..i], FileLen(0))?,
+                max: parse(&s[i + 2..], FileLen::MAX)?,
+            },
+            None if s.trim().is_empty() => return Err("Empty size range".to_owned()),
+            None => {
+                let len = parse(s, FileLen(0))?;
+                SizeRange { min: len, max: len }
+            }
+        };
+        if range.min > range.max {
+            return Err(format!(
+                "Invalid size range {}: The lower bound is greater than the upper bound",
+                s
+            ));
+        }
+        Ok(range)
+    }
+}
+
+/// Use powers of 1024 (KiB, MiB, ...) instead of powers of 1000 (KB, MB, ...)
+/// in the human-readable sizes
+static BINARY_UNITS: AtomicBool = AtomicBool::new(false);
+/// Use a comma instead of a dot as the decimal separator in the human-readable sizes
+static DECIMAL_COMMA: AtomicBool = AtomicBool::new(false);
+
+/// Sets the units of the sizes displayed by `FileLen`.
+/// Affects only the human-readable sizes; byte counts are always written in full.
+pub fn set_binary_units(binary: bool) {
+    BINARY_UNITS.store(binary, Ordering::Relaxed);
+}
+
+/// Sets whether the sizes displayed by `FileLen` use a comma as the decimal separator
+pub fn set_decimal_comma(comma: bool) {
+    DECIMAL_COMMA.store(comma, Ordering::Relaxed);
+}
+
+/// Languages writing numbers with a decimal comma, e.g. `1,5`
+const DECIMAL_COMMA_LANGUAGES: [&str; 40] = [
+    "af", "az", "be", "bg", "bs", "ca", "cs", "da", "de", "el", "es", "et", "eu", "fi", "fr", "gl",
+    "hr", "hu", "id", "is", "it", "ka", "kk", "lt", "lv", "mk", "nb", "nl", "nn", "no", "pl", "pt",
+    "ro", "ru", "sk", "sl", "sq", "sr", "sv", "uk",
+];
+
+/// Countries where the languages above use a decimal dot, e.g. Switzerland and Mexico
+const DECIMAL_DOT_LOCALES: [&str; 5] = ["de_CH", "de_LI", "it_CH", "es_MX", "es_US"];
+
+/// Returns true if numbers are written with a decimal comma in the given POSIX locale,
+/// e.g. `de_DE.UTF-8`
+pub fn uses_decimal_comma(locale: &str) -> bool {
+    let locale = locale
+        .split(|c| c == '.' || c == '@')
+        .next()
+        .unwrap_or_default();
+    let language = locale.split('_').next().unwrap_or_default();
+    DECIMAL_COMMA_LANGUAGES.contains(&language) && !DECIMAL_DOT_LOCALES.contains(&locale)
+}
+
+/// Returns the locale used for formatting numbers, taken from the `LC_ALL`, `LC_NUMERIC`
+/// and `LANG` environment variables, in this order
+pub fn numeric_locale() -> Option<String> {
+    ["LC_ALL", "LC_NUMERIC", "LANG"]
+        .iter()
+        .filter_map(|v| std::env::var(v).ok())
+        .find(|v| !v.is_empty())
+}
+
+/// Formats the size in a human-readable form, e.g. `1.5 MB`
+fn format_size(len: u64, binary: bool, decimal_comma: bool) -> String {
+    const PREFIXES: [char; 6] = ['K', 'M', 'G', 'T', 'P', 'E'];
+    let (unit, suffix) = if binary {
+        (1024.0, "iB")
+    } else {
+        (1000.0, "B")
+    };
+    let mut size = len as f64;
+    if size < unit {
+        return format!("{} B", len);
+    }
+    let mut prefix = 0;
+    size /= unit;
+    while size >= unit && prefix < PREFIXES.len() - 1 {
+        size /= unit;
+        prefix += 1;
+    }
+    let size = format!("{:.1} {}{}", size, PREFIXES[prefix], suffix);
+    if decimal_comma {
+        size.replace('.', ",")
+    } else {
+        size
+    }
+}
+
 impl Display for FileLen {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        f.pad(format!("{}", ByteSize(self.0)).as_str())
+        let binary = BINARY_UNITS.load(Ordering::Relaxed);
+        let decimal_comma = DECIMAL_COMMA.load(Ordering::Relaxed);
+        f.pad(format_size(self.0, binary, decimal_comma).as_str())
     }
 }
 
@@ -214,10 +350,10 @@ const DEVICE_MASK: u64 = 0xFFFF000000000000;
 
 impl FileInfo {
     #[cfg(unix)]
-    fn new(path: Path, devices: &DiskDevices) -> io::Result<FileInfo> {
+    fn new(path: Path, devices: &DiskDevices, timeout: Option<Duration>) -> io::Result<FileInfo> {
         use std::os::unix::fs::MetadataExt;
 
-        match std::fs::metadata(&path.to_path_buf()) {
+        match io_timeout::metadata(&path, timeout) {
             Ok(metadata) => {
                 let device_index = devices.get_by_path(&path).index as u64;
                 Ok(FileInfo {
@@ -233,10 +369,25 @@ impl FileInfo {
         }
     }
 
+    /// Creates the file info from the metadata fetched by the directory walker,
+    /// without reading the metadata again.
+    #[cfg(unix)]
+    pub(crate) fn from_stat(path: Path, stat: FileStat, devices: &DiskDevices) -> FileInfo {
+        let device_index = devices.get_by_path(&path).index as u64;
+        FileInfo {
+            path,
+            len: FileLen(stat.len),
+            location: device_index << 48 | stat.ino & OFFSET_MASK,
+        }
+    }
+
     #[cfg(windows)]
-    fn new(path: Path, devices: &DiskDevices) -> io::Result<FileInfo> {
-        let info = File::open(path.to_path_buf()).and_then(|f| winapi_util::file::information(&f));
-        match info {
+    fn new(path: Path, devices: &DiskDevices, timeout: Option<Duration>) -> io::Result<FileInfo> {
+        let path_buf = path.to_path_buf();
+        let info = io_timeout::run(timeout, move || {
+            File::open(path_buf).and_then(|f| winapi_util::file::information(&f))
+        });
+        match info.and_then(|info| info) {
             Ok(info) => {
                 let device_index = devices.get_by_path(&path).index as u64;
                 Ok(FileInfo {
@@ -267,23 +418,68 @@ impl FileInfo {
     }
 }
 
-/// Returns file information for the given path.
+/// Returns file information for the given path, giving up after `timeout`.
 /// On failure, logs an error to stderr and returns `None`.
 pub(crate) fn file_info_or_log_err(
     file: Path,
     devices: &DiskDevices,
+    timeout: Option<Duration>,
     log: &Log,
 ) -> Option<FileInfo> {
-    match FileInfo::new(file, devices) {
+    match FileInfo::new(file.clone(), devices, timeout) {
         Ok(info) => Some(info),
         Err(e) if e.kind() == ErrorKind::NotFound => None,
         Err(e) => {
-            log.warn(e);
+            log.unreadable_file(&file, FileOp::Stat, &e, &e);
             None
         }
     }
 }
 
+/// Returns the amount of disk space allocated to the file.
+/// It can be smaller than the logical length for sparse or compressed files,
+/// or larger, because of the block size granularity.
+#[cfg(unix)]
+pub(crate) fn allocated_len(metadata: &std::fs::Metadata) -> FileLen {
+    use std::os::unix::fs::MetadataExt;
+    // st_blocks is always counted in 512-byte units, regardless of the file system block size
+    FileLen(metadata.blocks() * 512)
+}
+
+/// Returns the logical length of the file, as this platform
+/// doesn't provide the information on the allocated size.
+#[cfg(not(unix))]
+pub(crate) fn allocated_len(metadata: &std::fs::Metadata) -> FileLen {
+    FileLen(metadata.len())
+}
+
+/// Returns the number of bytes available to unprivileged users
+/// on the file system the file is located on.
+#[cfg(unix)]
+#[allow(clippy::useless_conversion)] // the field types differ between platforms
+pub(crate) fn available_space(file: &Path) -> io::Result<u64> {
+    match nix::sys::statvfs::statvfs(&file.to_path_buf()) {
+        Ok(stat) => Ok(u64::from(stat.blocks_available()) * u64::from(stat.fragment_size())),
+        Err(nix::Error::Sys(errno)) => Err(io::Error::from_raw_os_error(errno as i32)),
+        Err(e) => Err(io::Error::new(ErrorKind::Other, e.to_string())),
+    }
+}
+
+/// Returns the number of bytes available on the disk the file is located on.
+#[cfg(not(unix))]
+pub(crate) fn available_space(file: &Path) -> io::Result<u64> {
+    use sysinfo::{DiskExt, System, SystemExt};
+    let file = file.to_path_buf();
+    let mut sys = System::new();
+    sys.refresh_disks_list();
+    sys.get_disks()
+        .iter()
+        .filter(|d| file.starts_with(d.get_mount_point()))
+        .max_by_key(|d| d.get_mount_point().components().count())
+        .map(|d| d.get_available_space())
+        .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "Disk not found"))
+}
+
 /// Useful for identifying files in presence of hardlinks
 #[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub(crate) struct FileId {
@@ -295,7 +491,7 @@ impl FileId {
     #[cfg(unix)]
     pub fn new(file: &Path) -> io::Result<FileId> {
         use std::os::unix::fs::MetadataExt;
-        match std::fs::metadata(&file.to_path_buf()) {
+        match std::fs::metadata(file.to_path_buf()) {
             Ok(metadata) => Ok(FileId {
                 inode: metadata.ino() as u128,
                 device: metadata.dev(),
@@ -355,7 +551,7 @@ pub(crate) fn file_id_or_log_err(file: &Path, log: &Log) -> Option<FileId> {
         Ok(id) => Some(id),
         Err(e) if e.kind() == ErrorKind::NotFound => None,
         Err(e) => {
-            log.warn(e);
+            log.unreadable_file(file, FileOp::Stat, &e, &e);
             None
         }
     }
@@ -364,15 +560,113 @@ pub(crate) fn file_id_or_log_err(file: &Path, log: &Log) -> Option<FileId> {
 /// Returns the physical offset of the first data block of the file
 #[cfg(target_os = "linux")]
 pub(crate) fn get_physical_file_location(path: &Path) -> io::Result<Option<u64>> {
-    let mut extents = fiemap::fiemap(&path.to_path_buf())?;
+    let mut extents = fiemap::fiemap(path.to_path_buf())?;
     match extents.next() {
         Some(fe) => Ok(Some(fe?.fe_physical)),
         None => Ok(None),
     }
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
-pub struct FileHash(pub u128);
+/// Identifies the data stored on disk by a file.
+/// Files with equal `DataId`s are hard links to the same inode or reflinked copies
+/// sharing the same extents.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) enum DataId {
+    Inode(FileId),
+    Extent { device: u64, physical: u64 },
+}
+
+impl DataId {
+    pub fn new(path: &Path) -> io::Result<DataId> {
+        let id = FileId::new(path)?;
+        #[cfg(target_os = "linux")]
+        if let Some(physical) = get_shared_extent_location(path)? {
+            return Ok(DataId::Extent {
+                device: id.device,
+                physical,
+            });
+        }
+        Ok(DataId::Inode(id))
+    }
+}
+
+/// Returns the physical offset of the first extent of the file,
+/// if the filesystem reports it as shared with other files
+#[cfg(target_os = "linux")]
+fn get_shared_extent_location(path: &Path) -> io::Result<Option<u64>> {
+    use fiemap::FiemapExtentFlags;
+    let unreliable = FiemapExtentFlags::UNKNOWN
+        | FiemapExtentFlags::DELALLOC
+        | FiemapExtentFlags::DATA_INLINE
+        | FiemapExtentFlags::NOT_ALIGNED;
+    let mut extents = fiemap::fiemap(path.to_path_buf())?;
+    match extents.next() {
+        Some(fe) => {
+            let fe = fe?;
+            let shared = fe.fe_flags.contains(FiemapExtentFlags::SHARED)
+                && !fe.fe_flags.intersects(unreliable);
+            Ok(Some(fe.fe_physical).filter(|_| shared))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Hash of the contents of a file, stored as big-endian bytes.
+/// Its width depends on the hash function: 128 bits for the default MetroHash,
+/// but 256 or 512 bits for SHA-2, or less if truncated.
+/// Hashes up to 128 bits wide are stored inline, without allocating.
+#[derive(Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct FileHash(SmallVec<[u8; 16]>);
+
+impl FileHash {
+    pub fn from_bytes(bytes: &[u8]) -> FileHash {
+        FileHash(SmallVec::from_slice(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// Returns the width of the hash in bits
+    pub fn bits(&self) -> usize {
+        self.0.len() * 8
+    }
+
+    /// Parses a hash written as hexadecimal digits, most significant first.
+    /// The number of digits must be even.
+    pub fn from_hex(s: &str) -> Result<FileHash, String> {
+        let digits = s.as_bytes();
+        if digits.len() % 2 != 0 {
+            return Err(format!("Odd number of hexadecimal digits in hash: {}", s));
+        }
+        let digit = |c: u8| {
+            (c as char)
+                .to_digit(16)
+                .ok_or_else(|| format!("Invalid hexadecimal digit in hash: {}", s))
+        };
+        let mut bytes = SmallVec::with_capacity(digits.len() / 2);
+        for pair in digits.chunks(2) {
+            bytes.push((digit(pair[0])? * 16 + digit(pair[1])?) as u8);
+        }
+        Ok(FileHash(bytes))
+    }
+}
+
+/// Returns the identifier of the group of files with the given length and hash.
+/// The identifier doesn't change between runs, so it can be used to find the same group
+/// in another report and to match the actions performed on the files of the group.
+pub fn group_id(len: FileLen, hash: &FileHash) -> String {
+    let mut hasher = MetroHash128::new();
+    hasher.write(&len.0.to_le_bytes());
+    hasher.write(hash.as_bytes());
+    format!("{:016x}", hasher.finish128().0)
+}
+
+impl From<u128> for FileHash {
+    fn from(value: u128) -> Self {
+        FileHash::from_bytes(&value.to_be_bytes())
+    }
+}
 
 pub trait AsFileHash {
     fn as_file_hash(&self) -> &FileHash;
@@ -392,15 +686,31 @@ impl<T> AsFileHash for (T, FileHash) {
 
 impl Display for FileHash {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        f.pad(format!("{:032x}", self.0).as_str())
+        let mut hex = String::with_capacity(self.0.len() * 2);
+        for b in self.0.iter() {
+            write!(hex, "{:02x}", b).unwrap();
+        }
+        f.pad(hex.as_str())
     }
 }
 
+/// Combines two hashes byte by byte.
+/// If they differ in width, the shorter one is aligned to the right,
+/// as if it were padded with leading zeroes.
 impl BitXor for FileHash {
     type Output = Self;
 
     fn bitxor(self, rhs: Self) -> Self::Output {
-        FileHash(rhs.0 ^ self.0)
+        let (mut long, short) = if self.0.len() >= rhs.0.len() {
+            (self, rhs)
+        } else {
+            (rhs, self)
+        };
+        let offset = long.0.len() - short.0.len();
+        for (a, b) in long.0[offset..].iter_mut().zip(short.0.iter()) {
+            *a ^= b;
+        }
+        long
     }
 }
 
@@ -419,8 +729,133 @@ impl<'de> Deserialize<'de> for FileHash {
         D: Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        let hash_value = u128::from_str_radix(s.as_str(), 16).map_err(serde::de::Error::custom)?;
-        Ok(FileHash(hash_value))
+        FileHash::from_hex(s.as_str()).map_err(serde::de::Error::custom)
+    }
+}
+
+/// Hash function for hashing the contents of files
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HashFn {
+    /// 128-bit MetroHash, fast, but not cryptographically secure. Always used for grouping.
+    Metro,
+    /// 256-bit SHA-2
+    Sha256,
+    /// 512-bit SHA-2
+    Sha512,
+}
+
+impl HashFn {
+    pub fn variants() -> Vec<&'static str> {
+        vec!["metro", "sha256", "sha512"]
+    }
+}
+
+impl Display for HashFn {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            HashFn::Metro => "metro",
+            HashFn::Sha256 => "sha256",
+            HashFn::Sha512 => "sha512",
+        };
+        f.pad(name)
+    }
+}
+
+impl FromStr for HashFn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "metro" => Ok(HashFn::Metro),
+            "sha256" => Ok(HashFn::Sha256),
+            "sha512" => Ok(HashFn::Sha512),
+            s => Err(format!("Unrecognized hash function: {}", s)),
+        }
+    }
+}
+
+/// Hashes of the contents of a file, computed with the hash functions
+/// other than the one used for grouping
+pub type Digests = BTreeMap<HashFn, FileHash>;
+
+/// Hash functions computed in addition to the one used for grouping,
+/// together with the backend computing them
+#[derive(Clone, Debug, Default)]
+pub struct ExtraHashes {
+    pub fns: Vec<HashFn>,
+    pub backend: HashBackend,
+}
+
+impl ExtraHashes {
+    pub fn is_empty(&self) -> bool {
+        self.fns.iter().all(|&f| f == HashFn::Metro)
+    }
+}
+
+/// Computes the hash used for grouping and any number of additional hashes
+/// from the same data
+struct Hashers {
+    metro: MetroHash128,
+    others: Vec<(HashFn, Digester)>,
+}
+
+enum Digester {
+    Sha256(sha2::Sha256),
+    Sha512(sha2::Sha512),
+    Command(CommandDigest),
+}
+
+impl Hashers {
+    fn new(hashes: &ExtraHashes) -> io::Result<Hashers> {
+        use sha2::Digest;
+        let backend = &hashes.backend;
+        let others = hashes
+            .fns
+            .iter()
+            .filter(|&&f| f != HashFn::Metro)
+            .map(|&f| {
+                let digester = match (f, backend) {
+                    (_, HashBackend::Command(command)) => {
+                        Digester::Command(CommandDigest::new(command, f)?)
+                    }
+                    (HashFn::Sha512, _) => Digester::Sha512(sha2::Sha512::new()),
+                    _ => Digester::Sha256(sha2::Sha256::new()),
+                };
+                Ok((f, digester))
+            })
+            .collect::<io::Result<_>>()?;
+        Ok(Hashers {
+            metro: MetroHash128::new(),
+            others,
+        })
+    }
+
+    fn write(&mut self, buf: &[u8]) {
+        use sha2::Digest;
+        self.metro.write(buf);
+        for (_, d) in self.others.iter_mut() {
+            match d {
+                Digester::Sha256(h) => h.update(buf),
+                Digester::Sha512(h) => h.update(buf),
+                Digester::Command(h) => h.update(buf),
+            }
+        }
+    }
+
+    fn finish(self) -> io::Result<(FileHash, Digests)> {
+        use sha2::Digest;
+        let (a, b) = self.metro.finish128();
+        let digests = self
+            .others
+            .into_iter()
+            .map(|(f, d)| match d {
+                Digester::Sha256(h) => Ok((f, FileHash::from_bytes(&h.finalize()))),
+                Digester::Sha512(h) => Ok((f, FileHash::from_bytes(&h.finalize()))),
+                Digester::Command(h) => Ok((f, h.finalize()?)),
+            })
+            .collect::<io::Result<_>>()?;
+        Ok((FileHash::from(((a as u128) << 64) | b as u128), digests))
     }
 }
 
@@ -463,7 +898,7 @@ fn configure_readahead(file: &File, offset: FilePos, len: FileLen, cache_policy:
 /// Tells the system to remove given file fragment from the page cache.
 /// On non-Unix systems, does nothing.
 #[allow(unused)]
-fn evict_page_cache(file: &File, offset: FilePos, len: FileLen) {
+pub(crate) fn evict_page_cache(file: &File, offset: FilePos, len: FileLen) {
     #[cfg(target_os = "linux")]
     {
         use nix::fcntl::*;
@@ -509,11 +944,38 @@ pub(crate) enum Caching {
     Random,
 }
 
+/// Determines how a file is read when computing its hash.
+#[derive(Debug, Copy, Clone)]
+pub(crate) struct ReadPolicy {
+    pub caching: Caching,
+    /// Gives up opening the file or a single read from it after this time,
+    /// see `--io-timeout`
+    pub timeout: Option<Duration>,
+}
+
+impl Caching {
+    /// Returns the policy of reading with this caching and the given timeout
+    pub fn with_timeout(self, timeout: Option<Duration>) -> ReadPolicy {
+        ReadPolicy {
+            caching: self,
+            timeout,
+        }
+    }
+}
+
+impl From<Caching> for ReadPolicy {
+    fn from(caching: Caching) -> Self {
+        caching.with_timeout(None)
+    }
+}
+
 /// Opens a file and positions it at the given offset.
 /// Additionally, sends the advice to the operating system about how many bytes will be read.
-fn open(path: &Path, offset: FilePos, len: FileLen, cache_policy: Caching) -> io::Result<File> {
-    let mut file = open_noatime(path)?;
-    configure_readahead(&file, offset, len, cache_policy);
+/// Gives up after the timeout of the policy.
+fn open(path: &Path, offset: FilePos, len: FileLen, policy: ReadPolicy) -> io::Result<File> {
+    let path = path.clone();
+    let mut file = io_timeout::run(policy.timeout, move || open_noatime(&path))??;
+    configure_readahead(&file, offset, len, policy.caching);
     if offset > FilePos::zero() {
         file.seek(offset.into())?;
     }
@@ -586,15 +1048,128 @@ pub(crate) fn stream_hash(
     buf_len: usize,
     progress: impl Fn(usize),
 ) -> io::Result<(FileLen, FileHash)> {
-    let mut hasher = MetroHash128::new();
+    let hashes = ExtraHashes::default();
+    stream_hashes(stream, len, buf_len, &hashes, progress).map(|(len, hash, _)| (len, hash))
+}
+
+/// Computes the 128-bit hash value used for grouping together with the `hashes`
+/// over at most `len` bytes of the stream, in a single pass.
+/// Returns the number of the bytes read, the 128-bit hash value and the other hashes.
+pub(crate) fn stream_hashes(
+    stream: &mut impl Read,
+    len: FileLen,
+    buf_len: usize,
+    hashes: &ExtraHashes,
+    progress: impl Fn(usize),
+) -> io::Result<(FileLen, FileHash, Digests)> {
+    let mut hashers = Hashers::new(hashes)?;
+    // Reading in more than one chunk can overlap with hashing on another thread
+    if len > FileLen(buf_len as u64) {
+        if let Some(pool) = hash_pool::pool() {
+            let write = |hashers: &mut Hashers, buf: &[u8]| hashers.write(buf);
+            let (read_len, hashers) =
+                hash_pool::scan(&pool, stream, len, buf_len, hashers, write, progress)?;
+            let (hash, digests) = hashers.finish()?;
+            return Ok((FileLen(read_len), hash, digests));
+        }
+    }
     let mut read_len: FileLen = FileLen(0);
     scan(stream, len, buf_len, |buf| {
-        hasher.write(buf);
+        hashers.write(buf);
         read_len += FileLen(buf.len() as u64);
         (progress)(buf.len());
     })?;
-    let (a, b) = hasher.finish128();
-    Ok((read_len, FileHash(((a as u128) << 64) | b as u128)))
+    let (hash, digests) = hashers.finish()?;
+    Ok((read_len, hash, digests))
+}
+
+/// Returns true if the file occupies less disk space than its length suggests,
+/// which means it likely has holes.
+#[cfg(target_os = "linux")]
+fn is_sparse(file: &File) -> io::Result<bool> {
+    let metadata = file.metadata()?;
+    Ok(allocated_len(&metadata) < FileLen(metadata.len()))
+}
+
+/// Returns the position of the next data region at or after `pos` or `None` if
+/// there is no more data in the file.
+#[cfg(target_os = "linux")]
+fn seek_data(file: &File, pos: u64) -> io::Result<Option<u64>> {
+    match unsafe { libc::lseek(file.as_raw_fd(), to_off_t(pos), libc::SEEK_DATA) } {
+        -1 => {
+            let e = io::Error::last_os_error();
+            match e.raw_os_error() {
+                Some(libc::ENXIO) => Ok(None),
+                _ => Err(e),
+            }
+        }
+        offset => Ok(Some(offset as u64)),
+    }
+}
+
+/// Returns the position of the next hole at or after `pos`.
+/// There is always an implicit hole at the end of the file.
+#[cfg(target_os = "linux")]
+fn seek_hole(file: &File, pos: u64) -> io::Result<u64> {
+    match unsafe { libc::lseek(file.as_raw_fd(), to_off_t(pos), libc::SEEK_HOLE) } {
+        -1 => Err(io::Error::last_os_error()),
+        offset => Ok(offset as u64),
+    }
+}
+
+/// Computes the hashes of `len` bytes of a sparse file starting at `offset`.
+/// Reads only the data regions and hashes holes as zeros, so the result is the same
+/// as if the whole range was read.
+#[cfg(target_os = "linux")]
+fn sparse_file_hashes(
+    stream: &mut TimedRead,
+    offset: FilePos,
+    len: FileLen,
+    buf_len: usize,
+    hasher: &mut Hashers,
+    progress: impl Fn(usize),
+) -> io::Result<()> {
+    let zeros = vec![0; buf_len];
+    let hash_zeros = |hasher: &mut Hashers, mut count: u64| {
+        while count > 0 {
+            let chunk = min(count, zeros.len() as u64) as usize;
+            hasher.write(&zeros[..chunk]);
+            (progress)(chunk);
+            count -= chunk as u64;
+        }
+    };
+
+    // Don't hash past the end of file, to get the same result as a regular read would give
+    let file_len = stream.call(|f| f.metadata())??.len();
+    let end = min(u64::from(offset) + u64::from(len), file_len);
+    let mut pos = u64::from(offset);
+    while pos < end {
+        let data_start = min(
+            stream.call(move |f| seek_data(f, pos))??.unwrap_or(end),
+            end,
+        );
+        hash_zeros(hasher, data_start - pos);
+        if data_start == end {
+            break;
+        }
+        let data_end = min(stream.call(move |f| seek_hole(f, data_start))??, end);
+        stream.call(move |mut f| f.seek(SeekFrom::Start(data_start)))??;
+        let read = scan(
+            &mut *stream,
+            FileLen(data_end - data_start),
+            buf_len,
+            |buf| {
+                hasher.write(buf);
+                (progress)(buf.len());
+            },
+        )?;
+        if read < data_end - data_start {
+            // the file got truncated while we were reading it
+            break;
+        }
+        pos = data_end;
+    }
+    Ok(())
 }
 
 /// Computes hash of initial `len` bytes of a file.
@@ -605,13 +1180,166 @@ pub(crate) fn file_hash(
     offset: FilePos,
     len: FileLen,
     buf_len: usize,
-    cache_policy: Caching,
+    policy: impl Into<ReadPolicy>,
     progress: impl Fn(usize),
 ) -> io::Result<FileHash> {
-    let mut file = open(path, offset, len, cache_policy)?;
-    let hash = stream_hash(&mut file, len, buf_len, progress)?.1;
+    let hashes = ExtraHashes::default();
+    file_hashes(path, offset, len, buf_len, policy, &hashes, progress).map(|(hash, _)| hash)
+}
+
+/// Computes the hash of `len` bytes of a file starting at `offset` used for grouping
+/// together with the `hashes`, reading the file only once.
+pub(crate) fn file_hashes(
+    path: &Path,
+    offset: FilePos,
+    len: FileLen,
+    buf_len: usize,
+    policy: impl Into<ReadPolicy>,
+    hashes: &ExtraHashes,
+    progress: impl Fn(usize),
+) -> io::Result<(FileHash, Digests)> {
+    let policy = policy.into();
+    let _fd = fd::acquire(1);
+    let mut file = open(path, offset, len, policy)?;
+    let mut stream = TimedRead::new(&file, policy.timeout)?;
+    #[cfg(target_os = "linux")]
+    {
+        if len > FileLen(buf_len as u64) && stream.call(is_sparse)?? {
+            let mut hashers = Hashers::new(hashes)?;
+            sparse_file_hashes(&mut stream, offset, len, buf_len, &mut hashers, progress)?;
+            drop(stream);
+            evict_page_cache_if_low_mem(&mut file, len);
+            return hashers.finish();
+        }
+    }
+    let (_, hash, digests) = stream_hashes(&mut stream, len, buf_len, hashes, progress)?;
+    drop(stream);
     evict_page_cache_if_low_mem(&mut file, len);
-    Ok(hash)
+    Ok((hash, digests))
+}
+
+/// Computes the hashes of `len` bytes of a file starting at `offset` by mapping the file
+/// into memory. The result is the same as returned by [`file_hashes`].
+pub(crate) fn mmap_file_hashes(
+    path: &Path,
+    offset: FilePos,
+    len: FileLen,
+    buf_len: usize,
+    hashes: &ExtraHashes,
+    progress: impl Fn(usize),
+) -> io::Result<(FileHash, Digests)> {
+    let _fd = fd::acquire(1);
+    let file = open_noatime(path)?;
+    let file_len = file.metadata()?.len();
+    let start = min(u64::from(offset), file_len);
+    let end = min(start.saturating_add(len.into()), file_len);
+    let mmap = unsafe {
+        memmap2::MmapOptions::new()
+            .offset(start)
+            .len((end - start) as usize)
+            .map(&file)?
+    };
+    #[cfg(unix)]
+    let _ = mmap.advise(memmap2::Advice::Sequential);
+    // Another process may truncate the file while we're reading it.
+    // Windows doesn't allow truncating mapped files.
+    #[cfg(unix)]
+    let guard = sigbus::register(mmap.as_ptr(), mmap.len())
+        .ok_or_else(|| io::Error::new(ErrorKind::Other, "Too many files mapped at once"))?;
+
+    let mut hashers = Hashers::new(hashes)?;
+    for chunk in mmap.chunks(max(buf_len, 1)) {
+        hashers.write(chunk);
+        (progress)(chunk.len());
+    }
+    #[cfg(unix)]
+    if guard.faulted() {
+        return Err(io::Error::new(
+            ErrorKind::UnexpectedEof,
+            format!("File {} was truncated while being read", path),
+        ));
+    }
+    hashers.finish()
+}
+
+/// Number of bytes at the beginning and at the end of a file covered by its fingerprint
+pub(crate) const FINGERPRINT_LEN: u64 = 4096;
+
+/// Computes a cheap fingerprint of a file from its length and up to 4 KB of data
+/// at its beginning and at its end.
+/// Files with different fingerprints differ, but files with the same fingerprint
+/// may still differ in the middle.
+pub(crate) fn file_fingerprint(
+    path: &Path,
+    len: FileLen,
+    timeout: Option<Duration>,
+) -> io::Result<u128> {
+    let _fd = fd::acquire(1);
+    let file = open(path, FilePos(0), len, Caching::Random.with_timeout(timeout))?;
+    let mut stream = TimedRead::new(&file, timeout)?;
+    let mut buf = Vec::with_capacity(2 * FINGERPRINT_LEN as usize);
+    (&mut stream).take(FINGERPRINT_LEN).read_to_end(&mut buf)?;
+    if len.0 > FINGERPRINT_LEN {
+        let tail_start = max(FINGERPRINT_LEN, len.0 - FINGERPRINT_LEN);
+        (&file).seek(SeekFrom::Start(tail_start))?;
+        (&mut stream).take(FINGERPRINT_LEN).read_to_end(&mut buf)?;
+    }
+    let mut hasher = MetroHash128::new();
+    hasher.write_u64(len.0);
+    hasher.write(&buf);
+    let (a, b) = hasher.finish128();
+    Ok(((a as u128) << 64) | b as u128)
+}
+
+/// Computes the hashes of initial `len` bytes of a file like [`file_hashes`]
+/// or logs an error and returns `None` if failed.
+/// If file is not found, no error is logged and `None` is returned.
+pub(crate) fn file_hashes_or_log_err(
+    path: &Path,
+    len: FileLen,
+    buf_len: usize,
+    policy: impl Into<ReadPolicy>,
+    hashes: &ExtraHashes,
+    progress: impl Fn(usize),
+    log: &Log,
+) -> Option<(FileHash, Digests)> {
+    match file_hashes(path, FilePos(0), len, buf_len, policy, hashes, progress) {
+        Ok(hashes) => Some(hashes),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => {
+            log.unreadable_file(
+                path,
+                FileOp::Read,
+                &e,
+                format!("Failed to compute hash of file {}: {}", path.display(), e),
+            );
+            None
+        }
+    }
+}
+
+/// Computes the fingerprint of a file like [`file_fingerprint`]
+/// or logs an error and returns `None` if failed.
+/// If file is not found, no error is logged and `None` is returned.
+pub(crate) fn file_fingerprint_or_log_err(
+    path: &Path,
+    len: FileLen,
+    timeout: Option<Duration>,
+    log: &Log,
+) -> Option<FileHash> {
+    match file_fingerprint(path, len, timeout) {
+        Ok(fingerprint) => Some(FileHash::from(fingerprint)),
+        Err(e) if e.kind() == ErrorKind::NotFound => None,
+        Err(e) => {
+            log.unreadable_file(
+                path,
+                FileOp::Read,
+                &e,
+                format!("Failed to read file {}: {}", path.display(), e),
+            );
+            None
+        }
+    }
 }
 
 /// Computes the file hash or logs an error and returns none if failed.
@@ -621,19 +1349,20 @@ pub(crate) fn file_hash_or_log_err(
     offset: FilePos,
     len: FileLen,
     buf_len: usize,
-    caching: Caching,
+    policy: impl Into<ReadPolicy>,
     progress: impl Fn(usize),
     log: &Log,
 ) -> Option<FileHash> {
-    match file_hash(path, offset, len, buf_len, caching, progress) {
+    match file_hash(path, offset, len, buf_len, policy, progress) {
         Ok(hash) => Some(hash),
         Err(e) if e.kind() == ErrorKind::NotFound => None,
         Err(e) => {
-            log.warn(format!(
-                "Failed to compute hash of file {}: {}",
-                path.display(),
-                e
-            ));
+            log.unreadable_file(
+                path,
+                FileOp::Read,
+                &e,
+                format!("Failed to compute hash of file {}: {}", path.display(), e),
+            );
             None
         }
     }
@@ -649,6 +1378,51 @@ mod test {
 
     use super::*;
 
+    #[test]
+    fn test_parse_size_range() {
+        let range = |min, max| SizeRange {
+            min: FileLen(min),
+            max: FileLen(max),
+        };
+        assert_eq!("1K..2K".parse(), Ok(range(1000, 2000)));
+        assert_eq!("1KiB..".parse(), Ok(range(1024, u64::MAX)));
+        assert_eq!("..100".parse(), Ok(range(0, 100)));
+        assert_eq!("4K".parse(), Ok(range(4000, 4000)));
+        assert!("2K..1K".parse::<SizeRange>().is_err());
+        assert!("foo..1K".parse::<SizeRange>().is_err());
+        assert!("".parse::<SizeRange>().is_err());
+    }
+
+    #[test]
+    fn test_size_ranges() {
+        let ranges = ["..1K".parse().unwrap(), "1M..2M".parse().unwrap()];
+        assert!(SizeRange::any_contains(&ranges, FileLen(1000)));
+        assert!(!SizeRange::any_contains(&ranges, FileLen(1001)));
+        assert!(SizeRange::any_contains(&ranges, FileLen(1_500_000)));
+        assert!(!SizeRange::any_contains(&ranges, FileLen(2_000_001)));
+        assert!(SizeRange::any_contains(&[], FileLen(1)));
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(999, false, false), "999 B");
+        assert_eq!(format_size(1500, false, false), "1.5 KB");
+        assert_eq!(format_size(1536, true, false), "1.5 KiB");
+        assert_eq!(format_size(6_600_000, false, true), "6,6 MB");
+        assert_eq!(format_size(6_600_000, true, false), "6.3 MiB");
+    }
+
+    #[test]
+    fn test_uses_decimal_comma() {
+        assert!(uses_decimal_comma("de_DE.UTF-8"));
+        assert!(uses_decimal_comma("pl_PL"));
+        assert!(uses_decimal_comma("fr_FR@euro"));
+        assert!(!uses_decimal_comma("de_CH.UTF-8"));
+        assert!(!uses_decimal_comma("en_US.UTF-8"));
+        assert!(!uses_decimal_comma("C"));
+        assert!(!uses_decimal_comma("POSIX"));
+    }
+
     #[test]
     fn test_format_bytes() {
         let file_len = FileLen(16000);
@@ -656,6 +1430,110 @@ mod test {
         assert_eq!(human_readable, "16.0 KB");
     }
 
+    #[test]
+    fn test_stream_hashes() {
+        let data = b"abc";
+        let hashes = ExtraHashes {
+            fns: vec![HashFn::Metro, HashFn::Sha256, HashFn::Sha512],
+            backend: HashBackend::Builtin,
+        };
+        let (len, hash, digests) =
+            stream_hashes(&mut &data[..], FileLen::MAX, 4096, &hashes, |_| {}).unwrap();
+        assert_eq!(len, FileLen(3));
+        assert_eq!(
+            hash,
+            stream_hash(&mut &data[..], FileLen::MAX, 4096, |_| {})
+                .unwrap()
+                .1
+        );
+        assert_eq!(digests.len(), 2);
+        assert_eq!(
+            digests[&HashFn::Sha256].to_string(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert_eq!(digests[&HashFn::Sha512].bits(), 512);
+        assert!(digests[&HashFn::Sha512]
+            .to_string()
+            .starts_with("ddaf35a193617aba"));
+    }
+
+    #[test]
+    fn test_file_hash_hex() {
+        let hash = FileHash::from(0x00112233445566778899aabbccddeeff);
+        assert_eq!(hash.to_string(), "00112233445566778899aabbccddeeff");
+        assert_eq!(FileHash::from_hex(&hash.to_string()), Ok(hash));
+
+        let wide = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        let hash = FileHash::from_hex(wide).unwrap();
+        assert_eq!(hash.bits(), 256);
+        assert_eq!(hash.to_string(), wide);
+
+        assert!(FileHash::from_hex("abc").is_err());
+        assert!(FileHash::from_hex("xy").is_err());
+    }
+
+    #[test]
+    fn test_file_hash_xor_aligns_to_the_right() {
+        let short = FileHash::from_bytes(&[0x0f, 0xf0]);
+        let long = FileHash::from_bytes(&[0x01, 0x02, 0x03]);
+        assert_eq!(short ^ long, FileHash::from_bytes(&[0x01, 0x0d, 0xf3]));
+    }
+
+    #[test]
+    fn test_sparse_file_hash() {
+        use std::io::{Seek, SeekFrom};
+        let test_root = PathBuf::from("target/test/sparse_file_hash/");
+        create_dir_all(&test_root).unwrap();
+
+        let sparse = test_root.join("sparse");
+        let mut f = File::create(&sparse).unwrap();
+        f.write_all(b"foo").unwrap();
+        f.seek(SeekFrom::Start(1024 * 1024)).unwrap();
+        f.write_all(b"bar").unwrap();
+        f.set_len(2 * 1024 * 1024).unwrap();
+        drop(f);
+
+        let dense = test_root.join("dense");
+        let mut content = vec![0; 2 * 1024 * 1024];
+        content[0..3].copy_from_slice(b"foo");
+        content[1024 * 1024..1024 * 1024 + 3].copy_from_slice(b"bar");
+        File::create(&dense).unwrap().write_all(&content).unwrap();
+
+        for &(offset, len) in &[(0, u64::MAX), (0, 1024 * 1024 + 1), (1000, 1024 * 1024)] {
+            let hash = |path: &PathBuf| {
+                file_hash(
+                    &Path::from(path),
+                    FilePos(offset),
+                    FileLen(len),
+                    4096,
+                    Caching::Default,
+                    |_| {},
+                )
+                .unwrap()
+            };
+            assert_eq!(hash(&sparse), hash(&dense));
+        }
+    }
+
+    #[test]
+    fn test_mmap_file_hash() {
+        let test_root = PathBuf::from("target/test/mmap_file_hash/");
+        create_dir_all(&test_root).unwrap();
+        let file = test_root.join("file");
+        let content: Vec<u8> = (0..100000).map(|i| (i % 251) as u8).collect();
+        File::create(&file).unwrap().write_all(&content).unwrap();
+        let path = Path::from(&file);
+
+        for &(offset, len) in &[(0, u64::MAX), (0, 5000), (4097, 50000)] {
+            let (offset, len) = (FilePos(offset), FileLen(len));
+            let hash1 = file_hash(&path, offset, len, 4096, Caching::Default, |_| {}).unwrap();
+            let hash2 = mmap_file_hashes(&path, offset, len, 4096, &ExtraHashes::default(), |_| {})
+                .unwrap()
+                .0;
+            assert_eq!(hash1, hash2);
+        }
+    }
+
     #[test]
     fn test_file_hash() {
         let test_root = PathBuf::from("target/test/file_hash/");
@@ -0,0 +1,116 @@
+//! Looking up the copies of a single file.
+//!
+//! This is a shortcut for `fclones group --containing`: the files of a different size than
+//! the target file are skipped already while scanning, so only the candidates
+//! with the same size are ever read.
+
+use std::env::current_dir;
+use std::fs;
+use std::sync::Arc;
+
+use crate::config::{FindCopiesConfig, GroupConfig};
+use crate::files::FileLen;
+use crate::log::Log;
+use crate::path::Path;
+use crate::{group_files, Error};
+
+/// Returns the paths of the files under `config.paths` with the same contents as `config.file`.
+/// The file itself is not included.
+/// Hard links to the file are reported as copies as well.
+pub fn find_copies(config: &FindCopiesConfig, log: &Log) -> Result<Vec<Path>, Error> {
+    let base_dir = Arc::new(Path::from(current_dir().unwrap_or_default()));
+    let file = base_dir.resolve(Path::from(&config.file)).canonicalize();
+    let metadata =
+        fs::metadata(file.to_path_buf()).map_err(|e| format!("Cannot access {}: {}", file, e))?;
+    if !metadata.is_file() {
+        return Err(format!("{} is not a regular file", file).into());
+    }
+    let len = FileLen(metadata.len());
+
+    let mut paths = config.paths.clone();
+    // The target must be scanned even if it is outside of the input paths,
+    // otherwise there would be nothing to compare the other files to
+    let roots: Vec<Path> = config
+        .paths
+        .iter()
+        .map(|p| base_dir.resolve(Path::from(p)).canonicalize())
+        .collect();
+    if !roots.iter().any(|r| r.is_prefix_of(&file)) {
+        paths.push(file.to_path_buf());
+    }
+    let group_config = GroupConfig {
+        paths,
+        containing: vec![file.to_path_buf()],
+        min_size: len,
+        max_size: Some(len),
+        hard_links: true,
+        follow_links: config.follow_links,
+        skip_hidden: config.skip_hidden,
+        ..GroupConfig::default()
+    };
+
+    let groups = group_files(&group_config, log)?;
+    Ok(groups
+        .into_iter()
+        .flat_map(|g| g.files)
+        .filter(|f| f != &file)
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::create_dir;
+    use std::path::PathBuf;
+
+    use crate::util::test::*;
+
+    use super::*;
+
+    fn test_log() -> Log {
+        let mut log = Log::new();
+        log.no_progress = true;
+        log
+    }
+
+    #[test]
+    fn finds_copies_outside_of_the_target_dir() {
+        with_dir("find_copies/outside", |root| {
+            let (a, b) = (root.join("a"), root.join("b"));
+            create_dir(&a).unwrap();
+            create_dir(&b).unwrap();
+            fs::write(a.join("target"), b"foo").unwrap();
+            fs::write(a.join("other"), b"bar").unwrap();
+            fs::write(b.join("copy"), b"foo").unwrap();
+            fs::write(b.join("other"), b"bar").unwrap();
+
+            let log = test_log();
+            let config = FindCopiesConfig {
+                follow_links: false,
+                skip_hidden: false,
+                file: a.join("target"),
+                paths: vec![b.clone()],
+            };
+            let copies = find_copies(&config, &log).unwrap();
+            assert_eq!(copies, vec![Path::from(b.join("copy"))]);
+
+            let config = FindCopiesConfig {
+                paths: vec![root.clone()],
+                ..config
+            };
+            let copies = find_copies(&config, &log).unwrap();
+            assert_eq!(copies, vec![Path::from(b.join("copy"))]);
+        });
+    }
+
+    #[test]
+    fn fails_on_missing_file() {
+        let log = test_log();
+        let config = FindCopiesConfig {
+            follow_links: false,
+            skip_hidden: false,
+            file: PathBuf::from("/nonexistent/file"),
+            paths: vec![PathBuf::from(".")],
+        };
+        assert!(find_copies(&config, &log).is_err());
+    }
+}
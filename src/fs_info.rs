@@ -0,0 +1,243 @@
+//! Probing the features of file systems which determine the dedupe operations that can be used.
+//!
+//! Features are detected by trying them out on a temporary file created in the probed directory,
+//! because file systems, mount options and servers of network file systems often don't
+//! advertise what they support.
+
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs::{self, File};
+use std::io;
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::config::FsInfoConfig;
+use crate::dedupe::FsCommand;
+use crate::device::DiskDevices;
+use crate::path::Path;
+use crate::Error;
+
+/// Features of the file system holding a path
+#[derive(Clone, Debug)]
+pub struct FsInfo {
+    /// The probed directory
+    pub path: Path,
+    /// Mount point of the file system
+    pub mount_point: Path,
+    /// Type of the file system, e.g. `ext4`, if known
+    pub file_system: Option<OsString>,
+    /// Name of the device holding the file system
+    pub device: OsString,
+    /// True for network file systems like NFS or SMB
+    pub network: bool,
+    pub hard_links: bool,
+    pub soft_links: bool,
+    /// Copies sharing the data with the original, made by `cp --reflink`.
+    /// Probed on Linux only.
+    pub reflinks: bool,
+    /// Extended attributes in the `user` namespace. Probed on Linux and macOS only.
+    pub xattrs: bool,
+    /// Creation time of files
+    pub birth_time: bool,
+}
+
+impl FsInfo {
+    /// Returns the `fclones` commands able to deduplicate files on the file system
+    pub fn supported_commands(&self) -> Vec<&'static str> {
+        let mut commands = vec!["remove", "move"];
+        if self.hard_links {
+            commands.push("link");
+        }
+        if self.soft_links {
+            commands.push("link --soft");
+        }
+        commands
+    }
+}
+
+impl Display for FsInfo {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let yes_no = |supported: bool| if supported { "yes" } else { "no" };
+        let file_system = match &self.file_system {
+            Some(fs) => fs.to_string_lossy(),
+            None => "unknown".into(),
+        };
+        writeln!(f, "Path: {}", self.path)?;
+        writeln!(f, "Mount point: {}", self.mount_point)?;
+        writeln!(f, "File system: {}", file_system)?;
+        writeln!(f, "Device: {}", self.device.to_string_lossy())?;
+        writeln!(f, "Network: {}", yes_no(self.network))?;
+        writeln!(f, "Hard links: {}", yes_no(self.hard_links))?;
+        writeln!(f, "Symbolic links: {}", yes_no(self.soft_links))?;
+        writeln!(f, "Reflinks: {}", yes_no(self.reflinks))?;
+        writeln!(f, "Extended attributes: {}", yes_no(self.xattrs))?;
+        writeln!(f, "Birth time: {}", yes_no(self.birth_time))?;
+        write!(
+            f,
+            "Supported commands: {}",
+            self.supported_commands().join(", ")
+        )
+    }
+}
+
+/// Probes the features of the file system holding `config.path`.
+/// Requires write access to the directory, or to the parent directory if the path is a file.
+pub fn fs_info(config: &FsInfoConfig) -> Result<FsInfo, Error> {
+    let path = Path::from(&config.path).canonicalize();
+    let dir = match fs::metadata(path.to_path_buf()) {
+        Ok(m) if m.is_dir() => Arc::new(path),
+        Ok(_) => path
+            .parent()
+            .cloned()
+            .ok_or("The path has no parent directory")?,
+        Err(e) => return Err(format!("Cannot access {}: {}", path, e).into()),
+    };
+    let probe_error = |e: io::Error| {
+        Error::from(format!(
+            "Cannot probe the file system in {}: {}. Write access to the directory is required",
+            dir, e
+        ))
+    };
+
+    let devices = DiskDevices::new(&HashMap::new());
+    let device = devices.get_by_path(&dir);
+    Ok(FsInfo {
+        path: dir.as_ref().clone(),
+        mount_point: devices.get_mount_point(&dir).clone(),
+        file_system: devices.get_file_system(&dir).map(|fs| fs.to_os_string()),
+        device: device.name.clone(),
+        network: device.network,
+        hard_links: probe_hard_links(&dir).map_err(probe_error)?,
+        soft_links: with_probe_file(&dir, probe_soft_links).map_err(probe_error)?,
+        reflinks: with_probe_file(&dir, probe_reflinks).map_err(probe_error)?,
+        xattrs: with_probe_file(&dir, probe_xattrs).map_err(probe_error)?,
+        birth_time: with_probe_file(&dir, probe_birth_time).map_err(probe_error)?,
+    })
+}
+
+/// Removes the file when dropped
+struct TempFile(Path);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(self.0.to_path_buf());
+    }
+}
+
+/// Creates a temporary file in the directory, runs the probe on it and removes the file.
+/// Returns an error if the temporary file could not be created.
+fn with_probe_file<T>(dir: &Arc<Path>, probe: impl FnOnce(&Path) -> T) -> io::Result<T> {
+    let path = FsCommand::temp_file(&dir.join(Path::from(".fclones-probe")));
+    let mut file = File::create(path.to_path_buf())?;
+    // Removes the file also if writing it fails or the probe panics:
+    let path = TempFile(path);
+    // Some file systems share data of non-empty files only
+    file.write_all(b"fclones")?;
+    drop(file);
+    Ok(probe(&path.0))
+}
+
+/// Checks if hard links can be created in the directory by linking a temporary file.
+/// Returns an error if the temporary file could not be created.
+pub(crate) fn probe_hard_links(dir: &Arc<Path>) -> io::Result<bool> {
+    with_probe_file(dir, |file| {
+        let link = FsCommand::temp_file(file);
+        let result = fs::hard_link(file.to_path_buf(), link.to_path_buf());
+        let _ = fs::remove_file(link.to_path_buf());
+        result.is_ok()
+    })
+}
+
+fn probe_soft_links(file: &Path) -> bool {
+    let link = FsCommand::temp_file(file);
+    let result = FsCommand::symlink(file, &link);
+    let _ = fs::remove_file(link.to_path_buf());
+    result.is_ok()
+}
+
+#[cfg(target_os = "linux")]
+fn probe_reflinks(file: &Path) -> bool {
+    let copy = FsCommand::temp_file(file);
+    let result = FsCommand::reflink(file, &copy);
+    let _ = fs::remove_file(copy.to_path_buf());
+    result.is_ok()
+}
+
+/// Probing reflinks is supported on Linux only
+#[cfg(not(target_os = "linux"))]
+fn probe_reflinks(_file: &Path) -> bool {
+    false
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn probe_xattrs(file: &Path) -> bool {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = match CString::new(file.to_path_buf().as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    let name = CString::new("user.fclones.probe").unwrap();
+    let value = b"1";
+    let value_ptr = value.as_ptr() as *const libc::c_void;
+    #[cfg(target_os = "linux")]
+    let result = unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), value_ptr, 1, 0) };
+    #[cfg(target_os = "macos")]
+    let result = unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), value_ptr, 1, 0, 0) };
+    result == 0
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+fn probe_xattrs(_file: &Path) -> bool {
+    false
+}
+
+fn probe_birth_time(file: &Path) -> bool {
+    fs::metadata(file.to_path_buf())
+        .and_then(|m| m.created())
+        .is_ok()
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use crate::util::test::with_dir;
+
+    use super::*;
+
+    #[test]
+    fn probe_hard_links_leaves_no_files() {
+        with_dir("fs_info/probe_hard_links", |root| {
+            let dir = Arc::new(Path::from(root));
+            assert!(probe_hard_links(&dir).unwrap());
+            assert_eq!(fs::read_dir(root).unwrap().count(), 0);
+        });
+    }
+
+    #[test]
+    fn failed_probe_leaves_no_files() {
+        with_dir("fs_info/failed_probe", |root| {
+            let dir = Arc::new(Path::from(root));
+            let result = std::panic::catch_unwind(|| with_probe_file(&dir, |_| panic!("probe")));
+            assert!(result.is_err());
+            assert_eq!(fs::read_dir(root).unwrap().count(), 0);
+        });
+    }
+
+    #[test]
+    fn fs_info_leaves_no_files() {
+        with_dir("fs_info/fs_info", |root| {
+            let config = FsInfoConfig {
+                path: PathBuf::from(root),
+            };
+            let info = fs_info(&config).unwrap();
+            assert!(info.soft_links);
+            assert!(info.supported_commands().contains(&"link --soft"));
+            assert_eq!(fs::read_dir(root).unwrap().count(), 0);
+        });
+    }
+}
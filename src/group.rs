@@ -1,6 +1,7 @@
 use std::collections::BTreeMap;
 use std::hash::Hash;
 use std::marker::PhantomData;
+use std::ops::RangeBounds;
 
 use smallvec::SmallVec;
 
@@ -47,6 +48,21 @@ where
         let (key, new_item) = (self.split_fn)(item);
         self.groups.entry(key).or_default().push(new_item);
     }
+
+    /// Removes the groups with keys in the given range and returns them in key order.
+    pub fn remove_range<R>(&mut self, range: R) -> Vec<(K, SmallVec<[V; 1]>)>
+    where
+        R: RangeBounds<K>,
+        K: Clone,
+    {
+        let keys: Vec<K> = self.groups.range(range).map(|(k, _)| k.clone()).collect();
+        keys.into_iter()
+            .map(|k| {
+                let v = self.groups.remove(&k).unwrap();
+                (k, v)
+            })
+            .collect()
+    }
 }
 
 impl<T, K, V, F> IntoIterator for GroupMap<T, K, V, F>
@@ -82,4 +98,27 @@ mod test {
         assert_eq!(groups[0], (1, SmallVec::from_vec(vec![10, 11])));
         assert_eq!(groups[1], (2, SmallVec::from_vec(vec![20, 21])));
     }
+
+    #[test]
+    fn remove_range_should_take_groups_out_of_the_map() {
+        use super::GroupMap;
+        use smallvec::SmallVec;
+
+        let mut map = GroupMap::new(|item: ((u32, u32), u32)| (item.0, item.1));
+        map.add(((1, 1), 10));
+        map.add(((2, 1), 20));
+        map.add(((2, 2), 21));
+        map.add(((3, 1), 30));
+
+        let removed = map.remove_range((2, 0)..=(2, u32::MAX));
+        assert_eq!(
+            removed,
+            vec![
+                ((2, 1), SmallVec::from_vec(vec![20])),
+                ((2, 2), SmallVec::from_vec(vec![21]))
+            ]
+        );
+        let keys: Vec<_> = map.into_iter().map(|item| item.0).collect();
+        assert_eq!(keys, vec![(1, 1), (3, 1)]);
+    }
 }
@@ -0,0 +1,268 @@
+//! Choosing the implementation of the hash functions requested with `--hash-fn`.
+//!
+//! The hash used for grouping the files is always computed by the built-in MetroHash.
+//! The SHA-2 hashes are much more expensive and make scans of large archives CPU-bound,
+//! so they can be computed by a backend selected at runtime: the built-in implementation,
+//! which uses the SHA extensions of x86 CPUs when present, or an external program,
+//! e.g. one offloading the hashing to an accelerator.
+
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::{Read, Write};
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::str::FromStr;
+use std::thread::JoinHandle;
+
+use crate::files::{FileHash, HashFn};
+
+/// Implementation of the hash functions other than the one used for grouping
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HashBackend {
+    /// Built-in implementation, using the SHA extensions of x86 CPUs if available
+    Builtin,
+    /// External program run for each file, given the name of the hash function
+    /// as the last argument, reading the data from the standard input
+    /// and printing the hexadecimal digest as the first word of its output
+    Command(String),
+}
+
+impl Default for HashBackend {
+    fn default() -> Self {
+        HashBackend::Builtin
+    }
+}
+
+impl HashBackend {
+    pub fn variants() -> Vec<&'static str> {
+        vec!["builtin", "command:<program>"]
+    }
+
+    /// Returns an error if the backend can't be used
+    pub fn check(&self) -> Result<(), String> {
+        match self {
+            HashBackend::Command(command) if command.trim().is_empty() => {
+                Err("Hash backend command must not be empty".to_owned())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Display for HashBackend {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            HashBackend::Builtin => f.pad("builtin"),
+            HashBackend::Command(command) => write!(f, "command:{}", command),
+        }
+    }
+}
+
+impl FromStr for HashBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(command) = s.strip_prefix("command:") {
+            return Ok(HashBackend::Command(command.to_owned()));
+        }
+        match s.to_lowercase().as_str() {
+            "builtin" => Ok(HashBackend::Builtin),
+            _ => Err(format!(
+                "Unrecognized hash backend: {}. Must be one of: {}",
+                s,
+                HashBackend::variants().join(", ")
+            )),
+        }
+    }
+}
+
+/// Returns the CPU extensions accelerating the hash functions detected on this machine
+pub fn cpu_features() -> Vec<&'static str> {
+    #[allow(unused_mut)]
+    let mut features = Vec::new();
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        if is_x86_feature_detected!("sha") {
+            features.push("sha-ni");
+        }
+        if is_x86_feature_detected!("avx2") {
+            features.push("avx2");
+        }
+    }
+    features
+}
+
+/// Computes a hash by streaming the data to an external program
+pub(crate) struct CommandDigest {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    /// Reads the output while the data is written, so the program never blocks on a full pipe
+    stdout: Option<JoinHandle<io::Result<Vec<u8>>>>,
+    error: Option<io::Error>,
+}
+
+impl CommandDigest {
+    /// Launches the program given as a shell-like command line
+    pub fn new(command: &str, hash_fn: HashFn) -> io::Result<CommandDigest> {
+        let args = shell_words::split(command)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let (program, args) = args.split_first().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "Empty hash backend command")
+        })?;
+        let mut child = Command::new(program)
+            .args(args)
+            .arg(hash_fn.to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let stdin = child.stdin.take();
+        let mut stdout = child.stdout.take().unwrap();
+        let stdout = std::thread::spawn(move || {
+            let mut output = Vec::new();
+            stdout.read_to_end(&mut output).map(|_| output)
+        });
+        Ok(CommandDigest {
+            child,
+            stdin,
+            stdout: Some(stdout),
+            error: None,
+        })
+    }
+
+    /// Passes the data to the program.
+    /// The first error is remembered and returned by [`finalize`](CommandDigest::finalize).
+    pub fn update(&mut self, buf: &[u8]) {
+        if let (Some(stdin), None) = (self.stdin.as_mut(), self.error.as_ref()) {
+            if let Err(e) = stdin.write_all(buf) {
+                self.error = Some(e);
+            }
+        }
+    }
+
+    /// Closes the input of the program and reads the digest it printed
+    pub fn finalize(mut self) -> io::Result<FileHash> {
+        drop(self.stdin.take());
+        let output = self.stdout.take().unwrap().join().unwrap();
+        let status = self.child.wait()?;
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        let fail = |msg: String| io::Error::new(io::ErrorKind::Other, msg);
+        if !status.success() {
+            return Err(fail(format!("Hash backend command {}", status)));
+        }
+        let output = output?;
+        let stdout = String::from_utf8_lossy(&output);
+        let digest = stdout.split_whitespace().next().unwrap_or_default();
+        if digest.is_empty() {
+            return Err(fail("No digest from hash backend command".to_owned()));
+        }
+        FileHash::from_hex(digest)
+            .map_err(|e| fail(format!("Invalid digest from hash backend command: {}", e)))
+    }
+}
+
+/// Stops the program if the digest was not finalized, e.g. because reading the file failed,
+/// so no zombie process or reader thread is left behind
+impl Drop for CommandDigest {
+    fn drop(&mut self) {
+        drop(self.stdin.take());
+        if let Some(stdout) = self.stdout.take() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+            let _ = stdout.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_backend() {
+        assert_eq!("builtin".parse(), Ok(HashBackend::Builtin));
+        assert_eq!("BUILTIN".parse(), Ok(HashBackend::Builtin));
+        assert_eq!(
+            "command:accel --device 0".parse(),
+            Ok(HashBackend::Command("accel --device 0".to_owned()))
+        );
+        assert!("gpu".parse::<HashBackend>().is_err());
+        assert!("sha-ni".parse::<HashBackend>().is_err());
+        assert_eq!(HashBackend::Builtin.to_string(), "builtin");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn command_digest() {
+        // Prints the data in hex instead of hashing it, to check what the program got
+        let command = "sh -c 'od -An -tx1 | tr -d \" \\n\"; echo \" $0\"'";
+        let mut digest = CommandDigest::new(command, HashFn::Sha256).unwrap();
+        digest.update(b"ab");
+        digest.update(b"c");
+        assert_eq!(digest.finalize().unwrap().to_string(), "616263");
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn command_digest_reads_output_while_writing() {
+        // The program prints more than a pipe can hold before reading all of its input
+        let command =
+            "sh -c 'head -c 1000000 /dev/zero | tr -c x \" \"; cat >/dev/null; echo abcd'";
+        let mut digest = CommandDigest::new(command, HashFn::Sha256).unwrap();
+        for _ in 0..64 {
+            digest.update(&[0; 64 * 1024]);
+        }
+        assert_eq!(digest.finalize().unwrap().to_string(), "abcd");
+    }
+
+    /// Returns the names of the child processes of this process, including zombies
+    #[cfg(target_os = "linux")]
+    fn child_process_names() -> Vec<String> {
+        let pid = std::process::id().to_string();
+        std::fs::read_dir("/proc")
+            .unwrap()
+            .filter_map(|e| std::fs::read_to_string(e.ok()?.path().join("stat")).ok())
+            .filter_map(|stat| {
+                // The name is given in parentheses and may contain spaces:
+                let (name, rest) = stat.split_once(" (")?.1.rsplit_once(") ")?;
+                let ppid = rest.split_whitespace().nth(1)?;
+                (ppid == pid).then(|| name.to_owned())
+            })
+            .collect()
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn command_digest_stops_program_if_reading_fails() {
+        use crate::files::{file_hashes, Caching, ExtraHashes, FileLen, FilePos};
+        use crate::path::Path;
+        use crate::util::test::with_dir;
+
+        with_dir("hash_backend/unreadable", |root| {
+            // A program with a unique name, to tell it apart from the programs of other tests
+            let program = root.join("fclones-zombie");
+            std::os::unix::fs::symlink("/bin/sh", &program).unwrap();
+            let command = format!(
+                "{} -c 'cat >/dev/null; echo 00'",
+                shell_words::quote(&program.to_string_lossy())
+            );
+            let hashes = ExtraHashes {
+                fns: vec![HashFn::Sha256],
+                backend: HashBackend::Command(command),
+            };
+            // A directory can be opened, but not read:
+            let result = file_hashes(
+                &Path::from(root),
+                FilePos(0),
+                FileLen(100),
+                4096,
+                Caching::Default,
+                &hashes,
+                |_| {},
+            );
+            assert!(result.is_err());
+            assert!(!child_process_names().contains(&"fclones-zombie".to_owned()));
+        });
+    }
+}
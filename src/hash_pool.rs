@@ -0,0 +1,197 @@
+//! Computing hashes on a dedicated thread pool.
+//!
+//! Files are read by the thread pools assigned to the devices, sized for the best I/O
+//! throughput of each device. If the same threads also computed the hashes, a slow hash
+//! function would keep them from issuing the next reads. Instead, the reading thread passes
+//! the buffers it filled to a thread of the hash pool, sized for the number of CPU cores,
+//! and reads the next chunk of the file while the previous one is being hashed.
+
+use std::cmp::min;
+use std::io;
+use std::io::Read;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::sync_channel;
+use std::sync::{Arc, Mutex};
+
+use lazy_static::lazy_static;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use crate::files::FileLen;
+
+/// Number of buffers in flight for a single file.
+/// One is being filled by the reader while the other is being hashed.
+const BUFFERS: usize = 2;
+
+/// Marks the thread count as not configured
+const DEFAULT_THREADS: usize = usize::MAX;
+
+lazy_static! {
+    static ref THREADS: AtomicUsize = AtomicUsize::new(DEFAULT_THREADS);
+    static ref POOL: Mutex<Option<Arc<ThreadPool>>> = Mutex::new(None);
+}
+
+/// Sets the number of threads computing hashes.
+/// If set to 0, hashes are computed by the threads reading the files.
+/// By default, there is one thread per CPU core.
+pub(crate) fn set_threads(count: usize) {
+    if THREADS.swap(count, Ordering::Relaxed) != count {
+        *POOL.lock().unwrap() = None;
+    }
+}
+
+/// Returns the hash pool or `None` if hashing shouldn't be offloaded
+pub(crate) fn pool() -> Option<Arc<ThreadPool>> {
+    let threads = match THREADS.load(Ordering::Relaxed) {
+        0 => return None,
+        DEFAULT_THREADS => num_cpus::get(),
+        n => n,
+    };
+    let mut pool = POOL.lock().unwrap();
+    let pool = pool.get_or_insert_with(|| {
+        let pool = ThreadPoolBuilder::default()
+            .num_threads(threads)
+            .thread_name(|i| format!("hash-{}", i))
+            .build()
+            .unwrap();
+        Arc::new(pool)
+    });
+    Some(pool.clone())
+}
+
+/// Reads up to `len` bytes from the stream in chunks of `buf_len` bytes and passes them
+/// to the `consumer` running on the hash `pool`, in the order they were read.
+/// The `progress` is called on the current thread after each chunk is read.
+/// Returns the number of bytes read and the final state of the `consumer`.
+/// Returns an error if the `consumer` panics, instead of waiting for it forever.
+pub(crate) fn scan<S, F>(
+    pool: &ThreadPool,
+    stream: &mut impl Read,
+    len: FileLen,
+    buf_len: usize,
+    mut state: S,
+    consumer: F,
+    progress: impl Fn(usize),
+) -> io::Result<(u64, S)>
+where
+    S: Send + 'static,
+    F: Fn(&mut S, &[u8]) + Send + 'static,
+{
+    let (data_tx, data_rx) = sync_channel::<Vec<u8>>(BUFFERS);
+    let (free_tx, free_rx) = sync_channel::<Vec<u8>>(BUFFERS);
+    let (result_tx, result_rx) = sync_channel::<S>(1);
+    for _ in 0..BUFFERS {
+        free_tx.send(vec![0; buf_len]).unwrap();
+    }
+    pool.spawn(move || {
+        // If the consumer panics, the channels are dropped and the reader gets an error
+        // when it sends or receives the next buffer:
+        let _ = panic::catch_unwind(AssertUnwindSafe(move || {
+            for buf in data_rx {
+                consumer(&mut state, &buf);
+                // The reader may have already finished and dropped the receiver
+                let _ = free_tx.send(buf);
+            }
+            let _ = result_tx.send(state);
+        }));
+    });
+    let disconnected = || io::Error::new(io::ErrorKind::Other, "Hashing thread failed");
+
+    let mut read: u64 = 0;
+    let len: u64 = len.into();
+    let result = loop {
+        if read >= len {
+            break Ok(());
+        }
+        let mut buf = match free_rx.recv() {
+            Ok(buf) => buf,
+            Err(_) => break Err(disconnected()),
+        };
+        buf.resize(buf_len, 0);
+        let to_read = min(len - read, buf_len as u64) as usize;
+        let result = loop {
+            match stream.read(&mut buf[..to_read]) {
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                result => break result,
+            }
+        };
+        match result {
+            Ok(0) => break Ok(()),
+            Ok(n) => {
+                read += n as u64;
+                buf.truncate(n);
+                progress(n);
+                if data_tx.send(buf).is_err() {
+                    break Err(disconnected());
+                }
+            }
+            Err(e) => break Err(e),
+        }
+    };
+    // Let the consumer know there is no more data
+    drop(data_tx);
+    let state = result_rx.recv().map_err(|_| disconnected());
+    result.and(state).map(|state| (read, state))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scan_passes_chunks_in_order() {
+        let pool = ThreadPoolBuilder::default().num_threads(1).build().unwrap();
+        let data: Vec<u8> = (0..100000).map(|i| (i % 251) as u8).collect();
+        let progress = AtomicUsize::new(0);
+        let (read, copy) = scan(
+            &pool,
+            &mut data.as_slice(),
+            FileLen(90000),
+            4096,
+            Vec::new(),
+            |copy: &mut Vec<u8>, buf: &[u8]| copy.extend_from_slice(buf),
+            |n| {
+                progress.fetch_add(n, Ordering::Relaxed);
+            },
+        )
+        .unwrap();
+        assert_eq!(read, 90000);
+        assert_eq!(copy, &data[..90000]);
+        assert_eq!(progress.load(Ordering::Relaxed), 90000);
+    }
+
+    #[test]
+    fn scan_stops_at_end_of_stream() {
+        let pool = ThreadPoolBuilder::default().num_threads(1).build().unwrap();
+        let data = vec![1u8; 10000];
+        let (read, count) = scan(
+            &pool,
+            &mut data.as_slice(),
+            FileLen::MAX,
+            4096,
+            0,
+            |count: &mut usize, buf: &[u8]| *count += buf.len(),
+            |_| {},
+        )
+        .unwrap();
+        assert_eq!(read, 10000);
+        assert_eq!(count, 10000);
+    }
+
+    #[test]
+    fn scan_fails_if_consumer_panics() {
+        let pool = ThreadPoolBuilder::default().num_threads(1).build().unwrap();
+        let data = vec![1u8; 100000];
+        let result = scan(
+            &pool,
+            &mut data.as_slice(),
+            FileLen::MAX,
+            4096,
+            (),
+            |_: &mut (), _: &[u8]| panic!("consumer failed"),
+            |_| {},
+        );
+        assert!(result.is_err());
+    }
+}
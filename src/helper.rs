@@ -0,0 +1,783 @@
+//! Performing the deduplication commands in a separate, privileged process.
+//!
+//! The unprivileged process reads the report and plans the commands, and sends each
+//! command as a single line of JSON to the helper over a pipe. The helper accepts only
+//! removing files and replacing files by links, only for files under the directories it was
+//! allowed to change, and only if the files are still the same files the unprivileged process
+//! has seen. Files are removed only together with a kept copy, and files are removed or replaced
+//! by links only if their contents are equal to the contents of the kept copies or link targets.
+//! Each request is answered with a single line of JSON with the amount of space reclaimed
+//! or the error.
+
+use std::io;
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::process::{Child, ChildStdin, ChildStdout, Stdio};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::dedupe::{FileMetadata, FsCommand};
+#[cfg(not(unix))]
+use crate::files::FileHash;
+use crate::files::{FileId, FileLen};
+#[cfg(unix)]
+use crate::lock::FileLock;
+use crate::log::Log;
+use crate::path::Path;
+use crate::report::{parse_path, to_file_uri};
+use crate::util::{raw_os_error, with_context};
+
+/// A file the request refers to, together with its metadata seen by the requesting process
+#[derive(Debug, Serialize, Deserialize)]
+struct FileRef {
+    path: String,
+    len: u64,
+    device: u64,
+    /// Decimal, because file identifiers on Windows don't fit in 64 bits
+    inode: String,
+}
+
+impl FileRef {
+    fn new(file: &FileMetadata) -> io::Result<FileRef> {
+        let id = FileId::new(&file.path)?;
+        Ok(FileRef {
+            path: file
+                .path
+                .to_str()
+                .unwrap_or_else(|| to_file_uri(&file.path.to_bytes())),
+            len: file.metadata.len(),
+            device: id.device,
+            inode: id.inode.to_string(),
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+enum Request {
+    Remove { file: FileRef, kept: FileRef },
+    HardLink { target: FileRef, link: FileRef },
+    SoftLink { target: FileRef, link: FileRef },
+}
+
+impl Request {
+    fn new(cmd: &FsCommand) -> io::Result<Request> {
+        match cmd {
+            FsCommand::Remove {
+                file,
+                kept: Some(kept),
+                ..
+            } => Ok(Request::Remove {
+                file: FileRef::new(file)?,
+                kept: FileRef::new(kept)?,
+            }),
+            FsCommand::Remove { kept: None, .. } => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "Removing files without a kept copy is not supported by the helper",
+            )),
+            FsCommand::HardLink { target, link, .. } => Ok(Request::HardLink {
+                target: FileRef::new(target)?,
+                link: FileRef::new(link)?,
+            }),
+            FsCommand::SoftLink { target, link, .. } => Ok(Request::SoftLink {
+                target: FileRef::new(target)?,
+                link: FileRef::new(link)?,
+            }),
+            FsCommand::Move { .. } => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "Moving files is not supported by the helper",
+            )),
+            FsCommand::RefLink { .. } => Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "Creating reflinks is not supported by the helper",
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Response {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reclaimed: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    os_error: Option<i32>,
+}
+
+impl From<io::Result<FileLen>> for Response {
+    fn from(result: io::Result<FileLen>) -> Self {
+        match result {
+            Ok(len) => Response {
+                reclaimed: Some(len.0),
+                ..Default::default()
+            },
+            Err(e) => Response {
+                error: Some(e.to_string()),
+                os_error: raw_os_error(&e),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+/// Executes the requests received from an unprivileged process
+struct Server<'a> {
+    allowed: Vec<Path>,
+    log: &'a Log,
+}
+
+fn denied(path: &Path, reason: &str) -> io::Error {
+    io::Error::new(
+        ErrorKind::PermissionDenied,
+        format!("Refusing to change {}: {}", path, reason),
+    )
+}
+
+#[cfg(unix)]
+fn nix_as_io_error<T>(result: nix::Result<T>) -> io::Result<T> {
+    match result {
+        Ok(x) => Ok(x),
+        Err(nix::Error::Sys(errno)) => Err(io::Error::from_raw_os_error(errno as i32)),
+        Err(e) => Err(io::Error::new(ErrorKind::Other, e.to_string())),
+    }
+}
+
+/// A regular file checked by the helper, open together with its parent directory.
+///
+/// The directories on the path are opened one by one, without following symbolic links,
+/// and the file is changed only through the handle of its parent directory,
+/// after checking again that the directory entry still refers to the opened file.
+/// This way the file can't be swapped for a different one between checking and changing it.
+#[cfg(unix)]
+struct OpenFile {
+    path: Path,
+    dir: std::fs::File,
+    name: std::ffi::OsString,
+    file: std::fs::File,
+    metadata: std::fs::Metadata,
+}
+
+#[cfg(unix)]
+impl OpenFile {
+    fn open(path: Path, writable: bool) -> io::Result<OpenFile> {
+        use nix::fcntl::{openat, AtFlags, OFlag};
+        use nix::sys::stat::{fstatat, Mode, SFlag};
+        use std::os::unix::io::{AsRawFd, FromRawFd};
+
+        let error = |e: io::Error| with_context(format!("Failed to open {}: {}", path, e), e);
+        let open_at = |dir: &std::fs::File, name: &std::ffi::OsStr, flags: OFlag| {
+            let flags = flags | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+            nix_as_io_error(openat(dir.as_raw_fd(), name, flags, Mode::empty()))
+                .map(|fd| unsafe { std::fs::File::from_raw_fd(fd) })
+        };
+
+        let name = path
+            .file_name()
+            .ok_or_else(|| denied(&path, "not a regular file"))?;
+        let parent = path
+            .parent()
+            .ok_or_else(|| denied(&path, "not a regular file"))?
+            .to_path_buf();
+        let mut dir = std::fs::File::open("/").map_err(error)?;
+        for component in parent.components() {
+            if let std::path::Component::Normal(c) = component {
+                let flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY;
+                dir = open_at(&dir, c, flags).map_err(error)?;
+            }
+        }
+
+        // Check the type before opening, so no device or fifo gets opened
+        let stat = nix_as_io_error(fstatat(
+            dir.as_raw_fd(),
+            name.as_os_str(),
+            AtFlags::AT_SYMLINK_NOFOLLOW,
+        ))
+        .map_err(error)?;
+        if SFlag::from_bits_truncate(stat.st_mode) & SFlag::S_IFMT != SFlag::S_IFREG {
+            return Err(denied(&path, "not a regular file"));
+        }
+        let access = if writable {
+            OFlag::O_RDWR
+        } else {
+            OFlag::O_RDONLY
+        };
+        let file = open_at(&dir, &name, access | OFlag::O_NONBLOCK).map_err(error)?;
+        let metadata = file.metadata().map_err(error)?;
+        let result = OpenFile {
+            path,
+            dir,
+            name,
+            file,
+            metadata,
+        };
+        result.verify()?;
+        Ok(result)
+    }
+
+    /// Returns an error if the directory entry doesn't refer to the opened file anymore
+    fn verify(&self) -> io::Result<()> {
+        use nix::fcntl::AtFlags;
+        use nix::sys::stat::fstatat;
+        use std::os::unix::fs::MetadataExt;
+        use std::os::unix::io::AsRawFd;
+
+        let stat = nix_as_io_error(fstatat(
+            self.dir.as_raw_fd(),
+            self.name.as_os_str(),
+            AtFlags::AT_SYMLINK_NOFOLLOW,
+        ))
+        .map_err(|e| with_context(format!("Failed to stat {}: {}", self.path, e), e))?;
+        if stat.st_dev as u64 != self.metadata.dev() || stat.st_ino as u64 != self.metadata.ino() {
+            return Err(denied(&self.path, "file replaced while being checked"));
+        }
+        Ok(())
+    }
+
+    /// Returns an error if the file was scanned as a different file or with a different length
+    fn check_scanned(&self, file: &FileRef) -> io::Result<()> {
+        use std::os::unix::fs::MetadataExt;
+        if self.metadata.len() != file.len
+            || self.metadata.dev() != file.device
+            || self.metadata.ino().to_string() != file.inode
+        {
+            return Err(denied(&self.path, "file changed since it was scanned"));
+        }
+        Ok(())
+    }
+
+    /// Returns an error if the file is locked by another process
+    fn check_not_locked(&self) -> io::Result<()> {
+        let file = self.file.try_clone()?;
+        FileLock::from_file(file)
+            .map(|_| {})
+            .map_err(|e| with_context(format!("Failed to lock file {}: {}", self.path, e), e))
+    }
+
+    /// Returns true if both files have the same contents
+    fn same_contents(&self, other: &OpenFile) -> io::Result<bool> {
+        use std::io::{Seek, SeekFrom};
+        let mut file1 = &self.file;
+        let mut file2 = &other.file;
+        file1.seek(SeekFrom::Start(0))?;
+        file2.seek(SeekFrom::Start(0))?;
+        FsCommand::same_file_contents(&mut file1.try_clone()?, &mut file2.try_clone()?)
+    }
+
+    /// Removes the file
+    fn remove(&self) -> io::Result<()> {
+        use nix::unistd::{unlinkat, UnlinkatFlags};
+        use std::os::unix::io::AsRawFd;
+
+        self.verify()?;
+        nix_as_io_error(unlinkat(
+            Some(self.dir.as_raw_fd()),
+            self.name.as_os_str(),
+            UnlinkatFlags::NoRemoveDir,
+        ))
+        .map_err(|e| with_context(format!("Failed to remove file {}: {}", self.path, e), e))
+    }
+
+    /// Replaces the file by a link created by `create` in the same directory,
+    /// under the temporary name passed to it.
+    /// The file is replaced atomically, so it can't get lost if creating the link fails.
+    fn replace(
+        &self,
+        create: impl FnOnce(&std::fs::File, &std::ffi::OsStr) -> nix::Result<()>,
+        log: &Log,
+    ) -> io::Result<()> {
+        use nix::fcntl::renameat;
+        use nix::unistd::{unlinkat, UnlinkatFlags};
+        use std::os::unix::io::AsRawFd;
+
+        let tmp = FsCommand::temp_file(&self.path).file_name().unwrap();
+        let dir = Some(self.dir.as_raw_fd());
+        nix_as_io_error(create(&self.dir, &tmp))
+            .map_err(|e| with_context(format!("Failed to link {}: {}", self.path, e), e))?;
+        let result = self.verify().and_then(|_| {
+            nix_as_io_error(renameat(dir, tmp.as_os_str(), dir, self.name.as_os_str()))
+                .map_err(|e| with_context(format!("Failed to replace {}: {}", self.path, e), e))
+        });
+        if result.is_err() {
+            if let Err(e) = unlinkat(dir, tmp.as_os_str(), UnlinkatFlags::NoRemoveDir) {
+                log.warn(format!(
+                    "Failed to remove temporary {}: {}",
+                    tmp.to_string_lossy(),
+                    e
+                ));
+            }
+        }
+        result
+    }
+}
+
+impl<'a> Server<'a> {
+    /// Returns the path of the referenced file if it is allowed to be changed
+    fn allowed_path(&self, file: &FileRef) -> io::Result<Path> {
+        let path = parse_path(&file.path)?;
+        if !path.is_absolute() || path.canonicalize() != path {
+            return Err(denied(&path, "path is not absolute or not canonical"));
+        }
+        if !self.allowed.iter().any(|dir| dir.is_prefix_of(&path)) {
+            return Err(denied(&path, "not under any of the allowed directories"));
+        }
+        Ok(path)
+    }
+
+    /// Opens the referenced file if it is allowed to be changed
+    /// and if it hasn't changed since the requesting process has seen it
+    #[cfg(unix)]
+    fn open(&self, file: &FileRef, writable: bool) -> io::Result<OpenFile> {
+        let opened = OpenFile::open(self.allowed_path(file)?, writable)?;
+        opened.check_scanned(file)?;
+        Ok(opened)
+    }
+
+    /// Opens the kept file and the file to be changed,
+    /// if the contents of both files are the same
+    #[cfg(unix)]
+    fn open_same(&self, kept: &FileRef, changed: &FileRef) -> io::Result<(OpenFile, OpenFile)> {
+        let kept = self.open(kept, false)?;
+        let changed = self.open(changed, true)?;
+        if kept.metadata.len() != changed.metadata.len() || !kept.same_contents(&changed)? {
+            return Err(denied(
+                &changed.path,
+                &format!("contents differ from {}", kept.path),
+            ));
+        }
+        changed.check_not_locked()?;
+        Ok((kept, changed))
+    }
+
+    #[cfg(unix)]
+    fn execute(&self, request: Request) -> io::Result<FileLen> {
+        use nix::unistd::{linkat, symlinkat, LinkatFlags};
+        use std::os::unix::io::AsRawFd;
+
+        match request {
+            Request::Remove { file, kept } => {
+                let (kept, file) = self.open_same(&kept, &file)?;
+                kept.verify()?;
+                file.remove()?;
+                Ok(FileLen(file.metadata.len()))
+            }
+            Request::HardLink { target, link } => {
+                let (target, link) = self.open_same(&target, &link)?;
+                let create = |dir: &std::fs::File, tmp: &std::ffi::OsStr| {
+                    target
+                        .verify()
+                        .map_err(|_| nix::Error::Sys(nix::errno::Errno::ESTALE))?;
+                    linkat(
+                        Some(target.dir.as_raw_fd()),
+                        target.name.as_os_str(),
+                        Some(dir.as_raw_fd()),
+                        tmp,
+                        LinkatFlags::NoSymlinkFollow,
+                    )
+                };
+                link.replace(create, self.log)?;
+                Ok(FileLen(link.metadata.len()))
+            }
+            Request::SoftLink { target, link } => {
+                let (target, link) = self.open_same(&target, &link)?;
+                let create = |dir: &std::fs::File, tmp: &std::ffi::OsStr| {
+                    symlinkat(&target.path.to_path_buf(), Some(dir.as_raw_fd()), tmp)
+                };
+                link.replace(create, self.log)?;
+                Ok(FileLen(link.metadata.len()))
+            }
+        }
+    }
+
+    /// Returns the metadata of the referenced file if it is allowed to be changed
+    /// and if it hasn't changed since the requesting process has seen it
+    #[cfg(not(unix))]
+    fn check(&self, file: &FileRef) -> io::Result<FileMetadata> {
+        let file_metadata = FileMetadata::new(self.allowed_path(file)?)?;
+        if !file_metadata.metadata.is_file() {
+            return Err(denied(&file_metadata.path, "not a regular file"));
+        }
+        let id = FileId::new(&file_metadata.path)?;
+        if file_metadata.metadata.len() != file.len
+            || id.device != file.device
+            || id.inode.to_string() != file.inode
+        {
+            return Err(denied(
+                &file_metadata.path,
+                "file changed since it was scanned",
+            ));
+        }
+        Ok(file_metadata)
+    }
+
+    /// Returns the metadata of the kept file and of the file to be changed,
+    /// if the contents of both files are the same
+    #[cfg(not(unix))]
+    fn check_same(
+        &self,
+        kept: &FileRef,
+        changed: &FileRef,
+    ) -> io::Result<(FileMetadata, FileMetadata)> {
+        let kept = self.check(kept)?;
+        let changed = self.check(changed)?;
+        if !FsCommand::same_contents(&kept.path, &changed.path)? {
+            return Err(denied(
+                &changed.path,
+                &format!("contents differ from {}", kept.path),
+            ));
+        }
+        Ok((kept, changed))
+    }
+
+    #[cfg(not(unix))]
+    fn execute(&self, request: Request) -> io::Result<FileLen> {
+        // The hash is needed only for reporting, which is done by the requesting process
+        let file_hash = FileHash::from(0);
+        let cmd = match request {
+            Request::Remove { file, kept } => {
+                let (kept, file) = self.check_same(&kept, &file)?;
+                FsCommand::Remove {
+                    file,
+                    kept: Some(kept.into()),
+                    file_hash,
+                }
+            }
+            Request::HardLink { target, link } => {
+                let (target, link) = self.check_same(&target, &link)?;
+                FsCommand::HardLink {
+                    target: target.into(),
+                    link,
+                    file_hash,
+                }
+            }
+            Request::SoftLink { target, link } => {
+                let (target, link) = self.check_same(&target, &link)?;
+                FsCommand::SoftLink {
+                    target: target.into(),
+                    link,
+                    file_hash,
+                }
+            }
+        };
+        cmd.execute(self.log)
+    }
+}
+
+/// Reads requests from `input`, executes them and writes the responses to `output`,
+/// until the input is closed.
+///
+/// Only files under the `allowed` directories can be changed.
+pub fn serve(
+    allowed: &[std::path::PathBuf],
+    input: impl BufRead,
+    mut output: impl Write,
+    log: &Log,
+) -> io::Result<()> {
+    let server = Server {
+        allowed: allowed
+            .iter()
+            .map(|d| Path::from(d).canonicalize())
+            .collect(),
+        log,
+    };
+    for line in input.lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let response = match serde_json::from_str::<Request>(&line) {
+            Ok(request) => Response::from(server.execute(request)),
+            Err(e) => Response {
+                error: Some(format!("Malformed request: {}", e)),
+                ..Default::default()
+            },
+        };
+        if let Some(e) = &response.error {
+            log.warn(e);
+        }
+        serde_json::to_writer(&mut output, &response)?;
+        writeln!(output)?;
+        output.flush()?;
+    }
+    Ok(())
+}
+
+/// Connection to a helper process started by `fclones helper`
+pub struct Helper {
+    process: Child,
+    pipe: Mutex<Option<(ChildStdin, BufReader<ChildStdout>)>>,
+}
+
+impl Helper {
+    /// Starts the helper with the given shell command,
+    /// e.g. `sudo fclones helper --allow /data`.
+    pub fn spawn(command: &str) -> io::Result<Helper> {
+        let args = shell_words::split(command)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+        let (program, args) = args.split_first().ok_or_else(|| {
+            io::Error::new(ErrorKind::InvalidInput, "Empty helper command".to_string())
+        })?;
+        let mut process = std::process::Command::new(program)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(|e| with_context(format!("Failed to start helper {}: {}", program, e), e))?;
+        let stdin = process.stdin.take().unwrap();
+        let stdout = BufReader::new(process.stdout.take().unwrap());
+        Ok(Helper {
+            process,
+            pipe: Mutex::new(Some((stdin, stdout))),
+        })
+    }
+
+    /// Sends the command to the helper and waits for the result.
+    /// Commands moving files are not supported.
+    pub fn execute(&self, cmd: &FsCommand) -> io::Result<FileLen> {
+        let request = Request::new(cmd)?;
+        let mut line = serde_json::to_string(&request)?;
+        line.push('\n');
+
+        let mut pipe = self.pipe.lock().unwrap();
+        let (stdin, stdout) = pipe.as_mut().unwrap();
+        let broken = |e: io::Error| with_context(format!("Lost connection to helper: {}", e), e);
+        stdin.write_all(line.as_bytes()).map_err(broken)?;
+        stdin.flush().map_err(broken)?;
+        let mut line = String::new();
+        if stdout.read_line(&mut line).map_err(broken)? == 0 {
+            return Err(io::Error::new(
+                ErrorKind::BrokenPipe,
+                "Lost connection to helper: helper exited".to_string(),
+            ));
+        }
+        drop(pipe);
+
+        let response: Response = serde_json::from_str(&line)?;
+        match (response.reclaimed, response.error) {
+            (_, Some(error)) => Err(match response.os_error {
+                Some(code) => with_context(error, io::Error::from_raw_os_error(code)),
+                None => io::Error::new(ErrorKind::Other, error),
+            }),
+            (Some(reclaimed), None) => Ok(FileLen(reclaimed)),
+            (None, None) => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "Malformed response from helper".to_string(),
+            )),
+        }
+    }
+}
+
+impl Drop for Helper {
+    fn drop(&mut self) {
+        // Closing the input makes the helper exit
+        drop(
+            self.pipe
+                .get_mut()
+                .unwrap_or_else(|e| e.into_inner())
+                .take(),
+        );
+        let _ = self.process.wait();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use serde_json::Value;
+
+    use crate::files::FileHash;
+    use crate::util::test::with_dir;
+
+    use super::*;
+
+    fn test_log() -> Log {
+        let mut log = Log::new();
+        log.no_progress = true;
+        log
+    }
+
+    fn file_ref(path: &std::path::Path) -> FileRef {
+        let file = FileMetadata::new(Path::from(path.canonicalize().unwrap())).unwrap();
+        FileRef::new(&file).unwrap()
+    }
+
+    /// Sends the requests to a helper allowed to change files in `allowed`
+    /// and returns the responses
+    fn serve_requests(allowed: &std::path::Path, requests: &[String]) -> Vec<Value> {
+        let input = requests.join("\n");
+        let mut output = Vec::new();
+        serve(
+            &[allowed.to_path_buf()],
+            input.as_bytes(),
+            &mut output,
+            &test_log(),
+        )
+        .unwrap();
+        String::from_utf8(output)
+            .unwrap()
+            .lines()
+            .map(|l| serde_json::from_str(l).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn removes_and_links_allowed_files() {
+        with_dir("helper/allowed", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            let file3 = root.join("file3");
+            fs::write(&file1, b"contents").unwrap();
+            fs::write(&file2, b"contents").unwrap();
+            fs::write(&file3, b"contents").unwrap();
+
+            let requests = vec![
+                serde_json::to_string(&Request::Remove {
+                    file: file_ref(&file2),
+                    kept: file_ref(&file1),
+                })
+                .unwrap(),
+                serde_json::to_string(&Request::HardLink {
+                    target: file_ref(&file1),
+                    link: file_ref(&file3),
+                })
+                .unwrap(),
+            ];
+            let responses = serve_requests(root, &requests);
+            assert_eq!(responses[0]["reclaimed"], 8);
+            assert_eq!(responses[1]["reclaimed"], 8);
+            assert!(!file2.exists());
+            assert_eq!(file_ref(&file1).inode, file_ref(&file3).inode);
+        });
+    }
+
+    #[test]
+    fn refuses_files_outside_of_allowed_dirs() {
+        with_dir("helper/outside", |root| {
+            let allowed = root.join("allowed");
+            fs::create_dir(&allowed).unwrap();
+            let file = root.join("file");
+            let kept = allowed.join("kept");
+            fs::write(&file, b"contents").unwrap();
+            fs::write(&kept, b"contents").unwrap();
+
+            let request = serde_json::to_string(&Request::Remove {
+                file: file_ref(&file),
+                kept: file_ref(&kept),
+            })
+            .unwrap();
+            let responses = serve_requests(&allowed, &[request]);
+            assert!(responses[0]["error"]
+                .as_str()
+                .unwrap()
+                .contains("not under any of the allowed directories"));
+            assert!(file.exists());
+        });
+    }
+
+    #[test]
+    fn refuses_changed_files_and_links_to_different_contents() {
+        with_dir("helper/changed", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            fs::write(&file1, b"contents").unwrap();
+            fs::write(&file2, b"CONTENTS").unwrap();
+
+            let mut changed = file_ref(&file1);
+            changed.inode.push('0');
+            let requests = vec![
+                serde_json::to_string(&Request::Remove {
+                    file: changed,
+                    kept: file_ref(&file2),
+                })
+                .unwrap(),
+                serde_json::to_string(&Request::SoftLink {
+                    target: file_ref(&file1),
+                    link: file_ref(&file2),
+                })
+                .unwrap(),
+                "{\"op\":\"chmod\"}".to_string(),
+                serde_json::to_string(&Request::Remove {
+                    file: file_ref(&file1),
+                    kept: file_ref(&file2),
+                })
+                .unwrap(),
+                format!(
+                    "{{\"op\":\"remove\",\"file\":{}}}",
+                    serde_json::to_string(&file_ref(&file1)).unwrap()
+                ),
+            ];
+            let responses = serve_requests(root, &requests);
+            assert!(responses[0]["error"]
+                .as_str()
+                .unwrap()
+                .contains("changed since it was scanned"));
+            assert!(responses[1]["error"]
+                .as_str()
+                .unwrap()
+                .contains("contents differ"));
+            assert!(responses[2]["error"]
+                .as_str()
+                .unwrap()
+                .starts_with("Malformed request"));
+            assert!(responses[3]["error"]
+                .as_str()
+                .unwrap()
+                .contains("contents differ"));
+            assert!(responses[4]["error"]
+                .as_str()
+                .unwrap()
+                .starts_with("Malformed request"));
+            assert!(file1.exists());
+            assert_eq!(fs::read(&file2).unwrap(), b"CONTENTS");
+        });
+    }
+
+    #[test]
+    fn refuses_removing_without_kept_copy() {
+        with_dir("helper/no_kept_copy", |root| {
+            let file = root.join("file");
+            fs::write(&file, b"contents").unwrap();
+            let cmd = FsCommand::Remove {
+                file: FileMetadata::new(Path::from(&file)).unwrap(),
+                kept: None,
+                file_hash: FileHash::from(0),
+            };
+            let e = Request::new(&cmd).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::Unsupported);
+        });
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn refuses_files_replaced_after_checking() {
+        with_dir("helper/replaced", |root| {
+            let file = root.join("file");
+            let other = root.join("other");
+            fs::write(&file, b"contents").unwrap();
+            fs::write(&other, b"contents").unwrap();
+
+            let path = Path::from(file.canonicalize().unwrap());
+            let opened = OpenFile::open(path, true).unwrap();
+            fs::rename(&other, &file).unwrap();
+            let e = opened.remove().unwrap_err();
+            assert!(e.to_string().contains("replaced while being checked"));
+            assert!(file.exists());
+        });
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn refuses_paths_through_symlinks() {
+        with_dir("helper/symlinked_dir", |root| {
+            let dir = root.join("dir");
+            let link = root.join("link");
+            fs::create_dir(&dir).unwrap();
+            fs::write(dir.join("file"), b"contents").unwrap();
+            std::os::unix::fs::symlink(&dir, &link).unwrap();
+
+            let path = Path::from(root.canonicalize().unwrap().join("link").join("file"));
+            assert!(OpenFile::open(path, true).is_err());
+        });
+    }
+}
@@ -0,0 +1,85 @@
+//! Patterns always excluded from scans, see `--ignore-file`.
+//!
+//! By default the patterns are read from `fclones/ignore` in the user's configuration directory,
+//! i.e. `$XDG_CONFIG_HOME/fclones/ignore` or `~/.config/fclones/ignore` on Unix
+//! and `%APPDATA%\fclones\ignore` on Windows.
+//! Each line holds a pattern of paths to exclude, as given to `--exclude`.
+//! A pattern ending with `/` is a pattern of directory names, as given to `--exclude-dir`.
+//! Empty lines and lines starting with `#` are ignored.
+
+use std::env;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader};
+use std::path::PathBuf;
+
+/// Patterns read from an ignore file
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct IgnoreFile {
+    /// Patterns of excluded paths
+    pub exclude_patterns: Vec<String>,
+    /// Patterns of names of directories not descended into
+    pub exclude_dir_patterns: Vec<String>,
+}
+
+impl IgnoreFile {
+    /// Returns the location of the ignore file read by default,
+    /// or `None` if the configuration directory can't be determined
+    pub fn default_path() -> Option<PathBuf> {
+        let non_empty = |var| {
+            env::var_os(var)
+                .filter(|v| !v.is_empty())
+                .map(PathBuf::from)
+        };
+        let config_dir = if cfg!(windows) {
+            non_empty("APPDATA")
+        } else {
+            non_empty("XDG_CONFIG_HOME").or_else(|| non_empty("HOME").map(|h| h.join(".config")))
+        };
+        config_dir.map(|d| d.join("fclones").join("ignore"))
+    }
+
+    /// Reads the patterns from a file
+    pub fn read(path: &std::path::Path) -> io::Result<IgnoreFile> {
+        Self::parse(BufReader::new(File::open(path)?))
+    }
+
+    fn parse(input: impl BufRead) -> io::Result<IgnoreFile> {
+        let mut result = IgnoreFile::default();
+        for line in input.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.strip_suffix('/') {
+                Some(dir) => result.exclude_dir_patterns.push(dir.to_owned()),
+                None => result.exclude_patterns.push(line.to_owned()),
+            }
+        }
+        Ok(result)
+    }
+
+    /// Returns the total number of patterns
+    pub fn len(&self) -> usize {
+        self.exclude_patterns.len() + self.exclude_dir_patterns.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_path_and_dir_patterns() {
+        let input = "# caches\n**/.cache/**\n\n  node_modules/ \n*.tmp\n";
+        let ignore = IgnoreFile::parse(input.as_bytes()).unwrap();
+        assert_eq!(ignore.exclude_patterns, vec!["**/.cache/**", "*.tmp"]);
+        assert_eq!(ignore.exclude_dir_patterns, vec!["node_modules"]);
+        assert_eq!(ignore.len(), 3);
+    }
+}
@@ -0,0 +1,294 @@
+//! Abandoning file operations that don't complete in time, see `--io-timeout`.
+//!
+//! A read from a failing disk or from a hung network file system can block forever,
+//! and a thread blocked in a system call can't be interrupted. Therefore, when a timeout
+//! is set, each stat, open and read is performed by a helper thread, while the calling thread
+//! waits for the result only as long as the timeout allows. When the timeout expires,
+//! the helper thread is abandoned together with the file it was reading and the caller gets
+//! a `TimedOut` error, so it can move on to the next file.
+//!
+//! The timeout is passed by the caller, so jobs running at the same time can use
+//! different ones. An abandoned thread keeps its file open until the stalled call returns,
+//! therefore the duplicated descriptors count against the budget of [`crate::fd`] and
+//! the number of abandoned threads is capped.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs::{File, Metadata, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::time::Duration;
+use std::{fs, io, thread};
+
+use crate::fd;
+use crate::fd::FdGuard;
+use crate::path::Path;
+use crate::report::{escape_path, unescape_path};
+
+/// Maximum number of abandoned helper threads whose stalled calls haven't returned yet.
+/// Once reached, the calls fail immediately instead of leaving more threads behind.
+const MAX_ABANDONED: usize = 64;
+
+/// Number of abandoned helper threads whose stalled calls haven't returned yet
+static ABANDONED: AtomicUsize = AtomicUsize::new(0);
+
+/// States of a call passed to the helper thread
+const CALL_PENDING: u8 = 0;
+const CALL_DONE: u8 = 1;
+const CALL_ABANDONED: u8 = 2;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+thread_local! {
+    /// Helper thread performing the blocking calls of the current thread
+    static WORKER: RefCell<Option<Sender<Job>>> = RefCell::new(None);
+}
+
+/// Reads the files listed by `--quarantine`.
+/// Returns an empty set if the list doesn't exist yet.
+pub(crate) fn read_quarantine(list: &std::path::Path) -> io::Result<HashSet<Path>> {
+    let file = match File::open(list) {
+        Ok(file) => file,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(HashSet::new()),
+        Err(e) => return Err(e),
+    };
+    let mut paths = HashSet::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        if !line.is_empty() {
+            paths.insert(unescape_path(&line)?);
+        }
+    }
+    Ok(paths)
+}
+
+/// Appends the files to the list given by `--quarantine`, one per line.
+/// The paths are escaped like in the `text-v2` report, so any path fits in a single line.
+pub(crate) fn append_to_quarantine(list: &std::path::Path, files: &[Path]) -> io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(list)?;
+    let mut out = BufWriter::new(file);
+    for f in files {
+        writeln!(out, "{}", escape_path(f))?;
+    }
+    out.flush()
+}
+
+fn spawn_worker() -> io::Result<Sender<Job>> {
+    let (tx, rx) = channel::<Job>();
+    thread::Builder::new()
+        .name("io-timeout".to_owned())
+        .spawn(move || rx.into_iter().for_each(|job| job()))?;
+    Ok(tx)
+}
+
+/// Marks the call as done when dropped, also if the call panicked.
+/// Releases the slot of the abandoned thread if the caller has given up waiting.
+struct CallGuard(Arc<AtomicU8>);
+
+impl Drop for CallGuard {
+    fn drop(&mut self) {
+        if self.0.swap(CALL_DONE, Ordering::AcqRel) == CALL_ABANDONED {
+            ABANDONED.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
+
+/// Calls `f` on the helper thread of the current thread and waits at most `timeout`
+/// for the result. If the timeout is not set, just calls `f`.
+pub(crate) fn run<T: Send + 'static>(
+    timeout: Option<Duration>,
+    f: impl FnOnce() -> T + Send + 'static,
+) -> io::Result<T> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return Ok(f()),
+    };
+    if ABANDONED.load(Ordering::Acquire) >= MAX_ABANDONED {
+        return Err(io::Error::new(
+            ErrorKind::Other,
+            format!(
+                "Too many stalled I/O calls ({}) abandoned because of the timeout",
+                MAX_ABANDONED
+            ),
+        ));
+    }
+    let state = Arc::new(AtomicU8::new(CALL_PENDING));
+    let guard = CallGuard(state.clone());
+    let (tx, rx) = sync_channel(1);
+    let mut job: Job = Box::new(move || {
+        let result = f();
+        drop(guard);
+        let _ = tx.send(result);
+    });
+    WORKER.with(|worker| -> io::Result<()> {
+        let mut worker = worker.borrow_mut();
+        loop {
+            if worker.is_none() {
+                *worker = Some(spawn_worker()?);
+            }
+            // The helper thread exits only if a job panicked; replace it then
+            match worker.as_ref().unwrap().send(job) {
+                Ok(()) => return Ok(()),
+                Err(e) => {
+                    job = e.0;
+                    *worker = None;
+                }
+            }
+        }
+    })?;
+    let helper_failed = || io::Error::new(ErrorKind::Other, "I/O helper thread failed");
+    match rx.recv_timeout(timeout) {
+        Ok(result) => Ok(result),
+        Err(RecvTimeoutError::Timeout) => {
+            ABANDONED.fetch_add(1, Ordering::AcqRel);
+            if state.swap(CALL_ABANDONED, Ordering::AcqRel) == CALL_DONE {
+                // The call returned just after the timeout, so the thread is not stuck:
+                ABANDONED.fetch_sub(1, Ordering::AcqRel);
+                return rx.recv().map_err(|_| helper_failed());
+            }
+            // The helper thread is stuck, leave it behind:
+            WORKER.with(|worker| *worker.borrow_mut() = None);
+            Err(io::Error::new(
+                ErrorKind::TimedOut,
+                format!("I/O timed out after {:.1} s", timeout.as_secs_f64()),
+            ))
+        }
+        Err(RecvTimeoutError::Disconnected) => Err(helper_failed()),
+    }
+}
+
+/// Returns the metadata of a file, following symbolic links.
+/// Gives up after `timeout`.
+pub(crate) fn metadata(path: &Path, timeout: Option<Duration>) -> io::Result<Metadata> {
+    let path = path.to_path_buf();
+    run(timeout, move || fs::metadata(path))?
+}
+
+/// Returns the metadata of a file, without following symbolic links.
+/// Gives up after `timeout`.
+pub(crate) fn symlink_metadata(path: &Path, timeout: Option<Duration>) -> io::Result<Metadata> {
+    let path = path.to_path_buf();
+    run(timeout, move || fs::symlink_metadata(path))?
+}
+
+/// A duplicate of a file handle used by the helper thread.
+/// Keeps its descriptor reserved in the budget until the helper thread lets it go,
+/// which may happen long after the caller has given up.
+struct SharedFile {
+    file: File,
+    _fd: FdGuard<'static>,
+}
+
+/// Reads a file giving up each read after the given timeout.
+/// The reads go through a duplicate of the file handle, which shares the position
+/// with the original one, so the original can still be used for seeking.
+pub(crate) struct TimedRead<'a> {
+    file: &'a File,
+    shared: Option<Arc<SharedFile>>,
+    timeout: Option<Duration>,
+    buf: Vec<u8>,
+}
+
+impl<'a> TimedRead<'a> {
+    pub fn new(file: &'a File, timeout: Option<Duration>) -> io::Result<TimedRead<'a>> {
+        let shared = match timeout {
+            Some(_) => Some(Arc::new(SharedFile {
+                _fd: fd::reserve(1),
+                file: file.try_clone()?,
+            })),
+            None => None,
+        };
+        Ok(TimedRead {
+            file,
+            shared,
+            timeout,
+            buf: Vec::new(),
+        })
+    }
+
+    /// Calls `f` with the file, giving up after the timeout.
+    /// Use for other blocking calls than reads, e.g. `fstat` or `lseek`.
+    pub fn call<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&File) -> T + Send + 'static,
+    ) -> io::Result<T> {
+        match &self.shared {
+            Some(shared) => {
+                let shared = shared.clone();
+                run(self.timeout, move || f(&shared.file))
+            }
+            None => Ok(f(self.file)),
+        }
+    }
+}
+
+impl<'a> Read for TimedRead<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.shared.is_none() {
+            return (&mut &*self.file).read(buf);
+        }
+        let mut data = std::mem::take(&mut self.buf);
+        data.resize(buf.len(), 0);
+        let (data, result) = self.call(move |mut file| {
+            let result = file.read(&mut data);
+            (data, result)
+        })?;
+        let len = result?;
+        buf[..len].copy_from_slice(&data[..len]);
+        self.buf = data;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::*;
+
+    #[test]
+    fn abandons_stalled_calls() {
+        let timeout = Some(Duration::from_millis(100));
+        assert_eq!(run(timeout, || 1).unwrap(), 1);
+        let result = run(timeout, || thread::sleep(Duration::from_millis(500)));
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::TimedOut);
+        assert_eq!(ABANDONED.load(Ordering::Acquire), 1);
+        // A new helper thread takes over:
+        assert_eq!(run(timeout, || 2).unwrap(), 2);
+        // The abandoned thread gives its slot back once the stalled call returns:
+        thread::sleep(Duration::from_secs(1));
+        assert_eq!(ABANDONED.load(Ordering::Acquire), 0);
+    }
+
+    #[test]
+    fn quarantine_list_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let list = dir.path().join("quarantine.txt");
+        assert!(read_quarantine(&list).unwrap().is_empty());
+        let files = vec![
+            Path::from("/data/a"),
+            Path::from("/data/b c"),
+            Path::from(" /data/line\nbreak\\ "),
+            Path::from("file:///data/d"),
+        ];
+        append_to_quarantine(&list, &files[..2]).unwrap();
+        append_to_quarantine(&list, &files[2..]).unwrap();
+        let read = read_quarantine(&list).unwrap();
+        assert_eq!(read, files.into_iter().collect());
+    }
+
+    #[test]
+    fn reads_through_helper_thread() {
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"contents").unwrap();
+        std::io::Seek::seek(&mut file, std::io::SeekFrom::Start(3)).unwrap();
+        let mut data = String::new();
+        TimedRead::new(&file, Some(Duration::from_secs(10)))
+            .unwrap()
+            .read_to_string(&mut data)
+            .unwrap();
+        assert_eq!(data, "tents");
+    }
+}
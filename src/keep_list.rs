@@ -0,0 +1,98 @@
+//! Files to keep chosen for particular groups, see `--keep-list`.
+//!
+//! The list is usually produced by an external review tool. Each line holds a group
+//! identifier or a group hash, followed by whitespace and the path of the file that must be kept
+//! in that group, e.g. `2f0c4b1e9a7d3c58 /photos/2019/img_001.jpg`. Paths that are not valid
+//! Unicode can be given as `file://` URIs. Empty lines and lines starting with `#` are ignored.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, ErrorKind};
+
+use crate::path::Path;
+use crate::report::parse_path;
+use crate::FileGroup;
+
+/// Paths of the files to keep, by group identifier or group hash
+#[derive(Debug, Default)]
+pub struct KeepList {
+    files: HashMap<String, Path>,
+}
+
+impl KeepList {
+    /// Reads the list from a file
+    pub fn read(list: &std::path::Path) -> io::Result<KeepList> {
+        Self::parse(BufReader::new(File::open(list)?))
+    }
+
+    fn parse(input: impl BufRead) -> io::Result<KeepList> {
+        let mut files = HashMap::new();
+        for (i, line) in input.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, path) = match line.find(char::is_whitespace) {
+                Some(pos) => (&line[..pos], line[pos..].trim_start()),
+                None => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Line {}: group and path expected: {}", i + 1, line),
+                    ))
+                }
+            };
+            files.insert(key.to_lowercase(), parse_path(path)?);
+        }
+        Ok(KeepList { files })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.files.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.len()
+    }
+
+    /// Returns the file to keep in the group, looked up by the group identifier
+    /// and then by the group hash
+    pub fn get<P>(&self, group: &FileGroup<P>) -> Option<&Path> {
+        self.files
+            .get(&group.id())
+            .or_else(|| self.files.get(&group.file_hash.to_string().to_lowercase()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::files::{FileHash, FileLen};
+
+    use super::*;
+
+    #[test]
+    fn find_file_by_group_id_or_hash() {
+        let group = |hash: u128| {
+            FileGroup::new(
+                FileLen(100),
+                FileHash::from(hash),
+                vec![Path::from("/a"), Path::from("/b")],
+            )
+        };
+        let g1 = group(1);
+        let g2 = group(2);
+        let input = format!(
+            "# reviewed\n{} /b\n\n{}   /dir with spaces/a\n",
+            g1.id(),
+            g2.file_hash
+        );
+        let list = KeepList::parse(input.as_bytes()).unwrap();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list.get(&g1), Some(&Path::from("/b")));
+        assert_eq!(list.get(&g2), Some(&Path::from("/dir with spaces/a")));
+        assert_eq!(list.get(&group(3)), None);
+        assert!(KeepList::parse("abc\n".as_bytes()).is_err());
+    }
+}
@@ -1,62 +1,127 @@
+#![cfg_attr(test, allow(clippy::field_reassign_with_default))]
+
 use core::fmt;
+use std::borrow::Borrow;
 use std::cell::RefCell;
-use std::cmp::{max, Reverse};
-use std::collections::HashMap;
+use std::cmp::{max, min};
+use std::collections::{HashMap, HashSet};
 use std::env::{args, current_dir};
 use std::ffi::{OsStr, OsString};
 use std::fmt::{Display, Formatter};
+use std::fs;
 use std::fs::File;
 use std::io;
 use std::io::BufWriter;
-use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::mpsc::{channel, Receiver, Sender};
-use std::sync::Arc;
-
-use chrono::{DateTime, Local};
+use std::iter;
+use std::mem;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use std::vec;
+
+use chrono::{DateTime, FixedOffset, Local};
 use console::Term;
 use crossbeam_utils::thread;
+use dashmap::DashMap;
 use itertools::Itertools;
 use rayon::prelude::*;
+use rayon::ThreadPool;
+use serde::ser::SerializeStruct;
 use serde::*;
 use sysinfo::DiskType;
 use thread_local::ThreadLocal;
 
-pub use dedupe::{dedupe, log_script, run_script, DedupeOp, DedupeResult};
+#[cfg(feature = "async")]
+pub use async_api::{dedupe_async, group_files_async};
+pub use audit::AuditLog;
+pub use dedupe::{dedupe, log_script, run_script, DedupeOp, DedupeResult, RunOptions};
+pub use refilter::{refilter, write_refiltered_report};
 
+use crate::annotation::Annotations;
+use crate::baseline::Baseline;
 use crate::config::*;
 use crate::device::{DiskDevice, DiskDevices};
 use crate::files::FileInfo;
 use crate::files::*;
 use crate::group::*;
-use crate::log::Log;
+use crate::ignore_file::IgnoreFile;
+use crate::log::{FileOp, Log};
 use crate::path::Path;
-use crate::report::{FileStats, ReportHeader, ReportWriter};
+use crate::progress::{FastProgressBar, StageProgress};
+use crate::report::{
+    copies_histogram, ext_stats, owner_stats, root_stats, size_histogram, DeviceMetadata,
+    FileStats, GroupingHash, HostMetadata, LinkStats, ReportHeader, ReportTheme, ReportWriter,
+    RootFileSystem,
+};
 use crate::selector::PathSelector;
 use crate::semaphore::Semaphore;
+use crate::summary::GroupSummary;
 use crate::transform::Transform;
-use crate::walk::Walk;
+use crate::walk::{FileStat, Walk};
 
+pub mod annotation;
+#[cfg(feature = "async")]
+pub mod async_api;
+pub mod bench;
 pub mod config;
+pub mod contains;
+#[cfg(unix)]
+pub mod daemon;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 pub mod files;
+pub mod find_copies;
+pub mod fs_info;
+pub mod hash_backend;
+pub mod helper;
+pub mod ignore_file;
+pub mod keep_list;
 pub mod log;
+pub mod notify;
 pub mod path;
+pub mod plan;
 pub mod progress;
+pub mod replicate;
 pub mod report;
+pub mod summary;
+pub mod syslog;
+pub mod systemd;
+pub mod window;
 
+mod audit;
+mod baseline;
 mod dedupe;
 mod device;
+mod fd;
 mod group;
+mod hash_pool;
+mod io_timeout;
 mod lock;
+mod owner;
 mod pattern;
+mod refilter;
 mod regex;
 mod selector;
 mod semaphore;
+#[cfg(unix)]
+mod sigbus;
+mod snapshot;
 mod transform;
+mod trash;
 mod util;
 mod walk;
+mod xattr;
 
 const TIMESTAMP_FMT: &str = "%Y-%m-%d %H:%M:%S.%3f %z";
 
+/// Files shorter than this are hashed in batches instead of in separate tasks
+const SMALL_FILE_LEN: FileLen = FileLen(4096);
+
+/// Maximum number of small files hashed by a single task of a batch
+const SMALL_FILE_BATCH_SIZE: usize = 256;
+
 /// Error reported by top-level fclones functions
 #[derive(Debug)]
 pub struct Error {
@@ -96,23 +161,117 @@ struct AppCtx<'a> {
     devices: DiskDevices,
     transform: Option<Transform>,
     path_selector: PathSelector,
+    roots: Vec<Path>,
+    /// Canonical paths of the files given by `--containing`
+    containing: Vec<Path>,
+    baseline: Option<Baseline>,
+    /// Files listed by `--quarantine`, left out of the scan
+    quarantined: HashSet<Path>,
+    counters: Counters,
+    progress: Arc<StageProgress>,
+}
+
+/// Counters updated concurrently while processing files, used to build the `GroupSummary`
+#[derive(Default)]
+struct Counters {
+    scanned_entries: AtomicUsize,
+    scanned_files: AtomicUsize,
+    skipped_hidden: AtomicUsize,
+    skipped_by_name: AtomicUsize,
+    skipped_dirs: AtomicUsize,
+    skipped_by_size: AtomicUsize,
+    skipped_by_time: AtomicUsize,
+    skipped_special_files: AtomicUsize,
+    skipped_file_links: AtomicUsize,
+    skipped_quarantined: AtomicUsize,
+    bytes_hashed: AtomicU64,
 }
 
 impl<'a> AppCtx<'a> {
+    /// Returns true if the caller requested to stop grouping with [`GroupConfig::cancel`]
+    fn cancelled(&self) -> bool {
+        let cancel = self.config.cancel.as_ref();
+        cancel.map_or(false, |c| c.load(Ordering::Relaxed))
+    }
+
+    /// Blocks until the run window given by [`GroupConfig::run_window`] is open,
+    /// or until grouping is cancelled.
+    /// Must not be called while holding a file open.
+    fn wait_for_window(&self) {
+        window::wait(self.config.run_window, || self.cancelled())
+    }
+
+    fn check_cancelled(&self) -> Result<(), Error> {
+        if self.cancelled() {
+            Err(Error::from("Cancelled"))
+        } else {
+            Ok(())
+        }
+    }
+
     pub fn new(config: &'a GroupConfig, log: &'a Log) -> Result<AppCtx<'a>, Error> {
         let thread_pool_sizes = config.thread_pool_sizes();
-        let devices = DiskDevices::new(&thread_pool_sizes);
+        let mut devices = DiskDevices::new(&thread_pool_sizes);
+        Self::check_buffer_size_config(&config.buffer_sizes(), &devices)?;
+        devices.set_buffer_sizes(&config.buffer_sizes());
         let transform = match config.transform() {
             None => None,
             Some(Ok(transform)) => Some(transform),
             Some(Err(e)) => return Err(Error::new(format!("Invalid transform: {}", e))),
         };
         let base_dir = Path::from(current_dir().unwrap_or_default());
+        let ignored = Self::ignored_patterns(config, log)?;
         let selector = config
-            .path_selector(&base_dir)
+            .path_selector(&base_dir, &ignored)
             .map_err(|e| format!("Invalid pattern: {}", e))?;
 
         Self::check_pool_config(thread_pool_sizes, &devices)?;
+        let base_dir = base_dir.share();
+        let roots = if config.stdin {
+            vec![]
+        } else {
+            Self::input_roots(config, &base_dir, log)?
+        };
+        if transform.is_some() && !config.extra_hash_fns().is_empty() {
+            return Err(Error::from(
+                "--hash-fn with hash functions other than metro can't be used with --transform",
+            ));
+        }
+        if let (Some(rf_over), Some(rf_under)) = (config.rf_over, config.rf_under) {
+            if rf_under <= rf_over + 1 {
+                return Err(Error::from(format!(
+                    "No file can have more than {} and fewer than {} copies. \
+                     Check --rf-over and --rf-under",
+                    rf_over, rf_under
+                )));
+            }
+        }
+        if config.compare && roots.len() != 2 {
+            return Err(Error::from(
+                "--compare requires exactly two input paths, none contained in the other",
+            ));
+        }
+        let baseline = match &config.baseline {
+            Some(path) => Some(Baseline::load(path, &base_dir, config.normalize_unicode)?),
+            None => None,
+        };
+        let containing = config
+            .containing
+            .iter()
+            .map(|p| base_dir.resolve(Path::from(p)).canonicalize())
+            .collect();
+
+        let quarantined = match &config.quarantine {
+            Some(list) => io_timeout::read_quarantine(list)
+                .map_err(|e| format!("Failed to read quarantine list {}: {}", list.display(), e))?,
+            None => HashSet::new(),
+        };
+        if !quarantined.is_empty() {
+            log.info(format!(
+                "Skipping {} files listed in the quarantine list",
+                quarantined.len()
+            ));
+        }
 
         Ok(AppCtx {
             config,
@@ -120,9 +279,151 @@ impl<'a> AppCtx<'a> {
             devices,
             transform,
             path_selector: selector,
+            roots,
+            containing,
+            baseline,
+            quarantined,
+            counters: Counters::default(),
+            progress: config.progress.clone().unwrap_or_default(),
         })
     }
 
+    /// Reads the patterns of the ignore file, unless disabled with `--no-ignore-file`.
+    /// The default ignore file is optional, but the one given with `--ignore-file` must exist.
+    fn ignored_patterns(config: &GroupConfig, log: &Log) -> Result<IgnoreFile, Error> {
+        if config.no_ignore_file {
+            return Ok(IgnoreFile::default());
+        }
+        let path = match config.ignore_file.clone().or_else(IgnoreFile::default_path) {
+            Some(path) => path,
+            None => return Ok(IgnoreFile::default()),
+        };
+        let ignore = match IgnoreFile::read(&path) {
+            Ok(ignore) => ignore,
+            Err(e) if e.kind() == io::ErrorKind::NotFound && config.ignore_file.is_none() => {
+                return Ok(IgnoreFile::default())
+            }
+            Err(e) => {
+                return Err(Error::new(format!(
+                    "Failed to read ignore file {}: {}",
+                    path.display(),
+                    e
+                )))
+            }
+        };
+        if !ignore.is_empty() {
+            log.info(format!(
+                "Excluding {} patterns of ignore file {}",
+                ignore.len(),
+                path.display()
+            ));
+        }
+        Ok(ignore)
+    }
+
+    /// Returns absolute input paths given as the arguments.
+    /// Paths contained in other input paths are removed or reported as an error,
+    /// depending on the overlap policy.
+    /// Overlaps are detected on the canonical paths, but the paths are returned
+    /// as given by the user, so the reported files stay under the same roots,
+    /// even if they are reached through symbolic links.
+    fn input_roots(
+        config: &GroupConfig,
+        base_dir: &Arc<Path>,
+        log: &Log,
+    ) -> Result<Vec<Path>, Error> {
+        let roots: Vec<(Path, Path)> = config
+            .paths
+            .iter()
+            .map(|p| {
+                let root = input_root(config, base_dir, p);
+                let canonical = root.canonicalize();
+                (root, canonical)
+            })
+            .collect();
+        let mut result = Vec::with_capacity(roots.len());
+        for (root, canonical) in roots.iter() {
+            let outer = roots
+                .iter()
+                .find(|(_, c)| c != canonical && c.is_prefix_of(canonical))
+                .map(|(r, _)| r);
+            match (outer, config.overlapping_roots) {
+                (Some(outer), OverlapPolicy::Error) => {
+                    return Err(Error::new(format!(
+                        "Input path {} is contained in {}",
+                        root, outer
+                    )))
+                }
+                // With limited depth, the outer path may not reach all files of the nested path,
+                // so we must scan both. The same files would be merged later by path.
+                (Some(outer), OverlapPolicy::Merge) if config.depth.is_none() => log.info(format!(
+                    "Skipping {} because it is contained in {}",
+                    root, outer
+                )),
+                _ => result.push(root.clone()),
+            }
+        }
+        Ok(result)
+    }
+
+    /// Starts a hashing stage which is going to read the given numbers of bytes from each device,
+    /// indexed by device, as returned by [`bytes_per_device`].
+    /// Returns a progress bar displaying the number of bytes hashed so far, and if more than
+    /// one device is read, how far each device got. It should be updated with `inc_device`.
+    /// The stage progress is updated by `count_hashed_bytes`.
+    fn hashing_progress_bar(
+        &self,
+        stage: &'static str,
+        msg: &str,
+        device_bytes: Vec<u64>,
+    ) -> Arc<FastProgressBar> {
+        let bytes = device_bytes.iter().sum();
+        self.progress.start(stage, bytes);
+        systemd::set_total(bytes);
+        if device_bytes.iter().filter(|&&b| b > 0).count() > 1 {
+            let devices = device_bytes
+                .into_iter()
+                .enumerate()
+                .map(|(i, b)| (self.devices[i].short_name(), b))
+                .collect();
+            self.log.device_bytes_progress_bar(msg, bytes, devices)
+        } else {
+            self.log.bytes_progress_bar(msg, bytes)
+        }
+    }
+
+    /// Adds the number of bytes to the counter of hashed data
+    fn count_hashed_bytes(&self, delta: usize) {
+        systemd::tick(delta as u64);
+        self.progress.inc(delta as u64);
+        self.counters
+            .bytes_hashed
+            .fetch_add(delta as u64, Ordering::Relaxed);
+    }
+
+    /// Checks if all buffer size names refer to existing devices or device types
+    fn check_buffer_size_config(
+        buffer_sizes: &HashMap<OsString, FileLen>,
+        devices: &DiskDevices,
+    ) -> Result<(), Error> {
+        let mut allowed_names = DiskDevices::device_types();
+        allowed_names.push("network");
+        allowed_names.push("default");
+        for name in buffer_sizes.keys() {
+            let name = name.to_string_lossy();
+            match name.strip_prefix("dev:") {
+                Some(name) if devices.get_by_name(OsStr::new(name)).is_none() => {
+                    return Err(Error::new(format!("Unknown device: {}", name)));
+                }
+                None if !allowed_names.contains(&name.as_ref()) => {
+                    return Err(Error::new(format!("Unknown device type: {}", name)));
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
     /// Checks if all thread pool names refer to existing pools or devices
     fn check_pool_config(
         thread_pool_sizes: HashMap<OsString, Parallelism>,
@@ -151,14 +452,95 @@ impl<'a> AppCtx<'a> {
 }
 
 /// A group of files that have something in common, e.g. same size or same hash
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct FileGroup<F> {
     /// Length of each file
     pub file_len: FileLen,
     /// Hash of a part or the whole of the file
     pub file_hash: FileHash,
+    /// Hashes of the whole contents of the files computed with the hash functions selected
+    /// by `--hash-fn` other than the one used for `file_hash`
+    #[serde(default)]
+    pub hashes: Digests,
     /// Group of files with the same length and hash
     pub files: Vec<F>,
+    /// Number of files the group had before it was truncated to the first files
+    /// by `--max-group-files`, or `None` if all the files are listed
+    #[serde(default)]
+    pub truncated_from: Option<usize>,
+    /// Notes added to the group of a JSON report by its reviewers, see [`annotation`]
+    #[serde(default)]
+    pub annotations: Annotations,
+}
+
+/// Serializes the group together with its identifier
+impl<F: Serialize> Serialize for FileGroup<F> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("FileGroup", 7)?;
+        s.serialize_field("id", &self.id())?;
+        s.serialize_field("file_len", &self.file_len)?;
+        s.serialize_field("file_hash", &self.file_hash)?;
+        if self.hashes.is_empty() {
+            s.skip_field("hashes")?;
+        } else {
+            s.serialize_field("hashes", &self.hashes)?;
+        }
+        s.serialize_field("files", &self.files)?;
+        match self.truncated_from {
+            Some(count) => s.serialize_field("truncated_from", &count)?,
+            None => s.skip_field("truncated_from")?,
+        }
+        if self.annotations.is_empty() {
+            s.skip_field("annotations")?;
+        } else {
+            s.serialize_field("annotations", &self.annotations)?;
+        }
+        s.end()
+    }
+}
+
+impl<F> FileGroup<F> {
+    /// Creates a group of files with the given length and hash,
+    /// with no other hashes and no annotations
+    pub fn new(file_len: FileLen, file_hash: FileHash, files: Vec<F>) -> FileGroup<F> {
+        FileGroup {
+            file_len,
+            file_hash,
+            hashes: Digests::new(),
+            files,
+            truncated_from: None,
+            annotations: Annotations::new(),
+        }
+    }
+
+    /// Returns the identifier of the group, derived from the file length and the hash.
+    /// See [`group_id`](files::group_id).
+    pub fn id(&self) -> String {
+        files::group_id(self.file_len, &self.file_hash)
+    }
+
+    /// Returns the number of files in the group,
+    /// including the files left out when the group was truncated
+    pub fn file_count(&self) -> usize {
+        self.truncated_from.unwrap_or(self.files.len())
+    }
+
+    /// Leaves only the first `max_files` files in the group,
+    /// remembering how many files the group had
+    pub fn truncate(&mut self, max_files: usize) {
+        if self.files.len() > max_files {
+            self.truncated_from = Some(self.file_count());
+            self.files.truncate(max_files);
+        }
+    }
+
+    /// Returns true if the group hash or any of the hashes computed with other hash functions
+    /// is equal to one of the given hashes
+    pub fn has_hash(&self, hashes: &[FileHash]) -> bool {
+        hashes
+            .iter()
+            .any(|h| &self.file_hash == h || self.hashes.values().any(|v| v == h))
+    }
 }
 
 /// Computes summaries of the results obtained from each grouping stage.
@@ -182,26 +564,26 @@ where
     F: 'a,
 {
     fn total_count(self) -> usize {
-        self.into_iter().map(|g| g.files.len()).sum()
+        self.into_iter().map(|g| g.file_count()).sum()
     }
 
     fn total_size(self) -> FileLen {
         self.into_iter()
-            .map(|g| g.file_len * g.files.len() as u64)
+            .map(|g| g.file_len * g.file_count() as u64)
             .sum()
     }
 
     fn selected_count(self, rf_over: usize, rf_under: usize) -> usize {
         self.into_iter()
-            .filter(|&g| g.files.len() < rf_under)
-            .map(|g| g.files.len().saturating_sub(rf_over))
+            .filter(|&g| g.file_count() < rf_under)
+            .map(|g| g.file_count().saturating_sub(rf_over))
             .sum()
     }
 
     fn selected_size(self, rf_over: usize, rf_under: usize) -> FileLen {
         self.into_iter()
-            .filter(|&g| g.files.len() < rf_under)
-            .map(|g| g.file_len * g.files.len().saturating_sub(rf_over) as u64)
+            .filter(|&g| g.file_count() < rf_under)
+            .map(|g| g.file_len * g.file_count().saturating_sub(rf_over) as u64)
             .sum()
     }
 }
@@ -214,6 +596,19 @@ struct HashedFileInfo {
     file_info: FileInfo,
 }
 
+/// Files shared by the tasks hashing them, each task taking the next file
+/// when done with the previous one
+#[derive(Clone)]
+struct SharedQueue(Arc<Mutex<vec::IntoIter<HashedFileInfo>>>);
+
+impl Iterator for SharedQueue {
+    type Item = HashedFileInfo;
+
+    fn next(&mut self) -> Option<HashedFileInfo> {
+        self.0.lock().unwrap().next()
+    }
+}
+
 /// Partitions files into separate vectors, where each vector holds files persisted
 /// on the same disk device. The vectors are returned in the same order as devices.
 fn partition_by_devices(
@@ -228,7 +623,7 @@ fn partition_by_devices(
         for f in g.files {
             let device = &devices[f.get_device_index()];
             result[device.index].push(HashedFileInfo {
-                file_hash: g.file_hash,
+                file_hash: g.file_hash.clone(),
                 file_info: f,
             });
         }
@@ -236,6 +631,16 @@ fn partition_by_devices(
     result
 }
 
+/// Sums the numbers of bytes to read from each device, given as pairs of
+/// device index and byte count. Returns the sums indexed by device.
+fn bytes_per_device(devices: &DiskDevices, files: impl Iterator<Item = (usize, u64)>) -> Vec<u64> {
+    let mut bytes = vec![0; devices.len()];
+    for (device, len) in files {
+        bytes[device] += len;
+    }
+    bytes
+}
+
 /// Iterates over grouped files, in parallel
 fn flat_iter(files: &[FileGroup<FileInfo>]) -> impl ParallelIterator<Item = &FileInfo> {
     files.par_iter().flat_map(|g| &g.files)
@@ -257,6 +662,10 @@ enum AccessType {
 /// were in the different groups to end up in the same group if they have the same length
 /// and they hash to the same value. If you don't want this, you need to combine the old
 /// hash with the new hash in the provided `hash_fn`.
+///
+/// If `sink` is given, a group is passed to it as soon as all files of its length have been
+/// hashed, instead of being returned at the end. This requires `hash_fn` to not change the
+/// lengths of the files.
 fn rehash<'a, F1, F2, H>(
     groups: Vec<FileGroup<FileInfo>>,
     group_pre_filter: F1,
@@ -264,6 +673,7 @@ fn rehash<'a, F1, F2, H>(
     devices: &DiskDevices,
     access_type: AccessType,
     hash_fn: H,
+    mut sink: Option<&mut dyn FnMut(FileGroup<FileInfo>)>,
 ) -> Vec<FileGroup<FileInfo>>
 where
     F1: Fn(&FileGroup<FileInfo>) -> bool,
@@ -274,19 +684,32 @@ where
     type HashFn<'a> = dyn Fn((&mut FileInfo, FileHash)) -> Option<FileHash> + Sync + Send + 'a;
     let hash_fn: &HashFn<'a> = &hash_fn;
 
-    let (tx, rx): (Sender<HashedFileInfo>, Receiver<HashedFileInfo>) = channel();
+    // Each hashed file is sent together with its length, so the receiver can tell when all
+    // files of the given length are done. Files that couldn't be hashed are sent as `None`.
+    type Hashed = (FileLen, Option<HashedFileInfo>);
+    let (tx, rx): (Sender<Hashed>, Receiver<Hashed>) = channel();
 
     // There is no point in fclonesing groups containing a single file.
     // Normally when searching for duplicates such groups are filtered out automatically after
     // each stage, however they are possible when searching for unique files.
-    let (groups_to_fclones, groups_to_pass): (Vec<_>, Vec<_>) =
+    let (groups_to_fclones, mut groups_to_pass): (Vec<_>, Vec<_>) =
         groups.into_iter().partition(group_pre_filter);
 
+    // Count the files of each length, so we know when a group can't receive any more files:
+    let mut pending: HashMap<FileLen, usize> = HashMap::new();
+    if let Some(sink) = sink.as_mut() {
+        for f in groups_to_fclones.iter().flat_map(|g| g.files.iter()) {
+            *pending.entry(f.len).or_default() += 1;
+        }
+        groups_to_pass.drain(..).for_each(sink);
+    }
+
     // This way we can split fclonesing to separate thread-pools, one per device:
     let files = partition_by_devices(groups_to_fclones, devices);
     let mut hash_map =
         GroupMap::new(|f: HashedFileInfo| ((f.file_info.len, f.file_hash), f.file_info));
     let hash_map_ref = &mut hash_map;
+    let group_post_filter = &group_post_filter;
 
     // Scope needed so threads can access shared stuff like groups or shared functions.
     // The threads we launch are guaranteed to not live longer than this scope.
@@ -324,30 +747,66 @@ where
                 // when fclonesing 1M of files.
                 let semaphore = Arc::new(Semaphore::new(8 * thread_count));
 
-                // Run hashing on the thread-pool dedicated to the device
-                for mut f in files {
-                    let tx = tx.clone();
-                    let guard = semaphore.clone().access_owned();
-
-                    // Spawning a task into a thread-pool requires a static lifetime,
-                    // because generally the task could outlive caller's stack frame.
-                    // However, this is not the case for rehash function, because
-                    // we don't exit before all tasks are fclonesed.
-                    // In the perfect world we should use scopes for that. Unfortunately
-                    // the current implementation of rayon scopes runs the scope body
-                    // on one of the thread-pool worker threads, so it is not possible
-                    // to safely block inside the scope, because that leads to deadlock
-                    // when the pool has only one thread.
-                    let hash_fn: &HashFn<'static> = unsafe { std::mem::transmute(hash_fn) };
-                    thread_pool.spawn_fifo(move || {
-                        if let Some(hash) = hash_fn((&mut f.file_info, f.file_hash)) {
-                            f.file_hash = hash;
-                            tx.send(f).unwrap();
+                // Run hashing of the given files in a single task
+                let spawn =
+                    |pool: &ThreadPool, task: Box<dyn Iterator<Item = HashedFileInfo> + Send>| {
+                        let tx = tx.clone();
+                        let guard = semaphore.clone().access_owned();
+
+                        // Spawning a task into a thread-pool requires a static lifetime,
+                        // because generally the task could outlive caller's stack frame.
+                        // However, this is not the case for rehash function, because
+                        // we don't exit before all tasks are fclonesed.
+                        // In the perfect world we should use scopes for that. Unfortunately
+                        // the current implementation of rayon scopes runs the scope body
+                        // on one of the thread-pool worker threads, so it is not possible
+                        // to safely block inside the scope, because that leads to deadlock
+                        // when the pool has only one thread.
+                        let hash_fn: &HashFn<'static> = unsafe { std::mem::transmute(hash_fn) };
+                        pool.spawn_fifo(move || {
+                            for mut f in task {
+                                let len = f.file_info.len;
+                                let hashed =
+                                    hash_fn((&mut f.file_info, mem::take(&mut f.file_hash))).map(
+                                        |hash| {
+                                            f.file_hash = hash;
+                                            f
+                                        },
+                                    );
+                                tx.send((len, hashed)).unwrap();
+                            }
+                            // This forces moving the guard into this task and be released when
+                            // the task is done
+                            drop(guard);
+                        });
+                    };
+
+                // Run hashing on the thread-pool dedicated to the device.
+                // Hashing a tiny file takes less time than scheduling a task for it,
+                // so tiny files are hashed in batches on the sequential pool.
+                // The tasks of a batch take the files one by one from a shared queue,
+                // so the threads of the pool keep reading files located close to each other.
+                let seq_pool = device.seq_thread_pool();
+                let task_count = seq_pool.current_num_threads().max(1);
+                let spawn_batch = |batch: Vec<HashedFileInfo>| {
+                    let queue = SharedQueue(Arc::new(Mutex::new(batch.into_iter())));
+                    for _ in 0..task_count {
+                        spawn(seq_pool, Box::new(queue.clone()));
+                    }
+                };
+                let mut batch = Vec::new();
+                for f in files {
+                    if f.file_info.len < SMALL_FILE_LEN {
+                        batch.push(f);
+                        if batch.len() == SMALL_FILE_BATCH_SIZE * task_count {
+                            spawn_batch(mem::take(&mut batch));
                         }
-                        // This forces moving the guard into this task and be released when
-                        // the task is done
-                        drop(guard);
-                    });
+                    } else {
+                        spawn(thread_pool, Box::new(iter::once(f)));
+                    }
+                }
+                if !batch.is_empty() {
+                    spawn_batch(batch);
                 }
             });
         }
@@ -357,8 +816,24 @@ where
 
         // Collect the results from all threads and group them.
         // Note that this will happen as soon as data are available
-        while let Ok(hashed_file) = rx.recv() {
-            hash_map_ref.add(hashed_file);
+        while let Ok((len, hashed_file)) = rx.recv() {
+            if let Some(hashed_file) = hashed_file {
+                hash_map_ref.add(hashed_file);
+            }
+            if let Some(sink) = sink.as_mut() {
+                let remaining = pending.get_mut(&len).unwrap();
+                *remaining -= 1;
+                if *remaining == 0 {
+                    // Hashes of any width sort after the empty one
+                    let range = (len, FileHash::default())..(len + FileLen(1), FileHash::default());
+                    for ((len, hash), files) in hash_map_ref.remove_range(range) {
+                        let group = FileGroup::new(len, hash, files.to_vec());
+                        if group_post_filter(&group) {
+                            sink(group);
+                        }
+                    }
+                }
+            }
         }
     })
     .unwrap();
@@ -366,39 +841,160 @@ where
     // Convert the hashmap into vector, leaving only large-enough groups:
     hash_map
         .into_iter()
-        .map(|((len, hash), files)| FileGroup {
-            file_len: len,
-            file_hash: hash,
-            files: files.to_vec(),
-        })
-        .filter(group_post_filter)
-        .chain(groups_to_pass.into_iter())
+        .map(|((len, hash), files)| FileGroup::new(len, hash, files.to_vec()))
+        .filter(|g| group_post_filter(g))
+        .chain(groups_to_pass)
         .collect()
 }
 
+/// Returns true if the modification and creation time of the file fall within
+/// the ranges set in the configuration.
+/// If the timestamps can't be read, logs a warning and returns false.
+fn matches_time_filters(ctx: &AppCtx<'_>, path: &Path) -> bool {
+    let config = &ctx.config;
+    let metadata = match io_timeout::metadata(path, config.io_timeout) {
+        Ok(m) => m,
+        Err(e) => {
+            let msg = format!("Failed to read metadata of {}: {}", path, e);
+            ctx.log.unreadable_file(path, FileOp::Stat, &e, msg);
+            return false;
+        }
+    };
+    let in_range = |time: io::Result<std::time::SystemTime>,
+                    after: Option<DateTime<FixedOffset>>,
+                    before: Option<DateTime<FixedOffset>>,
+                    kind: &str| {
+        if after.is_none() && before.is_none() {
+            return true;
+        }
+        match time {
+            Ok(time) => {
+                let time: DateTime<FixedOffset> = DateTime::<Local>::from(time).into();
+                after.iter().all(|&t| time > t) && before.iter().all(|&t| time < t)
+            }
+            Err(e) => {
+                ctx.log.warn(format!(
+                    "Failed to read {} time of file {}: {}",
+                    kind, path, e
+                ));
+                false
+            }
+        }
+    };
+    in_range(
+        metadata.modified(),
+        config.modified_after,
+        config.modified_before,
+        "modification",
+    ) && in_range(
+        metadata.created(),
+        config.created_after,
+        config.created_before,
+        "creation",
+    )
+}
+
+/// Returns the disk space allocated to a file found by the walk.
+/// Uses the metadata fetched by the walk if available.
+fn scanned_allocated_len(
+    info: &FileInfo,
+    stat: Option<FileStat>,
+    timeout: Option<Duration>,
+) -> FileLen {
+    match stat {
+        #[cfg(unix)]
+        Some(stat) => FileLen(stat.blocks * 512),
+        _ => match io_timeout::symlink_metadata(&info.path, timeout) {
+            Ok(metadata) => allocated_len(&metadata),
+            Err(_) => info.len,
+        },
+    }
+}
+
 /// Walks the directory tree and collects matching files in parallel into a vector
 fn scan_files(ctx: &AppCtx<'_>) -> Vec<Vec<FileInfo>> {
     let file_collector = ThreadLocal::new();
     let spinner = ctx.log.spinner("Scanning files");
-    let spinner_tick = &|_: &Path| spinner.tick();
+    let spinner_tick = &|_: &Path| {
+        spinner.tick();
+        systemd::tick(0);
+    };
 
     let config = &ctx.config;
     let min_size = config.min_size;
     let max_size = config.max_size.unwrap_or(FileLen::MAX);
+    let time_filters = config.has_time_filters();
 
     let mut walk = Walk::new();
     walk.depth = config.depth.unwrap_or(usize::MAX);
     walk.skip_hidden = config.skip_hidden;
+    walk.skip_trash = !config.include_trash;
+    walk.skip_snapshots = !config.include_snapshots;
     walk.follow_links = config.follow_links;
+    walk.report_links = config.report_symlinks || config.file_links == SpecialFilePolicy::Follow;
+    walk.file_links = config.file_links;
+    walk.special_files = config.special_files;
     walk.path_selector = ctx.path_selector.clone();
     walk.log = Some(ctx.log);
+    walk.run_window = config.run_window;
+    walk.io_timeout = config.io_timeout;
     walk.on_visit = spinner_tick;
-    walk.run(ctx.config.input_paths(), |path| {
-        file_info_or_log_err(path, &ctx.devices, ctx.log)
-            .into_iter()
+    let roots = if config.stdin {
+        config.input_paths()
+    } else {
+        Box::new(ctx.roots.clone().into_iter())
+    };
+    walk.run_with_stat(roots, |path, stat| {
+        if ctx.cancelled() {
+            return;
+        }
+        ctx.counters.scanned_files.fetch_add(1, Ordering::Relaxed);
+        let info = match stat {
+            #[cfg(unix)]
+            Some(stat) => {
+                ctx.log.scanned_file_owner(&path, stat.uid);
+                Some(FileInfo::from_stat(path, stat, &ctx.devices))
+            }
+            _ => file_info_or_log_err(path, &ctx.devices, config.io_timeout, ctx.log),
+        };
+        info.into_iter()
             .filter(|info| {
                 let l = info.len;
-                l >= min_size && l <= max_size
+                let matches = if l == FileLen(0) && (config.ignore_empty || config.empty_as_group) {
+                    config.empty_as_group
+                } else {
+                    l >= min_size
+                        && l <= max_size
+                        && SizeRange::any_contains(&config.size_ranges, l)
+                        && (config.allocated_size_ranges.is_empty()
+                            || SizeRange::any_contains(
+                                &config.allocated_size_ranges,
+                                scanned_allocated_len(info, stat, config.io_timeout),
+                            ))
+                };
+                if !matches {
+                    ctx.counters.skipped_by_size.fetch_add(1, Ordering::Relaxed);
+                    ctx.log.filtered_file(&info.path, "Size out of range");
+                }
+                matches
+            })
+            .filter(|info| {
+                let matches = !time_filters || matches_time_filters(ctx, &info.path);
+                if !matches {
+                    ctx.counters.skipped_by_time.fetch_add(1, Ordering::Relaxed);
+                    ctx.log.filtered_file(&info.path, "Time out of range");
+                }
+                matches
+            })
+            .filter(|info| {
+                let quarantined = ctx.quarantined.contains(&info.path);
+                if quarantined {
+                    ctx.counters
+                        .skipped_quarantined
+                        .fetch_add(1, Ordering::Relaxed);
+                    ctx.log.filtered_file(&info.path, "Quarantined");
+                }
+                !quarantined
             })
             .for_each(|info| {
                 let vec = file_collector.get_or(|| RefCell::new(Vec::new()));
@@ -406,8 +1002,23 @@ fn scan_files(ctx: &AppCtx<'_>) -> Vec<Vec<FileInfo>> {
             });
     });
 
+    let scanned_entries = spinner.position();
+    ctx.counters
+        .scanned_entries
+        .store(scanned_entries, Ordering::Relaxed);
+    let counters = &ctx.counters;
+    let walk_counters = [
+        (&walk.skipped_special_files, &counters.skipped_special_files),
+        (&walk.skipped_file_links, &counters.skipped_file_links),
+        (&walk.skipped_hidden, &counters.skipped_hidden),
+        (&walk.skipped_by_name, &counters.skipped_by_name),
+        (&walk.skipped_dirs, &counters.skipped_dirs),
+    ];
+    for (from, to) in walk_counters.iter() {
+        to.store(from.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
     ctx.log
-        .info(format!("Scanned {} file entries", spinner.position()));
+        .info(format!("Scanned {} file entries", scanned_entries));
 
     let files: Vec<_> = file_collector.into_iter().map(|r| r.into_inner()).collect();
 
@@ -421,6 +1032,17 @@ fn scan_files(ctx: &AppCtx<'_>) -> Vec<Vec<FileInfo>> {
     files
 }
 
+/// Sets the hashes of the group of empty files to the hashes of empty contents,
+/// so the group doesn't need to go through the hashing stages
+fn empty_file_group(config: &GroupConfig, mut group: FileGroup<FileInfo>) -> FileGroup<FileInfo> {
+    let hashes = config.extra_hashes();
+    let (_, hash, digests) = stream_hashes(&mut io::empty(), FileLen(0), 1, &hashes, |_| {})
+        .expect("Reading empty contents must not fail");
+    group.file_hash = hash;
+    group.hashes = digests;
+    group
+}
+
 fn group_by_size(ctx: &AppCtx<'_>, files: Vec<Vec<FileInfo>>) -> Vec<FileGroup<FileInfo>> {
     let file_count: usize = files.iter().map(|v| v.len()).sum();
     let progress = ctx.log.progress_bar("Grouping by size", file_count as u64);
@@ -438,11 +1060,7 @@ fn group_by_size(ctx: &AppCtx<'_>, files: Vec<Vec<FileInfo>>) -> Vec<FileGroup<F
     let groups: Vec<_> = groups
         .into_iter()
         .filter(|(_, files)| files.len() > rf_over)
-        .map(|(l, files)| FileGroup {
-            file_len: l,
-            file_hash: FileHash(0),
-            files: files.into_vec(),
-        })
+        .map(|(l, files)| FileGroup::new(l, FileHash::from(0), files.into_vec()))
         .collect();
 
     let count: usize = groups.selected_count(rf_over, rf_under);
@@ -456,6 +1074,8 @@ fn group_by_size(ctx: &AppCtx<'_>, files: Vec<Vec<FileInfo>>) -> Vec<FileGroup<F
 
 /// Removes duplicate files matching by full-path or by inode-id.
 /// Deduplication by inode-id is not performed if the flag to preserve hard-links (-H) is set.
+/// In that case, on case-insensitive file systems, paths differing only in letter case
+/// and pointing to the same inode are still considered duplicates.
 fn deduplicate<F>(ctx: &AppCtx<'_>, files: &mut Vec<FileInfo>, progress: F)
 where
     F: Fn(&Path) + Sync + Send,
@@ -468,6 +1088,18 @@ where
     for (_, file_group) in groups.into_iter() {
         if file_group.len() == 1 {
             files.extend(file_group.into_iter().inspect(|p| progress(&p.path)));
+        } else if ctx.config.hard_links && ctx.config.ignore_case() {
+            // Paths differing only in letter case are the same file only
+            // if they also share the file identifier:
+            files.extend(
+                file_group
+                    .into_iter()
+                    .inspect(|p| progress(&p.path))
+                    .unique_by(|p| {
+                        let id = file_id_or_log_err(&p.path, ctx.log);
+                        (p.path.hash128_ignore_case(), id)
+                    }),
+            )
         } else if ctx.config.hard_links {
             files.extend(
                 file_group
@@ -480,12 +1112,25 @@ where
                 file_group
                     .into_iter()
                     .inspect(|p| progress(&p.path))
-                    .unique_by(|p| file_id_or_log_err(&p.path, ctx.log)),
+                    .unique_by(|p| {
+                        // A reported symbolic link is an entry of its own,
+                        // not another path to its target:
+                        if ctx.config.report_symlinks && is_symlink(&p.path) {
+                            Err(p.path.hash128())
+                        } else {
+                            Ok(file_id_or_log_err(&p.path, ctx.log))
+                        }
+                    }),
             )
         }
     }
 }
 
+/// Returns true if the path itself is a symbolic link
+fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path.to_path_buf()).map_or(false, |m| m.file_type().is_symlink())
+}
+
 fn remove_same_files(
     ctx: &AppCtx<'_>,
     groups: Vec<FileGroup<FileInfo>>,
@@ -585,6 +1230,10 @@ fn group_transformed(
         &ctx.devices,
         AccessType::Sequential,
         |(fi, _)| {
+            ctx.wait_for_window();
+            if ctx.cancelled() {
+                return None;
+            }
             let result = transform
                 .run_or_log_err(&fi.path, ctx.log)
                 .map(|(len, hash)| {
@@ -594,6 +1243,7 @@ fn group_transformed(
             progress.tick();
             result
         },
+        None,
     );
 
     let count = groups.selected_count(rf_over, rf_under);
@@ -632,19 +1282,38 @@ fn prefix_len<'a>(
 }
 
 /// Groups files by a hash of their first few thousand bytes.
+/// Files not longer than `prefix_len` are hashed whole, so their hash is the same as the
+/// hash of their full contents. The hashes by the extra hash functions of such files
+/// are computed in the same pass and stored in `digests`.
 fn group_by_prefix(
     ctx: &AppCtx<'_>,
     prefix_len: FileLen,
     groups: Vec<FileGroup<FileInfo>>,
+    digests: &DashMap<Path, Digests>,
 ) -> Vec<FileGroup<FileInfo>> {
     let pre_filter = |g: &FileGroup<FileInfo>| g.files.len() > 1;
-    let remaining_files = groups.iter().filter(|&g| pre_filter(g)).total_count();
-    let progress = ctx
-        .log
-        .progress_bar("Grouping by prefix", remaining_files as u64);
+    let fingerprint = ctx.config.fingerprint_filter;
+    // Long files are hashed only up to the minimum prefix length of their device,
+    // or fingerprinted, see the hash function below
+    let bytes_to_scan = groups
+        .iter()
+        .filter(|&g| pre_filter(g))
+        .flat_map(|g| g.files.iter())
+        .map(|fi| {
+            let device = fi.get_device_index();
+            let len = match fi.len {
+                len if len <= prefix_len => len.0,
+                len if fingerprint => min(len.0, 2 * FINGERPRINT_LEN),
+                len => min(len, ctx.devices[device].min_prefix_len()).0,
+            };
+            (device, len)
+        });
+    let bytes_to_scan = bytes_per_device(&ctx.devices, bytes_to_scan);
+    let progress = ctx.hashing_progress_bar("prefix", "Grouping by prefix", bytes_to_scan);
 
     let rf_over = ctx.config.rf_over();
     let rf_under = ctx.config.rf_under();
+    let hash_fns = ctx.config.extra_hashes();
 
     let groups = rehash(
         groups,
@@ -653,9 +1322,39 @@ fn group_by_prefix(
         &ctx.devices,
         AccessType::Random,
         |(fi, _)| {
-            progress.tick();
+            ctx.wait_for_window();
+            if ctx.cancelled() {
+                return None;
+            }
             let device = &ctx.devices[fi.get_device_index()];
             let buf_len = device.buf_len();
+            let progress = |delta| {
+                ctx.count_hashed_bytes(delta);
+                progress.inc_device(fi.get_device_index(), delta)
+            };
+            if fi.len <= prefix_len && !hash_fns.is_empty() {
+                let hashes = file_hashes_or_log_err(
+                    &fi.path,
+                    fi.len,
+                    buf_len,
+                    Caching::Default.with_timeout(ctx.config.io_timeout),
+                    &hash_fns,
+                    progress,
+                    ctx.log,
+                );
+                return hashes.map(|(hash, file_digests)| {
+                    digests.insert(fi.path.clone(), file_digests);
+                    hash
+                });
+            }
+            // The fingerprint covers the prefix, so it separates at least the same files,
+            // and also the files differing only at the end
+            if fi.len > prefix_len && fingerprint {
+                let hash =
+                    file_fingerprint_or_log_err(&fi.path, fi.len, ctx.config.io_timeout, ctx.log);
+                progress(min(fi.len.0, 2 * FINGERPRINT_LEN) as usize);
+                return hash;
+            }
             let (caching, prefix_len) = if fi.len <= prefix_len {
                 (Caching::Default, prefix_len)
             } else {
@@ -667,11 +1366,12 @@ fn group_by_prefix(
                 FilePos(0),
                 prefix_len,
                 buf_len,
-                caching,
-                |_| {},
+                caching.with_timeout(ctx.config.io_timeout),
+                progress,
                 ctx.log,
             )
         },
+        None,
     );
 
     let count = groups.selected_count(rf_over, rf_under);
@@ -704,10 +1404,13 @@ fn group_by_suffix(ctx: &AppCtx<'_>, groups: Vec<FileGroup<FileInfo>>) -> Vec<Fi
     let suffix_len = suffix_len(&ctx.devices, flat_iter(&groups));
     let suffix_threshold = suffix_threshold(&ctx.devices, flat_iter(&groups));
     let pre_filter = |g: &FileGroup<FileInfo>| g.file_len >= suffix_threshold && g.files.len() > 1;
-    let remaining_files = groups.iter().filter(|&g| pre_filter(g)).total_count();
-    let progress = ctx
-        .log
-        .progress_bar("Grouping by suffix", remaining_files as u64);
+    let bytes_to_scan = groups
+        .iter()
+        .filter(|&g| pre_filter(g))
+        .flat_map(|g| g.files.iter())
+        .map(|fi| (fi.get_device_index(), suffix_len.0));
+    let bytes_to_scan = bytes_per_device(&ctx.devices, bytes_to_scan);
+    let progress = ctx.hashing_progress_bar("suffix", "Grouping by suffix", bytes_to_scan);
 
     let rf_over = ctx.config.rf_over();
     let rf_under = ctx.config.rf_under();
@@ -719,7 +1422,10 @@ fn group_by_suffix(ctx: &AppCtx<'_>, groups: Vec<FileGroup<FileInfo>>) -> Vec<Fi
         &ctx.devices,
         AccessType::Random,
         |(fi, old_hash)| {
-            progress.tick();
+            ctx.wait_for_window();
+            if ctx.cancelled() {
+                return None;
+            }
             let device = &ctx.devices[fi.get_device_index()];
             let buf_len = device.buf_len();
             file_hash_or_log_err(
@@ -727,12 +1433,16 @@ fn group_by_suffix(ctx: &AppCtx<'_>, groups: Vec<FileGroup<FileInfo>>) -> Vec<Fi
                 fi.len.as_pos() - suffix_len,
                 suffix_len,
                 buf_len,
-                Caching::Default,
-                |_| {},
+                Caching::Default.with_timeout(ctx.config.io_timeout),
+                |delta| {
+                    ctx.count_hashed_bytes(delta);
+                    progress.inc_device(fi.get_device_index(), delta)
+                },
                 ctx.log,
             )
             .map(|new_hash| old_hash ^ new_hash)
         },
+        None,
     );
 
     let count = groups.selected_count(rf_over, rf_under);
@@ -744,50 +1454,294 @@ fn group_by_suffix(ctx: &AppCtx<'_>, groups: Vec<FileGroup<FileInfo>>) -> Vec<Fi
     groups
 }
 
-fn group_by_contents(
+/// Groups the files of the size groups that contain files with hashes known
+/// from the baseline report. Other files in these groups are hashed as a whole,
+/// because their prefix or suffix hashes couldn't be compared with the known hashes.
+/// The hashes by the extra hash functions are taken from the baseline report too,
+/// or computed in the same pass as the hash used for grouping.
+/// Returns the size groups without known files and the groups found.
+fn group_by_baseline(
     ctx: &AppCtx<'_>,
-    min_file_len: FileLen,
+    baseline: &Baseline,
     groups: Vec<FileGroup<FileInfo>>,
-) -> Vec<FileGroup<FileInfo>> {
-    let pre_filter = |g: &FileGroup<FileInfo>| g.files.len() > 1 && g.file_len >= min_file_len;
-    let bytes_to_scan = groups.iter().filter(|&g| pre_filter(g)).total_size();
-    let progress = &ctx
-        .log
-        .bytes_progress_bar("Grouping by contents", bytes_to_scan.0);
+) -> (Vec<FileGroup<FileInfo>>, Vec<FileGroup<FileInfo>>) {
+    let hash_fns = ctx.config.extra_hashes();
+    let known: HashMap<Path, (FileHash, Digests)> = groups
+        .par_iter()
+        .flat_map(|g| g.files.par_iter())
+        .filter_map(|f| {
+            baseline
+                .hashes(f, &hash_fns.fns)
+                .map(|hashes| (f.path.clone(), hashes))
+        })
+        .collect();
+    let (with_known, rest): (Vec<_>, Vec<_>) = groups
+        .into_iter()
+        .partition(|g| g.files.iter().any(|f| known.contains_key(&f.path)));
+
+    let bytes_to_scan = with_known
+        .iter()
+        .flat_map(|g| g.files.iter())
+        .filter(|f| !known.contains_key(&f.path))
+        .map(|f| (f.get_device_index(), f.len.0));
+    let bytes_to_scan = bytes_per_device(&ctx.devices, bytes_to_scan);
+    let progress = &ctx.hashing_progress_bar("baseline", "Grouping with baseline", bytes_to_scan);
 
     let rf_over = ctx.config.rf_over();
     let rf_under = ctx.config.rf_under();
 
+    let digests: DashMap<Path, Digests> = DashMap::new();
+    let keep_digests = |path: &Path, (hash, file_digests): (FileHash, Digests)| {
+        if !hash_fns.is_empty() {
+            digests.insert(path.clone(), file_digests);
+        }
+        hash
+    };
     let groups = rehash(
+        with_known,
+        |_| true,
+        |g| g.files.len() > rf_over,
+        &ctx.devices,
+        AccessType::Sequential,
+        |(fi, _)| {
+            ctx.wait_for_window();
+            if ctx.cancelled() {
+                return None;
+            }
+            if let Some(hashes) = known.get(&fi.path) {
+                return Some(keep_digests(&fi.path, hashes.clone()));
+            }
+            let buf_len = ctx.devices[fi.get_device_index()].buf_len();
+            file_hashes_or_log_err(
+                &fi.path,
+                fi.len,
+                buf_len,
+                Caching::Sequential.with_timeout(ctx.config.io_timeout),
+                &hash_fns,
+                |delta| {
+                    ctx.count_hashed_bytes(delta);
+                    progress.inc_device(fi.get_device_index(), delta)
+                },
+                ctx.log,
+            )
+            .map(|hashes| keep_digests(&fi.path, hashes))
+        },
+        None,
+    );
+    let groups: Vec<_> = groups
+        .into_iter()
+        .flat_map(|g| split_by_digests(g, &digests, rf_over))
+        .collect();
+
+    let count = groups.selected_count(rf_over, rf_under);
+    let bytes = groups.selected_size(rf_over, rf_under);
+    ctx.log.info(format!(
+        "Reused hashes of {} files from the baseline report; found {} ({}) {} files",
+        known.len(),
+        count,
+        bytes,
+        ctx.config.search_type()
+    ));
+    (rest, groups)
+}
+
+/// Groups files by the hash of their whole contents.
+/// Passes each group to the `sink` as soon as all files of its length are hashed.
+fn group_by_contents(
+    ctx: &AppCtx<'_>,
+    prefix_len: FileLen,
+    groups: Vec<FileGroup<FileInfo>>,
+    digests: DashMap<Path, Digests>,
+    sink: &mut dyn FnMut(FileGroup<FileInfo>),
+) {
+    // Files not longer than the prefix were hashed whole by the prefix stage already
+    let pre_filter = |g: &FileGroup<FileInfo>| g.files.len() > 1 && g.file_len > prefix_len;
+    let skipped_count: usize = groups
+        .iter()
+        .filter(|&g| g.file_len <= prefix_len)
+        .map(|g| g.files.len())
+        .sum();
+    if skipped_count > 0 {
+        ctx.log.info(format!(
+            "Reusing prefix hashes as contents hashes of {} files not longer than {}",
+            skipped_count, prefix_len
+        ));
+    }
+    let bytes_to_scan = groups
+        .iter()
+        .filter(|&g| pre_filter(g))
+        .flat_map(|g| g.files.iter())
+        .map(|fi| (fi.get_device_index(), fi.len.0));
+    let bytes_to_scan = bytes_per_device(&ctx.devices, bytes_to_scan);
+    let progress = &ctx.hashing_progress_bar("contents", "Grouping by contents", bytes_to_scan);
+    let mmap_threshold = ctx.config.mmap_threshold();
+
+    let rf_over = ctx.config.rf_over();
+    let rf_under = ctx.config.rf_under();
+
+    let hash_fns = ctx.config.extra_hashes();
+
+    let mut count = 0;
+    let mut bytes = FileLen(0);
+    let mut counting_sink = |g: FileGroup<FileInfo>| {
+        for g in split_by_digests(g, &digests, rf_over) {
+            count += iter::once(&g).selected_count(rf_over, rf_under);
+            bytes += iter::once(&g).selected_size(rf_over, rf_under);
+            sink(g)
+        }
+    };
+
+    let remaining = rehash(
         groups,
         pre_filter,
         |g| g.files.len() > rf_over,
         &ctx.devices,
         AccessType::Sequential,
         |(fi, _)| {
+            ctx.wait_for_window();
+            if ctx.cancelled() {
+                return None;
+            }
             let device = &ctx.devices[fi.get_device_index()];
             let buf_len = device.buf_len();
-            file_hash_or_log_err(
+            let progress = |delta| {
+                ctx.count_hashed_bytes(delta);
+                progress.inc_device(fi.get_device_index(), delta)
+            };
+            let keep_digests = |(hash, file_digests): (FileHash, Digests)| {
+                if !hash_fns.is_empty() {
+                    digests.insert(fi.path.clone(), file_digests);
+                }
+                hash
+            };
+            if matches!(mmap_threshold, Some(threshold) if fi.len >= threshold) {
+                // On failure, fall back to regular reads below:
+                let hashes =
+                    mmap_file_hashes(&fi.path, FilePos(0), fi.len, buf_len, &hash_fns, progress);
+                if let Ok(hashes) = hashes {
+                    return Some(keep_digests(hashes));
+                }
+            }
+            file_hashes_or_log_err(
                 &fi.path,
-                FilePos(0),
                 fi.len,
                 buf_len,
-                Caching::Sequential,
-                |delta| progress.inc(delta),
+                Caching::Sequential.with_timeout(ctx.config.io_timeout),
+                &hash_fns,
+                progress,
                 ctx.log,
             )
+            .map(keep_digests)
         },
+        Some(&mut counting_sink),
     );
+    remaining.into_iter().for_each(&mut counting_sink);
 
-    let count = groups.selected_count(rf_over, rf_under);
-    let bytes = groups.selected_size(rf_over, rf_under);
     ctx.log.info(format!(
         "Found {} ({}) {} files",
         count,
         bytes,
         ctx.config.search_type()
     ));
-    groups
+}
+
+/// Sets the hashes computed by other hash functions than the one used for grouping
+/// on the group. Files with the same grouping hash, but different other hashes
+/// are split into separate groups. Groups left with `rf_over` files or fewer
+/// after the split are dropped.
+fn split_by_digests(
+    group: FileGroup<FileInfo>,
+    digests: &DashMap<Path, Digests>,
+    rf_over: usize,
+) -> Vec<FileGroup<FileInfo>> {
+    if digests.is_empty() {
+        return vec![group];
+    }
+    let mut split: Vec<FileGroup<FileInfo>> = Vec::new();
+    for f in group.files {
+        let hashes = digests.remove(&f.path).map(|(_, d)| d).unwrap_or_default();
+        match split.iter_mut().find(|g| g.hashes == hashes) {
+            Some(g) => g.files.push(f),
+            None => split.push(FileGroup {
+                file_len: group.file_len,
+                file_hash: group.file_hash.clone(),
+                hashes,
+                files: vec![f],
+                truncated_from: None,
+                annotations: group.annotations.clone(),
+            }),
+        }
+    }
+    if split.len() > 1 {
+        split.retain(|g| g.files.len() > rf_over);
+    }
+    split
+}
+
+/// Splits the group into groups of files with the same extended attributes,
+/// or only the same access control lists, if requested with `--match-xattrs` or `--match-acl`.
+/// Files that have their attributes unreadable are placed in separate groups.
+/// Groups left with `rf_over` files or fewer after the split are dropped.
+fn split_by_attributes(
+    ctx: &AppCtx<'_>,
+    group: FileGroup<FileInfo>,
+    rf_over: usize,
+) -> Vec<FileGroup<FileInfo>> {
+    let config = ctx.config;
+    if !config.match_xattrs && !config.match_acl {
+        return vec![group];
+    }
+    let mut split: Vec<FileGroup<FileInfo>> = Vec::new();
+    let mut index: HashMap<xattr::Attributes, usize> = HashMap::new();
+    for f in group.files {
+        let attrs = match xattr::read_attributes(&f.path) {
+            Ok(mut attrs) => {
+                if !config.match_xattrs {
+                    attrs.retain(|(name, _)| xattr::is_acl(name));
+                }
+                Some(attrs)
+            }
+            Err(e) => {
+                ctx.log.unreadable_file(
+                    &f.path,
+                    FileOp::ReadXattrs,
+                    &e,
+                    format!("Failed to read extended attributes of {}: {}", f.path, e),
+                );
+                None
+            }
+        };
+        // Files with unreadable attributes don't match any other file:
+        if let Some(&i) = attrs.as_ref().and_then(|a| index.get(a)) {
+            split[i].files.push(f);
+            continue;
+        }
+        if let Some(attrs) = attrs {
+            index.insert(attrs, split.len());
+        }
+        split.push(FileGroup {
+            file_len: group.file_len,
+            file_hash: group.file_hash.clone(),
+            hashes: group.hashes.clone(),
+            files: vec![f],
+            truncated_from: None,
+            annotations: group.annotations.clone(),
+        });
+    }
+    if split.len() > 1 {
+        ctx.log.warn(format!(
+            "Files with hash {} differ in {} and were split into {} groups",
+            group.file_hash,
+            if config.match_xattrs {
+                "extended attributes"
+            } else {
+                "access control lists"
+            },
+            split.len()
+        ));
+        split.retain(|g| g.files.len() > rf_over);
+    }
+    split
 }
 
 /// Groups identical files together by 128-bit hash of their contents.
@@ -843,11 +1797,9 @@ fn group_by_contents(
 /// use fclones::log::Log;
 /// use fclones::config::GroupConfig;
 /// use fclones::{group_files, write_report};
-/// use std::path::PathBuf;
 ///
 /// let log = Log::new();
-/// let mut config = GroupConfig::default();
-/// config.paths = vec![PathBuf::from("/path/to/a/dir")];
+/// let config = GroupConfig::builder().path("/path/to/a/dir").build();
 ///
 /// let groups = group_files(&config, &log).unwrap();
 /// println!("Found {} groups: ", groups.len());
@@ -856,36 +1808,440 @@ fn group_by_contents(
 /// write_report(&config, &log, &groups).unwrap();
 /// ```
 pub fn group_files(config: &GroupConfig, log: &Log) -> Result<Vec<FileGroup<Path>>, Error> {
-    let spinner = log.spinner("Initializing");
-    let ctx = AppCtx::new(config, log)?;
+    group_files_with_summary(config, log).map(|(groups, _)| groups)
+}
 
-    drop(spinner);
-    let matching_files = scan_files(&ctx);
-    let size_groups = group_by_size(&ctx, matching_files);
-    let mut size_groups_pruned = remove_same_files(&ctx, size_groups);
-    update_file_locations(&ctx, &mut size_groups_pruned);
+/// Works like [`group_files`], but additionally returns a summary of the run,
+/// with counts of files skipped by the filters, counts of errors and the time spent
+/// in each stage.
+pub fn group_files_with_summary(
+    config: &GroupConfig,
+    log: &Log,
+) -> Result<(Vec<FileGroup<Path>>, GroupSummary), Error> {
+    let mut groups = Vec::new();
+    let summary = group_files_to_sink(config, log, |g| groups.push(g))?;
+    if config.deterministic {
+        groups.par_sort_by(|a, b| {
+            (&a.file_hash, a.file_len, &a.files).cmp(&(&b.file_hash, b.file_len, &b.files))
+        });
+    } else {
+        groups.par_sort_by(|a, b| (b.file_len, &b.file_hash).cmp(&(a.file_len, &a.file_hash)));
+    }
+    if config.isolate {
+        // Sections of the report, each one listing the groups of one input path:
+        let roots = report_roots(config)
+            .into_iter()
+            .map(Path::from)
+            .collect_vec();
+        groups.sort_by_cached_key(|g| {
+            let root = g.files.first().and_then(|f| root_index(f, &roots));
+            root.unwrap_or(roots.len())
+        });
+    }
+    Ok((groups, summary))
+}
+
+/// Works like [`group_files_with_summary`], but instead of collecting the groups,
+/// passes each group to the `sink` as soon as it is found, which may happen
+/// long before all files are processed.
+/// The groups are passed in no particular order, but the files in each group are sorted.
+/// The `sink` is called on the caller's thread.
+pub fn group_files_to_sink<S>(
+    config: &GroupConfig,
+    log: &Log,
+    mut sink: S,
+) -> Result<GroupSummary, Error>
+where
+    S: FnMut(FileGroup<Path>),
+{
+    let spinner = log.spinner("Initializing");
+    if let Some(limit) = config.max_open_files {
+        fd::set_limit(limit);
+    }
+    if let Some(threads) = config.hash_threads {
+        hash_pool::set_threads(threads);
+    }
+    config.hash_backend.check()?;
+    check_include_metadata(config)?;
+    if let Some(run_window) = config.run_window {
+        let wait = run_window.time_until_open(Local::now().time());
+        if wait > Duration::from_secs(0) {
+            log.info(format!(
+                "Outside of the run window {}, waiting {} min for it to open",
+                run_window,
+                (wait.as_secs() + 59) / 60
+            ));
+        }
+    }
+    log.mark_scan_start();
+    if config.report_errors {
+        log.record_scan_errors();
+    }
+    if config.unreadable_report.is_some() {
+        log.record_unreadable();
+    }
+    if config.stats_by == Some(StatsBy::Owner) {
+        log.record_owners();
+    }
+    let start = GroupSummary::start(log);
+    let ctx = AppCtx::new(config, log)?;
+    let mut summary = GroupSummary::default();
+
+    drop(spinner);
+    let stage = GroupSummary::start_stage("scan", log);
+    let matching_files = scan_files(&ctx);
+    let count = matching_files.iter().map(|v| v.len()).sum();
+    let size = matching_files
+        .iter()
+        .flat_map(|v| v.iter().map(|i| i.len))
+        .sum();
+    summary.end_stage(stage, log, count, size);
+    ctx.check_cancelled()?;
+
+    let stage = GroupSummary::start_stage("size", log);
+    let size_groups = group_by_size(&ctx, matching_files);
+    summary.end_stage(
+        stage,
+        log,
+        size_groups.total_count(),
+        size_groups.total_size(),
+    );
+    ctx.check_cancelled()?;
+
+    let stage = GroupSummary::start_stage("same files", log);
+    let size_groups = select_containing(&ctx, size_groups);
+    let mut size_groups_pruned = isolate_roots(&ctx, remove_same_files(&ctx, size_groups));
+    update_file_locations(&ctx, &mut size_groups_pruned);
+    summary.end_stage(
+        stage,
+        log,
+        size_groups_pruned.total_count(),
+        size_groups_pruned.total_size(),
+    );
+    ctx.check_cancelled()?;
+
+    let rf_under = ctx.config.rf_under();
+    let mut found_count = 0;
+    let mut found_size = FileLen(0);
+    let compare_roots = if config.compare { &ctx.roots[..] } else { &[] };
+    let mut emit_group = |g: FileGroup<FileInfo>| {
+        let selected = match compare_roots {
+            [from, to] if config.missing => is_missing(&g, from, to),
+            _ => spans_all_roots(&g, compare_roots),
+        };
+        let selected = selected
+            && (config.with_hash.is_empty() || g.has_hash(&config.with_hash))
+            && (ctx.containing.is_empty() || contains_any(&g, &ctx.containing));
+        if !selected {
+            return;
+        }
+        found_count += g.files.len();
+        found_size += g.file_len * g.files.len() as u64;
+        if g.files.len() < rf_under {
+            let mut files: Vec<Path> = g.files.into_iter().map(|fi| fi.path).collect();
+            files.sort();
+            sink(FileGroup {
+                file_len: g.file_len,
+                file_hash: g.file_hash,
+                hashes: g.hashes,
+                files,
+                truncated_from: None,
+                annotations: g.annotations,
+            });
+        }
+    };
+    let rf_over = ctx.config.rf_over();
+    let mut emit = |g: FileGroup<FileInfo>| {
+        split_by_root(&ctx, g, rf_over)
+            .into_iter()
+            .flat_map(|g| split_by_attributes(&ctx, g, rf_over))
+            .for_each(&mut emit_group)
+    };
+
+    if config.empty_as_group {
+        let (empty, rest): (Vec<_>, Vec<_>) = size_groups_pruned
+            .into_iter()
+            .partition(|g| g.file_len == FileLen(0));
+        size_groups_pruned = rest;
+        empty
+            .into_iter()
+            .map(|g| empty_file_group(ctx.config, g))
+            .filter(|g| g.files.len() > rf_over)
+            .for_each(&mut emit);
+    }
+
+    if let Some(baseline) = &ctx.baseline {
+        let stage = GroupSummary::start_stage("baseline", log);
+        let (rest, groups) = group_by_baseline(&ctx, baseline, size_groups_pruned);
+        summary.end_stage(stage, log, groups.total_count(), groups.total_size());
+        ctx.check_cancelled()?;
+        groups.into_iter().for_each(&mut emit);
+        size_groups_pruned = rest;
+    }
 
-    let groups = match &ctx.transform {
-        Some(transform) => group_transformed(&ctx, transform, size_groups_pruned),
+    match &ctx.transform {
+        Some(transform) => {
+            let stage = GroupSummary::start_stage("transform", log);
+            let groups = group_transformed(&ctx, transform, size_groups_pruned);
+            groups.into_iter().for_each(&mut emit);
+            summary.end_stage(stage, log, found_count, found_size);
+            ctx.check_cancelled()?;
+        }
         _ => {
             let prefix_len = prefix_len(&ctx.devices, flat_iter(&size_groups_pruned));
-            let prefix_groups = group_by_prefix(&ctx, prefix_len, size_groups_pruned);
+            let stage = GroupSummary::start_stage("prefix", log);
+            let digests = DashMap::new();
+            let prefix_groups = group_by_prefix(&ctx, prefix_len, size_groups_pruned, &digests);
+            summary.end_stage(
+                stage,
+                log,
+                prefix_groups.total_count(),
+                prefix_groups.total_size(),
+            );
+            ctx.check_cancelled()?;
+            let stage = GroupSummary::start_stage("suffix", log);
             let suffix_groups = group_by_suffix(&ctx, prefix_groups);
-            group_by_contents(&ctx, prefix_len, suffix_groups)
+            summary.end_stage(
+                stage,
+                log,
+                suffix_groups.total_count(),
+                suffix_groups.total_size(),
+            );
+            ctx.check_cancelled()?;
+            let stage = GroupSummary::start_stage("contents", log);
+            group_by_contents(&ctx, prefix_len, suffix_groups, digests, &mut emit);
+            summary.end_stage(stage, log, found_count, found_size);
+            ctx.check_cancelled()?;
         }
     };
-    let mut groups: Vec<_> = groups
-        .into_par_iter()
-        .map(|g| FileGroup {
-            file_len: g.file_len,
-            file_hash: g.file_hash,
-            files: g.files.into_iter().map(|fi| fi.path).collect(),
+
+    let counters = &ctx.counters;
+    summary.scanned_entries = counters.scanned_entries.load(Ordering::Relaxed);
+    summary.scanned_files = counters.scanned_files.load(Ordering::Relaxed);
+    summary.skipped_hidden = counters.skipped_hidden.load(Ordering::Relaxed);
+    summary.skipped_by_name = counters.skipped_by_name.load(Ordering::Relaxed);
+    summary.skipped_dirs = counters.skipped_dirs.load(Ordering::Relaxed);
+    summary.skipped_by_size = counters.skipped_by_size.load(Ordering::Relaxed);
+    summary.skipped_by_time = counters.skipped_by_time.load(Ordering::Relaxed);
+    summary.skipped_special_files = counters.skipped_special_files.load(Ordering::Relaxed);
+    summary.skipped_file_links = counters.skipped_file_links.load(Ordering::Relaxed);
+    summary.skipped_quarantined = counters.skipped_quarantined.load(Ordering::Relaxed);
+    summary.bytes_hashed = FileLen(counters.bytes_hashed.load(Ordering::Relaxed));
+    summary.finish(start, log);
+
+    if let Some(path) = &config.unreadable_report {
+        let unreadable = log.take_unreadable();
+        let error = |e: io::Error| {
+            format!(
+                "Failed to write unreadable files report {}: {}",
+                path.display(),
+                e
+            )
+        };
+        let file = File::create(path).map_err(error)?;
+        report::write_unreadable_report(BufWriter::new(file), &unreadable, &ctx.devices)
+            .map_err(error)?;
+        if !unreadable.is_empty() {
+            log.info(format!(
+                "Listed {} unreadable files in {}",
+                unreadable.len(),
+                path.display()
+            ));
+        }
+    }
+
+    let timed_out = log.take_timed_out();
+    if !timed_out.is_empty() {
+        log.warn(format!(
+            "Abandoned {} files because of --io-timeout",
+            timed_out.len()
+        ));
+        if let Some(list) = &config.quarantine {
+            io_timeout::append_to_quarantine(list, &timed_out).map_err(|e| {
+                format!("Failed to write quarantine list {}: {}", list.display(), e)
+            })?;
+        }
+    }
+    Ok(summary)
+}
+
+/// Keeps only the groups containing any of the files given by `--containing`, if any were given.
+/// Their copies must have the same size, so other groups don't need to be hashed at all.
+fn select_containing(
+    ctx: &AppCtx<'_>,
+    mut groups: Vec<FileGroup<FileInfo>>,
+) -> Vec<FileGroup<FileInfo>> {
+    if !ctx.containing.is_empty() {
+        groups.retain(|g| contains_any(g, &ctx.containing));
+        if groups.is_empty() {
+            ctx.log
+                .warn("None of the files given by --containing was found under the input paths");
+        }
+    }
+    groups
+}
+
+/// Returns true if the group contains any of the given files
+fn contains_any(group: &FileGroup<FileInfo>, paths: &[Path]) -> bool {
+    group.files.iter().any(|f| paths.contains(&f.path))
+}
+
+/// Splits the groups by input paths if requested with `--isolate`, before they are hashed,
+/// so files with no copies under the same input path are not read at all.
+/// The later stages may merge the split groups again, so they must be split once more
+/// when found, see [`split_by_root`].
+fn isolate_roots(ctx: &AppCtx<'_>, groups: Vec<FileGroup<FileInfo>>) -> Vec<FileGroup<FileInfo>> {
+    if !ctx.config.isolate {
+        return groups;
+    }
+    let rf_over = ctx.config.rf_over();
+    groups
+        .into_iter()
+        .flat_map(|g| split_by_root(ctx, g, rf_over))
+        .collect()
+}
+
+/// Splits the group into groups of files under the same input path,
+/// if requested with `--isolate`, so files under different input paths are never
+/// reported as copies of each other.
+/// Groups left with `rf_over` files or fewer after the split are dropped.
+fn split_by_root(
+    ctx: &AppCtx<'_>,
+    group: FileGroup<FileInfo>,
+    rf_over: usize,
+) -> Vec<FileGroup<FileInfo>> {
+    if !ctx.config.isolate {
+        return vec![group];
+    }
+    let mut split: Vec<(Option<usize>, FileGroup<FileInfo>)> = Vec::new();
+    for f in group.files {
+        let root = root_index(&f.path, &ctx.roots);
+        match split.iter_mut().find(|(r, _)| *r == root) {
+            Some((_, g)) => g.files.push(f),
+            None => split.push((
+                root,
+                FileGroup {
+                    file_len: group.file_len,
+                    file_hash: group.file_hash.clone(),
+                    hashes: group.hashes.clone(),
+                    files: vec![f],
+                    truncated_from: None,
+                    annotations: group.annotations.clone(),
+                },
+            )),
+        }
+    }
+    if split.len() > 1 {
+        split.retain(|(_, g)| g.files.len() > rf_over);
+    }
+    split.into_iter().map(|(_, g)| g).collect()
+}
+
+/// Returns the index of the longest root containing the path, or `None` if there is no such root.
+/// A root nested in another root, scanned because of a limited `--depth`, takes precedence
+/// over the outer root.
+fn root_index(path: &Path, roots: &[Path]) -> Option<usize> {
+    roots
+        .iter()
+        .enumerate()
+        .filter(|(_, root)| root.is_prefix_of(path))
+        .max_by_key(|(_, root)| root.component_count())
+        .map(|(i, _)| i)
+}
+
+/// Returns true if the group contains at least one file under each of the roots
+fn spans_all_roots(group: &FileGroup<FileInfo>, roots: &[Path]) -> bool {
+    roots
+        .iter()
+        .all(|root| group.files.iter().any(|f| root.is_prefix_of(&f.path)))
+}
+
+/// Returns true if the group contains files under `from`, but none under `to`
+fn is_missing(group: &FileGroup<FileInfo>, from: &Path, to: &Path) -> bool {
+    group.files.iter().any(|f| from.is_prefix_of(&f.path))
+        && !group.files.iter().any(|f| to.is_prefix_of(&f.path))
+}
+
+/// Returns the amount of disk space allocated to redundant files of each group.
+/// Because we don't know yet which files would be kept, we assume the ones that take
+/// the most space are kept, so the returned values are never overestimated.
+/// Files that can't be accessed are assumed to take no space.
+fn allocated_sizes(groups: &[FileGroup<Path>], rf_over: usize, log: &Log) -> Vec<FileLen> {
+    groups
+        .par_iter()
+        .map(|g| {
+            let mut sizes: Vec<FileLen> = g
+                .files
+                .iter()
+                .map(|f| match std::fs::metadata(f.to_path_buf()) {
+                    Ok(m) => allocated_len(&m),
+                    Err(e) => {
+                        let msg = format!("Failed to read metadata of {}: {}", f, e);
+                        log.unreadable_file(f, FileOp::Stat, &e, msg);
+                        FileLen(0)
+                    }
+                })
+                .collect();
+            sizes.sort();
+            let redundant_count = sizes.len().saturating_sub(rf_over);
+            sizes.into_iter().take(redundant_count).sum::<FileLen>()
         })
-        .collect();
-    groups.retain(|g| g.files.len() < ctx.config.rf_under());
-    groups.par_sort_by_key(|g| Reverse((g.file_len, g.file_hash)));
-    groups.par_iter_mut().for_each(|g| g.files.sort());
-    Ok(groups)
+        .collect()
+}
+
+/// Orders the files of each group so that files sharing their data on disk are adjacent
+/// and returns the link cluster numbers of the files, in the same order.
+/// Clusters are numbered from 1 within each group; files not sharing data with any other
+/// file of the group get `None`. Files that can't be accessed are assumed not to be linked.
+fn link_clusters(groups: &mut [FileGroup<Path>], log: &Log) -> Vec<Vec<Option<u32>>> {
+    groups
+        .par_iter_mut()
+        .map(|g| {
+            let ids: Vec<Option<DataId>> = g
+                .files
+                .iter()
+                .map(|f| match DataId::new(f) {
+                    Ok(id) => Some(id),
+                    Err(e) => {
+                        log.warn(e);
+                        None
+                    }
+                })
+                .collect();
+            let mut counts: HashMap<DataId, usize> = HashMap::new();
+            for id in ids.iter().flatten() {
+                *counts.entry(*id).or_default() += 1;
+            }
+            let mut numbers: HashMap<DataId, u32> = HashMap::new();
+            let clusters = ids.into_iter().map(|id| {
+                let id = id.filter(|id| counts[id] > 1)?;
+                let next = numbers.len() as u32 + 1;
+                Some(*numbers.entry(id).or_insert(next))
+            });
+            let mut files = g.files.drain(..).zip(clusters).collect_vec();
+            files.sort_by_key(|(_, cluster)| cluster.unwrap_or(u32::MAX));
+            let (files, clusters) = files.into_iter().unzip();
+            g.files = files;
+            clusters
+        })
+        .collect()
+}
+
+/// Computes how many files are already shared with another file of their group.
+/// In each link cluster, all files but one are counted.
+fn link_stats(groups: &[FileGroup<Path>], clusters: &[Vec<Option<u32>>]) -> LinkStats {
+    let mut stats = LinkStats {
+        file_count: 0,
+        file_size: FileLen(0),
+    };
+    for (g, clusters) in groups.iter().zip(clusters) {
+        let linked_count = clusters.iter().flatten().count();
+        let cluster_count = clusters.iter().flatten().max().copied().unwrap_or(0) as usize;
+        let shared_count = linked_count - cluster_count;
+        stats.file_count += shared_count;
+        stats.file_size += g.file_len * shared_count as u64;
+    }
+    stats
 }
 
 /// Writes the list of groups to a file or the standard output.
@@ -893,53 +2249,477 @@ pub fn group_files(config: &GroupConfig, log: &Log) -> Result<Vec<FileGroup<Path
 /// # Parameters
 /// - `config.output`: a path to the output file, `None` for standard output
 /// - `config.format`: selects the format of the output, see [`config::OutputFormat`]
+/// - `config.extra_outputs`: other files to write the report to, each in its own format
 /// - `log`: used for drawing a progress bar to standard error
 /// - `groups`: list of groups of files to print, e.g. obtained from [`group_files`]
 ///
 /// # Errors
 /// Returns [`io::Error`] on I/O write error or if the output file cannot be created.
+/// The other outputs are still written, and the first error is returned afterwards.
 pub fn write_report(config: &GroupConfig, log: &Log, groups: &[FileGroup<Path>]) -> io::Result<()> {
-    let now = Local::now();
+    let base = report_base(config);
+    let roots = if config.group_by_root || config.isolate || config.root_stats {
+        report_roots(config)
+    } else {
+        vec![]
+    };
+    let linked_groups;
+    let mut clusters = vec![];
+    let groups = if config.link_clusters {
+        let mut groups = groups.to_vec();
+        clusters = link_clusters(&mut groups, log);
+        linked_groups = groups;
+        &linked_groups
+    } else {
+        groups
+    };
     let rf_over = max(1, config.rf_over());
-    let header = ReportHeader {
-        timestamp: DateTime::from_utc(now.naive_utc(), *now.offset()),
-        version: env!("CARGO_PKG_VERSION").to_owned(),
-        command: args().collect(),
-        stats: Some(FileStats {
+    let allocated = if config.use_allocated_size {
+        allocated_sizes(groups, rf_over, log)
+    } else {
+        vec![]
+    };
+    let mut header = new_report_header(
+        log,
+        Some(FileStats {
             group_count: groups.len(),
             redundant_file_count: groups.selected_count(rf_over, usize::MAX),
-            redundant_file_size: groups.selected_size(rf_over, usize::MAX),
+            redundant_file_size: if config.use_allocated_size {
+                allocated.iter().copied().sum()
+            } else {
+                groups.selected_size(rf_over, usize::MAX)
+            },
         }),
+    );
+    if config.report_errors {
+        header.errors = log.take_scan_errors();
+        if config.deterministic {
+            header.errors.sort();
+        }
+    }
+    if config.root_stats {
+        header.root_stats = root_stats(groups, &roots);
+    }
+    if config.ext_stats {
+        header.ext_stats = ext_stats(groups);
+    }
+    if config.stats_by == Some(StatsBy::Owner) {
+        let owners = log.take_owners();
+        header.owner_stats = owner_stats(groups, |f| {
+            owners.get(f).copied().or_else(|| owner::file_owner(f).ok())
+        });
+    }
+    if config.histogram {
+        header.size_histogram = size_histogram(groups, rf_over);
+        header.copies_histogram = copies_histogram(groups, rf_over);
+    }
+    if config.link_clusters {
+        header.link_stats = Some(link_stats(groups, &clusters));
+    }
+    if config.header_metadata {
+        header.host = Some(host_metadata(config, &base));
+    }
+
+    let mut roots = if config.group_by_root || config.isolate {
+        roots
+    } else {
+        vec![]
+    };
+    let relative_groups;
+    let groups = match &base {
+        Some(base) => {
+            for r in header.root_stats.iter_mut() {
+                r.root = relative_path(&Path::from(&r.root), base).to_string();
+            }
+            relativize_roots(&mut roots, base);
+            relative_groups = groups.iter().map(|g| relative_group(g, base)).collect_vec();
+            &relative_groups
+        }
+        None => groups,
+    };
+    // A failed output, e.g. the standard output closed by its reader, doesn't stop writing
+    // the others, so the first error is reported after all outputs are written
+    let mut result = Ok(());
+    for (file, format) in config.outputs() {
+        let truncated_groups;
+        let groups = match truncation_limit(config, format) {
+            Some(max_files) => {
+                truncated_groups = groups
+                    .iter()
+                    .map(|g| truncated_group(g.clone(), max_files))
+                    .collect_vec();
+                &truncated_groups
+            }
+            None => groups,
+        };
+        let output = ReportOutput {
+            file,
+            format,
+            path_format: config.path_format,
+            compact_paths: config.compact_paths,
+            csv_delimiter: config.csv_delimiter,
+            csv_bom: config.csv_bom,
+            csv_group_id: config.csv_group_id,
+            roots: roots.clone(),
+            link_clusters: clusters.clone(),
+            allocated_sizes: allocated.clone(),
+            devices: device_metadata(config, &base),
+            stats_trailer: None,
+        };
+        let written = write_groups(output, &header, groups, log);
+        if result.is_ok() {
+            result = written;
+        }
+    }
+    result
+}
+
+/// Writes the groups to a file or the standard output as they arrive,
+/// e.g. from [`group_files_to_sink`] running on another thread.
+///
+/// Because the report statistics are not known before the last group is written,
+/// they are written after the last group instead of in the header.
+/// The text and JSON report readers pick them up from there.
+/// The options that need all groups upfront, like `--deterministic`, `--root-stats`,
+/// `--ext-stats` or `--histogram`, are ignored.
+pub fn write_report_streaming<I>(config: &GroupConfig, log: &Log, groups: I) -> io::Result<()>
+where
+    I: IntoIterator<Item = FileGroup<Path>>,
+{
+    let base = report_base(config);
+    let mut roots = if config.group_by_root || config.isolate {
+        report_roots(config)
+    } else {
+        vec![]
+    };
+    if let Some(base) = &base {
+        relativize_roots(&mut roots, base);
+    }
+    let groups = groups.into_iter().map(|g| match &base {
+        Some(base) => relative_group(&g, base),
+        None => g,
+    });
+    let mut header = new_report_header(log, None);
+    if config.header_metadata {
+        header.host = Some(host_metadata(config, &base));
+    }
+    let write = |file, format, groups: &mut dyn Iterator<Item = FileGroup<Path>>| {
+        let max_files = truncation_limit(config, format);
+        let groups = groups.map(move |g| match max_files {
+            Some(max_files) => truncated_group(g, max_files),
+            None => g,
+        });
+        let output = ReportOutput {
+            file,
+            format,
+            path_format: config.path_format,
+            compact_paths: config.compact_paths,
+            csv_delimiter: config.csv_delimiter,
+            csv_bom: config.csv_bom,
+            csv_group_id: config.csv_group_id,
+            roots: roots.clone(),
+            link_clusters: vec![],
+            allocated_sizes: vec![],
+            devices: device_metadata(config, &base),
+            stats_trailer: Some(max(1, config.rf_over())),
+        };
+        write_group_iter(output, &header, groups, log)
     };
 
-    match &config.output {
-        Some(path) => {
+    let outputs = config.outputs();
+    if let [(file, format)] = outputs[..] {
+        return write(file, format, &mut groups.into_iter());
+    }
+
+    // Each output is written by its own thread, so all outputs are written
+    // in a single pass over the groups
+    thread::scope(|s| {
+        let mut senders = Vec::new();
+        let mut writers = Vec::new();
+        for (file, format) in outputs {
+            let (tx, rx) = sync_channel::<FileGroup<Path>>(64);
+            let write = &write;
+            senders.push(tx);
+            writers.push(s.spawn(move |_| write(file, format, &mut rx.into_iter())));
+        }
+        for g in groups {
+            // A failed writer dropped its receiver, but the others continue
+            for tx in senders.iter() {
+                let _ = tx.send(g.clone());
+            }
+        }
+        drop(senders);
+        // Wait for all writers before reporting the first error
+        let results: Vec<_> = writers.into_iter().map(|w| w.join().unwrap()).collect();
+        results.into_iter().collect()
+    })
+    .unwrap()
+}
+
+/// Returns a report header with the start time of the last scan and command line arguments.
+/// If no scan was started, the current time is used.
+fn new_report_header(log: &Log, stats: Option<FileStats>) -> ReportHeader {
+    let now = Local::now();
+    ReportHeader {
+        timestamp: log
+            .scan_start()
+            .unwrap_or_else(|| DateTime::from_utc(now.naive_utc(), *now.offset())),
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        command: args().collect(),
+        hash: Some(GroupingHash::CURRENT),
+        stats,
+        errors: vec![],
+        root_stats: vec![],
+        ext_stats: vec![],
+        owner_stats: vec![],
+        size_histogram: vec![],
+        copies_histogram: vec![],
+        link_stats: None,
+        host: None,
+    }
+}
+
+/// Describes the machine the scan runs on, for the report header, see `--header-metadata`
+fn host_metadata(config: &GroupConfig, base: &Option<Path>) -> HostMetadata {
+    use sysinfo::{System, SystemExt};
+    let system = System::new();
+    let os = [system.get_name(), system.get_version()]
+        .iter()
+        .flatten()
+        .join(" ");
+    let devices = DiskDevices::default();
+    let root_file_systems = report_roots(config)
+        .into_iter()
+        .map(|root| {
+            let path = Path::from(&root);
+            let file_system = devices.get_file_system(&path);
+            RootFileSystem {
+                root: match base {
+                    Some(base) => relative_path(&path, base).to_string(),
+                    None => root,
+                },
+                file_system: file_system.map_or(String::new(), |fs| fs.to_string_lossy().into()),
+            }
+        })
+        .collect();
+    let features = [
+        ("async", cfg!(feature = "async")),
+        ("ffi", cfg!(feature = "ffi")),
+    ];
+    HostMetadata {
+        hostname: system.get_host_name().unwrap_or_default(),
+        os,
+        arch: std::env::consts::ARCH.to_owned(),
+        root_file_systems,
+        features: features
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(f, _)| f.to_string())
+            .collect(),
+    }
+}
+
+/// Returns the absolute directory the paths in the report should be relative to,
+/// if requested with `--relative-to`
+fn report_base(config: &GroupConfig) -> Option<Path> {
+    let cwd = Arc::new(Path::from(current_dir().unwrap_or_default()));
+    config
+        .relative_to
+        .as_ref()
+        .map(|b| cwd.resolve(Path::from(b)).canonicalize())
+}
+
+/// Fails if `--include-metadata` is requested for a report format that can't carry it
+fn check_include_metadata(config: &GroupConfig) -> Result<(), Error> {
+    if config.include_metadata.is_empty() {
+        return Ok(());
+    }
+    for (_, format) in config.outputs() {
+        if !matches!(format, OutputFormat::Json | OutputFormat::Csv) {
+            return Err(Error::new(format!(
+                "--include-metadata is supported only by the json and csv formats, not by {}",
+                format
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the lookup of the devices of the reported files, if requested by `--include-metadata`
+fn device_metadata(config: &GroupConfig, base: &Option<Path>) -> Option<DeviceMetadata> {
+    config
+        .include_metadata
+        .contains(&FileMetadataKind::Device)
+        .then(|| DeviceMetadata::new(base.clone()))
+}
+
+fn relativize_roots(roots: &mut [String], base: &Path) {
+    for r in roots.iter_mut() {
+        *r = relative_path(&Path::from(r.as_str()), base).to_string();
+    }
+}
+
+fn relative_group(group: &FileGroup<Path>, base: &Path) -> FileGroup<Path> {
+    FileGroup {
+        file_len: group.file_len,
+        file_hash: group.file_hash.clone(),
+        hashes: group.hashes.clone(),
+        files: group.files.iter().map(|f| relative_path(f, base)).collect(),
+        truncated_from: group.truncated_from,
+        annotations: group.annotations.clone(),
+    }
+}
+
+/// Returns the maximum number of files listed in each group of the report,
+/// if the groups should be truncated
+fn truncation_limit(config: &GroupConfig, format: OutputFormat) -> Option<usize> {
+    config
+        .max_group_files
+        .filter(|_| !matches!(format, OutputFormat::Fdupes))
+}
+
+fn truncated_group(mut group: FileGroup<Path>, max_files: usize) -> FileGroup<Path> {
+    group.truncate(max_files);
+    group
+}
+
+/// Returns the path relative to `base` if the path is located under `base`.
+/// Otherwise returns the path unchanged.
+fn relative_path(path: &Path, base: &Path) -> Path {
+    if base != path && base.is_prefix_of(path) {
+        path.strip_prefix(base).unwrap()
+    } else {
+        path.clone()
+    }
+}
+
+/// Returns the absolute input paths as they are displayed in the report.
+/// Returns an empty list if the input paths are read from the standard input.
+fn report_roots(config: &GroupConfig) -> Vec<String> {
+    if config.stdin {
+        return vec![];
+    }
+    let base_dir = Arc::new(Path::from(current_dir().unwrap_or_default()));
+    config
+        .paths
+        .iter()
+        .map(|p| input_root(config, &base_dir, p).to_string())
+        .unique()
+        .collect()
+}
+
+/// Returns the absolute path of an input path, as the walker reports it.
+/// The walker keeps the symbolic links given as input paths, unless it follows links.
+fn input_root(config: &GroupConfig, base_dir: &Arc<Path>, path: &std::path::Path) -> Path {
+    let path = base_dir.resolve(Path::from(path));
+    if config.follow_links {
+        path.canonicalize()
+    } else {
+        path.resolve_dots()
+    }
+}
+
+/// Describes where and how the report should be written
+pub(crate) struct ReportOutput<'a> {
+    /// Output file, or `None` for the standard output
+    pub file: Option<&'a PathBuf>,
+    pub format: OutputFormat,
+    pub path_format: PathFormat,
+    pub compact_paths: bool,
+    pub csv_delimiter: CsvDelimiter,
+    pub csv_bom: bool,
+    pub csv_group_id: bool,
+    /// Input paths to annotate the files with, see `--group-by-root`
+    pub roots: Vec<String>,
+    /// Link cluster numbers of the files of each group, see `--link-clusters`
+    pub link_clusters: Vec<Vec<Option<u32>>>,
+    /// Disk space allocated to the redundant files of each group, see `--use-allocated-size`
+    pub allocated_sizes: Vec<FileLen>,
+    /// Finds the devices of the files, see `--include-metadata device`
+    pub devices: Option<DeviceMetadata>,
+    /// If set, the report statistics are computed with the given `rf_over` while writing
+    /// the groups and written after the last group
+    pub stats_trailer: Option<usize>,
+}
+
+impl<'a> ReportOutput<'a> {
+    fn writer<W: std::io::Write>(self, out: W, color: bool) -> ReportWriter<W> {
+        let writer = ReportWriter::new(out, color)
+            .with_theme(ReportTheme::from_env())
+            .with_path_format(self.path_format)
+            .with_compact_paths(self.compact_paths)
+            .with_csv_delimiter(self.csv_delimiter.0)
+            .with_csv_bom(self.csv_bom)
+            .with_csv_group_id(self.csv_group_id)
+            .group_by_roots(self.roots)
+            .with_link_clusters(self.link_clusters)
+            .with_allocated_sizes(self.allocated_sizes)
+            .with_device_metadata(self.devices);
+        match self.stats_trailer {
+            Some(rf_over) => writer.with_stats_trailer(rf_over),
+            None => writer,
+        }
+    }
+}
+
+/// Writes the report header and the groups to the output file if given,
+/// or to the standard output otherwise.
+pub(crate) fn write_groups(
+    output: ReportOutput,
+    header: &ReportHeader,
+    groups: &[FileGroup<Path>],
+    log: &Log,
+) -> io::Result<()> {
+    match output.file {
+        Some(_) => {
             let progress = log.progress_bar("Writing report", groups.len() as u64);
             let iter = groups.iter().inspect(|_g| progress.tick());
+            write_group_iter(output, header, iter, log)
+        }
+        None => write_group_iter(output, header, groups.iter(), log),
+    }
+}
+
+/// Writes the report header and the groups given by an iterator
+fn write_group_iter<I, G>(
+    output: ReportOutput,
+    header: &ReportHeader,
+    groups: I,
+    log: &Log,
+) -> io::Result<()>
+where
+    I: IntoIterator<Item = G>,
+    G: Borrow<FileGroup<Path>> + Serialize,
+{
+    let format = output.format;
+    match output.file {
+        Some(path) => {
             let file = BufWriter::new(File::create(path)?);
-            let mut reporter = ReportWriter::new(file, false);
-            reporter.write(config.format, &header, iter)
+            let color = log.color == ColorMode::Always;
+            let mut reporter = output.writer(file, color);
+            reporter.write(format, header, groups)
         }
         None => {
-            let term = Term::stdout();
-            let color = term.is_term();
-            let mut reporter = ReportWriter::new(BufWriter::new(term), color);
-            reporter.write(config.format, &header, groups.iter())
+            let color = log.stdout_color();
+            let mut reporter = output.writer(BufWriter::new(Term::stdout()), color);
+            reporter.write(format, header, groups)
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use std::fs::{hard_link, File, OpenOptions};
+    use std::fs::{create_dir, hard_link, File, OpenOptions};
     use std::io::{Read, Write};
     use std::path::PathBuf;
     use std::sync::atomic::{AtomicBool, Ordering};
     use std::sync::Mutex;
 
+    use fallible_iterator::FallibleIterator;
     use rand::seq::SliceRandom;
 
     use crate::path::Path;
+    use crate::report::open_report;
     use crate::util::test::*;
 
     use super::*;
@@ -951,10 +2731,10 @@ mod test {
     #[test]
     fn test_rehash_puts_files_with_different_hashes_to_different_groups() {
         let devices = DiskDevices::default();
-        let input = vec![FileGroup {
-            file_len: FileLen(200),
-            file_hash: FileHash(0),
-            files: vec![
+        let input = vec![FileGroup::new(
+            FileLen(200),
+            FileHash::from(0),
+            vec![
                 FileInfo {
                     len: FileLen(200),
                     location: 0,
@@ -966,7 +2746,7 @@ mod test {
                     path: Path::from("file2"),
                 },
             ],
-        }];
+        )];
 
         let result = rehash(
             input,
@@ -974,7 +2754,8 @@ mod test {
             |_| true,
             &devices,
             AccessType::Random,
-            |(fi, _)| Some(FileHash(fi.location as u128)),
+            |(fi, _)| Some(FileHash::from(fi.location as u128)),
+            None,
         );
 
         assert_eq!(result.len(), 2);
@@ -988,24 +2769,24 @@ mod test {
     fn test_rehash_puts_files_with_same_hashes_to_same_groups() {
         let devices = DiskDevices::default();
         let input = vec![
-            FileGroup {
-                file_len: FileLen(200),
-                file_hash: FileHash(0),
-                files: vec![FileInfo {
+            FileGroup::new(
+                FileLen(200),
+                FileHash::from(0),
+                vec![FileInfo {
                     len: FileLen(200),
                     location: 0,
                     path: Path::from("file1"),
                 }],
-            },
-            FileGroup {
-                file_len: FileLen(500),
-                file_hash: FileHash(0),
-                files: vec![FileInfo {
+            ),
+            FileGroup::new(
+                FileLen(500),
+                FileHash::from(0),
+                vec![FileInfo {
                     len: FileLen(200),
                     location: 35847587,
                     path: Path::from("file2"),
                 }],
-            },
+            ),
         ];
 
         let result = rehash(
@@ -1014,7 +2795,8 @@ mod test {
             |_| true,
             &devices,
             AccessType::Random,
-            |(_, _)| Some(FileHash(123456)),
+            |(_, _)| Some(FileHash::from(123456)),
+            None,
         );
 
         assert_eq!(result.len(), 1);
@@ -1024,15 +2806,15 @@ mod test {
     #[test]
     fn test_rehash_can_skip_processing_files() {
         let devices = DiskDevices::default();
-        let input = vec![FileGroup {
-            file_len: FileLen(200),
-            file_hash: FileHash(0),
-            files: vec![FileInfo {
+        let input = vec![FileGroup::new(
+            FileLen(200),
+            FileHash::from(0),
+            vec![FileInfo {
                 len: FileLen(200),
                 location: 0,
                 path: Path::from("file1"),
             }],
-        }];
+        )];
 
         let called = AtomicBool::new(false);
         let result = rehash(
@@ -1043,8 +2825,9 @@ mod test {
             AccessType::Random,
             |(fi, _)| {
                 called.store(true, Ordering::Release);
-                Some(FileHash(fi.location as u128))
+                Some(FileHash::from(fi.location as u128))
             },
+            None,
         );
 
         assert_eq!(result.len(), 1);
@@ -1054,10 +2837,10 @@ mod test {
     #[test]
     fn test_rehash_post_filter_removes_groups() {
         let devices = DiskDevices::default();
-        let input = vec![FileGroup {
-            file_len: FileLen(200),
-            file_hash: FileHash(0),
-            files: vec![
+        let input = vec![FileGroup::new(
+            FileLen(200),
+            FileHash::from(0),
+            vec![
                 FileInfo {
                     len: FileLen(200),
                     location: 0,
@@ -1069,7 +2852,7 @@ mod test {
                     path: Path::from("file2"),
                 },
             ],
-        }];
+        )];
 
         let result = rehash(
             input,
@@ -1077,7 +2860,8 @@ mod test {
             |g| g.files.len() >= 2,
             &devices,
             AccessType::Random,
-            |(fi, _)| Some(FileHash(fi.location as u128)),
+            |(fi, _)| Some(FileHash::from(fi.location as u128)),
+            None,
         );
 
         assert!(result.is_empty())
@@ -1090,15 +2874,15 @@ mod test {
         let count = 1000;
         let mut input = Vec::with_capacity(count);
         for i in 0..count {
-            input.push(FileGroup {
-                file_len: FileLen(0),
-                file_hash: FileHash(0),
-                files: vec![FileInfo {
+            input.push(FileGroup::new(
+                FileLen(0),
+                FileHash::from(0),
+                vec![FileInfo {
                     len: FileLen(0),
                     location: i as u64,
                     path: Path::from(format!("file{}", i)),
                 }],
-            })
+            ))
         }
         input.shuffle(&mut rand::thread_rng());
 
@@ -1111,8 +2895,9 @@ mod test {
             AccessType::Random,
             |(fi, _)| {
                 processing_order.lock().unwrap().push(fi.location as i32);
-                Some(FileHash(fi.location as u128))
+                Some(FileHash::from(fi.location as u128))
             },
+            None,
         );
         let processing_order = processing_order.into_inner().unwrap();
 
@@ -1146,61 +2931,413 @@ mod test {
     }
 
     #[test]
-    fn identical_large_files() {
-        with_dir("main/identical_large_files", |root| {
-            let file1 = root.join("file1");
-            let file2 = root.join("file2");
-            write_test_file(&file1, &[0; MAX_PREFIX_LEN], &[1; 4096], &[2; 4096]);
-            write_test_file(&file2, &[0; MAX_PREFIX_LEN], &[1; 4096], &[2; 4096]);
+    fn empty_files() {
+        with_dir("main/empty_files", |root| {
+            let mut paths = vec![];
+            for (i, contents) in [&b""[..], b"", b"", b"aaa", b"aaa"].iter().enumerate() {
+                let file = root.join(format!("file{}", i));
+                write_test_file(&file, contents, b"", b"");
+                paths.push(file);
+            }
 
             let log = test_log();
             let mut config = GroupConfig::default();
-            config.paths = vec![file1, file2];
-
+            config.paths = paths;
+            config.ignore_empty = true;
             let results = group_files(&config, &log).unwrap();
             assert_eq!(results.len(), 1);
-            assert_eq!(results[0].files.len(), 2);
-        });
-    }
-
-    #[test]
-    fn files_differing_by_size() {
-        with_dir("main/files_differing_by_size", |root| {
-            let file1 = root.join("file1");
-            let file2 = root.join("file2");
-            write_test_file(&file1, b"aaaa", b"", b"");
-            write_test_file(&file2, b"aaa", b"", b"");
-
-            let log = test_log();
-            let mut config = GroupConfig::default();
-            config.paths = vec![file1.clone(), file2.clone()];
-            config.rf_over = Some(0);
+            assert_eq!(results[0].file_len, FileLen(3));
 
+            config.ignore_empty = false;
+            config.empty_as_group = true;
+            config.min_size = FileLen(1);
+            config.hash_fn = vec![HashFn::Metro, HashFn::Sha256];
             let results = group_files(&config, &log).unwrap();
             assert_eq!(results.len(), 2);
+            let empty = results.iter().find(|g| g.file_len == FileLen(0)).unwrap();
+            assert_eq!(empty.files.len(), 3);
             assert_eq!(
-                results[0].files,
-                vec![Path::from(file1.canonicalize().unwrap())]
-            );
-            assert_eq!(
-                results[1].files,
-                vec![Path::from(file2.canonicalize().unwrap())]
+                empty.hashes.get(&HashFn::Sha256).map(|h| h.to_string()),
+                Some("e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_owned())
             );
+
+            config.rf_over = Some(3);
+            let results = group_files(&config, &log).unwrap();
+            assert!(results.iter().all(|g| g.file_len != FileLen(0)));
         });
     }
 
     #[test]
-    fn files_differing_by_prefix() {
-        with_dir("main/files_differing_by_prefix", |root| {
-            let file1 = root.join("file1");
-            let file2 = root.join("file2");
-            write_test_file(&file1, b"aaa", b"", b"");
-            write_test_file(&file2, b"bbb", b"", b"");
+    fn size_ranges() {
+        with_dir("main/size_ranges", |root| {
+            let mut paths = vec![];
+            for (i, len) in [10, 10, 100, 100, 1000, 1000].iter().enumerate() {
+                let file = root.join(format!("file{}", i));
+                write_test_file(&file, &vec![b'a'; *len], b"", b"");
+                paths.push(file);
+            }
 
             let log = test_log();
             let mut config = GroupConfig::default();
-            config.paths = vec![file1.clone(), file2.clone()];
-            config.unique = true;
+            config.paths = paths;
+            config.size_ranges = vec!["..50".parse().unwrap(), "500..".parse().unwrap()];
+            let mut results = group_files(&config, &log).unwrap();
+            results.sort_by_key(|g| g.file_len);
+            assert_eq!(results.len(), 2);
+            assert_eq!(results[0].file_len, FileLen(10));
+            assert_eq!(results[1].file_len, FileLen(1000));
+        });
+    }
+
+    #[test]
+    fn many_small_files() {
+        with_dir("main/many_small_files", |root| {
+            // More files than fit in a single batch, with a large file in between
+            let mut paths = vec![];
+            for i in 0..SMALL_FILE_BATCH_SIZE + 10 {
+                let file = root.join(format!("small{}", i));
+                write_test_file(&file, format!("{:08}", i / 2).as_bytes(), b"", b"");
+                paths.push(file);
+            }
+            for i in 0..2 {
+                let file = root.join(format!("large{}", i));
+                write_test_file(&file, &vec![b'a'; SMALL_FILE_LEN.0 as usize], b"", b"");
+                paths.push(file);
+            }
+
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = paths;
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), (SMALL_FILE_BATCH_SIZE + 10) / 2 + 1);
+            assert!(results.iter().all(|g| g.files.len() == 2));
+        });
+    }
+
+    #[test]
+    fn fingerprint_filter() {
+        with_dir("main/fingerprint_filter", |root| {
+            let head = vec![b'h'; 8192];
+            let tail = vec![b't'; 8192];
+            let files = [
+                ("dup1", b"same".as_ref()),
+                ("dup2", b"same".as_ref()),
+                ("mid1", b"mid1".as_ref()),
+                ("mid2", b"mid2".as_ref()),
+                ("unique", b"uniq".as_ref()),
+            ];
+            let mut paths = vec![];
+            for (name, mid) in files.iter() {
+                let file = root.join(name);
+                let tail = if *name == "unique" { &head } else { &tail };
+                write_test_file(&file, &head, mid, tail);
+                paths.push(file);
+            }
+
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = paths;
+            config.fingerprint_filter = true;
+            let results = group_files(&config, &log).unwrap();
+            // mid1 and mid2 share the fingerprint, because they differ only in the middle,
+            // but they are separated by the contents stage
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].files.len(), 2);
+            assert!(results[0].files[0].to_string().ends_with("dup1"));
+            assert!(results[0].files[1].to_string().ends_with("dup2"));
+        });
+    }
+
+    #[test]
+    fn quarantine_skips_listed_files() {
+        with_dir("main/quarantine_skips_listed_files", |root| {
+            let files: Vec<_> = (1..=3).map(|i| root.join(format!("file{}", i))).collect();
+            for f in files.iter() {
+                write_test_file(f, b"", b"same contents", b"");
+            }
+            let list = root.join("quarantine.txt");
+            std::fs::write(&list, format!("{}\n", files[2].display())).unwrap();
+
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = files.clone();
+            config.io_timeout = Some(Duration::from_secs(60));
+            config.quarantine = Some(list);
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].files.len(), 2);
+            assert!(!results[0].files.contains(&Path::from(&files[2])));
+        });
+    }
+
+    #[test]
+    fn unreadable_report() {
+        with_dir("main/unreadable_report", |root| {
+            let file = root.join("file");
+            let missing = root.join("missing");
+            write_test_file(&file, b"", b"contents", b"");
+            let report = root.join("unreadable.json");
+
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![file, missing.clone()];
+            config.unreadable_report = Some(report.clone());
+            group_files(&config, &log).unwrap();
+
+            let report: serde_json::Value =
+                serde_json::from_str(&std::fs::read_to_string(&report).unwrap()).unwrap();
+            let files = report["files"].as_array().unwrap();
+            assert_eq!(files.len(), 1);
+            assert_eq!(files[0]["path"], missing.to_str().unwrap());
+            assert_eq!(files[0]["operation"], "stat");
+            assert_eq!(files[0]["error_kind"], "NotFound");
+            assert!(files[0]["mount_point"].is_string());
+        });
+    }
+
+    #[test]
+    fn log_json_events() {
+        with_dir("main/log_json_events", |root| {
+            let small = root.join("small");
+            let missing = root.join("missing");
+            write_test_file(&small, b"", b"s", b"");
+            write_test_file(&root.join("big1"), b"", b"big file", b"");
+            write_test_file(&root.join("big2"), b"", b"big file", b"");
+            let events_file = root.join("events.json");
+
+            let log = test_log();
+            log.write_events_to(Box::new(File::create(&events_file).unwrap()));
+            let mut config = GroupConfig::default();
+            config.paths = vec![root.join("big1"), root.join("big2"), small, missing];
+            config.min_size = FileLen(2);
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            log.flush_events();
+
+            let events: Vec<serde_json::Value> = std::fs::read_to_string(&events_file)
+                .unwrap()
+                .lines()
+                .map(|line| serde_json::from_str(line).unwrap())
+                .collect();
+            let kind_of = |name: &str| {
+                events
+                    .iter()
+                    .find(|e| e["path"].as_str().map_or(false, |p| p.ends_with(name)))
+                    .map(|e| e["kind"].as_str().unwrap().to_owned())
+            };
+            assert_eq!(kind_of("small").as_deref(), Some("filtered"));
+            assert_eq!(kind_of("missing").as_deref(), Some("unreadable"));
+            assert_eq!(kind_of("big1"), None);
+        });
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn allocated_size_ranges() {
+        with_dir("main/allocated_size_ranges", |root| {
+            let mut paths = vec![];
+            for i in 0..4 {
+                let file = root.join(format!("file{}", i));
+                if i < 2 {
+                    write_test_file(&file, &vec![b'a'; 1 << 20], b"", b"");
+                } else {
+                    // sparse file of the same length, taking almost no disk space
+                    File::create(&file).unwrap().set_len(1 << 20).unwrap();
+                }
+                paths.push(file);
+            }
+
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = paths;
+            config.allocated_size_ranges = vec!["512K..".parse().unwrap()];
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].files.len(), 2);
+            assert!(results[0]
+                .files
+                .iter()
+                .all(|f| f.to_string().ends_with("file0") || f.to_string().ends_with("file1")));
+        });
+    }
+
+    #[test]
+    fn replication_factor_range() {
+        with_dir("main/rf_range", |root| {
+            let mut paths = vec![];
+            for (i, contents) in [b"a", b"b", b"b", b"c", b"c", b"c"].iter().enumerate() {
+                let file = root.join(format!("file{}", i));
+                write_test_file(&file, *contents, b"", b"");
+                paths.push(file);
+            }
+
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = paths;
+            config.rf_under = Some(3);
+            let results = group_files(&config, &log).unwrap();
+            let mut counts: Vec<_> = results.iter().map(|g| g.files.len()).collect();
+            counts.sort_unstable();
+            assert_eq!(counts, vec![1, 2]);
+
+            config.rf_over = Some(1);
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].files.len(), 2);
+
+            config.rf_under = Some(2);
+            assert!(group_files(&config, &log).is_err());
+        });
+    }
+
+    #[test]
+    fn other_hashes_of_small_files() {
+        with_dir("main/other_hashes", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            write_test_file(&file1, b"abc", b"", b"");
+            write_test_file(&file2, b"abc", b"", b"");
+
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![file1, file2];
+            config.hash_fn = vec![HashFn::Metro, HashFn::Sha256];
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].files.len(), 2);
+            assert_eq!(
+                results[0]
+                    .hashes
+                    .get(&HashFn::Sha256)
+                    .map(|h| h.to_string()),
+                Some("ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_owned())
+            );
+        });
+    }
+
+    #[test]
+    fn prefix_hash_of_small_files_is_contents_hash() {
+        with_dir("main/prefix_hash_of_small_files", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            write_test_file(&file1, b"abc", b"", b"");
+            write_test_file(&file2, b"abc", b"", b"");
+
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![file1.clone(), file2];
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            let contents_hash = file_hash(
+                &Path::from(&file1),
+                FilePos(0),
+                FileLen(3),
+                4096,
+                Caching::Default,
+                |_| {},
+            );
+            assert_eq!(results[0].file_hash, contents_hash.unwrap());
+        });
+    }
+
+    #[test]
+    fn cancelled_grouping_fails() {
+        with_dir("main/cancelled_grouping", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            write_test_file(&file1, b"abc", b"", b"");
+            write_test_file(&file2, b"abc", b"", b"");
+
+            let log = test_log();
+            let cancel = Arc::new(AtomicBool::new(true));
+            let config = GroupConfig::builder()
+                .paths(vec![file1, file2])
+                .cancel(cancel)
+                .build();
+            let result = group_files(&config, &log);
+            assert_eq!(result.unwrap_err().message, "Cancelled");
+        });
+    }
+
+    #[test]
+    fn progress_counts_all_hashed_bytes() {
+        with_dir("main/progress_counts_all_hashed_bytes", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            write_test_file(&file1, &[0; MAX_PREFIX_LEN], &[1; 4096], &[2; 4096]);
+            write_test_file(&file2, &[0; MAX_PREFIX_LEN], &[1; 4096], &[2; 4096]);
+
+            let log = test_log();
+            let progress = Arc::new(StageProgress::default());
+            let config = GroupConfig::builder()
+                .paths(vec![file1.clone(), file2])
+                .progress(progress.clone())
+                .build();
+            group_files(&config, &log).unwrap();
+            let snapshot = progress.snapshot();
+            let file_len = file1.metadata().unwrap().len();
+            assert_eq!(snapshot.stage, Some("contents"));
+            assert_eq!(snapshot.bytes_total, 2 * file_len);
+            assert_eq!(snapshot.bytes_done, 2 * file_len);
+        });
+    }
+
+    #[test]
+    fn identical_large_files() {
+        with_dir("main/identical_large_files", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            write_test_file(&file1, &[0; MAX_PREFIX_LEN], &[1; 4096], &[2; 4096]);
+            write_test_file(&file2, &[0; MAX_PREFIX_LEN], &[1; 4096], &[2; 4096]);
+
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![file1, file2];
+
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].files.len(), 2);
+        });
+    }
+
+    #[test]
+    fn files_differing_by_size() {
+        with_dir("main/files_differing_by_size", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            write_test_file(&file1, b"aaaa", b"", b"");
+            write_test_file(&file2, b"aaa", b"", b"");
+
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![file1.clone(), file2.clone()];
+            config.rf_over = Some(0);
+
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 2);
+            assert_eq!(
+                results[0].files,
+                vec![Path::from(file1.canonicalize().unwrap())]
+            );
+            assert_eq!(
+                results[1].files,
+                vec![Path::from(file2.canonicalize().unwrap())]
+            );
+        });
+    }
+
+    #[test]
+    fn files_differing_by_prefix() {
+        with_dir("main/files_differing_by_prefix", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            write_test_file(&file1, b"aaa", b"", b"");
+            write_test_file(&file2, b"bbb", b"", b"");
+
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![file1.clone(), file2.clone()];
+            config.unique = true;
 
             let results = group_files(&config, &log).unwrap();
             assert_eq!(results.len(), 2);
@@ -1289,6 +3426,32 @@ mod test {
         });
     }
 
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn duplicate_input_files_differing_in_case() {
+        with_dir("main/duplicate_input_files_differing_in_case", |root| {
+            // Simulates a case-insensitive file system with a hard link:
+            let file1 = root.join("file1");
+            let file2 = root.join("FILE1");
+            write_test_file(&file1, b"foo", b"", b"");
+            hard_link(&file1, &file2).unwrap();
+
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![root.clone()];
+            config.hard_links = true;
+            config.case.case_insensitive = true;
+            let results = group_files(&config, &log).unwrap();
+            assert!(results.is_empty());
+
+            config.case.case_insensitive = false;
+            config.case.case_sensitive = true;
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].files.len(), 2);
+        });
+    }
+
     #[test]
     #[cfg(unix)]
     fn duplicate_input_files_non_canonical() {
@@ -1296,7 +3459,7 @@ mod test {
 
         with_dir("main/duplicate_input_files_non_canonical", |root| {
             let dir = root.join("dir");
-            symlink(&root, &dir).unwrap();
+            symlink(root, &dir).unwrap();
 
             let file1 = root.join("file1");
             let file2 = root.join("dir/file1");
@@ -1315,40 +3478,803 @@ mod test {
     }
 
     #[test]
-    fn report() {
-        with_dir("main/report", |root| {
-            let file = root.join("file1");
+    #[cfg(unix)]
+    fn reported_symlinks_are_separate_entries() {
+        use std::os::unix::fs::symlink;
+
+        with_dir("main/reported_symlinks_are_separate_entries", |root| {
+            let file = root.join("file");
+            let link = root.join("link");
             write_test_file(&file, b"foo", b"", b"");
+            symlink(&file, &link).unwrap();
 
-            let report_file = root.join("report.txt");
             let log = test_log();
             let mut config = GroupConfig::default();
-            config.paths = vec![file.clone()];
+            config.paths = vec![root.clone()];
+            config.report_symlinks = true;
+
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].files.len(), 2);
+        });
+    }
+
+    #[test]
+    fn report_errors_lists_only_unreadable_paths() {
+        with_dir("main/report_errors_lists_only_unreadable_paths", |root| {
+            let missing = root.join("missing");
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![missing.clone()];
+            config.report_errors = true;
+
+            log.record_scan_errors();
+            log.warn("Unrelated warning");
+            group_files(&config, &log).unwrap();
+            let errors = log.take_scan_errors();
+            assert_eq!(errors.len(), 1);
+            assert!(errors[0].contains(&missing.display().to_string()));
+        });
+    }
+
+    #[test]
+    fn modified_before_filter() {
+        with_dir("main/modified_before_filter", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            write_test_file(&file1, b"foo", b"", b"");
+            write_test_file(&file2, b"foo", b"", b"");
+            let old_time = filetime::FileTime::from_unix_time(1_000_000_000, 0);
+            filetime::set_file_mtime(&file1, old_time).unwrap();
+
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![file1.clone(), file2.clone()];
+            config.modified_before = Some(Local::now().into());
+
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].files.len(), 2);
+
             config.unique = true;
-            config.output = Some(report_file.clone());
+            config.modified_before = Some((Local::now() - chrono::Duration::days(1)).into());
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].files[0], Path::from(&file1));
+        });
+    }
+
+    #[test]
+    fn overlapping_roots() {
+        with_dir("main/overlapping_roots", |root| {
+            let dir = root.join("dir");
+            create_dir(&dir).unwrap();
+            let file1 = dir.join("file1");
+            let file2 = dir.join("file2");
+            write_test_file(&file1, b"foo", b"", b"");
+            write_test_file(&file2, b"foo", b"", b"");
 
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![dir.clone(), root.clone()];
             let results = group_files(&config, &log).unwrap();
-            write_report(&config, &log, &results).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].files.len(), 2);
 
-            assert!(report_file.exists());
-            let mut report = String::new();
-            File::open(report_file)
-                .unwrap()
-                .read_to_string(&mut report)
+            config.overlapping_roots = OverlapPolicy::Error;
+            assert!(group_files(&config, &log).is_err());
+        });
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn symlinked_root_is_kept_as_given() {
+        use std::os::unix::fs::symlink;
+
+        with_dir("main/symlinked_root_is_kept_as_given", |root| {
+            let dir = root.join("dir");
+            let link = root.join("link");
+            create_dir(&dir).unwrap();
+            symlink(&dir, &link).unwrap();
+            write_test_file(&dir.join("file1"), b"foo", b"", b"");
+            write_test_file(&dir.join("file2"), b"foo", b"", b"");
+
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![link.clone()];
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            let link = Path::from(&link);
+            assert!(results[0].files.iter().all(|f| link.is_prefix_of(f)));
+        });
+    }
+
+    #[test]
+    fn deterministic_order() {
+        with_dir("main/deterministic_order", |root| {
+            for (name, content) in [("a", b"foo"), ("b", b"bar"), ("c", b"baz"), ("d", b"foo")] {
+                write_test_file(&root.join(name), content, b"", b"");
+            }
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![root.clone()];
+            config.unique = true;
+            config.deterministic = true;
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 2);
+            for g in results.windows(2) {
+                assert!((&g[0].file_hash, &g[0].files) < (&g[1].file_hash, &g[1].files));
+            }
+        });
+    }
+
+    #[test]
+    fn streamed_groups() {
+        with_dir("main/streamed_groups", |root| {
+            let files = [("a", b"foo"), ("b", b"foo"), ("c", b"ba1"), ("d", b"ba1")];
+            for (name, content) in files {
+                write_test_file(&root.join(name), content, b"", b"");
+            }
+            write_test_file(&root.join("e"), b"longer", b"", b"");
+            write_test_file(&root.join("f"), b"longer", b"", b"");
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![root.clone()];
+
+            let mut streamed = Vec::new();
+            let summary = group_files_to_sink(&config, &log, |g| streamed.push(g)).unwrap();
+            streamed.sort_by(|a, b| (b.file_len, &b.file_hash).cmp(&(a.file_len, &a.file_hash)));
+            assert_eq!(streamed, group_files(&config, &log).unwrap());
+            assert_eq!(summary.stages.last().unwrap().file_count, 6);
+
+            let output = root.join("report.json");
+            config.output = Some(output.clone());
+            config.format = OutputFormat::Json;
+            write_report_streaming(&config, &log, streamed).unwrap();
+            let mut reader = open_report(File::open(output).unwrap()).unwrap();
+            let stats = reader.read_header().unwrap().stats.unwrap();
+            assert_eq!(stats.group_count, 3);
+            assert_eq!(stats.redundant_file_count, 3);
+            assert_eq!(stats.redundant_file_size, FileLen(2 * 3 + 6));
+        });
+    }
+
+    #[test]
+    fn baseline_hashes_are_reused() {
+        with_dir("main/baseline", |root| {
+            for name in ["a", "b", "c"] {
+                write_test_file(&root.join(name), b"foo", b"", b"");
+            }
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![root.join("a"), root.join("b"), root.join("c")];
+            config.output = Some(root.join("baseline.json"));
+            config.format = OutputFormat::Json;
+            let results = group_files(&config, &log).unwrap();
+            write_report(&config, &log, &results).unwrap();
+
+            // Change the contents, but not the size of one of the files
+            // and add a new file with the same contents as the unchanged files:
+            let future = filetime::FileTime::from_unix_time(i32::MAX as i64, 0);
+            write_test_file(&root.join("c"), b"bar", b"", b"");
+            filetime::set_file_mtime(root.join("c"), future).unwrap();
+            write_test_file(&root.join("d"), b"foo", b"", b"");
+
+            config.baseline = config.output.take();
+            config.paths.push(root.join("d"));
+            let (results, summary) = group_files_with_summary(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(
+                results[0].files,
+                vec![root.join("a"), root.join("b"), root.join("d")]
+                    .into_iter()
+                    .map(Path::from)
+                    .collect_vec()
+            );
+            assert_eq!(summary.bytes_hashed, FileLen(6));
+        });
+    }
+
+    #[test]
+    fn baseline_groups_have_other_hashes() {
+        with_dir("main/baseline_other_hashes", |root| {
+            for name in ["a", "b"] {
+                write_test_file(&root.join(name), b"foo", b"", b"");
+            }
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![root.join("a"), root.join("b")];
+            config.output = Some(root.join("baseline.json"));
+            config.format = OutputFormat::Json;
+            let results = group_files(&config, &log).unwrap();
+            write_report(&config, &log, &results).unwrap();
+
+            config.baseline = config.output.take();
+            config.hash_fn = vec![HashFn::Metro, HashFn::Sha256];
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(
+                results[0]
+                    .hashes
+                    .get(&HashFn::Sha256)
+                    .map(|h| h.to_string()),
+                Some("2c26b46b68ffc68ff99b453c1d30413413422d706483bfa0f98a5e886266e7ae".to_owned())
+            );
+        });
+    }
+
+    #[test]
+    fn baseline_of_other_hash_fn_is_rejected() {
+        with_dir("main/baseline_hash_fn", |root| {
+            for name in ["a", "b"] {
+                write_test_file(&root.join(name), b"foo", b"", b"");
+            }
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![root.join("a"), root.join("b")];
+            config.output = Some(root.join("baseline.json"));
+            config.format = OutputFormat::Json;
+            let results = group_files(&config, &log).unwrap();
+            write_report(&config, &log, &results).unwrap();
+
+            // The report is stamped with the start of the scan, not the time it was written:
+            let report = config.output.take().unwrap();
+            let mut reader = open_report(File::open(&report).unwrap()).unwrap();
+            let header = reader.read_header().unwrap();
+            assert_eq!(Some(header.timestamp), log.scan_start());
+            assert_eq!(header.hash, Some(GroupingHash::CURRENT));
+
+            let json = fs::read_to_string(&report).unwrap();
+            let json = json.replace("\"metro\"", "\"sha256\"");
+            fs::write(&report, json).unwrap();
+            config.baseline = Some(report);
+            assert!(group_files(&config, &log).is_err());
+        });
+    }
+
+    #[test]
+    fn isolate_roots() {
+        with_dir("main/isolate", |root| {
+            let (a, b) = (root.join("a"), root.join("b"));
+            create_dir(&a).unwrap();
+            create_dir(&b).unwrap();
+            write_test_file(&a.join("1"), b"across", b"", b"");
+            write_test_file(&b.join("1"), b"across", b"", b"");
+            write_test_file(&b.join("2"), b"within b", b"", b"");
+            write_test_file(&b.join("3"), b"within b", b"", b"");
+            write_test_file(&a.join("2"), b"within both", b"", b"");
+            write_test_file(&a.join("3"), b"within both", b"", b"");
+            write_test_file(&b.join("4"), b"within both", b"", b"");
+            write_test_file(&b.join("5"), b"within both", b"", b"");
+
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![b.clone(), a.clone()];
+            config.isolate = true;
+            let results = group_files(&config, &log).unwrap();
+            let files = results.into_iter().map(|g| g.files).collect_vec();
+            assert_eq!(files.len(), 3);
+            assert!(files.contains(&vec![Path::from(b.join("2")), Path::from(b.join("3"))]));
+            assert!(files.contains(&vec![Path::from(b.join("4")), Path::from(b.join("5"))]));
+            // groups are ordered by the input path:
+            assert_eq!(
+                files[2],
+                vec![Path::from(a.join("2")), Path::from(a.join("3"))]
+            );
+        });
+    }
+
+    #[test]
+    fn isolate_nested_roots() {
+        with_dir("main/isolate_nested", |root| {
+            let (a, b) = (root.join("a"), root.join("a").join("b"));
+            create_dir(&a).unwrap();
+            create_dir(&b).unwrap();
+            write_test_file(&a.join("1"), b"across", b"", b"");
+            write_test_file(&b.join("1"), b"across", b"", b"");
+            write_test_file(&b.join("2"), b"within b", b"", b"");
+            write_test_file(&b.join("3"), b"within b", b"", b"");
+            write_test_file(&a.join("2"), b"within a", b"", b"");
+            write_test_file(&a.join("3"), b"within a", b"", b"");
+
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            // With limited depth, both nested roots are scanned:
+            config.paths = vec![a.clone(), b.clone()];
+            config.depth = Some(2);
+            config.isolate = true;
+            let results = group_files(&config, &log).unwrap();
+            let files = results.into_iter().map(|g| g.files).collect_vec();
+            assert_eq!(
+                files,
+                vec![
+                    vec![Path::from(a.join("2")), Path::from(a.join("3"))],
+                    vec![Path::from(b.join("2")), Path::from(b.join("3"))],
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn compare_reports_only_groups_spanning_both_roots() {
+        with_dir("main/compare", |root| {
+            let (a, b) = (root.join("a"), root.join("b"));
+            create_dir(&a).unwrap();
+            create_dir(&b).unwrap();
+            write_test_file(&a.join("1"), b"across", b"", b"");
+            write_test_file(&b.join("1"), b"across", b"", b"");
+            write_test_file(&a.join("2"), b"within", b"", b"");
+            write_test_file(&a.join("3"), b"within", b"", b"");
+
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![a.clone(), b.clone()];
+            config.compare = true;
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(
+                results[0].files,
+                vec![Path::from(a.join("1")), Path::from(b.join("1"))]
+            );
+
+            config.paths = vec![root.clone(), b];
+            assert!(group_files(&config, &log).is_err());
+        });
+    }
+
+    #[test]
+    fn missing_lists_files_without_copy_under_second_root() {
+        with_dir("main/missing", |root| {
+            let (a, b) = (root.join("a"), root.join("b"));
+            create_dir(&a).unwrap();
+            create_dir(&b).unwrap();
+            write_test_file(&a.join("1"), b"backed up", b"", b"");
+            write_test_file(&b.join("1"), b"backed up", b"", b"");
+            write_test_file(&a.join("2"), b"not backed up", b"", b"");
+            write_test_file(&a.join("3"), b"not backed up", b"", b"");
+            write_test_file(&a.join("4"), b"single missing", b"", b"");
+            write_test_file(&b.join("5"), b"only in backup", b"", b"");
+
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![a.clone(), b.clone()];
+            config.compare = true;
+            config.missing = true;
+            let results = group_files(&config, &log).unwrap();
+            let files = results.into_iter().map(|g| g.files).collect_vec();
+            assert_eq!(files.len(), 2);
+            assert!(files.contains(&vec![Path::from(a.join("2")), Path::from(a.join("3"))]));
+            assert!(files.contains(&vec![Path::from(a.join("4"))]));
+
+            // Swapping the roots lists the files missing from the first one:
+            config.paths = vec![b.clone(), a];
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].files, vec![Path::from(b.join("5"))]);
+        });
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn match_xattrs() {
+        with_dir("main/match_xattrs", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            write_test_file(&file1, b"foo", b"", b"");
+            write_test_file(&file2, b"foo", b"", b"");
+            if !write_xattr(&file1, "user.fclones.test", b"bar") {
+                return;
+            }
+
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![root.clone()];
+            assert_eq!(group_files(&config, &log).unwrap().len(), 1);
+            config.match_acl = true;
+            assert_eq!(group_files(&config, &log).unwrap().len(), 1);
+            config.match_xattrs = true;
+            assert!(group_files(&config, &log).unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn select_groups_by_hash_or_contained_file() {
+        with_dir("main/select_groups", |root| {
+            let file1 = root.join("file1");
+            write_test_file(&file1, b"foo", b"", b"");
+            write_test_file(&root.join("file2"), b"foo", b"", b"");
+            write_test_file(&root.join("file3"), b"bar", b"", b"");
+            write_test_file(&root.join("file4"), b"bar", b"", b"");
+
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![root.clone()];
+            let all = group_files(&config, &log).unwrap();
+            assert_eq!(all.len(), 2);
+
+            config.containing = vec![file1.clone()];
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results.len(), 1);
+            assert!(results[0].files.contains(&Path::from(&file1)));
+
+            config.containing = vec![];
+            config.with_hash = vec![all[1].file_hash.clone()];
+            let results = group_files(&config, &log).unwrap();
+            assert_eq!(results, vec![all[1].clone()]);
+        });
+    }
+
+    #[test]
+    fn summary() {
+        with_dir("main/summary", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            let file3 = root.join("file3");
+            write_test_file(&file1, b"foo", b"", b"");
+            write_test_file(&file2, b"foo", b"", b"");
+            write_test_file(&file3, b"", b"", b"");
+            write_test_file(&root.join(".hidden"), b"foo", b"", b"");
+            write_test_file(&root.join("file4.tmp"), b"foo", b"", b"");
+
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![root.clone()];
+            config.min_size = FileLen(1);
+            config.skip_hidden = true;
+            config.exclude_patterns = vec!["**/*.tmp".to_owned()];
+
+            let (groups, summary) = group_files_with_summary(&config, &log).unwrap();
+            assert_eq!(groups.len(), 1);
+            assert_eq!(summary.scanned_entries, 6);
+            assert_eq!(summary.scanned_files, 3);
+            assert_eq!(summary.skipped_hidden, 1);
+            assert_eq!(summary.skipped_by_name, 1);
+            assert_eq!(summary.skipped_by_size, 1);
+            assert_eq!(summary.skipped_by_time, 0);
+            assert_eq!(summary.bytes_hashed, FileLen(6));
+            assert_eq!(summary.stages.first().unwrap().file_count, 2);
+            assert_eq!(summary.stages.last().unwrap().file_count, 2);
+            assert_eq!(summary.error_count(), 0);
+            assert_eq!(summary.events.filtered, 2);
+            assert_eq!(summary.events.unreadable, 0);
+        });
+    }
+
+    #[test]
+    fn ignore_file_patterns_are_merged_with_exclude_patterns() {
+        with_dir("main/ignore_file", |root| {
+            let dir = root.join("dir");
+            create_dir(&dir).unwrap();
+            let cache = dir.join("cache");
+            create_dir(&cache).unwrap();
+            for f in ["file1", "file2", "file3.tmp", "file4.bak", "cache/file5"].iter() {
+                write_test_file(&dir.join(f), b"foo", b"", b"");
+            }
+            let ignore_file = root.join("ignore");
+            std::fs::write(&ignore_file, "# temporary files\n**/*.tmp\ncache/\n").unwrap();
+
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![dir.clone()];
+            config.exclude_patterns = vec!["**/*.bak".to_owned()];
+            config.ignore_file = Some(ignore_file);
+            let groups = group_files(&config, &log).unwrap();
+            assert_eq!(groups.len(), 1);
+            let mut files = groups[0].files.clone();
+            files.sort();
+            assert_eq!(
+                files,
+                vec![Path::from(dir.join("file1")), Path::from(dir.join("file2"))]
+            );
+        });
+    }
+
+    #[test]
+    fn missing_or_unreadable_ignore_file_is_an_error() {
+        with_dir("main/ignore_file_errors", |root| {
+            write_test_file(&root.join("file1"), b"foo", b"", b"");
+            write_test_file(&root.join("file2"), b"foo", b"", b"");
+
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![root.clone()];
+            config.ignore_file = Some(root.join("missing"));
+            let err = group_files(&config, &log).unwrap_err();
+            assert!(err.message.contains("Failed to read ignore file"));
+
+            // A directory can't be read as an ignore file:
+            config.ignore_file = Some(root.clone());
+            let err = group_files(&config, &log).unwrap_err();
+            assert!(err.message.contains("Failed to read ignore file"));
+
+            config.no_ignore_file = true;
+            assert_eq!(group_files(&config, &log).unwrap().len(), 1);
+        });
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn allocated_size_of_sparse_files() {
+        with_dir("main/allocated_size", |root| {
+            let len = 1024 * 1024;
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            File::create(&file1).unwrap().set_len(len).unwrap();
+            File::create(&file2).unwrap().set_len(len).unwrap();
+
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![file1, file2];
+            let groups = group_files(&config, &log).unwrap();
+            assert_eq!(groups.selected_size(1, usize::MAX), FileLen(len));
+            assert!(allocated_sizes(&groups, 1, &log)[0] < FileLen(len));
+        });
+    }
+
+    #[test]
+    fn report() {
+        with_dir("main/report", |root| {
+            let file = root.join("file1");
+            write_test_file(&file, b"foo", b"", b"");
+
+            let report_file = root.join("report.txt");
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![file.clone()];
+            config.unique = true;
+            config.output = Some(report_file.clone());
+
+            let results = group_files(&config, &log).unwrap();
+            write_report(&config, &log, &results).unwrap();
+
+            assert!(report_file.exists());
+            let mut report = String::new();
+            File::open(report_file)
+                .unwrap()
+                .read_to_string(&mut report)
                 .unwrap();
             assert!(report.contains("file1"))
         });
     }
 
+    #[test]
+    fn report_to_multiple_outputs() {
+        with_dir("main/report_multiple_outputs", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            write_test_file(&file1, b"foo", b"", b"");
+            write_test_file(&file2, b"foo", b"", b"");
+
+            let json_file = root.join("report.json");
+            let summary_file = root.join("summary.txt");
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![file1, file2];
+            config.output = Some(json_file.clone());
+            config.format = OutputFormat::Json;
+            config.extra_outputs = vec![format!("summary:{}", summary_file.display())
+                .parse()
+                .unwrap()];
+
+            let check = || {
+                let json = std::fs::read_to_string(&json_file).unwrap();
+                assert!(json.starts_with('{'));
+                assert!(json.contains("file1"));
+                let summary = std::fs::read_to_string(&summary_file).unwrap();
+                assert!(!summary.contains("file1"));
+            };
+
+            let results = group_files(&config, &log).unwrap();
+            write_report(&config, &log, &results).unwrap();
+            check();
+
+            std::fs::remove_file(&json_file).unwrap();
+            std::fs::remove_file(&summary_file).unwrap();
+            write_report_streaming(&config, &log, results).unwrap();
+            check();
+        });
+    }
+
+    #[test]
+    fn report_to_other_outputs_when_one_fails() {
+        with_dir("main/report_other_outputs", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            write_test_file(&file1, b"foo", b"", b"");
+            write_test_file(&file2, b"foo", b"", b"");
+
+            let summary_file = root.join("summary.txt");
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![file1, file2];
+            config.output = Some(root.join("missing").join("report.txt"));
+            config.extra_outputs = vec![format!("summary:{}", summary_file.display())
+                .parse()
+                .unwrap()];
+
+            let results = group_files(&config, &log).unwrap();
+            assert!(write_report(&config, &log, &results).is_err());
+            assert!(summary_file.exists());
+        });
+    }
+
+    #[test]
+    fn report_relative_paths() {
+        with_dir("main/report_relative_paths", |root| {
+            let dir = root.join("dir");
+            create_dir(&dir).unwrap();
+            write_test_file(&dir.join("file1"), b"foo", b"", b"");
+            write_test_file(&dir.join("file2"), b"foo", b"", b"");
+
+            let report_file = root.join("report.txt");
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![dir];
+            config.output = Some(report_file.clone());
+            config.relative_to = Some(root.clone());
+
+            let results = group_files(&config, &log).unwrap();
+            write_report(&config, &log, &results).unwrap();
+
+            let mut reader = crate::report::open_report(File::open(report_file).unwrap()).unwrap();
+            reader.read_header().unwrap();
+            let groups: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+            assert_eq!(groups.len(), 1);
+            assert_eq!(
+                groups[0].files,
+                vec![Path::from("dir/file1"), Path::from("dir/file2")]
+            );
+        });
+    }
+
+    #[test]
+    fn report_device_metadata() {
+        with_dir("main/report_device_metadata", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            write_test_file(&file1, b"foo", b"", b"");
+            write_test_file(&file2, b"foo", b"", b"");
+
+            let report_file = root.join("report.json");
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![root.clone()];
+            config.output = Some(report_file.clone());
+            config.format = OutputFormat::Json;
+            config.include_metadata = vec![FileMetadataKind::Device];
+
+            let results = group_files(&config, &log).unwrap();
+            write_report(&config, &log, &results).unwrap();
+
+            let report: serde_json::Value =
+                serde_json::from_reader(File::open(&report_file).unwrap()).unwrap();
+            let group = &report["groups"][0];
+            let devices = group["devices"].as_array().unwrap();
+            assert_eq!(devices.len(), 2);
+            let mount_point = devices[0]["mount_point"].as_str().unwrap();
+            assert!(Path::from(mount_point).is_prefix_of(&Path::from(&file1)));
+            assert!(devices[0]["device"].is_string());
+
+            // The report can still be read back:
+            let mut reader = open_report(File::open(report_file).unwrap()).unwrap();
+            reader.read_header().unwrap();
+            let groups: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+            assert_eq!(groups[0].files.len(), 2);
+        });
+    }
+
+    #[test]
+    fn reject_device_metadata_for_text_formats() {
+        with_dir("main/reject_device_metadata", |root| {
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![root.clone()];
+            config.include_metadata = vec![FileMetadataKind::Device];
+            for format in [
+                OutputFormat::Default,
+                OutputFormat::TextV2,
+                OutputFormat::Fdupes,
+            ] {
+                config.format = format;
+                assert!(group_files(&config, &log).is_err());
+            }
+            config.format = OutputFormat::Csv;
+            assert!(group_files(&config, &log).is_ok());
+        });
+    }
+
+    #[test]
+    fn report_link_clusters() {
+        with_dir("main/report_link_clusters", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            let file3 = root.join("file3");
+            write_test_file(&file1, b"foo", b"", b"");
+            write_test_file(&file2, b"foo", b"", b"");
+            hard_link(&file1, &file3).unwrap();
+
+            let report_file = root.join("report.txt");
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![root.clone()];
+            config.output = Some(report_file.clone());
+            config.hard_links = true;
+            config.deterministic = true;
+            config.link_clusters = true;
+
+            let results = group_files(&config, &log).unwrap();
+            write_report(&config, &log, &results).unwrap();
+
+            let mut report = String::new();
+            File::open(&report_file)
+                .unwrap()
+                .read_to_string(&mut report)
+                .unwrap();
+            assert!(report.contains("  # Link cluster 1:\n"));
+            assert!(report.contains("  # Not linked\n"));
+
+            let mut reader = open_report(File::open(report_file).unwrap()).unwrap();
+            let header = reader.read_header().unwrap();
+            assert_eq!(
+                header.link_stats,
+                Some(LinkStats {
+                    file_count: 1,
+                    file_size: FileLen(3)
+                })
+            );
+            let groups: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+            assert_eq!(groups.len(), 1);
+            assert_eq!(
+                groups[0].files,
+                vec![Path::from(file1), Path::from(file3), Path::from(file2)]
+            );
+        });
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn owner_stats_use_owners_found_by_scan() {
+        use std::os::unix::fs::MetadataExt;
+
+        with_dir("main/owner_stats", |root| {
+            let file1 = root.join("file1");
+            let file2 = root.join("file2");
+            write_test_file(&file1, b"foo", b"", b"");
+            write_test_file(&file2, b"foo", b"", b"");
+            let uid = fs::metadata(&file1).unwrap().uid();
+
+            let report_file = root.join("report.txt");
+            let log = test_log();
+            let mut config = GroupConfig::default();
+            config.paths = vec![root.clone()];
+            config.output = Some(report_file.clone());
+            config.stats_by = Some(StatsBy::Owner);
+
+            let results = group_files(&config, &log).unwrap();
+            // the owners must not be read again after scanning
+            fs::remove_file(&file1).unwrap();
+            fs::remove_file(&file2).unwrap();
+            write_report(&config, &log, &results).unwrap();
+
+            let mut reader = open_report(File::open(report_file).unwrap()).unwrap();
+            let header = reader.read_header().unwrap();
+            assert_eq!(header.owner_stats.len(), 1);
+            assert_eq!(header.owner_stats[0].uid, uid);
+            assert_eq!(header.owner_stats[0].file_count, 2);
+            assert!(log.take_owners().is_empty());
+        });
+    }
+
     fn write_test_file(path: &PathBuf, prefix: &[u8], mid: &[u8], suffix: &[u8]) {
         let mut file = OpenOptions::new()
             .write(true)
             .create(true)
-            .open(&path)
+            .truncate(true)
+            .open(path)
             .unwrap();
-        file.write(prefix).unwrap();
-        file.write(mid).unwrap();
-        file.write(suffix).unwrap();
+        file.write_all(prefix).unwrap();
+        file.write_all(mid).unwrap();
+        file.write_all(suffix).unwrap();
     }
 
     fn test_log() -> Log {
@@ -2,6 +2,7 @@ use std::fs::File;
 use std::{fs, io};
 
 use crate::path::Path;
+use crate::util::with_context;
 
 /// Portable file locking.
 ///
@@ -55,6 +56,13 @@ impl FileLock {
         Self::nix_as_io_error(result).map(|_| {})
     }
 
+    /// Locks a file already open for writing.
+    #[cfg(unix)]
+    pub(crate) fn from_file(file: File) -> io::Result<FileLock> {
+        Self::fcntl_lock(&file)?;
+        Ok(FileLock { file })
+    }
+
     /// Locks a file and obtains its metadata.
     /// On error, the error message will contain the path.
     pub fn new(path: &Path) -> io::Result<FileLock> {
@@ -64,15 +72,13 @@ impl FileLock {
             .write(true)
             .create(false)
             .open(&path_buf)
-            .map_err(|e| {
-                io::Error::new(e.kind(), format!("Failed to open file {}: {}", path, e))
-            })?;
+            .map_err(|e| with_context(format!("Failed to open file {}: {}", path, e), e))?;
 
         #[cfg(unix)]
         if let Err(e) = Self::fcntl_lock(&file) {
-            return Err(io::Error::new(
-                e.kind(),
+            return Err(with_context(
                 format!("Failed to lock file {}: {}", path, e),
+                e,
             ));
         };
 
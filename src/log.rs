@@ -1,25 +1,126 @@
 //! Logging and progress reporting.
 
+use std::collections::HashMap;
+use std::io;
+use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex, Weak};
 
-use console::style;
+use console::{style, StyledObject, Term};
+use dashmap::DashMap;
 use indicatif::ProgressDrawTarget;
 use nom::lib::std::fmt::Display;
+use serde::Serialize;
 
+use crate::config::ColorMode;
+use crate::path::Path;
 use crate::progress::FastProgressBar;
-use chrono::Local;
+use crate::syslog;
+use crate::syslog::Severity;
+use chrono::{DateTime, FixedOffset, Local};
+
+/// Kind of an event written to the machine-readable event stream, see `--log-json`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    /// An error that stopped the program
+    Error,
+    /// A warning not related to a particular file
+    Warning,
+    /// A file or directory that couldn't be read
+    Unreadable,
+    /// A file that changed since it was scanned or reported
+    Changed,
+    /// A file left out by the selection criteria, e.g. its size or name,
+    /// or because it is not a regular file or is listed more than once
+    Filtered,
+}
+
+/// Number of variants of [`EventKind`]
+const EVENT_KINDS: usize = 5;
+
+/// Operation on a file that failed, see [`UnreadableFile`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileOp {
+    /// Reading the metadata of the file
+    Stat,
+    /// Reading the target of a symbolic link
+    ReadLink,
+    /// Listing a directory
+    ReadDir,
+    /// Opening or reading the contents of the file
+    Read,
+    /// Reading the extended attributes of the file
+    ReadXattrs,
+}
+
+/// A file that couldn't be accessed, recorded if requested by
+/// [`record_unreadable`](Log::record_unreadable)
+#[derive(Clone, Debug, Serialize)]
+pub struct UnreadableFile {
+    pub path: Path,
+    pub operation: FileOp,
+    /// Description of the error
+    pub error: String,
+    /// Kind of the error, e.g. `PermissionDenied` or `TimedOut`
+    pub error_kind: String,
+    /// Error code returned by the operating system, if any
+    pub errno: Option<i32>,
+}
+
+/// A single line of the event stream
+#[derive(Serialize)]
+struct Event<'a> {
+    timestamp: DateTime<FixedOffset>,
+    kind: EventKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<&'a Path>,
+    message: &'a str,
+}
+
+/// Number of events buffered before writing them out.
+/// Errors are written out immediately, because the program is likely to exit right after them.
+const EVENT_BATCH_LEN: usize = 256;
+
+/// Destination of the event stream, see [`write_events_to`](Log::write_events_to)
+struct EventStream {
+    out: BufWriter<Box<dyn Write + Send>>,
+    pending: usize,
+}
 
 pub struct Log {
     program_name: String,
     progress_bar: Mutex<Weak<FastProgressBar>>,
+    scan_errors: Mutex<Option<Vec<String>>>,
+    scan_start: Mutex<Option<DateTime<FixedOffset>>>,
+    unreadable: Mutex<Option<Vec<UnreadableFile>>>,
+    timed_out: Mutex<Vec<Path>>,
+    owners: DashMap<Path, u32>,
+    owners_enabled: AtomicBool,
+    warning_count: AtomicUsize,
+    event_counts: [AtomicUsize; EVENT_KINDS],
+    events: Mutex<Option<EventStream>>,
+    events_enabled: AtomicBool,
     pub log_stderr_to_stdout: bool,
     pub no_progress: bool,
+    pub color: ColorMode,
 }
 
 impl Log {
     pub fn new() -> Log {
         Log {
             progress_bar: Mutex::new(Weak::default()),
+            scan_errors: Mutex::new(None),
+            scan_start: Mutex::new(None),
+            unreadable: Mutex::new(None),
+            timed_out: Mutex::new(Vec::new()),
+            owners: DashMap::new(),
+            owners_enabled: AtomicBool::new(false),
+            warning_count: AtomicUsize::new(0),
+            event_counts: Default::default(),
+            events: Mutex::new(None),
+            events_enabled: AtomicBool::new(false),
             program_name: std::env::current_exe()
                 .unwrap()
                 .file_name()
@@ -28,6 +129,7 @@ impl Log {
                 .to_string(),
             log_stderr_to_stdout: false,
             no_progress: false,
+            color: ColorMode::Auto,
         }
     }
 
@@ -78,6 +180,31 @@ impl Log {
         result
     }
 
+    /// Clears any previous progress bar or spinner and installs a new progress bar
+    /// displaying the progress of each device.
+    /// See [`FastProgressBar::new_device_bytes_progress_bar`].
+    pub fn device_bytes_progress_bar(
+        &self,
+        msg: &str,
+        len: u64,
+        devices: Vec<(String, u64)>,
+    ) -> Arc<FastProgressBar> {
+        if self.no_progress {
+            return Arc::new(FastProgressBar::new_hidden());
+        }
+        self.progress_bar
+            .lock()
+            .unwrap()
+            .upgrade()
+            .iter()
+            .for_each(|pb| pb.finish_and_clear());
+        let result = Arc::new(FastProgressBar::new_device_bytes_progress_bar(
+            msg, len, devices,
+        ));
+        *self.progress_bar.lock().unwrap() = Arc::downgrade(&result);
+        result
+    }
+
     /// Prints a message to stdout.
     /// Does not interfere with progress bar.
     pub fn println<I: Display>(&self, msg: I) {
@@ -103,48 +230,248 @@ impl Log {
 
     const TIMESTAMP_FMT: &'static str = "[%Y-%m-%d %H:%M:%S.%3f]";
 
-    pub fn info<I: Display>(&self, msg: I) {
-        let timestamp = Local::now();
-        let msg = format!(
+    /// Returns true if the log messages should be colored
+    fn stderr_color(&self) -> bool {
+        let term = if self.log_stderr_to_stdout {
+            Term::stdout()
+        } else {
+            Term::stderr()
+        };
+        self.color.enabled(term.is_term())
+    }
+
+    /// Returns true if the data written to the standard output should be colored
+    pub fn stdout_color(&self) -> bool {
+        self.color.enabled(Term::stdout().is_term())
+    }
+
+    /// Formats the log message prefix and the message
+    fn format_msg<I: Display>(&self, level: StyledObject<&str>, msg: I) -> String {
+        let color = self.stderr_color();
+        format!(
             "{} {}: {} {}",
-            style(timestamp.format(Self::TIMESTAMP_FMT))
-                .for_stderr()
+            style(Local::now().format(Self::TIMESTAMP_FMT))
                 .dim()
-                .white(),
-            style(&self.program_name).for_stderr().yellow(),
-            style(" info:").for_stderr().green(),
+                .white()
+                .force_styling(color),
+            style(&self.program_name).yellow().force_styling(color),
+            level.force_styling(color),
             msg
-        );
+        )
+    }
+
+    pub fn info<I: Display>(&self, msg: I) {
+        let msg = self.format_msg(style(" info:").green(), msg);
         self.eprintln(msg);
     }
 
+    /// Remembers the current time as the start of the scan, to be recorded in the report.
+    pub fn mark_scan_start(&self) {
+        *self.scan_start.lock().unwrap() = Some(Local::now().into());
+    }
+
+    /// Returns the time remembered by `mark_scan_start`
+    pub fn scan_start(&self) -> Option<DateTime<FixedOffset>> {
+        *self.scan_start.lock().unwrap()
+    }
+
+    /// Starts remembering the warnings about files or directories that couldn't be read,
+    /// so they can be retrieved later with `take_scan_errors`.
+    /// Other warnings are not recorded.
+    pub fn record_scan_errors(&self) {
+        let mut scan_errors = self.scan_errors.lock().unwrap();
+        if scan_errors.is_none() {
+            *scan_errors = Some(Vec::new())
+        }
+    }
+
+    /// Returns the scan errors recorded so far and clears them.
+    /// Returns an empty vector if recording hasn't been enabled by `record_scan_errors`.
+    pub fn take_scan_errors(&self) -> Vec<String> {
+        match self.scan_errors.lock().unwrap().as_mut() {
+            Some(scan_errors) => std::mem::take(scan_errors),
+            None => Vec::new(),
+        }
+    }
+
+    /// Starts recording the files reported by [`unreadable_file`](Log::unreadable_file)
+    pub fn record_unreadable(&self) {
+        let mut unreadable = self.unreadable.lock().unwrap();
+        if unreadable.is_none() {
+            *unreadable = Some(Vec::new())
+        }
+    }
+
+    /// Returns the unreadable files recorded so far and clears them.
+    /// Returns an empty vector if recording hasn't been enabled by `record_unreadable`.
+    pub fn take_unreadable(&self) -> Vec<UnreadableFile> {
+        match self.unreadable.lock().unwrap().as_mut() {
+            Some(unreadable) => std::mem::take(unreadable),
+            None => Vec::new(),
+        }
+    }
+
+    /// Returns the files abandoned because of `--io-timeout` since the last call.
+    /// Directories that couldn't be listed in time are not included.
+    pub fn take_timed_out(&self) -> Vec<Path> {
+        std::mem::take(&mut *self.timed_out.lock().unwrap())
+    }
+
+    /// Starts recording the owners of the files passed to
+    /// [`scanned_file_owner`](Log::scanned_file_owner), so they don't have to be read again
+    /// when writing the report
+    pub fn record_owners(&self) {
+        self.owners_enabled.store(true, Ordering::Relaxed);
+    }
+
+    /// Remembers the owner of a file found by the directory walker,
+    /// if recording has been enabled by `record_owners`
+    pub fn scanned_file_owner(&self, path: &Path, uid: u32) {
+        if self.owners_enabled.load(Ordering::Relaxed) {
+            self.owners.insert(path.clone(), uid);
+        }
+    }
+
+    /// Returns the owners of the files recorded so far and clears them
+    pub fn take_owners(&self) -> HashMap<Path, u32> {
+        let owners = self
+            .owners
+            .iter()
+            .map(|e| (e.key().clone(), *e.value()))
+            .collect();
+        self.owners.clear();
+        owners
+    }
+
+    /// Returns the number of warnings logged so far
+    pub fn warning_count(&self) -> usize {
+        self.warning_count.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of events of given kind logged so far.
+    /// Filtered files are counted even if the event stream is not enabled.
+    pub fn event_count(&self, kind: EventKind) -> usize {
+        self.event_counts[kind as usize].load(Ordering::Relaxed)
+    }
+
+    fn count_event(&self, kind: EventKind) {
+        self.event_counts[kind as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Starts writing the warnings, the errors and the skipped files to `out`
+    /// as JSON objects, one per line, in addition to the human-readable log.
+    /// The events are written in batches, call [`flush_events`](Log::flush_events)
+    /// to write out the remaining ones. They are also written out when the log is dropped.
+    pub fn write_events_to(&self, out: Box<dyn Write + Send>) {
+        *self.events.lock().unwrap() = Some(EventStream {
+            out: BufWriter::new(out),
+            pending: 0,
+        });
+        self.events_enabled.store(true, Ordering::Release);
+    }
+
+    /// Writes out the events buffered so far
+    pub fn flush_events(&self) {
+        if let Some(events) = self.events.lock().unwrap().as_mut() {
+            let _ = events.out.flush();
+            events.pending = 0;
+        }
+    }
+
+    /// Returns true if the events are written by [`write_events_to`](Log::write_events_to)
+    pub fn events_enabled(&self) -> bool {
+        self.events_enabled.load(Ordering::Acquire)
+    }
+
+    /// Writes the event to the event stream, if enabled.
+    /// Failures to write are ignored, so they don't hide the original problem.
+    fn event(&self, kind: EventKind, path: Option<&Path>, message: &str) {
+        if !self.events_enabled() {
+            return;
+        }
+        let event = Event {
+            timestamp: Local::now().into(),
+            kind,
+            path,
+            message,
+        };
+        // Serialize before taking the lock, so the threads wait only for copying the bytes
+        let mut line = match serde_json::to_vec(&event) {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+        line.push(b'\n');
+        if let Some(events) = self.events.lock().unwrap().as_mut() {
+            let _ = events.out.write_all(&line);
+            events.pending += 1;
+            if kind == EventKind::Error || events.pending >= EVENT_BATCH_LEN {
+                let _ = events.out.flush();
+                events.pending = 0;
+            }
+        }
+    }
+
     pub fn warn<I: Display>(&self, msg: I) {
-        let timestamp = Local::now();
-        let msg = format!(
-            "{} {}: {} {}",
-            style(timestamp.format(Self::TIMESTAMP_FMT))
-                .for_stderr()
-                .dim()
-                .white(),
-            style(&self.program_name).for_stderr().yellow(),
-            style(" warn:").for_stderr().yellow(),
-            msg
-        );
+        self.warn_event(EventKind::Warning, None, msg)
+    }
+
+    /// Logs a warning concerning the given file, e.g. one that couldn't be read or has changed.
+    /// The event stream gets the path as a separate field.
+    pub fn warn_file<I: Display>(&self, kind: EventKind, path: &Path, msg: I) {
+        self.warn_event(kind, Some(path), msg)
+    }
+
+    /// Logs a warning about a file that couldn't be accessed because `op` failed with `error`.
+    /// Unlike [`warn_file`](Log::warn_file), keeps the details of the error
+    /// for the report of unreadable files.
+    pub fn unreadable_file<I: Display>(&self, path: &Path, op: FileOp, error: &io::Error, msg: I) {
+        if let Some(unreadable) = self.unreadable.lock().unwrap().as_mut() {
+            unreadable.push(UnreadableFile {
+                path: path.clone(),
+                operation: op,
+                error: error.to_string(),
+                error_kind: format!("{:?}", error.kind()),
+                errno: error.raw_os_error(),
+            });
+        }
+        if error.kind() == io::ErrorKind::TimedOut && op != FileOp::ReadDir {
+            self.timed_out.lock().unwrap().push(path.clone());
+        }
+        self.warn_event(EventKind::Unreadable, Some(path), msg)
+    }
+
+    fn warn_event<I: Display>(&self, kind: EventKind, path: Option<&Path>, msg: I) {
+        self.warning_count.fetch_add(1, Ordering::Relaxed);
+        self.count_event(kind);
+        if kind == EventKind::Unreadable {
+            if let Some(scan_errors) = self.scan_errors.lock().unwrap().as_mut() {
+                scan_errors.push(msg.to_string());
+            }
+        }
+        syslog::send(Severity::Warning, &msg);
+        if self.events_enabled() {
+            self.event(kind, path, &msg.to_string());
+        }
+        let msg = self.format_msg(style(" warn:").yellow(), msg);
         self.eprintln(msg);
     }
 
+    /// Records a file left out by the selection criteria.
+    /// Written only to the event stream, because there may be very many of them.
+    pub fn filtered_file<I: Display>(&self, path: &Path, reason: I) {
+        self.count_event(EventKind::Filtered);
+        if self.events_enabled() {
+            self.event(EventKind::Filtered, Some(path), &reason.to_string());
+        }
+    }
+
     pub fn err<I: Display>(&self, msg: I) {
-        let timestamp = Local::now();
-        let msg = format!(
-            "{} {}: {} {}",
-            style(timestamp.format(Self::TIMESTAMP_FMT))
-                .for_stderr()
-                .dim()
-                .white(),
-            style(&self.program_name).for_stderr().yellow(),
-            style("error:").for_stderr().red(),
-            msg
-        );
+        self.count_event(EventKind::Error);
+        syslog::send(Severity::Error, &msg);
+        if self.events_enabled() {
+            self.event(EventKind::Error, None, &msg.to_string());
+        }
+        let msg = self.format_msg(style("error:").red(), msg);
         self.eprintln(msg);
     }
 }
@@ -1,22 +1,47 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
+use std::fmt::Display;
 use std::fs::File;
-use std::io::{stdin, Write};
+use std::io::{stdin, ErrorKind, Write};
 use std::process::exit;
-use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::{Arc, Mutex};
 use std::{fs, io};
 
+use chrono::{DateTime, FixedOffset, Local};
+use crossbeam_utils::thread;
 use fallible_iterator::FallibleIterator;
 use itertools::Itertools;
-use rayon::iter::ParallelBridge;
+use rayon::iter::{ParallelBridge, ParallelIterator};
 use regex::Regex;
+use serde_json::json;
 use structopt::StructOpt;
 
-use fclones::config::{Command, Config, DedupeConfig, GroupConfig, Parallelism};
+use fclones::annotation::AnnotationFilter;
+use fclones::bench::run_bench;
+use fclones::config::{
+    BenchConfig, Command, Config, ContainsConfig, DaemonConfig, DedupeConfig, FindCopiesConfig,
+    FsInfoConfig, GroupConfig, HelperConfig, Parallelism, PlanDiffConfig, PlanFormat,
+    RefilterConfig, ReplicateConfig, Verify,
+};
+use fclones::contains::contains;
+use fclones::files;
+use fclones::files::uses_decimal_comma;
+use fclones::find_copies::find_copies;
+use fclones::fs_info::fs_info;
+use fclones::helper::{serve, Helper};
+use fclones::keep_list::KeepList;
 use fclones::log::Log;
-use fclones::report::open_report;
-use fclones::{dedupe, log_script, run_script, DedupeOp};
-use fclones::{group_files, write_report, Error};
+use fclones::notify;
+use fclones::plan::{diff_plans, read_plan, write_plan, GroupAnnotations, PlanStats};
+use fclones::replicate::replicate;
+use fclones::report::{open_report, ReportHeader, ReportReader};
+use fclones::summary::{DedupeSummary, GroupSummary};
+use fclones::{dedupe, log_script, run_script, AuditLog, DedupeOp, FileGroup, RunOptions};
+use fclones::{group_files_to_sink, group_files_with_summary, write_report_streaming};
+use fclones::{refilter, write_refiltered_report, write_report, Error};
+use fclones::{syslog, systemd};
 
 /// Strips a red "error:" prefix and usage information added by clap.
 /// Removes ansi formatting.
@@ -64,7 +89,7 @@ fn run_group(mut config: GroupConfig, log: &mut Log) -> Result<(), Error> {
         // problems and report as warnings.
         let mut access_error = false;
         let depth = config.depth;
-        config.paths.retain(|p| match fs::metadata(&p) {
+        config.paths.retain(|p| match fs::metadata(p) {
             Ok(m) if m.is_dir() && depth == Some(0) => {
                 log.warn(format!(
                     "Skipping directory {} because recursive scan is disabled.",
@@ -88,7 +113,8 @@ fn run_group(mut config: GroupConfig, log: &mut Log) -> Result<(), Error> {
     }
 
     configure_main_thread_pool(&config.thread_pool_sizes());
-    if let Some(output) = &config.output {
+    let extra_outputs = config.extra_outputs.iter().map(|o| &o.path);
+    for output in config.output.iter().chain(extra_outputs) {
         // Try to create the output file now and fail early so that
         // the user doesn't waste time to only find that the report cannot be written at the end:
         if let Err(e) = File::create(output) {
@@ -101,9 +127,268 @@ fn run_group(mut config: GroupConfig, log: &mut Log) -> Result<(), Error> {
     }
 
     log.info("Started grouping");
-    let results = group_files(&config, log).map_err(|e| Error::new(e.message))?;
+    let summary = if config.stream {
+        match run_group_streaming(&mut config, log, |c, log, groups| {
+            write_report_streaming(c, log, groups)
+        })? {
+            Some(summary) => summary,
+            None => return Ok(()),
+        }
+    } else {
+        let (results, summary) =
+            group_files_with_summary(&config, log).map_err(|e| Error::new(e.message))?;
+        match write_report(&config, log, &results) {
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => log_output_closed(log),
+            Err(e) => return Err(Error::new(format!("Failed to write report: {}", e))),
+            Ok(()) => {}
+        }
+        summary
+    };
+    for line in summary.to_string().lines() {
+        log.info(line);
+    }
+    let found = summary.stages.last();
+    notify::set_stats(json!({
+        "scanned_entries": summary.scanned_entries,
+        "bytes_hashed": summary.bytes_hashed.0,
+        "file_count": found.map_or(0, |s| s.file_count),
+        "file_size": found.map_or(0, |s| s.file_size.0),
+        "error_count": summary.error_count(),
+    }));
+    Ok(())
+}
+
+/// Number of found groups waiting to be written in the streaming mode.
+/// When the output can't keep up, the grouping blocks on a full queue,
+/// so it doesn't hash files faster than the report can be consumed.
+const STREAM_QUEUE_LEN: usize = 256;
+
+/// Informs the user that the reader of the report went away, e.g. `fclones group | head`.
+/// This is not an error, the reader has got everything it asked for.
+fn log_output_closed(log: &Log) {
+    log.info("Output closed by the reader, the report is incomplete");
+}
+
+/// Groups files on a background thread and writes each group with `write` as soon as it is found.
+/// Returns `None` if the output was closed by the reader before all groups were written.
+/// In that case the grouping is cancelled, as there is no one to read its results.
+fn run_group_streaming<W>(
+    config: &mut GroupConfig,
+    log: &Log,
+    write: W,
+) -> Result<Option<GroupSummary>, Error>
+where
+    W: FnOnce(&GroupConfig, &Log, Receiver<FileGroup<fclones::path::Path>>) -> io::Result<()>,
+{
+    let cancel = config.cancel.get_or_insert_with(Default::default).clone();
+    let config = &*config;
+    let (tx, rx) = sync_channel(STREAM_QUEUE_LEN);
+    thread::scope(|s| {
+        let sink_cancel = cancel.clone();
+        let grouping = s.spawn(move |_| {
+            // If writing the report failed, the receiver is gone and there is no point
+            // in sending more groups:
+            group_files_to_sink(config, log, |g| {
+                if tx.send(g).is_err() {
+                    sink_cancel.store(true, Ordering::Relaxed)
+                }
+            })
+        });
+        // The receiver must be dropped as soon as writing stops,
+        // otherwise the grouping could wait forever on the full queue:
+        let written = write(config, log, rx);
+        if written.is_err() {
+            cancel.store(true, Ordering::Relaxed);
+        }
+        let grouped = grouping.join().unwrap();
+        match written {
+            Err(e) if e.kind() == ErrorKind::BrokenPipe => {
+                log_output_closed(log);
+                Ok(None)
+            }
+            Err(e) => Err(Error::new(format!("Failed to write report: {}", e))),
+            Ok(()) => grouped.map(Some),
+        }
+    })
+    .unwrap()
+}
+
+/// Writes the text to the standard output.
+/// Unlike `println!`, doesn't panic if the output was closed by the reader.
+fn print_output(text: impl Display, log: &Log) -> Result<(), Error> {
+    match writeln!(io::stdout(), "{}", text) {
+        Err(e) if e.kind() == ErrorKind::BrokenPipe => {
+            log_output_closed(log);
+            Ok(())
+        }
+        Err(e) => Err(Error::new(format!("Output error: {}", e))),
+        Ok(()) => Ok(()),
+    }
+}
+
+fn run_bench_command(config: BenchConfig, log: &mut Log) -> Result<(), Error> {
+    log.info("Started benchmarking");
+    let report = run_bench(&config, log)?;
+    print_output(report, log)
+}
+
+fn run_fs_info(config: FsInfoConfig, log: &Log) -> Result<(), Error> {
+    let info = fs_info(&config)?;
+    print_output(info, log)
+}
+
+fn run_find_copies(config: FindCopiesConfig, log: &Log) -> Result<(), Error> {
+    let copies = find_copies(&config, log)?;
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for path in copies {
+        writeln!(out, "{}", path).map_err(|e| format!("Output error: {}", e))?;
+    }
+    Ok(())
+}
+
+fn run_contains(config: ContainsConfig, log: &Log) -> Result<(), Error> {
+    let coverage = contains(&config, log)?;
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for path in coverage.uncovered.iter() {
+        writeln!(out, "{}", path).map_err(|e| format!("Output error: {}", e))?;
+    }
+    if coverage.is_complete() {
+        log.info(format!(
+            "All {} files under {} have copies under {}",
+            coverage.file_count,
+            config.dir.display(),
+            config.root.display()
+        ));
+        Ok(())
+    } else {
+        Err(Error::new(format!(
+            "{} of {} files under {} have no copy under {}",
+            coverage.uncovered.len(),
+            coverage.file_count,
+            config.dir.display(),
+            config.root.display()
+        )))
+    }
+}
+
+fn run_plan_diff(config: PlanDiffConfig, log: &Log) -> Result<(), Error> {
+    let old = read_plan(&config.old).map_err(|e| Error::new(e.to_string()))?;
+    let new = read_plan(&config.new).map_err(|e| Error::new(e.to_string()))?;
+    let diff = diff_plans(old, new);
+    print!("{}", diff);
+    log.info(format!(
+        "{} actions added, {} removed, {} changed, {} unchanged",
+        diff.added.len(),
+        diff.removed.len(),
+        diff.changed.len(),
+        diff.unchanged_count
+    ));
+    Ok(())
+}
+
+fn run_replicate(config: ReplicateConfig, log: &Log) -> Result<(), Error> {
+    let input_error = |e: io::Error| format!("Input error: {}", e);
+    let mut reader = open_report(stdin()).map_err(input_error)?;
+    let header = reader.read_header().map_err(input_error)?;
+    let rf_under =
+        match config.rf_under {
+            Some(rf_under) => rf_under,
+            None => match report_group_config(&header)?.and_then(|c| c.rf_under) {
+                Some(rf_under) => rf_under,
+                None => return Err(Error::from(
+                    "Could not extract --rf-under setting from the earlier fclones configuration.",
+                )),
+            },
+        };
+    let groups: Vec<_> = reader
+        .read_groups()
+        .map_err(input_error)?
+        .iterator()
+        .collect::<Result<_, _>>()
+        .map_err(input_error)?;
+
+    let stdout = io::stdout();
+    let result = replicate(groups, rf_under, &config, stdout.lock(), log)
+        .map_err(|e| format!("Output error: {}", e))?;
+    notify::set_stats(json!({
+        "copied_count": result.copied_count,
+        "copied_size": result.copied_size.0,
+        "failed_count": result.failed_count,
+    }));
+    if config.dry_run {
+        log.info(format!(
+            "Would copy {} files ({})",
+            result.copied_count, result.copied_size
+        ));
+    } else {
+        log.info(format!(
+            "Copied {} files ({})",
+            result.copied_count, result.copied_size
+        ));
+    }
+    if result.failed_count > 0 {
+        return Err(Error::new(format!(
+            "Failed to replicate {} groups",
+            result.failed_count
+        )));
+    }
+    Ok(())
+}
+
+fn run_helper(config: HelperConfig, log: &Log) -> Result<(), Error> {
+    let stdin = stdin();
+    let stdout = io::stdout();
+    serve(&config.allow, stdin.lock(), stdout.lock(), log)
+        .map_err(|e| Error::new(format!("Helper error: {}", e)))
+}
 
-    write_report(&config, log, &results)
+#[cfg(unix)]
+fn run_daemon(config: DaemonConfig, log: &Log) -> Result<(), Error> {
+    // Progress bars of concurrent scans would overwrite each other
+    let mut daemon_log = Log::new();
+    daemon_log.no_progress = true;
+    daemon_log.color = log.color;
+    fclones::daemon::serve(&config, Arc::new(daemon_log))
+}
+
+#[cfg(not(unix))]
+fn run_daemon(_config: DaemonConfig, _log: &Log) -> Result<(), Error> {
+    Err(Error::from("The daemon is supported on Unix only"))
+}
+
+/// Fails if the groups are to be selected by annotations, but the report can't carry them,
+/// because no group would be selected then
+fn check_only_annotated(
+    reader: &dyn ReportReader,
+    filters: &[AnnotationFilter],
+) -> Result<(), Error> {
+    if !filters.is_empty() && !reader.has_annotations() {
+        return Err(Error::from(
+            "--only-annotated requires a JSON report, other report formats have no annotations",
+        ));
+    }
+    Ok(())
+}
+
+fn run_refilter(config: RefilterConfig, log: &mut Log) -> Result<(), Error> {
+    let input_error = |e: io::Error| format!("Input error: {}", e);
+    let mut reader = open_report(stdin()).map_err(input_error)?;
+    check_only_annotated(reader.as_ref(), &config.only_annotated)?;
+    let header = reader.read_header().map_err(input_error)?;
+    let rf_over = match config.rf_over {
+        Some(rf_over) => rf_over,
+        None => report_rf_over(&header)?.unwrap_or(1),
+    };
+    let groups: Vec<_> = reader
+        .read_groups()
+        .map_err(input_error)?
+        .iterator()
+        .collect::<Result<_, _>>()
+        .map_err(input_error)?;
+    let groups = refilter(groups, &config, rf_over);
+    write_refiltered_report(&config, log, header, rf_over, &groups)
         .map_err(|e| Error::new(format!("Failed to write report: {}", e)))
 }
 
@@ -121,18 +406,34 @@ fn get_output_writer(config: &DedupeConfig) -> Result<Box<dyn Write + Send>, Err
     }
 }
 
-pub fn run_dedupe(op: DedupeOp, config: DedupeConfig, log: &mut Log) -> Result<(), Error> {
-    let input_error = |e: io::Error| format!("Input error: {}", e);
-    let mut dedupe_config = config;
-    let mut reader = open_report(stdin()).map_err(input_error)?;
-    let header = reader.read_header().map_err(input_error)?;
+/// Returns the configuration of the `fclones group` run that produced the report,
+/// or `None` if the report was produced by a different command.
+fn report_group_config(header: &ReportHeader) -> Result<Option<GroupConfig>, Error> {
     let find_config: Config = Config::from_iter_safe(&header.command).map_err(|e| {
         let message: String = extract_error_cause(&e.message);
         format!("Unrecognized earlier fclones configuration: {}", message)
     })?;
+    match find_config.command {
+        Command::Group(c) => Ok(Some(*c)),
+        _ => Ok(None),
+    }
+}
 
-    let rf_over = match find_config.command {
-        Command::Group(c) => c.rf_over(),
+/// Returns the `--rf-over` setting of the `fclones group` run that produced the report,
+/// or `None` if the report was produced by a different command.
+fn report_rf_over(header: &ReportHeader) -> Result<Option<usize>, Error> {
+    Ok(report_group_config(header)?.map(|c| c.rf_over()))
+}
+
+pub fn run_dedupe(op: DedupeOp, config: DedupeConfig, log: &mut Log) -> Result<(), Error> {
+    let input_error = |e: io::Error| format!("Input error: {}", e);
+    let mut dedupe_config = config;
+    let mut reader = open_report(stdin()).map_err(input_error)?;
+    check_only_annotated(reader.as_ref(), &dedupe_config.only_annotated)?;
+    let header = reader.read_header().map_err(input_error)?;
+    let group_config = report_group_config(&header)?;
+    let rf_over = match group_config.as_ref().map(|c| c.rf_over()) {
+        Some(rf_over) => rf_over,
         _ if dedupe_config.rf_over.is_some() => dedupe_config.rf_over.unwrap(),
         _ => {
             return Err(Error::from(
@@ -142,10 +443,76 @@ pub fn run_dedupe(op: DedupeOp, config: DedupeConfig, log: &mut Log) -> Result<(
     };
 
     dedupe_config.rf_over = Some(rf_over);
+    let keep_count = dedupe_config.keep_copies.unwrap_or(rf_over);
+    // Paths in the report are absolute, so the root must be absolute as well to match them:
+    dedupe_config.drop_root = dedupe_config.drop_root.map(|root| {
+        let cwd = Arc::new(fclones::path::Path::from(
+            std::env::current_dir().unwrap_or_default(),
+        ));
+        cwd.resolve(fclones::path::Path::from(root))
+            .canonicalize()
+            .to_path_buf()
+    });
+    dedupe_config.normalize_patterns();
     if dedupe_config.modified_before.is_none() {
         dedupe_config.modified_before = Some(header.timestamp);
     }
+    // The reported hashes of transformed files can't be checked against the original contents
+    let transformed = matches!(&group_config, Some(c) if c.transform.is_some());
+    if transformed && dedupe_config.verify > Verify::Stat {
+        log.warn(format!(
+            "--verify {} is not supported for reports produced with --transform. \
+            Verifying the metadata of the files only.",
+            dedupe_config.verify
+        ));
+        dedupe_config.verify = Verify::Stat;
+    }
 
+    let open_log = |path: &Option<std::path::PathBuf>| match path {
+        Some(path) => AuditLog::open(path)
+            .map(Some)
+            .map_err(|e| Error::new(e.to_string())),
+        None => Ok(None),
+    };
+    let completed = match &dedupe_config.resume_from {
+        Some(path) => AuditLog::read_completed(path).map_err(|e| Error::new(e.to_string()))?,
+        None => HashSet::new(),
+    };
+    if let Some(path) = &dedupe_config.resume_from {
+        log.info(format!(
+            "Skipping {} files already processed according to {}",
+            completed.len(),
+            path.display()
+        ));
+    }
+    if let Some(path) = &dedupe_config.keep_list {
+        dedupe_config.keep_files = KeepList::read(path).map_err(|e| {
+            Error::new(format!(
+                "Failed to read keep list {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        log.info(format!(
+            "Read files to keep in {} groups from {}",
+            dedupe_config.keep_files.len(),
+            path.display()
+        ));
+    }
+    let audit = open_log(&dedupe_config.audit_log)?;
+    let helper = match &dedupe_config.helper {
+        Some(_) if matches!(op, DedupeOp::Move { .. }) => {
+            return Err(Error::from("--helper is not supported by the move command"))
+        }
+        Some(command) if !dedupe_config.dry_run => {
+            Some(Helper::spawn(command).map_err(|e| Error::new(e.to_string()))?)
+        }
+        _ => None,
+    };
+    let failures = open_log(&dedupe_config.failed_log)?;
+
+    let run = DedupeSummary::start(log);
+    dedupe_config.counters = Some(run.counters.clone());
     if dedupe_config.dry_run {
         log.info("Started deduplicating (dry run)");
     } else {
@@ -160,7 +527,12 @@ pub fn run_dedupe(op: DedupeOp, config: DedupeConfig, log: &mut Log) -> Result<(
         None => log.spinner("Deduplicating"),
     };
 
+    let base = dedupe_config
+        .base
+        .as_ref()
+        .map(|b| Arc::new(fclones::path::Path::from(b)));
     let groups = reader.read_groups();
+    let annotations = GroupAnnotations::new();
 
     let groups = groups
         .map_err(input_error)?
@@ -174,33 +546,112 @@ pub fn run_dedupe(op: DedupeOp, config: DedupeConfig, log: &mut Log) -> Result<(
         })
         .take_while(|g| g.is_some())
         .map(|g| g.unwrap())
-        .inspect(|_| progress.tick())
+        .map(|mut g| {
+            if let Some(base) = &base {
+                g.files = g.files.iter().map(|f| base.resolve(f)).collect();
+            }
+            g
+        })
+        .inspect(|g| {
+            progress.tick();
+            if !g.annotations.is_empty() {
+                annotations.insert(g.id(), g.annotations.clone());
+            }
+        })
+        .filter_map(|mut g| {
+            if !completed.is_empty() {
+                g.files.retain(|f| !completed.contains(f));
+                // All redundant files of the group have been processed already:
+                if g.files.len() <= keep_count {
+                    return None;
+                }
+            }
+            Some(g)
+        })
         .par_bridge();
 
     let script = dedupe(groups, op, &dedupe_config, log);
-    if dedupe_config.dry_run {
+    let dedupe_result = if dedupe_config.dry_run {
+        let stats = dedupe_config
+            .stats
+            .then(|| Mutex::new(PlanStats::new(dedupe_config.verify)));
+        let script = script.inspect(|cmd| {
+            if let Some(stats) = &stats {
+                stats.lock().unwrap().add(cmd)
+            }
+        });
         let out = get_output_writer(&dedupe_config)?;
-        let result = log_script(script, out).map_err(|e| format!("Output error: {}", e))?;
+        let result = match dedupe_config.plan_format {
+            PlanFormat::Shell => log_script(script, out),
+            PlanFormat::Json => write_plan(script, &annotations, out),
+        };
+        let result = result.map_err(|e| format!("Output error: {}", e))?;
         log.info(format!(
             "Would process {} files and reclaim {} space",
             result.processed_count, result.reclaimed_space
         ));
+        if let Some(stats) = stats {
+            log.eprintln(stats.into_inner().unwrap());
+        }
+        result
     } else {
-        let result = run_script(script, log);
+        let options = RunOptions {
+            audit: audit.as_ref(),
+            failures: failures.as_ref(),
+            retries: dedupe_config.retries,
+            retry_delay: dedupe_config.retry_delay,
+            serialize_network: dedupe_config.serialize_network,
+            max_open_files: dedupe_config.max_open_files,
+            helper: helper.as_ref(),
+        };
+        let result = run_script(script, &options, log);
+        notify::set_stats(json!({
+            "processed_count": result.processed_count,
+            "reclaimed_space": result.reclaimed_space.0,
+            "failed_count": result.failed_count,
+        }));
         log.info(format!(
             "Processed {} files and reclaimed {} space",
             result.processed_count, result.reclaimed_space
         ));
+        if result.failed_count > 0 {
+            log.warn(format!("Failed to process {} files", result.failed_count));
+        }
+        result
     };
+    let summary = DedupeSummary::finish(run, &dedupe_result, log);
+    if summary.not_permitted_links > 0 {
+        log.warn(format!(
+            "Skipped {} files, because fs.protected_hardlinks doesn't permit \
+            linking to files of other users",
+            summary.not_permitted_links
+        ));
+    }
+    for line in summary.to_string().lines() {
+        log.info(line);
+    }
     result.map_err(|e| Error::new(format!("Failed to read file list: {}", e)))
 }
 
+/// Logs the outcome of the run to the system log
+fn log_syslog_summary(started: DateTime<FixedOffset>, result: &Result<(), Error>, warnings: usize) {
+    let command = shell_words::join(std::env::args());
+    let duration = Local::now().signed_duration_since(started);
+    let duration = duration.num_milliseconds() as f64 / 1000.0;
+    let error = result.as_ref().err().map(|e| e.to_string());
+    let (severity, msg) = syslog::summary(&command, duration, warnings, error.as_deref());
+    syslog::send(severity, msg);
+}
+
 fn main() {
     let config = Config::from_args();
     let mut log = Log::new();
     if config.quiet {
         log.no_progress = true;
     }
+    log.color = config.color;
+    files::set_binary_units(config.binary);
+    files::set_decimal_comma(files::numeric_locale().map_or(false, |l| uses_decimal_comma(&l)));
 
     let cwd = match std::env::current_dir() {
         Ok(cwd) => cwd,
@@ -210,31 +661,130 @@ fn main() {
         }
     };
 
+    if config.log_syslog {
+        if let Err(e) = syslog::enable() {
+            log.warn(format!("Failed to open the system log: {}", e));
+        }
+    }
+    if let Some(path) = &config.log_json {
+        let path = cwd.join(path);
+        match fs::OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(file) => log.write_events_to(Box::new(file)),
+            Err(e) => {
+                log.err(format!(
+                    "Failed to open event log {}: {}",
+                    path.display(),
+                    e
+                ));
+                exit(1);
+            }
+        }
+    }
+    if config.notify_systemd && !systemd::enable() {
+        log.warn("Not running as a systemd service, --notify-systemd ignored");
+    }
+    if !matches!(config.command, Command::Daemon(_)) {
+        systemd::ready();
+    }
+    let notify_webhook = config.notify_webhook.clone();
+    let notify_cmd = config.notify_cmd.clone();
+    let started = Local::now().into();
+
     let result = match config.command {
-        Command::Group(config) => run_group(config, &mut log),
+        Command::Group(config) => run_group(*config, &mut log),
         Command::Remove(config) => run_dedupe(DedupeOp::Remove, config, &mut log),
         Command::Link { config, soft: true } => run_dedupe(DedupeOp::SoftLink, config, &mut log),
         Command::Link {
             config,
             soft: false,
         } => run_dedupe(DedupeOp::HardLink, config, &mut log),
-        Command::Move { config, target } => {
+        Command::Move {
+            config,
+            target,
+            no_cross_device,
+        } => {
             let target = fclones::path::Path::from(target);
             let target = Arc::new(fclones::path::Path::from(cwd)).resolve(target);
-            run_dedupe(DedupeOp::Move(Arc::new(target)), config, &mut log)
+            let op = DedupeOp::Move {
+                target: Arc::new(target),
+                cross_device: !no_cross_device,
+            };
+            run_dedupe(op, config, &mut log)
         }
+        Command::Bench(config) => run_bench_command(config, &mut log),
+        Command::Refilter(config) => run_refilter(config, &mut log),
+        Command::Daemon(config) => run_daemon(config, &log),
+        Command::FsInfo(config) => run_fs_info(config, &log),
+        Command::FindCopies(config) => run_find_copies(config, &log),
+        Command::Contains(config) => run_contains(config, &log),
+        Command::Helper(config) => run_helper(config, &log),
+        Command::PlanDiff(config) => run_plan_diff(config, &log),
+        Command::Replicate(config) => run_replicate(config, &log),
     };
 
+    match &result {
+        Ok(()) => systemd::stopping("Finished"),
+        Err(e) => systemd::stopping(format!("Failed: {}", e)),
+    }
+    if syslog::is_enabled() {
+        log_syslog_summary(started, &result, log.warning_count());
+    }
+    if notify_webhook.is_some() || notify_cmd.is_some() {
+        let error = result.as_ref().err().map(|e| e.to_string());
+        let summary = notify::RunSummary::new(started, error, log.warning_count());
+        if let Some(url) = &notify_webhook {
+            if let Err(e) = notify::post_webhook(url, &summary) {
+                log.warn(format!("Failed to notify {}: {}", url, e));
+            }
+        }
+        if let Some(cmd) = &notify_cmd {
+            if let Err(e) = notify::run_command(cmd, &summary) {
+                log.warn(format!("Failed to run notification command: {}", e));
+            }
+        }
+    }
     if let Err(e) = result {
         if !e.message.is_empty() {
             log.err(e);
         }
+        log.flush_events();
         exit(1);
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::io;
+    use std::io::ErrorKind;
+    use std::sync::atomic::Ordering;
+
+    use fclones::config::GroupConfig;
+    use fclones::log::Log;
+
+    #[test]
+    fn test_group_streaming_stops_when_output_closed() {
+        let dir = tempfile::tempdir().unwrap();
+        // Many more groups than fit in the queue, so the grouping is blocked when the output
+        // gets closed:
+        for i in 0..2 * super::STREAM_QUEUE_LEN {
+            for name in ["a", "b"].iter() {
+                let path = dir.path().join(format!("{}_{}", name, i));
+                std::fs::write(path, format!("contents {}", i)).unwrap();
+            }
+        }
+        let mut config = GroupConfig {
+            paths: vec![dir.path().into()],
+            stream: true,
+            ..Default::default()
+        };
+        let log = Log::new();
+        let result = super::run_group_streaming(&mut config, &log, |_, _, groups| {
+            assert_eq!(groups.iter().take(2).count(), 2);
+            Err(io::Error::new(ErrorKind::BrokenPipe, "Output closed"))
+        });
+        assert!(matches!(result, Ok(None)));
+        assert!(config.cancel.unwrap().load(Ordering::Relaxed));
+    }
 
     #[test]
     fn test_extract_error_cause_strips_error_prefix() {
@@ -0,0 +1,300 @@
+//! Notifying other programs about the outcome of a run.
+//!
+//! When the run completes or fails, a JSON summary with the status, the error,
+//! the duration and the statistics of the run is posted to a webhook or passed
+//! to a command on its standard input. Useful for unattended runs, e.g. started by cron.
+
+use std::io;
+use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use chrono::{DateTime, FixedOffset, Local};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use serde_json::Value;
+
+/// How long to wait for the webhook server to accept the connection and to respond
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(30);
+
+lazy_static! {
+    static ref STATS: Mutex<Option<Value>> = Mutex::new(None);
+}
+
+/// Sets the statistics of the run to include in the summary
+pub fn set_stats(stats: Value) {
+    *STATS.lock().unwrap() = Some(stats);
+}
+
+/// Outcome of a run, sent as JSON
+#[derive(Debug, Serialize)]
+pub struct RunSummary {
+    /// Full shell command containing the arguments of the run
+    pub command: Vec<String>,
+    /// `ok` or `failed`
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub started: DateTime<FixedOffset>,
+    pub finished: DateTime<FixedOffset>,
+    pub duration_secs: f64,
+    pub warning_count: usize,
+    /// Statistics set by the command with [`set_stats`], if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stats: Option<Value>,
+}
+
+impl RunSummary {
+    /// Creates the summary of a run started at `started` and finishing now
+    pub fn new(
+        started: DateTime<FixedOffset>,
+        error: Option<String>,
+        warning_count: usize,
+    ) -> RunSummary {
+        let now = Local::now();
+        let finished = DateTime::from_utc(now.naive_utc(), *now.offset());
+        let duration = finished - started;
+        RunSummary {
+            command: std::env::args().collect(),
+            status: if error.is_none() { "ok" } else { "failed" },
+            error,
+            started,
+            finished,
+            duration_secs: duration.num_milliseconds() as f64 / 1000.0,
+            warning_count,
+            stats: STATS.lock().unwrap().take(),
+        }
+    }
+}
+
+/// Posts the summary to the given `http://` or `https://` URL
+pub fn post_webhook(url: &str, summary: &RunSummary) -> io::Result<()> {
+    let invalid = |msg: &str| io::Error::new(ErrorKind::InvalidInput, format!("{}: {}", msg, url));
+    let (https, rest) = if let Some(rest) = url.strip_prefix("http://") {
+        (false, rest)
+    } else if let Some(rest) = url.strip_prefix("https://") {
+        (true, rest)
+    } else {
+        return Err(invalid("Unsupported URL"));
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(i) => (&rest[..i], &rest[i..]),
+        None => (rest, "/"),
+    };
+    if authority.is_empty() || authority.contains('@') {
+        return Err(invalid("Unsupported URL"));
+    }
+    let (host, address) = if authority.contains(':') && !authority.ends_with(']') {
+        let i = authority.rfind(':').unwrap();
+        (&authority[..i], authority.to_owned())
+    } else {
+        let port = if https { 443 } else { 80 };
+        (authority, format!("{}:{}", authority, port))
+    };
+    let addresses: Vec<_> = address.to_socket_addrs()?.collect();
+    if addresses.is_empty() {
+        return Err(invalid("Cannot resolve host"));
+    }
+    let stream = connect(&addresses)?;
+    stream.set_read_timeout(Some(WEBHOOK_TIMEOUT))?;
+    stream.set_write_timeout(Some(WEBHOOK_TIMEOUT))?;
+
+    let body = serde_json::to_vec(summary)?;
+    let status_line = if https {
+        post_tls(host, stream, path, authority, &body)?
+    } else {
+        post(stream, path, authority, &body)?
+    };
+    let status_line = status_line.trim_end();
+    match status_line.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(io::Error::new(
+            ErrorKind::Other,
+            format!("Webhook {} responded with: {}", url, status_line),
+        )),
+    }
+}
+
+/// Connects to the first address accepting the connection.
+/// Returns the error of the last address if none of them does.
+fn connect(addresses: &[SocketAddr]) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for address in addresses {
+        match TcpStream::connect_timeout(address, WEBHOOK_TIMEOUT) {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error
+        .unwrap_or_else(|| io::Error::new(ErrorKind::InvalidInput, "No address to connect to")))
+}
+
+#[cfg(feature = "https")]
+fn post_tls(
+    host: &str,
+    stream: TcpStream,
+    path: &str,
+    authority: &str,
+    body: &[u8],
+) -> io::Result<String> {
+    let tls_error =
+        |e: &dyn std::fmt::Display| io::Error::new(ErrorKind::Other, format!("TLS error: {}", e));
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let connector = native_tls::TlsConnector::new().map_err(|e| tls_error(&e))?;
+    let stream = connector.connect(host, stream).map_err(|e| tls_error(&e))?;
+    post(stream, path, authority, body)
+}
+
+#[cfg(not(feature = "https"))]
+fn post_tls(
+    _host: &str,
+    _stream: TcpStream,
+    _path: &str,
+    _authority: &str,
+    _body: &[u8],
+) -> io::Result<String> {
+    Err(io::Error::new(
+        ErrorKind::Unsupported,
+        "HTTPS is not supported by this build, use --notify-cmd with an HTTP client instead",
+    ))
+}
+
+/// Sends the POST request with the given body and returns the status line of the response
+fn post(
+    mut stream: impl Read + Write,
+    path: &str,
+    authority: &str,
+    body: &[u8],
+) -> io::Result<String> {
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         User-Agent: fclones/{}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n",
+        path,
+        authority,
+        env!("CARGO_PKG_VERSION"),
+        body.len()
+    )?;
+    stream.write_all(body)?;
+    stream.flush()?;
+
+    let mut status_line = String::new();
+    BufReader::new(stream).read_line(&mut status_line)?;
+    Ok(status_line)
+}
+
+/// Runs the given shell command with the summary on its standard input
+/// and waits for it to finish
+pub fn run_command(command: &str, summary: &RunSummary) -> io::Result<()> {
+    let args = shell_words::split(command)
+        .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+    let (program, args) = args
+        .split_first()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "Empty command".to_string()))?;
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .spawn()?;
+    let body = serde_json::to_vec(summary)?;
+    let written = child.stdin.take().unwrap().write_all(&body);
+    let status = child.wait()?;
+    // A command not interested in the summary may exit without reading it
+    match written {
+        Err(e) if e.kind() != ErrorKind::BrokenPipe => return Err(e),
+        _ => {}
+    }
+    if !status.success() {
+        return Err(io::Error::new(
+            ErrorKind::Other,
+            format!("{} exited with {}", program, status),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::thread;
+
+    use super::*;
+
+    fn summary() -> RunSummary {
+        RunSummary::new(Local::now().into(), Some("failure".to_owned()), 2)
+    }
+
+    #[test]
+    fn posts_summary_to_webhook() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0; 4096];
+            while !request.ends_with(b"}") {
+                let n = stream.read(&mut buf).unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+        let url = format!("http://127.0.0.1:{}/hooks/fclones", port);
+        post_webhook(&url, &summary()).unwrap();
+        let request = server.join().unwrap();
+        assert!(request.starts_with("POST /hooks/fclones HTTP/1.1\r\n"));
+        assert!(request.contains("\"status\":\"failed\""));
+        assert!(request.contains("\"error\":\"failure\""));
+        assert!(request.contains("\"warning_count\":2"));
+    }
+
+    #[test]
+    fn rejects_unsupported_urls() {
+        assert!(post_webhook("ftp://example.com/hook", &summary()).is_err());
+        assert!(post_webhook("http://user@example.com/hook", &summary()).is_err());
+    }
+
+    #[test]
+    fn connects_to_next_address_if_first_refuses() {
+        let refusing = TcpListener::bind("127.0.0.1:0").unwrap();
+        let refusing_address = refusing.local_addr().unwrap();
+        drop(refusing);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let stream = connect(&[refusing_address, address]).unwrap();
+        assert_eq!(stream.peer_addr().unwrap(), address);
+        assert!(connect(&[refusing_address]).is_err());
+    }
+
+    #[test]
+    #[cfg(feature = "https")]
+    fn https_webhook_requires_tls_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let _ = stream.write_all(b"HTTP/1.1 204 No Content\r\n\r\n");
+        });
+        let url = format!("https://127.0.0.1:{}/hooks/fclones", port);
+        let e = post_webhook(&url, &summary()).unwrap_err();
+        assert!(e.to_string().starts_with("TLS error"), "{}", e);
+        server.join().unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn fails_if_command_fails() {
+        run_command("true", &summary()).unwrap();
+        run_command("sh -c 'cat >/dev/null'", &summary()).unwrap();
+        assert!(run_command("false", &summary()).is_err());
+    }
+}
@@ -0,0 +1,35 @@
+//! Looking up the owners of files and the names of the users.
+
+use std::io;
+
+use crate::path::Path;
+
+/// Returns the user id of the owner of the file
+#[cfg(unix)]
+pub fn file_owner(path: &Path) -> io::Result<u32> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(std::fs::symlink_metadata(path.to_path_buf())?.uid())
+}
+
+#[cfg(not(unix))]
+pub fn file_owner(_path: &Path) -> io::Result<u32> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "File owners are supported on Unix only",
+    ))
+}
+
+/// Returns the name of the user with the given id, or `None` if there is no such user
+#[cfg(unix)]
+pub fn user_name(uid: u32) -> Option<String> {
+    use nix::unistd::{Uid, User};
+    User::from_uid(Uid::from_raw(uid))
+        .ok()
+        .flatten()
+        .map(|u| u.name)
+}
+
+#[cfg(not(unix))]
+pub fn user_name(_uid: u32) -> Option<String> {
+    None
+}
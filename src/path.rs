@@ -4,7 +4,8 @@ use std::ffi::{CStr, CString, OsString};
 use std::fmt;
 use std::fmt::Display;
 use std::hash::Hash;
-use std::path::{Component, PathBuf};
+use std::iter::once;
+use std::path::{Component, PathBuf, Prefix};
 use std::sync::Arc;
 
 use metrohash::MetroHash128;
@@ -12,6 +13,7 @@ use nom::lib::std::fmt::Formatter;
 use serde::{Serialize, Serializer};
 use smallvec::SmallVec;
 
+use crate::config::UnicodeForm;
 use crate::path::string::{c_to_os_str, os_to_c_str};
 
 #[cfg(unix)]
@@ -20,6 +22,18 @@ pub const PATH_ESCAPE_CHAR: &str = "\\";
 #[cfg(windows)]
 pub const PATH_ESCAPE_CHAR: &str = "^";
 
+#[cfg(unix)]
+const ROOT_DIR: &str = "/";
+
+#[cfg(windows)]
+const ROOT_DIR: &str = "\\";
+
+/// Paths at least this long are passed to the Windows API in the extended-length form.
+/// The limit for directories is by 12 characters lower than `MAX_PATH` (260),
+/// so that a file name in 8.3 format can still be appended.
+#[cfg(windows)]
+const MAX_PATH_LEN: usize = 248;
+
 /// Memory-efficient file path representation.
 ///
 /// When storing multiple paths with common parent, the standard [`std::path::PathBuf`]
@@ -41,16 +55,32 @@ impl Path {
         }
     }
 
+    /// Returns the canonical path if this path contains `.` or `..` components.
+    /// Otherwise returns the path unchanged, keeping the symbolic links in it.
+    pub fn resolve_dots(&self) -> Path {
+        let mut has_dots = false;
+        self.for_each_component(|c| has_dots |= c.to_bytes() == b"." || c.to_bytes() == b"..");
+        if has_dots {
+            self.canonicalize()
+        } else {
+            self.clone()
+        }
+    }
+
     #[cfg(unix)]
     pub fn is_absolute(&self) -> bool {
         let root = self.root().component.as_bytes();
         root == b"/"
     }
 
+    /// Returns true if the path starts with a drive or UNC share prefix followed by the root dir.
+    /// Drive-relative paths like `C:foo` and root-relative paths like `\foo` are not absolute.
     #[cfg(windows)]
     pub fn is_absolute(&self) -> bool {
-        let root = self.root().component.as_bytes();
-        root[root.len() - 1] == b':'
+        let components = self.components();
+        components.len() >= 2
+            && is_prefix(components[0].to_bytes())
+            && components[1].to_bytes() == ROOT_DIR.as_bytes()
     }
 
     pub fn is_relative(&self) -> bool {
@@ -86,24 +116,43 @@ impl Path {
 
     /// If `path` is relative, works the same as [`join`].
     /// If `path` is absolute, ignores `self` and returns `path`.
+    ///
+    /// On Windows, a root-relative `path` like `\foo` is resolved against the drive of `self`.
+    /// A drive-relative `path` like `C:foo` is resolved against `self` if `self` is on the same
+    /// drive. Otherwise it is resolved against the current directory of that drive if it exists
+    /// or against the root of that drive if it doesn't.
     pub fn resolve<P: AsRef<Path>>(self: &Arc<Path>, path: P) -> Path {
         let path = path.as_ref();
-        if path.is_relative() {
-            self.join(path)
-        } else {
-            path.clone()
+        if path.is_absolute() {
+            return path.clone();
+        }
+        let components = path.components();
+        let first = components[0].to_bytes();
+        if cfg!(windows) && is_prefix(first) {
+            let rest = components.iter().skip(1).copied();
+            if self.is_absolute() && self.root().component.as_bytes().eq_ignore_ascii_case(first) {
+                return Path::make(self.components().into_iter().chain(rest));
+            }
+            if let Ok(p) = dunce::canonicalize(path.to_plain_path_buf()) {
+                return Path::from(p);
+            }
+            let root_dir = CString::new(ROOT_DIR).unwrap();
+            return Path::make(
+                once(components[0])
+                    .chain(once(root_dir.as_c_str()))
+                    .chain(rest),
+            );
+        }
+        if cfg!(windows) && first == ROOT_DIR.as_bytes() && self.is_absolute() {
+            return Path::make(once(self.root().component.as_c_str()).chain(components));
         }
+        self.join(path)
     }
 
     /// Returns the name of the last component of this path or None
     /// if the path is directory (e.g. root dir or parent dir).
     pub fn file_name(&self) -> Option<OsString> {
-        match self.component.as_bytes() {
-            b"/" => None,
-            b".." => None,
-            b"." => None,
-            _ => Some(c_to_os_str(self.component.as_c_str())),
-        }
+        self.file_name_cstr().map(c_to_os_str)
     }
 
     /// Returns the name of the last component of this path or None
@@ -114,6 +163,7 @@ impl Path {
             b"/" => None,
             b".." => None,
             b"." => None,
+            c if cfg!(windows) && (c == ROOT_DIR.as_bytes() || is_prefix(c)) => None,
             _ => Some(self.component.as_c_str()),
         }
     }
@@ -142,10 +192,11 @@ impl Path {
 
     /// If this path is absolute, strips the root component and returns a relative path.
     /// Otherwise returns a clone of this path.
-    /// E.g. `/foo/bar` becomes `foo/bar`
+    /// E.g. `/foo/bar` becomes `foo/bar` and `C:\foo\bar` becomes `foo\bar`.
     pub fn strip_root(&self) -> Path {
         if self.is_absolute() {
-            Path::make(self.components().into_iter().skip(1))
+            let root_len = if cfg!(windows) { 2 } else { 1 };
+            Path::make(self.components().into_iter().skip(root_len))
         } else {
             self.clone()
         }
@@ -162,22 +213,66 @@ impl Path {
             self_components.next();
             other_components.next();
         }
-        self_components.peek() == None
+        self_components.peek().is_none()
+    }
+
+    /// Converts this path to a standard library path buffer.
+    /// We need this to be able to use this path with other standard library I/O functions.
+    #[cfg(unix)]
+    pub fn to_path_buf(&self) -> PathBuf {
+        self.to_plain_path_buf()
     }
 
     /// Converts this path to a standard library path buffer.
     /// We need this to be able to use this path with other standard library I/O functions.
+    ///
+    /// Long absolute paths are converted to the extended-length form
+    /// (`\\?\C:\...` or `\\?\UNC\server\share\...`), so Windows API calls don't fail
+    /// on paths longer than `MAX_PATH`.
+    #[cfg(windows)]
     pub fn to_path_buf(&self) -> PathBuf {
+        let path = self.to_plain_path_buf();
+        if path.as_os_str().len() < MAX_PATH_LEN {
+            return path;
+        }
+        match path.to_str().and_then(to_extended_length) {
+            Some(p) => PathBuf::from(p),
+            None => path,
+        }
+    }
+
+    /// Converts this path to a standard library path buffer, without adding any prefixes
+    fn to_plain_path_buf(&self) -> PathBuf {
         let mut result = PathBuf::from(OsString::with_capacity(self.capacity()));
         self.for_each_component(|c| result.push(c_to_os_str(c)));
         result
     }
 
+    /// Converts this path to a string, or returns `None` if the path is not valid Unicode.
+    pub fn to_str(&self) -> Option<String> {
+        self.to_plain_path_buf().into_os_string().into_string().ok()
+    }
+
+    /// Returns the bytes of this path as given by the operating system.
+    /// On Windows, where paths are always valid Unicode here, returns the bytes of the UTF-8 string.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        string::os_to_bytes(self.to_plain_path_buf().into_os_string())
+    }
+
+    /// Creates a path from the bytes returned by [`Path::to_bytes`].
+    /// Returns `None` if the bytes can't form a valid path on this platform.
+    pub fn from_bytes(bytes: Vec<u8>) -> Option<Path> {
+        if bytes.is_empty() || bytes.contains(&0) {
+            return None;
+        }
+        string::bytes_to_os(bytes).map(Path::from)
+    }
+
     /// Converts this path to an UTF encoded string.
     /// Any non-Unicode sequences are replaced with
     /// [`U+FFFD REPLACEMENT CHARACTER`][U+FFFD].
     pub fn to_string_lossy(&self) -> String {
-        self.to_path_buf().to_string_lossy().to_string()
+        self.to_plain_path_buf().to_string_lossy().to_string()
     }
 
     pub fn display(&self) -> &Self {
@@ -194,6 +289,33 @@ impl Path {
         (a as u128) << 64 | (b as u128)
     }
 
+    /// Returns a hash of the full path that is the same for paths differing only in letter case.
+    pub fn hash128_ignore_case(&self) -> u128 {
+        let mut hasher = MetroHash128::new();
+        self.to_string_lossy().to_lowercase().hash(&mut hasher);
+        let (a, b) = hasher.finish128();
+        (a as u128) << 64 | (b as u128)
+    }
+
+    /// Returns this path converted to the given Unicode normalization form.
+    /// Any non-Unicode sequences are replaced with U+FFFD, so the result should be used
+    /// only for comparing paths, not for accessing files.
+    pub fn normalized(&self, form: UnicodeForm) -> Path {
+        Path::from(form.normalize(&self.to_string_lossy()))
+    }
+
+    /// Returns true if the paths are equal after converting them to lower case.
+    pub fn eq_ignore_case(&self, other: &Path) -> bool {
+        self.to_string_lossy().to_lowercase() == other.to_string_lossy().to_lowercase()
+    }
+
+    /// Creates the path of the entry named `name` in the directory `self`.
+    /// Cheaper than [`join`](Path::join), because `name` is not parsed into components,
+    /// so it must be a single file name, without any separators.
+    pub(crate) fn child(self: &Arc<Path>, name: CString) -> Path {
+        self.push(name)
+    }
+
     fn new(component: CString) -> Path {
         Path {
             component,
@@ -270,9 +392,29 @@ impl Path {
         result
     }
 
+    /// Quotes the path so it can be passed to the shell.
+    /// Paths that are not valid Unicode are written in the `$'...'` form with the bytes
+    /// escaped, understood by bash, zsh and ksh.
     #[cfg(unix)]
     pub fn shell_quote(&self) -> String {
-        shell_words::quote(self.to_string().as_str()).into()
+        match self.to_str() {
+            Some(s) => shell_words::quote(s.as_str()).into(),
+            None => {
+                let mut quoted = String::from("$'");
+                for b in self.to_bytes() {
+                    match b {
+                        b'\'' | b'\\' => {
+                            quoted.push('\\');
+                            quoted.push(b as char)
+                        }
+                        0x20..=0x7e => quoted.push(b as char),
+                        _ => quoted.push_str(&format!("\\x{:02x}", b)),
+                    }
+                }
+                quoted.push('\'');
+                quoted
+            }
+        }
     }
 
     #[cfg(windows)]
@@ -296,9 +438,51 @@ impl AsRef<Path> for Path {
     }
 }
 
-/// Converts std path Component to a new CString
+/// Returns true if the path component is a Windows drive or UNC share prefix,
+/// e.g. `C:` or `\\server\share`.
+fn is_prefix(component: &[u8]) -> bool {
+    component.len() >= 2 && (component.ends_with(b":") || component.starts_with(b"\\\\"))
+}
+
+/// Converts std path Component to a new CString.
+/// Verbatim prefixes are replaced by their regular forms, i.e. `\\?\C:` becomes `C:`
+/// and `\\?\UNC\server\share` becomes `\\server\share`, so the same file is always
+/// represented by the same [`Path`], no matter which form it was given in.
 fn component_to_c_string(c: &Component<'_>) -> CString {
-    os_to_c_str(c.as_os_str())
+    match c {
+        Component::Prefix(prefix) => match prefix.kind() {
+            Prefix::VerbatimDisk(drive) => CString::new(vec![drive, b':']).unwrap(),
+            Prefix::VerbatimUNC(server, share) => {
+                let mut unc = OsString::from("\\\\");
+                unc.push(server);
+                unc.push("\\");
+                unc.push(share);
+                os_to_c_str(&unc)
+            }
+            _ => os_to_c_str(c.as_os_str()),
+        },
+        _ => os_to_c_str(c.as_os_str()),
+    }
+}
+
+/// Returns the extended-length form of an absolute Windows path,
+/// or `None` if the path is relative or already uses a verbatim or device prefix.
+#[cfg(any(windows, test))]
+fn to_extended_length(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    if path.starts_with("\\\\?\\") || path.starts_with("\\\\.\\") {
+        None
+    } else if let Some(unc) = path.strip_prefix("\\\\") {
+        Some(format!("\\\\?\\UNC\\{}", unc))
+    } else if bytes.len() >= 3
+        && bytes[0].is_ascii_alphabetic()
+        && bytes[1] == b':'
+        && bytes[2] == b'\\'
+    {
+        Some(format!("\\\\?\\{}", path))
+    } else {
+        None
+    }
 }
 
 impl<P> From<P> for Path
@@ -320,22 +504,27 @@ where
 
 impl Display for Path {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        f.pad(format!("{}", self.to_path_buf().display()).as_str())
+        f.pad(format!("{}", self.to_plain_path_buf().display()).as_str())
     }
 }
 
+/// Paths that are not valid Unicode are serialized as `file://` URIs
+/// with the bytes percent-encoded, so they can be restored exactly.
 impl Serialize for Path {
     fn serialize<S>(&self, serializer: S) -> Result<<S as Serializer>::Ok, <S as Serializer>::Error>
     where
         S: Serializer,
     {
-        serializer.collect_str(self)
+        match self.to_str() {
+            Some(s) => serializer.serialize_str(&s),
+            None => serializer.serialize_str(&crate::report::to_file_uri(&self.to_bytes())),
+        }
     }
 }
 
 impl fmt::Debug for Path {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", self.to_path_buf().display())
+        write!(f, "{}", self.to_plain_path_buf().display())
     }
 }
 
@@ -354,6 +543,18 @@ mod string {
         CString::new(str.as_bytes()).unwrap()
     }
 
+    #[cfg(unix)]
+    pub fn os_to_bytes(str: OsString) -> Vec<u8> {
+        use std::os::unix::ffi::OsStringExt;
+        str.into_vec()
+    }
+
+    #[cfg(unix)]
+    pub fn bytes_to_os(bytes: Vec<u8>) -> Option<OsString> {
+        use std::os::unix::ffi::OsStringExt;
+        Some(OsString::from_vec(bytes))
+    }
+
     #[cfg(windows)]
     pub fn c_to_os_str(str: &CStr) -> OsString {
         OsString::from(str.to_str().unwrap())
@@ -363,6 +564,16 @@ mod string {
     pub fn os_to_c_str(str: &OsStr) -> CString {
         CString::new(str.to_str().unwrap().as_bytes()).unwrap()
     }
+
+    #[cfg(windows)]
+    pub fn os_to_bytes(str: OsString) -> Vec<u8> {
+        str.to_string_lossy().into_owned().into_bytes()
+    }
+
+    #[cfg(windows)]
+    pub fn bytes_to_os(bytes: Vec<u8>) -> Option<OsString> {
+        String::from_utf8(bytes).ok().map(OsString::from)
+    }
 }
 
 #[cfg(test)]
@@ -462,4 +673,125 @@ mod test {
         assert!(Path::from("/foo/bar").is_prefix_of(&Path::from("/foo/bar/baz")));
         assert!(!Path::from("/foo/bar").is_prefix_of(&Path::from("/foo")))
     }
+
+    #[test]
+    #[cfg(unix)]
+    fn shell_quote_non_unicode() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        let path = Path::from(OsStr::from_bytes(b"/a'b/c\xff"));
+        assert_eq!(path.shell_quote(), "$'/a\\'b/c\\xff'");
+        assert_eq!(Path::from("/a b").shell_quote(), "'/a b'");
+    }
+
+    #[test]
+    fn normalized() {
+        let nfc = Path::from("/photos/caf\u{e9}.jpg");
+        let nfd = Path::from("/photos/cafe\u{301}.jpg");
+        assert_ne!(nfc, nfd);
+        assert_eq!(nfd.normalized(UnicodeForm::Nfc), nfc);
+        assert_eq!(nfc.normalized(UnicodeForm::Nfd), nfd);
+    }
+
+    #[test]
+    fn ignore_case() {
+        let a = Path::from("/Foo/BAR.txt");
+        let b = Path::from("/foo/bar.TXT");
+        assert!(a.eq_ignore_case(&b));
+        assert_eq!(a.hash128_ignore_case(), b.hash128_ignore_case());
+        assert_ne!(a.hash128(), b.hash128());
+        assert!(!a.eq_ignore_case(&Path::from("/foo/baz.txt")));
+    }
+
+    #[test]
+    fn extended_length() {
+        assert_eq!(
+            to_extended_length(r"C:\foo\bar"),
+            Some(r"\\?\C:\foo\bar".to_owned())
+        );
+        assert_eq!(
+            to_extended_length(r"\\server\share\foo"),
+            Some(r"\\?\UNC\server\share\foo".to_owned())
+        );
+        assert_eq!(to_extended_length(r"\\?\C:\foo"), None);
+        assert_eq!(to_extended_length(r"\\.\pipe\foo"), None);
+        assert_eq!(to_extended_length(r"C:foo"), None);
+        assert_eq!(to_extended_length(r"foo\bar"), None);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn windows_absolute_paths() {
+        assert!(Path::from(r"C:\foo").is_absolute());
+        assert!(Path::from(r"\\server\share\foo").is_absolute());
+        assert!(Path::from(r"\\?\C:\foo").is_absolute());
+        assert!(!Path::from(r"C:foo").is_absolute());
+        assert!(!Path::from(r"\foo").is_absolute());
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn windows_verbatim_prefix_is_normalized() {
+        assert_eq!(Path::from(r"\\?\C:\foo"), Path::from(r"C:\foo"));
+        assert_eq!(
+            Path::from(r"\\?\UNC\server\share\foo"),
+            Path::from(r"\\server\share\foo")
+        );
+        assert_eq!(Path::from(r"\\?\C:\foo").to_string(), r"C:\foo");
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn windows_long_path_uses_extended_length_form() {
+        let name = "a".repeat(100);
+        let path = Path::from(format!(r"C:\{}\{}\{}", name, name, name));
+        let path_buf = path.to_path_buf();
+        assert!(path_buf.to_str().unwrap().starts_with(r"\\?\C:\"));
+        assert_eq!(Path::from(path_buf), path);
+        assert!(!path.to_string().starts_with(r"\\?\"));
+
+        let path = Path::from(format!(r"\\server\share\{}\{}\{}", name, name, name));
+        let path_buf = path.to_path_buf();
+        assert!(path_buf
+            .to_str()
+            .unwrap()
+            .starts_with(r"\\?\UNC\server\share\"));
+        assert_eq!(Path::from(path_buf), path);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn windows_resolve() {
+        let base = Path::from(r"C:\base").share();
+        assert_eq!(base.resolve(Path::from("foo")), Path::from(r"C:\base\foo"));
+        assert_eq!(base.resolve(Path::from(r"\foo")), Path::from(r"C:\foo"));
+        assert_eq!(
+            base.resolve(Path::from(r"C:foo")),
+            Path::from(r"C:\base\foo")
+        );
+        assert_eq!(base.resolve(Path::from(r"D:\foo")), Path::from(r"D:\foo"));
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn windows_strip_root() {
+        assert_eq!(
+            Path::from(r"C:\foo\bar").strip_root(),
+            Path::from(r"foo\bar")
+        );
+        assert_eq!(
+            Path::from(r"\\server\share\foo").strip_root(),
+            Path::from("foo")
+        );
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn windows_file_name() {
+        assert_eq!(Path::from(r"C:\").file_name_cstr(), None);
+        assert_eq!(
+            Path::from(r"C:\foo").file_name(),
+            Some(OsString::from("foo"))
+        );
+    }
 }
@@ -11,8 +11,10 @@ use nom::sequence::tuple;
 use nom::IResult;
 use regex::escape;
 
+use crate::config::UnicodeForm;
 use crate::path::PATH_ESCAPE_CHAR;
 use crate::regex::Regex;
+use std::borrow::Cow;
 use std::str::FromStr;
 
 #[derive(Debug)]
@@ -38,6 +40,8 @@ pub struct Pattern {
     src: String,
     anchored_regex: Regex,
     prefix_regex: Regex,
+    case_insensitive: bool,
+    normalization: Option<UnicodeForm>,
 }
 
 impl FromStr for Pattern {
@@ -48,23 +52,24 @@ impl FromStr for Pattern {
     }
 }
 
+#[derive(Default)]
 pub struct PatternOpts {
     case_insensitive: bool,
+    normalization: Option<UnicodeForm>,
 }
 
 impl PatternOpts {
     pub fn case_insensitive() -> PatternOpts {
         PatternOpts {
             case_insensitive: true,
+            normalization: None,
         }
     }
-}
 
-impl Default for PatternOpts {
-    fn default() -> PatternOpts {
-        PatternOpts {
-            case_insensitive: false,
-        }
+    /// Converts both the pattern and the matched paths to the given Unicode normalization form
+    pub fn normalized(mut self, form: Option<UnicodeForm>) -> PatternOpts {
+        self.normalization = form;
+        self
     }
 }
 
@@ -86,7 +91,10 @@ impl Pattern {
     pub fn regex_with(pattern: &str, opts: &PatternOpts) -> Result<Pattern, PatternError> {
         let pattern = pattern.trim_start_matches('^');
         let pattern = pattern.trim_end_matches('$');
-        let pattern = pattern.to_string();
+        let pattern = match opts.normalization {
+            Some(form) => form.normalize(pattern),
+            None => pattern.to_string(),
+        };
 
         let anchored_regex = "^".to_string() + &pattern + "$";
         let anchored_regex = Regex::new(anchored_regex.as_str(), opts.case_insensitive);
@@ -98,6 +106,8 @@ impl Pattern {
                 src: pattern,
                 anchored_regex,
                 prefix_regex: prefix_regex.unwrap(),
+                case_insensitive: opts.case_insensitive,
+                normalization: opts.normalization,
             }),
             Err(e) => Err(PatternError {
                 input: pattern,
@@ -139,9 +149,7 @@ impl Pattern {
     pub fn glob_with(glob: &str, opts: &PatternOpts) -> Result<Pattern, PatternError> {
         let result: IResult<&str, String> = Self::glob_to_regex(Scope::TopLevel, glob);
         match result {
-            Ok((remaining, regex)) if remaining.is_empty() => {
-                Self::regex_with(regex.as_str(), opts)
-            }
+            Ok(("", regex)) => Self::regex_with(regex.as_str(), opts),
             Ok((remaining, _)) => Err(PatternError {
                 input: glob.to_string(),
                 cause: format!(
@@ -156,25 +164,42 @@ impl Pattern {
         }
     }
 
+    /// Returns a copy of this pattern that converts both itself and the matched paths
+    /// to the given Unicode normalization form
+    pub fn normalized(&self, form: Option<UnicodeForm>) -> Pattern {
+        let opts = PatternOpts {
+            case_insensitive: self.case_insensitive,
+            normalization: form,
+        };
+        Self::regex_with(&self.src, &opts).unwrap()
+    }
+
     /// Returns true if this pattern fully matches the given path
     pub fn matches(&self, path: &str) -> bool {
-        self.anchored_regex.is_match(path)
+        self.anchored_regex.is_match(&self.normalize(path))
     }
 
     /// Returns true if a prefix of this pattern fully matches the given path
     pub fn matches_partially(&self, path: &str) -> bool {
-        self.anchored_regex.is_partial_match(path)
+        self.anchored_regex.is_partial_match(&self.normalize(path))
     }
 
     /// Returns true if this pattern fully matches a prefix of the given path
     pub fn matches_prefix(&self, path: &str) -> bool {
-        self.prefix_regex.is_match(path)
+        self.prefix_regex.is_match(&self.normalize(path))
     }
 
     /// Returns true if this pattern fully matches given file path
     pub fn matches_path(&self, path: &Path) -> bool {
-        self.anchored_regex
-            .is_match(path.to_string_lossy().as_ref())
+        self.matches(path.to_string_lossy().as_ref())
+    }
+
+    /// Converts the matched path to the normalization form of this pattern
+    fn normalize<'a>(&self, path: &'a str) -> Cow<'a, str> {
+        match self.normalization {
+            Some(form) => Cow::Owned(form.normalize(path)),
+            None => Cow::Borrowed(path),
+        }
     }
 
     /// Parses a UNIX glob and converts it to a regular expression
@@ -288,13 +313,14 @@ impl Add<Pattern> for Pattern {
     type Output = Pattern;
 
     fn add(self, rhs: Pattern) -> Self::Output {
-        Pattern::regex((self.to_string() + &rhs.to_string()).as_str()).unwrap()
+        let opts = PatternOpts::default().normalized(self.normalization.or(rhs.normalization));
+        Pattern::regex_with((self.to_string() + &rhs.to_string()).as_str(), &opts).unwrap()
     }
 }
 
-impl ToString for Pattern {
-    fn to_string(&self) -> String {
-        self.src.clone()
+impl Display for Pattern {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.pad(&self.src)
     }
 }
 
@@ -331,6 +357,25 @@ mod test {
         assert_eq!(glob_to_regex_str("foo\\}"), "foo\\}");
     }
 
+    #[test]
+    fn unicode_normalization() {
+        let nfc = "caf\u{e9}.jpg";
+        let nfd = "cafe\u{301}.jpg";
+        let p = Pattern::glob(nfc).unwrap();
+        assert!(p.matches(nfc));
+        assert!(!p.matches(nfd));
+
+        for form in [UnicodeForm::Nfc, UnicodeForm::Nfd] {
+            let opts = PatternOpts::default().normalized(Some(form));
+            let p = Pattern::glob_with(nfc, &opts).unwrap();
+            assert!(p.matches(nfc));
+            assert!(p.matches(nfd));
+            let p = Pattern::glob(nfd).unwrap().normalized(Some(form));
+            assert!(p.matches(nfc));
+            assert!(p.matches(nfd));
+        }
+    }
+
     #[test]
     fn question_mark() {
         let p = Pattern::glob("foo???").unwrap();
@@ -0,0 +1,537 @@
+//! Saving deduplication plans produced by `--dry-run` and comparing them.
+//!
+//! A plan is written as one line of JSON per action, with the kind of the action,
+//! the paths involved and the hash and size of the file. Comparing the plan of a new dry run
+//! to an earlier one shows only the actions that changed, e.g. because the files changed
+//! in the meantime or because the run was given different options.
+//! Before running a plan, [`PlanStats`] estimates how much work it is.
+
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crossbeam_utils::atomic::AtomicCell;
+use dashmap::DashMap;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use serde::{Deserialize, Serialize};
+use sysinfo::DiskType;
+
+use crate::annotation::Annotations;
+use crate::config::Verify;
+use crate::dedupe::{DedupeResult, FsCommand};
+use crate::device::{DiskDevice, DiskDevices};
+use crate::files::{group_id, FileLen};
+use crate::path::Path;
+use crate::report::to_file_uri;
+
+/// A single action of a plan
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PlanEntry {
+    /// `remove`, `move`, `soft_link` or `hard_link`
+    pub action: String,
+    /// The file to be removed, moved or replaced by a link
+    pub source: String,
+    /// The target of the move or of the link
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub destination: Option<String>,
+    pub hash: String,
+    pub size: u64,
+    /// Identifier of the group of the file in the report
+    #[serde(default)]
+    pub group: String,
+    /// Annotations of the group in the report, see [`annotation`](crate::annotation)
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: Annotations,
+}
+
+/// Annotations of the groups of the deduplicated report, by group identifier.
+/// Filled while the groups are read, so the actions of the plan can be annotated
+/// like the groups they come from.
+pub type GroupAnnotations = DashMap<String, Annotations>;
+
+fn path_to_string(path: &Path) -> String {
+    path.to_str()
+        .unwrap_or_else(|| to_file_uri(&path.to_bytes()))
+}
+
+impl PlanEntry {
+    pub fn new(cmd: &FsCommand) -> PlanEntry {
+        let (action, source, destination, hash) = cmd.describe();
+        PlanEntry {
+            action: action.to_owned(),
+            source: path_to_string(&source.path),
+            destination: destination.map(path_to_string),
+            hash: hash.to_string(),
+            size: source.metadata.len(),
+            group: group_id(FileLen(source.metadata.len()), hash),
+            annotations: Annotations::new(),
+        }
+    }
+}
+
+impl Display for PlanEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.action, self.source)?;
+        if let Some(destination) = &self.destination {
+            write!(f, " -> {}", destination)?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes the actions of a script generated by [`dedupe`](crate::dedupe) as a plan,
+/// one line of JSON per action.
+/// Each action gets the annotations of its group found in `annotations`.
+///
+/// Does not perform any filesystem changes.
+/// Returns the number of files processed and the amount of disk space that would be
+/// reclaimed if all actions of the plan were executed with no error.
+pub fn write_plan(
+    script: impl IntoParallelIterator<Item = FsCommand>,
+    annotations: &GroupAnnotations,
+    out: impl Write + Send,
+) -> io::Result<DedupeResult> {
+    let writer = Mutex::new(BufWriter::new(out));
+    let err = AtomicCell::new(None);
+    let result = script
+        .into_par_iter()
+        .map(|cmd| {
+            let mut entry = PlanEntry::new(&cmd);
+            if let Some(a) = annotations.get(&entry.group) {
+                entry.annotations = a.clone();
+            }
+            let mut line = match serde_json::to_vec(&entry) {
+                Ok(line) => line,
+                Err(e) => {
+                    err.store(Some(e.into()));
+                    return None;
+                }
+            };
+            line.push(b'\n');
+            if let Err(e) = writer.lock().unwrap().write_all(&line) {
+                err.store(Some(e));
+                return None;
+            }
+            Some(DedupeResult {
+                processed_count: 1,
+                reclaimed_space: cmd.space_to_reclaim(),
+                failed_count: 0,
+            })
+        })
+        .while_some()
+        .reduce(DedupeResult::default, |a, b| a + b);
+
+    if let Err(e) = writer.into_inner().unwrap().flush() {
+        err.store(Some(e));
+    }
+    match err.take() {
+        None => Ok(result),
+        Some(e) => Err(e),
+    }
+}
+
+/// Reads a plan written by [`write_plan`]
+pub fn read_plan(path: &std::path::Path) -> io::Result<Vec<PlanEntry>> {
+    let error = |e: io::Error| {
+        io::Error::new(
+            e.kind(),
+            format!("Failed to read plan {}: {}", path.display(), e),
+        )
+    };
+    let file = File::open(path).map_err(error)?;
+    let mut entries = Vec::new();
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(error)?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let entry = serde_json::from_str(&line).map_err(|e| {
+            error(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("Malformed action in line {}: {}", i + 1, e),
+            ))
+        })?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+/// Differences between two plans.
+/// The actions are matched by their source files, and are sorted by them.
+#[derive(Debug, Default)]
+pub struct PlanDiff {
+    /// Actions present only in the new plan
+    pub added: Vec<PlanEntry>,
+    /// Actions present only in the old plan
+    pub removed: Vec<PlanEntry>,
+    /// Actions on the same files that differ between the plans, old first
+    pub changed: Vec<(PlanEntry, PlanEntry)>,
+    /// Number of actions equal in both plans
+    pub unchanged_count: usize,
+}
+
+impl PlanDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Finds the actions that differ between the plans
+pub fn diff_plans(old: Vec<PlanEntry>, mut new: Vec<PlanEntry>) -> PlanDiff {
+    let mut old: BTreeMap<String, PlanEntry> =
+        old.into_iter().map(|e| (e.source.clone(), e)).collect();
+    let mut diff = PlanDiff::default();
+    new.sort_by(|a, b| a.source.cmp(&b.source));
+    for entry in new {
+        match old.remove(&entry.source) {
+            Some(old_entry) if old_entry == entry => diff.unchanged_count += 1,
+            Some(old_entry) => diff.changed.push((old_entry, entry)),
+            None => diff.added.push(entry),
+        }
+    }
+    diff.removed = old.into_iter().map(|(_, e)| e).collect();
+    diff
+}
+
+/// Lists the differences, one action per line, with `-` for the removed actions,
+/// `+` for the added ones and `~` for the changed ones
+impl Display for PlanDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        for e in self.removed.iter() {
+            writeln!(f, "- {}", e)?;
+        }
+        for e in self.added.iter() {
+            writeln!(f, "+ {}", e)?;
+        }
+        for (old, new) in self.changed.iter() {
+            write!(f, "~ {}: ", new.source)?;
+            if old.action != new.action || old.destination != new.destination {
+                write!(f, "{}", old.action)?;
+                if let Some(destination) = &old.destination {
+                    write!(f, " -> {}", destination)?;
+                }
+                write!(f, " => {}", new.action)?;
+                if let Some(destination) = &new.destination {
+                    write!(f, " -> {}", destination)?;
+                }
+            } else {
+                write!(f, "{}", new.action)?;
+            }
+            if old.hash != new.hash || old.size != new.size {
+                write!(
+                    f,
+                    " (contents changed: {} B {} => {} B {})",
+                    old.size, old.hash, new.size, new.hash
+                )?;
+            }
+            if old.annotations != new.annotations {
+                write!(f, " (annotations changed)")?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// Number of the largest groups listed by [`PlanStats`]
+const LARGEST_GROUPS: usize = 5;
+
+/// Rough time of a single metadata operation, e.g. removing a file or creating a link,
+/// and the throughput of reading or writing file contents on a device
+fn device_model(device: &DiskDevice) -> (Duration, f64) {
+    const MB: f64 = 1_000_000.0;
+    if device.network {
+        return (Duration::from_millis(5), 50.0 * MB);
+    }
+    match device.disk_type {
+        DiskType::SSD => (Duration::from_micros(200), 500.0 * MB),
+        DiskType::HDD => (Duration::from_millis(5), 120.0 * MB),
+        DiskType::Removable => (Duration::from_millis(5), 30.0 * MB),
+        DiskType::Unknown(_) => (Duration::from_millis(1), 100.0 * MB),
+    }
+}
+
+fn transfer_time(bytes: u64, throughput: f64) -> Duration {
+    Duration::from_secs_f64(bytes as f64 / throughput)
+}
+
+/// Number and total size of files
+#[derive(Clone, Copy, Default)]
+struct Count {
+    files: u64,
+    bytes: u64,
+}
+
+impl Count {
+    fn add(&mut self, bytes: u64) {
+        self.files += 1;
+        self.bytes += bytes;
+    }
+}
+
+/// Work of a plan on a single device
+#[derive(Default)]
+struct DeviceStats {
+    count: Count,
+    duration: Duration,
+}
+
+/// Statistics of the actions of a plan with an estimate of how long running it would take,
+/// printed by `--dry-run --stats`.
+///
+/// The duration is estimated from a simple model of each device, with a fixed time
+/// of a metadata operation and a fixed throughput of reading and writing data depending
+/// on the type of the device. The devices are assumed to work in parallel, each processing
+/// one file at a time.
+pub struct PlanStats {
+    devices: DiskDevices,
+    verify: Verify,
+    actions: BTreeMap<&'static str, Count>,
+    per_device: BTreeMap<String, DeviceStats>,
+    groups: HashMap<String, Count>,
+}
+
+impl PlanStats {
+    /// Creates empty statistics of a plan to be executed with the given verification level
+    pub fn new(verify: Verify) -> PlanStats {
+        PlanStats {
+            devices: DiskDevices::new(&HashMap::new()),
+            verify,
+            actions: BTreeMap::new(),
+            per_device: BTreeMap::new(),
+            groups: HashMap::new(),
+        }
+    }
+
+    /// Accounts for the action
+    pub fn add(&mut self, cmd: &FsCommand) {
+        let (action, source, _, hash) = cmd.describe();
+        let len = source.metadata.len();
+        let device = self.devices.get_by_path(&source.path);
+        let (latency, throughput) = device_model(device);
+
+        let mut duration = match self.verify {
+            Verify::None | Verify::Stat => Duration::from_secs(0),
+            Verify::Prefix => latency,
+            Verify::Full => transfer_time(len, throughput),
+        };
+        let copied = match cmd {
+            FsCommand::Move { target, .. } => {
+                let target_device = self.devices.get_by_path(target);
+                (target_device.index != device.index).then(|| target_device)
+            }
+            _ => None,
+        };
+        let action = match (cmd, copied) {
+            (FsCommand::Move { .. }, Some(target_device)) => {
+                let (target_latency, target_throughput) = device_model(target_device);
+                duration += transfer_time(len, throughput.min(target_throughput));
+                duration += latency + target_latency;
+                "move (copy)"
+            }
+            // A link replaces the file in three steps: rename, link and remove
+            (FsCommand::SoftLink { .. }, _)
+            | (FsCommand::HardLink { .. }, _)
+            | (FsCommand::RefLink { .. }, _) => {
+                duration += latency * 3;
+                action
+            }
+            _ => {
+                duration += latency;
+                action
+            }
+        };
+
+        self.actions.entry(action).or_default().add(len);
+        let device_stats = self
+            .per_device
+            .entry(device.name.to_string_lossy().into_owned())
+            .or_default();
+        device_stats.count.add(len);
+        device_stats.duration += duration;
+        self.groups
+            .entry(group_id(FileLen(len), hash))
+            .or_default()
+            .add(len);
+    }
+
+    /// Returns the estimated time of running the whole plan
+    pub fn duration(&self) -> Duration {
+        self.per_device
+            .values()
+            .map(|d| d.duration)
+            .max()
+            .unwrap_or_default()
+    }
+}
+
+/// Formats the duration rounded to seconds, minutes or hours, depending on how long it is
+fn format_duration(d: Duration) -> String {
+    let secs = d.as_secs();
+    if secs >= 3600 {
+        format!("{} h {} min", secs / 3600, secs % 3600 / 60)
+    } else if secs >= 60 {
+        format!("{} min {} s", secs / 60, secs % 60)
+    } else {
+        format!("{:.1} s", d.as_secs_f64())
+    }
+}
+
+impl Display for PlanStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Actions:")?;
+        for (action, c) in self.actions.iter() {
+            writeln!(
+                f,
+                "  {:<12} {:>10} files {:>12}",
+                action,
+                c.files,
+                FileLen(c.bytes)
+            )?;
+        }
+        writeln!(f, "Devices:")?;
+        for (name, d) in self.per_device.iter() {
+            writeln!(
+                f,
+                "  {:<24} {:>10} files {:>12}   ~{}",
+                name,
+                d.count.files,
+                FileLen(d.count.bytes),
+                format_duration(d.duration)
+            )?;
+        }
+        let mut groups: Vec<_> = self.groups.iter().collect();
+        groups.sort_by_key(|(id, c)| (Reverse(c.bytes), id.to_string()));
+        writeln!(f, "Largest groups:")?;
+        for (id, c) in groups.into_iter().take(LARGEST_GROUPS) {
+            writeln!(f, "  {} {:>10} files {:>12}", id, c.files, FileLen(c.bytes))?;
+        }
+        writeln!(
+            f,
+            "Estimated duration: {}",
+            format_duration(self.duration())
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(action: &str, source: &str, destination: Option<&str>, hash: &str) -> PlanEntry {
+        PlanEntry {
+            action: action.to_owned(),
+            source: source.to_owned(),
+            destination: destination.map(|d| d.to_owned()),
+            hash: hash.to_owned(),
+            size: 100,
+            group: String::new(),
+            annotations: Annotations::new(),
+        }
+    }
+
+    #[test]
+    fn diff_reports_added_removed_and_changed_actions() {
+        let old = vec![
+            entry("remove", "/a", None, "01"),
+            entry("remove", "/b", None, "01"),
+            entry("hard_link", "/c", Some("/x"), "02"),
+            entry("remove", "/d", None, "03"),
+        ];
+        let new = vec![
+            entry("hard_link", "/c", Some("/y"), "02"),
+            entry("remove", "/e", None, "04"),
+            entry("remove", "/d", None, "05"),
+            entry("remove", "/a", None, "01"),
+        ];
+        let diff = diff_plans(old, new);
+        assert_eq!(diff.unchanged_count, 1);
+        assert_eq!(diff.removed, vec![entry("remove", "/b", None, "01")]);
+        assert_eq!(diff.added, vec![entry("remove", "/e", None, "04")]);
+        assert_eq!(diff.changed.len(), 2);
+        assert_eq!(
+            diff.to_string(),
+            "- remove /b\n\
+             + remove /e\n\
+             ~ /c: hard_link -> /x => hard_link -> /y\n\
+             ~ /d: remove (contents changed: 100 B 03 => 100 B 05)\n"
+        );
+    }
+
+    #[test]
+    fn write_and_read_plan() {
+        use crate::dedupe::FileMetadata;
+        use crate::files::FileHash;
+
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("file");
+        std::fs::write(&file, b"contents").unwrap();
+        let cmd = FsCommand::Remove {
+            file: FileMetadata::new(Path::from(&file)).unwrap(),
+            kept: None,
+            file_hash: FileHash::from(0xab),
+        };
+        let plan_file = dir.path().join("plan.json");
+        let annotations = GroupAnnotations::new();
+        let mut reviewed = Annotations::new();
+        reviewed.insert("reviewed".to_owned(), true.into());
+        annotations.insert(
+            group_id(FileLen(8), &FileHash::from(0xab)),
+            reviewed.clone(),
+        );
+        let result =
+            write_plan(vec![cmd], &annotations, File::create(&plan_file).unwrap()).unwrap();
+        assert_eq!(result.processed_count, 1);
+
+        let plan = read_plan(&plan_file).unwrap();
+        assert_eq!(plan.len(), 1);
+        assert_eq!(plan[0].action, "remove");
+        assert_eq!(plan[0].source, file.to_str().unwrap());
+        assert_eq!(plan[0].hash, FileHash::from(0xab).to_string());
+        assert_eq!(plan[0].size, 8);
+        assert_eq!(plan[0].annotations, reviewed);
+    }
+
+    #[test]
+    fn plan_stats() {
+        use crate::dedupe::FileMetadata;
+        use crate::files::FileHash;
+
+        let dir = tempfile::tempdir().unwrap();
+        let mut stats = PlanStats::new(Verify::Full);
+        for (name, len) in [("a", 100), ("b", 100), ("c", 10)].iter() {
+            let file = dir.path().join(name);
+            std::fs::write(&file, vec![0; *len]).unwrap();
+            stats.add(&FsCommand::Remove {
+                file: FileMetadata::new(Path::from(&file)).unwrap(),
+                kept: None,
+                file_hash: FileHash::from(*len as u128),
+            });
+        }
+        assert_eq!(stats.actions["remove"].files, 3);
+        assert_eq!(stats.actions["remove"].bytes, 210);
+        assert_eq!(stats.per_device.len(), 1);
+        assert!(stats.duration() > Duration::from_secs(0));
+
+        let report = stats.to_string();
+        assert!(report.contains("Estimated duration:"));
+        let largest = report.lines().skip_while(|l| *l != "Largest groups:");
+        let largest: Vec<_> = largest.skip(1).take(2).collect();
+        assert!(largest[0].contains(&group_id(FileLen(100), &FileHash::from(100))));
+        assert!(largest[1].contains(&group_id(FileLen(10), &FileHash::from(10))));
+    }
+
+    #[test]
+    fn diff_of_equal_plans_is_empty() {
+        let plan = vec![entry("remove", "/a", None, "01")];
+        let diff = diff_plans(plan.clone(), plan);
+        assert!(diff.is_empty());
+        assert_eq!(diff.unchanged_count, 1);
+    }
+}
@@ -3,9 +3,10 @@
 use atomic_counter::{AtomicCounter, RelaxedCounter};
 use console::style;
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
-use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 /// A wrapper over `indicatif::ProgressBar` that makes updating its progress lockless.
 /// Unfortunately `indicatif::ProgressBar` wraps state in a `Mutex`, so updates are slow
@@ -15,9 +16,27 @@ use std::time::Duration;
 /// `ProgressBar` at a low rate.
 pub struct FastProgressBar {
     counter: Arc<RelaxedCounter>,
+    /// Progress of each device, indexed by device, empty if not tracked
+    device_counters: Arc<Vec<RelaxedCounter>>,
     progress_bar: Arc<ProgressBar>,
 }
 
+/// Formats the progress of each device, e.g. `sda 45%, nvme0n1 done`.
+/// The devices are given by name and the number of bytes to process.
+/// Devices with nothing to process are omitted.
+fn device_status(devices: &[(String, u64)], done: impl Fn(usize) -> u64) -> String {
+    devices
+        .iter()
+        .enumerate()
+        .filter(|(_, (_, total))| *total > 0)
+        .map(|(i, (name, total))| match done(i) {
+            done if done >= *total => format!("{} done", name),
+            done => format!("{} {}%", name, done * 100 / total),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
 impl FastProgressBar {
     /// Width of the progress bar in characters
     const WIDTH: usize = 50;
@@ -32,18 +51,40 @@ impl FastProgressBar {
     /// The thread periodically copies the `FastProgressBar` position into the wrapped
     /// `ProgressBar` instance.
     pub fn wrap(progress_bar: ProgressBar) -> FastProgressBar {
+        Self::wrap_with_devices(progress_bar, Vec::new())
+    }
+
+    /// Like `wrap`, but also tracks the progress of each of the given devices
+    /// and displays it as the message of the progress bar.
+    /// The devices are given by name and the number of bytes to process.
+    fn wrap_with_devices(
+        progress_bar: ProgressBar,
+        devices: Vec<(String, u64)>,
+    ) -> FastProgressBar {
         let pb = Arc::new(progress_bar);
         let pb2 = pb.clone();
         let counter = Arc::new(RelaxedCounter::new(0));
         let counter2 = counter.clone();
+        let device_counters: Arc<Vec<_>> =
+            Arc::new(devices.iter().map(|_| RelaxedCounter::new(0)).collect());
+        let device_counters2 = device_counters.clone();
         thread::spawn(move || {
+            let mut last_status = String::new();
             while Arc::strong_count(&counter2) > 1 && !pb2.is_finished() {
                 pb2.set_position(counter2.get() as u64);
+                if !devices.is_empty() {
+                    let status = device_status(&devices, |i| device_counters2[i].get() as u64);
+                    if status != last_status {
+                        pb2.set_message(&status);
+                        last_status = status;
+                    }
+                }
                 thread::sleep(Duration::from_millis(Self::REFRESH_PERIOD_MS));
             }
         });
         FastProgressBar {
             counter,
+            device_counters,
             progress_bar: pb,
         }
     }
@@ -102,7 +143,7 @@ impl FastProgressBar {
     pub fn new_bytes_progress_bar(msg: &str, len: u64) -> FastProgressBar {
         let inner = ProgressBar::new(len);
         let template = style("{msg:28}").cyan().bold().for_stderr().to_string()
-            + &"[{bar:WIDTH}] {bytes:>10}/{total_bytes}"
+            + &"[{bar:WIDTH}] {bytes:>10}/{total_bytes} ETA {eta}"
                 .replace("WIDTH", Self::WIDTH.to_string().as_str());
 
         inner.set_style(
@@ -115,6 +156,30 @@ impl FastProgressBar {
         FastProgressBar::wrap(inner)
     }
 
+    /// Create a new preconfigured progress bar displaying progress in bytes,
+    /// followed by the progress of each of the given devices.
+    /// The devices are given by name and the number of bytes to read from them,
+    /// and are indexed the same way as in `inc_device`.
+    pub fn new_device_bytes_progress_bar(
+        msg: &str,
+        len: u64,
+        devices: Vec<(String, u64)>,
+    ) -> FastProgressBar {
+        let inner = ProgressBar::new(len);
+        let template = style("{prefix:28}").cyan().bold().for_stderr().to_string()
+            + &"[{bar:WIDTH}] {bytes:>10}/{total_bytes} ETA {eta} {wide_msg}"
+                .replace("WIDTH", Self::WIDTH.to_string().as_str());
+
+        inner.set_style(
+            ProgressStyle::default_bar()
+                .template(template.as_str())
+                .progress_chars(Self::PROGRESS_CHARS),
+        );
+        inner.set_prefix(msg);
+
+        FastProgressBar::wrap_with_devices(inner, devices)
+    }
+
     /// Creates a new invisible progress bar.
     /// This is useful when you need to disable progress bar, but you need to pass an instance
     /// of a `ProgressBar` to something that expects it.
@@ -149,6 +214,15 @@ impl FastProgressBar {
         self.counter.add(delta);
     }
 
+    /// Increases the position and the progress of the given device.
+    /// Devices not tracked by this progress bar are ignored.
+    pub fn inc_device(&self, device: usize, delta: usize) {
+        self.counter.add(delta);
+        if let Some(counter) = self.device_counters.get(device) {
+            counter.add(delta);
+        }
+    }
+
     pub fn position(&self) -> usize {
         self.counter.get()
     }
@@ -190,12 +264,87 @@ impl Drop for FastProgressBar {
     }
 }
 
+/// Progress of the hashing stage of grouping currently in progress, measured in bytes.
+///
+/// Unlike the progress bars, it can be read by other threads, also when the progress bars
+/// are hidden, e.g. to report the progress of scans run in the background.
+#[derive(Debug, Default)]
+pub struct StageProgress {
+    stage: Mutex<Option<(&'static str, Instant)>>,
+    bytes_done: AtomicU64,
+    bytes_total: AtomicU64,
+}
+
+/// The state of a [`StageProgress`] at a point in time
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProgressSnapshot {
+    /// Name of the stage, e.g. "prefix", or `None` if no hashing stage has started yet
+    pub stage: Option<&'static str>,
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+    /// Estimated time left until the end of the stage, if enough data has been processed
+    pub eta: Option<Duration>,
+}
+
+impl StageProgress {
+    /// Starts tracking a new stage which is going to process `bytes_total` bytes
+    pub(crate) fn start(&self, stage: &'static str, bytes_total: u64) {
+        *self.stage.lock().unwrap() = Some((stage, Instant::now()));
+        self.bytes_done.store(0, Ordering::Relaxed);
+        self.bytes_total.store(bytes_total, Ordering::Relaxed);
+    }
+
+    /// Records that `bytes` bytes were processed in the current stage
+    pub(crate) fn inc(&self, bytes: u64) {
+        self.bytes_done.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> ProgressSnapshot {
+        let stage = *self.stage.lock().unwrap();
+        let bytes_done = self.bytes_done.load(Ordering::Relaxed);
+        let bytes_total = self.bytes_total.load(Ordering::Relaxed);
+        ProgressSnapshot {
+            stage: stage.map(|(name, _)| name),
+            bytes_done,
+            bytes_total,
+            eta: stage.and_then(|(_, start)| eta(bytes_done, bytes_total, start.elapsed())),
+        }
+    }
+}
+
+/// Estimates the time left to process `total` bytes, assuming the rate stays the same as
+/// the average rate of processing `done` bytes in `elapsed` time.
+/// Returns `None` if nothing has been processed yet.
+pub(crate) fn eta(done: u64, total: u64, elapsed: Duration) -> Option<Duration> {
+    if done == 0 {
+        return None;
+    }
+    let left = total.saturating_sub(done) as f64;
+    Some(Duration::from_secs_f64(
+        elapsed.as_secs_f64() * left / done as f64,
+    ))
+}
+
 #[cfg(test)]
 mod test {
 
     use super::*;
     use rayon::prelude::*;
 
+    #[test]
+    fn device_status_shows_progress_of_busy_devices() {
+        let devices = vec![
+            ("nvme0n1".to_owned(), 100),
+            ("sda".to_owned(), 0),
+            ("sdb".to_owned(), 200),
+        ];
+        let done = [100, 0, 90];
+        assert_eq!(
+            device_status(&devices, |i| done[i]),
+            "nvme0n1 done, sdb 45%"
+        );
+    }
+
     #[test]
     fn all_ticks_should_be_counted() {
         let collection = vec![0; 100000];
@@ -210,4 +359,25 @@ mod test {
         assert_eq!(pb.position(), 100000);
         assert_eq!(pb.last_displayed_position(), 100000);
     }
+
+    #[test]
+    fn eta_is_proportional_to_bytes_left() {
+        let elapsed = Duration::from_secs(10);
+        assert_eq!(eta(0, 1000, elapsed), None);
+        assert_eq!(eta(250, 1000, elapsed), Some(Duration::from_secs(30)));
+        assert_eq!(eta(1000, 1000, elapsed), Some(Duration::from_secs(0)));
+    }
+
+    #[test]
+    fn stage_progress_snapshot() {
+        let progress = StageProgress::default();
+        assert_eq!(progress.snapshot().stage, None);
+        progress.start("prefix", 1000);
+        progress.inc(100);
+        let snapshot = progress.snapshot();
+        assert_eq!(snapshot.stage, Some("prefix"));
+        assert_eq!(snapshot.bytes_done, 100);
+        assert_eq!(snapshot.bytes_total, 1000);
+        assert!(snapshot.eta.is_some());
+    }
 }
@@ -0,0 +1,277 @@
+//! Filtering groups of an existing report without rescanning the file system.
+
+use std::cmp::Reverse;
+use std::env::current_dir;
+use std::io;
+use std::sync::Arc;
+
+use crate::annotation;
+use crate::config::RefilterConfig;
+use crate::files::FileLen;
+use crate::log::Log;
+use crate::owner;
+use crate::path::Path;
+use crate::report::{
+    copies_histogram, ext_stats, owner_stats, root_stats, size_histogram, FileStats, ReportHeader,
+};
+use crate::{write_groups, FileGroup, ReportOutput, StageMetrics};
+
+/// Returns true if the file passes the name and path filters given in the config
+fn matches_patterns(path: &Path, config: &RefilterConfig) -> bool {
+    let name_matches = || match path.file_name_cstr() {
+        Some(name) => config
+            .name_patterns
+            .iter()
+            .any(|p| p.matches(name.to_string_lossy().as_ref())),
+        None => false,
+    };
+    let path_matches = || {
+        config
+            .path_patterns
+            .iter()
+            .any(|p| p.matches_path(&path.to_path_buf()))
+    };
+    let excluded = || {
+        config
+            .exclude_patterns
+            .iter()
+            .any(|p| p.matches_path(&path.to_path_buf()))
+    };
+
+    (config.name_patterns.is_empty() && config.path_patterns.is_empty()
+        || name_matches()
+        || path_matches())
+        && !excluded()
+}
+
+/// Applies the filters given in the config to the groups of an existing report.
+///
+/// Groups not matching `--with-hash`, `--containing` or `--only-annotated`
+/// are dropped as a whole.
+/// Files not matching the other filters are removed from their groups.
+/// Groups left with `rf_over` files or fewer are dropped.
+/// If `--top` is given, only that many groups with the most redundant data are kept,
+/// in the original order.
+pub fn refilter<I>(groups: I, config: &RefilterConfig, rf_over: usize) -> Vec<FileGroup<Path>>
+where
+    I: IntoIterator<Item = FileGroup<Path>>,
+{
+    let min_size = config.min_size.unwrap_or(FileLen(0));
+    let max_size = config.max_size.unwrap_or(FileLen::MAX);
+    let base_dir = Arc::new(Path::from(current_dir().unwrap_or_default()));
+    let containing: Vec<Path> = config
+        .containing
+        .iter()
+        .map(|p| base_dir.resolve(Path::from(p)).canonicalize())
+        .collect();
+    let mut groups: Vec<_> = groups
+        .into_iter()
+        .filter(|g| g.file_len >= min_size && g.file_len <= max_size)
+        .filter(|g| config.with_hash.is_empty() || g.has_hash(&config.with_hash))
+        .filter(|g| containing.is_empty() || g.files.iter().any(|f| containing.contains(f)))
+        .filter(|g| annotation::matches_all(&config.only_annotated, &g.annotations))
+        .map(|mut g| {
+            g.files.retain(|f| matches_patterns(f, config));
+            g
+        })
+        .filter(|g| !g.files.is_empty() && g.files.len() > rf_over)
+        .collect();
+
+    if let Some(top) = config.top {
+        let redundant_size = |g: &FileGroup<Path>| g.file_len * (g.files.len() - rf_over) as u64;
+        let mut order: Vec<usize> = (0..groups.len()).collect();
+        order.sort_by_key(|&i| Reverse(redundant_size(&groups[i])));
+        let mut selected = vec![false; groups.len()];
+        for &i in order.iter().take(top) {
+            selected[i] = true;
+        }
+        let mut selected = selected.into_iter();
+        groups.retain(|_| selected.next().unwrap());
+    }
+    groups
+}
+
+/// Writes the refiltered groups in the format given in the config.
+/// The header of the original report is preserved, except the statistics
+/// which are recomputed for the remaining groups, including the statistics of the roots.
+pub fn write_refiltered_report(
+    config: &RefilterConfig,
+    log: &Log,
+    header: ReportHeader,
+    rf_over: usize,
+    groups: &[FileGroup<Path>],
+) -> io::Result<()> {
+    let roots: Vec<String> = header.root_stats.iter().map(|r| r.root.clone()).collect();
+    let header = ReportHeader {
+        stats: Some(FileStats {
+            group_count: groups.len(),
+            redundant_file_count: groups.selected_count(rf_over, usize::MAX),
+            redundant_file_size: groups.selected_size(rf_over, usize::MAX),
+        }),
+        root_stats: root_stats(groups, &roots),
+        ext_stats: if header.ext_stats.is_empty() {
+            vec![]
+        } else {
+            ext_stats(groups)
+        },
+        owner_stats: if header.owner_stats.is_empty() {
+            vec![]
+        } else {
+            owner_stats(groups, |f| owner::file_owner(f).ok())
+        },
+        size_histogram: if header.size_histogram.is_empty() {
+            vec![]
+        } else {
+            size_histogram(groups, rf_over)
+        },
+        copies_histogram: if header.copies_histogram.is_empty() {
+            vec![]
+        } else {
+            copies_histogram(groups, rf_over)
+        },
+        link_stats: None,
+        ..header
+    };
+    let output = ReportOutput {
+        file: config.output.as_ref(),
+        format: config.format,
+        path_format: config.path_format,
+        compact_paths: config.compact_paths,
+        csv_delimiter: config.csv_delimiter,
+        csv_bom: config.csv_bom,
+        csv_group_id: config.csv_group_id,
+        roots: vec![],
+        link_clusters: vec![],
+        allocated_sizes: vec![],
+        devices: None,
+        stats_trailer: None,
+    };
+    write_groups(output, &header, groups, log)
+}
+
+#[cfg(test)]
+mod test {
+
+    use crate::files::FileHash;
+    use crate::pattern::Pattern;
+
+    use super::*;
+
+    fn group(len: u64, paths: &[&str]) -> FileGroup<Path> {
+        FileGroup::new(
+            FileLen(len),
+            FileHash::from(len as u128),
+            paths.iter().map(|&p| Path::from(p)).collect(),
+        )
+    }
+
+    fn paths(groups: &[FileGroup<Path>]) -> Vec<Vec<String>> {
+        groups
+            .iter()
+            .map(|g| g.files.iter().map(|f| f.to_string()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn drops_files_not_matching_patterns() {
+        let groups = vec![
+            group(300, &["/a/1.mp4", "/b/1.mp4", "/b/1.txt"]),
+            group(200, &["/a/2.mp4", "/b/2.txt"]),
+        ];
+        let config = RefilterConfig {
+            path_patterns: vec![Pattern::glob("**/*.mp4").unwrap()],
+            ..Default::default()
+        };
+        let result = refilter(groups, &config, 1);
+        assert_eq!(paths(&result), vec![vec!["/a/1.mp4", "/b/1.mp4"]]);
+    }
+
+    #[test]
+    fn filters_by_size_and_exclude_patterns() {
+        let groups = vec![
+            group(300, &["/a/1", "/b/1", "/c/1"]),
+            group(200, &["/a/2", "/b/2"]),
+            group(100, &["/a/3", "/b/3"]),
+        ];
+        let config = RefilterConfig {
+            min_size: Some(FileLen(200)),
+            exclude_patterns: vec![Pattern::glob("/c/**").unwrap()],
+            ..Default::default()
+        };
+        let result = refilter(groups, &config, 1);
+        assert_eq!(
+            paths(&result),
+            vec![vec!["/a/1", "/b/1"], vec!["/a/2", "/b/2"]]
+        );
+    }
+
+    #[test]
+    fn keeps_groups_with_hash_or_containing_file() {
+        let groups = vec![
+            group(300, &["/a/1", "/b/1"]),
+            group(200, &["/a/2", "/b/2"]),
+            group(100, &["/a/3", "/b/3"]),
+        ];
+        let config = RefilterConfig {
+            with_hash: vec![FileHash::from(200)],
+            ..Default::default()
+        };
+        let result = refilter(groups.clone(), &config, 1);
+        assert_eq!(paths(&result), vec![vec!["/a/2", "/b/2"]]);
+
+        let config = RefilterConfig {
+            containing: vec!["/b/3".into()],
+            ..Default::default()
+        };
+        let result = refilter(groups, &config, 1);
+        assert_eq!(paths(&result), vec![vec!["/a/3", "/b/3"]]);
+    }
+
+    #[test]
+    fn keeps_only_annotated_groups() {
+        let mut groups = vec![
+            group(300, &["/a/1", "/b/1"]),
+            group(200, &["/a/2", "/b/2"]),
+            group(100, &["/a/3", "/b/3"]),
+        ];
+        groups[0]
+            .annotations
+            .insert("reviewed".to_owned(), true.into());
+        groups[1]
+            .annotations
+            .insert("reviewed".to_owned(), false.into());
+        let config = RefilterConfig {
+            only_annotated: vec!["reviewed=true".parse().unwrap()],
+            ..Default::default()
+        };
+        let result = refilter(groups, &config, 1);
+        assert_eq!(paths(&result), vec![vec!["/a/1", "/b/1"]]);
+        assert_eq!(result[0].annotations["reviewed"], true);
+    }
+
+    #[test]
+    fn keeps_top_groups_in_original_order() {
+        let groups = vec![
+            group(300, &["/a/1", "/b/1"]),
+            group(200, &["/a/2", "/b/2", "/c/2", "/d/2"]),
+            group(100, &["/a/3", "/b/3", "/c/3"]),
+            group(
+                50,
+                &["/a/4", "/b/4", "/c/4", "/d/4", "/e/4", "/f/4", "/g/4"],
+            ),
+        ];
+        let config = RefilterConfig {
+            top: Some(2),
+            ..Default::default()
+        };
+        let result = refilter(groups, &config, 1);
+        let lens: Vec<_> = result.iter().map(|g| g.file_len).collect();
+        assert_eq!(lens, vec![FileLen(300), FileLen(200)]);
+
+        let config = RefilterConfig {
+            top: Some(0),
+            ..Default::default()
+        };
+        assert!(refilter(result, &config, 1).is_empty());
+    }
+}
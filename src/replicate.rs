@@ -0,0 +1,319 @@
+//! Copying files with too few copies to another directory.
+//!
+//! Together with `fclones group --rf-under`, which reports the files with fewer copies
+//! than required, it keeps a minimum number of copies of every file.
+//! The copies are found by contents, so files already copied elsewhere,
+//! even under a different name, are not copied again.
+
+use std::env::current_dir;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::sync::Arc;
+
+use crate::config::ReplicateConfig;
+use crate::dedupe::{move_target, FsCommand};
+use crate::files::{file_hash_or_log_err, Caching, FileHash, FileLen, FilePos};
+use crate::log::Log;
+use crate::path::Path;
+use crate::FileGroup;
+
+/// Counts of the files copied by [`replicate`]
+#[derive(Debug, Default)]
+pub struct ReplicateResult {
+    /// Number of files copied, or to be copied in a dry run
+    pub copied_count: usize,
+    /// Total size of the copied files
+    pub copied_size: FileLen,
+    /// Number of groups that couldn't be replicated
+    pub failed_count: usize,
+}
+
+/// Returns the path in the target directory the file should be copied to.
+/// Files under `base` keep their path relative to `base`, other files keep their full path.
+fn replica_path(target: &Arc<Path>, base: Option<&Path>, file: &Path) -> Path {
+    match base
+        .filter(|b| b.is_prefix_of(file))
+        .and_then(|b| file.strip_prefix(b))
+    {
+        Some(relative) => target.join(relative),
+        None => move_target(target, file),
+    }
+}
+
+/// Size of the buffer used for reading the files when checking their contents
+const BUF_LEN: usize = 64 * 1024;
+
+/// Returns the files of the group outside of the target directory that still exist
+/// and have the reported contents.
+///
+/// Groups of a single file are reported with a zero hash, because their contents are never read.
+/// The hash of such files can't be checked, so only their length is compared.
+fn find_sources<'a>(group: &'a FileGroup<Path>, target: &Path, log: &Log) -> Vec<&'a Path> {
+    let hash_known = group.file_hash != FileHash::from(0);
+    group
+        .files
+        .iter()
+        .filter(|f| !target.is_prefix_of(f))
+        .filter(|f| match fs::metadata(f.to_path_buf()) {
+            Ok(m) => m.is_file() && FileLen(m.len()) == group.file_len,
+            Err(_) => false,
+        })
+        .filter(|f| {
+            if !hash_known {
+                return true;
+            }
+            let hash = file_hash_or_log_err(
+                f,
+                FilePos(0),
+                group.file_len,
+                BUF_LEN,
+                Caching::Sequential,
+                |_| {},
+                log,
+            );
+            match hash {
+                Some(hash) if hash == group.file_hash => true,
+                Some(_) => {
+                    log.warn(format!("Not copying {}: Contents changed", f));
+                    false
+                }
+                None => false,
+            }
+        })
+        .collect()
+}
+
+/// Copies files of each group with fewer than `rf_under` files to the target directory
+/// given in the config, until the group has `rf_under` files.
+/// Each file of the group is copied at most once, so a group can't get more new copies than
+/// it has files outside of the target directory. Copies already in the target are counted in.
+///
+/// In a dry run, only writes the copy commands to `out`.
+pub fn replicate<I>(
+    groups: I,
+    rf_under: usize,
+    config: &ReplicateConfig,
+    mut out: impl Write,
+    log: &Log,
+) -> io::Result<ReplicateResult>
+where
+    I: IntoIterator<Item = FileGroup<Path>>,
+{
+    let cwd = Arc::new(Path::from(current_dir().unwrap_or_default()));
+    let target = Arc::new(cwd.resolve(Path::from(&config.target)).canonicalize());
+    let base = config
+        .base
+        .as_ref()
+        .map(|b| cwd.resolve(Path::from(b)).canonicalize());
+
+    // Relative paths in the report are relative to the base directory, see `--relative-to`
+    let report_dir = base.clone().map(Arc::new).unwrap_or_else(|| cwd.clone());
+
+    let mut result = ReplicateResult::default();
+    for mut group in groups {
+        if group.file_count() >= rf_under {
+            continue;
+        }
+        group.files = group.files.iter().map(|f| report_dir.resolve(f)).collect();
+        let sources = find_sources(&group, &target, log);
+        if sources.is_empty() {
+            log.warn(format!(
+                "Cannot replicate group {}: None of the files is accessible or unchanged",
+                group.id()
+            ));
+            result.failed_count += 1;
+            continue;
+        }
+
+        let mut missing = rf_under - group.file_count();
+        let mut failed = false;
+        for source in sources {
+            if missing == 0 {
+                break;
+            }
+            let replica = replica_path(&target, base.as_ref(), source);
+            if group.files.contains(&replica) {
+                continue;
+            }
+            if replica.to_path_buf().exists() {
+                match FsCommand::same_contents(source, &replica) {
+                    Ok(true) => missing -= 1,
+                    Ok(false) => {
+                        log.warn(format!(
+                            "Cannot copy {} to {}: Target already exists",
+                            source, replica
+                        ));
+                        failed = true;
+                    }
+                    Err(e) => {
+                        log.warn(e);
+                        failed = true;
+                    }
+                }
+                continue;
+            }
+            if config.dry_run {
+                writeln!(out, "cp {} {}", source.shell_quote(), replica.shell_quote())?;
+            } else if let Err(e) = FsCommand::copy(source, &replica) {
+                log.warn(e);
+                failed = true;
+                continue;
+            }
+            missing -= 1;
+            result.copied_count += 1;
+            result.copied_size += group.file_len;
+        }
+        if missing > 0 && !failed {
+            log.warn(format!(
+                "Group {} needs {} more copies, but each file can be copied to the target only once",
+                group.id(),
+                missing
+            ));
+        }
+        if failed {
+            result.failed_count += 1;
+        }
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use crate::files::file_hash;
+    use crate::util::test::{read_file, with_dir, write_file};
+
+    use super::*;
+
+    fn group(files: &[&PathBuf]) -> FileGroup<Path> {
+        FileGroup::new(
+            FileLen(3),
+            FileHash::from(0),
+            files.iter().map(|f| Path::from(*f)).collect(),
+        )
+    }
+
+    #[test]
+    fn copies_files_with_too_few_copies() {
+        with_dir("replicate/basic", |root| {
+            let data = root.join("data");
+            let backup = root.join("backup");
+            let single = data.join("dir/single");
+            let double_1 = data.join("double_1");
+            let double_2 = data.join("double_2");
+            fs::create_dir_all(single.parent().unwrap()).unwrap();
+            write_file(&single, "abc");
+            write_file(&double_1, "def");
+            write_file(&double_2, "def");
+
+            let config = ReplicateConfig {
+                target: backup.clone(),
+                base: Some(data.clone()),
+                ..ReplicateConfig::default()
+            };
+            let groups = vec![group(&[&single]), group(&[&double_1, &double_2])];
+            let result = replicate(groups, 2, &config, io::sink(), &Log::new()).unwrap();
+            assert_eq!(result.copied_count, 1);
+            assert_eq!(result.failed_count, 0);
+            assert_eq!(read_file(&backup.join("dir/single")), "abc");
+            assert!(!backup.join("double_1").exists());
+
+            // The copy in the target counts, so the next run has nothing to do
+            let groups = vec![group(&[&single])];
+            let result = replicate(groups, 2, &config, io::sink(), &Log::new()).unwrap();
+            assert_eq!(result.copied_count, 0);
+            assert_eq!(result.failed_count, 0);
+        });
+    }
+
+    #[test]
+    fn copies_as_many_files_as_missing() {
+        with_dir("replicate/many", |root| {
+            let data = root.join("data");
+            let file_1 = data.join("file_1");
+            let file_2 = data.join("file_2");
+            fs::create_dir_all(&data).unwrap();
+            write_file(&file_1, "abc");
+            write_file(&file_2, "abc");
+
+            let config = ReplicateConfig {
+                target: root.join("backup"),
+                base: Some(data.clone()),
+                ..ReplicateConfig::default()
+            };
+            let groups = vec![group(&[&file_1, &file_2])];
+            let result = replicate(groups, 3, &config, io::sink(), &Log::new()).unwrap();
+            assert_eq!(result.copied_count, 1);
+
+            let groups = vec![group(&[&file_1, &file_2])];
+            let result = replicate(groups, 4, &config, io::sink(), &Log::new()).unwrap();
+            assert_eq!(result.copied_count, 1);
+            assert_eq!(result.failed_count, 0);
+            assert!(root.join("backup/file_1").exists());
+            assert!(root.join("backup/file_2").exists());
+
+            // Both files are in the target already, so no more copies can be made
+            let groups = vec![group(&[&file_1, &file_2])];
+            let result = replicate(groups, 5, &config, io::sink(), &Log::new()).unwrap();
+            assert_eq!(result.copied_count, 0);
+            assert_eq!(result.failed_count, 0);
+        });
+    }
+
+    #[test]
+    fn skips_files_with_changed_contents() {
+        with_dir("replicate/changed", |root| {
+            let file_1 = root.join("file_1");
+            let file_2 = root.join("file_2");
+            write_file(&file_1, "abc");
+            write_file(&file_2, "abc");
+            let hash = file_hash(
+                &Path::from(&file_1),
+                FilePos(0),
+                FileLen(3),
+                BUF_LEN,
+                Caching::Default,
+                |_| {},
+            )
+            .unwrap();
+            write_file(&file_2, "xyz");
+
+            let config = ReplicateConfig {
+                target: root.join("backup"),
+                base: Some(root.clone()),
+                ..ReplicateConfig::default()
+            };
+            let mut group = group(&[&file_1, &file_2]);
+            group.file_hash = hash;
+            let result = replicate(vec![group], 4, &config, io::sink(), &Log::new()).unwrap();
+            assert_eq!(result.copied_count, 1);
+            assert!(root.join("backup/file_1").exists());
+            assert!(!root.join("backup/file_2").exists());
+        });
+    }
+
+    #[test]
+    fn dry_run_writes_commands() {
+        with_dir("replicate/dry_run", |root| {
+            let file = root.join("file");
+            write_file(&file, "abc");
+            let config = ReplicateConfig {
+                target: root.join("backup"),
+                base: Some(root.clone()),
+                dry_run: true,
+                ..ReplicateConfig::default()
+            };
+            // Relative paths in the report are resolved against the base directory
+            let relative = PathBuf::from("file");
+            let mut out = Vec::new();
+            let result = replicate(vec![group(&[&relative])], 2, &config, &mut out, &Log::new());
+            assert_eq!(result.unwrap().copied_count, 1);
+            assert!(!root.join("backup").exists());
+            let out = String::from_utf8(out).unwrap();
+            assert!(out.starts_with("cp "));
+            assert!(out.trim_end().ends_with("backup/file"));
+        });
+    }
+}
@@ -1,22 +1,30 @@
 //! Output formatting.
 
-use std::borrow::Borrow;
-use std::cell::Cell;
+use std::borrow::{Borrow, Cow};
+use std::cell::{Cell, RefCell};
 use std::cmp::min;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Display;
 use std::io;
 use std::io::{BufRead, BufReader, Error, ErrorKind, Read, Write};
+use std::path::MAIN_SEPARATOR;
+use std::rc::Rc;
+use std::sync::Arc;
 
-use chrono::{DateTime, FixedOffset};
-use console::style;
+use chrono::{DateTime, FixedOffset, Local};
+use console::Style;
 use fallible_iterator::FallibleIterator;
-use itertools::Itertools;
 use lazy_static::lazy_static;
 use regex::Regex;
-use serde::{Deserialize, Serialize};
-
-use crate::config::OutputFormat;
-use crate::files::{FileHash, FileLen};
+use serde::{Deserialize, Serialize, Serializer};
+use sysinfo::DiskType;
+
+use crate::annotation::Annotations;
+use crate::config::{OutputFormat, PathFormat};
+use crate::device::DiskDevices;
+use crate::files::{group_id, Digests, FileHash, FileLen, HashFn};
+use crate::log::UnreadableFile;
+use crate::owner;
 use crate::path::Path;
 use crate::util::IteratorWrapper;
 use crate::{FileGroup, TIMESTAMP_FMT};
@@ -30,18 +38,739 @@ pub struct FileStats {
     pub redundant_file_size: FileLen,
 }
 
+impl FileStats {
+    /// Adds a group to the statistics, counting all but `rf_over` files as redundant
+    fn add<P>(&mut self, group: &FileGroup<P>, rf_over: usize) {
+        let redundant_count = group.file_count().saturating_sub(rf_over);
+        self.group_count += 1;
+        self.redundant_file_count += redundant_count;
+        self.redundant_file_size += group.file_len * redundant_count as u64;
+    }
+}
+
+/// Describes how many of the reported files were found under a single input path.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RootStats {
+    pub root: String,
+    pub file_count: usize,
+    pub file_size: FileLen,
+}
+
+/// Describes how many of the reported files have the same extension.
+/// The extension is lowercase and without the leading dot; empty for files without one.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ExtStats {
+    pub ext: String,
+    pub file_count: usize,
+    pub file_size: FileLen,
+}
+
+/// Describes how many of the reported files are owned by a single user.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct OwnerStats {
+    pub uid: u32,
+    /// The name of the user, or the user id if the name is unknown
+    pub owner: String,
+    pub file_count: usize,
+    pub file_size: FileLen,
+    /// Space taken by the files of the user that have another copy owned by the same user.
+    /// This much can be reclaimed by the user alone, keeping a single copy of each file.
+    pub redundant_file_size: FileLen,
+}
+
+/// Describes how much space can be reclaimed by removing the redundant files
+/// of the groups with the file size or the number of files in a range.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct HistogramBucket {
+    /// The smallest file size or number of files in the bucket
+    pub min: u64,
+    /// The largest file size or number of files in the bucket, or `None` if unbounded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max: Option<u64>,
+    pub group_count: usize,
+    pub redundant_file_count: usize,
+    pub redundant_file_size: FileLen,
+}
+
+/// Lower bounds of the file size buckets of `--histogram`
+const SIZE_BUCKETS: &[u64] = &[
+    0,
+    1_000,
+    10_000,
+    100_000,
+    1_000_000,
+    10_000_000,
+    100_000_000,
+    1_000_000_000,
+    10_000_000_000,
+];
+
+/// Lower bounds of the file count buckets of `--histogram`
+const COPIES_BUCKETS: &[u64] = &[1, 2, 3, 4, 8, 16, 32, 64];
+
+/// Describes how many of the reported files already share their data on disk with
+/// another file of their group, see `--link-clusters`.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct LinkStats {
+    pub file_count: usize,
+    pub file_size: FileLen,
+}
+
+/// File system type of an input path, see [`HostMetadata`]
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct RootFileSystem {
+    pub root: String,
+    pub file_system: String,
+}
+
+/// Describes the machine the report was produced on, see `--header-metadata`.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct HostMetadata {
+    pub hostname: String,
+    /// Name and version of the operating system
+    pub os: String,
+    /// Processor architecture, e.g. `x86_64`
+    pub arch: String,
+    #[serde(default)]
+    pub root_file_systems: Vec<RootFileSystem>,
+    /// Optional features of `fclones` enabled at build time, e.g. `async`
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+/// The hash function the files of the report were grouped by
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct GroupingHash {
+    pub function: HashFn,
+    /// Width of the hash in bits
+    pub bits: usize,
+}
+
+impl GroupingHash {
+    /// The hash computed by this version of fclones
+    pub const CURRENT: GroupingHash = GroupingHash {
+        function: HashFn::Metro,
+        bits: 128,
+    };
+}
+
+impl Display for GroupingHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}, {} bits", self.function, self.bits)
+    }
+}
+
 /// Data in the header of the whole report.
 #[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct ReportHeader {
     /// The program version that produced the report
     pub version: String,
-    /// The date and time when the report was produced
+    /// The date and time when the scan that produced the report started.
+    /// Files modified later might have changed after they were read.
     pub timestamp: DateTime<FixedOffset>,
     /// Full shell command containing arguments of the search run that produced the report
     pub command: Vec<String>,
+    /// The hash function the files were grouped by.
+    /// Missing in reports written by older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<GroupingHash>,
     /// Information on the number of duplicate files reported.
     /// This is optional to allow streaming the report out before finding all files in the future.
     pub stats: Option<FileStats>,
+    /// Files and directories that could not be accessed during the scan, and why.
+    /// Filled only if requested with `--report-errors`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<String>,
+    /// Number and size of the reported files under each input path.
+    /// Filled only if requested with `--root-stats`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub root_stats: Vec<RootStats>,
+    /// Number and size of the reported files with each extension, largest first.
+    /// Filled only if requested with `--ext-stats`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ext_stats: Vec<ExtStats>,
+    /// Number and size of the reported files owned by each user, largest first.
+    /// Filled only if requested with `--stats-by owner`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub owner_stats: Vec<OwnerStats>,
+    /// Redundant data in the groups of files of each size range.
+    /// Filled only if requested with `--histogram`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub size_histogram: Vec<HistogramBucket>,
+    /// Redundant data in the groups with each range of the number of files.
+    /// Filled only if requested with `--histogram`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub copies_histogram: Vec<HistogramBucket>,
+    /// Number and size of the reported files that are hard links or reflinks
+    /// of another file in the same group.
+    /// Filled only if requested with `--link-clusters`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub link_stats: Option<LinkStats>,
+    /// The machine the report was produced on.
+    /// Filled only if requested with `--header-metadata`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub host: Option<HostMetadata>,
+}
+
+/// Returns the index of the longest root containing the path, or `None` if there is no such root.
+/// Both the path and the roots must be absolute.
+fn root_index(path: &str, roots: &[String]) -> Option<usize> {
+    roots
+        .iter()
+        .enumerate()
+        .filter(|(_, root)| {
+            path.starts_with(root.as_str())
+                && (path.len() == root.len()
+                    || root.ends_with(MAIN_SEPARATOR)
+                    || path[root.len()..].starts_with(MAIN_SEPARATOR))
+        })
+        .max_by_key(|(_, root)| root.len())
+        .map(|(i, _)| i)
+}
+
+/// Orders the files by the root they were found under, keeping the original order
+/// of files within the same root. Files outside of all roots are placed last.
+fn sort_by_root<'a, P: ReportPath>(
+    files: &'a [P],
+    roots: &[String],
+) -> Vec<(Option<usize>, &'a P)> {
+    let mut files: Vec<_> = files
+        .iter()
+        .map(|f| (root_index(&f.to_string(), roots), f))
+        .collect();
+    files.sort_by_key(|(root, _)| root.unwrap_or(usize::MAX));
+    files
+}
+
+/// Computes how many files in the groups were found under each of the roots
+/// and how much space they take.
+pub fn root_stats<'a, I, P>(groups: I, roots: &[String]) -> Vec<RootStats>
+where
+    I: IntoIterator<Item = &'a FileGroup<P>>,
+    P: Display + 'a,
+{
+    let mut stats: Vec<RootStats> = roots
+        .iter()
+        .map(|root| RootStats {
+            root: root.clone(),
+            file_count: 0,
+            file_size: FileLen(0),
+        })
+        .collect();
+    for g in groups {
+        for f in g.files.iter() {
+            if let Some(i) = root_index(&f.to_string(), roots) {
+                stats[i].file_count += 1;
+                stats[i].file_size += g.file_len;
+            }
+        }
+    }
+    stats
+}
+
+/// Computes how many files in the groups have each extension and how much space they take.
+/// The result is sorted by the total size, largest first.
+pub fn ext_stats<'a, I, P>(groups: I) -> Vec<ExtStats>
+where
+    I: IntoIterator<Item = &'a FileGroup<P>>,
+    P: Display + 'a,
+{
+    let mut stats: HashMap<String, ExtStats> = HashMap::new();
+    for g in groups {
+        for f in g.files.iter() {
+            let f = f.to_string();
+            let ext = std::path::Path::new(&f)
+                .extension()
+                .map(|e| e.to_string_lossy().to_lowercase())
+                .unwrap_or_default();
+            let s = stats.entry(ext.clone()).or_insert_with(|| ExtStats {
+                ext,
+                file_count: 0,
+                file_size: FileLen(0),
+            });
+            s.file_count += 1;
+            s.file_size += g.file_len;
+        }
+    }
+    let mut stats: Vec<_> = stats.into_iter().map(|(_, s)| s).collect();
+    stats.sort_by(|a, b| b.file_size.cmp(&a.file_size).then(a.ext.cmp(&b.ext)));
+    stats
+}
+
+/// Computes how many files in the groups are owned by each user and how much space they take.
+/// The owners are given by `owner`; files it returns no owner for are skipped.
+/// The result is sorted by the total size, largest first.
+pub fn owner_stats(
+    groups: &[FileGroup<Path>],
+    owner: impl Fn(&Path) -> Option<u32>,
+) -> Vec<OwnerStats> {
+    let mut stats: HashMap<u32, OwnerStats> = HashMap::new();
+    for g in groups {
+        let mut counts: HashMap<u32, usize> = HashMap::new();
+        for uid in g.files.iter().filter_map(&owner) {
+            *counts.entry(uid).or_default() += 1;
+        }
+        for (uid, count) in counts {
+            let s = stats.entry(uid).or_insert_with(|| OwnerStats {
+                uid,
+                owner: owner::user_name(uid).unwrap_or_else(|| uid.to_string()),
+                file_count: 0,
+                file_size: FileLen(0),
+                redundant_file_size: FileLen(0),
+            });
+            s.file_count += count;
+            s.file_size += g.file_len * count as u64;
+            s.redundant_file_size += g.file_len * (count - 1) as u64;
+        }
+    }
+    let mut stats: Vec<_> = stats.into_iter().map(|(_, s)| s).collect();
+    stats.sort_by(|a, b| b.file_size.cmp(&a.file_size).then(a.uid.cmp(&b.uid)));
+    stats
+}
+
+/// Sorts the groups into buckets by the value returned by `key`.
+/// The buckets are given by their lower bounds, in ascending order.
+/// Buckets with no groups are omitted.
+fn histogram<'a, I, P, K>(groups: I, rf_over: usize, bounds: &[u64], key: K) -> Vec<HistogramBucket>
+where
+    I: IntoIterator<Item = &'a FileGroup<P>>,
+    P: 'a,
+    K: Fn(&FileGroup<P>) -> u64,
+{
+    let mut buckets: Vec<HistogramBucket> = bounds
+        .iter()
+        .enumerate()
+        .map(|(i, &min)| HistogramBucket {
+            min,
+            max: bounds.get(i + 1).map(|next| next - 1),
+            group_count: 0,
+            redundant_file_count: 0,
+            redundant_file_size: FileLen(0),
+        })
+        .collect();
+    for g in groups {
+        let value = key(g);
+        let i = bounds.iter().rposition(|&min| min <= value).unwrap_or(0);
+        let redundant_count = g.file_count().saturating_sub(rf_over);
+        let b = &mut buckets[i];
+        b.group_count += 1;
+        b.redundant_file_count += redundant_count;
+        b.redundant_file_size += g.file_len * redundant_count as u64;
+    }
+    buckets.retain(|b| b.group_count > 0);
+    buckets
+}
+
+/// Computes how much space can be reclaimed in the groups of files of each size range
+pub fn size_histogram<'a, I, P>(groups: I, rf_over: usize) -> Vec<HistogramBucket>
+where
+    I: IntoIterator<Item = &'a FileGroup<P>>,
+    P: 'a,
+{
+    histogram(groups, rf_over, SIZE_BUCKETS, |g| g.file_len.0)
+}
+
+/// Computes how much space can be reclaimed in the groups with each range of the number of files
+pub fn copies_histogram<'a, I, P>(groups: I, rf_over: usize) -> Vec<HistogramBucket>
+where
+    I: IntoIterator<Item = &'a FileGroup<P>>,
+    P: 'a,
+{
+    histogram(groups, rf_over, COPIES_BUCKETS, |g| g.file_count() as u64)
+}
+
+/// Percent-encodes the bytes of the path and prepends the `file://` scheme.
+/// Unreserved characters and directory separators are left as they are.
+pub(crate) fn to_file_uri(path: &[u8]) -> String {
+    let path = if cfg!(windows) {
+        let mut p = vec![b'/'];
+        p.extend(path.iter().map(|&b| if b == b'\\' { b'/' } else { b }));
+        p
+    } else {
+        path.to_owned()
+    };
+    let mut uri = String::with_capacity(path.len() + 7);
+    uri.push_str("file://");
+    for b in path {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                uri.push(b as char)
+            }
+            _ => uri.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    uri
+}
+
+/// Decodes the bytes of the path from a `file://` URI produced by `to_file_uri`.
+/// Returns `None` if the string is not a valid file URI.
+fn from_file_uri(uri: &str) -> Option<Vec<u8>> {
+    let encoded = uri.strip_prefix("file://")?.as_bytes();
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut i = 0;
+    while i < encoded.len() {
+        if encoded[i] == b'%' {
+            let hex = std::str::from_utf8(encoded.get(i + 1..i + 3)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            bytes.push(encoded[i]);
+            i += 1;
+        }
+    }
+    if cfg!(windows) {
+        let start = bytes.iter().take_while(|&&b| b == b'/').count();
+        Some(
+            bytes[start..]
+                .iter()
+                .map(|&b| if b == b'/' { b'\\' } else { b })
+                .collect(),
+        )
+    } else {
+        Some(bytes)
+    }
+}
+
+/// A path that can be written to a report and read back without losing information
+pub trait ReportPath: Display {
+    /// Returns the bytes of the path if it is not valid Unicode, or `None` if it is.
+    /// Such paths can't be written as plain text and are written as `file://` URIs instead.
+    fn non_unicode_bytes(&self) -> Option<Vec<u8>>;
+
+    /// Returns the path as given, before formatting it for the report
+    fn to_path(&self) -> Path;
+}
+
+impl ReportPath for Path {
+    fn non_unicode_bytes(&self) -> Option<Vec<u8>> {
+        match self.to_str() {
+            Some(_) => None,
+            None => Some(self.to_bytes()),
+        }
+    }
+
+    fn to_path(&self) -> Path {
+        self.clone()
+    }
+}
+
+impl ReportPath for String {
+    fn non_unicode_bytes(&self) -> Option<Vec<u8>> {
+        None
+    }
+
+    fn to_path(&self) -> Path {
+        Path::from(self.as_str())
+    }
+}
+
+/// Formats the path as configured by `--path-format`
+fn format_path(path: &str, format: PathFormat) -> Cow<'_, str> {
+    match format {
+        PathFormat::Plain => Cow::Borrowed(path),
+        PathFormat::Uri => Cow::Owned(to_file_uri(path.as_bytes())),
+        PathFormat::ShellQuoted => shell_words::quote(path),
+    }
+}
+
+/// Returns true if `parse_path` would not read back the path from its formatted form,
+/// e.g. because it spans multiple lines, has whitespace trimmed by the report readers,
+/// or looks like a URI or a quoted string.
+fn is_ambiguous(formatted: &str, format: PathFormat) -> bool {
+    formatted.contains(|c| c == '\n' || c == '\r')
+        || formatted.trim() != formatted
+        || formatted.starts_with("file://")
+        || format == PathFormat::Plain && is_quoted(formatted)
+}
+
+/// Returns true if the string looks like a path quoted by `--path-format shell-quoted`
+fn is_quoted(s: &str) -> bool {
+    s.len() > 1 && s.starts_with('\'') && s.ends_with('\'')
+}
+
+/// Formats the path to be written to a report.
+/// Paths that are not valid Unicode or can't be read back unambiguously in the requested
+/// format are always written as `file://` URIs.
+fn report_path<P: ReportPath>(path: &P, format: PathFormat) -> String {
+    match path.non_unicode_bytes() {
+        Some(bytes) => to_file_uri(&bytes),
+        None => {
+            let path = path.to_string();
+            let formatted = format_path(&path, format);
+            if is_ambiguous(&formatted, format) {
+                to_file_uri(path.as_bytes())
+            } else {
+                formatted.into_owned()
+            }
+        }
+    }
+}
+
+/// Tag on the first line of reports in the `text-v2` format
+const TEXT_V2_TAG: &str = "# fclones text-v2";
+
+/// Escapes a path for the `text-v2` format, so it always fits in a single line
+/// and can't be confused with the delimiters.
+/// Backslashes, tabs and line breaks are escaped with a backslash, other control characters
+/// and bytes that are not valid UTF-8 are written as `\xHH`.
+pub(crate) fn escape_path<P: ReportPath>(path: &P) -> String {
+    let bytes = path
+        .non_unicode_bytes()
+        .unwrap_or_else(|| path.to_string().into_bytes());
+    let mut result = String::with_capacity(bytes.len());
+    let mut rest = bytes.as_slice();
+    while !rest.is_empty() {
+        let (valid, invalid) = match std::str::from_utf8(rest) {
+            Ok(valid) => {
+                rest = &[];
+                (valid, &[][..])
+            }
+            Err(e) => {
+                let (valid, invalid) = rest.split_at(e.valid_up_to());
+                let len = e.error_len().unwrap_or(invalid.len());
+                rest = &invalid[len..];
+                (std::str::from_utf8(valid).unwrap(), &invalid[..len])
+            }
+        };
+        for c in valid.chars() {
+            match c {
+                '\\' => result.push_str("\\\\"),
+                '\t' => result.push_str("\\t"),
+                '\n' => result.push_str("\\n"),
+                '\r' => result.push_str("\\r"),
+                c if c.is_control() => {
+                    let mut buf = [0; 4];
+                    for b in c.encode_utf8(&mut buf).bytes() {
+                        result.push_str(&format!("\\x{:02x}", b));
+                    }
+                }
+                c => result.push(c),
+            }
+        }
+        for b in invalid {
+            result.push_str(&format!("\\x{:02x}", b));
+        }
+    }
+    result
+}
+
+/// Reverses `escape_path`
+pub(crate) fn unescape_path(s: &str) -> io::Result<Path> {
+    let invalid = || Error::new(ErrorKind::InvalidData, format!("Malformed path: {}", s));
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buf = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => bytes.push(b'\\'),
+            Some('t') => bytes.push(b'\t'),
+            Some('n') => bytes.push(b'\n'),
+            Some('r') => bytes.push(b'\r'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                if hex.len() != 2 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(invalid());
+                }
+                bytes.push(u8::from_str_radix(&hex, 16).unwrap());
+            }
+            _ => return Err(invalid()),
+        }
+    }
+    Path::from_bytes(bytes).ok_or_else(invalid)
+}
+
+/// Parses the path written in any of the formats supported by `--path-format`
+pub(crate) fn parse_path(s: &str) -> io::Result<Path> {
+    let invalid = || Error::new(ErrorKind::InvalidData, format!("Malformed path: {}", s));
+    if s.starts_with("file://") {
+        from_file_uri(s)
+            .and_then(Path::from_bytes)
+            .ok_or_else(invalid)
+    } else if is_quoted(s) {
+        match shell_words::split(s).map_err(|_| invalid())?.as_slice() {
+            [p] => Ok(Path::from(p.as_str())),
+            _ => Err(invalid()),
+        }
+    } else {
+        Ok(Path::from(s))
+    }
+}
+
+/// Styles of the parts of the text report.
+#[derive(Clone, Debug)]
+pub struct ReportTheme {
+    /// Style of the report header lines and other comments
+    pub header: Style,
+    /// Style of the group headers
+    pub group: Style,
+    /// Style of the file paths
+    pub path: Style,
+}
+
+impl ReportTheme {
+    /// Name of the environment variable the theme is read from
+    pub const ENV_VAR: &'static str = "FCLONES_COLORS";
+
+    /// Parses a theme specification in the form of colon-separated `<part>=<style>` entries,
+    /// where part is one of `header`, `group` or `path`, and style is a dot-separated list
+    /// of colors and attributes, e.g. `header=cyan:group=yellow.bold:path=green`.
+    /// Parts not given in the specification keep their default styles.
+    /// Unknown parts, colors and attributes are ignored.
+    pub fn parse(spec: &str) -> ReportTheme {
+        let mut theme = ReportTheme::default();
+        for entry in spec.split(':') {
+            if let Some((part, s)) = entry.split_once('=') {
+                let s = Style::from_dotted_str(s.trim());
+                match part.trim() {
+                    "header" => theme.header = s,
+                    "group" => theme.group = s,
+                    "path" => theme.path = s,
+                    _ => {}
+                }
+            }
+        }
+        theme
+    }
+
+    /// Reads the theme from the `FCLONES_COLORS` environment variable.
+    /// Returns the default theme if the variable is not set.
+    pub fn from_env() -> ReportTheme {
+        match std::env::var(Self::ENV_VAR) {
+            Ok(spec) => ReportTheme::parse(&spec),
+            Err(_) => ReportTheme::default(),
+        }
+    }
+}
+
+impl Default for ReportTheme {
+    fn default() -> Self {
+        ReportTheme {
+            header: Style::new().cyan(),
+            group: Style::new().yellow(),
+            path: Style::new(),
+        }
+    }
+}
+
+/// A group of files with each file annotated with the root it was found under.
+/// Used for writing JSON reports with `--group-by-root`.
+#[derive(Serialize)]
+struct RootedFileGroup {
+    id: String,
+    file_len: FileLen,
+    file_hash: FileHash,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    hashes: Digests,
+    files: Vec<String>,
+    roots: Vec<Option<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    devices: Option<Vec<FileDevice>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allocated_size: Option<FileLen>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    truncated_from: Option<usize>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    annotations: Annotations,
+}
+
+/// A group of files with each file annotated with the link cluster it belongs to
+/// or the device holding it.
+/// Used for writing JSON reports with `--link-clusters`, `--include-metadata device`
+/// or `--use-allocated-size`.
+#[derive(Serialize)]
+struct LinkedFileGroup {
+    id: String,
+    file_len: FileLen,
+    file_hash: FileHash,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    hashes: Digests,
+    files: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link_clusters: Option<Vec<Option<u32>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    devices: Option<Vec<FileDevice>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allocated_size: Option<FileLen>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    truncated_from: Option<usize>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    annotations: Annotations,
+}
+
+/// Table of directories shared by the files of a compact report.
+/// Each directory is stored together with its trailing separator,
+/// so a path is restored by concatenating the directory and the file name.
+#[derive(Default)]
+struct PathTable {
+    dirs: Vec<String>,
+    index: HashMap<String, usize>,
+}
+
+impl PathTable {
+    /// Splits the path into the directory and the file name and registers the directory.
+    /// Returns the index of the directory and the file name.
+    fn add(&mut self, path: &str) -> (usize, String) {
+        let split = path
+            .rfind(|c| c == '/' || c == MAIN_SEPARATOR)
+            .map(|i| i + 1)
+            .unwrap_or(0);
+        let (dir, name) = path.split_at(split);
+        let index = match self.index.get(dir) {
+            Some(&index) => index,
+            None => {
+                let index = self.dirs.len();
+                self.dirs.push(dir.to_owned());
+                self.index.insert(dir.to_owned(), index);
+                index
+            }
+        };
+        (index, name.to_owned())
+    }
+}
+
+impl Serialize for PathTable {
+    fn serialize<S: Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+        self.dirs.serialize(s)
+    }
+}
+
+/// A group of files written with `--compact-paths`.
+/// Each file is a pair of the index of its directory in the path table and its name.
+#[derive(Serialize)]
+struct CompactFileGroup {
+    id: String,
+    file_len: FileLen,
+    file_hash: FileHash,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    hashes: Digests,
+    files: Vec<(usize, String)>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    roots: Option<Vec<Option<String>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    link_clusters: Option<Vec<Option<u32>>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    devices: Option<Vec<FileDevice>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    allocated_size: Option<FileLen>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    truncated_from: Option<usize>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    annotations: Annotations,
+}
+
+/// A compact report. The table of directories is written after the groups,
+/// because it gets filled while the groups are serialized.
+#[derive(Serialize)]
+struct CompactReport<'a, G: Serialize> {
+    header: &'a ReportHeader,
+    groups: G,
+    dirs: &'a RefCell<PathTable>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stats: Option<&'a RefCell<FileStats>>,
 }
 
 /// A helper struct that allows to serialize the report with serde.
@@ -51,6 +780,9 @@ pub struct ReportHeader {
 struct SerializableReport<'a, G: Serialize> {
     header: &'a ReportHeader,
     groups: G,
+    /// Statistics written after the groups, see [`ReportWriter::with_stats_trailer`]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stats: Option<&'a RefCell<FileStats>>,
 }
 
 /// A structure for holding contents of the report after fully deserializing the report.
@@ -60,7 +792,22 @@ struct SerializableReport<'a, G: Serialize> {
 #[derive(Deserialize)]
 struct DeserializedReport {
     header: ReportHeader,
-    groups: Vec<FileGroup<String>>,
+    groups: Vec<FileGroup<DeserializedPath>>,
+    /// Table of directories, present only in compact reports
+    #[serde(default)]
+    dirs: Vec<String>,
+    /// Statistics of streamed reports, which are not known when the header is written
+    #[serde(default)]
+    stats: Option<FileStats>,
+}
+
+/// A path stored in a JSON report, either as a whole or as
+/// an index into the table of directories and a file name.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum DeserializedPath {
+    Plain(String),
+    Compact(usize, String),
 }
 
 /// Formats and writes duplicate files report to a stream.
@@ -68,27 +815,211 @@ struct DeserializedReport {
 pub struct ReportWriter<W: Write> {
     out: W,
     color: bool,
+    theme: ReportTheme,
+    roots: Vec<String>,
+    path_format: PathFormat,
+    compact_paths: bool,
+    link_clusters: Vec<Vec<Option<u32>>>,
+    allocated_sizes: Vec<FileLen>,
+    devices: Option<DeviceMetadata>,
+    trailer: Option<StatsTrailer>,
+    csv_delimiter: u8,
+    csv_bom: bool,
+    csv_group_id: bool,
+}
+
+/// Device holding a file, written with `--include-metadata device`
+#[derive(Serialize)]
+struct FileDevice {
+    device: String,
+    mount_point: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_system: Option<String>,
+}
+
+/// Finds the devices holding the files of the report, see `--include-metadata device`.
+/// The devices are looked up in the table of mounted file systems, so the files
+/// are not accessed again.
+pub(crate) struct DeviceMetadata {
+    devices: DiskDevices,
+    /// Directory the relative paths of the report are relative to, see `--relative-to`
+    base: Option<Arc<Path>>,
+}
+
+impl DeviceMetadata {
+    pub fn new(base: Option<Path>) -> DeviceMetadata {
+        DeviceMetadata {
+            devices: DiskDevices::default(),
+            base: base.map(Arc::new),
+        }
+    }
+
+    fn get(&self, file: &impl ReportPath) -> FileDevice {
+        let path = file.to_path();
+        let path = match &self.base {
+            Some(base) => base.resolve(path),
+            None => path,
+        };
+        FileDevice {
+            device: self
+                .devices
+                .get_by_path(&path)
+                .name
+                .to_string_lossy()
+                .into_owned(),
+            mount_point: self.devices.get_mount_point(&path).to_string_lossy(),
+            file_system: self
+                .devices
+                .get_file_system(&path)
+                .map(|fs| fs.to_string_lossy().into_owned()),
+        }
+    }
+
+    fn get_all<'a, P: ReportPath + 'a>(
+        &self,
+        files: impl IntoIterator<Item = &'a P>,
+    ) -> Vec<FileDevice> {
+        files.into_iter().map(|f| self.get(f)).collect()
+    }
+}
+
+/// Statistics of the groups written so far, together with the replication factor
+/// used for computing them
+struct StatsTrailer {
+    rf_over: usize,
+    stats: Rc<RefCell<FileStats>>,
 }
 
 impl<W: Write> ReportWriter<W> {
     pub fn new(out: W, color: bool) -> ReportWriter<W> {
-        ReportWriter { out, color }
+        ReportWriter {
+            out,
+            color,
+            theme: ReportTheme::default(),
+            roots: Vec::new(),
+            path_format: PathFormat::Plain,
+            compact_paths: false,
+            link_clusters: Vec::new(),
+            allocated_sizes: Vec::new(),
+            devices: None,
+            trailer: None,
+            csv_delimiter: b',',
+            csv_bom: false,
+            csv_group_id: false,
+        }
+    }
+
+    /// Makes the writer compute the statistics of the written groups and write them
+    /// after the last group. This allows to write the report while the groups are still
+    /// being found, when the statistics can't be given in the header yet.
+    /// Supported by the text and JSON formats.
+    pub fn with_stats_trailer(mut self, rf_over: usize) -> ReportWriter<W> {
+        self.trailer = Some(StatsTrailer {
+            rf_over,
+            stats: Rc::new(RefCell::new(FileStats {
+                group_count: 0,
+                redundant_file_count: 0,
+                redundant_file_size: FileLen(0),
+            })),
+        });
+        self
+    }
+
+    /// Makes the JSON and CSV reports store each directory only once
+    /// and refer to it by index from the file paths
+    pub fn with_compact_paths(mut self, compact: bool) -> ReportWriter<W> {
+        self.compact_paths = compact;
+        self
+    }
+
+    /// Sets the format of the paths in the text and `fdupes` reports
+    pub fn with_path_format(mut self, path_format: PathFormat) -> ReportWriter<W> {
+        self.path_format = path_format;
+        self
+    }
+
+    /// Sets the character separating the fields of the CSV report
+    pub fn with_csv_delimiter(mut self, delimiter: u8) -> ReportWriter<W> {
+        self.csv_delimiter = delimiter;
+        self
+    }
+
+    /// Makes the CSV report start with the UTF-8 byte order mark,
+    /// which tells spreadsheets like Microsoft Excel the encoding of the file
+    pub fn with_csv_bom(mut self, bom: bool) -> ReportWriter<W> {
+        self.csv_bom = bom;
+        self
+    }
+
+    /// Makes the CSV report include the group identifier after the file count
+    pub fn with_csv_group_id(mut self, group_id: bool) -> ReportWriter<W> {
+        self.csv_group_id = group_id;
+        self
+    }
+
+    /// Sets the styles used for coloring the text report
+    pub fn with_theme(mut self, theme: ReportTheme) -> ReportWriter<W> {
+        self.theme = theme;
+        self
+    }
+
+    /// Makes the writer annotate each file with the root it was found under.
+    /// Supported only by the text and JSON formats.
+    pub fn group_by_roots(mut self, roots: Vec<String>) -> ReportWriter<W> {
+        self.roots = roots;
+        self
+    }
+
+    /// Makes the writer mark files sharing their data on disk.
+    /// The n-th item holds the link cluster numbers of the files of the n-th written group,
+    /// `None` for files not sharing data with any other file of the group.
+    /// Supported only by the text and JSON formats.
+    pub fn with_link_clusters(mut self, clusters: Vec<Vec<Option<u32>>>) -> ReportWriter<W> {
+        self.link_clusters = clusters;
+        self
+    }
+
+    /// Makes the writer annotate each group with the disk space allocated to its redundant files.
+    /// The n-th item holds the allocated size of the n-th written group.
+    /// Supported only by the text, text-v2 and JSON formats.
+    pub fn with_allocated_sizes(mut self, sizes: Vec<FileLen>) -> ReportWriter<W> {
+        self.allocated_sizes = sizes;
+        self
+    }
+
+    /// Makes the writer annotate each file with the device, mount point and file system
+    /// holding it. Supported only by the JSON and CSV formats.
+    pub(crate) fn with_device_metadata(
+        mut self,
+        devices: Option<DeviceMetadata>,
+    ) -> ReportWriter<W> {
+        self.devices = devices;
+        self
     }
 
     fn write_header_line(&mut self, line: &str) -> io::Result<()> {
         writeln!(
             self.out,
             "{}",
-            style(format!("# {}", line))
-                .cyan()
+            self.theme
+                .header
+                .apply_to(format!("# {}", line))
                 .force_styling(self.color)
         )
     }
 
+    fn write_stats_lines(&mut self, stats: &FileStats) -> io::Result<()> {
+        self.write_header_line(&format!("Found {} file groups", stats.group_count))?;
+        self.write_header_line(&format!(
+            "{} B ({}) in {} redundant files can be removed",
+            stats.redundant_file_size.0, stats.redundant_file_size, stats.redundant_file_count
+        ))
+    }
+
     /// Writes the report in human-readable text format.
     ///
     /// A group of identical files starts with a group header at column 0,
-    /// containing the size and hash of each file in the group.
+    /// containing the size and hash of each file in the group and the group identifier.
     /// Then file paths are printed in separate, indented lines.
     ///
     /// # Example
@@ -98,13 +1029,13 @@ impl<W: Write> ReportWriter<W> {
     /// # Command: target/debug/fclones find . -o report.txt
     /// # Found 553 file groups
     /// # 271.8 MB in 4266 redundant files can be removed
-    /// 5649a555c131508c4a757d9e14c4aea6, 6626689 B (6.6 MB) * 5:
+    /// 5649a555c131508c4a757d9e14c4aea6, 6626689 B (6.6 MB) * 5: id 57466d90a153de21
     ///     /home/pkolaczk/Projekty/fclones/target/debug/deps/libregex_syntax-94c84f5600b85f6e.rmeta
     ///     /home/pkolaczk/Projekty/fclones/target/package/fclones-0.10.0/target/debug/deps/libregex_syntax-94c84f5600b85f6e.rmeta
     ///     /home/pkolaczk/Projekty/fclones/target/package/fclones-0.10.1/target/debug/deps/libregex_syntax-94c84f5600b85f6e.rmeta
     ///     /home/pkolaczk/Projekty/fclones/target/package/fclones-0.10.2/target/debug/deps/libregex_syntax-94c84f5600b85f6e.rmeta
     ///     /home/pkolaczk/Projekty/fclones/target/package/fclones-0.11.0/target/debug/deps/libregex_syntax-94c84f5600b85f6e.rmeta
-    /// f79ce189d76620fd921986943087dc3a, 5815999 B (5.8 MB) * 5:
+    /// f79ce189d76620fd921986943087dc3a, 5815999 B (5.8 MB) * 5: id d93628ecf7705e86
     ///     /home/pkolaczk/Projekty/fclones/target/debug/deps/libserde-af05e0212e5def7d.rmeta
     ///     /home/pkolaczk/Projekty/fclones/target/package/fclones-0.10.0/target/debug/deps/libserde-af05e0212e5def7d.rmeta
     ///     /home/pkolaczk/Projekty/fclones/target/package/fclones-0.10.1/target/debug/deps/libserde-af05e0212e5def7d.rmeta
@@ -115,8 +1046,168 @@ impl<W: Write> ReportWriter<W> {
     where
         I: IntoIterator<Item = G>,
         G: Borrow<FileGroup<P>>,
-        P: Display,
+        P: ReportPath,
     {
+        self.write_text_header(header)?;
+
+        for (i, g) in groups.into_iter().enumerate() {
+            let g = g.borrow();
+            let hashes: String = g
+                .hashes
+                .iter()
+                .map(|(f, h)| format!(", {}:{}", f, h))
+                .collect();
+            let allocated = match self.allocated_sizes.get(i) {
+                Some(size) => format!(", {} B ({}) allocated to redundant files", size.0, size),
+                None => String::new(),
+            };
+            let truncated = match g.truncated_from {
+                Some(count) => format!(", truncated from {} files", count),
+                None => String::new(),
+            };
+            let group_header = format!(
+                "{}, {} B ({}) * {}{}: id {}{}{}",
+                g.file_hash,
+                g.file_len.0,
+                g.file_len,
+                g.files.len(),
+                hashes,
+                g.id(),
+                allocated,
+                truncated
+            );
+            let group_header = self.theme.group.apply_to(group_header);
+            writeln!(self.out, "{}", group_header.force_styling(self.color),)?;
+            let clusters = self
+                .link_clusters
+                .get_mut(i)
+                .map(std::mem::take)
+                .unwrap_or_default();
+            if clusters.iter().any(Option::is_some) {
+                self.write_files_by_cluster(&g.files, &clusters)?;
+            } else if self.roots.is_empty() {
+                for f in g.files.iter() {
+                    let f = report_path(f, self.path_format);
+                    self.write_path(&f)?;
+                }
+            } else {
+                self.write_files_by_root(&g.files)?;
+            }
+            self.flush_if_streaming()?;
+        }
+        self.write_stats_trailer()
+    }
+
+    /// Writes only the header of the text report, without the file lists.
+    /// The groups are consumed, so the statistics can be written after them
+    /// when the report is streamed.
+    ///
+    /// # Example
+    /// ```text
+    /// # Report by fclones 0.15.0
+    /// # Timestamp: 2021-08-27 12:11:23.456 +0200
+    /// # Command: fclones group . -f summary --ext-stats
+    /// # Found 2 file groups
+    /// # 3.5 GB (3456000000 B) in 5 redundant files can be removed
+    /// # Extension mkv: 4 files, 3000000000 B (3.0 GB)
+    /// # Extension (none): 3 files, 500000000 B (500.0 MB)
+    /// ```
+    pub fn write_as_summary<I, G, P>(&mut self, header: &ReportHeader, groups: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = G>,
+        G: Borrow<FileGroup<P>>,
+        P: ReportPath,
+    {
+        self.write_text_header(header)?;
+        groups.into_iter().for_each(drop);
+        self.write_stats_trailer()
+    }
+
+    /// Writes the report in the `text-v2` format.
+    ///
+    /// The header is the same as in the default text format, preceded by a line with the
+    /// format tag. Each group starts with a line tagged with `[group <id>]` followed by
+    /// space-separated `key=value` fields. Then each file path is written in a separate line
+    /// starting with a tab, with backslashes, tabs, line breaks, control characters and
+    /// bytes that are not valid UTF-8 escaped with a backslash.
+    /// The last field of the group line is the SHA-256 checksum of the preceding fields and
+    /// the file lines of the group, so an edited group is rejected before it is read.
+    /// The report ends with an `[end]` line holding the number of groups and files
+    /// and the SHA-256 checksum of all group and file lines, so a truncated or edited report
+    /// is rejected by the reader. Root and link cluster annotations are not written.
+    ///
+    /// # Example
+    /// Tabs starting the file lines are shown as `→`:
+    /// ```text
+    /// # fclones text-v2
+    /// # Report by fclones 0.12.0
+    /// # Timestamp: Mon, 03 May 2021 13:22:51 +0000
+    /// # Command: fclones group . -f text-v2
+    /// # Found 1 file groups
+    /// # 6626689 B (6.6 MB) in 1 redundant files can be removed
+    /// [group 2f0c4b1e9a7d3c58] hash=5649a555c131508c4a757d9e14c4aea6 size=6626689 files=2 checksum=sha256:8d4c2f3b8a7a1ec5c2e0c6ad1a0ee2bc7a1b4e4dcd1b6a8e9fb5f0c2e6c3b7a1
+    /// →/home/user/notes\ttab.txt
+    /// →/home/user/backup/notes\ttab.txt
+    /// [end] groups=1 files=2 checksum=sha256:c44308cae71626f8534b0f81e7985ff9e4bd76975ab3df4fce84e774d813e99d
+    /// ```
+    pub fn write_as_text_v2<I, G, P>(&mut self, header: &ReportHeader, groups: I) -> io::Result<()>
+    where
+        I: IntoIterator<Item = G>,
+        G: Borrow<FileGroup<P>>,
+        P: ReportPath,
+    {
+        use sha2::Digest;
+        writeln!(self.out, "{}", TEXT_V2_TAG)?;
+        self.write_text_header(header)?;
+
+        let mut checksum = sha2::Sha256::new();
+        let mut group_count = 0;
+        let mut file_count = 0;
+        for (i, g) in groups.into_iter().enumerate() {
+            let g = g.borrow();
+            let mut line = format!(
+                "[group {}] hash={} size={} files={}",
+                g.id(),
+                g.file_hash,
+                g.file_len.0,
+                g.files.len()
+            );
+            for (f, h) in g.hashes.iter() {
+                line.push_str(&format!(" {}={}", f, h));
+            }
+            if let Some(size) = self.allocated_sizes.get(i) {
+                line.push_str(&format!(" allocated={}", size.0));
+            }
+            if let Some(count) = g.truncated_from {
+                line.push_str(&format!(" truncated-from={}", count));
+            }
+            let mut file_lines = String::new();
+            for f in g.files.iter() {
+                file_lines.push('\t');
+                file_lines.push_str(&escape_path(f));
+                file_lines.push('\n');
+            }
+            let group_checksum = group_checksum(&line, &file_lines);
+            line.push_str(&format!(" checksum=sha256:{}\n", group_checksum));
+            line.push_str(&file_lines);
+            checksum.update(line.as_bytes());
+            self.out.write_all(line.as_bytes())?;
+            group_count += 1;
+            file_count += g.files.len();
+            self.flush_if_streaming()?;
+        }
+        self.write_stats_trailer()?;
+        writeln!(
+            self.out,
+            "[end] groups={} files={} checksum=sha256:{}",
+            group_count,
+            file_count,
+            FileHash::from_bytes(&checksum.finalize())
+        )
+    }
+
+    /// Writes the comment lines with the header of the text report
+    fn write_text_header(&mut self, header: &ReportHeader) -> io::Result<()> {
         let command = shell_words::join(header.command.iter());
         self.write_header_line(&format!("Report by fclones {}", header.version))?;
         self.write_header_line(&format!(
@@ -124,32 +1215,160 @@ impl<W: Write> ReportWriter<W> {
             header.timestamp.format(TIMESTAMP_FMT)
         ))?;
         self.write_header_line(&format!("Command: {}", command))?;
+        if let Some(hash) = &header.hash {
+            self.write_header_line(&format!("Hash: {}", hash))?;
+        }
         if let Some(stats) = &header.stats {
-            self.write_header_line(&format!("Found {} file groups", stats.group_count))?;
+            self.write_stats_lines(stats)?;
+        }
+        for r in header.root_stats.iter() {
+            self.write_header_line(&format!(
+                "Root {}: {} files, {} B ({})",
+                r.root, r.file_count, r.file_size.0, r.file_size
+            ))?;
+        }
+        for e in header.ext_stats.iter() {
+            let ext = if e.ext.is_empty() { "(none)" } else { &e.ext };
             self.write_header_line(&format!(
-                "{} B ({}) in {} redundant files can be removed",
-                stats.redundant_file_size.0, stats.redundant_file_size, stats.redundant_file_count
+                "Extension {}: {} files, {} B ({})",
+                ext, e.file_count, e.file_size.0, e.file_size
             ))?;
         }
+        for o in header.owner_stats.iter() {
+            self.write_header_line(&format!(
+                "Owner {} ({}): {} files, {} B ({}), {} B ({}) in own copies",
+                o.owner,
+                o.uid,
+                o.file_count,
+                o.file_size.0,
+                o.file_size,
+                o.redundant_file_size.0,
+                o.redundant_file_size
+            ))?;
+        }
+        self.write_histogram_lines("Size", " B", &header.size_histogram)?;
+        self.write_histogram_lines("Copies", "", &header.copies_histogram)?;
+        if let Some(s) = &header.link_stats {
+            self.write_header_line(&format!(
+                "Already shared by links: {} files, {} B ({})",
+                s.file_count, s.file_size.0, s.file_size
+            ))?;
+        }
+        if let Some(host) = &header.host {
+            self.write_header_line(&format!("Host: {}", host.hostname))?;
+            self.write_header_line(&format!("System: {} ({})", host.os, host.arch))?;
+            for r in host.root_file_systems.iter() {
+                self.write_header_line(&format!("File system of {}: {}", r.root, r.file_system))?;
+            }
+            if !host.features.is_empty() {
+                self.write_header_line(&format!("Features: {}", host.features.join(", ")))?;
+            }
+        }
+        if !header.errors.is_empty() {
+            self.write_header_line(&format!("Scan errors: {}", header.errors.len()))?;
+            for e in header.errors.iter() {
+                // Each message must fit in a single line of the header:
+                self.write_header_line(&format!("  {}", e.replace('\n', " ")))?;
+            }
+        }
+        Ok(())
+    }
 
-        for g in groups {
-            let g = g.borrow();
-            let group_header = format!(
-                "{}, {} B ({}) * {}:",
-                g.file_hash,
-                g.file_len.0,
-                g.file_len,
-                g.files.len()
-            );
-            let group_header = style(group_header).yellow();
-            writeln!(self.out, "{}", group_header.force_styling(self.color),)?;
-            for f in g.files.iter() {
-                writeln!(self.out, "    {}", f)?;
+    /// Writes a line for each bucket of a histogram, e.g.
+    /// `# Size 1000-9999 B: 3 groups, 4 redundant files, 12000 B (12.0 KB)`
+    fn write_histogram_lines(
+        &mut self,
+        label: &str,
+        unit: &str,
+        buckets: &[HistogramBucket],
+    ) -> io::Result<()> {
+        for b in buckets {
+            let range = match b.max {
+                Some(max) if max == b.min => format!("{}", b.min),
+                Some(max) => format!("{}-{}", b.min, max),
+                None => format!("{}+", b.min),
+            };
+            self.write_header_line(&format!(
+                "{} {}{}: {} groups, {} redundant files, {} B ({})",
+                label,
+                range,
+                unit,
+                b.group_count,
+                b.redundant_file_count,
+                b.redundant_file_size.0,
+                b.redundant_file_size
+            ))?;
+        }
+        Ok(())
+    }
+
+    /// Writes the statistics computed while writing the groups, if the report is streamed
+    fn write_stats_trailer(&mut self) -> io::Result<()> {
+        if let Some(trailer) = &self.trailer {
+            let stats = RefCell::borrow(&trailer.stats).clone();
+            self.write_stats_lines(&stats)?;
+        }
+        Ok(())
+    }
+
+    /// Makes the groups visible to the reader as soon as they are written,
+    /// if the report is written while the groups are still being found
+    fn flush_if_streaming(&mut self) -> io::Result<()> {
+        match self.trailer {
+            Some(_) => self.out.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Writes the files of a group, preceding each run of files of the same link cluster
+    /// with a comment line. Files of the same cluster are expected to be adjacent.
+    fn write_files_by_cluster<P: ReportPath>(
+        &mut self,
+        files: &[P],
+        clusters: &[Option<u32>],
+    ) -> io::Result<()> {
+        let cluster_at = |i: usize| clusters.get(i).copied().flatten();
+        for (i, f) in files.iter().enumerate() {
+            let cluster = cluster_at(i);
+            if i == 0 || cluster != cluster_at(i - 1) {
+                let line = match cluster {
+                    Some(c) => format!("  # Link cluster {}:", c),
+                    None => "  # Not linked".to_owned(),
+                };
+                let line = self.theme.header.apply_to(line).force_styling(self.color);
+                writeln!(self.out, "{}", line)?;
+            }
+            self.write_path(&report_path(f, self.path_format))?;
+        }
+        Ok(())
+    }
+
+    /// Writes the files of a group, preceding the files found under each root
+    /// with a comment line naming the root.
+    fn write_files_by_root<P: ReportPath>(&mut self, files: &[P]) -> io::Result<()> {
+        let mut last_root = None;
+        let files = sort_by_root(files, &self.roots);
+        for (i, (root, f)) in files.into_iter().enumerate() {
+            if i == 0 || root != last_root {
+                let line = match root {
+                    Some(i) => format!("  # Root: {}", self.roots[i]),
+                    None => "  # Outside of input paths".to_owned(),
+                };
+                let line = self.theme.header.apply_to(line).force_styling(self.color);
+                writeln!(self.out, "{}", line)?;
+                last_root = root;
             }
+            self.write_path(&report_path(f, self.path_format))?;
         }
         Ok(())
     }
 
+    /// Writes an indented path of a file in a group, already formatted by `report_path`
+    fn write_path(&mut self, path: &str) -> io::Result<()> {
+        let path = self.theme.path.apply_to(path).force_styling(self.color);
+        writeln!(self.out, "    {}", path)
+    }
+
     /// Writes the report in `fdupes` compatible format.
     /// This is very similar to the TEXT format, but there are no headers
     /// for each group, and groups are separated with empty lines.
@@ -157,14 +1376,15 @@ impl<W: Write> ReportWriter<W> {
     where
         I: IntoIterator<Item = G>,
         G: Borrow<FileGroup<P>>,
-        P: Display,
+        P: ReportPath,
     {
         for g in groups {
             let g = g.borrow();
             for f in g.files.iter() {
-                writeln!(self.out, "{}", f)?;
+                writeln!(self.out, "{}", report_path(f, self.path_format))?;
             }
             writeln!(self.out)?;
+            self.flush_if_streaming()?;
         }
         Ok(())
     }
@@ -176,31 +1396,84 @@ impl<W: Write> ReportWriter<W> {
     /// Columns:
     /// - file size in bytes
     /// - file hash (may be empty)
-    /// - number of files in the group
+    /// - number of files in the group, which is greater than the number of the listed files
+    ///   if the group was truncated with `--max-group-files`
+    /// - group identifier, only if requested with `with_csv_group_id`
     /// - file paths - each file in a separate column
+    ///
+    /// With `--include-metadata device`, each path is followed by the device, the mount point
+    /// and the file system type of the file, so each file takes four columns.
+    ///
+    /// With compact paths, each directory is announced once by a `dir,<index>,<path>`
+    /// record placed before the first group referring to it, and the files are written
+    /// as `<index>:<name>`.
+    ///
+    /// There is no reader for CSV reports, so they can't be passed to the deduplication
+    /// commands. Use the JSON or text formats for reports meant to be read back.
+    ///
+    /// The fields are separated by commas, unless another delimiter is set with
+    /// `with_csv_delimiter`. The report is preceded by the UTF-8 byte order mark
+    /// if requested with `with_csv_bom`.
     pub fn write_as_csv<I, G, P>(&mut self, _header: &ReportHeader, groups: I) -> io::Result<()>
     where
         I: IntoIterator<Item = G>,
         G: Borrow<FileGroup<P>>,
-        P: Display,
+        P: ReportPath,
     {
+        if self.csv_bom {
+            self.out.write_all("\u{feff}".as_bytes())?;
+        }
         let mut wtr = csv::WriterBuilder::new()
-            .delimiter(b',')
+            .delimiter(self.csv_delimiter)
             .quote_style(csv::QuoteStyle::Necessary)
             .flexible(true)
             .from_writer(&mut self.out);
 
-        wtr.write_record(&["size", "hash", "count", "files"])?;
+        let streaming = self.trailer.is_some();
+        let mut table = PathTable::default();
+        let devices = self.devices.as_ref();
+        let mut columns = vec!["size", "hash", "count"];
+        if self.csv_group_id {
+            columns.push("id");
+        }
+        if devices.is_some() {
+            columns.extend(["file", "device", "mount_point", "file_system"].iter());
+        } else {
+            columns.push("files");
+        }
+        wtr.write_record(&columns)?;
         for g in groups {
             let g = g.borrow();
             let mut record = csv::StringRecord::new();
             record.push_field(g.file_len.0.to_string().as_str());
             record.push_field(g.file_hash.to_string().as_str());
-            record.push_field(g.files.len().to_string().as_str());
-            for f in g.files.iter() {
-                record.push_field(format!("{}", f).as_ref());
+            record.push_field(g.file_count().to_string().as_str());
+            if self.csv_group_id {
+                record.push_field(g.id().as_str());
+            }
+            for path in g.files.iter() {
+                let f = report_path(path, PathFormat::Plain);
+                if self.compact_paths {
+                    let known_dirs = table.dirs.len();
+                    let (index, name) = table.add(&f);
+                    if index >= known_dirs {
+                        wtr.write_record(["dir", &index.to_string(), &table.dirs[index]])?;
+                    }
+                    record.push_field(&format!("{}:{}", index, name));
+                } else {
+                    record.push_field(&f);
+                }
+                if let Some(devices) = devices {
+                    let d = devices.get(path);
+                    record.push_field(&d.device);
+                    record.push_field(&d.mount_point);
+                    record.push_field(d.file_system.as_deref().unwrap_or_default());
+                }
             }
             wtr.write_record(&record)?;
+            if streaming {
+                wtr.flush()?;
+            }
         }
         wtr.flush()
     }
@@ -229,6 +1502,7 @@ impl<W: Write> ReportWriter<W> {
     ///   },
     ///   "groups": [
     ///     {
+    ///       "id": "57466d90a153de21",
     ///       "file_len": 6626689,
     ///       "file_hash": "5649a555c131508c4a757d9e14c4aea6",
     ///       "files": [
@@ -240,6 +1514,7 @@ impl<W: Write> ReportWriter<W> {
     ///       ]
     ///     },
     ///     {
+    ///       "id": "d93628ecf7705e86",
     ///       "file_len": 5815999,
     ///       "file_hash": "f79ce189d76620fd921986943087dc3a",
     ///       "files": [
@@ -262,6 +1537,7 @@ impl<W: Write> ReportWriter<W> {
         let report = SerializableReport {
             header,
             groups: IteratorWrapper(Cell::new(Some(groups))),
+            stats: self.trailer.as_ref().map(|t| t.stats.as_ref()),
         };
 
         serde_json::to_writer_pretty(&mut self.out, &report)?;
@@ -278,12 +1554,118 @@ impl<W: Write> ReportWriter<W> {
     where
         I: IntoIterator<Item = G>,
         G: Borrow<FileGroup<P>> + Serialize,
-        P: Display + Serialize,
+        P: ReportPath + Serialize,
     {
+        let trailer = self.trailer.as_ref().map(|t| (t.rf_over, t.stats.clone()));
+        let groups = groups.into_iter().inspect(move |g| {
+            if let Some((rf_over, stats)) = &trailer {
+                stats.borrow_mut().add(g.borrow(), *rf_over);
+            }
+        });
         match format {
             OutputFormat::Default => self.write_as_text(header, groups),
             OutputFormat::Fdupes => self.write_as_fdupes(header, groups),
             OutputFormat::Csv => self.write_as_csv(header, groups),
+            OutputFormat::Summary => self.write_as_summary(header, groups),
+            OutputFormat::TextV2 => self.write_as_text_v2(header, groups),
+            OutputFormat::Json if self.compact_paths => {
+                let roots = self.roots.clone();
+                let mut clusters = std::mem::take(&mut self.link_clusters).into_iter();
+                let mut allocated = std::mem::take(&mut self.allocated_sizes).into_iter();
+                let devices = self.devices.take();
+                let table = RefCell::new(PathTable::default());
+                let groups = groups.into_iter().map(|g| {
+                    let g = g.borrow();
+                    let (file_roots, files): (Vec<_>, Vec<_>) = sort_by_root(&g.files, &roots)
+                        .into_iter()
+                        .map(|(root, f)| (root.map(|i| roots[i].clone()), f))
+                        .unzip();
+                    let mut table = table.borrow_mut();
+                    CompactFileGroup {
+                        id: g.id(),
+                        file_len: g.file_len,
+                        file_hash: g.file_hash.clone(),
+                        hashes: g.hashes.clone(),
+                        devices: devices.as_ref().map(|d| d.get_all(files.iter().copied())),
+                        files: files
+                            .iter()
+                            .map(|f| table.add(&report_path(*f, PathFormat::Plain)))
+                            .collect(),
+                        roots: Some(file_roots).filter(|_| !roots.is_empty()),
+                        link_clusters: clusters.next(),
+                        allocated_size: allocated.next(),
+                        truncated_from: g.truncated_from,
+                        annotations: g.annotations.clone(),
+                    }
+                });
+                let report = CompactReport {
+                    header,
+                    groups: IteratorWrapper(Cell::new(Some(groups))),
+                    dirs: &table,
+                    stats: self.trailer.as_ref().map(|t| t.stats.as_ref()),
+                };
+                serde_json::to_writer_pretty(&mut self.out, &report)?;
+                Ok(())
+            }
+            OutputFormat::Json if !self.roots.is_empty() => {
+                let roots = self.roots.clone();
+                let mut allocated = std::mem::take(&mut self.allocated_sizes).into_iter();
+                let devices = self.devices.take();
+                let groups = groups.into_iter().map(move |g| {
+                    let g = g.borrow();
+                    let (file_roots, files): (_, Vec<_>) = sort_by_root(&g.files, &roots)
+                        .into_iter()
+                        .map(|(root, f)| (root.map(|i| roots[i].clone()), f))
+                        .unzip();
+                    RootedFileGroup {
+                        id: g.id(),
+                        file_len: g.file_len,
+                        file_hash: g.file_hash.clone(),
+                        hashes: g.hashes.clone(),
+                        devices: devices.as_ref().map(|d| d.get_all(files.iter().copied())),
+                        files: files
+                            .iter()
+                            .map(|f| report_path(*f, PathFormat::Plain))
+                            .collect(),
+                        roots: file_roots,
+                        allocated_size: allocated.next(),
+                        truncated_from: g.truncated_from,
+                        annotations: g.annotations.clone(),
+                    }
+                });
+                self.write_as_json(header, groups)
+            }
+            OutputFormat::Json
+                if !self.link_clusters.is_empty()
+                    || !self.allocated_sizes.is_empty()
+                    || self.devices.is_some() =>
+            {
+                let linked = !self.link_clusters.is_empty();
+                let mut clusters = std::mem::take(&mut self.link_clusters).into_iter();
+                let mut allocated = std::mem::take(&mut self.allocated_sizes).into_iter();
+                let devices = self.devices.take();
+                let groups = groups.into_iter().map(move |g| {
+                    let g = g.borrow();
+                    let files: Vec<_> = g
+                        .files
+                        .iter()
+                        .map(|f| report_path(f, PathFormat::Plain))
+                        .collect();
+                    LinkedFileGroup {
+                        id: g.id(),
+                        file_len: g.file_len,
+                        file_hash: g.file_hash.clone(),
+                        hashes: g.hashes.clone(),
+                        devices: devices.as_ref().map(|d| d.get_all(&g.files)),
+                        files,
+                        link_clusters: linked.then(|| clusters.next().unwrap_or_default()),
+                        allocated_size: allocated.next(),
+                        truncated_from: g.truncated_from,
+                        annotations: g.annotations.clone(),
+                    }
+                });
+                self.write_as_json(header, groups)
+            }
             OutputFormat::Json => self.write_as_json(header, groups),
         }
     }
@@ -301,6 +1683,12 @@ pub trait ReportReader {
 
     /// Opens an iterator over groups.
     fn read_groups(self: Box<Self>) -> io::Result<Box<GroupIterator>>;
+
+    /// Returns true if the groups can carry annotations.
+    /// Only JSON reports can, the groups of the other reports have no annotations.
+    fn has_annotations(&self) -> bool {
+        false
+    }
 }
 
 /// Iterates the contents of the report.
@@ -317,6 +1705,8 @@ struct GroupHeader {
     count: usize,
     file_len: FileLen,
     file_hash: FileHash,
+    hashes: Digests,
+    truncated_from: Option<usize>,
 }
 
 impl<R> TextReportIterator<R>
@@ -353,8 +1743,13 @@ where
         };
 
         lazy_static! {
-            static ref GROUP_HEADER_RE: Regex =
-                Regex::new(r"^([a-f0-9]{32}), ([0-9]+) B [^*]* \* ([0-9]+):").unwrap();
+            static ref GROUP_HEADER_RE: Regex = Regex::new(
+                r"^([a-f0-9]+), ([0-9]+) B [^*]* \* ([0-9]+)((?:, [a-z0-9]+:[a-f0-9]+)*):"
+            )
+            .unwrap();
+            static ref HASH_RE: Regex = Regex::new(r", ([a-z0-9]+):([a-f0-9]+)").unwrap();
+            static ref TRUNCATED_RE: Regex =
+                Regex::new(r", truncated from ([0-9]+) files$").unwrap();
         }
 
         let captures = GROUP_HEADER_RE.captures(header_str).ok_or_else(|| {
@@ -365,20 +1760,36 @@ where
         })?;
 
         Ok(Some(GroupHeader {
-            file_hash: FileHash(
-                u128::from_str_radix(captures.get(1).unwrap().as_str(), 16).unwrap(),
-            ),
+            file_hash: FileHash::from_hex(captures.get(1).unwrap().as_str())
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e))?,
             file_len: FileLen(captures.get(2).unwrap().as_str().parse::<u64>().unwrap()),
             count: captures.get(3).unwrap().as_str().parse::<usize>().unwrap(),
+            hashes: HASH_RE
+                .captures_iter(captures.get(4).unwrap().as_str())
+                .map(|c| {
+                    let hash_fn = c[1].parse::<HashFn>();
+                    let hash_fn = hash_fn.map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                    let hash = FileHash::from_hex(&c[2]);
+                    let hash = hash.map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+                    Ok((hash_fn, hash))
+                })
+                .collect::<io::Result<_>>()?,
+            truncated_from: TRUNCATED_RE
+                .captures(header_str)
+                .and_then(|c| c[1].parse::<usize>().ok()),
         }))
     }
 
     fn read_paths(&mut self, count: usize) -> io::Result<Vec<Path>> {
         let mut paths = Vec::with_capacity(min(count, 1024));
-        for _ in 0..count {
+        while paths.len() < count {
             self.line_buf.clear();
             let n = self.stream.read_line(&mut self.line_buf)?;
             let path_str = &self.line_buf;
+            if !path_str.starts_with("    ") && path_str.trim_start().starts_with('#') {
+                // comments within a group, e.g. root annotations
+                continue;
+            }
             if n == 0 {
                 return Err(Error::new(
                     ErrorKind::UnexpectedEof,
@@ -391,7 +1802,7 @@ where
                     format!("Path expected: {}", path_str),
                 ));
             }
-            paths.push(Path::from(path_str.trim()));
+            paths.push(parse_path(path_str.trim())?);
         }
         Ok(paths)
     }
@@ -411,7 +1822,10 @@ impl<R: BufRead + 'static> FallibleIterator for TextReportIterator<R> {
                 Ok(Some(FileGroup {
                     file_len: header.file_len,
                     file_hash: header.file_hash,
+                    hashes: header.hashes,
                     files: paths,
+                    truncated_from: header.truncated_from,
+                    annotations: Annotations::new(),
                 }))
             }
             Ok(None) => Ok(None),
@@ -454,15 +1868,39 @@ impl<R: BufRead> TextReportReader<R> {
             .map(|c| c.unwrap().as_str().to_owned())
             .collect())
     }
-}
 
-impl<R: BufRead + Send + 'static> ReportReader for TextReportReader<R> {
-    fn read_header(&mut self) -> io::Result<ReportHeader> {
+    /// Reads the histogram lines starting with the given prefix, written by
+    /// `ReportWriter::write_histogram_lines`
+    fn read_histogram(&mut self, prefix: &[u8], regex: &Regex) -> io::Result<Vec<HistogramBucket>> {
+        let mut buckets = Vec::new();
+        while self.stream.fill_buf()?.starts_with(prefix) {
+            let line = self.read_extract(regex, "Malformed header: Bad histogram")?;
+            let parse_error = |e: std::num::ParseIntError| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Malformed header: Failed to parse histogram: {}", e),
+                )
+            };
+            let min = line[0].parse().map_err(parse_error)?;
+            let max = match line[1].as_str() {
+                "" => Some(min),
+                "+" => None,
+                max => Some(max[1..].parse().map_err(parse_error)?),
+            };
+            buckets.push(HistogramBucket {
+                min,
+                max,
+                group_count: line[2].parse().map_err(parse_error)?,
+                redundant_file_count: line[3].parse().map_err(parse_error)?,
+                redundant_file_size: FileLen(line[4].parse().map_err(parse_error)?),
+            });
+        }
+        Ok(buckets)
+    }
+
+    /// Reads the group count and the redundant file statistics lines
+    fn read_stats(&mut self) -> io::Result<FileStats> {
         lazy_static! {
-            static ref VERSION_RE: Regex =
-                Regex::new(r"^# Report by fclones ([0-9]+\.[0-9]+\.[0-9]+)").unwrap();
-            static ref TIMESTAMP_RE: Regex = Regex::new(r"^# Timestamp: (.*)").unwrap();
-            static ref COMMAND_RE: Regex = Regex::new(r"^# Command: (.*)").unwrap();
             static ref GROUP_COUNT_RE: Regex =
                 Regex::new(r"^# Found ([0-9]+) file groups").unwrap();
             static ref STATS_RE: Regex =
@@ -470,27 +1908,6 @@ impl<R: BufRead + Send + 'static> ReportReader for TextReportReader<R> {
                     .unwrap();
         }
 
-        let version = self
-            .read_extract(&VERSION_RE, "Malformed header: Missing fclones version")?
-            .swap_remove(0);
-        let timestamp = self
-            .read_extract(&TIMESTAMP_RE, "Malformed header: Missing timestamp")?
-            .swap_remove(0);
-        let timestamp = DateTime::parse_from_str(&timestamp, TIMESTAMP_FMT).map_err(|e| {
-            Error::new(
-                ErrorKind::InvalidData,
-                format!("Malformed header: Failed to parse timestamp: {}", e),
-            )
-        })?;
-        let command = self
-            .read_extract(&COMMAND_RE, "Malformed header: Missing command")?
-            .swap_remove(0);
-        let command = shell_words::split(&command).map_err(|e| {
-            Error::new(
-                ErrorKind::InvalidData,
-                format!("Malformed header: Failed to parse command arguments: {}", e),
-            )
-        })?;
         let group_count = self
             .read_extract(&GROUP_COUNT_RE, "Malformed header: Missing group count")?
             .swap_remove(0);
@@ -520,23 +1937,507 @@ impl<R: BufRead + Send + 'static> ReportReader for TextReportReader<R> {
                 ),
             )
         })?;
+        Ok(FileStats {
+            group_count,
+            redundant_file_count,
+            redundant_file_size,
+        })
+    }
+}
+
+impl<R: BufRead + Send + 'static> ReportReader for TextReportReader<R> {
+    fn read_header(&mut self) -> io::Result<ReportHeader> {
+        lazy_static! {
+            static ref VERSION_RE: Regex =
+                Regex::new(r"^# Report by fclones ([0-9]+\.[0-9]+\.[0-9]+)").unwrap();
+            static ref TIMESTAMP_RE: Regex = Regex::new(r"^# Timestamp: (.*)").unwrap();
+            static ref COMMAND_RE: Regex = Regex::new(r"^# Command: (.*)").unwrap();
+            static ref HASH_RE: Regex = Regex::new(r"^# Hash: (.*), ([0-9]+) bits$").unwrap();
+            static ref ERROR_COUNT_RE: Regex = Regex::new(r"^# Scan errors: ([0-9]+)").unwrap();
+            static ref ERROR_RE: Regex = Regex::new(r"^#   (.*)").unwrap();
+            static ref ROOT_STATS_RE: Regex =
+                Regex::new(r"^# Root (.*): ([0-9]+) files, ([0-9]+) B").unwrap();
+            static ref EXT_STATS_RE: Regex =
+                Regex::new(r"^# Extension (.*): ([0-9]+) files, ([0-9]+) B").unwrap();
+            static ref OWNER_STATS_RE: Regex = Regex::new(
+                r"^# Owner (.*) \(([0-9]+)\): ([0-9]+) files, ([0-9]+) B \(.*\), ([0-9]+) B"
+            )
+            .unwrap();
+            static ref SIZE_HISTOGRAM_RE: Regex = Regex::new(
+                r"^# Size ([0-9]+)(-[0-9]+|\+|) B: ([0-9]+) groups, ([0-9]+) redundant files, ([0-9]+) B"
+            )
+            .unwrap();
+            static ref COPIES_HISTOGRAM_RE: Regex = Regex::new(
+                r"^# Copies ([0-9]+)(-[0-9]+|\+|): ([0-9]+) groups, ([0-9]+) redundant files, ([0-9]+) B"
+            )
+            .unwrap();
+            static ref LINK_STATS_RE: Regex =
+                Regex::new(r"^# Already shared by links: ([0-9]+) files, ([0-9]+) B").unwrap();
+            // The lines are trimmed, so an empty value loses the space after the colon
+            static ref HOST_RE: Regex = Regex::new(r"^# Host: ?(.*)").unwrap();
+            static ref SYSTEM_RE: Regex = Regex::new(r"^# System: (.*) \(([^()]*)\)$").unwrap();
+            static ref ROOT_FILE_SYSTEM_RE: Regex =
+                Regex::new(r"^# File system of (.*): ?(.*)").unwrap();
+            static ref FEATURES_RE: Regex = Regex::new(r"^# Features: (.*)").unwrap();
+        }
+
+        let version = self
+            .read_extract(&VERSION_RE, "Malformed header: Missing fclones version")?
+            .swap_remove(0);
+        let timestamp = self
+            .read_extract(&TIMESTAMP_RE, "Malformed header: Missing timestamp")?
+            .swap_remove(0);
+        let timestamp = DateTime::parse_from_str(&timestamp, TIMESTAMP_FMT).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Malformed header: Failed to parse timestamp: {}", e),
+            )
+        })?;
+        let command = self
+            .read_extract(&COMMAND_RE, "Malformed header: Missing command")?
+            .swap_remove(0);
+        let command = shell_words::split(&command).map_err(|e| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Malformed header: Failed to parse command arguments: {}", e),
+            )
+        })?;
+        let hash = if self.stream.fill_buf()?.starts_with(b"# Hash: ") {
+            let hash_line = self.read_extract(&HASH_RE, "Malformed header: Bad hash")?;
+            let function = hash_line[0].parse().map_err(|e| {
+                Error::new(ErrorKind::InvalidData, format!("Malformed header: {}", e))
+            })?;
+            let bits = hash_line[1].parse().map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Malformed header: Failed to parse hash width: {}", e),
+                )
+            })?;
+            Some(GroupingHash { function, bits })
+        } else {
+            None
+        };
+        // Streamed reports carry the statistics after the last group, not in the header:
+        let stats = if self.stream.fill_buf()?.starts_with(b"# Found ") {
+            Some(self.read_stats()?)
+        } else {
+            None
+        };
+
+        // Root stats and the list of errors are optional, so we must peek before consuming
+        // the line, otherwise we might consume the first group header:
+        let mut root_stats = Vec::new();
+        while self.stream.fill_buf()?.starts_with(b"# Root ") {
+            let root_line =
+                self.read_extract(&ROOT_STATS_RE, "Malformed header: Bad root stats")?;
+            let parse_error = |e: std::num::ParseIntError| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Malformed header: Failed to parse root stats: {}", e),
+                )
+            };
+            root_stats.push(RootStats {
+                root: root_line[0].clone(),
+                file_count: root_line[1].parse().map_err(parse_error)?,
+                file_size: FileLen(root_line[2].parse().map_err(parse_error)?),
+            });
+        }
+
+        let mut ext_stats = Vec::new();
+        while self.stream.fill_buf()?.starts_with(b"# Extension ") {
+            let mut ext_line =
+                self.read_extract(&EXT_STATS_RE, "Malformed header: Bad extension stats")?;
+            let parse_error = |e: std::num::ParseIntError| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Malformed header: Failed to parse extension stats: {}", e),
+                )
+            };
+            if ext_line[0] == "(none)" {
+                ext_line[0].clear();
+            }
+            ext_stats.push(ExtStats {
+                ext: ext_line[0].clone(),
+                file_count: ext_line[1].parse().map_err(parse_error)?,
+                file_size: FileLen(ext_line[2].parse().map_err(parse_error)?),
+            });
+        }
+
+        let mut owner_stats = Vec::new();
+        while self.stream.fill_buf()?.starts_with(b"# Owner ") {
+            let owner_line =
+                self.read_extract(&OWNER_STATS_RE, "Malformed header: Bad owner stats")?;
+            let parse_error = |e: std::num::ParseIntError| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Malformed header: Failed to parse owner stats: {}", e),
+                )
+            };
+            owner_stats.push(OwnerStats {
+                owner: owner_line[0].clone(),
+                uid: owner_line[1].parse().map_err(parse_error)?,
+                file_count: owner_line[2].parse().map_err(parse_error)?,
+                file_size: FileLen(owner_line[3].parse().map_err(parse_error)?),
+                redundant_file_size: FileLen(owner_line[4].parse().map_err(parse_error)?),
+            });
+        }
+
+        let size_histogram = self.read_histogram(b"# Size ", &SIZE_HISTOGRAM_RE)?;
+        let copies_histogram = self.read_histogram(b"# Copies ", &COPIES_HISTOGRAM_RE)?;
+
+        let link_stats = if self
+            .stream
+            .fill_buf()?
+            .starts_with(b"# Already shared by links: ")
+        {
+            let link_line =
+                self.read_extract(&LINK_STATS_RE, "Malformed header: Bad link stats")?;
+            let parse_error = |e: std::num::ParseIntError| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Malformed header: Failed to parse link stats: {}", e),
+                )
+            };
+            Some(LinkStats {
+                file_count: link_line[0].parse().map_err(parse_error)?,
+                file_size: FileLen(link_line[1].parse().map_err(parse_error)?),
+            })
+        } else {
+            None
+        };
+
+        let host = if self.stream.fill_buf()?.starts_with(b"# Host: ") {
+            let hostname = self
+                .read_extract(&HOST_RE, "Malformed header: Bad host name")?
+                .swap_remove(0);
+            let mut system =
+                self.read_extract(&SYSTEM_RE, "Malformed header: Missing system description")?;
+            let mut root_file_systems = Vec::new();
+            while self.stream.fill_buf()?.starts_with(b"# File system of ") {
+                let mut line = self.read_extract(
+                    &ROOT_FILE_SYSTEM_RE,
+                    "Malformed header: Bad file system of input path",
+                )?;
+                root_file_systems.push(RootFileSystem {
+                    file_system: line.swap_remove(1),
+                    root: line.swap_remove(0),
+                });
+            }
+            let features = if self.stream.fill_buf()?.starts_with(b"# Features: ") {
+                self.read_extract(&FEATURES_RE, "Malformed header: Bad features")?[0]
+                    .split(", ")
+                    .map(|f| f.to_owned())
+                    .collect()
+            } else {
+                vec![]
+            };
+            Some(HostMetadata {
+                hostname,
+                arch: system.swap_remove(1),
+                os: system.swap_remove(0),
+                root_file_systems,
+                features,
+            })
+        } else {
+            None
+        };
+
+        let mut errors = Vec::new();
+        if self.stream.fill_buf()?.starts_with(b"# Scan errors: ") {
+            let error_count = self
+                .read_extract(&ERROR_COUNT_RE, "Malformed header: Missing error count")?
+                .swap_remove(0);
+            let error_count: usize = error_count.parse().map_err(|e| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Malformed header: Failed to parse error count: {}", e),
+                )
+            })?;
+            for _ in 0..error_count {
+                errors.push(
+                    self.read_extract(&ERROR_RE, "Malformed header: Missing error message")?
+                        .swap_remove(0),
+                );
+            }
+        }
+
+        Ok(ReportHeader {
+            version,
+            timestamp,
+            command,
+            hash,
+            stats,
+            errors,
+            root_stats,
+            ext_stats,
+            owner_stats,
+            size_histogram,
+            copies_histogram,
+            link_stats,
+            host,
+        })
+    }
+
+    fn read_groups(
+        self: Box<Self>,
+    ) -> io::Result<Box<dyn FallibleIterator<Item = FileGroup<Path>, Error = Error> + Send>> {
+        Ok(Box::new(TextReportIterator::new(self.stream)))
+    }
+}
+
+/// Computes the checksum of a `text-v2` group from its group line without the checksum field
+/// and its file lines
+fn group_checksum(group_line: &str, file_lines: &str) -> FileHash {
+    use sha2::Digest;
+    let mut checksum = sha2::Sha256::new();
+    checksum.update(group_line.as_bytes());
+    checksum.update(b"\n");
+    checksum.update(file_lines.as_bytes());
+    FileHash::from_bytes(&checksum.finalize())
+}
+
+/// Iterates the groups of a report in the `text-v2` format.
+/// Verifies the checksum of each group before returning the group, and the number of groups
+/// and files and the checksum given in the `[end]` line.
+pub struct TextV2ReportIterator<R: BufRead> {
+    stream: R,
+    line_buf: String,
+    checksum: sha2::Sha256,
+    group_count: usize,
+    file_count: usize,
+    finished: bool,
+}
+
+impl<R: BufRead> TextV2ReportIterator<R> {
+    fn new(stream: R) -> TextV2ReportIterator<R> {
+        use sha2::Digest;
+        TextV2ReportIterator {
+            stream,
+            line_buf: String::new(),
+            checksum: sha2::Sha256::new(),
+            group_count: 0,
+            file_count: 0,
+            finished: false,
+        }
+    }
+
+    fn read_line(&mut self) -> io::Result<()> {
+        self.line_buf.clear();
+        if self.stream.read_line(&mut self.line_buf)? == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "Missing [end] line. The report is truncated.",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Parses the `key=value` fields of a group or end line
+    fn parse_fields(fields: &str) -> io::Result<Vec<(&str, &str)>> {
+        fields
+            .split_whitespace()
+            .map(|f| {
+                f.split_once('=').ok_or_else(|| {
+                    Error::new(ErrorKind::InvalidData, format!("Malformed field: {}", f))
+                })
+            })
+            .collect()
+    }
+
+    fn parse_group_header(line: &str) -> io::Result<GroupHeader> {
+        let malformed = |msg: &str| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("Malformed group line: {}: {}", msg, line),
+            )
+        };
+        let (id, fields) = line
+            .strip_prefix("[group ")
+            .and_then(|l| l.split_once(']'))
+            .ok_or_else(|| malformed("Group tag expected"))?;
+        let mut file_hash = None;
+        let mut file_len = None;
+        let mut count = None;
+        let mut hashes = Digests::new();
+        let mut truncated_from = None;
+        for (key, value) in Self::parse_fields(fields)? {
+            match key {
+                "hash" => file_hash = FileHash::from_hex(value).ok(),
+                "size" => file_len = value.parse().ok().map(FileLen),
+                "files" => count = value.parse().ok(),
+                "truncated-from" => {
+                    truncated_from =
+                        Some(value.parse().map_err(|_| malformed("Bad truncated-from"))?)
+                }
+                key => {
+                    // fields added by newer versions are skipped
+                    if let Ok(hash_fn) = key.parse::<HashFn>() {
+                        let hash = FileHash::from_hex(value).map_err(|e| malformed(&e))?;
+                        hashes.insert(hash_fn, hash);
+                    }
+                }
+            }
+        }
+        let file_hash = file_hash.ok_or_else(|| malformed("Missing or bad hash"))?;
+        let file_len = file_len.ok_or_else(|| malformed("Missing or bad size"))?;
+        let count = count.ok_or_else(|| malformed("Missing or bad file count"))?;
+        if id != group_id(file_len, &file_hash) {
+            return Err(malformed("Group id doesn't match the size and hash"));
+        }
+        Ok(GroupHeader {
+            count,
+            file_len,
+            file_hash,
+            hashes,
+            truncated_from,
+        })
+    }
+
+    /// Checks the `[end]` line against the groups read so far
+    fn check_end(&mut self, fields: &str) -> io::Result<()> {
+        use sha2::Digest;
+        let checksum = FileHash::from_bytes(&std::mem::take(&mut self.checksum).finalize());
+        let expected = [
+            ("groups", self.group_count.to_string()),
+            ("files", self.file_count.to_string()),
+            ("checksum", format!("sha256:{}", checksum)),
+        ];
+        let fields = Self::parse_fields(fields)?;
+        for (key, value) in expected.iter() {
+            match fields.iter().find(|(k, _)| k == key) {
+                Some((_, v)) if v == value => {}
+                Some((_, v)) => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "Report doesn't match its [end] line: {} is {}, but the report has {}",
+                            key, v, value
+                        ),
+                    ))
+                }
+                None => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Missing {} in the [end] line", key),
+                    ))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn read_group(&mut self) -> io::Result<Option<FileGroup<Path>>> {
+        use sha2::Digest;
+        loop {
+            self.read_line()?;
+            if self.line_buf.starts_with('#') {
+                // comments, e.g. the statistics of a streamed report
+                continue;
+            }
+            if let Some(fields) = self.line_buf.strip_prefix("[end]") {
+                let fields = fields.to_owned();
+                self.check_end(&fields)?;
+                return Ok(None);
+            }
+            break;
+        }
+        self.checksum.update(self.line_buf.as_bytes());
+        let group_line = self.line_buf.trim_end().to_owned();
+        let header = Self::parse_group_header(&group_line)?;
+        let (group_fields, expected_checksum) =
+            group_line.rsplit_once(" checksum=sha256:").ok_or_else(|| {
+                Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Malformed group line: Missing checksum: {}", group_line),
+                )
+            })?;
+        let mut file_lines = String::new();
+        let mut files = Vec::with_capacity(min(header.count, 1024));
+        for _ in 0..header.count {
+            self.read_line()?;
+            self.checksum.update(self.line_buf.as_bytes());
+            file_lines.push_str(&self.line_buf);
+            let path = self
+                .line_buf
+                .strip_prefix('\t')
+                .and_then(|l| l.strip_suffix('\n'))
+                .ok_or_else(|| {
+                    Error::new(
+                        ErrorKind::InvalidData,
+                        format!("Path expected: {}", self.line_buf),
+                    )
+                })?;
+            files.push(unescape_path(path)?);
+        }
+        let checksum = group_checksum(group_fields, &file_lines);
+        if checksum.to_string() != expected_checksum {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "Group {} doesn't match its checksum. The report was edited.",
+                    group_id(header.file_len, &header.file_hash)
+                ),
+            ));
+        }
+        self.group_count += 1;
+        self.file_count += files.len();
+        Ok(Some(FileGroup {
+            file_len: header.file_len,
+            file_hash: header.file_hash,
+            hashes: header.hashes,
+            files,
+            truncated_from: header.truncated_from,
+            annotations: Annotations::new(),
+        }))
+    }
+}
+
+impl<R: BufRead + 'static> FallibleIterator for TextV2ReportIterator<R> {
+    type Item = FileGroup<Path>;
+    type Error = std::io::Error;
 
-        Ok(ReportHeader {
-            version,
-            timestamp,
-            command,
-            stats: Some(FileStats {
-                group_count,
-                redundant_file_count,
-                redundant_file_size,
-            }),
-        })
+    fn next(&mut self) -> Result<Option<Self::Item>, Self::Error> {
+        if self.finished {
+            return Ok(None);
+        }
+        let result = self.read_group();
+        if !matches!(result, Ok(Some(_))) {
+            self.finished = true;
+        }
+        result
+    }
+}
+
+/// Reads a report in the `text-v2` format, see [`ReportWriter::write_as_text_v2`].
+/// Does not load the whole report into memory.
+pub struct TextV2ReportReader<R: BufRead> {
+    inner: TextReportReader<R>,
+}
+
+impl<R: BufRead> TextV2ReportReader<R> {
+    /// Creates a new reader for reading from the given stream
+    pub fn new(stream: R) -> TextV2ReportReader<R> {
+        TextV2ReportReader {
+            inner: TextReportReader::new(stream),
+        }
+    }
+}
+
+impl<R: BufRead + Send + 'static> ReportReader for TextV2ReportReader<R> {
+    fn read_header(&mut self) -> io::Result<ReportHeader> {
+        if self.inner.read_line()?.trim_end() != TEXT_V2_TAG {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Malformed header: Missing text-v2 tag",
+            ));
+        }
+        self.inner.read_header()
     }
 
     fn read_groups(
         self: Box<Self>,
     ) -> io::Result<Box<dyn FallibleIterator<Item = FileGroup<Path>, Error = Error> + Send>> {
-        Ok(Box::new(TextReportIterator::new(self.stream)))
+        Ok(Box::new(TextV2ReportIterator::new(self.inner.stream)))
     }
 }
 
@@ -561,15 +2462,58 @@ impl JsonReportReader {
 
 impl ReportReader for JsonReportReader {
     fn read_header(&mut self) -> io::Result<ReportHeader> {
-        Ok(self.report.header.clone())
+        let mut header = self.report.header.clone();
+        if header.stats.is_none() {
+            header.stats = self.report.stats.clone();
+        }
+        Ok(header)
+    }
+
+    fn has_annotations(&self) -> bool {
+        true
     }
 
     fn read_groups(self: Box<Self>) -> io::Result<Box<GroupIterator>> {
-        let iter = self.report.groups.into_iter().map(|g| {
+        // Directories of paths that are not valid Unicode are written as parts of `file://` URIs,
+        // which must be joined with the file name before decoding:
+        let dirs: Vec<Option<Arc<Path>>> = self
+            .report
+            .dirs
+            .iter()
+            .map(|d| {
+                Some(d)
+                    .filter(|d| !d.is_empty() && !d.starts_with("file://"))
+                    .map(|d| Path::from(d).share())
+            })
+            .collect();
+        let uri_dirs = self.report.dirs;
+        let iter = self.report.groups.into_iter().map(move |g| {
+            let files = g
+                .files
+                .into_iter()
+                .map(|f| match f {
+                    DeserializedPath::Plain(s) if s.starts_with("file://") => parse_path(&s),
+                    DeserializedPath::Plain(s) => Ok(Path::from(s.as_str())),
+                    DeserializedPath::Compact(index, name) => match dirs.get(index) {
+                        Some(Some(dir)) => Ok(dir.join(Path::from(name.as_str()))),
+                        Some(None) if uri_dirs[index].starts_with("file://") => {
+                            parse_path(&(uri_dirs[index].clone() + &name))
+                        }
+                        Some(None) => Ok(Path::from(name.as_str())),
+                        None => Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("Invalid directory index {} of file {}", index, name),
+                        )),
+                    },
+                })
+                .collect::<io::Result<Vec<_>>>()?;
             Ok(FileGroup {
                 file_len: g.file_len,
                 file_hash: g.file_hash,
-                files: g.files.iter().map(|s| Path::from(s.as_str())).collect_vec(),
+                hashes: g.hashes,
+                files,
+                truncated_from: g.truncated_from,
+                annotations: g.annotations,
             })
         });
         let iter = fallible_iterator::convert(iter);
@@ -577,6 +2521,67 @@ impl ReportReader for JsonReportReader {
     }
 }
 
+/// A file of the report of unreadable files, together with the device holding it
+#[derive(Serialize)]
+struct UnreadableEntry<'a> {
+    #[serde(flatten)]
+    file: &'a UnreadableFile,
+    device: String,
+    mount_point: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_system: Option<String>,
+    disk_type: &'static str,
+    network: bool,
+}
+
+/// Report of the files that couldn't be accessed, written with `--unreadable-report`
+#[derive(Serialize)]
+struct UnreadableReport<'a> {
+    version: &'static str,
+    timestamp: DateTime<FixedOffset>,
+    command: Vec<String>,
+    files: Vec<UnreadableEntry<'a>>,
+}
+
+/// Writes the files that couldn't be accessed as a JSON document.
+/// Each file comes with the failed operation, the error and the device holding the file,
+/// so damaged devices can be told apart from files with wrong permissions.
+pub(crate) fn write_unreadable_report(
+    out: impl Write,
+    files: &[UnreadableFile],
+    devices: &DiskDevices,
+) -> io::Result<()> {
+    let files = files
+        .iter()
+        .map(|f| {
+            let device = devices.get_by_path(&f.path);
+            UnreadableEntry {
+                file: f,
+                device: device.name.to_string_lossy().into_owned(),
+                mount_point: devices.get_mount_point(&f.path).to_string_lossy(),
+                file_system: devices
+                    .get_file_system(&f.path)
+                    .map(|fs| fs.to_string_lossy().into_owned()),
+                disk_type: match device.disk_type {
+                    DiskType::SSD => "ssd",
+                    DiskType::HDD => "hdd",
+                    DiskType::Removable => "removable",
+                    DiskType::Unknown(_) => "unknown",
+                },
+                network: device.network,
+            }
+        })
+        .collect();
+    let report = UnreadableReport {
+        version: env!("CARGO_PKG_VERSION"),
+        timestamp: Local::now().into(),
+        command: std::env::args().collect(),
+        files,
+    };
+    serde_json::to_writer_pretty(out, &report)?;
+    Ok(())
+}
+
 /// Returns a `ReportReader` that can read and decode the report from the given stream.
 /// Automatically detects the type of the report.
 pub fn open_report(r: impl Read + Send + 'static) -> io::Result<Box<dyn ReportReader>> {
@@ -585,14 +2590,17 @@ pub fn open_report(r: impl Read + Send + 'static) -> io::Result<Box<dyn ReportRe
     let preview = String::from_utf8_lossy(preview);
     if preview.starts_with('{') {
         Ok(Box::new(JsonReportReader::new(buf_reader)?))
+    } else if preview.starts_with(TEXT_V2_TAG) {
+        Ok(Box::new(TextV2ReportReader::new(buf_reader)))
     } else if preview.starts_with('#') {
         Ok(Box::new(TextReportReader::new(buf_reader)))
     } else {
         Err(io::Error::new(
             ErrorKind::InvalidData,
             format!(
-                "Unknown report format. Supported formats are: {}, {}",
+                "Unknown report format. Supported formats are: {}, {}, {}",
                 OutputFormat::Default,
+                OutputFormat::TextV2,
                 OutputFormat::Json
             ),
         ))
@@ -614,11 +2622,20 @@ mod test {
             version: env!("CARGO_PKG_VERSION").to_owned(),
             timestamp: DateTime::parse_from_str("2021-08-27 12:11:23.456 +0000", TIMESTAMP_FMT)
                 .unwrap(),
+            hash: Some(GroupingHash::CURRENT),
             stats: Some(FileStats {
                 group_count: 4,
                 redundant_file_count: 234,
                 redundant_file_size: FileLen(1000),
             }),
+            errors: vec![],
+            root_stats: vec![],
+            ext_stats: vec![],
+            owner_stats: vec![],
+            size_histogram: vec![],
+            copies_histogram: vec![],
+            link_stats: None,
+            host: None,
         }
     }
 
@@ -631,7 +2648,7 @@ mod test {
         let input = output.reopen().unwrap();
 
         let mut writer = ReportWriter::new(output, false);
-        writer.write_as_text(&header1, groups.into_iter()).unwrap();
+        writer.write_as_text(&header1, groups).unwrap();
 
         let mut reader = TextReportReader::new(BufReader::new(input));
         let header2 = reader.read_header().unwrap();
@@ -641,20 +2658,110 @@ mod test {
         assert_eq!(header2.stats, header1.stats);
     }
 
+    #[test]
+    fn test_group_id_in_all_formats() {
+        let header = dummy_report_header();
+        let group = FileGroup::new(
+            FileLen(100),
+            FileHash::from(0x00112233445566778899aabbccddeeff),
+            vec![Path::from("/a"), Path::from("/b")],
+        );
+        let id = group.id();
+        assert_eq!(id.len(), 16);
+        let mut other = group.clone();
+        other.file_len = FileLen(101);
+        assert_ne!(other.id(), id);
+
+        for format in [OutputFormat::Default, OutputFormat::Csv, OutputFormat::Json] {
+            let mut out = Vec::new();
+            let mut writer = ReportWriter::new(&mut out, false).with_csv_group_id(true);
+            writer.write(format, &header, [&group]).unwrap();
+            let out = String::from_utf8(out).unwrap();
+            assert!(
+                out.contains(&id),
+                "{:?} report without group id: {}",
+                format,
+                out
+            );
+        }
+    }
+
+    #[test]
+    fn test_csv_delimiter_and_bom() {
+        let header = dummy_report_header();
+        let group = FileGroup::new(
+            FileLen(100),
+            FileHash::from(0x00112233445566778899aabbccddeeff),
+            vec![Path::from("/zdjęcia/a;b"), Path::from("/zdjęcia/c")],
+        );
+        let mut out = Vec::new();
+        ReportWriter::new(&mut out, false)
+            .with_csv_delimiter(b';')
+            .with_csv_bom(true)
+            .write(OutputFormat::Csv, &header, [&group])
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let expected = "\u{feff}size;hash;count;files\n\
+             100;00112233445566778899aabbccddeeff;2;\"/zdjęcia/a;b\";/zdjęcia/c\n";
+        assert_eq!(out, expected);
+
+        let mut out = Vec::new();
+        ReportWriter::new(&mut out, false)
+            .with_csv_group_id(true)
+            .write(OutputFormat::Csv, &header, [&group])
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let expected = format!(
+            "size,hash,count,id,files\n\
+             100,00112233445566778899aabbccddeeff,2,{},/zdjęcia/a;b,/zdjęcia/c\n",
+            group.id()
+        );
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn test_text_report_colors() {
+        let header = dummy_report_header();
+        let groups = [FileGroup::new(
+            FileLen(100),
+            FileHash::from(0x00112233445566778899aabbccddeeff),
+            vec![Path::from("a"), Path::from("b")],
+        )];
+        let write = |color: bool, theme: ReportTheme| {
+            let mut out = Vec::new();
+            let mut writer = ReportWriter::new(&mut out, color).with_theme(theme);
+            writer.write_as_text(&header, groups.iter()).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let plain = write(false, ReportTheme::parse("path=green"));
+        assert!(!plain.contains('\x1b'));
+        let colored = write(true, ReportTheme::default());
+        assert!(colored.contains("\x1b[36m# Report by fclones"));
+        assert!(colored.contains("\n    a\n"));
+        let themed = write(
+            true,
+            ReportTheme::parse("header=red:path=green.bold:foo=blue"),
+        );
+        assert!(themed.contains("\x1b[31m# Report by fclones"));
+        assert!(themed.contains("\x1b[33m00112233445566778899aabbccddeeff"));
+        assert!(!themed.contains("\n    a\n"));
+    }
+
     #[test]
     fn test_text_report_reader_reads_files() {
         let header = dummy_report_header();
         let groups = vec![
-            FileGroup {
-                file_len: FileLen(100),
-                file_hash: FileHash(0x00112233445566778899aabbccddeeff),
-                files: vec![Path::from("a"), Path::from("b")],
-            },
-            FileGroup {
-                file_len: FileLen(40),
-                file_hash: FileHash(0x0000000000000555555555ffffffffff),
-                files: vec![Path::from("c"), Path::from("d")],
-            },
+            FileGroup::new(
+                FileLen(100),
+                FileHash::from(0x00112233445566778899aabbccddeeff),
+                vec![Path::from("a"), Path::from("b")],
+            ),
+            FileGroup::new(
+                FileLen(40),
+                FileHash::from(0x0000000000000555555555ffffffffff),
+                vec![Path::from("c"), Path::from("d")],
+            ),
         ];
 
         let output = NamedTempFile::new().unwrap();
@@ -669,6 +2776,106 @@ mod test {
         assert_eq!(groups, groups2);
     }
 
+    #[test]
+    fn test_report_readers_read_other_hashes() {
+        let header = dummy_report_header();
+        let mut hashes = Digests::new();
+        let sha256 = FileHash::from_hex(&"ba7816bf8f01cfea414140de5dae2223".repeat(2)).unwrap();
+        let sha512 = FileHash::from_hex(&"ddaf35a193617aba".repeat(8)).unwrap();
+        hashes.insert(HashFn::Sha256, sha256);
+        hashes.insert(HashFn::Sha512, sha512);
+        let groups = vec![FileGroup {
+            file_len: FileLen(3),
+            file_hash: FileHash::from(0x00112233445566778899aabbccddeeff),
+            hashes,
+            files: vec![Path::from("a"), Path::from("b")],
+            truncated_from: None,
+            annotations: Annotations::new(),
+        }];
+
+        for &format in [
+            OutputFormat::Default,
+            OutputFormat::TextV2,
+            OutputFormat::Json,
+        ]
+        .iter()
+        {
+            let output = NamedTempFile::new().unwrap();
+            let input = output.reopen().unwrap();
+            let mut writer = ReportWriter::new(output, false);
+            writer.write(format, &header, groups.iter()).unwrap();
+            let mut reader = open_report(input).unwrap();
+            reader.read_header().unwrap();
+            let groups2: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+            assert_eq!(groups, groups2);
+        }
+    }
+
+    #[test]
+    fn test_report_readers_read_truncated_groups() {
+        let header = dummy_report_header();
+        let mut group = FileGroup::new(
+            FileLen(0),
+            FileHash::from(0),
+            (0..10).map(|i| Path::from(format!("f{}", i))).collect(),
+        );
+        group.truncate(3);
+        assert_eq!(group.files.len(), 3);
+        assert_eq!(group.file_count(), 10);
+        let groups = vec![group];
+
+        for &format in [
+            OutputFormat::Default,
+            OutputFormat::TextV2,
+            OutputFormat::Json,
+        ]
+        .iter()
+        {
+            let output = NamedTempFile::new().unwrap();
+            let input = output.reopen().unwrap();
+            let mut writer = ReportWriter::new(output, false);
+            writer.write(format, &header, groups.iter()).unwrap();
+            let mut reader = open_report(input).unwrap();
+            reader.read_header().unwrap();
+            let groups2: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+            assert_eq!(groups, groups2);
+        }
+    }
+
+    #[test]
+    fn test_report_readers_read_wide_hashes() {
+        let header = dummy_report_header();
+        let groups = vec![
+            FileGroup::new(
+                FileLen(3),
+                FileHash::from_hex(&"ddaf35a193617aba".repeat(4)).unwrap(),
+                vec![Path::from("a"), Path::from("b")],
+            ),
+            FileGroup::new(
+                FileLen(2),
+                FileHash::from_hex("0a1b2c3d").unwrap(),
+                vec![Path::from("c"), Path::from("d")],
+            ),
+        ];
+
+        for &format in [
+            OutputFormat::Default,
+            OutputFormat::TextV2,
+            OutputFormat::Json,
+        ]
+        .iter()
+        {
+            let output = NamedTempFile::new().unwrap();
+            let input = output.reopen().unwrap();
+            let mut writer = ReportWriter::new(output, false);
+            writer.write(format, &header, groups.iter()).unwrap();
+            let mut reader = open_report(input).unwrap();
+            reader.read_header().unwrap();
+            let groups2: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+            assert_eq!(groups, groups2);
+        }
+    }
+
     #[test]
     fn test_text_report_iterator_stops_on_error() {
         let mut output = NamedTempFile::new().unwrap();
@@ -716,6 +2923,57 @@ mod test {
         assert!(g.files.contains(&Path::from("/file4")));
     }
 
+    #[test]
+    fn test_allocated_sizes_of_groups() {
+        let header = dummy_report_header();
+        let groups = vec![FileGroup::new(
+            FileLen(100),
+            FileHash::from(0x00112233445566778899aabbccddeeff),
+            vec![Path::from("/a"), Path::from("/b")],
+        )];
+
+        for format in [OutputFormat::Default, OutputFormat::TextV2] {
+            let output = NamedTempFile::new().unwrap();
+            let input = output.reopen().unwrap();
+            let mut check = output.reopen().unwrap();
+            let mut writer =
+                ReportWriter::new(output, false).with_allocated_sizes(vec![FileLen(4096)]);
+            writer.write(format, &header, groups.iter()).unwrap();
+            let mut report = String::new();
+            check.read_to_string(&mut report).unwrap();
+            assert!(report.contains("4096"), "{}", report);
+            let mut reader = open_report(input).unwrap();
+            reader.read_header().unwrap();
+            let groups2: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+            assert_eq!(groups, groups2);
+        }
+    }
+
+    #[test]
+    fn test_text_report_reader_reads_errors() {
+        let mut header = dummy_report_header();
+        header.errors = vec![
+            "Failed to read dir /foo: Permission denied".to_owned(),
+            "Failed to stat /bar: No such file or directory".to_owned(),
+        ];
+        let groups = vec![FileGroup::new(
+            FileLen(100),
+            FileHash::from(0x00112233445566778899aabbccddeeff),
+            vec![Path::from("/a"), Path::from("/b")],
+        )];
+
+        let output = NamedTempFile::new().unwrap();
+        let input = output.reopen().unwrap();
+
+        let mut writer = ReportWriter::new(output, false);
+        writer.write_as_text(&header, groups.iter()).unwrap();
+
+        let mut reader = Box::new(TextReportReader::new(BufReader::new(input)));
+        assert_eq!(reader.read_header().unwrap().errors, header.errors);
+        let groups2: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+        assert_eq!(groups, groups2);
+    }
+
     #[test]
     fn test_json_report_header() {
         let header1 = dummy_report_header();
@@ -725,7 +2983,7 @@ mod test {
         let input = output.reopen().unwrap();
 
         let mut writer = ReportWriter::new(output, false);
-        writer.write_as_json(&header1, groups.into_iter()).unwrap();
+        writer.write_as_json(&header1, groups).unwrap();
 
         let mut reader = JsonReportReader::new(input).unwrap();
         let header2 = reader.read_header().unwrap();
@@ -739,16 +2997,16 @@ mod test {
     fn test_json_report_reader_reads_files() {
         let header = dummy_report_header();
         let groups = vec![
-            FileGroup {
-                file_len: FileLen(100),
-                file_hash: FileHash(0x00112233445566778899aabbccddeeff),
-                files: vec![Path::from("a"), Path::from("b")],
-            },
-            FileGroup {
-                file_len: FileLen(40),
-                file_hash: FileHash(0x0000000000000555555555ffffffffff),
-                files: vec![Path::from("c"), Path::from("d")],
-            },
+            FileGroup::new(
+                FileLen(100),
+                FileHash::from(0x00112233445566778899aabbccddeeff),
+                vec![Path::from("a"), Path::from("b")],
+            ),
+            FileGroup::new(
+                FileLen(40),
+                FileHash::from(0x0000000000000555555555ffffffffff),
+                vec![Path::from("c"), Path::from("d")],
+            ),
         ];
 
         let output = NamedTempFile::new().unwrap();
@@ -763,6 +3021,551 @@ mod test {
         assert_eq!(groups, groups2);
     }
 
+    #[test]
+    fn test_text_report_reader_reads_streamed_report() {
+        let header = ReportHeader {
+            stats: None,
+            ..dummy_report_header()
+        };
+        let groups = vec![FileGroup::new(
+            FileLen(100),
+            FileHash::from(0x00112233445566778899aabbccddeeff),
+            vec![Path::from("a"), Path::from("b"), Path::from("c")],
+        )];
+
+        let mut output = Vec::new();
+        let mut writer = ReportWriter::new(&mut output, false).with_stats_trailer(1);
+        writer
+            .write(OutputFormat::Default, &header, groups.iter())
+            .unwrap();
+        let text = String::from_utf8(output).unwrap();
+        assert!(text.ends_with(
+            "# Found 1 file groups\n# 200 B (200 B) in 2 redundant files can be removed\n"
+        ));
+
+        let mut reader = TextReportReader::new(io::Cursor::new(text.into_bytes()));
+        assert_eq!(reader.read_header().unwrap().stats, None);
+        let groups2: Vec<_> = Box::new(reader).read_groups().unwrap().collect().unwrap();
+        assert_eq!(groups, groups2);
+    }
+
+    #[test]
+    fn test_json_report_preserves_annotations() {
+        let header = dummy_report_header();
+        let mut annotations = Annotations::new();
+        annotations.insert("reviewed".to_owned(), true.into());
+        annotations.insert("ticket".to_owned(), "OPS-12".into());
+        let groups = vec![FileGroup {
+            file_len: FileLen(100),
+            file_hash: FileHash::from(0x00112233445566778899aabbccddeeff),
+            hashes: Digests::new(),
+            files: vec![Path::from("/a"), Path::from("/b")],
+            truncated_from: None,
+            annotations,
+        }];
+
+        for compact in [false, true].iter() {
+            let mut output = Vec::new();
+            let mut writer = ReportWriter::new(&mut output, false).with_compact_paths(*compact);
+            writer
+                .write(OutputFormat::Json, &header, groups.iter())
+                .unwrap();
+            let reader = JsonReportReader::new(io::Cursor::new(output)).unwrap();
+            assert!(reader.has_annotations());
+            let groups2: Vec<_> = Box::new(reader).read_groups().unwrap().collect().unwrap();
+            assert_eq!(groups, groups2);
+        }
+    }
+
+    #[test]
+    fn test_text_reports_have_no_annotations() {
+        let header = dummy_report_header();
+        let groups: Vec<FileGroup<Path>> = vec![];
+        for format in [OutputFormat::Default, OutputFormat::TextV2].iter() {
+            let mut output = Vec::new();
+            let mut writer = ReportWriter::new(&mut output, false);
+            writer.write(*format, &header, groups.iter()).unwrap();
+            let reader = open_report(io::Cursor::new(output)).unwrap();
+            assert!(!reader.has_annotations());
+        }
+    }
+
+    #[test]
+    fn test_json_report_reader_reads_compact_paths() {
+        let header = dummy_report_header();
+        let groups = vec![
+            FileGroup {
+                file_len: FileLen(100),
+                file_hash: FileHash::from(0x00112233445566778899aabbccddeeff),
+                hashes: Digests::new(),
+                files: vec![Path::from("/dir/a"), Path::from("/dir/sub/b")],
+                truncated_from: None,
+                annotations: Annotations::new(),
+            },
+            FileGroup::new(
+                FileLen(40),
+                FileHash::from(0x0000000000000555555555ffffffffff),
+                vec![Path::from("/dir/c"), Path::from("d"), Path::from("/e")],
+            ),
+        ];
+
+        let output = NamedTempFile::new().unwrap();
+        let input = output.reopen().unwrap();
+        let json_input = output.reopen().unwrap();
+
+        let mut writer = ReportWriter::new(output, false).with_compact_paths(true);
+        writer
+            .write(OutputFormat::Json, &header, groups.iter())
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_reader(json_input).unwrap();
+        assert_eq!(
+            json["dirs"],
+            serde_json::json!(["/dir/", "/dir/sub/", "", "/"])
+        );
+        assert_eq!(json["groups"][1]["files"][0], serde_json::json!([0, "c"]));
+
+        let mut reader = Box::new(JsonReportReader::new(input).unwrap());
+        reader.read_header().unwrap();
+        let groups2: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+        assert_eq!(groups, groups2);
+    }
+
+    #[test]
+    fn test_text_report_reader_reads_files_grouped_by_root() {
+        let mut header = dummy_report_header();
+        header.root_stats = vec![
+            RootStats {
+                root: "/laptop".to_owned(),
+                file_count: 1,
+                file_size: FileLen(100),
+            },
+            RootStats {
+                root: "/nas".to_owned(),
+                file_count: 2,
+                file_size: FileLen(200),
+            },
+        ];
+        let groups = [FileGroup::new(
+            FileLen(100),
+            FileHash::from(0x00112233445566778899aabbccddeeff),
+            vec![
+                Path::from("/nas/a"),
+                Path::from("/laptop/b"),
+                Path::from("/other/c"),
+                Path::from("/nas/d"),
+            ],
+        )];
+
+        let output = NamedTempFile::new().unwrap();
+        let input = output.reopen().unwrap();
+        let roots = vec!["/laptop".to_owned(), "/nas".to_owned()];
+        let mut writer = ReportWriter::new(output, false).group_by_roots(roots);
+        writer.write_as_text(&header, groups.iter()).unwrap();
+
+        let mut reader = Box::new(TextReportReader::new(BufReader::new(input)));
+        assert_eq!(reader.read_header().unwrap().root_stats, header.root_stats);
+        let groups2: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+        assert_eq!(groups2.len(), 1);
+        assert_eq!(
+            groups2[0].files,
+            vec![
+                Path::from("/laptop/b"),
+                Path::from("/nas/a"),
+                Path::from("/nas/d"),
+                Path::from("/other/c")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_text_report_reader_reads_files_grouped_by_link_cluster() {
+        let mut header = dummy_report_header();
+        header.link_stats = Some(LinkStats {
+            file_count: 1,
+            file_size: FileLen(100),
+        });
+        header.errors = vec!["error".to_owned()];
+        let files = vec![
+            Path::from("/a"),
+            Path::from("/b"),
+            Path::from("/c"),
+            Path::from("/d"),
+        ];
+        let groups = [FileGroup::new(
+            FileLen(100),
+            FileHash::from(0x00112233445566778899aabbccddeeff),
+            files.clone(),
+        )];
+
+        let output = NamedTempFile::new().unwrap();
+        let input = output.reopen().unwrap();
+        let clusters = vec![vec![Some(1), Some(1), Some(2), None]];
+        let mut writer = ReportWriter::new(output, false).with_link_clusters(clusters);
+        writer.write_as_text(&header, groups.iter()).unwrap();
+
+        let mut reader = Box::new(TextReportReader::new(BufReader::new(input)));
+        let header2 = reader.read_header().unwrap();
+        assert_eq!(header2.link_stats, header.link_stats);
+        assert_eq!(header2.errors, header.errors);
+        let groups2: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+        assert_eq!(groups2.len(), 1);
+        assert_eq!(groups2[0].files, files);
+    }
+
+    #[test]
+    fn test_file_uri() {
+        let path = if cfg!(windows) {
+            "C:\\dir\\a b%.txt"
+        } else {
+            "/dir/a b%.txt"
+        };
+        let uri = to_file_uri(path.as_bytes());
+        if !cfg!(windows) {
+            assert_eq!(uri, "file:///dir/a%20b%25.txt");
+        }
+        assert_eq!(from_file_uri(&uri).unwrap(), path.as_bytes());
+        assert_eq!(from_file_uri("file:///a%2"), None);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_non_unicode_paths_are_read_back_exactly() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let header = dummy_report_header();
+        let non_unicode = Path::from(OsStr::from_bytes(b"/dir/a\xff\xfe b"));
+        let groups = vec![FileGroup::new(
+            FileLen(100),
+            FileHash::from(0x00112233445566778899aabbccddeeff),
+            vec![Path::from("/dir/c"), non_unicode],
+        )];
+        for format in [
+            OutputFormat::Default,
+            OutputFormat::TextV2,
+            OutputFormat::Json,
+        ] {
+            for compact in [false, true] {
+                for path_format in [PathFormat::Plain, PathFormat::Uri, PathFormat::ShellQuoted] {
+                    let output = NamedTempFile::new().unwrap();
+                    let input = output.reopen().unwrap();
+                    let mut writer = ReportWriter::new(output, false)
+                        .with_path_format(path_format)
+                        .with_compact_paths(compact);
+                    writer.write(format, &header, groups.iter()).unwrap();
+
+                    let mut reader = open_report(input).unwrap();
+                    reader.read_header().unwrap();
+                    let groups2: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+                    assert_eq!(groups, groups2, "{} {:?} {}", format, path_format, compact);
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_non_unicode_paths_round_trip_through_csv_and_fdupes() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        let header = dummy_report_header();
+        let files = vec![
+            Path::from("/dir/c, d"),
+            Path::from(OsStr::from_bytes(b"/dir/a\xff\xfe b")),
+            Path::from(OsStr::from_bytes(b"/dir/\"q\"\n\x80")),
+        ];
+        let groups = [FileGroup::new(
+            FileLen(100),
+            FileHash::from(0x00112233445566778899aabbccddeeff),
+            files.clone(),
+        )];
+
+        for path_format in [PathFormat::Plain, PathFormat::Uri, PathFormat::ShellQuoted] {
+            let mut output = Vec::new();
+            let mut writer = ReportWriter::new(&mut output, false).with_path_format(path_format);
+            writer.write_as_fdupes(&header, groups.iter()).unwrap();
+            let output = String::from_utf8(output).unwrap();
+            let files2: Vec<_> = output
+                .lines()
+                .filter(|l| !l.is_empty())
+                .map(|l| parse_path(l).unwrap())
+                .collect();
+            assert_eq!(files2, files, "{:?}", path_format);
+        }
+
+        let mut output = Vec::new();
+        let mut writer = ReportWriter::new(&mut output, false);
+        writer.write_as_csv(&header, groups.iter()).unwrap();
+        let mut reader = csv::ReaderBuilder::new()
+            .flexible(true)
+            .from_reader(output.as_slice());
+        let headers = reader.headers().unwrap().clone();
+        let first_file = headers.iter().position(|h| h == "files").unwrap();
+        let records: Vec<_> = reader.records().map(|r| r.unwrap()).collect();
+        assert_eq!(records.len(), 1);
+        let files2: Vec<_> = records[0]
+            .iter()
+            .skip(first_file)
+            .map(|f| parse_path(f).unwrap())
+            .collect();
+        assert_eq!(files2, files);
+    }
+
+    #[test]
+    fn test_text_report_reader_reads_formatted_paths() {
+        let header = dummy_report_header();
+        let groups = vec![FileGroup::new(
+            FileLen(100),
+            FileHash::from(0x00112233445566778899aabbccddeeff),
+            vec![Path::from("a b"), Path::from("c'd"), Path::from("ż")],
+        )];
+        for path_format in [PathFormat::Uri, PathFormat::ShellQuoted].iter() {
+            let output = NamedTempFile::new().unwrap();
+            let input = output.reopen().unwrap();
+            let mut writer = ReportWriter::new(output, false).with_path_format(*path_format);
+            writer.write_as_text(&header, groups.iter()).unwrap();
+
+            let mut reader = Box::new(TextReportReader::new(BufReader::new(input)));
+            reader.read_header().unwrap();
+            let groups2: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+            assert_eq!(groups, groups2);
+        }
+    }
+
+    #[test]
+    fn test_path_formats_round_trip() {
+        let paths = [
+            "/dir/a b",
+            "/dir/c'd",
+            "'quoted'",
+            "'",
+            "''",
+            "file://not-a-uri",
+            "file:/x",
+            " leading space",
+            "trailing space ",
+            "\ttab",
+            "new\nline",
+            "back\\slash",
+            "%20",
+            "ż",
+        ];
+        for path_format in [PathFormat::Plain, PathFormat::Uri, PathFormat::ShellQuoted] {
+            for &p in paths.iter() {
+                let path = Path::from(p);
+                let formatted = report_path(&path, path_format);
+                assert!(
+                    !formatted.contains('\n'),
+                    "{:?} {:?}",
+                    path_format,
+                    formatted
+                );
+                assert_eq!(
+                    parse_path(formatted.trim()).unwrap(),
+                    path,
+                    "{:?} {:?}",
+                    path_format,
+                    formatted
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_text_report_round_trips_ambiguous_paths() {
+        let header = dummy_report_header();
+        let groups = vec![FileGroup::new(
+            FileLen(100),
+            FileHash::from(0x00112233445566778899aabbccddeeff),
+            vec![
+                Path::from("'x'"),
+                Path::from(" y"),
+                Path::from("file://z"),
+                Path::from("a\nb"),
+            ],
+        )];
+        for path_format in [PathFormat::Plain, PathFormat::Uri, PathFormat::ShellQuoted] {
+            let output = NamedTempFile::new().unwrap();
+            let input = output.reopen().unwrap();
+            let mut writer = ReportWriter::new(output, false).with_path_format(path_format);
+            writer.write_as_text(&header, groups.iter()).unwrap();
+
+            let mut reader = open_report(input).unwrap();
+            reader.read_header().unwrap();
+            let groups2: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+            assert_eq!(groups, groups2, "{:?}", path_format);
+        }
+    }
+
+    #[test]
+    fn test_ext_stats() {
+        let groups = [FileGroup::new(
+            FileLen(100),
+            FileHash::from(0),
+            vec![
+                Path::from("/a/x.JPG"),
+                Path::from("/b/y.jpg"),
+                Path::from("/c/z"),
+            ],
+        )];
+        let stats = ext_stats(groups.iter());
+        assert_eq!(
+            stats,
+            vec![
+                ExtStats {
+                    ext: "jpg".to_owned(),
+                    file_count: 2,
+                    file_size: FileLen(200),
+                },
+                ExtStats {
+                    ext: "".to_owned(),
+                    file_count: 1,
+                    file_size: FileLen(100),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_owner_stats() {
+        use std::os::unix::fs::MetadataExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let files: Vec<_> = ["a", "b", "c"]
+            .iter()
+            .map(|name| {
+                let path = dir.path().join(name);
+                std::fs::write(&path, b"0123456789").unwrap();
+                Path::from(path)
+            })
+            .collect();
+        let uid = std::fs::metadata(dir.path()).unwrap().uid();
+        let groups = [FileGroup {
+            file_len: FileLen(10),
+            file_hash: FileHash::from(0),
+            hashes: Digests::new(),
+            files,
+            truncated_from: None,
+            annotations: Annotations::new(),
+        }];
+        let stats = owner_stats(&groups, |f| owner::file_owner(f).ok());
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].uid, uid);
+        assert_eq!(stats[0].file_count, 3);
+        assert_eq!(stats[0].file_size, FileLen(30));
+        assert_eq!(stats[0].redundant_file_size, FileLen(20));
+    }
+
+    #[test]
+    fn test_histograms() {
+        let group = |len: u64, count: usize| {
+            FileGroup::new(
+                FileLen(len),
+                FileHash::from(len as u128),
+                (0..count).map(|i| Path::from(format!("/{}", i))).collect(),
+            )
+        };
+        let groups = [group(10, 2), group(20, 5), group(5000, 2)];
+        assert_eq!(
+            size_histogram(groups.iter(), 1),
+            vec![
+                HistogramBucket {
+                    min: 0,
+                    max: Some(999),
+                    group_count: 2,
+                    redundant_file_count: 5,
+                    redundant_file_size: FileLen(90),
+                },
+                HistogramBucket {
+                    min: 1000,
+                    max: Some(9999),
+                    group_count: 1,
+                    redundant_file_count: 1,
+                    redundant_file_size: FileLen(5000),
+                },
+            ]
+        );
+        assert_eq!(
+            copies_histogram(groups.iter(), 1),
+            vec![
+                HistogramBucket {
+                    min: 2,
+                    max: Some(2),
+                    group_count: 2,
+                    redundant_file_count: 2,
+                    redundant_file_size: FileLen(5010),
+                },
+                HistogramBucket {
+                    min: 4,
+                    max: Some(7),
+                    group_count: 1,
+                    redundant_file_count: 4,
+                    redundant_file_size: FileLen(80),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_summary_report_has_header_only() {
+        let mut header = dummy_report_header();
+        header.ext_stats = vec![
+            ExtStats {
+                ext: "mkv".to_owned(),
+                file_count: 2,
+                file_size: FileLen(200),
+            },
+            ExtStats {
+                ext: "".to_owned(),
+                file_count: 1,
+                file_size: FileLen(100),
+            },
+        ];
+        let groups = [FileGroup::new(
+            FileLen(100),
+            FileHash::from(0x00112233445566778899aabbccddeeff),
+            vec![Path::from("/a.mkv"), Path::from("/b.mkv"), Path::from("/c")],
+        )];
+        header.size_histogram = size_histogram(&groups, 1);
+        header.owner_stats = vec![OwnerStats {
+            uid: 1000,
+            owner: "alice".to_owned(),
+            file_count: 3,
+            file_size: FileLen(300),
+            redundant_file_size: FileLen(200),
+        }];
+        header.copies_histogram = vec![HistogramBucket {
+            min: 64,
+            max: None,
+            group_count: 1,
+            redundant_file_count: 99,
+            redundant_file_size: FileLen(9900),
+        }];
+
+        let mut output = NamedTempFile::new().unwrap();
+        let input = output.reopen().unwrap();
+        let mut writer = ReportWriter::new(output.as_file_mut(), false);
+        writer
+            .write(OutputFormat::Summary, &header, groups.iter())
+            .unwrap();
+
+        let mut reader = open_report(input).unwrap();
+        assert_eq!(reader.read_header().unwrap(), header);
+        let groups2: Vec<_> = reader.read_groups().unwrap().collect().unwrap();
+        assert!(groups2.is_empty());
+    }
+
+    #[test]
+    fn test_root_index_matches_whole_components() {
+        let roots = vec!["/a".to_owned(), "/a/b".to_owned(), "/".to_owned()];
+        assert_eq!(root_index("/a/x", &roots), Some(0));
+        assert_eq!(root_index("/a/b/x", &roots), Some(1));
+        assert_eq!(root_index("/ab/x", &roots), Some(2));
+        assert_eq!(root_index("x", &roots), None);
+    }
+
     fn write_read_header(header: &ReportHeader, format: OutputFormat) -> ReportHeader {
         let groups: Vec<FileGroup<Path>> = vec![];
         let output = NamedTempFile::new().unwrap();
@@ -773,12 +3576,116 @@ mod test {
         reader.read_header().unwrap()
     }
 
+    #[test]
+    fn test_host_metadata_in_header() {
+        let header = ReportHeader {
+            host: Some(HostMetadata {
+                hostname: "backup-01".to_owned(),
+                os: "Debian GNU/Linux 12 (bookworm)".to_owned(),
+                arch: "x86_64".to_owned(),
+                root_file_systems: vec![
+                    RootFileSystem {
+                        root: "/srv/data".to_owned(),
+                        file_system: "ext4".to_owned(),
+                    },
+                    RootFileSystem {
+                        root: "/mnt/nas: old".to_owned(),
+                        file_system: "nfs4".to_owned(),
+                    },
+                ],
+                features: vec!["async".to_owned(), "ffi".to_owned()],
+            }),
+            errors: vec!["Failed to read /srv/data/x".to_owned()],
+            ..dummy_report_header()
+        };
+        for format in [
+            OutputFormat::Default,
+            OutputFormat::TextV2,
+            OutputFormat::Json,
+        ] {
+            assert_eq!(write_read_header(&header, format), header, "{}", format);
+        }
+
+        let mut header = header;
+        header.host.as_mut().unwrap().features.clear();
+        header.host.as_mut().unwrap().root_file_systems.clear();
+        assert_eq!(write_read_header(&header, OutputFormat::Default), header);
+    }
+
+    #[test]
+    fn test_empty_host_metadata_in_header() {
+        let header = ReportHeader {
+            host: Some(HostMetadata {
+                hostname: "".to_owned(),
+                os: "".to_owned(),
+                arch: "".to_owned(),
+                root_file_systems: vec![
+                    RootFileSystem {
+                        root: "/dev/shm/x".to_owned(),
+                        file_system: "".to_owned(),
+                    },
+                    RootFileSystem {
+                        root: "/mnt/nas: old".to_owned(),
+                        file_system: "".to_owned(),
+                    },
+                ],
+                features: vec![],
+            }),
+            ..dummy_report_header()
+        };
+        for format in [OutputFormat::Default, OutputFormat::TextV2] {
+            assert_eq!(write_read_header(&header, format), header, "{}", format);
+        }
+    }
+
     #[test]
     fn test_format_autodetection() {
         let header = dummy_report_header();
         let reread_header_1 = write_read_header(&header, OutputFormat::Default);
         let reread_header_2 = write_read_header(&header, OutputFormat::Json);
+        let reread_header_3 = write_read_header(&header, OutputFormat::TextV2);
         assert_eq!(header, reread_header_1);
         assert_eq!(header, reread_header_2);
+        assert_eq!(header, reread_header_3);
+    }
+
+    #[test]
+    fn test_text_v2_report_escapes_paths_and_verifies_checksum() {
+        let header = dummy_report_header();
+        let groups = vec![FileGroup {
+            file_len: FileLen(100),
+            file_hash: FileHash::from(0x00112233445566778899aabbccddeeff),
+            hashes: Digests::new(),
+            files: vec![
+                Path::from("/dir/a\tb"),
+                Path::from("/dir/c\nd, 10 B * 2:"),
+                Path::from("/dir/ e\\x41 "),
+                Path::from("    # not a comment"),
+            ],
+            truncated_from: Some(7),
+            annotations: Annotations::new(),
+        }];
+        let mut output = Vec::new();
+        ReportWriter::new(&mut output, false)
+            .write(OutputFormat::TextV2, &header, groups.iter())
+            .unwrap();
+        let report = String::from_utf8(output).unwrap();
+        assert!(report.contains("\t/dir/a\\tb\n"));
+        assert!(report.contains("\t/dir/ e\\\\x41 \n"));
+
+        let read = |report: String| -> io::Result<Vec<FileGroup<Path>>> {
+            let mut reader = open_report(std::io::Cursor::new(report))?;
+            reader.read_header()?;
+            reader.read_groups()?.collect()
+        };
+        assert_eq!(read(report.clone()).unwrap(), groups);
+
+        let edited = report.replace("/dir/a", "/dir/x");
+        assert_eq!(read(edited).unwrap_err().kind(), ErrorKind::InvalidData);
+        let truncated = report[..report.find("[end]").unwrap()].to_owned();
+        assert_eq!(
+            read(truncated).unwrap_err().kind(),
+            ErrorKind::UnexpectedEof
+        );
     }
 }
@@ -9,12 +9,18 @@ use crate::pattern::Pattern;
 /// A path is selected only if it matches at least one include pattern
 /// and doesn't match any exclude patterns.
 /// An empty include pattern vector matches all paths.
+/// Files can be also selected by their extensions, matched case-insensitively.
 #[derive(Debug, Clone)]
 pub struct PathSelector {
     base_dir: Arc<Path>,
     included_names: Vec<Pattern>,
     included_paths: Vec<Pattern>,
     excluded_paths: Vec<Pattern>,
+    /// Lowercase file name suffixes, including the leading dot, e.g. `.jpg`
+    included_extensions: Vec<String>,
+    excluded_extensions: Vec<String>,
+    /// Patterns of the names of directories not to descend into
+    excluded_dir_names: Vec<Pattern>,
 }
 
 impl PathSelector {
@@ -25,14 +31,55 @@ impl PathSelector {
             included_names: vec![],
             included_paths: vec![],
             excluded_paths: vec![],
+            included_extensions: vec![],
+            excluded_extensions: vec![],
+            excluded_dir_names: vec![],
         }
     }
 
+    /// Converts extensions given by the user, e.g. `JPG`, `.jpg` or `*.jpg`,
+    /// to the lowercase suffixes they match
+    fn extension_suffixes(extensions: Vec<String>) -> Vec<String> {
+        extensions
+            .into_iter()
+            .map(|e| {
+                e.trim()
+                    .trim_start_matches('*')
+                    .trim_start_matches('.')
+                    .to_lowercase()
+            })
+            .filter(|e| !e.is_empty())
+            .map(|e| format!(".{}", e))
+            .collect()
+    }
+
+    /// Returns true if the file name ends with any of the suffixes, preceded by a nonempty stem
+    fn has_extension(name: &str, suffixes: &[String]) -> bool {
+        suffixes
+            .iter()
+            .any(|s| name.len() > s.len() && name.ends_with(s.as_str()))
+    }
+
     pub fn include_names(mut self, pat: Vec<Pattern>) -> PathSelector {
         self.included_names = pat;
         self
     }
 
+    pub fn include_extensions(mut self, extensions: Vec<String>) -> PathSelector {
+        self.included_extensions = Self::extension_suffixes(extensions);
+        self
+    }
+
+    pub fn exclude_extensions(mut self, extensions: Vec<String>) -> PathSelector {
+        self.excluded_extensions = Self::extension_suffixes(extensions);
+        self
+    }
+
+    pub fn exclude_dir_names(mut self, pat: Vec<Pattern>) -> PathSelector {
+        self.excluded_dir_names = pat;
+        self
+    }
+
     pub fn include_paths(mut self, pat: Vec<Pattern>) -> PathSelector {
         self.included_paths = pat
             .into_iter()
@@ -58,13 +105,40 @@ impl PathSelector {
                 .unwrap_or_default();
             let name = name.as_ref();
             let path = path.to_string_lossy();
-            (self.included_names.is_empty() || self.included_names.iter().any(|p| p.matches(name)))
+            self.matches_extension(name)
+                && (self.included_names.is_empty()
+                    || self.included_names.iter().any(|p| p.matches(name)))
                 && (self.included_paths.is_empty()
                     || self.included_paths.iter().any(|p| p.matches(&path)))
                 && self.excluded_paths.iter().all(|p| !p.matches(&path))
         })
     }
 
+    /// Returns true if the file name has one of the included extensions, if any,
+    /// and none of the excluded ones
+    fn matches_extension(&self, name: &str) -> bool {
+        if self.included_extensions.is_empty() && self.excluded_extensions.is_empty() {
+            return true;
+        }
+        let name = name.to_lowercase();
+        (self.included_extensions.is_empty()
+            || Self::has_extension(&name, &self.included_extensions))
+            && !Self::has_extension(&name, &self.excluded_extensions)
+    }
+
+    /// Returns true if the name of the directory matches any of the excluded directory names,
+    /// so the directory shouldn't be walked at all
+    pub fn excludes_dir_name(&self, path: &Path) -> bool {
+        if self.excluded_dir_names.is_empty() {
+            return false;
+        }
+        let name = path
+            .file_name_cstr()
+            .map(|s| s.to_string_lossy().to_string())
+            .unwrap_or_default();
+        self.excluded_dir_names.iter().any(|p| p.matches(&name))
+    }
+
     /// Returns true if the given directory may contain matching paths.
     /// Used to decide whether the directory walk should descend to that directory.
     /// The directory should be allowed only if:
@@ -199,6 +273,29 @@ mod test {
         assert!(!selector.matches_full_path(&Path::from("/test/bar")));
     }
 
+    #[test]
+    fn extensions() {
+        let selector = PathSelector::new(Path::from("/"))
+            .include_extensions(vec![
+                "jpg".to_owned(),
+                ".PNG".to_owned(),
+                "tar.gz".to_owned(),
+            ])
+            .exclude_extensions(vec!["*.part.jpg".to_owned()]);
+
+        // matching:
+        assert!(selector.matches_full_path(&Path::from("/foo/a.jpg")));
+        assert!(selector.matches_full_path(&Path::from("/foo/a.JPG")));
+        assert!(selector.matches_full_path(&Path::from("/foo/a.png")));
+        assert!(selector.matches_full_path(&Path::from("/foo/a.tar.gz")));
+
+        // not matching:
+        assert!(!selector.matches_full_path(&Path::from("/foo/a.gz")));
+        assert!(!selector.matches_full_path(&Path::from("/foo/ajpg")));
+        assert!(!selector.matches_full_path(&Path::from("/foo/.jpg")));
+        assert!(!selector.matches_full_path(&Path::from("/foo/a.part.jpg")));
+    }
+
     #[test]
     fn prefix_wildcard() {
         let selector = PathSelector::new(Path::from("/"))
@@ -0,0 +1,179 @@
+//! Recovering from `SIGBUS` raised when a memory-mapped file shrinks while being read.
+//!
+//! Reading a page of a mapped file past its current end raises `SIGBUS`, which kills
+//! the process by default. Another process may truncate any file we're hashing, so
+//! before reading a mapping, the reader registers its address range here.
+//! If a page of a registered range faults, the signal handler replaces the page with
+//! a page of zeros and marks the range as faulted, so the read completes and the reader
+//! can discard its result. Faults outside of the registered ranges are passed
+//! to the previous handler.
+
+use std::mem::MaybeUninit;
+use std::os::raw::c_int;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Once;
+
+use lazy_static::lazy_static;
+
+/// Maximum number of mappings read at the same time.
+/// Readers which can't register their mapping must read the file the regular way.
+const MAX_REGIONS: usize = 256;
+
+/// Marks a slot being filled in by `register`
+const RESERVED: usize = usize::MAX;
+
+struct Region {
+    start: AtomicUsize,
+    /// Zero if the slot is free
+    len: AtomicUsize,
+    faulted: AtomicBool,
+}
+
+lazy_static! {
+    static ref REGIONS: Vec<Region> = (0..MAX_REGIONS)
+        .map(|_| Region {
+            start: AtomicUsize::new(0),
+            len: AtomicUsize::new(0),
+            faulted: AtomicBool::new(false),
+        })
+        .collect();
+}
+
+static INSTALL: Once = Once::new();
+static PAGE_SIZE: AtomicUsize = AtomicUsize::new(4096);
+static mut PREVIOUS: MaybeUninit<libc::sigaction> = MaybeUninit::uninit();
+
+/// Unregisters the address range when dropped
+pub(crate) struct RegionGuard {
+    slot: usize,
+}
+
+impl RegionGuard {
+    /// Returns true if any page of the range was past the end of the file when read
+    pub fn faulted(&self) -> bool {
+        REGIONS[self.slot].faulted.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for RegionGuard {
+    fn drop(&mut self) {
+        REGIONS[self.slot].len.store(0, Ordering::SeqCst);
+    }
+}
+
+/// Protects reads of a mapped address range from `SIGBUS`.
+/// Returns `None` if too many ranges are registered already or the handler
+/// couldn't be installed.
+pub(crate) fn register(start: *const u8, len: usize) -> Option<RegionGuard> {
+    if !install() {
+        return None;
+    }
+    let regions = &*REGIONS;
+    for (slot, r) in regions.iter().enumerate() {
+        if r.len
+            .compare_exchange(0, RESERVED, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            r.start.store(start as usize, Ordering::SeqCst);
+            r.faulted.store(false, Ordering::SeqCst);
+            r.len.store(len, Ordering::SeqCst);
+            return Some(RegionGuard { slot });
+        }
+    }
+    None
+}
+
+/// Installs the signal handler once.
+/// Returns false if it couldn't be installed.
+fn install() -> bool {
+    static INSTALLED: AtomicBool = AtomicBool::new(false);
+    INSTALL.call_once(|| unsafe {
+        lazy_static::initialize(&REGIONS);
+        let page_size = libc::sysconf(libc::_SC_PAGESIZE);
+        if page_size > 0 {
+            PAGE_SIZE.store(page_size as usize, Ordering::SeqCst);
+        }
+        let mut action: libc::sigaction = std::mem::zeroed();
+        let handler: Handler = handle_sigbus;
+        action.sa_sigaction = handler as usize;
+        action.sa_flags = libc::SA_SIGINFO | libc::SA_NODEFER;
+        libc::sigemptyset(&mut action.sa_mask);
+        let installed = libc::sigaction(
+            libc::SIGBUS,
+            &action,
+            ptr::addr_of_mut!(PREVIOUS) as *mut libc::sigaction,
+        ) == 0;
+        INSTALLED.store(installed, Ordering::SeqCst);
+    });
+    INSTALLED.load(Ordering::SeqCst)
+}
+
+type Handler = extern "C" fn(c_int, *mut libc::siginfo_t, *mut libc::c_void);
+
+extern "C" fn handle_sigbus(_: c_int, info: *mut libc::siginfo_t, _: *mut libc::c_void) {
+    unsafe {
+        let addr = (*info).si_addr() as usize;
+        for r in REGIONS.iter() {
+            let len = r.len.load(Ordering::SeqCst);
+            if len == 0 || len == RESERVED {
+                continue;
+            }
+            let start = r.start.load(Ordering::SeqCst);
+            if addr < start || addr - start >= len {
+                continue;
+            }
+            let page_size = PAGE_SIZE.load(Ordering::SeqCst);
+            let page = addr & !(page_size - 1);
+            let zeros = libc::mmap(
+                page as *mut libc::c_void,
+                page_size,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED,
+                -1,
+                0,
+            );
+            if zeros != libc::MAP_FAILED {
+                r.faulted.store(true, Ordering::SeqCst);
+                return;
+            }
+        }
+        // Not our fault. Restore the previous handler, so the faulting instruction,
+        // executed again on return, raises the signal handled the previous way.
+        libc::sigaction(
+            libc::SIGBUS,
+            ptr::addr_of!(PREVIOUS) as *const libc::sigaction,
+            ptr::null_mut(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use super::*;
+    use crate::util::test::with_dir;
+
+    #[test]
+    fn read_of_truncated_mapping_is_marked_as_faulted() {
+        with_dir("target/test/sigbus/", |root| {
+            let path = root.join("file");
+            let mut file = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&path)
+                .unwrap();
+            file.write_all(&vec![1; 64 * 1024]).unwrap();
+            let mmap = unsafe { memmap2::Mmap::map(&file).unwrap() };
+            let guard = register(mmap.as_ptr(), mmap.len()).unwrap();
+            file.set_len(0).unwrap();
+            let sum: u64 = mmap.iter().map(|&b| b as u64).sum();
+            assert_eq!(sum, 0);
+            assert!(guard.faulted());
+        });
+    }
+}
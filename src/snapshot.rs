@@ -0,0 +1,61 @@
+//! Recognizing read-only copies of directory trees made by file systems with snapshot support.
+//!
+//! Files in a snapshot share their data with the live files, so removing them doesn't
+//! reclaim any space, and they are often not writable at all. Scanning the snapshots together
+//! with the live tree would report every file as duplicated, so they are skipped by default,
+//! unless `--include-snapshots` is given.
+
+use std::path::Path;
+
+/// Returns true if the directory is the `.zfs/snapshot` directory listing ZFS snapshots
+/// of a dataset. It is visible only if the `snapdir` property of the dataset is set to `visible`.
+pub fn is_zfs_snapshot_dir(dir: &Path) -> bool {
+    dir.ends_with(".zfs/snapshot")
+}
+
+/// Returns true if the directory opened as `fd`, with inode number `ino`, is the root
+/// of a btrfs subvolume created as a snapshot of another subvolume, or received with
+/// `btrfs receive`. Checking the inode number known from reading the directory first
+/// avoids the system calls for directories which can't be subvolumes.
+#[cfg(target_os = "linux")]
+pub(crate) fn is_btrfs_snapshot_fd(fd: std::os::unix::io::RawFd, ino: u64) -> bool {
+    /// Inode number of the root directory of every btrfs subvolume
+    const BTRFS_FIRST_FREE_OBJECTID: u64 = 256;
+    const BTRFS_SUPER_MAGIC: u32 = 0x9123_683E;
+    /// `_IOR(0x94, 60, struct btrfs_ioctl_get_subvol_info_args)`, available since Linux 4.18
+    /// to unprivileged users
+    const BTRFS_IOC_GET_SUBVOL_INFO: u32 = 0x81F8_943C;
+    /// Size of `struct btrfs_ioctl_get_subvol_info_args`
+    const SUBVOL_INFO_LEN: usize = 504;
+    /// Offsets of `parent_uuid` and `received_uuid` in `struct btrfs_ioctl_get_subvol_info_args`
+    const PARENT_UUID_OFFSET: usize = 312;
+    const RECEIVED_UUID_OFFSET: usize = 328;
+    const UUID_LEN: usize = 16;
+
+    if ino != BTRFS_FIRST_FREE_OBJECTID {
+        return false;
+    }
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::fstatfs(fd, &mut stat) } != 0 || stat.f_type as u32 != BTRFS_SUPER_MAGIC {
+        return false;
+    }
+
+    let mut info = [0u8; SUBVOL_INFO_LEN];
+    if unsafe { libc::ioctl(fd, BTRFS_IOC_GET_SUBVOL_INFO as _, info.as_mut_ptr()) } != 0 {
+        return false;
+    }
+    let is_set = |offset: usize| info[offset..offset + UUID_LEN].iter().any(|&b| b != 0);
+    is_set(PARENT_UUID_OFFSET) || is_set(RECEIVED_UUID_OFFSET)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn recognize_zfs_snapshot_dir() {
+        assert!(is_zfs_snapshot_dir(Path::new("/tank/home/.zfs/snapshot")));
+        assert!(!is_zfs_snapshot_dir(Path::new("/tank/home/.zfs")));
+        assert!(!is_zfs_snapshot_dir(Path::new("/tank/home/snapshot")));
+    }
+}
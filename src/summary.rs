@@ -0,0 +1,295 @@
+//! Summary of the work done by `group` and deduplication commands.
+
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::dedupe::DedupeResult;
+use crate::files::FileLen;
+use crate::log::{EventKind, Log};
+use crate::systemd;
+
+/// Numbers of errors and warnings logged during a run, by category
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EventCounts {
+    /// Errors that stopped the program
+    pub errors: usize,
+    /// Warnings not related to a particular file
+    pub warnings: usize,
+    /// Files or directories that couldn't be read
+    pub unreadable: usize,
+    /// Files that changed since they were scanned or reported
+    pub changed: usize,
+    /// Files left out by the selection criteria
+    pub filtered: usize,
+}
+
+impl EventCounts {
+    /// Returns the numbers of events logged so far
+    fn read(log: &Log) -> EventCounts {
+        EventCounts {
+            errors: log.event_count(EventKind::Error),
+            warnings: log.event_count(EventKind::Warning),
+            unreadable: log.event_count(EventKind::Unreadable),
+            changed: log.event_count(EventKind::Changed),
+            filtered: log.event_count(EventKind::Filtered),
+        }
+    }
+
+    /// Returns the numbers of events logged since `start` was read
+    fn since(start: &EventCounts, log: &Log) -> EventCounts {
+        let now = EventCounts::read(log);
+        EventCounts {
+            errors: now.errors - start.errors,
+            warnings: now.warnings - start.warnings,
+            unreadable: now.unreadable - start.unreadable,
+            changed: now.changed - start.changed,
+            filtered: now.filtered - start.filtered,
+        }
+    }
+}
+
+impl Display for EventCounts {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} errors, {} warnings, {} unreadable, {} changed, {} filtered",
+            self.errors, self.warnings, self.unreadable, self.changed, self.filtered
+        )
+    }
+}
+
+/// Describes the outcome of a single processing stage
+#[derive(Clone, Debug)]
+pub struct StageSummary {
+    /// Short name of the stage, e.g. "prefix"
+    pub name: &'static str,
+    /// Wall clock time spent in this stage
+    pub duration: Duration,
+    /// Number of files left after the stage
+    pub file_count: usize,
+    /// Total size of files left after the stage
+    pub file_size: FileLen,
+    /// Number of warnings and errors reported during this stage
+    pub error_count: usize,
+}
+
+/// Counts of files processed or skipped at each stage, errors and stage timings
+#[derive(Clone, Debug, Default)]
+pub struct GroupSummary {
+    /// Number of entries visited by the directory walker
+    pub scanned_entries: usize,
+    /// Number of regular files found by the directory walker, before applying the file filters
+    pub scanned_files: usize,
+    /// Number of hidden files and directories skipped because of `--skip-hidden`
+    pub skipped_hidden: usize,
+    /// Number of files and directories skipped by the name and path patterns
+    pub skipped_by_name: usize,
+    /// Number of trash, cache and snapshot directories skipped
+    pub skipped_dirs: usize,
+    /// Number of files skipped because of `--min-size` or `--max-size`
+    pub skipped_by_size: usize,
+    /// Number of files skipped because of the modification or creation time filters
+    pub skipped_by_time: usize,
+    /// Number of FIFOs, sockets and device nodes skipped by the directory walker
+    pub skipped_special_files: usize,
+    /// Number of symbolic links to regular files skipped by the directory walker
+    pub skipped_file_links: usize,
+    /// Number of files skipped because of `--quarantine`
+    pub skipped_quarantined: usize,
+    /// Number of bytes read and hashed
+    pub bytes_hashed: FileLen,
+    /// Summaries of the stages in the order of execution
+    pub stages: Vec<StageSummary>,
+    /// Numbers of errors and warnings by category
+    pub events: EventCounts,
+}
+
+/// Marks the start of a grouping run, so the numbers of events can be computed later
+pub(crate) struct GroupStart {
+    events: EventCounts,
+}
+
+/// Marks the start of a stage, so the stage duration and error count can be computed later
+pub(crate) struct StageStart {
+    name: &'static str,
+    start: Instant,
+    error_count: usize,
+}
+
+impl GroupSummary {
+    pub(crate) fn start(log: &Log) -> GroupStart {
+        GroupStart {
+            events: EventCounts::read(log),
+        }
+    }
+
+    pub(crate) fn finish(&mut self, start: GroupStart, log: &Log) {
+        self.events = EventCounts::since(&start.events, log);
+    }
+
+    pub(crate) fn start_stage(name: &'static str, log: &Log) -> StageStart {
+        systemd::set_status(format!("Grouping files, stage: {}", name));
+        StageStart {
+            name,
+            start: Instant::now(),
+            error_count: log.warning_count(),
+        }
+    }
+
+    pub(crate) fn end_stage(
+        &mut self,
+        stage: StageStart,
+        log: &Log,
+        file_count: usize,
+        file_size: FileLen,
+    ) {
+        self.stages.push(StageSummary {
+            name: stage.name,
+            duration: stage.start.elapsed(),
+            file_count,
+            file_size,
+            error_count: log.warning_count() - stage.error_count,
+        })
+    }
+
+    /// Returns the total time of all stages
+    pub fn total_duration(&self) -> Duration {
+        self.stages.iter().map(|s| s.duration).sum()
+    }
+
+    /// Returns the total number of warnings and errors
+    pub fn error_count(&self) -> usize {
+        self.stages.iter().map(|s| s.error_count).sum()
+    }
+}
+
+impl Display for GroupSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Scanned entries: {}", self.scanned_entries)?;
+        writeln!(f, "Scanned files: {}", self.scanned_files)?;
+        writeln!(f, "Skipped hidden: {}", self.skipped_hidden)?;
+        writeln!(f, "Skipped by name: {}", self.skipped_by_name)?;
+        writeln!(f, "Skipped trash and snapshots: {}", self.skipped_dirs)?;
+        writeln!(f, "Skipped by size: {}", self.skipped_by_size)?;
+        writeln!(f, "Skipped by time: {}", self.skipped_by_time)?;
+        writeln!(f, "Skipped special files: {}", self.skipped_special_files)?;
+        writeln!(f, "Skipped links to files: {}", self.skipped_file_links)?;
+        writeln!(f, "Skipped quarantined: {}", self.skipped_quarantined)?;
+        writeln!(f, "Bytes hashed: {}", self.bytes_hashed)?;
+        writeln!(f, "Events: {}", self.events)?;
+        for s in self.stages.iter() {
+            writeln!(
+                f,
+                "Stage {}: {} files ({}), {} errors, {:.3} s",
+                s.name,
+                s.file_count,
+                s.file_size,
+                s.error_count,
+                s.duration.as_secs_f64()
+            )?;
+        }
+        write!(
+            f,
+            "Total: {} errors, {:.3} s",
+            self.error_count(),
+            self.total_duration().as_secs_f64()
+        )
+    }
+}
+
+/// Counters updated concurrently while deduplicating, see [`DedupeSummary`]
+#[derive(Debug, Default)]
+pub struct DedupeCounters {
+    file_count: AtomicUsize,
+    bytes_hashed: AtomicU64,
+    not_permitted_links: AtomicUsize,
+}
+
+impl DedupeCounters {
+    pub(crate) fn add_files(&self, count: usize) {
+        self.file_count.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_hashed_bytes(&self, delta: usize) {
+        self.bytes_hashed.fetch_add(delta as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_not_permitted_link(&self) {
+        self.not_permitted_links.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn not_permitted_link_count(&self) -> usize {
+        self.not_permitted_links.load(Ordering::Relaxed)
+    }
+}
+
+/// Counts of files read from the report, processed or failed, errors and the run time
+/// of a deduplication run.
+/// Planning and executing the commands overlap, so only the total time is measured.
+#[derive(Clone, Debug, Default)]
+pub struct DedupeSummary {
+    /// Number of files read from the report
+    pub file_count: usize,
+    /// Number of bytes read and hashed to verify the files haven't changed
+    pub bytes_hashed: FileLen,
+    /// Number of files removed, moved or linked
+    pub processed_count: u64,
+    /// Number of commands that failed
+    pub failed_count: u64,
+    /// Number of files not linked, because `fs.protected_hardlinks` doesn't permit
+    /// linking to the files of other users
+    pub not_permitted_links: usize,
+    /// Disk space reclaimed
+    pub reclaimed_space: FileLen,
+    /// Numbers of errors and warnings by category
+    pub events: EventCounts,
+    /// Wall clock time of the run
+    pub duration: Duration,
+}
+
+/// Marks the start of a deduplication run.
+/// The counters must be passed to the deduplication in `DedupeConfig::counters`.
+pub struct DedupeStart {
+    pub counters: Arc<DedupeCounters>,
+    start: Instant,
+    events: EventCounts,
+}
+
+impl DedupeSummary {
+    pub fn start(log: &Log) -> DedupeStart {
+        DedupeStart {
+            counters: Arc::new(DedupeCounters::default()),
+            start: Instant::now(),
+            events: EventCounts::read(log),
+        }
+    }
+
+    pub fn finish(start: DedupeStart, result: &DedupeResult, log: &Log) -> DedupeSummary {
+        DedupeSummary {
+            file_count: start.counters.file_count.load(Ordering::Relaxed),
+            bytes_hashed: FileLen(start.counters.bytes_hashed.load(Ordering::Relaxed)),
+            processed_count: result.processed_count,
+            failed_count: result.failed_count,
+            not_permitted_links: start.counters.not_permitted_link_count(),
+            reclaimed_space: result.reclaimed_space,
+            events: EventCounts::since(&start.events, log),
+            duration: start.start.elapsed(),
+        }
+    }
+}
+
+impl Display for DedupeSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Files in report: {}", self.file_count)?;
+        writeln!(f, "Bytes hashed: {}", self.bytes_hashed)?;
+        writeln!(f, "Processed files: {}", self.processed_count)?;
+        writeln!(f, "Failed files: {}", self.failed_count)?;
+        writeln!(f, "Links not permitted: {}", self.not_permitted_links)?;
+        writeln!(f, "Reclaimed space: {}", self.reclaimed_space)?;
+        writeln!(f, "Events: {}", self.events)?;
+        write!(f, "Total: {:.3} s", self.duration.as_secs_f64())
+    }
+}
@@ -0,0 +1,194 @@
+//! Writing warnings, errors and the outcome of a run to the system log.
+//!
+//! On Unix the messages are sent to syslog with the `user` facility, tagged with `fclones`
+//! and the process id. On Windows they are written to the Application event log
+//! with `fclones` as the source. This lets the scheduled runs on many machines be monitored
+//! by the tools already collecting the system logs.
+
+use std::fmt::Display;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Severity of a message sent to the system log
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Notice,
+}
+
+/// Opens the system log. Until this is called, [`send`] does nothing.
+pub fn enable() -> io::Result<()> {
+    sys::open()?;
+    ENABLED.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Returns true if the messages are sent to the system log
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+/// Sends the message to the system log, if enabled by [`enable`]
+pub fn send(severity: Severity, msg: impl Display) {
+    if is_enabled() {
+        sys::send(severity, &clean(msg));
+    }
+}
+
+/// Neither syslog nor the event log accept NUL characters in the messages
+fn clean(msg: impl Display) -> String {
+    msg.to_string().replace('\0', " ")
+}
+
+/// Returns the severity and the message logged when the run of `command` finishes
+/// after `duration` seconds, failed with `error` if given
+pub fn summary(
+    command: &str,
+    duration: f64,
+    warnings: usize,
+    error: Option<&str>,
+) -> (Severity, String) {
+    match error {
+        None => (
+            Severity::Notice,
+            format!(
+                "Finished {} in {:.3} s with {} warnings",
+                command, duration, warnings
+            ),
+        ),
+        Some(e) => (
+            Severity::Error,
+            format!(
+                "Failed {} after {:.3} s with {} warnings: {}",
+                command, duration, warnings, e
+            ),
+        ),
+    }
+}
+
+#[cfg(unix)]
+mod sys {
+    use std::ffi::CString;
+    use std::io;
+    use std::os::raw::c_char;
+
+    use super::Severity;
+
+    /// Must live until the log is closed, so it is static
+    const IDENT: &[u8] = b"fclones\0";
+    const FORMAT: &[u8] = b"%s\0";
+
+    pub fn open() -> io::Result<()> {
+        unsafe {
+            libc::openlog(
+                IDENT.as_ptr() as *const c_char,
+                libc::LOG_PID | libc::LOG_NDELAY,
+                libc::LOG_USER,
+            )
+        };
+        Ok(())
+    }
+
+    pub fn send(severity: Severity, msg: &str) {
+        let priority = match severity {
+            Severity::Error => libc::LOG_ERR,
+            Severity::Warning => libc::LOG_WARNING,
+            Severity::Notice => libc::LOG_NOTICE,
+        };
+        let msg = CString::new(msg).unwrap_or_default();
+        unsafe { libc::syslog(priority, FORMAT.as_ptr() as *const c_char, msg.as_ptr()) };
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    use std::ffi::OsStr;
+    use std::io;
+    use std::os::windows::ffi::OsStrExt;
+    use std::ptr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use winapi::um::winbase::{RegisterEventSourceW, ReportEventW};
+    use winapi::um::winnt::{
+        EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE, EVENTLOG_WARNING_TYPE, HANDLE,
+    };
+
+    use super::Severity;
+
+    /// Handle of the event source, kept open until the process exits
+    static SOURCE: AtomicUsize = AtomicUsize::new(0);
+
+    fn to_wide(s: &str) -> Vec<u16> {
+        OsStr::new(s).encode_wide().chain(Some(0)).collect()
+    }
+
+    pub fn open() -> io::Result<()> {
+        let name = to_wide("fclones");
+        let handle = unsafe { RegisterEventSourceW(ptr::null(), name.as_ptr()) };
+        if handle.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        SOURCE.store(handle as usize, Ordering::Release);
+        Ok(())
+    }
+
+    pub fn send(severity: Severity, msg: &str) {
+        let handle = SOURCE.load(Ordering::Acquire) as HANDLE;
+        if handle.is_null() {
+            return;
+        }
+        let event_type = match severity {
+            Severity::Error => EVENTLOG_ERROR_TYPE,
+            Severity::Warning => EVENTLOG_WARNING_TYPE,
+            Severity::Notice => EVENTLOG_INFORMATION_TYPE,
+        };
+        let msg = to_wide(msg);
+        let mut strings = [msg.as_ptr()];
+        unsafe {
+            ReportEventW(
+                handle,
+                event_type,
+                0,
+                0,
+                ptr::null_mut(),
+                1,
+                0,
+                strings.as_mut_ptr(),
+                ptr::null_mut(),
+            )
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn summary_has_severity_of_outcome() {
+        let (severity, msg) = summary("fclones remove", 1.5, 2, None);
+        assert_eq!(severity, Severity::Notice);
+        assert_eq!(msg, "Finished fclones remove in 1.500 s with 2 warnings");
+
+        let (severity, msg) = summary("fclones remove", 0.25, 0, Some("Input error"));
+        assert_eq!(severity, Severity::Error);
+        assert_eq!(
+            msg,
+            "Failed fclones remove after 0.250 s with 0 warnings: Input error"
+        );
+    }
+
+    #[test]
+    fn clean_replaces_nul_characters() {
+        assert_eq!(clean("a\0b"), "a b");
+    }
+
+    #[test]
+    fn send_does_nothing_until_enabled() {
+        assert!(!is_enabled());
+        send(Severity::Warning, "not sent");
+    }
+}
@@ -0,0 +1,224 @@
+//! Reporting the state of the process to systemd, as described in `sd_notify(3)`.
+//!
+//! When fclones runs as a systemd service of `Type=notify`, it tells systemd when it is
+//! ready and what it is doing, which is shown by `systemctl status`. If the service sets
+//! `WatchdogSec`, fclones pings the watchdog as long as it makes progress. A read that hangs,
+//! e.g. on an unresponsive NFS server, stops the pings, so systemd can restart the service.
+
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+
+use crate::fd;
+use crate::files::FileLen;
+use crate::progress;
+use crate::window;
+
+/// How often the status is refreshed if the watchdog is not enabled
+const STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Number of entries visited and bytes read or written, used for detecting that
+/// the process is stuck
+static ACTIVITY: AtomicU64 = AtomicU64::new(0);
+static BYTES: AtomicU64 = AtomicU64::new(0);
+/// Number of bytes the current stage is going to process, 0 if unknown
+static TOTAL: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    static ref NOTIFIER: Mutex<Option<Notifier>> = Mutex::new(None);
+    static ref STAGE: Mutex<String> = Mutex::new(String::new());
+    static ref STAGE_START: Mutex<Instant> = Mutex::new(Instant::now());
+}
+
+/// Connects to the socket given by systemd in `NOTIFY_SOCKET` and starts reporting
+/// the progress in the background. If the watchdog is enabled, starts pinging it.
+/// Returns false if the process was not started by systemd.
+pub fn enable() -> bool {
+    let notifier = match Notifier::from_env() {
+        Some(notifier) => notifier,
+        None => return false,
+    };
+    *NOTIFIER.lock().unwrap() = Some(notifier);
+    let watchdog = watchdog_interval();
+    let interval = watchdog.map_or(STATUS_INTERVAL, |w| w / 2);
+    thread::Builder::new()
+        .name("systemd-notify".to_owned())
+        .spawn(move || report_progress(interval, watchdog.is_some()))
+        .unwrap();
+    true
+}
+
+/// Tells systemd that the service finished starting up
+pub fn ready() {
+    notify("READY=1");
+}
+
+/// Tells systemd that the service is shutting down, with the final status
+pub fn stopping(status: impl Display) {
+    notify(&format!("STOPPING=1\nSTATUS={}", status));
+}
+
+/// Sets the status of the service shown by `systemctl status`.
+/// The amount of data processed is appended to the status while it is refreshed.
+pub fn set_status(status: impl Display) {
+    let status = status.to_string();
+    BYTES.store(0, Ordering::Relaxed);
+    TOTAL.store(0, Ordering::Relaxed);
+    *STAGE_START.lock().unwrap() = Instant::now();
+    notify(&format!("STATUS={}", status));
+    *STAGE.lock().unwrap() = status;
+}
+
+/// Sets the number of bytes the current stage is going to process,
+/// so the status can tell the estimated time left
+pub(crate) fn set_total(bytes: u64) {
+    BYTES.store(0, Ordering::Relaxed);
+    TOTAL.store(bytes, Ordering::Relaxed);
+    *STAGE_START.lock().unwrap() = Instant::now();
+}
+
+/// Records that `bytes` bytes of data were processed. Pass 0 for operations that don't
+/// transfer file data, like visiting a directory entry.
+pub(crate) fn tick(bytes: u64) {
+    ACTIVITY.fetch_add(1, Ordering::Relaxed);
+    if bytes > 0 {
+        BYTES.fetch_add(bytes, Ordering::Relaxed);
+    }
+}
+
+/// Returns the number of operations recorded by `tick`
+#[cfg(test)]
+pub(crate) fn activity() -> u64 {
+    ACTIVITY.load(Ordering::Relaxed)
+}
+
+fn notify(state: &str) {
+    if let Some(notifier) = NOTIFIER.lock().unwrap().as_ref() {
+        notifier.send(state);
+    }
+}
+
+/// Returns the interval in which systemd expects the watchdog pings sent to this process
+fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    Some(Duration::from_micros(usec)).filter(|d| *d > Duration::from_secs(0))
+}
+
+/// Periodically refreshes the status and, if `watchdog` is set, pings the watchdog unless
+/// the process is waiting for an I/O operation that made no progress since the last ping
+fn report_progress(interval: Duration, watchdog: bool) {
+    let mut last_activity = ACTIVITY.load(Ordering::Relaxed);
+    loop {
+        thread::sleep(interval);
+        let activity = ACTIVITY.load(Ordering::Relaxed);
+        // Waiting for the run window to open is not being stuck
+        let paused = window::paused();
+        let stuck = activity == last_activity && fd::open_count() > 0 && !paused;
+        last_activity = activity;
+
+        let mut state = String::new();
+        let stage = STAGE.lock().unwrap().clone();
+        let bytes = BYTES.load(Ordering::Relaxed);
+        let total = TOTAL.load(Ordering::Relaxed);
+        if !stage.is_empty() && paused {
+            state.push_str(&format!(
+                "STATUS={}, paused outside of the run window\n",
+                stage
+            ));
+        } else if !stage.is_empty() && bytes > 0 && total > 0 {
+            let elapsed = STAGE_START.lock().unwrap().elapsed();
+            let eta = progress::eta(bytes, total, elapsed).unwrap_or_default();
+            state.push_str(&format!(
+                "STATUS={}, {} of {} processed, {} s left\n",
+                stage,
+                FileLen(bytes),
+                FileLen(total),
+                eta.as_secs()
+            ));
+        } else if !stage.is_empty() && bytes > 0 {
+            state.push_str(&format!("STATUS={}, {} processed\n", stage, FileLen(bytes)));
+        }
+        if watchdog && !stuck {
+            state.push_str("WATCHDOG=1\n");
+        }
+        if !state.is_empty() {
+            notify(state.trim_end());
+        }
+    }
+}
+
+/// Sends datagrams to the notification socket of systemd
+#[cfg(unix)]
+struct Notifier {
+    socket: std::os::unix::net::UnixDatagram,
+    path: std::path::PathBuf,
+}
+
+#[cfg(unix)]
+impl Notifier {
+    fn from_env() -> Option<Notifier> {
+        use std::os::unix::net::UnixDatagram;
+        let path = std::env::var_os("NOTIFY_SOCKET")?;
+        // Sockets in the abstract namespace would need the address built by hand
+        // and systemd uses them only for its own internal services
+        if path.to_string_lossy().starts_with('@') {
+            return None;
+        }
+        let socket = UnixDatagram::unbound().ok()?;
+        Some(Notifier {
+            socket,
+            path: path.into(),
+        })
+    }
+
+    fn send(&self, state: &str) {
+        // Nothing to do if systemd is gone; the service will be stopped anyway
+        let _ = self.socket.send_to(state.as_bytes(), &self.path);
+    }
+}
+
+#[cfg(not(unix))]
+struct Notifier;
+
+#[cfg(not(unix))]
+impl Notifier {
+    fn from_env() -> Option<Notifier> {
+        None
+    }
+
+    fn send(&self, _state: &str) {}
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::os::unix::net::UnixDatagram;
+
+    use crate::util::test::with_dir;
+
+    use super::*;
+
+    #[test]
+    fn send_state_to_socket() {
+        with_dir("systemd/send_state_to_socket", |root| {
+            let path = root.join("notify.sock");
+            let receiver = UnixDatagram::bind(&path).unwrap();
+            let notifier = Notifier {
+                socket: UnixDatagram::unbound().unwrap(),
+                path,
+            };
+            notifier.send("READY=1");
+            let mut buf = [0; 64];
+            let len = receiver.recv(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"READY=1");
+        });
+    }
+}
@@ -454,7 +454,7 @@ mod test {
             let input_path = root.join("input.txt");
             let mut input = File::create(&input_path).unwrap();
             let content = b"content";
-            input.write(content).unwrap();
+            input.write_all(content).unwrap();
             drop(input);
 
             let input_path = Path::from(input_path);
@@ -483,7 +483,7 @@ mod test {
             let input_path = root.join("input.txt");
             let mut input = File::create(&input_path).unwrap();
             let content = b"content";
-            input.write(content).unwrap();
+            input.write_all(content).unwrap();
             drop(input);
 
             let input_path = Path::from(input_path);
@@ -0,0 +1,123 @@
+//! Recognizing the directories where desktop environments keep deleted files,
+//! and the directories holding caches.
+//!
+//! Such directories are full of copies of other files, which are not worth reporting,
+//! so they are skipped by scans unless `--include-trash` is given.
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+use lazy_static::lazy_static;
+
+lazy_static! {
+    /// Cache directories of the current user
+    static ref USER_CACHE_DIRS: Vec<PathBuf> = user_cache_dirs();
+}
+
+/// Directories holding the home directories of the users
+const HOME_ROOTS: [&str; 2] = ["/home", "/Users"];
+
+/// Returns true if the directory holds deleted files:
+/// - `.Trash`, `.Trash-<uid>` and `~/.local/share/Trash` on Linux (freedesktop.org spec),
+/// - `.Trash` and `.Trashes` on macOS,
+/// - `$RECYCLE.BIN` and `RECYCLER` on Windows.
+pub fn is_trash(dir: &Path) -> bool {
+    let name = match dir.file_name().and_then(|n| n.to_str()) {
+        Some(name) => name,
+        None => return false,
+    };
+    name == ".Trash"
+        || name == ".Trashes"
+        || name.starts_with(".Trash-")
+        || name.eq_ignore_ascii_case("$RECYCLE.BIN")
+        || name.eq_ignore_ascii_case("RECYCLER")
+        || dir.ends_with(".local/share/Trash")
+}
+
+/// Returns true if the directory is a common location of caches:
+/// `~/.cache` or `$XDG_CACHE_HOME` on Linux and `~/Library/Caches` on macOS.
+/// Directories with the same names located elsewhere, e.g. in projects, are not caches,
+/// unless they are in the home directory of another user, under `/home`, `/Users` or `/root`.
+pub fn is_cache(dir: &Path) -> bool {
+    is_cache_of(dir, &USER_CACHE_DIRS)
+}
+
+fn is_cache_of(dir: &Path, user_cache_dirs: &[PathBuf]) -> bool {
+    if user_cache_dirs.iter().any(|d| d == dir) {
+        return true;
+    }
+    let home = if dir.ends_with("Library/Caches") {
+        dir.parent().and_then(Path::parent)
+    } else if dir.ends_with(".cache") {
+        dir.parent()
+    } else {
+        None
+    };
+    match home {
+        Some(home) => {
+            home == Path::new("/root")
+                || HOME_ROOTS
+                    .iter()
+                    .any(|r| home.parent() == Some(Path::new(r)))
+        }
+        None => false,
+    }
+}
+
+fn user_cache_dirs() -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    if let Some(dir) = env::var_os("XDG_CACHE_HOME") {
+        dirs.push(PathBuf::from(dir));
+    }
+    if let Some(home) = env::var_os("HOME") {
+        let home = PathBuf::from(home);
+        dirs.push(home.join(".cache"));
+        dirs.push(home.join("Library/Caches"));
+    }
+    dirs
+}
+
+/// Returns true if the file is located in a trash directory, at any depth
+pub fn is_in_trash(path: &Path) -> bool {
+    path.ancestors().skip(1).any(is_trash)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn recognize_trash_dirs() {
+        assert!(is_trash(Path::new("/mnt/usb/.Trash-1000")));
+        assert!(is_trash(Path::new("/Volumes/usb/.Trashes")));
+        assert!(is_trash(Path::new("/home/user/.local/share/Trash")));
+        assert!(is_trash(Path::new("/mnt/c/$Recycle.Bin")));
+        assert!(!is_trash(Path::new("/home/user/Trash")));
+        assert!(!is_trash(Path::new("/home/user/.Trashcan")));
+    }
+
+    #[test]
+    fn recognize_cache_dirs() {
+        let user_cache_dirs = [PathBuf::from("/data/me/.cache"), PathBuf::from("/xdg")];
+        let is_cache = |dir: &str| is_cache_of(Path::new(dir), &user_cache_dirs);
+        assert!(is_cache("/home/user/.cache"));
+        assert!(is_cache("/root/.cache"));
+        assert!(is_cache("/Users/user/Library/Caches"));
+        assert!(is_cache("/data/me/.cache"));
+        assert!(is_cache("/xdg"));
+        assert!(!is_cache("/home/user/cache"));
+        assert!(!is_cache("/home/user/project/.cache"));
+        assert!(!is_cache("/data/other/.cache"));
+        assert!(!is_cache("/Users/user/backup/Library/Caches"));
+    }
+
+    #[test]
+    fn recognize_files_in_trash() {
+        assert!(is_in_trash(Path::new("/mnt/usb/.Trash-1000/files/foo")));
+        assert!(is_in_trash(Path::new(
+            "/home/user/.local/share/Trash/files/foo"
+        )));
+        assert!(!is_in_trash(Path::new("/home/user/docs/foo")));
+        assert!(!is_in_trash(Path::new("/mnt/usb/.Trash-1000")));
+    }
+}
@@ -1,5 +1,6 @@
 use serde::{Serialize, Serializer};
 use std::cell::Cell;
+use std::{fmt, io};
 
 /// Allows for serializing iterators
 pub struct IteratorWrapper<T>(pub Cell<Option<T>>);
@@ -17,7 +18,7 @@ where
 /// Sorts an array using a key generation function that can fail.
 /// Items for which the key could not be obtained are sorted last.
 /// Returns vector of errors encountered when obtaining the keys.
-pub fn fallible_sort_by_key<T, K, E>(v: &mut Vec<T>, f: impl Fn(&T) -> Result<K, E>) -> Vec<E>
+pub fn fallible_sort_by_key<T, K, E>(v: &mut [T], f: impl Fn(&T) -> Result<K, E>) -> Vec<E>
 where
     K: Ord,
 {
@@ -26,6 +27,40 @@ where
     errors
 }
 
+/// An I/O error annotated with a message.
+/// Keeps the original error, so its OS error code can still be inspected.
+#[derive(Debug)]
+struct ErrorWithContext {
+    message: String,
+    source: io::Error,
+}
+
+impl fmt::Display for ErrorWithContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for ErrorWithContext {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Replaces the message of the error, keeping its kind and the original error as the source
+pub fn with_context(message: String, e: io::Error) -> io::Error {
+    io::Error::new(e.kind(), ErrorWithContext { message, source: e })
+}
+
+/// Returns the OS error code of the error, or of the original error
+/// if the message was replaced by [`with_context`]
+pub fn raw_os_error(e: &io::Error) -> Option<i32> {
+    e.raw_os_error().or_else(|| {
+        let inner = e.get_ref()?.downcast_ref::<ErrorWithContext>()?;
+        raw_os_error(&inner.source)
+    })
+}
+
 /// Utility functions intended for testing.
 /// Beware they typically panic instead of returning `Err`.
 #[cfg(test)]
@@ -70,8 +105,8 @@ pub mod test {
         let mut delay = std::time::Duration::from_millis(1);
         loop {
             thread::sleep(delay);
-            create_file(&f);
-            let ctime = fs::metadata(&f).unwrap().modified().unwrap();
+            create_file(f);
+            let ctime = fs::metadata(f).unwrap().modified().unwrap();
             if ctime != time {
                 return ctime;
             }
@@ -89,10 +124,24 @@ pub mod test {
     /// Reads contents of a file to a string.
     /// Panics on errors.
     pub fn read_file(path: &std::path::Path) -> String {
-        let f = File::open(&path).unwrap();
+        let f = File::open(path).unwrap();
         let mut r = BufReader::new(f);
         let mut result = String::new();
         r.read_to_string(&mut result).unwrap();
         result
     }
+
+    /// Sets an extended attribute of the file.
+    /// Returns false and tells the test is skipped if the file system of the test directory
+    /// doesn't support it.
+    pub fn write_xattr(path: &std::path::Path, name: &str, value: &[u8]) -> bool {
+        let file = crate::path::Path::from(path);
+        match crate::xattr::write_attribute(&file, &name.into(), value) {
+            Ok(()) => true,
+            Err(e) => {
+                eprintln!("Skipping the test: can't set {} on {}: {}", name, file, e);
+                false
+            }
+        }
+    }
 }
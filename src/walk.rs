@@ -1,17 +1,28 @@
+use std::collections::{HashMap, HashSet};
 use std::env::current_dir;
-use std::fs::{read_link, symlink_metadata, DirEntry, FileType, ReadDir};
+#[cfg(not(target_os = "linux"))]
+use std::fs;
+use std::fs::{read_link, symlink_metadata, FileType};
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::{fs, io};
+use std::time::Duration;
 
 use dashmap::DashSet;
 use rayon::Scope;
 
-use crate::log::Log;
+use crate::config::SpecialFilePolicy;
+use crate::files::FileId;
+use crate::io_timeout;
+use crate::log::{EventKind, FileOp, Log};
 use crate::path::Path;
 use crate::selector::PathSelector;
-use rayon::prelude::ParallelSliceMut;
+use crate::snapshot;
+use crate::trash;
+use crate::window;
+use crate::window::RunWindow;
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 enum EntryType {
     File,
     Dir,
@@ -19,46 +30,61 @@ enum EntryType {
     Other,
 }
 
+impl EntryType {
+    fn from_file_type(file_type: FileType) -> EntryType {
+        if file_type.is_symlink() {
+            EntryType::SymLink
+        } else if file_type.is_file() {
+            EntryType::File
+        } else if file_type.is_dir() {
+            EntryType::Dir
+        } else {
+            EntryType::Other
+        }
+    }
+}
+
+/// Metadata of a regular file fetched by the walker while reading the parent directory.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(not(unix), allow(dead_code))]
+pub struct FileStat {
+    pub ino: u64,
+    pub len: u64,
+    /// Number of 512-byte blocks allocated to the file
+    pub blocks: u64,
+    /// User id of the owner
+    pub uid: u32,
+}
+
 /// A path to a file, directory or symbolic link.
-/// Provides an abstraction over `Path` and `DirEntry`
+/// Provides an abstraction over `Path` and a directory entry
 #[derive(Debug)]
 struct Entry {
     tpe: EntryType,
     path: Path,
+    stat: Option<FileStat>,
 }
 
 impl Entry {
-    pub fn new(file_type: FileType, path: Path) -> Entry {
-        if file_type.is_symlink() {
-            Entry {
-                tpe: EntryType::SymLink,
-                path,
-            }
-        } else if file_type.is_file() {
-            Entry {
-                tpe: EntryType::File,
-                path,
-            }
-        } else if file_type.is_dir() {
-            Entry {
-                tpe: EntryType::Dir,
-                path,
-            }
-        } else {
-            Entry {
-                tpe: EntryType::Other,
-                path,
-            }
+    pub fn new(tpe: EntryType, path: Path) -> Entry {
+        Entry {
+            tpe,
+            path,
+            stat: None,
         }
     }
 
-    pub fn from_path(path: Path) -> io::Result<Entry> {
-        symlink_metadata(&path.to_path_buf()).map(|meta| Entry::new(meta.file_type(), path))
+    pub fn from_path(path: Path, timeout: Option<Duration>) -> io::Result<Entry> {
+        io_timeout::symlink_metadata(&path, timeout)
+            .map(|meta| Entry::new(EntryType::from_file_type(meta.file_type()), path))
     }
 
-    pub fn from_dir_entry(base: &Arc<Path>, dir_entry: DirEntry) -> io::Result<Entry> {
+    #[cfg(not(target_os = "linux"))]
+    pub fn from_dir_entry(base: &Arc<Path>, dir_entry: fs::DirEntry) -> io::Result<Entry> {
         let path = base.join(Path::from(dir_entry.file_name()));
-        dir_entry.file_type().map(|ft| Entry::new(ft, path))
+        dir_entry
+            .file_type()
+            .map(|ft| Entry::new(EntryType::from_file_type(ft), path))
     }
 }
 
@@ -68,16 +94,54 @@ pub struct Walk<'a> {
     pub base_dir: Arc<Path>,
     pub depth: usize,
     pub skip_hidden: bool,
+    /// Skips trash and cache directories found below the roots
+    pub skip_trash: bool,
+    /// Skips btrfs and ZFS snapshots found below the roots
+    pub skip_snapshots: bool,
     pub follow_links: bool,
+    pub report_links: bool,
     pub path_selector: PathSelector,
     pub on_visit: &'a (dyn Fn(&Path) + Sync + Send),
     pub log: Option<&'a Log>,
+    /// Directories are read only inside of this time window, if set
+    pub run_window: Option<RunWindow>,
+    /// Gives up reading a directory or the metadata of a file after this time, if set
+    pub io_timeout: Option<Duration>,
+    /// Maximum number of directories waiting to be visited by the worker threads.
+    /// Directories found when this limit is reached are visited immediately by the thread
+    /// that found them, depth-first, so the memory used by the queues stays bounded even
+    /// for very large and deep trees.
+    pub max_pending_dirs: usize,
+    /// Whether to warn about FIFOs, sockets and device nodes, which are never reported
+    pub special_files: SpecialFilePolicy,
+    /// Whether to warn about symbolic links to regular files skipped because neither
+    /// `follow_links` nor `report_links` is set
+    pub file_links: SpecialFilePolicy,
+    /// Number of FIFOs, sockets and device nodes skipped so far
+    pub skipped_special_files: AtomicUsize,
+    /// Number of symbolic links to regular files skipped so far
+    pub skipped_file_links: AtomicUsize,
+    /// Number of hidden files and directories skipped so far
+    pub skipped_hidden: AtomicUsize,
+    /// Number of files and directories excluded by the name and path patterns so far
+    pub skipped_by_name: AtomicUsize,
+    /// Number of trash, cache and snapshot directories skipped so far
+    pub skipped_dirs: AtomicUsize,
 }
 
+/// Enough to keep all threads busy, even if some directories are much larger than others
+const DEFAULT_MAX_PENDING_DIRS: usize = 4096;
+
 /// Private shared state scoped to a single `run` invocation.
 struct WalkState<F> {
     pub consumer: F,
+    pub fetch_stat: bool,
     pub visited: DashSet<u128>,
+    pub visited_dirs: DashSet<FileId>,
+    /// Roots of the walk, never skipped as trash or snapshots
+    pub roots: HashSet<Path>,
+    /// Number of spawned tasks for visiting directories and links that haven't completed yet
+    pub pending: AtomicUsize,
 }
 
 impl<'a> Walk<'a> {
@@ -88,10 +152,23 @@ impl<'a> Walk<'a> {
             base_dir: Arc::new(base_dir.clone()),
             depth: usize::MAX,
             skip_hidden: false,
+            skip_trash: false,
+            skip_snapshots: false,
             follow_links: false,
+            report_links: false,
             path_selector: PathSelector::new(base_dir),
             on_visit: &|_| {},
             log: None,
+            run_window: None,
+            io_timeout: None,
+            max_pending_dirs: DEFAULT_MAX_PENDING_DIRS,
+            special_files: SpecialFilePolicy::Skip,
+            file_links: SpecialFilePolicy::Skip,
+            skipped_special_files: AtomicUsize::new(0),
+            skipped_file_links: AtomicUsize::new(0),
+            skipped_hidden: AtomicUsize::new(0),
+            skipped_by_name: AtomicUsize::new(0),
+            skipped_dirs: AtomicUsize::new(0),
         }
     }
 
@@ -106,64 +183,161 @@ impl<'a> Walk<'a> {
         I: IntoIterator<Item = Path> + Send,
         F: Fn(Path) + Sync + Send,
     {
+        self.walk(roots, false, |path, _| consumer(path))
+    }
+
+    /// Like [`run`](Walk::run), but additionally passes the inode number and the length
+    /// of each file to the `consumer` if the walker could fetch them cheaply while reading
+    /// the parent directory. Otherwise, e.g. for files reached through symbolic links
+    /// or on platforms other than Linux, passes `None` and the consumer has to fetch
+    /// the metadata by itself.
+    pub fn run_with_stat<I, F>(&self, roots: I, consumer: F)
+    where
+        I: IntoIterator<Item = Path> + Send,
+        F: Fn(Path, Option<FileStat>) + Sync + Send,
+    {
+        self.walk(roots, true, consumer)
+    }
+
+    fn walk<I, F>(&self, roots: I, fetch_stat: bool, consumer: F)
+    where
+        I: IntoIterator<Item = Path> + Send,
+        F: Fn(Path, Option<FileStat>) + Sync + Send,
+    {
+        // Keep the roots as given, so the reported paths start with them:
+        let roots: Vec<Path> = roots
+            .into_iter()
+            .map(|p| self.base_dir.resolve(p).resolve_dots())
+            .collect();
         let state = WalkState {
             consumer,
+            fetch_stat,
             visited: DashSet::new(),
+            visited_dirs: DashSet::new(),
+            roots: roots.iter().cloned().collect(),
+            pending: AtomicUsize::new(0),
         };
+        let mut root_ids: HashMap<FileId, Path> = HashMap::new();
         rayon::scope(|scope| {
-            for p in roots.into_iter() {
-                let p = self.absolute(p);
-                match fs::metadata(&p.to_path_buf()) {
+            for p in roots {
+                // Different roots may resolve to the same physical location, e.g. through
+                // symbolic links. Scanning such location more than once would count its files
+                // many times, so we skip the duplicates.
+                if let Ok(id) = FileId::new(&p) {
+                    if let Some(first) = root_ids.get(&id) {
+                        self.log_info(format!(
+                            "Skipping {} because it refers to the same location as {}",
+                            p, first
+                        ));
+                        continue;
+                    }
+                    root_ids.insert(id, p.clone());
+                }
+                match io_timeout::metadata(&p, self.io_timeout) {
                     Ok(metadata) if metadata.is_dir() && self.depth == 0 => self.log_warn(format!(
                         "Skipping directory {} because recursive scan is disabled.",
                         p
                     )),
-                    _ => scope.spawn(|scope| self.visit_path(p, scope, 0, &state)),
+                    // Unless links are followed, roots given as symbolic links are visited
+                    // as their targets, but keep their own paths:
+                    Ok(metadata) if !self.follow_links => {
+                        let entry = Entry::new(EntryType::from_file_type(metadata.file_type()), p);
+                        if self.path_selector.matches_dir(&entry.path) {
+                            scope.spawn(|scope| self.visit_tree(vec![(entry, 0)], scope, &state))
+                        }
+                    }
+                    _ => scope.spawn(|scope| {
+                        let mut stack = Vec::new();
+                        self.visit_path(p, 0, &mut stack);
+                        self.visit_tree(stack, scope, &state)
+                    }),
                 }
             }
         });
     }
 
-    /// Visits path of any type (can be a symlink target, file or dir)
-    fn visit_path<'s, 'w, F>(
+    /// Visits the entries on the stack and the entries found below them.
+    /// Entries not handed over to other threads are pushed onto the stack instead of
+    /// being visited recursively, so deep trees can't overflow the stack of the thread.
+    fn visit_tree<'s, 'w, F>(
         &'s self,
-        path: Path,
+        mut stack: Vec<(Entry, usize)>,
         scope: &Scope<'w>,
-        level: usize,
         state: &'w WalkState<F>,
     ) where
-        F: Fn(Path) + Sync + Send,
+        F: Fn(Path, Option<FileStat>) + Sync + Send,
         's: 'w,
     {
+        while let Some((entry, level)) = stack.pop() {
+            self.visit_entry(entry, scope, level, state, &mut stack);
+        }
+    }
+
+    /// Visits path of any type (can be a symlink target, file or dir)
+    /// by pushing its entry onto the stack
+    fn visit_path(&self, path: Path, level: usize, stack: &mut Vec<(Entry, usize)>) {
         if self.path_selector.matches_dir(&path) {
-            Entry::from_path(path.clone())
-                .map_err(|e| self.log_warn(format!("Failed to stat {}: {}", path.display(), e)))
+            Entry::from_path(path.clone(), self.io_timeout)
+                .map_err(|e| {
+                    self.log_unreadable(
+                        &path,
+                        FileOp::Stat,
+                        &e,
+                        format!("Failed to stat {}: {}", path.display(), e),
+                    )
+                })
                 .into_iter()
-                .for_each(|entry| self.visit_entry(entry, scope, level, state))
+                .for_each(|entry| stack.push((entry, level)))
         }
     }
 
     /// Visits a path that was already converted to an `Entry` so the entry type is known.
     /// Faster than `visit_path` because it doesn't need to call `stat` internally.
+    /// Entries found below it are pushed onto the `stack` or handed over to other threads.
     fn visit_entry<'s, 'w, F>(
         &'s self,
         entry: Entry,
         scope: &Scope<'w>,
         level: usize,
         state: &'w WalkState<F>,
+        stack: &mut Vec<(Entry, usize)>,
     ) where
-        F: Fn(Path) + Sync + Send,
+        F: Fn(Path, Option<FileStat>) + Sync + Send,
         's: 'w,
     {
         // For progress reporting
         (self.on_visit)(&entry.path);
 
         // Skip hidden files
-        if self.skip_hidden {
-            if let Some(name) = entry.path.file_name_cstr() {
-                if name.to_string_lossy().starts_with('.') {
-                    return;
-                }
+        if self.skip_hidden && Self::is_hidden(&entry.path) {
+            self.skipped_hidden.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        // Skip the directories excluded by name, unless given explicitly as roots
+        if level > 0
+            && matches!(entry.tpe, EntryType::Dir)
+            && self.path_selector.excludes_dir_name(&entry.path)
+        {
+            self.skipped_by_name.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        // Skip trash, caches and snapshots, unless given explicitly as roots.
+        // Btrfs snapshots are recognized only when the directory is read.
+        if (self.skip_trash || self.skip_snapshots)
+            && level > 0
+            && matches!(entry.tpe, EntryType::Dir)
+            && !state.roots.contains(&entry.path)
+        {
+            let path = entry.path.to_path_buf();
+            if self.skip_trash && (trash::is_trash(&path) || trash::is_cache(&path)) {
+                self.skipped_dirs.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            if self.skip_snapshots && snapshot::is_zfs_snapshot_dir(&path) {
+                self.skip_snapshot(&entry.path);
+                return;
             }
         }
 
@@ -173,127 +347,387 @@ impl<'a> Walk<'a> {
             return;
         }
 
+        // Detect directory cycles. The same directory may be reachable by many different
+        // paths through symbolic links, so the path alone isn't enough to identify it.
+        if let EntryType::Dir = entry.tpe {
+            if self.follow_links && !self.first_visit_of_dir(&entry.path, state) {
+                return;
+            }
+        }
+
         match entry.tpe {
-            EntryType::File => self.visit_file(entry.path, state),
-            EntryType::Dir => self.visit_dir(entry.path, scope, level, state),
-            EntryType::SymLink => self.visit_link(&entry.path, scope, level, state),
-            EntryType::Other => {}
+            EntryType::File => self.visit_file(entry.path, entry.stat, state),
+            EntryType::Dir => self.visit_dir(entry.path, scope, level, state, stack),
+            EntryType::SymLink => self.visit_link(&entry.path, level, state, stack),
+            EntryType::Other => self.visit_special_file(&entry.path),
+        }
+    }
+
+    /// Counts a FIFO, a socket or a device node, which is never sent to the consumer
+    fn visit_special_file(&self, path: &Path) {
+        if !self.path_selector.matches_full_path(path) {
+            return;
+        }
+        self.skipped_special_files.fetch_add(1, Ordering::Relaxed);
+        if self.special_files == SpecialFilePolicy::Warn {
+            self.log_warn_file(
+                path,
+                format!("Skipping {} {}", special_file_kind(path), path.display()),
+            );
+        } else {
+            self.log_filtered(path, "Special file");
         }
     }
 
     /// If file matches selection criteria, sends it to the consumer
-    fn visit_file<F>(&self, path: Path, state: &WalkState<F>)
+    fn visit_file<F>(&self, path: Path, stat: Option<FileStat>, state: &WalkState<F>)
     where
-        F: Fn(Path) + Sync + Send,
+        F: Fn(Path, Option<FileStat>) + Sync + Send,
     {
         if self.path_selector.matches_full_path(&path) {
-            (state.consumer)(path)
+            (state.consumer)(path, stat)
+        } else {
+            self.skipped_by_name.fetch_add(1, Ordering::Relaxed);
+            self.log_filtered(&path, "Path excluded by name or pattern filters")
+        }
+    }
+
+    /// Returns true if the directory hasn't been visited yet and marks it as visited.
+    /// Directories are identified by device and inode.
+    /// If the identifier can't be obtained, assumes the directory wasn't visited.
+    fn first_visit_of_dir<F>(&self, path: &Path, state: &WalkState<F>) -> bool {
+        match FileId::new(path) {
+            Ok(id) => state.visited_dirs.insert(id),
+            Err(_) => true,
         }
     }
 
     /// Resolves a symbolic link.
-    /// If `follow_links` is set to false, does nothing.
-    fn visit_link<'s, 'w, F>(
-        &'s self,
+    /// If `follow_links` is set, the link target is visited like any other path.
+    /// If `report_links` is set, a link pointing to a regular file is reported as a file
+    /// and links to directories are skipped.
+    /// Otherwise does nothing.
+    fn visit_link<F>(
+        &self,
         path: &Path,
-        scope: &Scope<'w>,
         level: usize,
-        state: &'w WalkState<F>,
+        state: &WalkState<F>,
+        stack: &mut Vec<(Entry, usize)>,
     ) where
-        F: Fn(Path) + Sync + Send,
-        's: 'w,
+        F: Fn(Path, Option<FileStat>) + Sync + Send,
     {
         if self.follow_links {
             match self.resolve_link(path) {
-                Ok(target) => self.visit_path(target, scope, level, state),
-                Err(e) => self.log_warn(format!("Failed to read link {}: {}", path.display(), e)),
+                Ok(target) => self.visit_path(target, level, stack),
+                Err(e) => self.log_unreadable(
+                    path,
+                    FileOp::ReadLink,
+                    &e,
+                    format!("Failed to read link {}: {}", path.display(), e),
+                ),
+            }
+        } else if self.report_links {
+            match io_timeout::metadata(path, self.io_timeout) {
+                Ok(metadata) if metadata.is_file() => self.visit_file(path.clone(), None, state),
+                Ok(_) => {}
+                Err(e) => self.log_unreadable(
+                    path,
+                    FileOp::Stat,
+                    &e,
+                    format!("Failed to stat {}: {}", path.display(), e),
+                ),
+            }
+        } else if self.path_selector.matches_full_path(path) {
+            let links_to_file =
+                io_timeout::metadata(path, self.io_timeout).map_or(false, |m| m.is_file());
+            if links_to_file {
+                self.skipped_file_links.fetch_add(1, Ordering::Relaxed);
+                if self.file_links == SpecialFilePolicy::Warn {
+                    self.log_warn_file(
+                        path,
+                        format!("Skipping symbolic link to file {}", path.display()),
+                    );
+                } else {
+                    self.log_filtered(path, "Symbolic link to file");
+                }
             }
         }
     }
 
     /// Reads the contents of the directory pointed to by `path`
-    /// and recursively visits each child entry
+    /// and schedules a visit of each child entry
     fn visit_dir<'s, 'w, F>(
         &'s self,
         path: Path,
         scope: &Scope<'w>,
         level: usize,
         state: &'w WalkState<F>,
+        stack: &mut Vec<(Entry, usize)>,
     ) where
-        F: Fn(Path) + Sync + Send,
+        F: Fn(Path, Option<FileStat>) + Sync + Send,
         's: 'w,
     {
         if level < self.depth && self.path_selector.matches_dir(&path) {
-            match std::fs::read_dir(path.to_path_buf()) {
-                Ok(rd) => {
-                    for entry in Self::sorted_entries(path, rd) {
-                        scope.spawn(move |s| self.visit_entry(entry, s, level + 1, state))
+            window::wait(self.run_window, || false);
+            let skip_snapshot = self.skip_snapshots && level > 0 && !state.roots.contains(&path);
+            match self.read_dir(&path, state.fetch_stat, skip_snapshot) {
+                Ok(Some(entries)) => {
+                    for entry in Self::sorted_entries(entries) {
+                        self.visit_child(entry, scope, level + 1, state, stack)
                     }
                 }
-                Err(e) => self.log_warn(format!("Failed to read dir {}: {}", path.display(), e)),
+                Ok(None) => self.skip_snapshot(&path),
+                Err(e) => self.log_unreadable(
+                    &path,
+                    FileOp::ReadDir,
+                    &e,
+                    format!("Failed to read dir {}: {}", path.display(), e),
+                ),
             }
         }
     }
 
-    #[cfg(unix)]
-    fn sort_dir_entries_by_inode(entries: &mut Vec<DirEntry>) {
+    fn skip_snapshot(&self, path: &Path) {
+        self.skipped_dirs.fetch_add(1, Ordering::Relaxed);
+        self.log_warn(format!("Skipping snapshot {}", path.display()));
+    }
+
+    /// Schedules a visit of an entry of a directory.
+    /// Directories and links are handed over to other threads, unless too many of them
+    /// are already pending. Regular files are cheap to visit, so they are always visited
+    /// by the current thread, as are the directories that are not handed over.
+    fn visit_child<'s, 'w, F>(
+        &'s self,
+        entry: Entry,
+        scope: &Scope<'w>,
+        level: usize,
+        state: &'w WalkState<F>,
+        stack: &mut Vec<(Entry, usize)>,
+    ) where
+        F: Fn(Path, Option<FileStat>) + Sync + Send,
+        's: 'w,
+    {
+        let spawn = match entry.tpe {
+            EntryType::Dir | EntryType::SymLink => {
+                state.pending.load(Ordering::Relaxed) < self.max_pending_dirs
+            }
+            EntryType::File | EntryType::Other => false,
+        };
+        if spawn {
+            state.pending.fetch_add(1, Ordering::Relaxed);
+            scope.spawn(move |s| {
+                self.visit_tree(vec![(entry, level)], s, state);
+                state.pending.fetch_sub(1, Ordering::Relaxed);
+            })
+        } else {
+            stack.push((entry, level))
+        }
+    }
+
+    /// Returns true if the file name starts with a dot.
+    fn has_hidden_name(path: &Path) -> bool {
+        match path.file_name_cstr() {
+            Some(name) => name.to_bytes().starts_with(b"."),
+            None => false,
+        }
+    }
+
+    #[cfg(not(windows))]
+    fn is_hidden(path: &Path) -> bool {
+        Self::has_hidden_name(path)
+    }
+
+    /// On Windows, files with hidden or system attribute are also considered hidden.
+    #[cfg(windows)]
+    fn is_hidden(path: &Path) -> bool {
+        use std::os::windows::fs::MetadataExt;
+        const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+        const FILE_ATTRIBUTE_SYSTEM: u32 = 0x4;
+        Self::has_hidden_name(path)
+            || symlink_metadata(path.to_path_buf())
+                .map(|m| m.file_attributes() & (FILE_ATTRIBUTE_HIDDEN | FILE_ATTRIBUTE_SYSTEM) != 0)
+                .unwrap_or(false)
+    }
+
+    /// Reads the entries of a directory with raw `getdents64` calls.
+    /// Entry types are taken from `d_type`, so no `stat` calls are needed unless the
+    /// file system doesn't report them. If `fetch_stat` is set, the metadata of the regular files
+    /// that would be reported is fetched relative to the open directory, in the order of inode
+    /// numbers, which avoids resolving the full path of each file again later.
+    /// If `skip_snapshot` is set and the directory is a btrfs snapshot, returns `None`.
+    #[cfg(target_os = "linux")]
+    fn read_dir(
+        &self,
+        path: &Path,
+        fetch_stat: bool,
+        skip_snapshot: bool,
+    ) -> io::Result<Option<Vec<Entry>>> {
+        use rayon::prelude::ParallelSliceMut;
+        use std::ffi::CStr;
+
+        let timeout = self.io_timeout;
+        let dir_path = path.clone();
+        let (dir, (mut raw_entries, ino)) = io_timeout::run(timeout, move || {
+            let dir = raw::Dir::open(&dir_path)?;
+            let entries = dir.entries()?;
+            Ok::<_, io::Error>((Arc::new(dir), entries))
+        })??;
+        // On a timeout, the abandoned call keeps its own reference to the directory
+        let stat = |name: &CStr| {
+            let (dir, name) = (dir.clone(), name.to_owned());
+            io_timeout::run(timeout, move || dir.stat(&name))?
+        };
+        if skip_snapshot && ino.map_or(false, |ino| snapshot::is_btrfs_snapshot_fd(dir.fd(), ino)) {
+            return Ok(None);
+        }
+        // Accessing entries in the order of identifiers should be faster on rotational drives
+        raw_entries.par_sort_unstable_by_key(|e| e.ino);
+        Ok(Some(self.dir_entries(path, raw_entries, fetch_stat, stat)))
+    }
+
+    /// Converts the raw entries of the directory at `path` to `Entry`s.
+    /// Calls `stat` for the entries of unknown type and, if `fetch_stat` is set,
+    /// for the reported regular files. Entries that can't be stat-ed are logged and skipped.
+    #[cfg(target_os = "linux")]
+    fn dir_entries(
+        &self,
+        path: &Path,
+        raw_entries: Vec<raw::DirEntry>,
+        fetch_stat: bool,
+        stat: impl Fn(&std::ffi::CStr) -> io::Result<(EntryType, FileStat)>,
+    ) -> Vec<Entry> {
+        let parent = Arc::new(path.clone());
+        let mut entries = Vec::with_capacity(raw_entries.len());
+        for e in raw_entries {
+            let (tpe, file_stat) = match e.tpe {
+                Some(tpe) => (tpe, None),
+                None => match stat(&e.name) {
+                    Ok((tpe, stat)) => (tpe, Some(stat)),
+                    Err(err) => {
+                        let path = parent.child(e.name);
+                        self.log_unreadable(
+                            &path,
+                            FileOp::Stat,
+                            &err,
+                            format!("Failed to stat {}: {}", path.display(), err),
+                        );
+                        continue;
+                    }
+                },
+            };
+            let path = parent.child(e.name);
+            let stat = match tpe {
+                EntryType::File if fetch_stat && self.reports_file(&path) => {
+                    file_stat.or_else(|| {
+                        let name = path.file_name_cstr().unwrap();
+                        stat(name).ok().map(|(_, stat)| stat)
+                    })
+                }
+                _ => None,
+            };
+            entries.push(Entry { tpe, path, stat });
+        }
+        entries
+    }
+
+    /// Reads the entries of a directory with `std::fs::read_dir`.
+    #[cfg(not(target_os = "linux"))]
+    fn read_dir(
+        &self,
+        path: &Path,
+        _fetch_stat: bool,
+        _skip_snapshot: bool,
+    ) -> io::Result<Option<Vec<Entry>>> {
+        let parent = Arc::new(path.clone());
+        let dir_path = path.to_path_buf();
+        let mut entries: Vec<fs::DirEntry> = io_timeout::run(self.io_timeout, move || {
+            Ok::<_, io::Error>(fs::read_dir(dir_path)?.filter_map(|e| e.ok()).collect())
+        })??;
+        // Accessing entries in the order of identifiers should be faster on rotational drives
+        Self::sort_dir_entries_by_inode(&mut entries);
+        Ok(entries
+            .into_iter()
+            .filter_map(|e| Entry::from_dir_entry(&parent, e).ok())
+            .collect::<Vec<_>>()
+            .into())
+    }
+
+    /// Returns true if the regular file would be sent to the consumer.
+    #[cfg(target_os = "linux")]
+    fn reports_file(&self, path: &Path) -> bool {
+        !(self.skip_hidden && Self::is_hidden(path)) && self.path_selector.matches_full_path(path)
+    }
+
+    #[cfg(all(unix, not(target_os = "linux")))]
+    fn sort_dir_entries_by_inode(entries: &mut [fs::DirEntry]) {
+        use rayon::prelude::ParallelSliceMut;
         use std::os::unix::fs::DirEntryExt;
         entries.par_sort_unstable_by_key(|entry| entry.ino())
     }
 
     #[cfg(not(unix))]
-    fn sort_dir_entries_by_inode(_: &mut Vec<DirEntry>) {
+    fn sort_dir_entries_by_inode(_: &mut [fs::DirEntry]) {
         // do nothing
     }
 
     /// Sorts dir entries so that regular files are at the end.
-    /// Because each worker's queue is a LIFO, the files would be picked up first and the
-    /// dirs would be on the other side, amenable for stealing by other workers.
-    fn sorted_entries(parent: Path, rd: ReadDir) -> impl Iterator<Item = Entry> {
+    /// This way the dirs are queued before the current thread gets busy with the files,
+    /// so they are available for stealing by other workers early.
+    fn sorted_entries(entries: Vec<Entry>) -> impl Iterator<Item = Entry> {
         let mut files = vec![];
         let mut links = vec![];
         let mut dirs = vec![];
-        let path = Arc::new(parent);
-        let mut entries: Vec<DirEntry> = rd.filter_map(|e| e.ok()).collect();
-        // Accessing entries in the order of identifiers should be faster on rotational drives
-        Self::sort_dir_entries_by_inode(&mut entries);
-        entries
-            .into_iter()
-            .filter_map(|e| Entry::from_dir_entry(&path, e).ok())
-            .for_each(|e| match e.tpe {
-                EntryType::File => files.push(e),
-                EntryType::SymLink => links.push(e),
-                EntryType::Dir => dirs.push(e),
-                EntryType::Other => {}
-            });
+        entries.into_iter().for_each(|e| match e.tpe {
+            EntryType::File | EntryType::Other => files.push(e),
+            EntryType::SymLink => links.push(e),
+            EntryType::Dir => dirs.push(e),
+        });
         dirs.into_iter().chain(links).chain(files)
     }
 
     /// Returns the absolute target path of a symbolic link
     fn resolve_link(&self, link: &Path) -> io::Result<Path> {
-        let target = Path::from(read_link(link.to_path_buf())?);
-        let resolved = if target.is_relative() {
-            link.parent().unwrap().join(target)
-        } else {
-            target
-        };
-        Ok(self.absolute(resolved))
+        let link_buf = link.to_path_buf();
+        let target = Path::from(io_timeout::run(self.io_timeout, move || {
+            read_link(link_buf)
+        })??);
+        Ok(self.absolute(link.parent().unwrap().resolve(target)))
     }
 
     /// Returns absolute path with removed `.` and `..` components.
     /// Relative paths are resolved against `self.base_dir`.
     fn absolute(&self, path: Path) -> Path {
-        if path.is_relative() {
-            self.base_dir.join(path).canonicalize()
-        } else {
-            path.canonicalize()
-        }
+        self.base_dir.resolve(path).canonicalize()
     }
 
     /// Logs a warning
     fn log_warn(&self, msg: String) {
         self.log.iter().for_each(|l| l.warn(&msg))
     }
+
+    /// Logs a warning about a file or directory that couldn't be accessed
+    fn log_unreadable(&self, path: &Path, op: FileOp, error: &io::Error, msg: String) {
+        self.log
+            .iter()
+            .for_each(|l| l.unreadable_file(path, op, error, &msg))
+    }
+
+    /// Logs a warning about a file skipped because of its type
+    fn log_warn_file(&self, path: &Path, msg: String) {
+        self.log
+            .iter()
+            .for_each(|l| l.warn_file(EventKind::Filtered, path, &msg))
+    }
+
+    /// Records a file left out of the walk, without logging it to the human-readable log
+    fn log_filtered(&self, path: &Path, reason: &str) {
+        self.log.iter().for_each(|l| l.filtered_file(path, reason))
+    }
+
+    /// Logs an information message
+    fn log_info(&self, msg: String) {
+        self.log.iter().for_each(|l| l.info(&msg))
+    }
 }
 
 impl<'a> Default for Walk<'a> {
@@ -302,14 +736,204 @@ impl<'a> Default for Walk<'a> {
     }
 }
 
+/// Returns a human-readable name of the type of a special file, for the log
+#[cfg(unix)]
+fn special_file_kind(path: &Path) -> &'static str {
+    use std::os::unix::fs::FileTypeExt;
+    match symlink_metadata(path.to_path_buf()).map(|m| m.file_type()) {
+        Ok(t) if t.is_fifo() => "FIFO",
+        Ok(t) if t.is_socket() => "socket",
+        Ok(t) if t.is_block_device() => "block device",
+        Ok(t) if t.is_char_device() => "character device",
+        _ => "special file",
+    }
+}
+
+#[cfg(not(unix))]
+fn special_file_kind(_path: &Path) -> &'static str {
+    "special file"
+}
+
+/// Reading directories with raw `getdents64` system calls.
+/// Compared to `std::fs::read_dir`, many entries are read per system call into a large buffer
+/// and the directory stays open while its entries are processed, so their metadata can be
+/// fetched relative to the directory descriptor, without resolving their full paths.
+#[cfg(target_os = "linux")]
+mod raw {
+    use std::convert::TryInto;
+    use std::ffi::{CStr, CString};
+    use std::io;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::RawFd;
+
+    use super::{EntryType, FileStat};
+    use crate::path::Path;
+
+    const BUF_LEN: usize = 64 * 1024;
+
+    // Offsets of the fields of `struct linux_dirent64`
+    const D_INO: usize = 0;
+    const D_RECLEN: usize = 16;
+    const D_TYPE: usize = 18;
+    const D_NAME: usize = 19;
+
+    pub struct DirEntry {
+        pub ino: u64,
+        /// Set to `None` if the file system doesn't report the type in the directory entry
+        pub tpe: Option<EntryType>,
+        pub name: CString,
+    }
+
+    /// An open directory. Closed on drop.
+    pub struct Dir {
+        fd: RawFd,
+    }
+
+    impl Dir {
+        pub fn open(path: &Path) -> io::Result<Dir> {
+            let path = CString::new(path.to_path_buf().as_os_str().as_bytes())?;
+            let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC;
+            let fd = unsafe { libc::open(path.as_ptr(), flags) };
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(Dir { fd })
+        }
+
+        pub fn fd(&self) -> RawFd {
+            self.fd
+        }
+
+        /// Reads all entries of the directory except `.` and `..`.
+        /// Returns also the inode number of the directory, given by the `.` entry.
+        pub fn entries(&self) -> io::Result<(Vec<DirEntry>, Option<u64>)> {
+            let mut buf = vec![0u8; BUF_LEN];
+            let mut entries = Vec::new();
+            let mut dir_ino = None;
+            loop {
+                let len = unsafe {
+                    libc::syscall(libc::SYS_getdents64, self.fd, buf.as_mut_ptr(), buf.len())
+                };
+                if len < 0 {
+                    let e = io::Error::last_os_error();
+                    if e.kind() == io::ErrorKind::Interrupted {
+                        continue;
+                    }
+                    return Err(e);
+                }
+                if len == 0 {
+                    return Ok((entries, dir_ino));
+                }
+                let mut offset = 0;
+                while offset < len as usize {
+                    let record = &buf[offset..];
+                    let reclen = u16::from_ne_bytes(record[D_RECLEN..D_TYPE].try_into().unwrap());
+                    let record = &record[..reclen as usize];
+                    offset += reclen as usize;
+
+                    let name = &record[D_NAME..];
+                    let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+                    let ino = u64::from_ne_bytes(record[D_INO..D_INO + 8].try_into().unwrap());
+                    if name == b"." {
+                        dir_ino = Some(ino);
+                        continue;
+                    }
+                    if name == b".." {
+                        continue;
+                    }
+                    entries.push(DirEntry {
+                        ino,
+                        tpe: entry_type_from_d_type(record[D_TYPE]),
+                        name: CString::new(name).unwrap(),
+                    });
+                }
+            }
+        }
+
+        /// Returns the type and the metadata of the entry named `name`.
+        /// Doesn't follow symbolic links.
+        pub fn stat(&self, name: &CStr) -> io::Result<(EntryType, FileStat)> {
+            #[cfg(target_env = "gnu")]
+            match self.statx(name) {
+                Err(e) if e.raw_os_error() == Some(libc::ENOSYS) => {}
+                result => return result,
+            }
+            self.fstatat(name)
+        }
+
+        /// Fetches only the fields we need, which is cheaper than `fstatat` on some file systems.
+        #[cfg(target_env = "gnu")]
+        fn statx(&self, name: &CStr) -> io::Result<(EntryType, FileStat)> {
+            let mut buf: libc::statx = unsafe { std::mem::zeroed() };
+            let flags = libc::AT_SYMLINK_NOFOLLOW;
+            let mask = libc::STATX_TYPE
+                | libc::STATX_INO
+                | libc::STATX_SIZE
+                | libc::STATX_BLOCKS
+                | libc::STATX_UID;
+            if unsafe { libc::statx(self.fd, name.as_ptr(), flags, mask, &mut buf) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let stat = FileStat {
+                ino: buf.stx_ino,
+                len: buf.stx_size,
+                blocks: buf.stx_blocks,
+                uid: buf.stx_uid,
+            };
+            Ok((entry_type_from_mode(buf.stx_mode as u32), stat))
+        }
+
+        fn fstatat(&self, name: &CStr) -> io::Result<(EntryType, FileStat)> {
+            let mut buf: libc::stat64 = unsafe { std::mem::zeroed() };
+            let flags = libc::AT_SYMLINK_NOFOLLOW;
+            if unsafe { libc::fstatat64(self.fd, name.as_ptr(), &mut buf, flags) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let stat = FileStat {
+                ino: buf.st_ino as u64,
+                len: buf.st_size as u64,
+                blocks: buf.st_blocks as u64,
+                uid: buf.st_uid,
+            };
+            Ok((entry_type_from_mode(buf.st_mode as u32), stat))
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            unsafe { libc::close(self.fd) };
+        }
+    }
+
+    fn entry_type_from_d_type(d_type: u8) -> Option<EntryType> {
+        match d_type {
+            libc::DT_REG => Some(EntryType::File),
+            libc::DT_DIR => Some(EntryType::Dir),
+            libc::DT_LNK => Some(EntryType::SymLink),
+            libc::DT_UNKNOWN => None,
+            _ => Some(EntryType::Other),
+        }
+    }
+
+    fn entry_type_from_mode(mode: u32) -> EntryType {
+        match mode & libc::S_IFMT {
+            libc::S_IFREG => EntryType::File,
+            libc::S_IFDIR => EntryType::Dir,
+            libc::S_IFLNK => EntryType::SymLink,
+            _ => EntryType::Other,
+        }
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::path::PathBuf;
     use std::sync::Mutex;
 
     use super::*;
+    use crate::pattern::Pattern;
     use crate::util::test::*;
-    use std::fs::{create_dir, File};
+    use std::fs::{create_dir, create_dir_all, File};
 
     #[test]
     fn list_files() {
@@ -335,6 +959,152 @@ mod test {
         });
     }
 
+    #[test]
+    fn list_files_through_io_timeout_helper() {
+        with_dir("target/test/walk/io_timeout/", |test_root| {
+            let dir = test_root.join("dir");
+            create_dir(&dir).unwrap();
+            let file1 = test_root.join("file1.txt");
+            let file2 = dir.join("file2.txt");
+            File::create(&file1).unwrap();
+            File::create(&file2).unwrap();
+            let mut walk = Walk::new();
+            walk.io_timeout = Some(Duration::from_secs(10));
+            assert_eq!(run_walk(walk, test_root.clone()), vec![file2, file1]);
+        });
+    }
+
+    #[test]
+    fn list_many_files() {
+        with_dir("target/test/walk/many_files/", |test_root| {
+            // more than fits into a single directory read buffer
+            let mut files: Vec<_> = (0..3000)
+                .map(|i| test_root.join(format!("file_with_a_long_name_{}.txt", i)))
+                .collect();
+            for f in files.iter() {
+                File::create(f).unwrap();
+            }
+            files.sort();
+            let walk = Walk::new();
+            assert_eq!(run_walk(walk, test_root.clone()), files);
+        });
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn report_file_stat() {
+        with_dir("target/test/walk/file_stat/", |test_root| {
+            use std::os::unix::fs::MetadataExt;
+            let file = test_root.join("file.txt");
+            write_file(&file, "foo");
+            let metadata = std::fs::metadata(&file).unwrap();
+
+            let results = Mutex::new(Vec::new());
+            let walk = Walk::new();
+            walk.run_with_stat(vec![Path::from(&test_root)], |path, stat| {
+                results.lock().unwrap().push((path.to_path_buf(), stat))
+            });
+            let results = results.into_inner().unwrap();
+            assert_eq!(results.len(), 1);
+            assert_eq!(results[0].0, file);
+            let stat = results[0].1.unwrap();
+            assert_eq!(stat.len, 3);
+            assert_eq!(stat.ino, metadata.ino());
+        });
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn log_entries_of_unknown_type_that_cannot_be_stat_ed() {
+        use std::ffi::CString;
+
+        let log = Log::new();
+        log.record_unreadable();
+        let mut walk = Walk::new();
+        walk.log = Some(&log);
+
+        let dir = Path::from("dir");
+        let raw_entries = vec![
+            raw::DirEntry {
+                ino: 1,
+                tpe: None,
+                name: CString::new("gone").unwrap(),
+            },
+            raw::DirEntry {
+                ino: 2,
+                tpe: Some(EntryType::File),
+                name: CString::new("file").unwrap(),
+            },
+        ];
+        let stat = |_: &std::ffi::CStr| Err(io::Error::from_raw_os_error(libc::ENOENT));
+        let entries = walk.dir_entries(&dir, raw_entries, false, stat);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path.to_path_buf(), PathBuf::from("dir/file"));
+
+        let unreadable = log.take_unreadable();
+        assert_eq!(unreadable.len(), 1);
+        assert_eq!(unreadable[0].path.to_path_buf(), PathBuf::from("dir/gone"));
+        assert_eq!(unreadable[0].operation, FileOp::Stat);
+        assert_eq!(unreadable[0].errno, Some(libc::ENOENT));
+        assert_eq!(log.warning_count(), 1);
+    }
+
+    #[test]
+    fn visit_dirs_inline_if_too_many_pending() {
+        with_dir("target/test/walk/max_pending/", |test_root| {
+            let mut files = Vec::new();
+            for i in 0..10 {
+                let dir = test_root.join(format!("dir{}", i)).join("nested");
+                create_dir_all(&dir).unwrap();
+                let file = dir.join("file.txt");
+                File::create(&file).unwrap();
+                files.push(file);
+            }
+            let mut walk = Walk::new();
+            walk.max_pending_dirs = 0;
+            assert_eq!(run_walk(walk, test_root.clone()), files);
+        });
+    }
+
+    #[test]
+    fn visit_deep_tree_inline_without_recursion() {
+        with_dir("target/test/walk/deep/", |test_root| {
+            let mut dir = test_root.clone();
+            for _ in 0..1000 {
+                dir.push("d");
+            }
+            create_dir_all(&dir).unwrap();
+            let file = dir.join("file.txt");
+            File::create(&file).unwrap();
+
+            // A stack this small overflows if each level is visited recursively:
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(1)
+                .stack_size(512 * 1024)
+                .build()
+                .unwrap();
+            let mut walk = Walk::new();
+            walk.max_pending_dirs = 0;
+            let files = pool.install(|| run_walk(walk, test_root.clone()));
+            assert_eq!(files, vec![file]);
+        });
+    }
+
+    #[test]
+    fn descend_into_dirs_with_long_paths() {
+        with_dir("target/test/walk/long_paths/", |test_root| {
+            let mut dir = test_root.clone();
+            for _ in 0..4 {
+                dir = dir.join("d".repeat(100));
+            }
+            create_dir_all(&dir).unwrap();
+            let file = dir.join("file.txt");
+            File::create(&file).unwrap();
+            let walk = Walk::new();
+            assert_eq!(run_walk(walk, test_root.clone()), vec![file]);
+        });
+    }
+
     #[test]
     #[cfg(unix)]
     fn follow_rel_file_sym_links() {
@@ -408,6 +1178,52 @@ mod test {
         });
     }
 
+    #[test]
+    #[cfg(unix)]
+    fn report_sym_links() {
+        with_dir("target/test/walk/report_links/", |test_root| {
+            use std::os::unix::fs::symlink;
+            let dir = test_root.join("dir");
+            let file = dir.join("file.txt");
+            let file_link = test_root.join("file_link");
+            let dir_link = test_root.join("dir_link");
+
+            create_dir(&dir).unwrap();
+            File::create(&file).unwrap();
+            symlink(&file, &file_link).unwrap();
+            symlink(&dir, &dir_link).unwrap();
+
+            let mut walk = Walk::new();
+            walk.report_links = true;
+            assert_eq!(run_walk(walk, test_root.clone()), vec![file, file_link]);
+        });
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn count_skipped_special_files_and_links() {
+        with_dir("target/test/walk/special_files/", |test_root| {
+            use nix::sys::stat::Mode;
+            use std::os::unix::fs::symlink;
+            let file = test_root.join("file.txt");
+            let fifo = test_root.join("fifo");
+            let file_link = test_root.join("file_link");
+
+            File::create(&file).unwrap();
+            nix::unistd::mkfifo(&fifo, Mode::S_IRWXU).unwrap();
+            symlink(&file, &file_link).unwrap();
+
+            let walk = Walk::new();
+            let results = Mutex::new(Vec::new());
+            walk.run(vec![Path::from(test_root)], |path| {
+                results.lock().unwrap().push(path.to_path_buf())
+            });
+            assert_eq!(results.into_inner().unwrap(), vec![file]);
+            assert_eq!(walk.skipped_special_files.load(Ordering::Relaxed), 1);
+            assert_eq!(walk.skipped_file_links.load(Ordering::Relaxed), 1);
+        });
+    }
+
     #[test]
     fn skip_hidden() {
         with_dir("target/test/walk/7/", |test_root| {
@@ -423,9 +1239,103 @@ mod test {
         });
     }
 
+    #[test]
+    fn skip_trash() {
+        with_dir("target/test/walk/skip_trash/", |test_root| {
+            let trash_dir = test_root.join(".Trash-1000").join("files");
+            create_dir_all(&trash_dir).unwrap();
+            File::create(trash_dir.join("file.txt")).unwrap();
+            let file = test_root.join("file.txt");
+            File::create(&file).unwrap();
+            let mut walk = Walk::new();
+            walk.skip_trash = true;
+            assert_eq!(run_walk(walk, test_root.clone()), vec![file]);
+        });
+    }
+
+    #[test]
+    fn skip_snapshots_unless_given_as_roots() {
+        with_dir("target/test/walk/skip_snapshots/", |test_root| {
+            let snapshots = test_root.join(".zfs").join("snapshot");
+            create_dir_all(snapshots.join("daily")).unwrap();
+            let snapshot_file = snapshots.join("daily").join("file.txt");
+            File::create(&snapshot_file).unwrap();
+            let file = test_root.join("file.txt");
+            File::create(&file).unwrap();
+            let mut walk = Walk::new();
+            walk.skip_snapshots = true;
+            assert_eq!(run_walk(walk, test_root.clone()), vec![file.clone()]);
+
+            let mut walk = Walk::new();
+            walk.skip_snapshots = true;
+            walk.follow_links = true;
+            let roots = vec![test_root.clone(), snapshots];
+            assert_eq!(run_walk_multi(walk, roots), vec![snapshot_file, file]);
+        });
+    }
+
+    #[test]
+    fn exclude_dirs_by_name() {
+        with_dir("target/test/walk/exclude_dir/", |test_root| {
+            let modules = test_root.join("app").join("node_modules");
+            create_dir_all(&modules).unwrap();
+            File::create(modules.join("file.js")).unwrap();
+            let file = test_root.join("app").join("file.js");
+            File::create(&file).unwrap();
+            let mut walk = Walk::new();
+            walk.path_selector = walk
+                .path_selector
+                .exclude_dir_names(vec![Pattern::glob("node_*").unwrap()]);
+            assert_eq!(run_walk(walk, test_root.clone()), vec![file]);
+
+            // roots are scanned even if they match
+            let mut walk = Walk::new();
+            walk.path_selector = walk
+                .path_selector
+                .exclude_dir_names(vec![Pattern::glob("node_*").unwrap()]);
+            assert_eq!(
+                run_walk(walk, modules.clone()),
+                vec![modules.join("file.js")]
+            );
+        });
+    }
+
+    #[test]
+    fn include_hidden_by_default() {
+        with_dir("target/test/walk/include_hidden/", |test_root| {
+            let hidden_file = test_root.join(".file.txt");
+            File::create(&hidden_file).unwrap();
+            let walk = Walk::new();
+            assert_eq!(run_walk(walk, test_root.clone()), vec![hidden_file]);
+        });
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn skip_roots_resolving_to_the_same_dir() {
+        with_dir("target/test/walk/same_roots/", |test_root| {
+            use std::os::unix::fs::symlink;
+            let dir = test_root.join("dir");
+            let link = test_root.join("link");
+            let file = dir.join("file.txt");
+
+            create_dir(&dir).unwrap();
+            File::create(&file).unwrap();
+            symlink(&dir, &link).unwrap();
+
+            let walk = Walk::new();
+            let roots = vec![dir.clone(), link, dir];
+            assert_eq!(run_walk_multi(walk, roots), vec![file]);
+        });
+    }
+
     fn run_walk(walk: Walk, root: PathBuf) -> Vec<PathBuf> {
+        run_walk_multi(walk, vec![root])
+    }
+
+    fn run_walk_multi(walk: Walk, roots: Vec<PathBuf>) -> Vec<PathBuf> {
         let results = Mutex::new(Vec::new());
-        walk.run(vec![Path::from(root)], |path| {
+        walk.run(roots.into_iter().map(Path::from), |path| {
             results.lock().unwrap().push(path.to_path_buf())
         });
 
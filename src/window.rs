@@ -0,0 +1,200 @@
+//! Restricting reading the files to a daily time window.
+//!
+//! Outside of the window, the threads scanning directories and hashing files wait before
+//! reading the next directory or the next file, and continue where they stopped
+//! when the window opens again. No file is kept open while waiting. Everything computed
+//! so far is kept in memory, so a scan can span many nights.
+//! Each grouping job has its own window, given by its configuration.
+
+use std::cmp::min;
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use chrono::{Local, NaiveTime};
+
+/// How long to sleep at most before checking the time and the cancellation again.
+/// The clock may be changed while waiting, e.g. by switching to daylight saving time.
+const MAX_SLEEP: Duration = Duration::from_secs(1);
+
+/// A range of the time of the day, e.g. `22:00-06:00`.
+/// The window spans midnight if it ends before it starts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RunWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl RunWindow {
+    /// Returns true if the time is inside of the window
+    pub fn contains(&self, time: NaiveTime) -> bool {
+        if self.start <= self.end {
+            time >= self.start && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+
+    /// Returns how long it takes from the given time until the window opens,
+    /// or zero if the window is open
+    pub fn time_until_open(&self, time: NaiveTime) -> Duration {
+        if self.contains(time) {
+            return Duration::from_secs(0);
+        }
+        let wait = self.start.signed_duration_since(time);
+        let wait = if wait < chrono::Duration::zero() {
+            wait + chrono::Duration::days(1)
+        } else {
+            wait
+        };
+        wait.to_std().unwrap_or_default()
+    }
+}
+
+impl FromStr for RunWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "Invalid run window: {}. Expected the start and end time, e.g. 22:00-06:00",
+                s
+            )
+        };
+        let (start, end) = s.split_once('-').ok_or_else(invalid)?;
+        let parse = |t: &str| NaiveTime::parse_from_str(t.trim(), "%H:%M").map_err(|_| invalid());
+        let window = RunWindow {
+            start: parse(start)?,
+            end: parse(end)?,
+        };
+        if window.start == window.end {
+            return Err(format!("Empty run window: {}", s));
+        }
+        Ok(window)
+    }
+}
+
+impl Display for RunWindow {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+/// Number of threads waiting for their run window to open
+static WAITING: AtomicUsize = AtomicUsize::new(0);
+
+/// Returns true if any thread is waiting for its run window to open
+pub(crate) fn paused() -> bool {
+    WAITING.load(Ordering::Relaxed) > 0
+}
+
+/// Blocks the current thread until the window is open or `cancelled` returns true.
+/// Returns immediately if the window is `None`.
+pub(crate) fn wait(window: Option<RunWindow>, cancelled: impl Fn() -> bool) {
+    let window = match window {
+        Some(window) => window,
+        None => return,
+    };
+    let mut waiting = false;
+    loop {
+        let wait = window.time_until_open(Local::now().time());
+        if wait == Duration::from_secs(0) || cancelled() {
+            break;
+        }
+        if !waiting {
+            WAITING.fetch_add(1, Ordering::Relaxed);
+            waiting = true;
+        }
+        thread::sleep(min(wait, MAX_SLEEP));
+    }
+    if waiting {
+        WAITING.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn time(s: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(s, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn parse_run_window() {
+        let window: RunWindow = "22:00-06:30".parse().unwrap();
+        assert_eq!(window.start, time("22:00"));
+        assert_eq!(window.end, time("06:30"));
+        assert_eq!(window.to_string(), "22:00-06:30");
+        assert!("22:00".parse::<RunWindow>().is_err());
+        assert!("25:00-06:00".parse::<RunWindow>().is_err());
+        assert!("06:00-06:00".parse::<RunWindow>().is_err());
+    }
+
+    #[test]
+    fn window_spanning_midnight() {
+        let window: RunWindow = "22:00-06:00".parse().unwrap();
+        assert!(window.contains(time("23:00")));
+        assert!(window.contains(time("00:00")));
+        assert!(!window.contains(time("06:00")));
+        assert!(!window.contains(time("12:00")));
+        assert_eq!(
+            window.time_until_open(time("01:00")),
+            Duration::from_secs(0)
+        );
+        assert_eq!(
+            window.time_until_open(time("21:00")),
+            Duration::from_secs(3600)
+        );
+    }
+
+    #[test]
+    fn wait_stops_when_cancelled() {
+        use std::sync::atomic::AtomicBool;
+        use std::sync::Arc;
+
+        let now = Local::now().time();
+        let open = RunWindow {
+            start: now - chrono::Duration::hours(1),
+            end: now + chrono::Duration::hours(1),
+        };
+        let closed = RunWindow {
+            start: now + chrono::Duration::hours(1),
+            end: now + chrono::Duration::hours(2),
+        };
+        wait(None, || false);
+        wait(Some(open), || false);
+
+        let cancel = Arc::new(AtomicBool::new(false));
+        let cancel_clone = cancel.clone();
+        let waiting =
+            thread::spawn(move || wait(Some(closed), || cancel_clone.load(Ordering::Relaxed)));
+        while !paused() {
+            thread::sleep(Duration::from_millis(10));
+        }
+        // other jobs don't wait for this window
+        wait(Some(open), || false);
+        cancel.store(true, Ordering::Relaxed);
+        waiting.join().unwrap();
+        assert!(!paused());
+    }
+
+    #[test]
+    fn window_within_day() {
+        let window: RunWindow = "01:00-05:00".parse().unwrap();
+        assert!(window.contains(time("01:00")));
+        assert!(!window.contains(time("05:00")));
+        assert_eq!(
+            window.time_until_open(time("23:00")),
+            Duration::from_secs(2 * 3600)
+        );
+    }
+}
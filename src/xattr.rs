@@ -0,0 +1,230 @@
+//! Reading and writing extended attributes of files.
+//!
+//! They are used to group only the files with matching attributes, see `--match-xattrs`
+//! and `--match-acl`, and to keep the security labels of files intact while deduplicating them.
+//!
+//! On Linux, access control lists and SELinux security contexts are stored as extended
+//! attributes too, in the `system.posix_acl_*` and `security.selinux` attributes.
+//! File capabilities are stored in `security.capability`.
+
+use std::ffi::OsString;
+use std::io;
+
+use crate::path::Path;
+
+/// Names of the attributes holding POSIX access control lists on Linux
+const ACL_ATTRS: &[&str] = &["system.posix_acl_access", "system.posix_acl_default"];
+
+/// Extended attributes of a file as name-value pairs, sorted by name
+pub type Attributes = Vec<(OsString, Vec<u8>)>;
+
+/// Namespace of the attributes used by the security modules of the kernel, holding e.g.
+/// SELinux contexts and file capabilities
+const SECURITY_PREFIX: &str = "security.";
+
+/// Attribute treated as a security label by the tests, because only privileged processes
+/// can set the attributes in the `security.` namespace
+#[cfg(test)]
+pub const TEST_SECURITY_LABEL: &str = "user.fclones-test-label";
+
+/// Returns true if the attribute holds an access control list
+pub fn is_acl(name: &OsString) -> bool {
+    ACL_ATTRS.iter().any(|&acl| name == acl)
+}
+
+/// Returns true if the attribute holds a security label, e.g. an SELinux context
+/// or file capabilities
+pub fn is_security_label(name: &OsString) -> bool {
+    #[cfg(test)]
+    if name == TEST_SECURITY_LABEL {
+        return true;
+    }
+    name.to_string_lossy().starts_with(SECURITY_PREFIX)
+}
+
+/// Reads the security labels of the file, sorted by name
+pub fn read_security_labels(path: &Path) -> io::Result<Attributes> {
+    let mut attrs = read_attributes(path)?;
+    attrs.retain(|(name, _)| is_security_label(name));
+    Ok(attrs)
+}
+
+/// Returns true if the error means that the file system does not support extended attributes
+pub fn is_not_supported(err: &io::Error) -> bool {
+    #[cfg(any(target_os = "linux", target_os = "macos"))]
+    return err.raw_os_error() == Some(libc::ENOTSUP);
+    #[cfg(not(any(target_os = "linux", target_os = "macos")))]
+    return err.kind() == io::ErrorKind::Other;
+}
+
+/// Sets an extended attribute of the file, replacing its current value if it exists
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn write_attribute(path: &Path, name: &OsString, value: &[u8]) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let invalid = |e| io::Error::new(io::ErrorKind::InvalidInput, e);
+    let path = CString::new(path.to_path_buf().as_os_str().as_bytes()).map_err(invalid)?;
+    let name = CString::new(name.as_bytes()).map_err(invalid)?;
+    let value_ptr = value.as_ptr() as *const libc::c_void;
+    #[cfg(target_os = "linux")]
+    let result = unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), value_ptr, value.len(), 0) };
+    #[cfg(target_os = "macos")]
+    let result =
+        unsafe { libc::setxattr(path.as_ptr(), name.as_ptr(), value_ptr, value.len(), 0, 0) };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Extended attributes are supported on Linux and macOS only
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn write_attribute(_path: &Path, _name: &OsString, _value: &[u8]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "Extended attributes are not supported",
+    ))
+}
+
+/// Reads the extended attributes of the file, sorted by name.
+/// Returns an empty list if the file system does not support extended attributes.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn read_attributes(path: &Path) -> io::Result<Attributes> {
+    use std::ffi::{CStr, CString, OsStr};
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.to_path_buf().as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+
+    let names = read_buf(|buf, len| unsafe { list(path.as_ptr(), buf, len) })?;
+    let mut attrs = Vec::new();
+    for name in names.split(|&b| b == 0).filter(|n| !n.is_empty()) {
+        let name = CString::new(name).unwrap();
+        let value = read_buf(|buf, len| unsafe { get(path.as_ptr(), name.as_ptr(), buf, len) })?;
+        let name: &CStr = &name;
+        attrs.push((OsStr::from_bytes(name.to_bytes()).to_os_string(), value));
+    }
+    attrs.sort();
+    Ok(attrs)
+}
+
+/// Extended attributes are supported on Linux and macOS only
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub fn read_attributes(_path: &Path) -> io::Result<Attributes> {
+    Ok(vec![])
+}
+
+/// Calls a function filling a buffer, like `getxattr`, first to get the required size
+/// of the buffer and then to fill it. Retries if the data grows in the meantime.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn read_buf<F>(f: F) -> io::Result<Vec<u8>>
+where
+    F: Fn(*mut libc::c_void, usize) -> libc::ssize_t,
+{
+    loop {
+        let len = f(std::ptr::null_mut(), 0);
+        if len < 0 {
+            return not_supported_as_empty(io::Error::last_os_error());
+        }
+        let mut buf = vec![0u8; len as usize];
+        let len = f(buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+        if len >= 0 {
+            buf.truncate(len as usize);
+            return Ok(buf);
+        }
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() != Some(libc::ERANGE) {
+            return not_supported_as_empty(err);
+        }
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+fn not_supported_as_empty(err: io::Error) -> io::Result<Vec<u8>> {
+    match err.raw_os_error() {
+        Some(libc::ENOTSUP) | Some(libc::ENODATA) => Ok(vec![]),
+        _ => Err(err),
+    }
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn list(
+    path: *const std::os::raw::c_char,
+    buf: *mut libc::c_void,
+    len: usize,
+) -> libc::ssize_t {
+    libc::listxattr(path, buf as *mut std::os::raw::c_char, len)
+}
+
+#[cfg(target_os = "macos")]
+unsafe fn list(
+    path: *const std::os::raw::c_char,
+    buf: *mut libc::c_void,
+    len: usize,
+) -> libc::ssize_t {
+    libc::listxattr(path, buf as *mut std::os::raw::c_char, len, 0)
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn get(
+    path: *const std::os::raw::c_char,
+    name: *const std::os::raw::c_char,
+    buf: *mut libc::c_void,
+    len: usize,
+) -> libc::ssize_t {
+    libc::getxattr(path, name, buf, len)
+}
+
+#[cfg(target_os = "macos")]
+unsafe fn get(
+    path: *const std::os::raw::c_char,
+    name: *const std::os::raw::c_char,
+    buf: *mut libc::c_void,
+    len: usize,
+) -> libc::ssize_t {
+    libc::getxattr(path, name, buf, len, 0, 0)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use crate::util::test::{with_dir, write_xattr};
+
+    use super::*;
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn write_and_read_user_attributes() {
+        with_dir("xattr/read", |root| {
+            let file = root.join("file");
+            fs::write(&file, b"foo").unwrap();
+            let path = Path::from(&file);
+            let attrs = read_attributes(&path).unwrap();
+            assert!(attrs
+                .iter()
+                .all(|(name, _)| !name.to_string_lossy().starts_with("user.")));
+
+            if !write_xattr(&file, "user.fclones.test", b"bar") {
+                return;
+            }
+            let attrs = read_attributes(&path).unwrap();
+            assert!(attrs.contains(&("user.fclones.test".into(), b"bar".to_vec())));
+        });
+    }
+
+    #[test]
+    fn recognize_acl() {
+        assert!(is_acl(&OsString::from("system.posix_acl_access")));
+        assert!(!is_acl(&OsString::from("security.selinux")));
+    }
+
+    #[test]
+    fn recognize_security_labels() {
+        assert!(is_security_label(&OsString::from("security.selinux")));
+        assert!(is_security_label(&OsString::from("security.capability")));
+        assert!(!is_security_label(&OsString::from("user.xdg.tags")));
+    }
+}